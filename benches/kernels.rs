@@ -0,0 +1,45 @@
+//! Benchmarks of the distance kernels and parsing on generated trees.
+//!
+//! Every kernel runs on the same workload, pairs of trees with sizes within the threshold
+//! like the pairs left by size buckets. The region layout group compares scans of the
+//! structural filter over rows of four regions, the layout of the index, with one array
+//! per region. Run with `cargo bench --features bench`.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tree_statistics::generate::{generate, GeneratorConfig, SizeDistribution};
+use tree_statistics::microbench::{Kernel, Workload};
+
+const PAIRS: usize = 1_000;
+const THRESHOLD: usize = 5;
+
+fn trees(min_size: usize, max_size: usize) -> Vec<String> {
+    generate(&GeneratorConfig {
+        count: 1_000,
+        min_size,
+        max_size,
+        size_distribution: SizeDistribution::Uniform,
+        max_fanout: 0,
+        depth_bias: 0.3,
+        alphabet: 50,
+        zipf: 1.0,
+        seed: 7,
+    })
+}
+
+fn bench_kernels(c: &mut Criterion) {
+    for (name, min_size, max_size) in [("small", 10, 30), ("large", 100, 200)] {
+        let workload = Workload::new(trees(min_size, max_size), PAIRS, THRESHOLD, 7).unwrap();
+        let mut group = c.benchmark_group(format!("kernels {name} trees"));
+        for kernel in Kernel::ALL {
+            group.throughput(Throughput::Elements(workload.items(kernel) as u64));
+            group.bench_function(format!("{kernel:?}"), |b| b.iter(|| workload.run(kernel)));
+        }
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_kernels
+}
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+//! Benchmarks of tree parsing on a collection of many small trees.
+//!
+//! The crate has no library target, so the parsing module is included directly.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use std::io::Write;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/parsing.rs"]
+mod parsing;
+
+use parsing::{parse_dataset_reporting, parse_single, parse_tree_tokens, LabelDict, TreeParser};
+
+const TREE: &str = "{article{author{a}}{author{b}}{title{t{x}{y}}}{year{2024}}{pages{1}{2}}}";
+
+fn collection_file(tree_count: usize) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("tree-statistics-bench-{tree_count}.bracket"));
+    let mut f = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    for _ in 0..tree_count {
+        writeln!(f, "{TREE}").unwrap();
+    }
+    path
+}
+
+fn bench_parse_tree(c: &mut Criterion) {
+    let mut ld = LabelDict::default();
+    parse_single(TREE.to_owned(), &mut ld);
+    let tokens = parse_tree_tokens(TREE.to_owned()).unwrap();
+
+    c.bench_function("parse_tree fresh", |b| {
+        b.iter(|| parsing::parse_tree(black_box(&tokens), &ld).unwrap())
+    });
+    let mut parser = TreeParser::default();
+    c.bench_function("parse_tree pooled", |b| {
+        b.iter(|| parser.parse(black_box(&tokens), &ld).unwrap())
+    });
+}
+
+fn bench_parse_dataset(c: &mut Criterion) {
+    let path = collection_file(100_000);
+    c.bench_function("parse_dataset 100k small trees", |b| {
+        b.iter_batched(
+            LabelDict::default,
+            |mut ld| parse_dataset_reporting(&path, &mut ld, Default::default()).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    std::fs::remove_file(path).unwrap();
+}
+
+criterion_group!(benches, bench_parse_tree, bench_parse_dataset);
+criterion_main!(benches);
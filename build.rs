@@ -0,0 +1,51 @@
+fn main() {
+    #[cfg(feature = "capi")]
+    generate_header();
+    #[cfg(feature = "grpc")]
+    generate_grpc_service();
+}
+
+/// Generates the C header of the `capi` library into `OUT_DIR`, the copy in include/ is
+/// refreshed with cbindgen by hand and checked against this one by the tests of src/capi.rs
+#[cfg(feature = "capi")]
+fn generate_header() {
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let config =
+        cbindgen::Config::from_file("cbindgen.toml").expect("Unable to read cbindgen.toml");
+    let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/capi.rs")
+        .generate()
+        .expect("Unable to generate C header")
+        .write_to_file(out_dir.join("tree_statistics.h"));
+}
+
+/// Generates the server of `proto/tree_statistics.proto`. Messages are written by hand
+/// in src/grpc.rs, so building does not need protoc.
+#[cfg(feature = "grpc")]
+fn generate_grpc_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+    println!("cargo:rerun-if-changed=proto/tree_statistics.proto");
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::proto::{input}"))
+            .output_type(format!("crate::grpc::proto::{output}"))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("TreeSimilarity")
+        .package("tree_statistics")
+        .method(
+            method("query", "Query", "QueryRequest", "QueryResponse")
+                .client_streaming()
+                .server_streaming()
+                .build(),
+        )
+        .method(method("stats", "Stats", "StatsRequest", "StatsResponse").build())
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+}
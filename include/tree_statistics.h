@@ -0,0 +1,108 @@
+#ifndef TREE_STATISTICS_H
+#define TREE_STATISTICS_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Label intersection lower bound
+ */
+#define TS_METHOD_LBLINT 0
+
+/**
+ * String edit distance lower bound
+ */
+#define TS_METHOD_SED 1
+
+/**
+ * Structural filter lower bound
+ */
+#define TS_METHOD_STRUCTURAL 2
+
+/**
+ * Parsed trees sorted by size
+ */
+typedef struct TsCollection TsCollection;
+
+/**
+ * Per tree indexes of one lower bound method
+ */
+typedef struct TsIndex TsIndex;
+
+/**
+ * Candidate tree ids owned by the library, free with [`ts_candidates_free`]
+ */
+typedef struct TsCandidates {
+  uintptr_t *ids;
+  uintptr_t len;
+} TsCandidates;
+
+/**
+ * Message of the last error on the calling thread, or NULL. The string is valid
+ * until the next failing call on the same thread.
+ */
+const char *ts_last_error(void);
+
+/**
+ * Parses a dataset file with one tree in bracket notation per line.
+ * Returns NULL on failure.
+ *
+ * # Safety
+ * `path` must be a valid NUL terminated string.
+ */
+struct TsCollection *ts_collection_parse(const char *path);
+
+/**
+ * Number of trees in the collection
+ *
+ * # Safety
+ * `collection` must be a handle returned by [`ts_collection_parse`].
+ */
+uintptr_t ts_collection_len(const struct TsCollection *collection);
+
+/**
+ * # Safety
+ * `collection` must be a handle returned by [`ts_collection_parse`] or NULL.
+ */
+void ts_collection_free(struct TsCollection *collection);
+
+/**
+ * Builds indexes of one of the `TS_METHOD_*` lower bounds over the collection.
+ * The index does not borrow the collection, which can be freed afterwards.
+ * Returns NULL on failure.
+ *
+ * # Safety
+ * `collection` must be a handle returned by [`ts_collection_parse`].
+ */
+struct TsIndex *ts_index_build(const struct TsCollection *collection, uint32_t method);
+
+/**
+ * Finds candidate trees whose lower bound to the query tree in bracket notation is
+ * at most `k`. Candidates are written into `out`. Returns 0 on success, -1 on failure.
+ *
+ * # Safety
+ * `index` must be a handle returned by [`ts_index_build`], `tree` a valid NUL
+ * terminated string and `out` a valid pointer.
+ */
+int32_t ts_index_query(struct TsIndex *index,
+                       const char *tree,
+                       uintptr_t k,
+                       struct TsCandidates *out);
+
+/**
+ * # Safety
+ * `candidates` must be filled by [`ts_index_query`] and not freed before.
+ */
+void ts_candidates_free(struct TsCandidates candidates);
+
+/**
+ * # Safety
+ * `index` must be a handle returned by [`ts_index_build`] or NULL.
+ */
+void ts_index_free(struct TsIndex *index);
+
+#endif  /* TREE_STATISTICS_H */
@@ -0,0 +1,94 @@
+//! Heap high-water marks of lower bound methods.
+//!
+//! With the `peak-memory` feature the global allocator is [`CountingAllocator`], the system
+//! allocator counting the bytes currently allocated and their maximum. Unlike the estimates
+//! of [`crate::memory`], counts include everything a method allocates, temporary buffers of
+//! indexing and querying as well as allocator requests for growing vectors. Counting costs
+//! two atomic updates per allocation, so it is left out of default builds and
+//! [`PeakScope::peak`] is `None` there.
+#[cfg(feature = "peak-memory")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "peak-memory")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "peak-memory")]
+fn allocated(bytes: usize) {
+    let current = CURRENT.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK.fetch_max(current, Ordering::Relaxed);
+}
+
+#[cfg(feature = "peak-memory")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                allocated(new_size - layout.size());
+            } else {
+                CURRENT.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Peak of the bytes allocated since the scope started, beyond the bytes allocated at its
+/// start. Memory kept from before, e.g. the collection and indexes of earlier methods, is
+/// not counted. Starting a scope resets the peak, so scopes must not overlap.
+pub struct PeakScope {
+    start: usize,
+}
+
+impl PeakScope {
+    pub fn start() -> Self {
+        let start = CURRENT.load(Ordering::Relaxed);
+        PEAK.store(start, Ordering::Relaxed);
+        Self { start }
+    }
+
+    /// `None` unless built with the `peak-memory` feature
+    pub fn peak(&self) -> Option<usize> {
+        cfg!(feature = "peak-memory")
+            .then(|| PEAK.load(Ordering::Relaxed).saturating_sub(self.start))
+    }
+}
+
+#[cfg(all(test, feature = "peak-memory"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peak_scope() {
+        // other tests allocate concurrently, so only a lower bound of the peak is certain
+        let scope = PeakScope::start();
+        let buffer = vec![1u8; 8 << 20];
+        drop(std::hint::black_box(buffer));
+        assert!(scope.peak().unwrap() >= 8 << 20);
+    }
+}
@@ -0,0 +1,524 @@
+//! Benchmark suite running lower bound methods across thresholds and
+//! reporting time, candidates, precision and recall in a single report.
+//!
+//! Warm runs reuse indexes built by an unmeasured warm-up run, as a long running service
+//! answering many queries does. Cold runs build all indexes of a method again and start
+//! with CPU caches evicted by writing a buffer larger than the last level cache from every
+//! thread, as the first queries after a service start do. Inputs are parsed before any run,
+//! so the OS page cache does not take part in either. Both report the fastest and slowest
+//! of the measured runs.
+//!
+//! Filters behave differently on tiny and huge trees, which aggregates over all queries
+//! hide. With size buckets the queries are also split into quantiles of their tree sizes
+//! and every bucket is measured on its own, after the run of all queries warmed the
+//! indexes of warm runs.
+//!
+//! Kernel runs measure single distance computations and parsing on a workload of dataset
+//! trees instead of whole methods, see [`crate::microbench`].
+use crate::collection::{CollectionView, UnsortedCollection};
+use crate::lb;
+use crate::lb::indexes::histograms::Candidates;
+use crate::microbench::{Kernel, Workload};
+use crate::number_format::FloatFormat;
+use crate::parsing::{LabelDict, ParsedTree};
+use crate::LowerBoundMethods;
+use clap::ValueEnum;
+use itertools::Itertools;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+use rustc_hash::FxHashSet;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheMode {
+    /// Indexes are built by an unmeasured warm-up run and reused by all measured runs
+    Warm,
+    /// Every run builds the indexes of the method again after evicting CPU caches
+    Cold,
+    /// Cold runs followed by warm runs, reported as separate records
+    Both,
+}
+
+impl CacheMode {
+    fn modes(self) -> &'static [CacheMode] {
+        match self {
+            CacheMode::Both => &[CacheMode::Cold, CacheMode::Warm],
+            CacheMode::Warm => &[CacheMode::Warm],
+            CacheMode::Cold => &[CacheMode::Cold],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchOptions {
+    pub cache: CacheMode,
+    /// Measured runs of every method and threshold
+    pub runs: usize,
+    pub quiet: bool,
+    /// Precision and recall, and time per item of kernels, are rounded to it
+    pub floats: FloatFormat,
+    /// Quantiles of query sizes reported besides all queries, 0 reports all queries only
+    pub size_buckets: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchRecord {
+    pub method: String,
+    /// Threshold used for all queries, or `None` when query file thresholds were used
+    pub threshold: Option<usize>,
+    /// Either cold or warm
+    pub cache: CacheMode,
+    /// Sizes of the smallest and largest query of a size bucket, `None` for all queries
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    pub queries: usize,
+    /// Fastest run including the indexing of queries, and of trees in cold runs
+    pub time_ms: u128,
+    /// Slowest run, cold starts show up here when the fastest run hides them
+    pub max_time_ms: u128,
+    pub candidates: usize,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelRecord {
+    pub kernel: Kernel,
+    pub threshold: usize,
+    pub cache: CacheMode,
+    /// Trees parsed or pairs compared in every run
+    pub items: usize,
+    pub time_ms: u128,
+    pub max_time_ms: u128,
+    /// Fastest run divided by the items
+    pub ns_per_item: f64,
+}
+
+/// Reads real results file with `t1,t2,distance` records.
+pub fn read_ground_truth(
+    results_path: &impl AsRef<Path>,
+) -> Result<Vec<(usize, usize, usize)>, anyhow::Error> {
+    let rreader = BufReader::new(File::open(results_path)?);
+    let mut rreader = csv::Reader::from_reader(rreader);
+    let mut real_result = vec![];
+    for result in rreader.records() {
+        let record = result?;
+        real_result.push((record[0].parse()?, record[1].parse()?, record[2].parse()?));
+    }
+    Ok(real_result)
+}
+
+/// Counts candidates among the ground truth pairs within threshold of the query (first
+/// element of a pair), returns them and the number of such pairs. Candidates are
+/// `(query, tree)` pairs and match only in that orientation.
+pub fn count_correct(
+    candidates: &[(usize, usize)],
+    ground_truth: &[(usize, usize, usize)],
+    threshold_of: impl Fn(usize) -> Option<usize>,
+) -> (usize, usize) {
+    let real: FxHashSet<(usize, usize)> = ground_truth
+        .iter()
+        .filter(|(q, _, dist)| threshold_of(*q).is_some_and(|k| *dist <= k))
+        .map(|(q, t, _)| (*q, *t))
+        .collect();
+    let correct = candidates.iter().filter(|pair| real.contains(pair)).count();
+    (correct, real.len())
+}
+
+/// Computes precision and recall of candidates against the ground truth pairs
+/// within threshold of the query (first element of a pair).
+pub fn precision_recall(
+    candidates: &[(usize, usize)],
+    ground_truth: &[(usize, usize, usize)],
+    threshold_of: impl Fn(usize) -> Option<usize>,
+) -> (f64, f64) {
+    let (correct, real) = count_correct(candidates, ground_truth, threshold_of);
+    let precision = correct as f64 / std::cmp::max(candidates.len(), 1) as f64;
+    let recall = if real == 0 {
+        1.0
+    } else {
+        correct as f64 / real as f64
+    };
+    (precision, recall)
+}
+
+/// Share of the ground truth pairs within threshold of the query found among the
+/// candidates, repeated candidates are counted once
+pub fn recall(
+    candidates: &[(usize, usize)],
+    ground_truth: &[(usize, usize, usize)],
+    threshold_of: impl Fn(usize) -> Option<usize>,
+) -> f64 {
+    let candidates = candidates.iter().copied().unique().collect::<Vec<_>>();
+    precision_recall(&candidates, ground_truth, threshold_of).1
+}
+
+/// Size of the buffer written before cold runs, larger than the last level cache of
+/// common CPUs
+const EVICTION_BYTES: usize = 64 << 20;
+
+/// Writes every word of `buffer` in parallel, so the caches of all threads running
+/// filters afterwards hold only the buffer
+fn evict_cpu_caches(buffer: &mut [u64]) {
+    buffer.par_chunks_mut(1 << 12).for_each(|chunk| {
+        for word in chunk {
+            *word = word.wrapping_add(1);
+        }
+    });
+    std::hint::black_box(buffer);
+}
+
+/// Query ids in `buckets` quantiles of their sizes. All queries of a size are in one
+/// bucket, so a bucket of a frequent size holds more queries and empty buckets are left out.
+pub fn size_quantiles(sizes: &[usize], buckets: usize) -> Vec<Vec<usize>> {
+    let mut sorted = sizes.to_vec();
+    sorted.sort_unstable();
+    let mut bounds = (1..=buckets)
+        .filter_map(|i| sorted.get((i * sorted.len()).div_ceil(buckets).checked_sub(1)?))
+        .copied()
+        .collect::<Vec<_>>();
+    bounds.dedup();
+    let mut quantiles = vec![vec![]; bounds.len()];
+    for (qid, size) in sizes.iter().enumerate() {
+        quantiles[bounds.partition_point(|bound| bound < size)].push(qid);
+    }
+    quantiles
+}
+
+/// Times of the measured runs of `method` and the candidates of the last one
+fn measure<'a>(
+    method: LowerBoundMethods,
+    cache: CacheMode,
+    pre: &lb::preprocess::Preprocessed<'a>,
+    preprocess: impl Fn() -> Result<lb::preprocess::Preprocessed<'a>, UnsortedCollection>,
+    eviction_buffer: &mut [u64],
+    queries: &[(usize, ParsedTree)],
+    runs: usize,
+) -> Result<(Vec<Duration>, Candidates), anyhow::Error> {
+    let mut times = vec![];
+    let mut candidates = vec![];
+    for _ in 0..runs.max(1) {
+        let cold_pre;
+        let pre = match cache {
+            CacheMode::Cold => {
+                cold_pre = preprocess()?;
+                evict_cpu_caches(eviction_buffer);
+                &cold_pre
+            }
+            _ => pre,
+        };
+        let start = Instant::now();
+        (candidates, _) = lb::run_method(method, pre, queries, &lb::RunConfig::default())?;
+        times.push(start.elapsed());
+    }
+    Ok((times, candidates))
+}
+
+/// Runs every method for every threshold. When `thresholds` is empty, the
+/// thresholds from the query file are used in a single run per method.
+pub fn run(
+    methods: &[LowerBoundMethods],
+    thresholds: &[usize],
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    label_dict: &LabelDict,
+    ground_truth: Option<&[(usize, usize, usize)]>,
+    options: BenchOptions,
+) -> Result<Vec<BenchRecord>, anyhow::Error> {
+    let preprocess = || lb::preprocess::Preprocessed::new(CollectionView::new(trees), label_dict);
+    let warm_pre = preprocess()?;
+    let mut eviction_buffer = if options.cache.modes().contains(&CacheMode::Cold) {
+        vec![0u64; EVICTION_BYTES / size_of::<u64>()]
+    } else {
+        vec![]
+    };
+    let sizes = queries.iter().map(|(_, q)| q.count()).collect::<Vec<_>>();
+    let buckets = if options.size_buckets > 0 {
+        size_quantiles(&sizes, options.size_buckets)
+    } else {
+        vec![]
+    };
+    let threshold_runs = if thresholds.is_empty() {
+        vec![None]
+    } else {
+        thresholds.iter().copied().map(Some).collect()
+    };
+
+    let mut records = vec![];
+    for threshold in threshold_runs {
+        let run_queries = match threshold {
+            Some(k) => queries.iter().map(|(_, q)| (k, q.clone())).collect(),
+            None => queries.to_vec(),
+        };
+        for (method, &cache) in itertools::iproduct!(methods, options.cache.modes()) {
+            if cache == CacheMode::Warm {
+                lb::run_method(*method, &warm_pre, &run_queries, &lb::RunConfig::default())?;
+            }
+            // all queries first, then every bucket with query ids of the whole run
+            let all = (0..run_queries.len()).collect::<Vec<_>>();
+            for (bucket, qids) in std::iter::once(&all).chain(&buckets).enumerate() {
+                let bucket_queries = qids
+                    .iter()
+                    .map(|qid| run_queries[*qid].clone())
+                    .collect::<Vec<_>>();
+                let (times, mut candidates) = measure(
+                    *method,
+                    cache,
+                    &warm_pre,
+                    preprocess,
+                    &mut eviction_buffer,
+                    &bucket_queries,
+                    options.runs,
+                )?;
+                for (qid, _) in candidates.iter_mut() {
+                    *qid = qids[*qid];
+                }
+                let mut in_bucket = vec![false; run_queries.len()];
+                qids.iter().for_each(|qid| in_bucket[*qid] = true);
+                let (precision, recall) = ground_truth
+                    .map(|gt| {
+                        let (precision, recall) = precision_recall(&candidates, gt, |qid| {
+                            run_queries
+                                .get(qid)
+                                .filter(|_| in_bucket[qid])
+                                .map(|(k, _)| *k)
+                        });
+                        (
+                            options.floats.round(precision),
+                            options.floats.round(recall),
+                        )
+                    })
+                    .unzip();
+                let bucket_sizes = qids.iter().map(|qid| sizes[*qid]);
+                let record = BenchRecord {
+                    method: format!("{method:?}"),
+                    threshold,
+                    cache,
+                    min_size: (bucket > 0).then(|| bucket_sizes.clone().min()).flatten(),
+                    max_size: (bucket > 0).then(|| bucket_sizes.max()).flatten(),
+                    queries: qids.len(),
+                    time_ms: times.iter().min().unwrap().as_millis(),
+                    max_time_ms: times.iter().max().unwrap().as_millis(),
+                    candidates: candidates.len(),
+                    precision,
+                    recall,
+                };
+                if !options.quiet {
+                    println!("{record:?}");
+                }
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Runs every kernel for every threshold on a workload of `pairs` pairs drawn from at
+/// most `pairs` sampled dataset trees
+pub fn run_kernels(
+    kernels: &[Kernel],
+    thresholds: &[usize],
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+    pairs: usize,
+    options: BenchOptions,
+) -> Result<Vec<KernelRecord>, anyhow::Error> {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(42);
+    let sampled = sample(&mut rng, trees.len(), pairs.min(trees.len()));
+    let lines = crate::export::export_trees(
+        &sampled.iter().map(|i| trees[i].clone()).collect::<Vec<_>>(),
+        label_dict,
+        crate::export::ExportFormat::Bracket,
+    );
+    let mut eviction_buffer = if options.cache.modes().contains(&CacheMode::Cold) {
+        vec![0u64; EVICTION_BYTES / size_of::<u64>()]
+    } else {
+        vec![]
+    };
+
+    let mut records = vec![];
+    for &threshold in thresholds {
+        let workload = Workload::new(lines.clone(), pairs, threshold, 42)?;
+        for (&kernel, &cache) in itertools::iproduct!(kernels, options.cache.modes()) {
+            if cache == CacheMode::Warm {
+                std::hint::black_box(workload.run(kernel));
+            }
+            let mut times = vec![];
+            for _ in 0..options.runs.max(1) {
+                if cache == CacheMode::Cold {
+                    evict_cpu_caches(&mut eviction_buffer);
+                }
+                let start = Instant::now();
+                std::hint::black_box(workload.run(kernel));
+                times.push(start.elapsed());
+            }
+            let fastest = *times.iter().min().unwrap();
+            let items = workload.items(kernel);
+            let record = KernelRecord {
+                kernel,
+                threshold,
+                cache,
+                items,
+                time_ms: fastest.as_millis(),
+                max_time_ms: times.iter().max().unwrap().as_millis(),
+                ns_per_item: options
+                    .floats
+                    .round(fastest.as_nanos() as f64 / items.max(1) as f64),
+            };
+            if !options.quiet {
+                println!("{record:?}");
+            }
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+pub fn write_report(
+    output: &impl AsRef<Path>,
+    records: &[impl Serialize],
+    format: ReportFormat,
+) -> Result<(), anyhow::Error> {
+    let w = BufWriter::new(File::create(output)?);
+    match format {
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(w);
+            for r in records {
+                writer.serialize(r)?;
+            }
+            writer.flush()?;
+        }
+        ReportFormat::Json => serde_json::to_writer_pretty(w, records)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precision_recall() {
+        let truth = vec![(0, 1, 1), (0, 2, 3), (1, 3, 0)];
+        let candidates = vec![(0, 1), (0, 4), (1, 3)];
+        let (precision, recall) = precision_recall(&candidates, &truth, |_| Some(2));
+        assert_eq!(precision, 2.0 / 3.0);
+        assert_eq!(recall, 1.0);
+
+        // query 3 is not within 0 of tree 1
+        let flipped = vec![(0, 1), (3, 1)];
+        let (precision, recall) = precision_recall(&flipped, &truth, |_| Some(2));
+        assert_eq!(precision, 0.5);
+        assert_eq!(recall, 0.5);
+
+        let (_, recall) = precision_recall(&candidates, &truth, |_| Some(3));
+        assert_eq!(recall, 2.0 / 3.0);
+
+        // repeated candidates count once
+        let repeated = vec![(0, 1), (0, 1), (1, 3)];
+        assert_eq!(super::recall(&repeated, &truth, |_| Some(3)), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_size_buckets() {
+        assert_eq!(
+            size_quantiles(&[5, 1, 9, 3, 7, 2], 3),
+            [vec![1, 5], vec![0, 3], vec![2, 4]]
+        );
+        // a frequent size fills its bucket and the next bucket is left out
+        assert_eq!(
+            size_quantiles(&[2, 2, 2, 2, 8], 4),
+            [vec![0, 1, 2, 3], vec![4]]
+        );
+        assert!(size_quantiles(&[], 4).is_empty());
+
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{a{b}{c}{d}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries =
+            ["{a{b}{c}{d}}", "{a}", "{a{b}}"].map(|t| (1, parse_single(t.to_owned(), &mut ld)));
+        let truth = [
+            (0, 2, 1),
+            (0, 3, 0),
+            (1, 0, 0),
+            (1, 1, 1),
+            (2, 0, 1),
+            (2, 1, 0),
+            (2, 2, 1),
+        ];
+        let options = BenchOptions {
+            cache: CacheMode::Warm,
+            runs: 1,
+            quiet: true,
+            floats: FloatFormat::default(),
+            size_buckets: 2,
+        };
+        let methods = [LowerBoundMethods::Lblint];
+        let records = run(&methods, &[], &trees, &queries, &ld, Some(&truth), options).unwrap();
+        let buckets = records
+            .iter()
+            .map(|r| (r.min_size, r.max_size, r.queries))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            [
+                (None, None, 3),
+                (Some(1), Some(2), 2),
+                (Some(4), Some(4), 1)
+            ]
+        );
+        // the buckets split the candidates of all queries
+        assert_eq!(
+            records[0].candidates,
+            records[1].candidates + records[2].candidates
+        );
+        assert!(records.iter().all(|r| r.recall == Some(1.0)));
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = [(1, parse_single("{a{c}}".to_owned(), &mut ld))];
+        let options = BenchOptions {
+            cache: CacheMode::Both,
+            runs: 2,
+            quiet: true,
+            floats: FloatFormat::default(),
+            size_buckets: 0,
+        };
+        let methods = [LowerBoundMethods::Lblint, LowerBoundMethods::Sed];
+        let records = run(&methods, &[], &trees, &queries, &ld, None, options).unwrap();
+        let caches = records.iter().map(|r| r.cache).collect::<Vec<_>>();
+        assert_eq!(
+            caches,
+            [
+                CacheMode::Cold,
+                CacheMode::Warm,
+                CacheMode::Cold,
+                CacheMode::Warm
+            ]
+        );
+        for pair in records.chunks(2) {
+            // fresh indexes find the same candidates as reused ones
+            assert_eq!(pair[0].candidates, pair[1].candidates);
+            assert!(pair.iter().all(|r| r.time_ms <= r.max_time_ms));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Thresholds expected to return a target number of results per query.
+//!
+//! The tree edit distances of every query to a uniform sample of dataset trees are
+//! computed exactly, the share of sampled trees within a threshold is extrapolated to the
+//! whole dataset. The suggested threshold of a query is the smallest one whose estimate
+//! reaches the target, the global threshold is the smallest one reaching the target on
+//! average over all queries. A sample of the whole dataset gives exact thresholds. Small
+//! targets need large samples, a target of 10 results of 100000 trees is expected in a
+//! sample only once it holds thousands of trees.
+use crate::parsing::ParsedTree;
+use crate::ted::ted;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuggestedThreshold {
+    pub threshold: usize,
+    /// Estimated number of dataset trees within the threshold
+    pub estimated_results: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// Dataset trees sampled for every query
+    pub sampled: usize,
+    pub queries: Vec<SuggestedThreshold>,
+    pub global: SuggestedThreshold,
+}
+
+/// Smallest of the sorted sampled `distances` with an extrapolated number of results of
+/// at least `target` on average, each distance stands for `trees / distances.len()`
+/// results. The largest distance if the target is not reached.
+fn suggest(distances: &[usize], target: usize, trees: usize) -> SuggestedThreshold {
+    let Some(&largest) = distances.last() else {
+        return SuggestedThreshold {
+            threshold: 0,
+            estimated_results: 0.0,
+        };
+    };
+    // fewest sampled distances whose extrapolation reaches the target
+    let needed = (target * distances.len()).div_ceil(trees).max(1);
+    let threshold = distances.get(needed - 1).copied().unwrap_or(largest);
+    let within = distances.partition_point(|d| *d <= threshold);
+    SuggestedThreshold {
+        threshold,
+        estimated_results: (within * trees) as f64 / distances.len() as f64,
+    }
+}
+
+/// Suggests thresholds for `target` results of every query from the distances to
+/// `sample_size` random dataset trees per query
+pub fn calibrate(
+    trees: &[ParsedTree],
+    queries: &[ParsedTree],
+    target: usize,
+    sample_size: usize,
+    seed: u64,
+) -> Calibration {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let sampled = sample_size.min(trees.len());
+    let samples = queries
+        .iter()
+        .map(|_| sample(&mut rng, trees.len(), sampled).into_vec())
+        .collect::<Vec<_>>();
+    let distances = queries
+        .par_iter()
+        .zip(samples)
+        .map(|(query, tids)| {
+            let mut distances = tids
+                .into_iter()
+                .map(|tid| ted(query, &trees[tid]))
+                .collect::<Vec<_>>();
+            distances.sort_unstable();
+            distances
+        })
+        .collect::<Vec<_>>();
+
+    let suggested = distances
+        .iter()
+        .map(|d| suggest(d, target, trees.len()))
+        .collect();
+    // the average over queries weighs the distances of every query the same
+    let mut pooled = distances.concat();
+    pooled.sort_unstable();
+    let global = suggest(&pooled, target, trees.len());
+    Calibration {
+        sampled,
+        queries: suggested,
+        global,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_calibrate() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = ["{a}", "{x{y}{z}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+
+        // a sample of all trees gives exact thresholds, distances of {a} are 0, 1, 2, 4
+        // and of {x{y}{z}} are 3, 3, 3, 1
+        let calibration = calibrate(&trees, &queries, 2, 10, 7);
+        assert_eq!(calibration.sampled, 4);
+        let thresholds = calibration.queries.iter().map(|s| s.threshold);
+        assert_eq!(thresholds.collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(calibration.queries[1].estimated_results, 4.0);
+        // 4 of the 8 distances are at most 2
+        assert_eq!(calibration.global.threshold, 2);
+        assert_eq!(calibration.global.estimated_results, 2.0);
+
+        // more results than trees get the largest distance
+        let calibration = calibrate(&trees, &queries, 5, 10, 7);
+        assert_eq!(calibration.queries[0].threshold, 4);
+        assert_eq!(calibration.queries[0].estimated_results, 4.0);
+
+        let sampled = calibrate(&trees, &queries, 1, 2, 7);
+        assert_eq!(sampled.sampled, 2);
+        assert_eq!(sampled, calibrate(&trees, &queries, 1, 2, 7));
+    }
+}
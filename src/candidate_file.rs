@@ -0,0 +1,802 @@
+//! Compact binary format for candidate pairs.
+//!
+//! Layout: magic `TSCB`, format version (u8), flags (u8), pair count (u64) and
+//! pairs of little endian u32 tree ids. With [`FLAG_DELTA`] each pair stores the
+//! difference to the previous pair, the second id only when the first id did not change.
+//! With [`FLAG_ZSTD`] everything after the header is a single zstd frame.
+//! [`PairOrder`] is recorded in the flags of binary files and as a header line of CSV and
+//! TSV files, files without it hold `query_id,tree_id` pairs.
+//!
+//! Both formats are encoded in parallel chunks which are written in order, so
+//! writing hundreds of millions of pairs is not bound by a single formatting thread.
+//! Grouped and JSON lines files hold one line per query with all its candidates, for
+//! retrieval evaluation scripts, they are written but not read back.
+//!
+//! [`CandidateWriter`] writes a file from consecutive slices of candidates, so sorted
+//! candidates merged from runs on disk by [`crate::external_sort`] are written without
+//! holding all of them. The pair count of binary files is filled in when it finishes.
+use clap::ValueEnum;
+use itertools::Itertools;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"TSCB";
+const VERSION: u8 = 1;
+/// Offset of the pair count in the header
+const COUNT_OFFSET: u64 = 6;
+pub const FLAG_DELTA: u8 = 0b01;
+pub const FLAG_ZSTD: u8 = 0b10;
+const FLAG_TREE_QUERY: u8 = 0b100;
+const FLAG_UNORDERED: u8 = 0b1000;
+/// Pairs encoded by one task of the parallel writer
+const WRITE_CHUNK_PAIRS: usize = 1 << 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum CandidateFormat {
+    /// One `t1,t2` pair per line
+    Csv,
+    /// One `t1<TAB>t2` pair per line
+    Tsv,
+    /// Binary u32 pairs
+    Binary,
+    /// Parquet columns `query_id` and `tree_id`, requires the `arrow` feature
+    Parquet,
+    /// One `qid: t1,t5,t9` line per query with candidates
+    Grouped,
+    /// One `{"query_id":0,"candidates":[1,5,9]}` line per query with candidates
+    JsonLines,
+}
+
+impl CandidateFormat {
+    /// One line per query instead of one per pair
+    pub fn is_grouped(&self) -> bool {
+        matches!(self, CandidateFormat::Grouped | CandidateFormat::JsonLines)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CandidateFormat::Csv => "csv",
+            CandidateFormat::Tsv => "tsv",
+            CandidateFormat::Binary => "bin",
+            CandidateFormat::Parquet => "parquet",
+            CandidateFormat::Grouped => "txt",
+            CandidateFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// Meaning of the two ids of candidate pairs
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum PairOrder {
+    /// `query_id,tree_id`, the order of files without a recorded order
+    #[default]
+    QueryTree,
+    /// `tree_id,query_id`
+    TreeQuery,
+    /// Tree ids of a self join, the smaller id first and every pair once
+    Unordered,
+}
+
+impl PairOrder {
+    /// Header line of delimited files, none in the default order so they stay plain pairs
+    fn header(self) -> Option<[&'static str; 2]> {
+        match self {
+            Self::QueryTree => None,
+            Self::TreeQuery => Some(["tree_id", "query_id"]),
+            Self::Unordered => Some(["t1", "t2"]),
+        }
+    }
+
+    fn flags(self) -> u8 {
+        match self {
+            Self::QueryTree => 0,
+            Self::TreeQuery => FLAG_TREE_QUERY,
+            Self::Unordered => FLAG_UNORDERED,
+        }
+    }
+
+    fn from_flags(flags: u8) -> Self {
+        if flags & FLAG_UNORDERED != 0 {
+            Self::Unordered
+        } else if flags & FLAG_TREE_QUERY != 0 {
+            Self::TreeQuery
+        } else {
+            Self::QueryTree
+        }
+    }
+
+    /// Order recorded in a binary or delimited candidates file
+    pub fn of_file(path: &impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        if is_binary(path)? {
+            let mut header = [0u8; 6];
+            File::open(path)?.read_exact(&mut header)?;
+            return Ok(Self::from_flags(header[5]));
+        }
+        let mut first_line = String::new();
+        std::io::BufRead::read_line(&mut BufReader::new(File::open(path)?), &mut first_line)?;
+        let ids = first_line
+            .trim_end()
+            .split([',', '\t'])
+            .take(2)
+            .collect_vec();
+        Ok([Self::TreeQuery, Self::Unordered]
+            .into_iter()
+            .find(|order| order.header().is_some_and(|header| header == ids[..]))
+            .unwrap_or_default())
+    }
+
+    /// Sorted `(query_id, tree_id)` candidates in this order, candidates in the default order
+    /// are borrowed as they are. Unordered pairs map queries to their trees with
+    /// `query_trees`, pairs found from both trees are kept once.
+    pub fn arrange<'a>(
+        self,
+        candidates: &'a [(usize, usize)],
+        query_trees: &[usize],
+    ) -> Cow<'a, [(usize, usize)]> {
+        let mut arranged: Vec<_> = match self {
+            Self::QueryTree => return Cow::Borrowed(candidates),
+            Self::TreeQuery => candidates
+                .par_iter()
+                .map(|(qid, tid)| (*tid, *qid))
+                .collect(),
+            Self::Unordered => candidates
+                .par_iter()
+                .map(|(qid, tid)| {
+                    let query_tree = query_trees[*qid];
+                    (query_tree.min(*tid), query_tree.max(*tid))
+                })
+                .collect(),
+        };
+        arranged.par_sort_unstable();
+        if self == Self::Unordered {
+            arranged.dedup();
+        }
+        Cow::Owned(arranged)
+    }
+}
+
+impl std::fmt::Display for PairOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("no variant is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BinaryOptions {
+    /// Delta encode pairs, works best on sorted candidates
+    pub delta: bool,
+    /// Compress the pairs with zstd, requires the `zstd` feature
+    pub compress: bool,
+    /// Order of the pair ids, unlike the other options recorded in delimited files as well
+    pub order: PairOrder,
+}
+
+/// Checks whether the file starts with the binary candidate magic bytes
+pub fn is_binary(path: &impl AsRef<Path>) -> Result<bool, std::io::Error> {
+    let mut magic = [0u8; 4];
+    let mut f = File::open(path)?;
+    match f.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Encodes candidates in chunks of [`WRITE_CHUNK_PAIRS`] in parallel and writes the
+/// chunks in order. `encode` gets a chunk, the pair preceding it and the output buffer,
+/// `before` is the pair written before the candidates. Only a window of a few chunks
+/// per thread is held in memory at a time.
+fn write_chunked<E>(
+    w: &mut impl Write,
+    candidates: &[(usize, usize)],
+    before: Option<(usize, usize)>,
+    encode: E,
+) -> Result<(), anyhow::Error>
+where
+    E: Fn(&[(usize, usize)], Option<(usize, usize)>, &mut Vec<u8>) -> Result<(), anyhow::Error>
+        + Sync,
+{
+    let window = WRITE_CHUNK_PAIRS * rayon::current_num_threads() * 2;
+    let mut buffers: Vec<Vec<u8>> = vec![];
+    for (window_idx, window_pairs) in candidates.chunks(window).enumerate() {
+        let window_start = window_idx * window;
+        buffers.resize_with(window_pairs.len().div_ceil(WRITE_CHUNK_PAIRS), Vec::new);
+        window_pairs
+            .par_chunks(WRITE_CHUNK_PAIRS)
+            .zip(buffers.par_iter_mut())
+            .enumerate()
+            .try_for_each(|(chunk_idx, (chunk, buf))| {
+                buf.clear();
+                let start = window_start + chunk_idx * WRITE_CHUNK_PAIRS;
+                let prev = start.checked_sub(1).map_or(before, |p| Some(candidates[p]));
+                encode(chunk, prev, buf)
+            })?;
+        for buf in &buffers {
+            w.write_all(buf)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_pairs(
+    candidates: &[(usize, usize)],
+    prev: Option<(usize, usize)>,
+    delta: bool,
+    buf: &mut Vec<u8>,
+) -> Result<(), anyhow::Error> {
+    let (mut prev1, mut prev2) = match prev {
+        Some((p1, p2)) => (p1 as u32, p2 as u32),
+        None => (0, 0),
+    };
+    buf.reserve(candidates.len() * 8);
+    for &(c1, c2) in candidates {
+        let (t1, t2): (u32, u32) = (c1.try_into()?, c2.try_into()?);
+        let (v1, v2) = if delta {
+            let d1 = t1.wrapping_sub(prev1);
+            let d2 = if d1 == 0 { t2.wrapping_sub(prev2) } else { t2 };
+            (d1, d2)
+        } else {
+            (t1, t2)
+        };
+        buf.extend_from_slice(&v1.to_le_bytes());
+        buf.extend_from_slice(&v2.to_le_bytes());
+        (prev1, prev2) = (t1, t2);
+    }
+    Ok(())
+}
+
+fn format_delimited_pairs(candidates: &[(usize, usize)], delimiter: u8, buf: &mut Vec<u8>) {
+    let mut itoa_buf = itoa::Buffer::new();
+    for &(c1, c2) in candidates {
+        buf.extend_from_slice(itoa_buf.format(c1).as_bytes());
+        buf.push(delimiter);
+        buf.extend_from_slice(itoa_buf.format(c2).as_bytes());
+        buf.push(b'\n');
+    }
+}
+
+fn decode_pairs(
+    count: usize,
+    delta: bool,
+    r: &mut impl Read,
+    mut f: impl FnMut((usize, usize)) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let (mut prev1, mut prev2) = (0u32, 0u32);
+    let mut buf = [0u8; 8];
+    for _ in 0..count {
+        r.read_exact(&mut buf)?;
+        let v1 = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        let v2 = u32::from_le_bytes(buf[4..].try_into().unwrap());
+        let (t1, t2) = if delta {
+            let t1 = prev1.wrapping_add(v1);
+            let t2 = if v1 == 0 { prev2.wrapping_add(v2) } else { v2 };
+            (t1, t2)
+        } else {
+            (v1, v2)
+        };
+        f((t1 as usize, t2 as usize))?;
+        (prev1, prev2) = (t1, t2);
+    }
+    Ok(())
+}
+
+/// Calls `f` with every pair of a binary file in order, without collecting them
+fn visit_binary(
+    path: &impl AsRef<Path>,
+    f: impl FnMut((usize, usize)) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 14];
+    r.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        anyhow::bail!("Not a binary candidates file");
+    }
+    if header[4] != VERSION {
+        anyhow::bail!("Unsupported binary candidates version {}", header[4]);
+    }
+    let flags = header[5];
+    let count = u64::from_le_bytes(header[6..].try_into().unwrap()) as usize;
+    let delta = flags & FLAG_DELTA != 0;
+
+    if flags & FLAG_ZSTD != 0 {
+        #[cfg(feature = "zstd")]
+        return decode_pairs(count, delta, &mut zstd::Decoder::with_buffer(r)?, f);
+        #[cfg(not(feature = "zstd"))]
+        anyhow::bail!("File is zstd compressed, build with the `zstd` feature to read it");
+    }
+    decode_pairs(count, delta, &mut r, f)
+}
+
+/// Calls `f` with every pair of a binary or delimited candidates file in order
+pub fn visit_pairs(
+    path: &impl AsRef<Path>,
+    f: impl FnMut((usize, usize)) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    if is_binary(path)? {
+        visit_binary(path, f)
+    } else {
+        // candidate files are written without a header
+        crate::pairs::visit_pairs(path, f)
+    }
+}
+
+pub fn write_csv(
+    path: impl AsRef<Path>,
+    candidates: &[(usize, usize)],
+) -> Result<(), anyhow::Error> {
+    write_candidates(
+        path,
+        candidates,
+        CandidateFormat::Csv,
+        BinaryOptions::default(),
+    )
+}
+
+fn format_group(group: &[(usize, usize)], json: bool, buf: &mut Vec<u8>) {
+    let mut itoa_buf = itoa::Buffer::new();
+    let query_id = itoa_buf.format(group[0].0);
+    if json {
+        buf.extend_from_slice(b"{\"query_id\":");
+        buf.extend_from_slice(query_id.as_bytes());
+        buf.extend_from_slice(b",\"candidates\":[");
+    } else {
+        buf.extend_from_slice(query_id.as_bytes());
+        buf.extend_from_slice(b": ");
+    }
+    for (i, (_, tid)) in group.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        buf.extend_from_slice(itoa_buf.format(*tid).as_bytes());
+    }
+    if json {
+        buf.extend_from_slice(b"]}");
+    }
+    buf.push(b'\n');
+}
+
+/// Writes one line per group of candidates of a query. Groups are formatted in parallel
+/// windows written in order.
+fn write_groups(
+    w: &mut impl Write,
+    candidates: &[(usize, usize)],
+    json: bool,
+) -> Result<(), anyhow::Error> {
+    let groups = candidates.chunk_by(|a, b| a.0 == b.0).collect::<Vec<_>>();
+    let window = WRITE_CHUNK_PAIRS * rayon::current_num_threads();
+    for window_groups in groups.chunks(window) {
+        let buffers = window_groups
+            .par_chunks(WRITE_CHUNK_PAIRS / 16)
+            .map(|groups| {
+                let mut buf = vec![];
+                for group in groups {
+                    format_group(group, json, &mut buf);
+                }
+                buf
+            })
+            .collect::<Vec<_>>();
+        for buf in &buffers {
+            w.write_all(buf)?;
+        }
+    }
+    Ok(())
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Writes a candidates file from consecutive slices of candidates. Grouped formats keep
+/// the candidates of the last query until the next slice or [`CandidateWriter::finish`],
+/// so a query split between slices is still one line. Parquet is only written whole by
+/// [`write_candidates`].
+pub struct CandidateWriter {
+    sink: Sink,
+    format: CandidateFormat,
+    delta: bool,
+    last: Option<(usize, usize)>,
+    open_group: Vec<(usize, usize)>,
+    written: u64,
+}
+
+impl CandidateWriter {
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: CandidateFormat,
+        options: BinaryOptions,
+    ) -> Result<Self, anyhow::Error> {
+        if format == CandidateFormat::Parquet {
+            anyhow::bail!("Parquet candidates are written at once, not in slices");
+        }
+        check_order(format, options.order)?;
+        let mut w = BufWriter::new(File::create(path)?);
+        if format != CandidateFormat::Binary {
+            if let (Some([first, second]), false) = (options.order.header(), format.is_grouped()) {
+                let delimiter = if format == CandidateFormat::Tsv {
+                    '\t'
+                } else {
+                    ','
+                };
+                writeln!(w, "{first}{delimiter}{second}")?;
+            }
+            return Ok(Self::new(Sink::Plain(w), format, options));
+        }
+        let mut flags = options.order.flags();
+        if options.delta {
+            flags |= FLAG_DELTA;
+        }
+        if options.compress {
+            flags |= FLAG_ZSTD;
+        }
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION, flags])?;
+        // the count is filled in by finish
+        w.write_all(&0u64.to_le_bytes())?;
+        if options.compress {
+            #[cfg(feature = "zstd")]
+            return Ok(Self::new(
+                Sink::Zstd(zstd::Encoder::new(w, 0)?),
+                format,
+                options,
+            ));
+            #[cfg(not(feature = "zstd"))]
+            anyhow::bail!("Compression requires building with the `zstd` feature");
+        }
+        Ok(Self::new(Sink::Plain(w), format, options))
+    }
+
+    fn new(sink: Sink, format: CandidateFormat, options: BinaryOptions) -> Self {
+        Self {
+            sink,
+            format,
+            delta: options.delta,
+            last: None,
+            open_group: vec![],
+            written: 0,
+        }
+    }
+    pub fn write(&mut self, candidates: &[(usize, usize)]) -> Result<(), anyhow::Error> {
+        match self.format {
+            CandidateFormat::Csv | CandidateFormat::Tsv => {
+                let delimiter = if self.format == CandidateFormat::Csv {
+                    b','
+                } else {
+                    b'\t'
+                };
+                write_chunked(&mut self.sink, candidates, None, |chunk, _, buf| {
+                    format_delimited_pairs(chunk, delimiter, buf);
+                    Ok(())
+                })?;
+            }
+            CandidateFormat::Binary => {
+                let delta = self.delta;
+                write_chunked(&mut self.sink, candidates, self.last, |chunk, prev, buf| {
+                    encode_pairs(chunk, prev, delta, buf)
+                })?;
+            }
+            CandidateFormat::Grouped | CandidateFormat::JsonLines => {
+                self.open_group.extend_from_slice(candidates);
+                let Some(&(last_query, _)) = self.open_group.last() else {
+                    return Ok(());
+                };
+                let open = self
+                    .open_group
+                    .iter()
+                    .rposition(|(qid, _)| *qid != last_query)
+                    .map_or(0, |p| p + 1);
+                let open_group = self.open_group.split_off(open);
+                let complete = std::mem::replace(&mut self.open_group, open_group);
+                let json = self.format == CandidateFormat::JsonLines;
+                write_groups(&mut self.sink, &complete, json)?;
+            }
+            CandidateFormat::Parquet => unreachable!("rejected by create"),
+        }
+        self.last = candidates.last().copied().or(self.last);
+        self.written += candidates.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes the file and returns the number of candidates written
+    #[cfg_attr(not(feature = "zstd"), allow(clippy::infallible_destructuring_match))]
+    pub fn finish(mut self) -> Result<u64, anyhow::Error> {
+        let json = self.format == CandidateFormat::JsonLines;
+        write_groups(&mut self.sink, &self.open_group, json)?;
+        let w = match self.sink {
+            Sink::Plain(w) => w,
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+        let mut file = w.into_inner().map_err(|e| e.into_error())?;
+        if self.format == CandidateFormat::Binary {
+            file.seek(SeekFrom::Start(COUNT_OFFSET))?;
+            file.write_all(&self.written.to_le_bytes())?;
+        }
+        Ok(self.written)
+    }
+}
+
+/// Files of grouped formats and Parquet columns are named by query, they hold only
+/// `query_id,tree_id` pairs
+fn check_order(format: CandidateFormat, order: PairOrder) -> Result<(), anyhow::Error> {
+    if order != PairOrder::QueryTree && (format.is_grouped() || format == CandidateFormat::Parquet)
+    {
+        anyhow::bail!("{format:?} candidates hold only query-tree pairs, not {order}");
+    }
+    Ok(())
+}
+
+/// Writes candidates in the chosen format, `options` apart from the pair order only
+/// apply to the binary format.
+/// Grouped formats sort unsorted candidates first.
+pub fn write_candidates(
+    path: impl AsRef<Path>,
+    candidates: &[(usize, usize)],
+    format: CandidateFormat,
+    options: BinaryOptions,
+) -> Result<(), anyhow::Error> {
+    let sorted;
+    check_order(format, options.order)?;
+    let candidates = match format {
+        CandidateFormat::Parquet => {
+            #[cfg(feature = "arrow")]
+            return crate::columnar::write_candidates(path, candidates);
+            #[cfg(not(feature = "arrow"))]
+            anyhow::bail!("Parquet output requires building with the `arrow` feature");
+        }
+        CandidateFormat::Grouped | CandidateFormat::JsonLines if !candidates.is_sorted() => {
+            sorted = {
+                let mut sorted = candidates.to_vec();
+                sorted.par_sort_unstable();
+                sorted
+            };
+            &sorted
+        }
+        _ => candidates,
+    };
+    let mut writer = CandidateWriter::create(path, format, options)?;
+    writer.write(candidates)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes candidates of an iterator in slices of a few chunks per thread, returns the
+/// number written. Grouped formats expect the candidates of a query to be consecutive.
+pub fn write_streamed(
+    path: impl AsRef<Path>,
+    candidates: impl Iterator<Item = Result<(usize, usize), std::io::Error>>,
+    format: CandidateFormat,
+    options: BinaryOptions,
+) -> Result<u64, anyhow::Error> {
+    if format == CandidateFormat::Parquet {
+        let candidates = candidates.collect::<Result<Vec<_>, _>>()?;
+        write_candidates(path, &candidates, format, options)?;
+        return Ok(candidates.len() as u64);
+    }
+    let mut writer = CandidateWriter::create(path, format, options)?;
+    let slice = WRITE_CHUNK_PAIRS * rayon::current_num_threads() * 2;
+    for chunk in &candidates.chunks(slice) {
+        writer.write(&chunk.collect::<Result<Vec<_>, _>>()?)?;
+    }
+    writer.finish()
+}
+
+/// Name of a candidates file from a template with `{method}`, `{k}` and `{dataset}`
+/// placeholders, `k` is `mixed` for queries with different thresholds. The extension of
+/// the format is appended.
+pub fn file_name(
+    template: &str,
+    method: &str,
+    k: Option<usize>,
+    dataset: &str,
+    format: CandidateFormat,
+) -> Result<String, anyhow::Error> {
+    let k = k.map_or_else(|| "mixed".to_owned(), |k| k.to_string());
+    let name = template
+        .replace("{method}", method)
+        .replace("{k}", &k)
+        .replace("{dataset}", dataset);
+    if name.contains(['{', '}', '/']) {
+        anyhow::bail!("Unknown placeholder or path separator in file name template {template}");
+    }
+    Ok(format!("{name}.{}", format.extension()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_pairs(path: &Path) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        visit_pairs(&path, |pair| {
+            pairs.push(pair);
+            Ok(())
+        })
+        .unwrap();
+        pairs
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let candidates = vec![(0, 3), (0, 7), (2, 1), (2, 9), (5, 5), (4, 0)];
+        let dir = std::env::temp_dir();
+        for delta in [false, true] {
+            let path = dir.join(format!("tree-statistics-candidates-{delta}.bin"));
+            let options = BinaryOptions {
+                delta,
+                compress: false,
+                ..Default::default()
+            };
+            write_candidates(&path, &candidates, CandidateFormat::Binary, options).unwrap();
+            assert!(is_binary(&path).unwrap());
+            assert_eq!(read_pairs(&path), candidates);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_chunked_writes_match_sequential() {
+        // spans several parallel chunks, delta encoding continues across chunk borders
+        let candidates = (0..3 * WRITE_CHUNK_PAIRS + 17)
+            .map(|i| (i / 1000, i % 1000 * 7))
+            .collect::<Vec<_>>();
+        let dir = std::env::temp_dir();
+        let path = dir.join("tree-statistics-candidates-chunked.bin");
+        let options = BinaryOptions {
+            delta: true,
+            compress: false,
+            ..Default::default()
+        };
+        write_candidates(&path, &candidates, CandidateFormat::Binary, options).unwrap();
+        assert_eq!(read_pairs(&path), candidates);
+
+        let path = dir.join("tree-statistics-candidates-chunked.csv");
+        write_csv(&path, &candidates).unwrap();
+        let expected: String = candidates
+            .iter()
+            .map(|(c1, c2)| format!("{c1},{c2}\n"))
+            .collect();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_writer_slices() {
+        let candidates = [(0, 3), (0, 7), (2, 1), (2, 9), (2, 11), (5, 5)];
+        let dir = std::env::temp_dir();
+        let path = dir.join("tree-statistics-candidates-slices.bin");
+        let options = BinaryOptions {
+            delta: true,
+            compress: false,
+            ..Default::default()
+        };
+        let mut writer = CandidateWriter::create(&path, CandidateFormat::Binary, options).unwrap();
+        for slice in candidates.chunks(4) {
+            writer.write(slice).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 6);
+        assert_eq!(read_pairs(&path), candidates);
+
+        // the candidates of query 2 are split between slices but stay on one line
+        let path = dir.join("tree-statistics-candidates-slices.txt");
+        let mut writer = CandidateWriter::create(&path, CandidateFormat::Grouped, options).unwrap();
+        for slice in candidates.chunks(3) {
+            writer.write(slice).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "0: 3,7\n2: 1,9,11\n5: 5\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_name() {
+        let name = |template, k| file_name(template, "Sed", k, "dblp", CandidateFormat::Tsv);
+        assert_eq!(
+            name("{method}_candidates", Some(2)).unwrap(),
+            "Sed_candidates.tsv"
+        );
+        assert_eq!(
+            name("{dataset}-{method}-k{k}", None).unwrap(),
+            "dblp-Sed-kmixed.tsv"
+        );
+        assert!(name("{method}-{threshold}", Some(2)).is_err());
+        assert!(name("../{method}", Some(2)).is_err());
+
+        let path = std::env::temp_dir().join("tree-statistics-candidates.tsv");
+        let options = BinaryOptions::default();
+        write_candidates(&path, &[(0, 3), (1, 2)], CandidateFormat::Tsv, options).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0\t3\n1\t2\n");
+        assert_eq!(crate::pairs::read_pairs(&path).unwrap(), [(0, 3), (1, 2)]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pair_order() {
+        let candidates = [(0, 4), (1, 1), (1, 3), (2, 0)];
+        // queries 0, 1 and 2 are trees 3, 0 and 1, trees 0 and 1 find each other
+        let query_trees = [3, 0, 1];
+        let arranged = PairOrder::Unordered.arrange(&candidates, &query_trees);
+        assert_eq!(arranged[..], [(0, 1), (0, 3), (3, 4)]);
+        let arranged = PairOrder::TreeQuery.arrange(&candidates, &query_trees);
+        assert_eq!(arranged[..], [(0, 2), (1, 1), (3, 1), (4, 0)]);
+        assert!(matches!(
+            PairOrder::QueryTree.arrange(&candidates, &query_trees),
+            Cow::Borrowed(_)
+        ));
+
+        let dir = std::env::temp_dir();
+        for format in [
+            CandidateFormat::Csv,
+            CandidateFormat::Tsv,
+            CandidateFormat::Binary,
+        ] {
+            let path = dir.join(format!("tree-statistics-order.{}", format.extension()));
+            for order in PairOrder::value_variants() {
+                let options = BinaryOptions {
+                    order: *order,
+                    ..Default::default()
+                };
+                write_candidates(&path, &arranged, format, options).unwrap();
+                assert_eq!(PairOrder::of_file(&path).unwrap(), *order, "{format:?}");
+                assert_eq!(read_pairs(&path), arranged[..]);
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+        let path = dir.join("tree-statistics-order.txt");
+        let options = BinaryOptions {
+            order: PairOrder::TreeQuery,
+            ..Default::default()
+        };
+        assert!(write_candidates(&path, &arranged, CandidateFormat::Grouped, options).is_err());
+    }
+
+    #[test]
+    fn test_grouped_candidates() {
+        let candidates = [(2, 4), (0, 3), (0, 1), (2, 9), (5, 0)];
+        let path = std::env::temp_dir().join("tree-statistics-candidates-grouped");
+        let options = BinaryOptions::default();
+        write_candidates(&path, &candidates, CandidateFormat::Grouped, options).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "0: 1,3\n2: 4,9\n5: 0\n"
+        );
+        write_candidates(&path, &candidates, CandidateFormat::JsonLines, options).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let groups = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[1]["query_id"], 2);
+        assert_eq!(groups[1]["candidates"], serde_json::json!([4, 9]));
+        write_candidates(&path, &[], CandidateFormat::JsonLines, options).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        std::fs::remove_file(path).unwrap();
+    }
+}
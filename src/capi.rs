@@ -0,0 +1,309 @@
+//! C API of the lower bound filters, built into a shared and a static library with
+//! the `capi` feature. The header is generated by build.rs, `include/tree_statistics.h`
+//! is its copy refreshed with `cbindgen --config cbindgen.toml --output
+//! include/tree_statistics.h src/capi.rs`.
+//!
+//! A collection owns the parsed trees, an index owns per tree indexes of a single
+//! lower bound method together with the label dictionary used to parse queries.
+//! Candidates are ids of trees in the order they were parsed from the dataset.
+//! Handles must not be used from multiple threads at once. Functions report
+//! failures through their return value and [`ts_last_error`], panics included, so
+//! no panic unwinds into the C caller.
+use crate::parsing::{self, LabelDict, ParsedTree, TreeParseError};
+use crate::tree_index::{Method, TreeIndex};
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Label intersection lower bound
+pub const TS_METHOD_LBLINT: u32 = 0;
+/// String edit distance lower bound
+pub const TS_METHOD_SED: u32 = 1;
+/// Structural filter lower bound
+pub const TS_METHOD_STRUCTURAL: u32 = 2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: impl Display) {
+    let msg = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Parsed trees sorted by size
+pub struct TsCollection {
+    trees: Vec<ParsedTree>,
+    /// Dataset position of each sorted tree
+    tree_ids: Vec<usize>,
+    label_dict: LabelDict,
+}
+
+/// Per tree indexes of one lower bound method
+pub struct TsIndex {
+    trees: TreeIndex,
+    tree_ids: Vec<usize>,
+    /// Copy of the collection dictionary, labels of queries are added into it
+    label_dict: LabelDict,
+}
+
+/// Candidate tree ids owned by the library, free with [`ts_candidates_free`]
+#[repr(C)]
+pub struct TsCandidates {
+    pub ids: *mut usize,
+    pub len: usize,
+}
+
+fn parse_query(tree: &str, ld: &mut LabelDict) -> Result<ParsedTree, TreeParseError> {
+    if !tree.is_ascii() {
+        return Err(TreeParseError::IsNotAscii);
+    }
+    let tokens = parsing::parse_tree_tokens(tree.to_owned())?;
+    parsing::update_label_dict(std::slice::from_ref(&tokens), ld);
+    parsing::parse_tree(&tokens, ld)
+}
+
+impl TsIndex {
+    fn new(collection: &TsCollection, method: u32) -> Result<Self, String> {
+        let method = match method {
+            TS_METHOD_LBLINT => Method::Lblint,
+            TS_METHOD_SED => Method::Sed,
+            TS_METHOD_STRUCTURAL => Method::Structural,
+            _ => return Err(format!("Unknown lower bound method {method}")),
+        };
+        Ok(Self {
+            trees: TreeIndex::new(&collection.trees, &collection.label_dict, method)
+                .map_err(|e| e.to_string())?,
+            tree_ids: collection.tree_ids.clone(),
+            label_dict: collection.label_dict.clone(),
+        })
+    }
+
+    /// Dataset ids of trees whose lower bound to the query is at most `k`, sorted
+    fn query(&mut self, tree: &str, k: usize) -> Result<Vec<usize>, String> {
+        let query = parse_query(tree, &mut self.label_dict).map_err(|e| e.to_string())?;
+        let mut candidates = self
+            .trees
+            .candidates(&query, &self.label_dict, k)
+            .map_err(|e| e.to_string())?;
+        for tid in candidates.iter_mut() {
+            *tid = self.tree_ids[*tid];
+        }
+        candidates.sort_unstable();
+        Ok(candidates)
+    }
+}
+
+/// Runs the body of an exported function, a panic is reported through [`ts_last_error`]
+/// and gives `on_panic` instead of unwinding into the C caller
+fn guarded<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        set_error(format!("Internal error: {msg}"));
+        on_panic
+    })
+}
+
+/// Message of the last error on the calling thread, or NULL. The string is valid
+/// until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn ts_last_error() -> *const c_char {
+    guarded(ptr::null(), || {
+        LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+    })
+}
+
+/// Parses a dataset file with one tree in bracket notation per line.
+/// Returns NULL on failure.
+///
+/// # Safety
+/// `path` must be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ts_collection_parse(path: *const c_char) -> *mut TsCollection {
+    guarded(ptr::null_mut(), || {
+        if path.is_null() {
+            set_error("Dataset path is NULL");
+            return ptr::null_mut();
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            set_error("Dataset path is not valid UTF-8");
+            return ptr::null_mut();
+        };
+        let mut label_dict = LabelDict::new();
+        let trees =
+            match parsing::parse_dataset_reporting(&path, &mut label_dict, Default::default()) {
+                Ok((trees, _, _)) => trees,
+                Err(e) => {
+                    set_error(e);
+                    return ptr::null_mut();
+                }
+            };
+        let mut tree_ids = (0..trees.len()).collect::<Vec<_>>();
+        tree_ids.sort_by_key(|tid| trees[*tid].count());
+        let mut trees = trees.into_iter().map(Some).collect::<Vec<_>>();
+        let trees = tree_ids
+            .iter()
+            .map(|tid| trees[*tid].take().unwrap())
+            .collect();
+        Box::into_raw(Box::new(TsCollection {
+            trees,
+            tree_ids,
+            label_dict,
+        }))
+    })
+}
+
+/// Number of trees in the collection
+///
+/// # Safety
+/// `collection` must be a handle returned by [`ts_collection_parse`].
+#[no_mangle]
+pub unsafe extern "C" fn ts_collection_len(collection: *const TsCollection) -> usize {
+    guarded(0, || collection.as_ref().map_or(0, |c| c.trees.len()))
+}
+
+/// # Safety
+/// `collection` must be a handle returned by [`ts_collection_parse`] or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ts_collection_free(collection: *mut TsCollection) {
+    guarded((), || {
+        if !collection.is_null() {
+            drop(Box::from_raw(collection));
+        }
+    })
+}
+
+/// Builds indexes of one of the `TS_METHOD_*` lower bounds over the collection.
+/// The index does not borrow the collection, which can be freed afterwards.
+/// Returns NULL on failure.
+///
+/// # Safety
+/// `collection` must be a handle returned by [`ts_collection_parse`].
+#[no_mangle]
+pub unsafe extern "C" fn ts_index_build(
+    collection: *const TsCollection,
+    method: u32,
+) -> *mut TsIndex {
+    guarded(ptr::null_mut(), || {
+        let Some(collection) = collection.as_ref() else {
+            set_error("Collection is NULL");
+            return ptr::null_mut();
+        };
+        match TsIndex::new(collection, method) {
+            Ok(index) => Box::into_raw(Box::new(index)),
+            Err(e) => {
+                set_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Finds candidate trees whose lower bound to the query tree in bracket notation is
+/// at most `k`. Candidates are written into `out`. Returns 0 on success, -1 on failure.
+///
+/// # Safety
+/// `index` must be a handle returned by [`ts_index_build`], `tree` a valid NUL
+/// terminated string and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ts_index_query(
+    index: *mut TsIndex,
+    tree: *const c_char,
+    k: usize,
+    out: *mut TsCandidates,
+) -> i32 {
+    guarded(-1, || {
+        let (Some(index), false, Some(out)) = (index.as_mut(), tree.is_null(), out.as_mut()) else {
+            set_error("Index, tree or output is NULL");
+            return -1;
+        };
+        let Ok(tree) = CStr::from_ptr(tree).to_str() else {
+            set_error("Query tree is not valid UTF-8");
+            return -1;
+        };
+        match index.query(tree, k) {
+            Ok(candidates) => {
+                let candidates = candidates.into_boxed_slice();
+                out.len = candidates.len();
+                out.ids = Box::into_raw(candidates) as *mut usize;
+                0
+            }
+            Err(e) => {
+                set_error(e);
+                -1
+            }
+        }
+    })
+}
+
+/// # Safety
+/// `candidates` must be filled by [`ts_index_query`] and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn ts_candidates_free(candidates: TsCandidates) {
+    guarded((), || {
+        if !candidates.ids.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                candidates.ids,
+                candidates.len,
+            )));
+        }
+    })
+}
+
+/// # Safety
+/// `index` must be a handle returned by [`ts_index_build`] or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ts_index_free(index: *mut TsIndex) {
+    guarded((), || {
+        if !index.is_null() {
+            drop(Box::from_raw(index));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_returns_dataset_ids() {
+        let mut label_dict = LabelDict::new();
+        let trees = ["{a{b}{c}{d}}", "{a}", "{a{b}{c}}"]
+            .map(|t| parsing::parse_single(t.to_owned(), &mut label_dict))
+            .to_vec();
+        let collection = TsCollection {
+            trees: vec![trees[1].clone(), trees[2].clone(), trees[0].clone()],
+            tree_ids: vec![1, 2, 0],
+            label_dict,
+        };
+        for method in [TS_METHOD_LBLINT, TS_METHOD_SED, TS_METHOD_STRUCTURAL] {
+            let mut index = TsIndex::new(&collection, method).unwrap();
+            assert_eq!(index.query("{a{b}{c}}", 0).unwrap(), vec![2]);
+            assert_eq!(index.query("{a{b}}", 1).unwrap(), vec![1, 2]);
+        }
+        assert!(TsIndex::new(&collection, 3).is_err());
+    }
+
+    #[test]
+    fn test_panic_is_reported() {
+        let result = guarded(-1, || panic!("broken index"));
+        assert_eq!(result, -1);
+        let msg = unsafe { CStr::from_ptr(ts_last_error()) };
+        assert_eq!(msg.to_str().unwrap(), "Internal error: broken index");
+    }
+
+    #[test]
+    fn test_header_is_current() {
+        assert_eq!(
+            include_str!("../include/tree_statistics.h"),
+            include_str!(concat!(env!("OUT_DIR"), "/tree_statistics.h")),
+            "refresh include/tree_statistics.h with cbindgen, see the module documentation"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Periodic checkpointing of candidate generation, so that long runs can be
+//! resumed after a crash or preemption instead of starting over.
+//!
+//! Two files are kept in the output directory for each method:
+//! - `<Method>.checkpoint` holds the number of processed queries and candidates as JSON
+//!   with the inputs of the run and the queries stopped at their time limit
+//! - `<Method>_candidates.partial.csv` holds candidates found so far
+//!
+//! The state file is written atomically only after the partial candidates were
+//! flushed, so on resume anything past `candidate_count` is discarded. Candidates are
+//! only resumed for the collection and queries they were found for, see
+//! [`CheckpointInputs`].
+use crate::collection_fingerprint::CollectionFingerprint;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Inputs of a run, a checkpoint written for other inputs is not resumed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointInputs {
+    pub collection: CollectionFingerprint,
+    /// FNV-1a hash of the query file, or of the threshold and query ids of a self join
+    pub queries: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    processed_queries: usize,
+    candidate_count: usize,
+    inputs: CheckpointInputs,
+    /// processed queries with partial candidates
+    #[serde(default)]
+    timed_out: Vec<usize>,
+}
+
+pub struct Checkpointer {
+    state_path: PathBuf,
+    partial_path: PathBuf,
+    writer: BufWriter<File>,
+    every: usize,
+    inputs: CheckpointInputs,
+    since_last: usize,
+    processed_queries: usize,
+    candidate_count: usize,
+    timed_out: Vec<usize>,
+    resumed: Vec<(usize, usize)>,
+}
+
+impl Checkpointer {
+    pub const DEFAULT_EVERY: usize = 1000;
+
+    /// Opens checkpoint files for `method` in `output_dir`. When `resume` is set
+    /// and a previous checkpoint exists, its candidates are loaded and queries
+    /// already processed will be skipped. A checkpoint of other `inputs` is an error.
+    pub fn open(
+        output_dir: &Path,
+        method: &str,
+        every: usize,
+        resume: bool,
+        inputs: CheckpointInputs,
+    ) -> Result<Self, anyhow::Error> {
+        let state_path = output_dir.join(format!("{method}.checkpoint"));
+        let partial_path = output_dir.join(format!("{method}_candidates.partial.csv"));
+
+        let (processed_queries, candidate_count, timed_out) = if resume && state_path.exists() {
+            let Ok(state) = serde_json::from_str::<State>(&fs::read_to_string(&state_path)?) else {
+                anyhow::bail!("Malformed checkpoint file {state_path:?}");
+            };
+            if let Err(mismatch) = state.inputs.collection.check(&inputs.collection) {
+                anyhow::bail!("Checkpoint {state_path:?} is stale, {mismatch}");
+            }
+            if state.inputs.queries != inputs.queries {
+                anyhow::bail!("Checkpoint {state_path:?} is stale, saved for other queries");
+            }
+            (
+                state.processed_queries,
+                state.candidate_count,
+                state.timed_out,
+            )
+        } else {
+            (0, 0, vec![])
+        };
+
+        let mut resumed = Vec::with_capacity(candidate_count);
+        if candidate_count > 0 {
+            let reader = BufReader::new(File::open(&partial_path)?);
+            for line in reader.lines().take(candidate_count) {
+                let line = line?;
+                let Some((c1, c2)) = line.split_once(',') else {
+                    anyhow::bail!("Malformed candidate line in {partial_path:?}: {line}");
+                };
+                resumed.push((c1.parse()?, c2.parse()?));
+            }
+            if resumed.len() != candidate_count {
+                anyhow::bail!(
+                    "Partial candidates file {partial_path:?} is shorter than checkpoint"
+                );
+            }
+        }
+
+        // rewrite the partial file so lines written after the last checkpoint are dropped
+        let mut writer = BufWriter::new(File::create(&partial_path)?);
+        for (c1, c2) in resumed.iter() {
+            writeln!(writer, "{c1},{c2}")?;
+        }
+
+        Ok(Self {
+            state_path,
+            partial_path,
+            writer,
+            every: every.max(1),
+            inputs,
+            since_last: 0,
+            processed_queries,
+            candidate_count,
+            timed_out,
+            resumed,
+        })
+    }
+
+    /// Index of the first query that was not yet processed.
+    pub fn resume_from(&self) -> usize {
+        self.processed_queries
+    }
+
+    /// Takes candidates loaded from a previous run.
+    pub fn take_resumed(&mut self) -> Vec<(usize, usize)> {
+        std::mem::take(&mut self.resumed)
+    }
+
+    /// Queries of the resumed run stopped at the time limit are stopped in this run too,
+    /// they are added to `timed_out` of the time limit of this run, their partial
+    /// candidates are resumed. Fails without a time limit in this run.
+    pub fn resume_timed_out(
+        &self,
+        timed_out: Option<&mut Vec<usize>>,
+    ) -> Result<(), anyhow::Error> {
+        match timed_out {
+            Some(timed_out) => timed_out.extend_from_slice(&self.timed_out),
+            None if !self.timed_out.is_empty() => anyhow::bail!(
+                "Checkpoint {:?} has queries stopped at the time limit, resume it with a time limit",
+                self.state_path
+            ),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Records that query `qid` stopped at the time limit, before it is done
+    pub fn query_timed_out(&mut self, qid: usize) {
+        self.timed_out.push(qid);
+    }
+
+    /// Records that query `qid` finished with `candidates`, saving
+    /// a checkpoint every configured number of queries.
+    pub fn query_done(&mut self, qid: usize, candidates: &[(usize, usize)]) -> io::Result<()> {
+        for (c1, c2) in candidates.iter() {
+            writeln!(self.writer, "{c1},{c2}")?;
+        }
+        self.candidate_count += candidates.len();
+        self.processed_queries = qid + 1;
+        self.since_last += 1;
+        if self.since_last >= self.every {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        let tmp_path = self.state_path.with_extension("checkpoint.tmp");
+        let state = State {
+            processed_queries: self.processed_queries,
+            candidate_count: self.candidate_count,
+            inputs: self.inputs.clone(),
+            timed_out: self.timed_out.clone(),
+        };
+        fs::write(&tmp_path, serde_json::to_string(&state)?)?;
+        fs::rename(tmp_path, &self.state_path)?;
+        self.since_last = 0;
+        Ok(())
+    }
+
+    /// Removes checkpoint files after the final output was written.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.partial_path)?;
+        if self.state_path.exists() {
+            fs::remove_file(&self.state_path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    fn inputs(trees: &[&str], queries: u64) -> CheckpointInputs {
+        let mut label_dict = LabelDict::default();
+        let trees = trees
+            .iter()
+            .map(|t| parse_single(t.to_string(), &mut label_dict))
+            .collect::<Vec<_>>();
+        CheckpointInputs {
+            collection: CollectionFingerprint::of_trees(&trees, &label_dict),
+            queries,
+        }
+    }
+
+    #[test]
+    fn test_resume_drops_unsaved_candidates() {
+        let dir = std::env::temp_dir().join(format!("ckpt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let trees = ["{a{b}}", "{c}"];
+
+        let mut c = Checkpointer::open(&dir, "Test", 2, false, inputs(&trees, 7)).unwrap();
+        c.query_done(0, &[(0, 1), (0, 2)]).unwrap();
+        c.query_timed_out(1);
+        c.query_done(1, &[(1, 3)]).unwrap();
+        // not checkpointed yet, simulates a crash
+        c.query_timed_out(2);
+        c.query_done(2, &[(2, 4)]).unwrap();
+        drop(c);
+
+        // a checkpoint of another collection or other queries is not resumed
+        assert!(Checkpointer::open(&dir, "Test", 2, true, inputs(&["{a{b}}"], 7)).is_err());
+        assert!(Checkpointer::open(&dir, "Test", 2, true, inputs(&trees, 8)).is_err());
+
+        let mut c = Checkpointer::open(&dir, "Test", 2, true, inputs(&trees, 7)).unwrap();
+        assert_eq!(c.resume_from(), 2);
+        assert_eq!(c.take_resumed(), vec![(0, 1), (0, 2), (1, 3)]);
+        // the partial candidates of query 1 are only resumed with a time limit
+        assert!(c.resume_timed_out(None).is_err());
+        let mut timed_out = vec![];
+        c.resume_timed_out(Some(&mut timed_out)).unwrap();
+        assert_eq!(timed_out, vec![1]);
+        c.finish().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
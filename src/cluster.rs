@@ -0,0 +1,146 @@
+//! Agglomerative clustering of trees by tree edit distance with a distance cutoff.
+//!
+//! The distance matrix is sparse, only pairs found by [`crate::join::self_join`] within
+//! the cutoff are known and all other pairs are further apart. Both linkages are exact
+//! under this pruning: single linkage merges clusters joined by any known pair and
+//! complete linkage only clusters whose pairs are all known.
+use clap::ValueEnum;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Linkage {
+    /// Distance of clusters is the distance of their closest trees
+    Single,
+    /// Distance of clusters is the distance of their farthest trees
+    Complete,
+}
+
+/// Disjoint sets of tree ids, the representative of a set is its smallest id
+pub struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn new(n: usize) -> Self {
+        Self {
+            parents: (0..n).collect(),
+        }
+    }
+
+    pub fn find(&mut self, mut x: usize) -> usize {
+        while self.parents[x] != x {
+            self.parents[x] = self.parents[self.parents[x]];
+            x = self.parents[x];
+        }
+        x
+    }
+
+    /// Joins sets of `a` and `b` and returns the representative of the joined set
+    pub fn union(&mut self, a: usize, b: usize) -> usize {
+        let (a, b) = (self.find(a), self.find(b));
+        let (root, child) = (a.min(b), a.max(b));
+        self.parents[child] = root;
+        root
+    }
+
+    /// Cluster of every id, clusters are numbered in order of their smallest id
+    pub fn assignments(&mut self) -> Vec<usize> {
+        let mut numbers = HashMap::new();
+        (0..self.parents.len())
+            .map(|x| {
+                let root = self.find(x);
+                let next = numbers.len();
+                *numbers.entry(root).or_insert(next)
+            })
+            .collect()
+    }
+}
+
+/// Clusters `n` trees given pairs `(t1, t2, distance)` within the cutoff and returns
+/// cluster of every tree
+pub fn cluster(n: usize, pairs: &[(usize, usize, usize)], linkage: Linkage) -> Vec<usize> {
+    let mut sets = UnionFind::new(n);
+    match linkage {
+        Linkage::Single => {
+            for &(t1, t2, _) in pairs {
+                sets.union(t1, t2);
+            }
+        }
+        Linkage::Complete => complete_linkage(&mut sets, pairs),
+    }
+    sets.assignments()
+}
+
+/// Merges the closest pair of clusters while all pairs of their trees are known. Links
+/// between clusters keep the number of known pairs and the largest distance.
+fn complete_linkage(sets: &mut UnionFind, pairs: &[(usize, usize, usize)]) {
+    let n = sets.parents.len();
+    let mut sizes = vec![1usize; n];
+    let mut neighbours = vec![HashSet::new(); n];
+    let mut links = HashMap::with_capacity(pairs.len());
+    let mut heap = BinaryHeap::with_capacity(pairs.len());
+    for &(t1, t2, distance) in pairs {
+        links.insert((t1, t2), (1usize, distance));
+        neighbours[t1].insert(t2);
+        neighbours[t2].insert(t1);
+        heap.push(Reverse((distance, t1, t2)));
+    }
+
+    let key = |a: usize, b: usize| (a.min(b), a.max(b));
+    while let Some(Reverse((distance, a, b))) = heap.pop() {
+        // skip merges of clusters that changed since they were pushed
+        if sets.find(a) != a || sets.find(b) != b {
+            continue;
+        }
+        match links.get(&(a, b)) {
+            Some(&(count, max)) if count == sizes[a] * sizes[b] && max == distance => {}
+            _ => continue,
+        }
+
+        links.remove(&(a, b));
+        let root = sets.union(a, b);
+        let child = if root == a { b } else { a };
+        sizes[root] += sizes[child];
+        neighbours[root].remove(&child);
+        for c in std::mem::take(&mut neighbours[child]) {
+            if c == root {
+                continue;
+            }
+            let (count, max) = links.remove(&key(child, c)).unwrap();
+            let link = links.entry(key(root, c)).or_insert((0, 0));
+            link.0 += count;
+            link.1 = link.1.max(max);
+            neighbours[c].remove(&child);
+            neighbours[c].insert(root);
+            neighbours[root].insert(c);
+        }
+        for &c in neighbours[root].iter() {
+            let (count, max) = links[&key(root, c)];
+            if count == sizes[root] * sizes[c] {
+                let (x, y) = key(root, c);
+                heap.push(Reverse((max, x, y)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linkages() {
+        // chain 0-1-2 with a missing 0-2 pair, 3 and 4 close, 5 alone
+        let pairs = [(0, 1, 1), (1, 2, 2), (3, 4, 0)];
+        assert_eq!(cluster(6, &pairs, Linkage::Single), vec![0, 0, 0, 1, 1, 2]);
+        assert_eq!(
+            cluster(6, &pairs, Linkage::Complete),
+            vec![0, 0, 1, 2, 2, 3]
+        );
+
+        // all pairs known, complete linkage merges the whole triangle
+        let pairs = [(0, 1, 1), (0, 2, 3), (1, 2, 2)];
+        assert_eq!(cluster(3, &pairs, Linkage::Complete), vec![0, 0, 0]);
+    }
+}
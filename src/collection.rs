@@ -0,0 +1,87 @@
+//! Tree collections sorted by size.
+//!
+//! Size buckets only find the trees within the threshold of a query when trees are
+//! sorted by size, and tree ids of candidate files are positions in the size sorted
+//! collection. An unsorted collection does not fail on its own, filters would silently
+//! skip trees within the threshold. A [`CollectionView`] records whether its trees are
+//! sorted when it is created, so APIs relying on the order take a view and check it in
+//! one place, an unsorted view is an [`UnsortedCollection`] error. [`sort_by_size`] sorts
+//! trees only when they are not sorted yet.
+use crate::parsing::ParsedTree;
+use rayon::prelude::*;
+use thiserror::Error;
+
+/// Sorts trees by size unless they already are, trees of equal size keep their order
+pub fn sort_by_size(trees: &mut [ParsedTree]) {
+    if !is_size_sorted(trees) {
+        trees.par_sort_by_key(|t| t.count());
+    }
+}
+
+pub fn is_size_sorted(trees: &[ParsedTree]) -> bool {
+    trees.is_sorted_by_key(|t| t.count())
+}
+
+/// Trees not sorted by size were given to `user` of them
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{user} needs trees sorted by size, sort them with sort_by_size first")]
+pub struct UnsortedCollection {
+    pub user: &'static str,
+}
+
+/// Borrowed trees of a collection with a record of whether they are sorted by size
+#[derive(Debug, Clone, Copy)]
+pub struct CollectionView<'a> {
+    trees: &'a [ParsedTree],
+    size_sorted: bool,
+}
+
+impl<'a> CollectionView<'a> {
+    /// View of the trees in their order, their order is checked once here
+    pub fn new(trees: &'a [ParsedTree]) -> Self {
+        Self {
+            trees,
+            size_sorted: is_size_sorted(trees),
+        }
+    }
+
+    /// Trees of a view sorted by size, an error naming `user` of the trees otherwise
+    pub fn size_sorted(&self, user: &'static str) -> Result<&'a [ParsedTree], UnsortedCollection> {
+        if !self.size_sorted {
+            return Err(UnsortedCollection { user });
+        }
+        Ok(self.trees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_collection_view() {
+        let mut ld = LabelDict::default();
+        let mut trees = ["{a{b}}", "{c}", "{d{e}}", "{f{g}{h}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        assert!(!is_size_sorted(&trees));
+
+        sort_by_size(&mut trees);
+        let sorted = CollectionView::new(&trees).size_sorted("test").unwrap();
+        let sizes = sorted.iter().map(|t| t.count()).collect::<Vec<_>>();
+        assert_eq!(sizes, [1, 2, 2, 3]);
+        // trees of equal size keep their order
+        assert_eq!(*trees[1].iter().next().unwrap().get(), ld["a"].0);
+    }
+
+    #[test]
+    fn test_unsorted_view() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a{b}}", "{c}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        assert_eq!(
+            CollectionView::new(&trees).size_sorted("test"),
+            Err(UnsortedCollection { user: "test" })
+        );
+    }
+}
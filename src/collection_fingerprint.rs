@@ -0,0 +1,173 @@
+//! Cheap summary of a tree collection kept with saved indexes.
+//!
+//! A saved index holds positions of trees in the size sorted collection, loading it for
+//! another version of the dataset silently gives candidates of the wrong trees. The
+//! fingerprint of the collection is saved with the index and compared with the one of the
+//! current collection on load. It holds the number of trees, a histogram of tree sizes in
+//! power of two buckets and a Bloom filter of label names, so it is computed in one pass
+//! over the nodes and is independent of the order of trees and of label ids. Edits that
+//! keep all three, e.g. renaming a node to a label used elsewhere, go unnoticed.
+use crate::parsing::{label_names, LabelDict, ParsedTree};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const LABEL_WORDS: usize = 64;
+const LABEL_BITS: u64 = (LABEL_WORDS * 64) as u64;
+const LABEL_HASHES: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionFingerprint {
+    trees: usize,
+    /// trees by the number of bits of their size
+    sizes: Vec<usize>,
+    /// Bloom filter of the names of all labels of the trees
+    labels: Vec<u64>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FingerprintMismatch {
+    #[error("built from {saved} trees, the collection has {current}")]
+    Trees { saved: usize, current: usize },
+    #[error("tree sizes differ from the collection")]
+    Sizes,
+    #[error("labels differ from the collection")]
+    Labels,
+}
+
+impl Default for CollectionFingerprint {
+    fn default() -> Self {
+        Self {
+            trees: 0,
+            sizes: vec![],
+            labels: vec![0; LABEL_WORDS],
+        }
+    }
+}
+
+/// 64-bit FNV-1a
+fn hash_label(label: &str) -> u64 {
+    label.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+impl CollectionFingerprint {
+    pub fn of_trees(trees: &[ParsedTree], label_dict: &LabelDict) -> Self {
+        let names = label_names(label_dict);
+        let mut fingerprint = Self::default();
+        for tree in trees {
+            fingerprint.add_tree(
+                tree.len(),
+                tree.iter().map(|node| names[node.get().get() as usize]),
+            );
+        }
+        fingerprint
+    }
+
+    /// Fingerprint of trees given by their sizes and the names of their labels, every
+    /// label of a tree once is enough
+    pub fn of_labels<'a, L: IntoIterator<Item = &'a str>>(
+        trees: impl IntoIterator<Item = (usize, L)>,
+    ) -> Self {
+        let mut fingerprint = Self::default();
+        for (size, labels) in trees {
+            fingerprint.add_tree(size, labels.into_iter());
+        }
+        fingerprint
+    }
+
+    fn add_tree<'a>(&mut self, size: usize, labels: impl Iterator<Item = &'a str>) {
+        self.trees += 1;
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.sizes.len() <= bucket {
+            self.sizes.resize(bucket + 1, 0);
+        }
+        self.sizes[bucket] += 1;
+        for label in labels {
+            // double hashing, the second hash is odd so the probes differ
+            let hash = hash_label(label);
+            let step = hash.rotate_left(32) | 1;
+            for i in 0..LABEL_HASHES {
+                let bit = hash.wrapping_add(i.wrapping_mul(step)) % LABEL_BITS;
+                self.labels[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+    }
+
+    pub fn trees(&self) -> usize {
+        self.trees
+    }
+
+    /// Smallest and largest size of the trees of every bucket of the size histogram with
+    /// their number, empty buckets are left out
+    pub fn size_buckets(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.sizes
+            .iter()
+            .enumerate()
+            .filter(|(_, trees)| **trees > 0)
+            .map(|(bits, trees)| match bits {
+                0 => (0, 0, *trees),
+                _ => (1 << (bits - 1), (1 << bits) - 1, *trees),
+            })
+    }
+
+    /// Checks that the fingerprint saved with an index is the one of the current collection
+    pub fn check(&self, current: &Self) -> Result<(), FingerprintMismatch> {
+        if self.trees != current.trees {
+            return Err(FingerprintMismatch::Trees {
+                saved: self.trees,
+                current: current.trees,
+            });
+        }
+        if self.sizes != current.sizes {
+            return Err(FingerprintMismatch::Sizes);
+        }
+        if self.labels != current.labels {
+            return Err(FingerprintMismatch::Labels);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_collection_fingerprint() {
+        let brackets = ["{a{b}{c}}", "{a}", "{x{y{z}}}"];
+        let mut ld = LabelDict::default();
+        let trees = brackets.map(|t| parse_single(t.to_owned(), &mut ld));
+        let fingerprint = CollectionFingerprint::of_trees(&trees, &ld);
+        let of_brackets = |brackets: &[&str]| {
+            let mut ld = LabelDict::default();
+            let trees = brackets
+                .iter()
+                .map(|t| parse_single(t.to_string(), &mut ld))
+                .collect::<Vec<_>>();
+            CollectionFingerprint::of_trees(&trees, &ld)
+        };
+        // the order of trees and label ids do not matter
+        let reversed = brackets.iter().rev().copied().collect::<Vec<_>>();
+        assert_eq!(of_brackets(&reversed), fingerprint);
+        assert_eq!(fingerprint.check(&fingerprint), Ok(()));
+
+        let changed = |brackets: &[&str]| fingerprint.check(&of_brackets(brackets));
+        assert_eq!(
+            changed(&brackets[..2]),
+            Err(FingerprintMismatch::Trees {
+                saved: 3,
+                current: 2
+            })
+        );
+        assert_eq!(
+            changed(&["{a{b}{c}}", "{a{b}}", "{x{y{z}}}"]),
+            Err(FingerprintMismatch::Sizes)
+        );
+        assert_eq!(
+            changed(&["{a{b}{c}}", "{w}", "{x{y{z}}}"]),
+            Err(FingerprintMismatch::Labels)
+        );
+    }
+}
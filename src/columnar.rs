@@ -0,0 +1,167 @@
+//! Parquet output of candidate pairs and per tree statistics, built with the `arrow` feature.
+//!
+//! Files are zstd compressed and written in record batches of [`BATCH_ROWS`] rows, so
+//! large candidate sets are never duplicated in memory as a whole. Tree ids are
+//! positions in the size sorted collection, the same as in CSV and binary files.
+use crate::statistics::StatisticsColumns;
+use arrow_array::builder::{ListBuilder, UInt32Builder};
+use arrow_array::{ArrayRef, RecordBatch, UInt32Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Rows of one record batch, each batch becomes at least one row group
+const BATCH_ROWS: usize = 1 << 20;
+
+fn writer(path: impl AsRef<Path>, schema: SchemaRef) -> Result<ArrowWriter<File>, anyhow::Error> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_max_row_group_size(BATCH_ROWS)
+        .build();
+    Ok(ArrowWriter::try_new(
+        File::create(path)?,
+        schema,
+        Some(props),
+    )?)
+}
+
+fn u32_column<T>(values: impl Iterator<Item = T>) -> Result<ArrayRef, anyhow::Error>
+where
+    u32: TryFrom<T>,
+    <u32 as TryFrom<T>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    let values = values.map(u32::try_from).collect::<Result<Vec<_>, _>>()?;
+    Ok(Arc::new(UInt32Array::from(values)))
+}
+
+/// Writes candidate pairs as columns `query_id` and `tree_id`
+pub fn write_candidates(
+    path: impl AsRef<Path>,
+    candidates: &[(usize, usize)],
+) -> Result<(), anyhow::Error> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("query_id", DataType::UInt32, false),
+        Field::new("tree_id", DataType::UInt32, false),
+    ]));
+    let mut w = writer(path, schema.clone())?;
+    for chunk in candidates.chunks(BATCH_ROWS) {
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                u32_column(chunk.iter().map(|(qid, _)| *qid))?,
+                u32_column(chunk.iter().map(|(_, tid)| *tid))?,
+            ],
+        )?;
+        w.write(&batch)?;
+    }
+    w.close()?;
+    Ok(())
+}
+
+fn list_column(lists: &[&[usize]]) -> Result<ArrayRef, anyhow::Error> {
+    let mut builder = ListBuilder::new(UInt32Builder::new());
+    for list in lists {
+        for value in *list {
+            builder.values().append_value(u32::try_from(*value)?);
+        }
+        builder.append(true);
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+/// Writes one row of statistics per tree, `degrees` and `depths` are list columns
+pub fn write_statistics(
+    path: impl AsRef<Path>,
+    stats: &StatisticsColumns,
+) -> Result<(), anyhow::Error> {
+    let item = Arc::new(Field::new("item", DataType::UInt32, true));
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("tree_id", DataType::UInt32, false),
+        Field::new("size", DataType::UInt32, false),
+        Field::new("distinct_labels", DataType::UInt32, false),
+        Field::new("collection_unique_labels", DataType::UInt32, false),
+        Field::new("degrees", DataType::List(item.clone()), false),
+        Field::new("depths", DataType::List(item), false),
+    ]));
+    let mut w = writer(path, schema.clone())?;
+    for start in (0..stats.sizes.len()).step_by(BATCH_ROWS) {
+        let rows = start..stats.sizes.len().min(start + BATCH_ROWS);
+        let degrees = rows
+            .clone()
+            .map(|i| stats.degrees_of(i))
+            .collect::<Vec<_>>();
+        let depths = rows.clone().map(|i| stats.depths_of(i)).collect::<Vec<_>>();
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                u32_column(rows.clone())?,
+                u32_column(stats.sizes[rows.clone()].iter().copied())?,
+                u32_column(stats.distinct_labels[rows.clone()].iter().copied())?,
+                u32_column(stats.collection_unique_labels[rows].iter().copied())?,
+                list_column(&degrees)?,
+                list_column(&depths)?,
+            ],
+        )?;
+        w.write(&batch)?;
+    }
+    w.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt32Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn read(path: &Path) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parquet_roundtrip() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("tree-statistics-candidates.parquet");
+        let candidates = vec![(0, 3), (0, 7), (2, 1)];
+        write_candidates(&path, &candidates).unwrap();
+        let batches = read(&path);
+        let tree_ids = batches[0].column(1).as_primitive::<UInt32Type>();
+        assert_eq!(tree_ids.values().to_vec(), vec![3, 7, 1]);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = dir.join("tree-statistics-statistics.parquet");
+        let mut ld = crate::parsing::LabelDict::default();
+        let trees = [crate::parsing::parse_single(
+            "{a{b}{c}}".to_owned(),
+            &mut ld,
+        )];
+        let ordering = crate::parsing::get_frequency_ordering(&ld);
+        let stats = crate::statistics::gather_chunked(&trees, &ordering, 1);
+        write_statistics(&path, &stats).unwrap();
+        let batches = read(&path);
+        assert_eq!(batches[0].num_rows(), 1);
+        let degrees = batches[0]
+            .column_by_name("degrees")
+            .unwrap()
+            .as_list::<i32>();
+        assert_eq!(
+            degrees
+                .value(0)
+                .as_primitive::<UInt32Type>()
+                .values()
+                .to_vec(),
+            vec![2, 1, 1]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+//! Structure shared by two trees, what is common instead of what differs.
+//!
+//! The largest common subtree is the largest complete subtree, a node with all its
+//! descendants, occurring in both trees. Subtrees are matched by their fingerprints. The
+//! largest common embedded subtree keeps the ancestors and the left-to-right order of
+//! its nodes but may skip nodes in between, it is the largest number of equally labelled
+//! nodes an edit mapping maps. Deleting and inserting all other nodes costs
+//! `|T1| + |T2| - 2 * embedded`, so its size follows from the distance in which a rename
+//! costs as much as a delete and an insert.
+use crate::hashing::SubtreeFingerprints;
+use crate::parsing::ParsedTree;
+use crate::ted::{ted_with_costs, CostModel};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// Distance of embedded subtrees, mapping different labels is never cheaper than
+/// deleting and inserting
+const INDEL: CostModel = CostModel {
+    delete: 1,
+    insert: 1,
+    rename: 2,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommonStructure {
+    /// nodes of the largest common subtree
+    pub subtree: usize,
+    /// nodes of the largest common embedded subtree
+    pub embedded: usize,
+}
+
+pub fn largest_common_subtree(t1: &ParsedTree, t2: &ParsedTree) -> usize {
+    let in_t2 = t2
+        .subtree_fingerprints()
+        .into_iter()
+        .map(|(_, fingerprint)| fingerprint)
+        .collect::<FxHashSet<_>>();
+    // children come before their parent in postorder
+    let mut sizes = FxHashMap::default();
+    let mut largest = 0;
+    for (nid, fingerprint) in t1.subtree_fingerprints() {
+        let size = 1 + nid.children(t1).map(|c| sizes[&c]).sum::<usize>();
+        sizes.insert(nid, size);
+        if in_t2.contains(&fingerprint) {
+            largest = largest.max(size);
+        }
+    }
+    largest
+}
+
+/// Cubic in the tree sizes like the exact tree edit distance
+pub fn largest_common_embedded_subtree(t1: &ParsedTree, t2: &ParsedTree) -> usize {
+    (t1.count() + t2.count() - ted_with_costs(t1, t2, &INDEL)) / 2
+}
+
+pub fn common_structure(t1: &ParsedTree, t2: &ParsedTree) -> CommonStructure {
+    CommonStructure {
+        subtree: largest_common_subtree(t1, t2),
+        embedded: largest_common_embedded_subtree(t1, t2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_common_structure() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{b{c}{d}}{e{f}}}".to_owned(), &mut ld);
+        let t2 = parse_single("{x{b{c}{d}}{y{e}{f}}}".to_owned(), &mut ld);
+        // {b{c}{d}} is in both, {e{f}} is not a complete subtree of the second tree
+        assert_eq!(largest_common_subtree(&t1, &t2), 3);
+        // b, c, d, e and f keep their order, f is no longer below e
+        assert_eq!(largest_common_embedded_subtree(&t1, &t2), 4);
+        assert_eq!(
+            common_structure(&t1, &t1),
+            CommonStructure {
+                subtree: 6,
+                embedded: 6
+            }
+        );
+        let t3 = parse_single("{z}".to_owned(), &mut ld);
+        assert_eq!(common_structure(&t1, &t3), CommonStructure::default());
+    }
+}
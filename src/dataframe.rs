@@ -0,0 +1,146 @@
+//! DataFrame API of per tree statistics and candidates, built with the `polars` feature.
+//!
+//! Tree ids are positions in the size sorted collection and query ids positions in the
+//! query file, the same as in candidate files of the command line, so frames join with
+//! its outputs as well as with metadata of the trees.
+use crate::indexing::IndexError;
+use crate::parsing::{self, LabelDict, ParseOptions, ParsedTree};
+use crate::statistics;
+use crate::tree_index::TreeIndex;
+use polars::prelude::*;
+use rayon::prelude::*;
+use std::path::Path;
+
+pub use crate::tree_index::Method;
+
+fn u32_column(name: &str, values: impl Iterator<Item = usize>) -> Result<Column, anyhow::Error> {
+    let values = values.map(u32::try_from).collect::<Result<Vec<_>, _>>()?;
+    Ok(Column::new(name.into(), values))
+}
+
+fn list_column<'a>(name: &str, lists: impl Iterator<Item = &'a [usize]>) -> Column {
+    let lists = lists
+        .map(|list| {
+            Series::new(
+                PlSmallStr::EMPTY,
+                list.iter().map(|&v| v as u32).collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    Column::new(name.into(), lists)
+}
+
+/// Trees of a dataset sorted by size
+pub struct Dataset {
+    trees: Vec<ParsedTree>,
+    label_dict: LabelDict,
+}
+
+impl Dataset {
+    /// Parses a dataset file with one tree in bracket notation per line
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut label_dict = LabelDict::new();
+        let (mut trees, _, _) =
+            parsing::parse_dataset_reporting(&path, &mut label_dict, ParseOptions::default())?;
+        trees.par_sort_by_key(|t| t.count());
+        Ok(Self { trees, label_dict })
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// One row per tree with columns `tree_id`, `size`, `distinct_labels`,
+    /// `collection_unique_labels` and lists `degrees` and `depths`
+    pub fn statistics(&self) -> Result<DataFrame, anyhow::Error> {
+        let freq_ordering = parsing::get_frequency_ordering(&self.label_dict);
+        let stats = statistics::gather_chunked(&self.trees, &freq_ordering, 256);
+        let trees = 0..stats.sizes.len();
+        Ok(DataFrame::new(vec![
+            u32_column("tree_id", trees.clone())?,
+            u32_column("size", stats.sizes.iter().copied())?,
+            u32_column("distinct_labels", stats.distinct_labels.iter().copied())?,
+            u32_column(
+                "collection_unique_labels",
+                stats.collection_unique_labels.iter().copied(),
+            )?,
+            list_column("degrees", trees.clone().map(|i| stats.degrees_of(i))),
+            list_column("depths", trees.map(|i| stats.depths_of(i))),
+        ])?)
+    }
+
+    /// Candidates of queries in a query file with `threshold;tree` lines, columns
+    /// `query_id` and `tree_id`
+    pub fn candidates(
+        &self,
+        queries: impl AsRef<Path>,
+        method: Method,
+    ) -> Result<DataFrame, anyhow::Error> {
+        // labels of queries extend a copy, the dataset stays usable for other query files
+        let mut label_dict = self.label_dict.clone();
+        let queries =
+            parsing::parse_queries_with(&queries, &mut label_dict, ParseOptions::default())?;
+        let index = TreeIndex::new(&self.trees, &label_dict, method)?;
+        let candidates = queries
+            .par_iter()
+            .enumerate()
+            .map(|(qid, (k, query))| {
+                let tids = index.candidates(query, &label_dict, *k)?;
+                Ok(tids.into_iter().map(move |tid| (qid, tid)))
+            })
+            .collect::<Result<Vec<_>, IndexError>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        Ok(DataFrame::new(vec![
+            u32_column("query_id", candidates.iter().map(|(qid, _)| *qid))?,
+            u32_column("tree_id", candidates.iter().map(|(_, tid)| *tid))?,
+        ])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let dir = std::env::temp_dir();
+        let dataset = dir.join("tree-statistics-dataframe.bracket");
+        let queries = dir.join("tree-statistics-dataframe-queries.txt");
+        std::fs::write(&dataset, "{a{b}{c}{d}}\n{a}\n{a{b}{c}}\n").unwrap();
+        std::fs::write(&queries, "0;{a{b}{c}}\n1;{a{b}{x}}\n").unwrap();
+
+        let dataset = Dataset::open(&dataset).unwrap();
+        let stats = dataset.statistics().unwrap();
+        assert_eq!(stats.shape(), (3, 6));
+        let sizes = stats.column("size").unwrap().u32().unwrap();
+        assert_eq!(sizes.into_no_null_iter().collect::<Vec<_>>(), vec![1, 3, 4]);
+
+        for method in [Method::Lblint, Method::Sed, Method::Structural] {
+            let candidates = dataset.candidates(&queries, method).unwrap();
+            let pairs = candidates
+                .column("query_id")
+                .unwrap()
+                .u32()
+                .unwrap()
+                .into_no_null_iter()
+                .zip(
+                    candidates
+                        .column("tree_id")
+                        .unwrap()
+                        .u32()
+                        .unwrap()
+                        .into_no_null_iter(),
+                )
+                .collect::<Vec<_>>();
+            assert_eq!(pairs, vec![(0, 1), (1, 1)], "{method:?}");
+        }
+        std::fs::remove_file(dir.join("tree-statistics-dataframe.bracket")).unwrap();
+        std::fs::remove_file(queries).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! Near duplicate trees, groups of trees connected by pairs within a small distance.
+use crate::cluster::UnionFind;
+use crate::hashing::{Fingerprint, SubtreeFingerprints};
+use crate::parsing::ParsedTree;
+use std::collections::HashMap;
+use tree_statistics::tree_cmp;
+
+/// Trees connected by near duplicate pairs, `trees` are sorted by id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateCluster {
+    /// Tree with the smallest sum of distances to the other trees of the cluster
+    pub representative: usize,
+    pub trees: Vec<usize>,
+}
+
+/// Groups `n` trees by pairs `(t1, t2, distance)` within `k` and returns groups of at
+/// least two trees, largest first. Distances of pairs not given count as `k + 1` when
+/// picking representatives.
+pub fn duplicate_clusters(
+    n: usize,
+    pairs: &[(usize, usize, usize)],
+    k: usize,
+) -> Vec<DuplicateCluster> {
+    let mut sets = UnionFind::new(n);
+    for &(t1, t2, _) in pairs {
+        sets.union(t1, t2);
+    }
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for &(t1, t2, _) in pairs {
+        groups.entry(sets.find(t1)).or_default().extend([t1, t2]);
+    }
+
+    // number of pairs and sum of their distances of every tree
+    let mut sums: HashMap<usize, (usize, usize)> = HashMap::new();
+    for &(t1, t2, distance) in pairs {
+        for t in [t1, t2] {
+            let sum = sums.entry(t).or_default();
+            sum.0 += 1;
+            sum.1 += distance;
+        }
+    }
+
+    let mut clusters = groups
+        .into_values()
+        .map(|mut trees| {
+            trees.sort_unstable();
+            trees.dedup();
+            let total = |t: &usize| {
+                let (known, sum) = sums[t];
+                sum + (trees.len() - 1 - known) * (k + 1)
+            };
+            let representative = *trees.iter().min_by_key(|t| (total(t), **t)).unwrap();
+            DuplicateCluster {
+                representative,
+                trees,
+            }
+        })
+        .collect::<Vec<_>>();
+    clusters.sort_unstable_by_key(|c| (std::cmp::Reverse(c.trees.len()), c.representative));
+    clusters
+}
+
+/// Pairs `(first, tree, 0)` of every tree with the first tree equal to it, identical
+/// trees are found by their fingerprints without computing any distance. Trees of one
+/// fingerprint are compared, so a fingerprint collision never reports distinct trees.
+pub fn exact_duplicate_pairs(trees: &[ParsedTree]) -> Vec<(usize, usize, usize)> {
+    // first trees of every distinct tree by fingerprint
+    let mut firsts: HashMap<Fingerprint, Vec<usize>> = HashMap::new();
+    trees
+        .iter()
+        .enumerate()
+        .filter_map(|(tid, tree)| {
+            let firsts = firsts.entry(tree.fingerprint()).or_default();
+            match firsts
+                .iter()
+                .find(|first| tree_cmp::structural_eq(&trees[**first], tree))
+            {
+                Some(first) => Some((*first, tid, 0)),
+                None => {
+                    firsts.push(tid);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_clusters() {
+        // 1 is within 1 of both 0 and 2, 4 and 5 are equal
+        let pairs = [(0, 1, 1), (1, 2, 1), (4, 5, 0)];
+        assert_eq!(
+            duplicate_clusters(7, &pairs, 2),
+            vec![
+                DuplicateCluster {
+                    representative: 1,
+                    trees: vec![0, 1, 2],
+                },
+                DuplicateCluster {
+                    representative: 4,
+                    trees: vec![4, 5],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exact_duplicate_pairs() {
+        use crate::parsing::{parse_single, LabelDict};
+        let mut ld = LabelDict::default();
+        let trees = ["{a{b}}", "{a{c}}", "{a{b}}", "{b{a}}", "{a{b}}", "{a{c}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let pairs = exact_duplicate_pairs(&trees);
+        assert_eq!(pairs, vec![(0, 2, 0), (0, 4, 0), (1, 5, 0)]);
+        let clusters = duplicate_clusters(trees.len(), &pairs, 0);
+        assert_eq!(clusters[0].trees, vec![0, 2, 4]);
+        assert_eq!(clusters[0].representative, 0);
+    }
+}
@@ -0,0 +1,201 @@
+//! JSON dumps of the index entries of single trees for debugging filter results.
+//!
+//! Filters only see trees through their indexes, a dump shows exactly what a filter
+//! compares: traversal strings of the SED bound, region vectors of the structural filter
+//! and postorder ids of the inverted lists of the label intersection. Labels are written
+//! with their names and ids, lists of labels are sorted by label id.
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, NodeCounter};
+use crate::lb::structural_filter::{LabelSetConverter, RegionWeights};
+use crate::parsing::{label_names, LabelDict, LabelId, ParsedTree};
+use crate::traversals::{self, Traversal, TraversalOptions};
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::Serialize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DumpedIndex {
+    /// Preorder and postorder traversal strings of the SED bound, every node with its
+    /// unweighted region counts as written by `traversals --structure`
+    Sed,
+    /// Region vectors of all nodes grouped by label
+    Structural,
+    /// Postorder ids of all nodes grouped by label
+    InvertedList,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TreeDump<'a> {
+    pub tree_id: usize,
+    pub size: usize,
+    #[serde(flatten)]
+    pub index: IndexDump<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexDump<'a> {
+    Sed { preorder: String, postorder: String },
+    Structural(Vec<StructuralLabel<'a>>),
+    InvertedList(Vec<PostingList<'a>>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct StructuralLabel<'a> {
+    pub label: &'a str,
+    pub label_id: LabelId,
+    pub nodes: Vec<StructuralNode>,
+}
+
+/// Weighted region counts of a node as compared by the structural filter
+#[derive(Debug, Serialize)]
+pub struct StructuralNode {
+    pub postorder_id: NodeCounter,
+    pub left: NodeCounter,
+    pub ancestors: NodeCounter,
+    pub right: NodeCounter,
+    pub descendants: NodeCounter,
+    /// weighted subtree height, only with a height weight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<NodeCounter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostingList<'a> {
+    pub label: &'a str,
+    pub label_id: LabelId,
+    pub postorder_ids: Vec<NodeCounter>,
+}
+
+/// Dumps the index of the trees at the given positions, region vectors of the structural
+/// filter are weighted by `weights` like in the lower-bound command
+pub fn dump<'a>(
+    trees: &[ParsedTree],
+    label_dict: &'a LabelDict,
+    index: DumpedIndex,
+    tree_ids: &[usize],
+    weights: RegionWeights,
+) -> Result<Vec<TreeDump<'a>>, IndexError> {
+    let names = label_names(label_dict);
+    let name = |label: &LabelId| names[label.get() as usize];
+    let traversal_options = TraversalOptions {
+        traversals: Traversal::DEFAULT.to_vec(),
+        separator: ";".to_owned(),
+        label_names: Some(names.clone()),
+        structure: true,
+    };
+    let mut converter = LabelSetConverter::with_weights(weights);
+    tree_ids
+        .iter()
+        .map(|&tree_id| {
+            let tree = &trees[tree_id];
+            let index = match index {
+                DumpedIndex::Sed => {
+                    let [preorder, postorder] = traversals::format_tree(tree, &traversal_options)
+                        .try_into()
+                        .map_err(|_| IndexError::EmptyTree)?;
+                    IndexDump::Sed {
+                        preorder,
+                        postorder,
+                    }
+                }
+                DumpedIndex::Structural => {
+                    let sets = converter.create_single(tree)?;
+                    let labels = sets
+                        .label_sets()
+                        .sorted_by_key(|set| set.base.id)
+                        .map(|set| {
+                            let vecs = &set.struct_vec;
+                            let nodes = (0..vecs.postorder_ids.len())
+                                .map(|n| {
+                                    let [left, ancestors, right, descendants] = vecs.regions[n];
+                                    StructuralNode {
+                                        postorder_id: vecs.postorder_ids[n],
+                                        left,
+                                        ancestors,
+                                        right,
+                                        descendants,
+                                        height: vecs.heights.get(n).copied(),
+                                    }
+                                })
+                                .collect();
+                            StructuralLabel {
+                                label: name(&set.base.id),
+                                label_id: set.base.id,
+                                nodes,
+                            }
+                        })
+                        .collect();
+                    IndexDump::Structural(labels)
+                }
+                DumpedIndex::InvertedList => {
+                    let index = InvertedListLabelPostorderIndex::index_tree(tree, label_dict)?;
+                    let lists = index
+                        .inverted_list
+                        .into_iter()
+                        .sorted_by_key(|(label, _)| *label)
+                        .map(|(label, postorder_ids)| PostingList {
+                            label: name(&label),
+                            label_id: label,
+                            postorder_ids,
+                        })
+                        .collect();
+                    IndexDump::InvertedList(lists)
+                }
+            };
+            Ok(TreeDump {
+                tree_id,
+                size: tree.count(),
+                index,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_dump_index() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}{c{b}}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let dump_json = |index| {
+            let dumps = dump(&trees, &ld, index, &[1], RegionWeights::default()).unwrap();
+            serde_json::to_value(&dumps).unwrap()
+        };
+
+        let sed = dump_json(DumpedIndex::Sed);
+        assert_eq!(sed[0]["tree_id"], 1);
+        assert_eq!(sed[0]["size"], 4);
+        assert_eq!(
+            sed[0]["sed"]["preorder"],
+            "a:0:0:0:3;b:0:1:2:0;c:1:1:0:1;b:1:2:0:0"
+        );
+        assert_eq!(
+            sed[0]["sed"]["postorder"],
+            "b:0:1:2:0;b:1:2:0:0;c:1:1:0:1;a:0:0:0:3"
+        );
+
+        let lists = dump_json(DumpedIndex::InvertedList);
+        let b = &lists[0]["inverted_list"][1];
+        assert_eq!(b["label"], "b");
+        assert_eq!(b["postorder_ids"], serde_json::json!([0, 1]));
+
+        let structural = dump_json(DumpedIndex::Structural);
+        let c = &structural[0]["structural"][2];
+        assert_eq!(c["label"], "c");
+        // c has the first b to its left, a as ancestor and the second b as descendant
+        let node = &c["nodes"][0];
+        assert_eq!(
+            [
+                &node["left"],
+                &node["ancestors"],
+                &node["right"],
+                &node["descendants"]
+            ],
+            [1, 1, 0, 1]
+        );
+        assert!(node.get("height").is_none());
+    }
+}
@@ -0,0 +1,245 @@
+//! Edit scripts applied to a single tree.
+//!
+//! A script has one operation per line. Nodes are addressed by their preorder position,
+//! counted from 0 at the root, in the tree as edited by the lines before:
+//!
+//! - `rename N LABEL` changes the label of node N
+//! - `delete N` removes node N, its children take its place among its siblings
+//! - `insert P FROM TO LABEL` adds a child of node P at child position FROM, the children
+//!   of P at positions FROM..TO become children of the new node
+//!
+//! Labels are the rest of the line, empty lines and lines starting with `#` are skipped.
+//! Every operation is one edit of the unit cost tree edit distance, so the distance of an
+//! edited tree to the original is at most the number of operations.
+use crate::edits::compact;
+use crate::parsing::{update_label_dict, LabelDict, LabelId, ParsedTree};
+use indextree::NodeId;
+use itertools::Itertools;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptOp {
+    Rename {
+        node: usize,
+        label: String,
+    },
+    Delete {
+        node: usize,
+    },
+    Insert {
+        parent: usize,
+        from: usize,
+        to: usize,
+        label: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("Line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("Line {line}: node {node} is out of range of a tree of {size} nodes")]
+    NodeOutOfRange {
+        line: usize,
+        node: usize,
+        size: usize,
+    },
+    #[error("Line {line}: the root cannot be deleted")]
+    DeleteRoot { line: usize },
+    #[error("Line {line}: children {from}..{to} are out of range of the {children} children of node {node}")]
+    ChildrenOutOfRange {
+        line: usize,
+        node: usize,
+        from: usize,
+        to: usize,
+        children: usize,
+    },
+}
+
+/// Operations with the line they are on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EditScript(pub Vec<(usize, ScriptOp)>);
+
+fn parse_op(line: usize, text: &str) -> Result<ScriptOp, ScriptError> {
+    let syntax = |message: &str| ScriptError::Syntax {
+        line,
+        message: message.to_owned(),
+    };
+    let (name, mut rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let mut number = |what: &str| {
+        let (value, tail) = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .unwrap_or((rest.trim_start(), ""));
+        rest = tail;
+        value
+            .parse::<usize>()
+            .map_err(|_| syntax(&format!("expected {what}, found {value:?}")))
+    };
+    let op = match name {
+        "rename" => ScriptOp::Rename {
+            node: number("node")?,
+            label: rest.trim().to_owned(),
+        },
+        "delete" => ScriptOp::Delete {
+            node: number("node")?,
+        },
+        "insert" => ScriptOp::Insert {
+            parent: number("parent")?,
+            from: number("first child")?,
+            to: number("end child")?,
+            label: rest.trim().to_owned(),
+        },
+        _ => return Err(syntax(&format!("unknown operation {name:?}"))),
+    };
+    match &op {
+        ScriptOp::Rename { label, .. } | ScriptOp::Insert { label, .. } if label.is_empty() => {
+            Err(syntax("missing label"))
+        }
+        ScriptOp::Delete { .. } if !rest.trim().is_empty() => {
+            Err(syntax(&format!("unexpected {:?}", rest.trim())))
+        }
+        _ => Ok(op),
+    }
+}
+
+impl EditScript {
+    pub fn parse(script: &str) -> Result<Self, ScriptError> {
+        script
+            .lines()
+            .enumerate()
+            .map(|(i, text)| (i + 1, text.trim()))
+            .filter(|(_, text)| !text.is_empty() && !text.starts_with('#'))
+            .map(|(line, text)| Ok((line, parse_op(line, text)?)))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+fn label_id(label: &str, label_dict: &mut LabelDict) -> LabelId {
+    update_label_dict(&[vec![label]], label_dict);
+    label_dict[label].0
+}
+
+/// Applies the script to a copy of the tree, labels of the script missing in the
+/// dictionary are added to it
+pub fn apply(
+    tree: &ParsedTree,
+    script: &EditScript,
+    label_dict: &mut LabelDict,
+) -> Result<ParsedTree, ScriptError> {
+    let mut edited = compact(tree);
+    let Some(root) = edited.iter().next().and_then(|n| edited.get_node_id(n)) else {
+        return Ok(edited);
+    };
+    for (line, op) in script.0.iter() {
+        let line = *line;
+        // nodes of the tree as edited so far, the root keeps its id
+        let preorder = root.descendants(&edited).collect_vec();
+        let node = |node: usize| -> Result<NodeId, ScriptError> {
+            preorder
+                .get(node)
+                .copied()
+                .ok_or(ScriptError::NodeOutOfRange {
+                    line,
+                    node,
+                    size: preorder.len(),
+                })
+        };
+        match op {
+            ScriptOp::Rename { node: n, label } => {
+                let nid = node(*n)?;
+                *edited[nid].get_mut() = label_id(label, label_dict);
+            }
+            ScriptOp::Delete { node: n } => {
+                if *n == 0 {
+                    return Err(ScriptError::DeleteRoot { line });
+                }
+                node(*n)?.remove(&mut edited);
+            }
+            ScriptOp::Insert {
+                parent,
+                from,
+                to,
+                label,
+            } => {
+                let pid = node(*parent)?;
+                let children = pid.children(&edited).collect_vec();
+                if from > to || *to > children.len() {
+                    return Err(ScriptError::ChildrenOutOfRange {
+                        line,
+                        node: *parent,
+                        from: *from,
+                        to: *to,
+                        children: children.len(),
+                    });
+                }
+                let new_node = edited.new_node(label_id(label, label_dict));
+                if *from < children.len() {
+                    children[*from].insert_before(new_node, &mut edited);
+                } else {
+                    pid.append(new_node, &mut edited);
+                }
+                for child in &children[*from..*to] {
+                    child.detach(&mut edited);
+                    new_node.append(*child, &mut edited);
+                }
+            }
+        }
+    }
+    Ok(compact(&edited))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{label_names, parse_single, tree_to_labeled_bracket};
+    use crate::ted::ted;
+
+    #[test]
+    fn test_apply_edit_script() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b{c}{d}}{e}}".to_owned(), &mut ld);
+        let script = EditScript::parse(
+            "# nodes are counted in preorder\n\
+             rename 4 new label\n\
+             \n\
+             delete 1\n\
+             insert 0 0 2 x\n",
+        )
+        .unwrap();
+        assert_eq!(script.0.len(), 3);
+        assert_eq!(script.0[0].0, 2);
+        let edited = apply(&tree, &script, &mut ld).unwrap();
+        assert_eq!(
+            tree_to_labeled_bracket(&edited, &label_names(&ld)),
+            "{a{x{c}{d}}{new label}}"
+        );
+        // deleting b and inserting x in its place is the same as renaming b
+        assert_eq!(ted(&tree, &edited), 2);
+
+        assert!(matches!(
+            EditScript::parse("move 1 2"),
+            Err(ScriptError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            EditScript::parse("rename 1"),
+            Err(ScriptError::Syntax { .. })
+        ));
+        for (script, expected) in [
+            ("delete 0", "Line 1: the root cannot be deleted"),
+            (
+                "delete 5",
+                "Line 1: node 5 is out of range of a tree of 5 nodes",
+            ),
+            (
+                "insert 4 0 1 x",
+                "Line 1: children 0..1 are out of range of the 0 children of node 4",
+            ),
+        ] {
+            let script = EditScript::parse(script).unwrap();
+            let error = apply(&tree, &script, &mut ld).unwrap_err();
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+}
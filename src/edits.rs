@@ -0,0 +1,157 @@
+//! Random tree edit operations used to synthesize perturbed trees.
+use crate::parsing::{LabelId, ParsedTree};
+use indextree::NodeId;
+use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EditOperation {
+    Insert,
+    Delete,
+    Rename,
+}
+
+fn root_of(tree: &ParsedTree) -> Option<NodeId> {
+    let root = tree
+        .iter()
+        .find(|n| !n.is_removed() && n.parent().is_none())?;
+    tree.get_node_id(root)
+}
+
+/// Copies live nodes of a tree into a new arena, so node count matches the tree size
+/// after nodes were removed.
+pub fn compact(tree: &ParsedTree) -> ParsedTree {
+    let mut compacted = ParsedTree::with_capacity(tree.count());
+    let Some(root_id) = root_of(tree) else {
+        return compacted;
+    };
+    let new_root = compacted.new_node(*tree.get(root_id).unwrap().get());
+    let mut stack = vec![(root_id, new_root)];
+    while let Some((nid, new_nid)) = stack.pop() {
+        for cnid in nid.children(tree) {
+            let new_child = compacted.new_node(*tree.get(cnid).unwrap().get());
+            new_nid.append(new_child, &mut compacted);
+            stack.push((cnid, new_child));
+        }
+    }
+    compacted
+}
+
+/// Draws a label from `1..=max_label`
+fn random_label(rng: &mut impl Rng, max_label: u32) -> LabelId {
+    LabelId::from_index(rng.gen_range(0..max_label) as usize)
+}
+
+/// Applies a single random edit operation on the tree in place. Root is never deleted.
+/// Labels for inserted or renamed nodes are drawn from `1..=max_label`.
+/// Returns the operation that was applied.
+pub fn apply_random_edit(
+    tree: &mut ParsedTree,
+    rng: &mut impl Rng,
+    max_label: LabelId,
+) -> Option<EditOperation> {
+    let root_id = root_of(tree)?;
+    let nodes = root_id.descendants(tree).collect_vec();
+    let max_label = max_label.get().max(2);
+
+    let mut operation = match rng.gen_range(0..3) {
+        0 => EditOperation::Insert,
+        1 => EditOperation::Delete,
+        _ => EditOperation::Rename,
+    };
+    if operation == EditOperation::Delete && nodes.len() == 1 {
+        operation = EditOperation::Insert;
+    }
+
+    match operation {
+        EditOperation::Rename => {
+            let nid = nodes[rng.gen_range(0..nodes.len())];
+            let old = *tree.get(nid).unwrap().get();
+            let mut new_label = random_label(rng, max_label);
+            if new_label == old {
+                new_label = LabelId::from_index((new_label.get() % max_label) as usize);
+            }
+            *tree.get_mut(nid).unwrap().get_mut() = new_label;
+        }
+        EditOperation::Delete => {
+            let nid = nodes[rng.gen_range(1..nodes.len())];
+            nid.remove(tree);
+        }
+        EditOperation::Insert => {
+            let parent = nodes[rng.gen_range(0..nodes.len())];
+            let children = parent.children(tree).collect_vec();
+            let from = rng.gen_range(0..=children.len());
+            let to = rng.gen_range(from..=children.len());
+            let new_node = tree.new_node(random_label(rng, max_label));
+            if from < children.len() {
+                children[from].insert_before(new_node, tree);
+            } else {
+                parent.append(new_node, tree);
+            }
+            for child in &children[from..to] {
+                child.detach(tree);
+                new_node.append(*child, tree);
+            }
+        }
+    }
+    Some(operation)
+}
+
+/// Applies `n` random edit operations and returns a compacted copy of the edited tree.
+pub fn apply_random_edits(
+    tree: &ParsedTree,
+    n: usize,
+    rng: &mut impl Rng,
+    max_label: LabelId,
+) -> ParsedTree {
+    let mut edited = compact(tree);
+    for _ in 0..n {
+        apply_random_edit(&mut edited, rng, max_label);
+    }
+    compact(&edited)
+}
+
+/// Applies `n` random edits to every tree of a collection. Each tree gets its own
+/// generator derived from `seed` and tree id, so the output does not depend on scheduling.
+/// Returns mutated trees together with their exact tree edit distance to the original.
+pub fn mutate_collection(
+    trees: &[ParsedTree],
+    n: usize,
+    seed: u64,
+    max_label: LabelId,
+) -> Vec<(ParsedTree, usize)> {
+    trees
+        .par_iter()
+        .enumerate()
+        .map(|(tid, tree)| {
+            let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed.wrapping_add(tid as u64));
+            let mutated = apply_random_edits(tree, n, &mut rng, max_label);
+            let distance = crate::ted::ted(tree, &mutated);
+            (mutated, distance)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{max_label, parse_single, LabelDict};
+    use crate::ted::ted;
+
+    #[test]
+    fn test_edits_within_ted_bound() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b{c}{d}}{e{f}}{g}}".to_owned(), &mut ld);
+        let mut rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(7);
+        for n in 0..6 {
+            let edited = apply_random_edits(&tree, n, &mut rng, max_label(&ld));
+            assert!(ted(&tree, &edited) <= n);
+            assert_eq!(
+                edited.count(),
+                edited.iter().filter(|n| !n.is_removed()).count()
+            );
+        }
+    }
+}
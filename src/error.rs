@@ -0,0 +1,97 @@
+//! Failure causes of the command line tool and their exit codes.
+//!
+//! Commands return `anyhow::Error`, the cause is found in the chain of the error when the
+//! process exits. Errors raised as [`Error`] carry their cause, I/O errors and parse errors
+//! of the dataset, queries and edit scripts are recognized by downcasting, anything else
+//! is a generic failure. Invalid arguments found by clap exit with 2 before a command
+//! runs, so scripts wrapping the tool can tell the causes apart by the exit code alone.
+use crate::edit_script::ScriptError;
+use crate::parsing::{DatasetParseError, TreeParseError};
+use thiserror::Error;
+
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_IO: u8 = 3;
+pub const EXIT_PARSE: u8 = 4;
+pub const EXIT_CONFIG: u8 = 5;
+pub const EXIT_VALIDATION: u8 = 6;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Parse(String),
+    /// Options that conflict, are out of range or need a feature the build lacks
+    #[error("{0}")]
+    Config(String),
+    /// Checks of results that failed, e.g. of the self test
+    #[error("{0}")]
+    Validation(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io(_) => EXIT_IO,
+            Error::Parse(_) => EXIT_PARSE,
+            Error::Config(_) => EXIT_CONFIG,
+            Error::Validation(_) => EXIT_VALIDATION,
+        }
+    }
+}
+
+fn tree_parse_code(error: &TreeParseError) -> u8 {
+    match error {
+        TreeParseError::LineReadError(_) => EXIT_IO,
+        _ => EXIT_PARSE,
+    }
+}
+
+/// Exit code of the first cause in the chain of the error with a known code
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<Error>() {
+                Some(error.exit_code())
+            } else if let Some(error) = cause.downcast_ref::<DatasetParseError>() {
+                Some(match error {
+                    DatasetParseError::IoError(_) => EXIT_IO,
+                    DatasetParseError::ParseError(error) => tree_parse_code(error),
+                    DatasetParseError::ReservedLabel(_) => EXIT_PARSE,
+                })
+            } else if let Some(error) = cause.downcast_ref::<TreeParseError>() {
+                Some(tree_parse_code(error))
+            } else if cause.is::<csv::Error>() || cause.is::<ScriptError>() {
+                Some(EXIT_PARSE)
+            } else if cause.is::<std::io::Error>() {
+                Some(EXIT_IO)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(EXIT_FAILURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_codes() {
+        let config = anyhow::Error::from(Error::Config("conflicting options".to_owned()));
+        assert_eq!(exit_code(&config), EXIT_CONFIG);
+        assert_eq!(config.to_string(), "conflicting options");
+
+        let missing = std::fs::File::open("/nonexistent/dataset.bracket")
+            .context("Reading the dataset")
+            .unwrap_err();
+        assert_eq!(exit_code(&missing), EXIT_IO);
+        let parse = anyhow::Error::from(DatasetParseError::ParseError(
+            TreeParseError::IncorrectFormat("{a".to_owned()),
+        ));
+        assert_eq!(exit_code(&parse), EXIT_PARSE);
+        assert_eq!(exit_code(&anyhow::anyhow!("unknown")), EXIT_FAILURE);
+    }
+}
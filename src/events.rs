@@ -0,0 +1,117 @@
+//! Stream of run events written as newline delimited JSON.
+//!
+//! Every line is one event object with the wall-clock time `ts_ms` in milliseconds since
+//! the unix epoch, the milliseconds `elapsed_ms` since the log was opened and the kind of
+//! event in `event`. Lines are flushed as they are written, so dashboards can tail the
+//! file while a long run is still going.
+use serde::Serialize;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Start {
+        command: &'a str,
+        arguments: &'a [String],
+    },
+    Phase {
+        name: &'a str,
+        millis: u128,
+    },
+    /// Heap high-water mark of a method, with the `peak-memory` feature
+    PeakMemory {
+        name: &'a str,
+        bytes: usize,
+    },
+    /// Candidates of one query produced by a method
+    Query {
+        method: &'a str,
+        query_id: usize,
+        candidates: usize,
+    },
+    Finish {
+        millis: u128,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts_ms: u128,
+    elapsed_ms: u128,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+#[derive(Debug)]
+pub struct EventLog {
+    w: LineWriter<File>,
+    opened: Instant,
+}
+
+impl EventLog {
+    /// Creates the file, replacing a previous one
+    pub fn create(path: &Path) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            w: LineWriter::new(File::create(path)?),
+            opened: Instant::now(),
+        })
+    }
+
+    pub fn emit(&mut self, event: &Event) -> Result<(), std::io::Error> {
+        let line = Line {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis()),
+            elapsed_ms: self.opened.elapsed().as_millis(),
+            event,
+        };
+        serde_json::to_writer(&mut self.w, &line)?;
+        writeln!(self.w)
+    }
+
+    /// Milliseconds since the log was opened
+    pub fn elapsed_millis(&self) -> u128 {
+        self.opened.elapsed().as_millis()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let path = std::env::temp_dir().join(format!("events-{}.ndjson", std::process::id()));
+        let mut log = EventLog::create(&path).unwrap();
+        log.emit(&Event::Phase {
+            name: "parsing",
+            millis: 3,
+        })
+        .unwrap();
+        log.emit(&Event::Query {
+            method: "Sed",
+            query_id: 1,
+            candidates: 7,
+        })
+        .unwrap();
+        // lines are on disk before the log is dropped
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let events = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "phase");
+        assert_eq!(events[0]["name"], "parsing");
+        assert_eq!(events[0]["millis"], 3);
+        assert!(events[0]["ts_ms"].as_u64().unwrap() > 0);
+        assert_eq!(events[1]["event"], "query");
+        assert_eq!(events[1]["query_id"], 1);
+        assert_eq!(events[1]["candidates"], 7);
+        drop(log);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Small datasets embedded in the library for examples and doctests.
+//!
+//! [`COLLECTION`] is a handful of bibliography records in bracket notation, sorted by
+//! size like the trees of a parsed dataset, with records of one venue differing by a
+//! few edits. [`QUERIES`] are thresholds and trees in the form of `threshold;tree` query
+//! lines, and [`RESULTS`] the pairs of query and tree positions within the threshold of
+//! the query with their exact tree edit distance, the ground truth of the examples.
+//!
+//! ```
+//! use tree_statistics::example_data::{COLLECTION, QUERIES, RESULTS};
+//! use tree_statistics::pair::pair_distances;
+//!
+//! // the results are every pair within the threshold of its query
+//! let mut results = vec![];
+//! for (qid, (k, query)) in QUERIES.iter().enumerate() {
+//!     for (tid, tree) in COLLECTION.iter().enumerate() {
+//!         let ted = pair_distances(query, tree, true)?.ted.unwrap();
+//!         if ted <= *k {
+//!             results.push((qid, tid, ted));
+//!         }
+//!     }
+//! }
+//! assert_eq!(results, RESULTS);
+//! # Ok::<(), tree_statistics::pair::PairError>(())
+//! ```
+
+/// Trees in bracket notation sorted by size
+pub const COLLECTION: &[&str] = &[
+    "{book{title{Trees}}}",
+    "{book{title{Graphs}}}",
+    "{article{title{Trees}}{year{2020}}}",
+    "{article{title{Forests}}{year{2020}}}",
+    "{article{title{Trees}}{year{2021}}{venue{VLDB}}}",
+    "{article{title{Tree joins}}{year{2021}}{venue{VLDB}}}",
+    "{article{title{Tree joins}}{year{2022}}{venue{SIGMOD}}}",
+    "{inproceedings{title{Edit distance}}{year{2019}}{pages{1}{12}}}",
+    "{inproceedings{title{Edit distance}}{year{2020}}{pages{1}{14}}}",
+    "{inproceedings{title{Bounds}}{year{2020}}{pages{3}{14}}{venue{ICDE}}}",
+];
+
+/// Thresholds and query trees
+pub const QUERIES: &[(usize, &str)] = &[
+    (1, "{book{title{Trees}}}"),
+    (2, "{article{title{Trees}}{year{2021}}}"),
+    (
+        3,
+        "{inproceedings{title{Edit distance}}{year{2020}}{pages{1}{12}}}",
+    ),
+];
+
+/// Query position, tree position and exact tree edit distance of every pair within the
+/// threshold of the query
+pub const RESULTS: &[(usize, usize, usize)] = &[
+    (0, 0, 0),
+    (0, 1, 1),
+    (1, 2, 1),
+    (1, 3, 2),
+    (1, 4, 2),
+    (2, 7, 1),
+    (2, 8, 1),
+];
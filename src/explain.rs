@@ -0,0 +1,275 @@
+//! Why a single pair of a query and a tree is a candidate or not.
+//!
+//! `lower-bound --explain QID,TID` computes every method of the run on one pair instead of
+//! scanning the collection. The size window is checked first, as by all methods, then the
+//! labels both trees share and, for the prefix filters of the label intersection and the
+//! structural filter, the rarest labels of the query they look up. A tree sharing none of
+//! them is only found by the prefix filter if the query fits the threshold by its size.
+//! Bounds are computed as by `pairs` with the options of the run, methods it does not support
+//! are listed without one.
+use crate::indexing::{Indexer, InvertedListLabelPostorderIndex};
+use crate::lb::size_buckets::size_lb;
+use crate::lb::structural_filter::LabelSetConverter;
+use crate::pairs;
+use crate::parsing::{label_names, LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
+use crate::LowerBoundMethods;
+use rustc_hash::FxHashMap;
+use std::fmt;
+
+/// Parses a `qid,tid` pair of `--explain`
+pub fn parse_pair(pair: &str) -> Result<(usize, usize), String> {
+    let (qid, tid) = pair
+        .split_once(',')
+        .ok_or_else(|| format!("expected QID,TID, got {pair}"))?;
+    let id = |id: &str| id.trim().parse::<usize>().map_err(|e| format!("{id}: {e}"));
+    Ok((id(qid)?, id(tid)?))
+}
+
+/// Nodes of a label in the query and the tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelNodes<'a> {
+    pub label: &'a str,
+    pub query: usize,
+    pub tree: usize,
+}
+
+impl fmt::Display for LabelNodes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (query {}, tree {})",
+            self.label, self.query, self.tree
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodDecision<'a> {
+    pub method: LowerBoundMethods,
+    /// labels looked up by the prefix filter of the method, rarest first
+    pub prefix: Option<Vec<LabelNodes<'a>>>,
+    /// bound of the pair at the threshold, `None` for methods `pairs` does not support
+    pub bound: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation<'a> {
+    pub query_id: usize,
+    pub tree_id: usize,
+    pub k: usize,
+    pub sizes: (usize, usize),
+    /// labels of both trees in increasing order of names
+    pub shared: Vec<LabelNodes<'a>>,
+    pub methods: Vec<MethodDecision<'a>>,
+}
+
+impl Explanation<'_> {
+    pub fn in_size_window(&self) -> bool {
+        size_lb(self.sizes.0, self.sizes.1, self.k) <= self.k
+    }
+}
+
+impl fmt::Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (query, tree) = self.sizes;
+        let k = self.k;
+        writeln!(
+            f,
+            "query {} of {query} nodes, tree {} of {tree} nodes, threshold {k}",
+            self.query_id, self.tree_id
+        )?;
+        let difference = query.abs_diff(tree);
+        if self.in_size_window() {
+            writeln!(f, "size window: difference {difference} <= {k}, kept")?;
+        } else {
+            writeln!(f, "size window: difference {difference} > {k}, pruned")?;
+        }
+        let overlap = self
+            .shared
+            .iter()
+            .map(|label| label.query.min(label.tree))
+            .sum::<usize>();
+        writeln!(f, "shared labels: {} of {} query nodes", overlap, query)?;
+        for label in &self.shared {
+            writeln!(f, "  {label}")?;
+        }
+        for decision in &self.methods {
+            let method = decision.method;
+            if let Some(prefix) = &decision.prefix {
+                let found = prefix.iter().any(|label| label.tree > 0);
+                let outcome = match (found, query <= k) {
+                    (true, _) => "shared by the tree, kept",
+                    (false, true) => "not shared by the tree, kept as the query fits by size",
+                    (false, false) => "not shared by the tree, pruned",
+                };
+                writeln!(f, "{method:?} prefix of {} labels: {outcome}", prefix.len())?;
+                for label in prefix {
+                    writeln!(f, "  {label}")?;
+                }
+            }
+            match decision.bound {
+                Some(bound) if bound <= k => {
+                    writeln!(f, "{method:?} bound: {bound} <= {k}, candidate")?
+                }
+                Some(bound) => writeln!(f, "{method:?} bound: {bound} > {k}, pruned")?,
+                None => writeln!(f, "{method:?} bound: not explained")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn label_counts(tree: &ParsedTree) -> FxHashMap<LabelId, usize> {
+    let mut counts = FxHashMap::default();
+    for node in tree.iter() {
+        *counts.entry(*node.get()).or_default() += 1;
+    }
+    counts
+}
+
+/// Decisions of all `methods` on the pair of `query` and `tree` at threshold `k`, the
+/// prefixes are taken in `ordering` and the bounds computed with `options` like in the scan
+pub fn explain<'a>(
+    methods: &[LowerBoundMethods],
+    (query_id, query): (usize, &ParsedTree),
+    (tree_id, tree): (usize, &ParsedTree),
+    k: usize,
+    ordering: &LabelFreqOrdering,
+    label_dict: &'a LabelDict,
+    options: &pairs::BoundOptions,
+) -> Result<Explanation<'a>, anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let names = label_names(label_dict);
+    let (query_labels, tree_labels) = (label_counts(query), label_counts(tree));
+    let nodes = |label: &LabelId| LabelNodes {
+        label: names[label.get() as usize],
+        query: query_labels.get(label).copied().unwrap_or_default(),
+        tree: tree_labels.get(label).copied().unwrap_or_default(),
+    };
+    let mut shared = query_labels
+        .keys()
+        .filter(|label| tree_labels.contains_key(label))
+        .map(nodes)
+        .collect::<Vec<_>>();
+    shared.sort_by_key(|label| label.label);
+
+    let mut decisions = vec![];
+    for &method in methods {
+        let prefix = match method {
+            LBM::Lblint => Some(
+                InvertedListLabelPostorderIndex::index_tree(query, label_dict)?
+                    .get_sorted_nodes(ordering)
+                    .into_iter()
+                    .take(k + 1)
+                    .map(|(label, _)| nodes(label))
+                    .collect(),
+            ),
+            LBM::Structural => Some(
+                LabelSetConverter::with_weights(options.region_weights)
+                    .create_single(query)?
+                    .get_prefix(ordering, k)
+                    .into_iter()
+                    .map(|set| nodes(&set.base.id))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let bound = pairs::supports(method)
+            .then(|| pairs::pair_bound(method, query, tree, label_dict, k, options))
+            .transpose()?;
+        decisions.push(MethodDecision {
+            method,
+            prefix,
+            bound,
+        });
+    }
+    Ok(Explanation {
+        query_id,
+        tree_id,
+        k,
+        sizes: (query.count(), tree.count()),
+        shared,
+        methods: decisions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{get_frequency_ordering, parse_single};
+
+    #[test]
+    fn test_explain_pair() {
+        assert_eq!(parse_pair("3, 17"), Ok((3, 17)));
+        assert!(parse_pair("3").is_err());
+
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b}{c}{c}}".to_owned(), &mut ld);
+        let query = parse_single("{a{d}{c}}".to_owned(), &mut ld);
+        let ordering = get_frequency_ordering(&ld);
+        let methods = [LowerBoundMethods::Lblint, LowerBoundMethods::Bib];
+        let options = pairs::BoundOptions::default();
+        let explanation = explain(
+            &methods,
+            (0, &query),
+            (5, &tree),
+            1,
+            &ordering,
+            &ld,
+            &options,
+        )
+        .unwrap();
+        assert!(explanation.in_size_window());
+        assert_eq!(
+            explanation.shared,
+            [
+                LabelNodes {
+                    label: "a",
+                    query: 1,
+                    tree: 1
+                },
+                LabelNodes {
+                    label: "c",
+                    query: 1,
+                    tree: 2
+                }
+            ]
+        );
+        // d and a are the rarest labels, the tree has no d
+        let prefix = explanation.methods[0].prefix.as_ref().unwrap();
+        assert_eq!(prefix.len(), 2);
+        assert_eq!(explanation.methods[0].bound, Some(2));
+        assert_eq!(explanation.methods[1].bound, None);
+        let text = explanation.to_string();
+        assert!(
+            text.contains("size window: difference 1 <= 1, kept"),
+            "{text}"
+        );
+        assert!(text.contains("Lblint bound: 2 > 1, pruned"), "{text}");
+        assert!(text.contains("Bib bound: not explained"), "{text}");
+
+        // without the weight of b the tree is as large as the query and within the threshold
+        let path = std::env::temp_dir().join(format!("explain-weights-{}.tsv", std::process::id()));
+        std::fs::write(&path, "b\t0\n").unwrap();
+        let weights = crate::label_weights::LabelWeights::read(&path, &ld).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let options = pairs::BoundOptions {
+            run: crate::lb::RunConfig {
+                label_weights: Some(&weights),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let explanation = explain(
+            &methods,
+            (0, &query),
+            (5, &tree),
+            1,
+            &ordering,
+            &ld,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(explanation.methods[0].bound, Some(1));
+    }
+}
@@ -0,0 +1,121 @@
+//! Export of trees for other tree edit distance tools.
+//!
+//! APTED and the similarity join tools of tree-similarity read one tree per line in bracket
+//! notation and number trees by their line. Trees are exported in the size sorted order of
+//! this tool, so line numbers are the tree ids of candidate and result files and pairs of
+//! both tools can be compared directly. Labels are written as read, including escaped
+//! braces, label ids are written for tools without escapes. JSON and Newick lines with
+//! unescaped labels are for tree libraries that do not read bracket notation.
+use crate::parsing::{
+    label_names, tree_to_labeled_bracket, tree_to_string, LabelDict, ParsedTree, TreeOutput,
+};
+use clap::ValueEnum;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Bracket notation with the labels of the input
+    Bracket,
+    /// Bracket notation with numeric label ids, the same labels get the same ids in the
+    /// dataset and the queries
+    LabelIds,
+    /// One JSON object per tree, `{"label":"a","children":[...]}`
+    Json,
+    /// One Newick tree per line, `((c,d)b,e)a;`
+    Newick,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Bracket | ExportFormat::LabelIds => "bracket",
+            ExportFormat::Json => "jsonl",
+            ExportFormat::Newick => "nwk",
+        }
+    }
+}
+
+/// Tree in the format with the names of [`label_names`], an empty tree is an empty string
+pub fn export_tree(tree: &ParsedTree, names: &[&str], format: ExportFormat) -> String {
+    match format {
+        _ if tree.is_empty() => String::new(),
+        ExportFormat::Bracket => tree_to_labeled_bracket(tree, names),
+        ExportFormat::LabelIds => tree_to_string(tree, TreeOutput::BracketNotation),
+        ExportFormat::Json => tree_to_string(tree, TreeOutput::Json(names)),
+        ExportFormat::Newick => tree_to_string(tree, TreeOutput::Newick(names)),
+    }
+}
+
+/// Lines of the exported trees in their order, empty trees are written as empty lines
+pub fn export_trees<'a>(
+    trees: impl IntoIterator<Item = &'a ParsedTree>,
+    label_dict: &LabelDict,
+    format: ExportFormat,
+) -> Vec<String> {
+    let names = label_names(label_dict);
+    trees
+        .into_iter()
+        .map(|tree| export_tree(tree, &names, format))
+        .collect()
+}
+
+/// Line of a pair of trees, quoted and separated by a comma in bracket notation, a JSON
+/// array of both trees or both Newick trees separated by a space
+pub fn export_pair(
+    t1: &ParsedTree,
+    t2: &ParsedTree,
+    names: &[&str],
+    format: ExportFormat,
+) -> String {
+    let (t1, t2) = (
+        export_tree(t1, names, format),
+        export_tree(t2, names, format),
+    );
+    match format {
+        ExportFormat::Bracket | ExportFormat::LabelIds => format!("\"{t1}\",\"{t2}\""),
+        ExportFormat::Json => format!("[{t1},{t2}]"),
+        ExportFormat::Newick => format!("{t1} {t2}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_export_trees() {
+        let mut ld = LabelDict::default();
+        let trees = [r"{a{b\{c}}", "{b{a}{a}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        assert_eq!(
+            export_trees(&trees, &ld, ExportFormat::Bracket),
+            [r"{a{b\{c}}", "{b{a}{a}}"]
+        );
+        assert_eq!(
+            export_trees(&trees, &ld, ExportFormat::LabelIds),
+            ["{1{2}}", "{3{1}{1}}"]
+        );
+
+        // exported trees parse back to the same trees
+        let mut exported_ld = LabelDict::default();
+        for (tree, line) in trees
+            .iter()
+            .zip(export_trees(&trees, &ld, ExportFormat::Bracket))
+        {
+            assert_eq!(parse_single(line, &mut exported_ld), *tree);
+        }
+
+        assert_eq!(
+            export_trees(&trees, &ld, ExportFormat::Newick),
+            ["(b{c)a;", "(a,a)b;"]
+        );
+        let names = label_names(&ld);
+        assert_eq!(
+            export_pair(&trees[0], &trees[1], &names, ExportFormat::LabelIds),
+            r#""{1{2}}","{3{1}{1}}""#
+        );
+        assert_eq!(
+            export_pair(&trees[1], &trees[1], &names, ExportFormat::Json),
+            r#"[{"label":"b","children":[{"label":"a"},{"label":"a"}]},{"label":"b","children":[{"label":"a"},{"label":"a"}]}]"#
+        );
+    }
+}
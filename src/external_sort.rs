@@ -0,0 +1,182 @@
+//! Sorting candidate pairs that do not fit in memory.
+//!
+//! Pairs are buffered until the buffer reaches the memory limit, then the buffer is
+//! sorted in parallel and spilled to a temporary file as a run of little endian u64
+//! pairs. The sorted pairs are a k-way merge of the runs and the last buffer, holding one
+//! pair of every run at a time. Without a limit nothing is spilled and the pairs are
+//! sorted once in memory, as before.
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type Pair = (usize, usize);
+
+const PAIR_BYTES: usize = size_of::<Pair>();
+/// Smallest run, lower limits still buffer this many pairs
+const MIN_RUN_PAIRS: usize = 1 << 12;
+
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary file of a sorted run, removed when dropped
+struct RunFile(PathBuf);
+
+impl Drop for RunFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub struct PairSorter {
+    buffer: Vec<Pair>,
+    run_pairs: usize,
+    runs: Vec<RunFile>,
+}
+
+impl PairSorter {
+    /// Pairs beyond `memory_limit` bytes are spilled to disk, `None` sorts in memory
+    pub fn new(memory_limit: Option<usize>) -> Self {
+        let run_pairs =
+            memory_limit.map_or(usize::MAX, |limit| (limit / PAIR_BYTES).max(MIN_RUN_PAIRS));
+        Self {
+            buffer: vec![],
+            run_pairs,
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, pair: Pair) -> Result<(), std::io::Error> {
+        if self.buffer.len() == self.run_pairs {
+            self.spill()?;
+        }
+        if self.buffer.capacity() == 0 && self.run_pairs != usize::MAX {
+            self.buffer.reserve_exact(self.run_pairs);
+        }
+        self.buffer.push(pair);
+        Ok(())
+    }
+
+    /// Number of runs spilled to disk so far
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self) -> Result<(), std::io::Error> {
+        self.buffer.par_sort_unstable();
+        let run = RunFile(std::env::temp_dir().join(format!(
+            "tree-statistics-run-{}-{}.bin",
+            std::process::id(),
+            NEXT_RUN.fetch_add(1, Ordering::Relaxed)
+        )));
+        let mut w = BufWriter::new(File::create(&run.0)?);
+        for &(t1, t2) in &self.buffer {
+            w.write_all(&(t1 as u64).to_le_bytes())?;
+            w.write_all(&(t2 as u64).to_le_bytes())?;
+        }
+        w.flush()?;
+        self.runs.push(run);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Sorts the buffered pairs and merges them with the runs on disk
+    pub fn finish(mut self) -> Result<SortedPairs, std::io::Error> {
+        self.buffer.par_sort_unstable();
+        let mut sources = vec![Source::Memory(self.buffer.into_iter())];
+        for run in self.runs {
+            let reader = BufReader::new(File::open(&run.0)?);
+            sources.push(Source::Run { reader, _file: run });
+        }
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (i, source) in sources.iter_mut().enumerate() {
+            if let Some(pair) = source.next()? {
+                heap.push(Reverse((pair, i)));
+            }
+        }
+        Ok(SortedPairs { sources, heap })
+    }
+}
+
+enum Source {
+    Memory(std::vec::IntoIter<Pair>),
+    /// the file is removed when the merge is dropped
+    Run {
+        reader: BufReader<File>,
+        _file: RunFile,
+    },
+}
+
+impl Source {
+    fn next(&mut self) -> Result<Option<Pair>, std::io::Error> {
+        match self {
+            Source::Memory(pairs) => Ok(pairs.next()),
+            Source::Run { reader, .. } => {
+                let mut buf = [0u8; 16];
+                match reader.read_exact(&mut buf) {
+                    Ok(()) => {
+                        let t1 = u64::from_le_bytes(buf[..8].try_into().unwrap());
+                        let t2 = u64::from_le_bytes(buf[8..].try_into().unwrap());
+                        Ok(Some((t1 as usize, t2 as usize)))
+                    }
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// Pairs in increasing order, duplicates are kept. Run files are removed when dropped.
+pub struct SortedPairs {
+    sources: Vec<Source>,
+    heap: BinaryHeap<Reverse<(Pair, usize)>>,
+}
+
+impl Iterator for SortedPairs {
+    type Item = Result<Pair, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((pair, source)) = self.heap.pop()?;
+        match self.sources[source].next() {
+            Ok(Some(next)) => self.heap.push(Reverse((next, source))),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(pair))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_external_sort() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(7);
+        let pairs = (0..3 * MIN_RUN_PAIRS + 5)
+            .map(|_| (rng.gen_range(0..200), rng.gen_range(0..200)))
+            .collect::<Vec<_>>();
+        let mut expected = pairs.clone();
+        expected.sort_unstable();
+
+        // any limit below a run spills every MIN_RUN_PAIRS pairs
+        for limit in [None, Some(1)] {
+            let mut sorter = PairSorter::new(limit);
+            for &pair in &pairs {
+                sorter.push(pair).unwrap();
+            }
+            assert_eq!(sorter.runs(), if limit.is_some() { 3 } else { 0 });
+            let paths = sorter.runs.iter().map(|r| r.0.clone()).collect::<Vec<_>>();
+            let sorted = sorter.finish().unwrap();
+            let sorted = sorted.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(sorted, expected);
+            assert!(paths.iter().all(|path| !path.exists()));
+        }
+        assert_eq!(PairSorter::new(Some(0)).finish().unwrap().count(), 0);
+    }
+}
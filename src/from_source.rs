@@ -0,0 +1,143 @@
+//! Source files parsed into syntax trees with tree-sitter, built with the `tree-sitter` feature.
+//!
+//! Each file becomes one tree, labels are kinds of named syntax nodes, e.g. `function_item`
+//! or `identifier`. Anonymous nodes such as punctuation and keywords are left out, so trees
+//! of code clones differ only in their structure and kinds of expressions. Trees go through
+//! the same tokens as bracket notation, labels get the same ids as in a bracket dataset.
+use crate::parsing::{self, LabelDict, ParsedTree, TreeParser};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::path::Path;
+use tree_sitter::{Parser, Tree};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Language {
+    C,
+    Go,
+    Java,
+    Javascript,
+    Python,
+    Rust,
+}
+
+impl Language {
+    fn grammar(self) -> tree_sitter::Language {
+        match self {
+            Self::C => tree_sitter_c::LANGUAGE.into(),
+            Self::Go => tree_sitter_go::LANGUAGE.into(),
+            Self::Java => tree_sitter_java::LANGUAGE.into(),
+            Self::Javascript => tree_sitter_javascript::LANGUAGE.into(),
+            Self::Python => tree_sitter_python::LANGUAGE.into(),
+            Self::Rust => tree_sitter_rust::LANGUAGE.into(),
+        }
+    }
+
+    /// Extensions of files picked up when searching a directory
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::C => &["c", "h"],
+            Self::Go => &["go"],
+            Self::Java => &["java"],
+            Self::Javascript => &["js", "mjs", "cjs", "jsx"],
+            Self::Python => &["py"],
+            Self::Rust => &["rs"],
+        }
+    }
+
+    fn parser(self) -> Parser {
+        let mut parser = Parser::new();
+        parser
+            .set_language(&self.grammar())
+            .expect("Grammar is incompatible with the tree-sitter version");
+        parser
+    }
+}
+
+/// Bracket notation tokens of named nodes of a syntax tree
+fn syntax_tokens(tree: &Tree) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        if node.is_named() {
+            tokens.push("{".to_owned());
+            tokens.push(node.kind().to_owned());
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        // close the node and its ancestors until one has a next sibling
+        loop {
+            if cursor.node().is_named() {
+                tokens.push("}".to_owned());
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return tokens;
+            }
+        }
+    }
+}
+
+/// Parses source files of `path` into one tree per file and adds their labels into `label_dict`
+pub fn parse_sources(
+    path: &Path,
+    language: Language,
+    label_dict: &mut LabelDict,
+) -> Result<Vec<ParsedTree>, anyhow::Error> {
+    let files = parsing::dataset_files(path, language.extensions())?;
+    let collection_tree_tokens = files
+        .par_iter()
+        .map_init(
+            || language.parser(),
+            |parser, file| {
+                let source = std::fs::read(file)?;
+                let tree = parser
+                    .parse(&source, None)
+                    .ok_or_else(|| anyhow::anyhow!("Unable to parse {}", file.display()))?;
+                Ok(syntax_tokens(&tree))
+            },
+        )
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    parsing::update_label_dict(&collection_tree_tokens, label_dict);
+    let trees = collection_tree_tokens
+        .par_iter()
+        .map_init(TreeParser::default, |parser, tokens| {
+            parser.parse(tokens, label_dict)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_nodes_become_labels() {
+        let tree = Language::Rust.parser().parse("fn f() {}", None).unwrap();
+        assert_eq!(
+            syntax_tokens(&tree).concat(),
+            "{source_file{function_item{identifier}{parameters}{block}}}"
+        );
+
+        let dir = std::env::temp_dir().join("tree-statistics-sources");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.py"), "def f(x):\n    return x + 1\n").unwrap();
+        std::fs::write(dir.join("nested/b.py"), "def g(y):\n    return y * 2\n").unwrap();
+        std::fs::write(dir.join("c.rs"), "fn f() {}").unwrap();
+        let mut label_dict = LabelDict::new();
+        let trees = parse_sources(&dir, Language::Python, &mut label_dict).unwrap();
+        assert_eq!(trees.len(), 2);
+        // both functions have the same shape and node kinds
+        assert_eq!(
+            parsing::tree_to_string(&trees[0], parsing::TreeOutput::BracketNotation),
+            parsing::tree_to_string(&trees[1], parsing::TreeOutput::BracketNotation)
+        );
+        assert!(label_dict.contains_key("function_definition"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
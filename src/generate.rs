@@ -0,0 +1,142 @@
+//! Synthesis of random tree collections with controllable shape and label distribution.
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SizeDistribution {
+    /// Tree sizes are uniform between min and max size
+    Uniform,
+    /// Tree sizes are normally distributed around the middle of min and max size
+    Normal,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub count: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub size_distribution: SizeDistribution,
+    /// Maximal number of children of a node, 0 means unlimited
+    pub max_fanout: usize,
+    /// Probability of attaching a new node below the last inserted node,
+    /// 0 produces bushy trees and 1 produces paths
+    pub depth_bias: f64,
+    /// Number of distinct labels
+    pub alphabet: usize,
+    /// Zipf exponent of label frequencies, 0 means uniform labels
+    pub zipf: f64,
+    pub seed: u64,
+}
+
+/// Cumulative distribution of Zipf weights over label ranks
+fn zipf_cdf(alphabet: usize, exponent: f64) -> Vec<f64> {
+    let mut cdf = Vec::with_capacity(alphabet);
+    let mut total = 0.0;
+    for rank in 1..=alphabet {
+        total += 1.0 / (rank as f64).powf(exponent);
+        cdf.push(total);
+    }
+    cdf.iter_mut().for_each(|c| *c /= total);
+    cdf
+}
+
+fn sample_size(config: &GeneratorConfig, rng: &mut impl Rng) -> usize {
+    let (min, max) = (
+        config.min_size.max(1),
+        config.max_size.max(config.min_size.max(1)),
+    );
+    match config.size_distribution {
+        SizeDistribution::Uniform => rng.gen_range(min..=max),
+        SizeDistribution::Normal => {
+            // Box-Muller transform
+            let (u1, u2): (f64, f64) = (rng.gen_range(f64::EPSILON..1.0), rng.gen());
+            let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+            let mean = (min + max) as f64 / 2.0;
+            let sd = (max - min) as f64 / 6.0;
+            ((mean + z * sd).round() as usize).clamp(min, max)
+        }
+    }
+}
+
+/// Generates a single random tree in bracket notation
+fn generate_tree(config: &GeneratorConfig, cdf: &[f64], rng: &mut impl Rng) -> String {
+    let size = sample_size(config, rng);
+    let mut children: Vec<Vec<usize>> = vec![vec![]; size];
+    for node in 1..size {
+        let mut parent = if rng.gen_bool(config.depth_bias.clamp(0.0, 1.0)) {
+            node - 1
+        } else {
+            rng.gen_range(0..node)
+        };
+        if config.max_fanout > 0 {
+            // fall back to the nearest earlier node with a free slot, the last node always has one
+            while children[parent].len() >= config.max_fanout {
+                parent = (parent + 1) % node;
+            }
+        }
+        children[parent].push(node);
+    }
+
+    let labels = (0..size)
+        .map(|_| {
+            let u: f64 = rng.gen();
+            cdf.partition_point(|c| *c < u).min(cdf.len() - 1)
+        })
+        .collect::<Vec<_>>();
+
+    let mut bracket = String::with_capacity(size * 6);
+    let mut stack = vec![(0, false)];
+    while let Some((node, visited)) = stack.pop() {
+        if visited {
+            bracket.push('}');
+            continue;
+        }
+        bracket.push_str(&format!("{{l{}", labels[node]));
+        stack.push((node, true));
+        stack.extend(children[node].iter().rev().map(|c| (*c, false)));
+    }
+    bracket
+}
+
+/// Generates a collection of random trees in bracket notation
+pub fn generate(config: &GeneratorConfig) -> Vec<String> {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(config.seed);
+    let cdf = zipf_cdf(config.alphabet.max(1), config.zipf);
+    (0..config.count)
+        .map(|_| generate_tree(config, &cdf, &mut rng))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_generated_trees_respect_config() {
+        let config = GeneratorConfig {
+            count: 20,
+            min_size: 5,
+            max_size: 15,
+            size_distribution: SizeDistribution::Normal,
+            max_fanout: 2,
+            depth_bias: 0.3,
+            alphabet: 4,
+            zipf: 1.0,
+            seed: 1,
+        };
+        let trees = generate(&config);
+        assert_eq!(trees, generate(&config));
+        let mut ld = LabelDict::default();
+        for t in trees {
+            let tree = parse_single(t, &mut ld);
+            assert!((5..=15).contains(&tree.count()));
+            assert!(tree.iter().all(|n| {
+                let nid = tree.get_node_id(n).unwrap();
+                nid.children(&tree).count() <= 2
+            }));
+        }
+        assert!(ld.len() <= 4);
+    }
+}
@@ -0,0 +1,144 @@
+//! Golden snapshots of run outputs for regression testing.
+//!
+//! A snapshot holds the outputs of a run that only change with the behavior of the tool:
+//! the collection summary, the number of candidates of every method and precisions.
+//! Timings are never part of it. The first run with `--golden DIR` records the snapshot
+//! of its command in `DIR/<command>.json`, later runs compare with it and fail listing
+//! every value that drifted, so a new version can be checked against the results of the
+//! previous one on the user's own datasets.
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot(BTreeMap<String, String>);
+
+impl Snapshot {
+    /// Values are compared as written, floats are formatted by the caller with a fixed
+    /// number of decimals
+    pub fn record(&mut self, key: impl Into<String>, value: impl Display) {
+        self.0.insert(key.into(), value.to_string());
+    }
+
+    /// Values differing from the golden snapshot, including values only one of them has
+    pub fn drift(&self, golden: &Snapshot) -> Vec<Drift> {
+        let mut keys = self.0.keys().chain(golden.0.keys()).collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+        keys.into_iter()
+            .filter_map(|key| {
+                let (expected, current) = (golden.0.get(key), self.0.get(key));
+                (expected != current).then(|| Drift {
+                    key: key.clone(),
+                    golden: expected.cloned(),
+                    current: current.cloned(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    pub key: String,
+    pub golden: Option<String>,
+    pub current: Option<String>,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let missing = "missing".to_owned();
+        write!(
+            f,
+            "{}: golden {}, now {}",
+            self.key,
+            self.golden.as_ref().unwrap_or(&missing),
+            self.current.as_ref().unwrap_or(&missing)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Recorded(PathBuf),
+    /// number of values equal to the golden snapshot
+    Matched(usize),
+}
+
+/// Records the snapshot when the directory has none for the command or `update` is set,
+/// otherwise fails with a validation error on drift
+pub fn check(
+    dir: &Path,
+    command: &str,
+    snapshot: &Snapshot,
+    update: bool,
+) -> Result<Outcome, anyhow::Error> {
+    let path = dir.join(format!("{command}.json"));
+    if update || !path.exists() {
+        std::fs::create_dir_all(dir)?;
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&path)?), snapshot)?;
+        return Ok(Outcome::Recorded(path));
+    }
+    let golden: Snapshot = serde_json::from_reader(BufReader::new(File::open(&path)?))
+        .map_err(|e| Error::Parse(format!("Invalid golden file {}: {e}", path.display())))?;
+    let drift = snapshot.drift(&golden);
+    if !drift.is_empty() {
+        let lines = drift.iter().map(Drift::to_string).collect::<Vec<_>>();
+        anyhow::bail!(Error::Validation(format!(
+            "{} values drifted from {}:\n{}",
+            drift.len(),
+            path.display(),
+            lines.join("\n")
+        )));
+    }
+    Ok(Outcome::Matched(snapshot.0.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_golden_check() {
+        let dir = std::env::temp_dir().join(format!("golden-{}", std::process::id()));
+        let mut snapshot = Snapshot::default();
+        snapshot.record("Sed candidates", 120);
+        snapshot.record("Sed precision", format!("{:.6}", 0.5));
+        assert!(matches!(
+            check(&dir, "lower-bound", &snapshot, false).unwrap(),
+            Outcome::Recorded(_)
+        ));
+        assert_eq!(
+            check(&dir, "lower-bound", &snapshot, false).unwrap(),
+            Outcome::Matched(2)
+        );
+
+        let mut changed = snapshot.clone();
+        changed.record("Sed candidates", 121);
+        changed.record("Lblint candidates", 300);
+        let drift = changed.drift(&snapshot);
+        assert_eq!(drift.len(), 2);
+        assert_eq!(
+            drift[0].to_string(),
+            "Lblint candidates: golden missing, now 300"
+        );
+        assert_eq!(drift[1].to_string(), "Sed candidates: golden 120, now 121");
+        let error = check(&dir, "lower-bound", &changed, false).unwrap_err();
+        assert_eq!(
+            crate::error::exit_code(&error),
+            crate::error::EXIT_VALIDATION
+        );
+
+        // updating records the new snapshot as golden
+        check(&dir, "lower-bound", &changed, true).unwrap();
+        assert_eq!(
+            check(&dir, "lower-bound", &changed, false).unwrap(),
+            Outcome::Matched(3)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! gRPC server answering streams of similarity queries, interface is described in
+//! `proto/tree_statistics.proto`.
+//!
+//! Each query stream is answered in order, queries are evaluated on the blocking pool
+//! and use rayon internally. Failed queries are answered with `error` set and do not
+//! end the stream.
+use crate::parsing::{LabelDict, ParsedTree};
+use crate::query_service::{method_name, QueryService};
+use crate::LowerBoundMethods;
+use proto::tree_similarity_server::{TreeSimilarity, TreeSimilarityServer};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+/// Messages of `proto/tree_statistics.proto`, keep tags in sync with it
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueryRequest {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(string, tag = "2")]
+        pub tree: String,
+        #[prost(uint64, tag = "3")]
+        pub k: u64,
+        #[prost(string, tag = "4")]
+        pub method: String,
+        #[prost(bool, tag = "5")]
+        pub verify: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Match {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub distance: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueryResponse {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(string, tag = "2")]
+        pub method: String,
+        #[prost(uint64, repeated, tag = "3")]
+        pub candidates: Vec<u64>,
+        #[prost(message, repeated, tag = "4")]
+        pub matches: Vec<Match>,
+        #[prost(uint64, tag = "5")]
+        pub filter_us: u64,
+        #[prost(uint64, tag = "6")]
+        pub verify_us: u64,
+        #[prost(string, tag = "7")]
+        pub error: String,
+        #[prost(bool, tag = "8")]
+        pub timed_out: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MethodIndex {
+        #[prost(string, tag = "1")]
+        pub method: String,
+        #[prost(uint64, tag = "2")]
+        pub bytes: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsResponse {
+        #[prost(uint64, tag = "1")]
+        pub trees: u64,
+        #[prost(uint64, tag = "2")]
+        pub labels: u64,
+        #[prost(message, repeated, tag = "3")]
+        pub indexes: Vec<MethodIndex>,
+    }
+
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/tree_statistics.TreeSimilarity.rs"
+    ));
+}
+
+/// Number of answered queries buffered per stream before evaluation waits for the client
+const RESPONSE_BUFFER: usize = 64;
+
+fn answer(service: &QueryService, request: proto::QueryRequest) -> proto::QueryResponse {
+    let method = Some(request.method.as_str()).filter(|m| !m.is_empty());
+    match service.query(&request.tree, request.k as usize, method, request.verify) {
+        Ok(result) => proto::QueryResponse {
+            id: request.id,
+            method: method_name(result.method),
+            candidates: result.candidates.into_iter().map(|c| c as u64).collect(),
+            matches: result
+                .matches
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(id, distance)| proto::Match {
+                    id: id as u64,
+                    distance: distance as u64,
+                })
+                .collect(),
+            filter_us: result.filter_time.as_micros() as u64,
+            verify_us: result.verify_time.map_or(0, |t| t.as_micros() as u64),
+            error: String::new(),
+            timed_out: result.timed_out,
+        },
+        Err(e) => proto::QueryResponse {
+            id: request.id,
+            error: e.to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+fn stats(service: &QueryService) -> Result<proto::StatsResponse, anyhow::Error> {
+    let indexes = service
+        .methods()
+        .iter()
+        .map(|m| {
+            Ok(proto::MethodIndex {
+                method: method_name(*m),
+                bytes: service.index_bytes(*m)? as u64,
+            })
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+    Ok(proto::StatsResponse {
+        trees: service.trees() as u64,
+        labels: service.labels() as u64,
+        indexes,
+    })
+}
+
+struct Server {
+    service: Arc<QueryService>,
+}
+
+#[tonic::async_trait]
+impl TreeSimilarity for Server {
+    type QueryStream = Pin<Box<dyn Stream<Item = Result<proto::QueryResponse, Status>> + Send>>;
+
+    async fn query(
+        &self,
+        request: Request<Streaming<proto::QueryRequest>>,
+    ) -> Result<Response<Self::QueryStream>, Status> {
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(RESPONSE_BUFFER);
+        let service = self.service.clone();
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let response = match request {
+                    Ok(request) => {
+                        let service = service.clone();
+                        tokio::task::spawn_blocking(move || answer(&service, request))
+                            .await
+                            .map_err(|e| Status::internal(e.to_string()))
+                    }
+                    Err(status) => Err(status),
+                };
+                let failed = response.is_err();
+                if tx.send(response).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<proto::StatsRequest>,
+    ) -> Result<Response<proto::StatsResponse>, Status> {
+        stats(&self.service)
+            .map(Response::new)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// Builds indexes of `methods` and serves queries on `listen` until the process is killed,
+/// filtering of a query stops after `timeout`.
+pub fn run(
+    trees: Vec<ParsedTree>,
+    label_dict: LabelDict,
+    methods: &[LowerBoundMethods],
+    listen: SocketAddr,
+    cache_size: usize,
+    timeout: Option<Duration>,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let start = Instant::now();
+    let service = Arc::new(
+        QueryService::new(trees, label_dict, methods)?
+            .with_cache(cache_size)
+            .with_timeout(timeout),
+    );
+    if !quiet {
+        println!(
+            "Built indexes in {}ms, listening on {listen}",
+            start.elapsed().as_millis()
+        );
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(TreeSimilarityServer::new(Server { service }))
+                .serve(listen),
+        )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_answer_and_stats() {
+        let mut ld = LabelDict::default();
+        let mut trees = ["{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        trees.sort_by_key(|t| t.count());
+        let service = QueryService::new(trees, ld, &[LowerBoundMethods::Sed]).unwrap();
+        let request = proto::QueryRequest {
+            id: 7,
+            tree: "{a{b}{q}}".to_owned(),
+            k: 1,
+            method: String::new(),
+            verify: true,
+        };
+        let response = answer(&service, request.clone());
+        assert_eq!(response.id, 7);
+        assert_eq!(response.method, "sed");
+        assert_eq!(response.candidates, vec![0, 1]);
+        assert_eq!(response.matches.len(), 2);
+        assert!(response.error.is_empty());
+
+        let failed = answer(
+            &service,
+            proto::QueryRequest {
+                method: "lblint".to_owned(),
+                ..request
+            },
+        );
+        assert_eq!(failed.id, 7);
+        assert!(!failed.error.is_empty());
+
+        let stats = stats(&service).unwrap();
+        assert_eq!(stats.trees, 3);
+        assert_eq!(stats.indexes.len(), 1);
+        assert!(stats.indexes[0].bytes > 0);
+    }
+}
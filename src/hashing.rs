@@ -0,0 +1,138 @@
+//! Stable fingerprints of trees and of all their subtrees.
+//!
+//! Fingerprints are computed bottom-up from the label of a node and the fingerprints of
+//! its children in order, so equal subtrees get equal fingerprints wherever they occur and
+//! however their arenas are laid out. Mixing uses fixed constants only, the fingerprint of
+//! a tree is the same across runs, platforms and builds. Labels are mixed by their
+//! [`LabelId`], trees are only comparable under the same label dictionary.
+use crate::parsing::{LabelId, ParsedTree};
+use indextree::{NodeEdge, NodeId};
+
+/// Two independently seeded 64-bit lanes
+pub type Fingerprint = u128;
+
+const SEEDS: [u64; 2] = [0x9e37_79b9_7f4a_7c15, 0xc2b2_ae3d_27d4_eb4f];
+const CHILD_MULTIPLIER: u64 = 0x0100_0000_01b3;
+
+/// splitmix64 finalizer
+#[inline(always)]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[inline(always)]
+fn lanes(fingerprint: Fingerprint) -> [u64; 2] {
+    [fingerprint as u64, (fingerprint >> 64) as u64]
+}
+
+#[inline(always)]
+fn from_lanes([low, high]: [u64; 2]) -> Fingerprint {
+    low as Fingerprint | (high as Fingerprint) << 64
+}
+
+/// Fingerprint of a node without children
+fn of_label(label: LabelId) -> Fingerprint {
+    from_lanes(SEEDS.map(|seed| mix(seed ^ label.get() as u64)))
+}
+
+/// Appends a child, the order of children changes the fingerprint
+fn with_child(node: Fingerprint, child: Fingerprint) -> Fingerprint {
+    let (node, child) = (lanes(node), lanes(child));
+    from_lanes(std::array::from_fn(|i| {
+        mix(node[i].wrapping_mul(CHILD_MULTIPLIER) ^ child[i])
+    }))
+}
+
+pub trait SubtreeFingerprints {
+    /// Fingerprints of the subtrees of all nodes in postorder, the root comes last
+    fn subtree_fingerprints(&self) -> Vec<(NodeId, Fingerprint)>;
+
+    /// Fingerprint of the whole tree, 0 for an empty tree
+    fn fingerprint(&self) -> Fingerprint {
+        self.subtree_fingerprints()
+            .last()
+            .map_or(0, |(_, fingerprint)| *fingerprint)
+    }
+}
+
+impl SubtreeFingerprints for ParsedTree {
+    fn subtree_fingerprints(&self) -> Vec<(NodeId, Fingerprint)> {
+        let Some(root) = self.iter().next() else {
+            return vec![];
+        };
+        let root = self.get_node_id(root).unwrap();
+        // fingerprints of unfinished nodes on the path from the root
+        let mut open: Vec<Fingerprint> = vec![];
+        let mut fingerprints = Vec::with_capacity(self.count());
+        for edge in root.traverse(self) {
+            match edge {
+                NodeEdge::Start(nid) => open.push(of_label(*self[nid].get())),
+                NodeEdge::End(nid) => {
+                    let fingerprint = open.pop().unwrap();
+                    if let Some(parent) = open.last_mut() {
+                        *parent = with_child(*parent, fingerprint);
+                    }
+                    fingerprints.push((nid, fingerprint));
+                }
+            }
+        }
+        fingerprints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, random_bracket_tree, LabelDict};
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+    use rustc_hash::FxHashMap;
+
+    #[test]
+    fn test_subtree_fingerprints() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b{c}}{d}{b{c}}}".to_owned(), &mut ld);
+        let fingerprints = tree.subtree_fingerprints();
+        assert_eq!(fingerprints.len(), 6);
+        // both {b{c}} subtrees and both {c} leaves are equal
+        assert_eq!(fingerprints[0].1, fingerprints[3].1);
+        assert_eq!(fingerprints[1].1, fingerprints[4].1);
+        assert_ne!(fingerprints[1].1, fingerprints[2].1);
+        assert_eq!(tree.fingerprint(), fingerprints[5].1);
+
+        let swapped = parse_single("{a{d}{b{c}}{b{c}}}".to_owned(), &mut ld);
+        assert_ne!(tree.fingerprint(), swapped.fingerprint());
+        let again = parse_single("{a{b{c}}{d}{b{c}}}".to_owned(), &mut ld);
+        assert_eq!(tree.fingerprint(), again.fingerprint());
+        assert_eq!(ParsedTree::new().fingerprint(), 0);
+
+        // fingerprints must not change between builds
+        let mut ld = LabelDict::default();
+        let fixed = parse_single("{a{b}}".to_owned(), &mut ld);
+        assert_eq!(
+            format!("{:032x}", fixed.fingerprint()),
+            "91e494058b21e3da95b031e8abb19ac9"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_collisions() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(3);
+        let mut ld = LabelDict::default();
+        let mut by_fingerprint = FxHashMap::default();
+        for _ in 0..30_000 {
+            // random tree of 4 to 12 nodes over 4 labels
+            let size = rng.gen_range(4..=12);
+            let input = random_bracket_tree(&mut rng, size, 4);
+            let tree = parse_single(input.clone(), &mut ld);
+            // equal fingerprints only for equal trees
+            let known = by_fingerprint
+                .entry(tree.fingerprint())
+                .or_insert(input.clone());
+            assert_eq!(*known, input);
+        }
+        assert!(by_fingerprint.len() > 20_000);
+    }
+}
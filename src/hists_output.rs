@@ -0,0 +1,160 @@
+//! Output files of the histograms written by `statistics --hists`.
+//!
+//! Every histogram is one file of values, one per line, named `<prefix><name>.csv` in
+//! the output directory, `.csv.gz` when compressed. Existing files fail the command,
+//! are overwritten or appended to. Appended gzip files get another gzip member, which
+//! `zcat` and gzip readers read as one stream.
+use clap::ValueEnum;
+use std::fmt::Display;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExistingFiles {
+    /// Stop before writing anything if any of the files exists
+    Fail,
+    /// Replace existing files
+    #[default]
+    Overwrite,
+    /// Append to existing files, e.g. histograms of several collections
+    Append,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistogramFiles {
+    dir: PathBuf,
+    prefix: String,
+    existing: ExistingFiles,
+    gzip: bool,
+}
+
+impl HistogramFiles {
+    /// Creates the output directory if it does not exist
+    pub fn new(
+        dir: impl AsRef<Path>,
+        prefix: String,
+        existing: ExistingFiles,
+        gzip: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let dir = dir.as_ref().to_path_buf();
+        if dir.exists() && !dir.is_dir() {
+            anyhow::bail!("Histogram output {} is not a directory", dir.display());
+        }
+        #[cfg(not(feature = "gzip"))]
+        if gzip {
+            anyhow::bail!("Compressing histograms requires building with the `gzip` feature");
+        }
+        create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            prefix,
+            existing,
+            gzip,
+        })
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        let extension = if self.gzip { "csv.gz" } else { "csv" };
+        self.dir.join(format!("{}{name}.{extension}", self.prefix))
+    }
+
+    /// Fails if files of the histograms exist and should not be touched
+    pub fn check(&self, names: &[&str]) -> Result<(), anyhow::Error> {
+        if self.existing != ExistingFiles::Fail {
+            return Ok(());
+        }
+        let existing = names
+            .iter()
+            .map(|name| self.path(name))
+            .filter(|path| path.exists())
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+        if !existing.is_empty() {
+            anyhow::bail!("Histogram files exist: {}", existing.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Writes the values of the histogram one per line
+    pub fn write<T: Display>(
+        &self,
+        name: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> Result<(), anyhow::Error> {
+        let path = self.path(name);
+        let mut options = File::options();
+        match self.existing {
+            ExistingFiles::Fail => options.write(true).create_new(true),
+            ExistingFiles::Overwrite => options.write(true).create(true).truncate(true),
+            ExistingFiles::Append => options.append(true).create(true),
+        };
+        let w = BufWriter::new(options.open(&path)?);
+        if self.gzip {
+            #[cfg(feature = "gzip")]
+            {
+                let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+                write_lines(&mut encoder, values)?;
+                encoder.finish()?.flush()?;
+            }
+        } else {
+            let mut w = w;
+            write_lines(&mut w, values)?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_lines<T: Display>(
+    w: &mut impl Write,
+    values: impl IntoIterator<Item = T>,
+) -> Result<(), std::io::Error> {
+    for value in values {
+        writeln!(w, "{value}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_existing_files() {
+        let dir = std::env::temp_dir().join(format!("hists-{}", std::process::id()));
+        let files = |existing| HistogramFiles::new(&dir, "a_".to_owned(), existing, false);
+        let overwrite = files(ExistingFiles::Overwrite).unwrap();
+        overwrite.write("degrees", [1, 2]).unwrap();
+        overwrite.write("degrees", [3]).unwrap();
+        let path = dir.join("a_degrees.csv");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "3\n");
+
+        files(ExistingFiles::Append)
+            .unwrap()
+            .write("degrees", [4])
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "3\n4\n");
+
+        let fail = files(ExistingFiles::Fail).unwrap();
+        assert!(fail.check(&["depths"]).is_ok());
+        assert!(fail.check(&["depths", "degrees"]).is_err());
+        assert!(HistogramFiles::new(&path, String::new(), ExistingFiles::Fail, false).is_err());
+
+        #[cfg(feature = "gzip")]
+        {
+            use std::io::Read;
+            let gzip = HistogramFiles::new(&dir, "a_".to_owned(), ExistingFiles::Append, true);
+            let gzip = gzip.unwrap();
+            gzip.write("depths", [1]).unwrap();
+            gzip.write("depths", [2]).unwrap();
+            let file = File::open(dir.join("a_depths.csv.gz")).unwrap();
+            let mut content = String::new();
+            flate2::read::MultiGzDecoder::new(file)
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(content, "1\n2\n");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Web pages parsed into DOM trees, built with the `html` feature.
+//!
+//! Each page becomes one tree of its elements rooted in `html`, labels are tag names,
+//! optionally followed by the id and sorted classes in CSS selector form, e.g.
+//! `div#main.nav.top`. Text, comments and other attributes are left out. Braces and
+//! backslashes in labels are escaped, so trees written in bracket notation parse back.
+use crate::parsing::{self, LabelDict, ParsedTree, TreeParser};
+use rayon::prelude::*;
+use scraper::{ElementRef, Html};
+use std::path::Path;
+
+const EXTENSIONS: [&str; 3] = ["html", "htm", "xhtml"];
+
+/// Attributes included in element labels besides the tag name
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HtmlLabels {
+    pub ids: bool,
+    pub classes: bool,
+}
+
+fn escape(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        if matches!(c, '{' | '}' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn element_label(element: ElementRef, labels: HtmlLabels) -> String {
+    let element = element.value();
+    let mut label = element.name().to_owned();
+    if labels.ids {
+        if let Some(id) = element.id() {
+            label.push('#');
+            label.push_str(id);
+        }
+    }
+    if labels.classes {
+        let mut classes = element.classes().collect::<Vec<_>>();
+        classes.sort_unstable();
+        for class in classes {
+            label.push('.');
+            label.push_str(class);
+        }
+    }
+    escape(&label)
+}
+
+/// Bracket notation tokens of elements of a page
+fn page_tokens(page: &str, labels: HtmlLabels) -> Vec<String> {
+    let document = Html::parse_document(page);
+    let root = document.root_element();
+    let mut tokens = vec!["{".to_owned(), element_label(root, labels)];
+    let mut stack = vec![root.child_elements()];
+    while let Some(children) = stack.last_mut() {
+        if let Some(child) = children.next() {
+            tokens.push("{".to_owned());
+            tokens.push(element_label(child, labels));
+            stack.push(child.child_elements());
+        } else {
+            stack.pop();
+            tokens.push("}".to_owned());
+        }
+    }
+    tokens
+}
+
+/// Parses pages of `path`, a file or a directory with `.html` files, into one tree per
+/// page and adds their labels into `label_dict`
+pub fn parse_pages(
+    path: &Path,
+    labels: HtmlLabels,
+    label_dict: &mut LabelDict,
+) -> Result<Vec<ParsedTree>, anyhow::Error> {
+    let files = parsing::dataset_files(path, &EXTENSIONS)?;
+    let collection_tree_tokens = files
+        .par_iter()
+        .map(|file| {
+            let page = std::fs::read(file)?;
+            Ok(page_tokens(&String::from_utf8_lossy(&page), labels))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    parsing::update_label_dict(&collection_tree_tokens, label_dict);
+    let trees = collection_tree_tokens
+        .par_iter()
+        .map_init(TreeParser::default, |parser, tokens| {
+            parser.parse(tokens, label_dict)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_labels() {
+        let page = r#"<p>text<div id="main" class="top nav"><a href="x">link</a></div>"#;
+        assert_eq!(
+            page_tokens(page, HtmlLabels::default()).concat(),
+            "{html{head}{body{p}{div{a}}}}"
+        );
+        let labels = HtmlLabels {
+            ids: true,
+            classes: true,
+        };
+        assert_eq!(
+            page_tokens(page, labels).concat(),
+            "{html{head}{body{p}{div#main.nav.top{a}}}}"
+        );
+        let tokens = page_tokens(r#"<b class="{x}">"#, labels);
+        assert_eq!(tokens[8], r"b.\{x\}");
+
+        let mut label_dict = LabelDict::new();
+        parsing::update_label_dict(std::slice::from_ref(&tokens), &mut label_dict);
+        let tree = parsing::parse_tree(&tokens, &label_dict).unwrap();
+        let bracket = parsing::tree_to_labeled_bracket(&tree, &parsing::label_names(&label_dict));
+        assert_eq!(parsing::parse_tree_tokens(bracket).unwrap(), tokens);
+    }
+}
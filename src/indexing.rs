@@ -1,54 +1,129 @@
-use std::num::NonZeroUsize;
-
+use crate::lb::label_signature::LabelSignature;
+use crate::memory::MemoryUsage;
 use crate::parsing::{LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
 use indextree::NodeId;
 
 use itertools::Itertools;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
 use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexError {
+    #[error("tree is empty")]
+    EmptyTree,
+    #[error("root of the tree was removed")]
+    RemovedRoot,
+    #[error(
+        "tree of {0} nodes overflows the node counters of the indexes, larger trees need \
+         the `wide-counters` feature"
+    )]
+    TreeTooLarge(usize),
+}
+
+/// Signed counter of postorder ids and region sizes in the indexes, signed so differences
+/// of counters need no casts. It takes 64 bits with the `wide-counters` feature.
+#[cfg(not(feature = "wide-counters"))]
+pub type NodeCounter = i32;
+#[cfg(feature = "wide-counters")]
+pub type NodeCounter = i64;
+
+/// Converts a node count to a [`NodeCounter`], fails instead of wrapping around
+pub fn node_counter(nodes: usize) -> Result<NodeCounter, IndexError> {
+    NodeCounter::try_from(nodes).map_err(|_| IndexError::TreeTooLarge(nodes))
+}
+
+/// Root of a tree for traversals, the first node of the arena
+pub fn root_id(tree: &ParsedTree) -> Result<NodeId, IndexError> {
+    let root = tree.iter().next().ok_or(IndexError::EmptyTree)?;
+    let root_id = tree.get_node_id(root).ok_or(IndexError::RemovedRoot)?;
+    if root_id.is_removed(tree) {
+        return Err(IndexError::RemovedRoot);
+    }
+    Ok(root_id)
+}
 
 pub trait Indexer {
-    fn index_tree(tree: &ParsedTree, label_dict: &LabelDict) -> Self
+    fn index_tree(tree: &ParsedTree, label_dict: &LabelDict) -> Result<Self, IndexError>
     where
         Self: Sized;
+
+    /// Indexes all trees of a collection in their order, in parallel outside the
+    /// browser. Builders sharing state between trees override it.
+    fn index_collection(
+        trees: &[ParsedTree],
+        label_dict: &LabelDict,
+    ) -> Result<Vec<Self>, IndexError>
+    where
+        Self: Sized + Send,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        return trees
+            .par_iter()
+            .map(|t| Self::index_tree(t, label_dict))
+            .collect();
+        #[cfg(target_arch = "wasm32")]
+        trees
+            .iter()
+            .map(|t| Self::index_tree(t, label_dict))
+            .collect()
+    }
+}
+
+/// Indexes of queries with their thresholds
+pub fn index_queries<I: Indexer>(
+    queries: &[(usize, ParsedTree)],
+    label_dict: &LabelDict,
+) -> Result<Vec<(usize, I)>, IndexError> {
+    queries
+        .iter()
+        .map(|(k, q)| Ok((*k, I::index_tree(q, label_dict)?)))
+        .collect()
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConstantsIndex {
     pub tree_size: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SEDIndex {
-    pub preorder: Vec<i32>,
-    pub postorder: Vec<i32>,
+    pub preorder: Vec<LabelId>,
+    pub postorder: Vec<LabelId>,
     pub c: ConstantsIndex,
 }
 
 impl Indexer for SEDIndex {
-    fn index_tree(tree: &ParsedTree, _label_dict: &LabelDict) -> Self {
-        let Some(root) = tree.iter().next() else {
-            panic!("Unable to get root but tree is not empty!");
-        };
-        let root_id = tree.get_node_id(root).unwrap();
-
+    fn index_tree(tree: &ParsedTree, _label_dict: &LabelDict) -> Result<Self, IndexError> {
+        let root_id = root_id(tree)?;
+        // inverted lists built from the traversals count postorder ids
+        node_counter(tree.count())?;
         let mut pre = Vec::with_capacity(tree.count());
         let mut post = Vec::with_capacity(tree.count());
 
         traverse(root_id, tree, &mut pre, &mut post);
 
-        Self {
+        Ok(Self {
             postorder: post,
             preorder: pre,
             c: ConstantsIndex {
                 tree_size: tree.count(),
             },
-        }
+        })
     }
 }
 
-fn traverse(nid: NodeId, tree: &ParsedTree, pre: &mut Vec<i32>, post: &mut Vec<i32>) {
+impl MemoryUsage for SEDIndex {
+    fn heap_bytes(&self) -> usize {
+        self.preorder.heap_bytes() + self.postorder.heap_bytes()
+    }
+}
+
+fn traverse(nid: NodeId, tree: &ParsedTree, pre: &mut Vec<LabelId>, post: &mut Vec<LabelId>) {
     // i am here at the current root
-    let label = tree.get(nid).unwrap().get();
+    let label = tree[nid].get();
     pre.push(*label);
     for cnid in nid.children(tree) {
         traverse(cnid, tree, pre, post);
@@ -56,46 +131,68 @@ fn traverse(nid: NodeId, tree: &ParsedTree, pre: &mut Vec<i32>, post: &mut Vec<i
     post.push(*label);
 }
 
-pub type InvListLblPost = FxHashMap<LabelId, Vec<i32>>;
+pub type InvListLblPost = FxHashMap<LabelId, Vec<NodeCounter>>;
+
+impl MemoryUsage for InvertedListLabelPostorderIndex {
+    fn heap_bytes(&self) -> usize {
+        self.inverted_list.heap_bytes()
+    }
+}
 
 /// Inverted list of nodes, key is index which is the label id in label dict
 /// and postings list contains postorder traversal number
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InvertedListLabelPostorderIndex {
     pub inverted_list: InvListLblPost,
+    /// Bloom filter of labels, used to reject trees before comparing inverted lists
+    pub signature: LabelSignature,
     pub c: ConstantsIndex,
 }
 
 impl Indexer for InvertedListLabelPostorderIndex {
-    fn index_tree(tree: &ParsedTree, _label_dict: &LabelDict) -> Self {
-        let Some(root) = tree.iter().next() else {
-            panic!("Unable to get root but tree is not empty!");
-        };
+    fn index_tree(tree: &ParsedTree, _label_dict: &LabelDict) -> Result<Self, IndexError> {
         let mut inverted_list = InvListLblPost::default();
-        let root_id = tree.get_node_id(root).unwrap();
+        let root_id = root_id(tree)?;
+        node_counter(tree.count())?;
         traverse_inverted(root_id, tree, &mut inverted_list, 0);
+        let signature = LabelSignature::from_labels(inverted_list.keys().copied());
 
-        Self {
+        Ok(Self {
             inverted_list,
+            signature,
             c: ConstantsIndex {
                 tree_size: tree.count(),
             },
-        }
+        })
     }
 }
 
 impl InvertedListLabelPostorderIndex {
+    /// Builds the inverted list from already computed traversals of a tree
+    pub fn from_traversals(traversals: &SEDIndex) -> Self {
+        let mut inverted_list = InvListLblPost::default();
+        for (postorder_id, label) in traversals.postorder.iter().enumerate() {
+            inverted_list
+                .entry(*label)
+                .or_default()
+                .push(postorder_id as NodeCounter);
+        }
+        let signature = LabelSignature::from_labels(inverted_list.keys().copied());
+
+        Self {
+            inverted_list,
+            signature,
+            c: ConstantsIndex {
+                tree_size: traversals.c.tree_size,
+            },
+        }
+    }
+
     pub fn get_sorted_nodes(&self, ordering: &LabelFreqOrdering) -> Vec<(&LabelId, usize)> {
         self.inverted_list
             .iter()
-            .sorted_by_key(|(&label, _)| {
-                if label as usize >= ordering.len() {
-                    return usize::MIN;
-                }
-                *ordering
-                    .get(NonZeroUsize::new(label as usize).unwrap())
-                    .unwrap()
-            })
+            // labels added after the ordering was built, e.g. by queries, go first
+            .sorted_by_key(|(&label, _)| ordering.get(label).copied().unwrap_or(usize::MIN))
             .map(|(l, lc)| (l, lc.len()))
             .collect_vec()
     }
@@ -105,9 +202,9 @@ fn traverse_inverted(
     nid: NodeId,
     tree: &ParsedTree,
     inverted_list: &mut InvListLblPost,
-    start_postorder: i32,
-) -> i32 {
-    let label = tree.get(nid).unwrap().get();
+    start_postorder: NodeCounter,
+) -> NodeCounter {
+    let label = tree[nid].get();
     let mut postorder_id = start_postorder;
     let mut children = 0;
     for cnid in nid.children(tree) {
@@ -183,4 +280,43 @@ mod tests {
         assert_eq!(idx.inverted_list, qh);
     }
     */
+
+    #[test]
+    fn test_node_counter() {
+        use super::*;
+        assert_eq!(node_counter(4), Ok(4));
+        let max = NodeCounter::MAX as usize;
+        assert_eq!(node_counter(max), Ok(NodeCounter::MAX));
+        assert_eq!(
+            node_counter(max + 1),
+            Err(IndexError::TreeTooLarge(max + 1))
+        );
+    }
+
+    #[test]
+    fn test_index_json_roundtrip() {
+        use super::*;
+        use crate::parsing::parse_single;
+        let mut label_dict = LabelDict::new();
+        let tree = parse_single("{a{a{f}{b}{x}}{b}{y}}".to_owned(), &mut label_dict);
+        let idx = InvertedListLabelPostorderIndex::index_tree(&tree, &label_dict).unwrap();
+        assert_eq!(
+            SEDIndex::index_tree(&ParsedTree::new(), &label_dict).err(),
+            Some(IndexError::EmptyTree)
+        );
+        let sets = crate::lb::structural_filter::LabelSetConverter::default()
+            .create(&[tree.clone(), ParsedTree::new()]);
+        assert_eq!(sets.err(), Some(IndexError::EmptyTree));
+
+        let json = serde_json::to_string(&idx).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InvertedListLabelPostorderIndex>(&json).unwrap(),
+            idx
+        );
+        let json = serde_json::to_string(&label_dict).unwrap();
+        assert_eq!(
+            serde_json::from_str::<LabelDict>(&json).unwrap(),
+            label_dict
+        );
+    }
 }
@@ -0,0 +1,352 @@
+//! Similarity self join of a collection, all pairs of trees within a distance.
+//!
+//! Every tree is queried against the collection with a lower bound method, candidates
+//! are verified with the bounded tree edit distance of a [`Verifier`]. Pairs are reported once, as
+//! `(t1, t2, distance)` with `t1 < t2` positions in the size sorted collection.
+//! [`rs_join`] joins two collections the same way, trees of the second collection are
+//! the queries. [`approximate_verify`] bounds the distance of candidates instead of
+//! computing it.
+use crate::error::Error;
+use crate::indexing::{index_queries, IndexError, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::label_intersection;
+use crate::lb::sed::sed;
+use crate::lb::{self, preprocess::Preprocessed};
+use crate::parsing::ParsedTree;
+use crate::ted::{top_down_ted, CostModel};
+use crate::verifier::{verify_pairs, Verifier};
+use crate::LowerBoundMethods;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Pairs of trees with tree edit distance at most `k` by the verifier, sorted by tree ids
+pub fn self_join(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    k: usize,
+    verifier: &dyn Verifier,
+) -> Result<Vec<(usize, usize, usize)>, anyhow::Error> {
+    let trees = pre.trees();
+    let queries = trees.iter().map(|t| (k, t.clone())).collect::<Vec<_>>();
+    let (candidates, _) = lb::run_method(method, pre, &queries, &lb::RunConfig::default())?;
+    let candidates = candidates
+        .into_par_iter()
+        .filter(|(t1, t2)| t1 < t2)
+        .collect::<Vec<_>>();
+    Ok(verify(trees, &queries, &candidates, verifier)?)
+}
+
+/// Pairs `(r, s, distance)` of trees `r` of the collection and `s` of `others` with tree
+/// edit distance at most `k`, sorted by ids. Ids are positions in both collections, trees
+/// of both have to share the label dictionary. Candidates of the lower bound are returned
+/// without a distance unless a verifier is given.
+pub fn rs_join(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    others: &[ParsedTree],
+    k: usize,
+    verifier: Option<&dyn Verifier>,
+) -> Result<Vec<(usize, usize, Option<usize>)>, anyhow::Error> {
+    let queries = others.iter().map(|t| (k, t.clone())).collect::<Vec<_>>();
+    let (candidates, _) = lb::run_method(method, pre, &queries, &lb::RunConfig::default())?;
+    let mut pairs = if let Some(verifier) = verifier {
+        verify(pre.trees(), &queries, &candidates, verifier)?
+            .into_par_iter()
+            .map(|(s, r, distance)| (r, s, Some(distance)))
+            .collect()
+    } else {
+        candidates
+            .into_par_iter()
+            .map(|(s, r)| (r, s, None))
+            .collect::<Vec<_>>()
+    };
+    pairs.par_sort_unstable();
+    Ok(pairs)
+}
+
+/// Verifies candidates of queries with the verifier, returns `(query id, tree id, distance)` of trees within
+/// the threshold of the query, sorted by ids
+pub fn verify(
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    verifier: &dyn Verifier,
+) -> Result<Vec<(usize, usize, usize)>, Error> {
+    let distances = verify_pairs(verifier, trees, queries, candidates)?;
+    let mut results = candidates
+        .par_iter()
+        .zip(distances)
+        .filter_map(|(&(qid, tid), distance)| Some((qid, tid, distance?)))
+        .collect::<Vec<_>>();
+    results.par_sort_unstable();
+    Ok(results)
+}
+
+/// Candidates verified by [`verify_with_budget`] and those left when the budget ran out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetedResults {
+    /// `(query id, tree id, distance)` of verified candidates within the threshold
+    pub results: Vec<(usize, usize, usize)>,
+    pub verified: usize,
+    /// `(query id, tree id)` of candidates not verified, sorted by ids
+    pub unverified: Vec<(usize, usize)>,
+}
+
+/// Order candidates are verified in by [`verify_with_budget`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum VerifyOrder {
+    /// Increasing label intersection bound, ties by increasing size of the larger tree, so
+    /// likely results with cheap distances come first
+    #[default]
+    BoundSize,
+    /// Increasing label intersection bound
+    Bound,
+    /// Increasing size of the larger tree, the most pairs verified per second
+    Size,
+    /// Increasing query and tree ids, the order of candidate files
+    Ids,
+}
+
+/// Candidates in the order they are verified, the sort keys come before the ids
+fn verification_order(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    order: VerifyOrder,
+) -> Result<Vec<(usize, usize, usize, usize)>, IndexError> {
+    let trees = pre.trees();
+    let bounds = if matches!(order, VerifyOrder::BoundSize | VerifyOrder::Bound) {
+        let label_sets = pre.label_sets()?;
+        let query_sets =
+            index_queries::<InvertedListLabelPostorderIndex>(queries, pre.label_dict())?;
+        candidates
+            .par_iter()
+            .map(|&(qid, tid)| label_intersection(&query_sets[qid].1, &label_sets[tid]))
+            .collect()
+    } else {
+        vec![0; candidates.len()]
+    };
+    let mut ordered = candidates
+        .par_iter()
+        .zip(bounds)
+        .map(|(&(qid, tid), bound)| {
+            let size = queries[qid].1.len().max(trees[tid].len());
+            match order {
+                VerifyOrder::BoundSize => (bound, size, qid, tid),
+                VerifyOrder::Bound => (bound, 0, qid, tid),
+                VerifyOrder::Size => (size, 0, qid, tid),
+                VerifyOrder::Ids => (0, 0, qid, tid),
+            }
+        })
+        .collect::<Vec<_>>();
+    ordered.par_sort_unstable();
+    Ok(ordered)
+}
+
+/// Verifies candidates in `order` until `budget` runs out, by default the most promising
+/// and cheapest pairs are answered first. Candidates are verified in parallel batches,
+/// the budget is checked before every batch.
+pub fn verify_with_budget(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    budget: Duration,
+    order: VerifyOrder,
+    verifier: &dyn Verifier,
+) -> Result<BudgetedResults, anyhow::Error> {
+    let start = Instant::now();
+    let trees = pre.trees();
+    let ordered = verification_order(pre, queries, candidates, order)?;
+
+    let batch = rayon::current_num_threads() * 16;
+    let mut verified = ordered.len();
+    let mut results = vec![];
+    for (i, pairs) in ordered.chunks(batch).enumerate() {
+        if start.elapsed() >= budget {
+            verified = i * batch;
+            break;
+        }
+        let pairs = pairs
+            .iter()
+            .map(|&(_, _, qid, tid)| (qid, tid))
+            .collect::<Vec<_>>();
+        let distances = verify_pairs(verifier, trees, queries, &pairs)?;
+        results.extend(
+            pairs
+                .into_iter()
+                .zip(distances)
+                .filter_map(|((qid, tid), distance)| Some((qid, tid, distance?))),
+        );
+    }
+    results.par_sort_unstable();
+    let mut unverified = ordered[verified..]
+        .iter()
+        .map(|&(_, _, qid, tid)| (qid, tid))
+        .collect::<Vec<_>>();
+    unverified.par_sort_unstable();
+    Ok(BudgetedResults {
+        results,
+        verified,
+        unverified,
+    })
+}
+
+/// Candidates bounded by [`approximate_verify`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApproximateResults {
+    /// `(query id, tree id, lower bound, upper bound)` of candidates not ruled out by their
+    /// lower bound, sorted by ids
+    pub bounded: Vec<(usize, usize, usize, usize)>,
+    /// candidates with an upper bound within the threshold, certain results
+    pub results: usize,
+    /// candidates with the threshold between their bounds
+    pub ambiguous: usize,
+    /// candidates with a lower bound above the threshold
+    pub rejected: usize,
+}
+
+/// Bounds the tree edit distance of candidates by an interval instead of computing it.
+/// The lower bound is the larger of the string edit distance and label intersection
+/// bounds, the upper bound the top-down distance with the costs of the cost model. Both
+/// take time of the product of the sizes at most, candidates with the threshold between
+/// their bounds are left ambiguous.
+pub fn approximate_verify(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    costs: &CostModel,
+) -> Result<ApproximateResults, IndexError> {
+    let trees = pre.trees();
+    let (traversals, label_sets) = (pre.traversals()?, pre.label_sets()?);
+    let query_traversals = index_queries::<SEDIndex>(queries, pre.label_dict())?;
+    let query_sets = query_traversals
+        .iter()
+        .map(|(_, traversals)| InvertedListLabelPostorderIndex::from_traversals(traversals))
+        .collect::<Vec<_>>();
+    let mut bounded = candidates
+        .par_iter()
+        .filter_map(|&(qid, tid)| {
+            let (k, query) = &queries[qid];
+            let lower = sed(&query_traversals[qid].1, &traversals[tid])
+                .max(label_intersection(&query_sets[qid], &label_sets[tid]));
+            // the top-down distance is only needed if the lower bound keeps the pair
+            (lower <= *k).then(|| {
+                let upper = top_down_ted(query, &trees[tid], costs).max(lower);
+                (qid, tid, lower, upper)
+            })
+        })
+        .collect::<Vec<_>>();
+    bounded.par_sort_unstable();
+    let results = bounded
+        .iter()
+        .filter(|(qid, _, _, upper)| *upper <= queries[*qid].0)
+        .count();
+    Ok(ApproximateResults {
+        results,
+        ambiguous: bounded.len() - results,
+        rejected: candidates.len() - bounded.len(),
+        bounded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::parsing::{parse_single, LabelDict};
+    use crate::verifier::ZhangShasha;
+
+    const UNIT: CostModel = CostModel::UNIT;
+    const ZS: ZhangShasha = ZhangShasha(UNIT);
+
+    #[test]
+    fn test_rs_join() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{x{y}{z}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let others = ["{b}", "{x{y}}", "{a{b}{c}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let pairs = rs_join(LowerBoundMethods::Sed, &pre, &others, 1, Some(&ZS)).unwrap();
+        let expected = [(0, 0, 1), (1, 0, 1), (1, 2, 1), (2, 1, 1)];
+        assert_eq!(pairs, expected.map(|(r, s, d)| (r, s, Some(d))));
+        let candidates = rs_join(LowerBoundMethods::Sed, &pre, &others, 1, None).unwrap();
+        assert!(expected
+            .iter()
+            .all(|(r, s, _)| candidates.contains(&(*r, *s, None))));
+    }
+
+    #[test]
+    fn test_verify_with_budget() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{x{y}{z}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let queries = vec![(1, trees[0].clone())];
+        let candidates = [(0, 0), (0, 1), (0, 2)];
+
+        let order = VerifyOrder::default();
+        let all =
+            verify_with_budget(&pre, &queries, &candidates, Duration::MAX, order, &ZS).unwrap();
+        assert_eq!(all.results, vec![(0, 0, 0), (0, 1, 1)]);
+        assert_eq!(all.verified, 3);
+        assert!(all.unverified.is_empty());
+        assert_eq!(
+            all.results,
+            verify(&trees, &queries, &candidates, &ZS).unwrap()
+        );
+
+        let none =
+            verify_with_budget(&pre, &queries, &candidates, Duration::ZERO, order, &ZS).unwrap();
+        assert!(none.results.is_empty());
+        assert_eq!(none.verified, 0);
+        assert_eq!(none.unverified, candidates);
+    }
+
+    #[test]
+    fn test_verification_order() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{x{y}}", "{a{b}{c}{d}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        // the first query is larger than the second
+        let queries = ["{a{b}{c}{d}{e}}", "{a{b}}"]
+            .map(|t| (3, parse_single(t.to_owned(), &mut ld)))
+            .to_vec();
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let candidates = [(0, 3), (1, 0), (1, 1), (1, 2)];
+        let order = |order| {
+            verification_order(&pre, &queries, &candidates, order)
+                .unwrap()
+                .into_iter()
+                .map(|(_, _, qid, tid)| (qid, tid))
+                .collect::<Vec<_>>()
+        };
+        // bounds are 1, 1, 0 and 2, the pairs of bound 1 are of sizes 5 and 2 in id order
+        assert_eq!(
+            order(VerifyOrder::BoundSize),
+            [(1, 1), (1, 0), (0, 3), (1, 2)]
+        );
+        assert_eq!(order(VerifyOrder::Bound), [(1, 1), (0, 3), (1, 0), (1, 2)]);
+        assert_eq!(order(VerifyOrder::Size), [(1, 0), (1, 1), (1, 2), (0, 3)]);
+        assert_eq!(order(VerifyOrder::Ids), candidates);
+    }
+
+    #[test]
+    fn test_approximate_verify() {
+        let mut ld = LabelDict::default();
+        let trees =
+            ["{a}", "{a{b}}", "{a{c}}", "{x{y}{z}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = vec![(1, parse_single("{a{b{c}}}".to_owned(), &mut ld))];
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let candidates = [(0, 0), (0, 1), (0, 2), (0, 3)];
+
+        let approximate = approximate_verify(&pre, &queries, &candidates, &UNIT).unwrap();
+        // {a{c}} is 1 away by deleting b, the top-down distance renames b to c instead
+        assert_eq!(approximate.bounded, vec![(0, 1, 1, 1), (0, 2, 1, 2)]);
+        assert_eq!(approximate.results, 1);
+        assert_eq!(approximate.ambiguous, 1);
+        assert_eq!(approximate.rejected, 2);
+        let exact = verify(&trees, &queries, &candidates, &ZS).unwrap();
+        for (qid, tid, distance) in exact {
+            let (.., lower, upper) = approximate.bounded.iter().find(|p| p.1 == tid).unwrap();
+            assert!((lower..=upper).contains(&&distance), "{qid} {tid}");
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! k nearest neighbor graph of a collection and top-k joins by tree edit distance.
+//!
+//! Trees are queried with a growing threshold, doubling every round, candidates of a
+//! lower bound method are verified with the bounded tree edit distance. Once a tree has
+//! at least `k` trees within the threshold its nearest neighbors are among them, so only
+//! trees with fewer neighbors are queried again. A top-k join searches the nearest
+//! dataset trees of query trees the same way. String edit distance is a lower bound of
+//! tree edit distance, the search of a tree starts at the distance of its `k`-th nearest
+//! tree by string edit distance, looked up in the BK-tree.
+use crate::indexing::{IndexError, Indexer, SEDIndex};
+use crate::lb::{self, preprocess::Preprocessed};
+use crate::parsing::ParsedTree;
+use crate::ted::ted_k;
+use crate::LowerBoundMethods;
+use rayon::prelude::*;
+
+/// Edges `(tree, neighbor, distance)` to the `k` nearest trees of every tree, sorted by
+/// tree and distance, ties are broken by tree id. With `max_distance` neighbors further
+/// apart are left out, trees may end up with less than `k` edges.
+pub fn knn_graph(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    k: usize,
+    max_distance: Option<usize>,
+) -> Result<Vec<(usize, usize, usize)>, anyhow::Error> {
+    let trees = pre.trees();
+    nearest(
+        method,
+        pre,
+        trees,
+        pre.traversals()?,
+        |query, tid| query == tid,
+        k,
+        max_distance,
+    )
+}
+
+/// Top-k join, `(query, tree, distance)` of the `k` nearest dataset trees of every query,
+/// sorted the same way as [`knn_graph`]
+pub fn top_k_join(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[ParsedTree],
+    k: usize,
+    max_distance: Option<usize>,
+) -> Result<Vec<(usize, usize, usize)>, anyhow::Error> {
+    let query_traversals = queries
+        .par_iter()
+        .map(|q| SEDIndex::index_tree(q, pre.label_dict()))
+        .collect::<Result<Vec<_>, IndexError>>()?;
+    nearest(
+        method,
+        pre,
+        queries,
+        &query_traversals,
+        |_, _| false,
+        k,
+        max_distance,
+    )
+}
+
+/// Nearest dataset trees of the queries, trees for which `is_self` holds are skipped
+fn nearest(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[ParsedTree],
+    query_traversals: &[SEDIndex],
+    is_self: impl Fn(usize, usize) -> bool + Sync,
+    k: usize,
+    max_distance: Option<usize>,
+) -> Result<Vec<(usize, usize, usize)>, anyhow::Error> {
+    let trees = pre.trees();
+    // distance of two trees is at most the sum of their sizes
+    let largest = |trees: &[ParsedTree]| trees.iter().map(|t| t.count()).max().unwrap_or(0);
+    let limit = max_distance.unwrap_or(largest(trees) + largest(queries));
+    let mut edges = vec![];
+    if k == 0 {
+        return Ok(edges);
+    }
+    let traversals = pre.traversals()?;
+    let sed_tree = pre.sed_tree()?;
+    // queries with their threshold of the next round
+    let mut remaining = (0..queries.len())
+        .into_par_iter()
+        .map(|query| {
+            let start = sed_tree
+                .nearest(traversals, &query_traversals[query], k + 1)
+                .into_iter()
+                .filter(|(_, item)| !is_self(query, *item))
+                .nth(k - 1)
+                .map_or(1, |(distance, _)| distance.max(1));
+            (query, start.min(limit))
+        })
+        .collect::<Vec<_>>();
+    while !remaining.is_empty() {
+        let round = remaining
+            .iter()
+            .map(|(query, threshold)| (*threshold, queries[*query].clone()))
+            .collect::<Vec<_>>();
+        let (candidates, _) = lb::run_method(method, pre, &round, &lb::RunConfig::default())?;
+        let mut found = candidates
+            .into_par_iter()
+            .filter(|(qid, tid)| !is_self(remaining[*qid].0, *tid))
+            .filter_map(|(qid, tid)| {
+                let (query, threshold) = remaining[qid];
+                let distance = ted_k(&queries[query], &trees[tid], threshold);
+                (distance <= threshold).then_some((qid, distance, tid))
+            })
+            .collect::<Vec<_>>();
+        found.par_sort_unstable();
+        let mut neighbors = vec![vec![]; remaining.len()];
+        for (qid, distance, tid) in found {
+            neighbors[qid].push((distance, tid));
+        }
+
+        let mut next = vec![];
+        for ((query, threshold), neighbors) in remaining.into_iter().zip(neighbors) {
+            if neighbors.len() >= k || threshold >= limit {
+                edges.extend(
+                    neighbors
+                        .into_iter()
+                        .take(k)
+                        .map(|(distance, tid)| (query, tid, distance)),
+                );
+            } else {
+                next.push((query, (threshold * 2).min(limit)));
+            }
+        }
+        remaining = next;
+    }
+    edges.par_sort_unstable_by_key(|&(query, neighbor, distance)| (query, distance, neighbor));
+    Ok(edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_nearest_neighbors() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}{v}{u}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+
+        let edges = knn_graph(LowerBoundMethods::Sed, &pre, 1, None).unwrap();
+        assert_eq!(edges, vec![(0, 1, 1), (1, 0, 1), (2, 1, 1), (3, 0, 6)]);
+
+        let edges = knn_graph(LowerBoundMethods::Sed, &pre, 2, Some(2)).unwrap();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 1, 1),
+                (0, 2, 2),
+                (1, 0, 1),
+                (1, 2, 1),
+                (2, 1, 1),
+                (2, 0, 2)
+            ]
+        );
+
+        let queries = ["{a{c}}", "{x{y}{z}{w}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let pairs = top_k_join(LowerBoundMethods::Sed, &pre, &queries, 2, None).unwrap();
+        assert_eq!(pairs, vec![(0, 0, 1), (0, 1, 1), (1, 3, 2), (1, 0, 4)]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Label orderings of the prefix filters of the label intersection and structural indexes.
+//!
+//! A query probes the index with the first `k + 1` of its labels in the ordering, so
+//! putting rare labels first touches fewer posting lists. Candidates are the same for
+//! any ordering, only the work differs. An ordering maps labels to ranks, labels with
+//! lower ranks go first. Size bucket orderings rank labels by their frequency in trees
+//! of about the size of the query instead of the whole collection.
+//!
+//! Orderings are written as CSV `max_size,label,rank` with the largest tree size of
+//! the bucket, empty for the last bucket, and read back from the same format or from
+//! `label,rank` for a single ordering of all sizes. Labels missing in a file go last.
+use crate::parsing::{label_names, max_label, LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
+use clap::ValueEnum;
+use std::path::Path;
+
+/// Number of size buckets with about the same number of trees
+const SIZE_BUCKETS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OrderingKind {
+    /// Rare labels first
+    #[default]
+    Ascending,
+    /// Frequent labels first
+    Descending,
+    /// Rare labels first by frequencies in trees of about the query size
+    SizeBuckets,
+}
+
+#[derive(Debug)]
+pub struct LabelOrdering {
+    /// largest tree size of each bucket in increasing order, the last bucket takes
+    /// all larger trees
+    max_sizes: Vec<usize>,
+    orderings: Vec<LabelFreqOrdering>,
+}
+
+/// Occurrences of each label in the trees, indexed by [`LabelId::index`]
+fn label_counts(trees: &[ParsedTree], labels: usize) -> Vec<usize> {
+    let mut counts = vec![0; labels];
+    for tree in trees {
+        for node in tree.iter() {
+            counts[node.get().index()] += 1;
+        }
+    }
+    counts
+}
+
+impl LabelOrdering {
+    /// Builds the ordering of the collection, trees have to be sorted by size
+    pub fn new(kind: OrderingKind, trees: &[ParsedTree], label_dict: &LabelDict) -> Self {
+        let labels = max_label(label_dict).index() + 1;
+        match kind {
+            OrderingKind::Ascending => Self::single(label_counts(trees, labels)),
+            OrderingKind::Descending => {
+                let counts = label_counts(trees, labels);
+                let most = counts.iter().copied().max().unwrap_or(0);
+                Self::single(counts.into_iter().map(|c| most - c).collect())
+            }
+            OrderingKind::SizeBuckets => {
+                let mut max_sizes = vec![];
+                let mut orderings = vec![];
+                let mut start = 0;
+                for bucket in 1..=SIZE_BUCKETS {
+                    let end = (trees.len() * bucket).div_ceil(SIZE_BUCKETS);
+                    if end <= start {
+                        continue;
+                    }
+                    // trees of the same size stay in one bucket
+                    let max_size = trees[end - 1].count();
+                    let end = trees.partition_point(|t| t.count() <= max_size);
+                    max_sizes.push(max_size);
+                    orderings.push(LabelFreqOrdering::new(label_counts(
+                        &trees[start..end],
+                        labels,
+                    )));
+                    start = end;
+                }
+                if orderings.is_empty() {
+                    return Self::single(vec![0; labels]);
+                }
+                *max_sizes.last_mut().unwrap() = usize::MAX;
+                Self {
+                    max_sizes,
+                    orderings,
+                }
+            }
+        }
+    }
+
+    fn single(ranks: Vec<usize>) -> Self {
+        Self {
+            max_sizes: vec![usize::MAX],
+            orderings: vec![LabelFreqOrdering::new(ranks)],
+        }
+    }
+
+    /// Ordering of queries of the size
+    pub fn for_size(&self, size: usize) -> &LabelFreqOrdering {
+        // the last bucket takes all sizes
+        &self.orderings[self.max_sizes.partition_point(|max| *max < size)]
+    }
+
+    /// Reads an ordering written by [`LabelOrdering::write`] or a `label,rank` CSV,
+    /// labels missing in the collection are skipped
+    pub fn read(path: &impl AsRef<Path>, label_dict: &LabelDict) -> Result<Self, anyhow::Error> {
+        let mut reader = csv::Reader::from_path(path)?;
+        // the size column comes first in bucketed orderings
+        let offset = match reader.headers()?.len() {
+            2 => 0,
+            3 => 1,
+            _ => anyhow::bail!("Label ordering has to have label,rank or max_size,label,rank"),
+        };
+        let labels = max_label(label_dict).index() + 1;
+        let mut buckets: Vec<(usize, Vec<usize>)> = vec![];
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let line = i + 2;
+            let invalid = |what: &str| anyhow::anyhow!("Invalid {what} on line {line}");
+            let max_size = if offset == 1 && !record[0].is_empty() {
+                record[0].trim().parse().map_err(|_| invalid("size"))?
+            } else {
+                usize::MAX
+            };
+            let label = &record[offset];
+            let rank = record[offset + 1]
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| invalid("rank"))?;
+            match buckets.last() {
+                Some((last, _)) if *last == max_size => {}
+                Some((last, _)) if *last > max_size => {
+                    anyhow::bail!("Size buckets are not increasing on line {line}")
+                }
+                _ => buckets.push((max_size, vec![usize::MAX; labels])),
+            }
+            if let Some((id, _)) = label_dict.get(label) {
+                buckets.last_mut().unwrap().1[id.index()] = rank;
+            }
+        }
+        if buckets.is_empty() {
+            anyhow::bail!("Label ordering is empty");
+        }
+        buckets.last_mut().unwrap().0 = usize::MAX;
+        let (max_sizes, orderings) = buckets
+            .into_iter()
+            .map(|(max_size, ranks)| (max_size, LabelFreqOrdering::new(ranks)))
+            .unzip();
+        Ok(Self {
+            max_sizes,
+            orderings,
+        })
+    }
+
+    /// Writes ranks of all labels of the ordering as `max_size,label,rank`
+    pub fn write(
+        &self,
+        path: &impl AsRef<Path>,
+        label_dict: &LabelDict,
+    ) -> Result<(), anyhow::Error> {
+        let names = label_names(label_dict);
+        let mut w = csv::Writer::from_path(path)?;
+        w.write_record(["max_size", "label", "rank"])?;
+        for (max_size, ordering) in self.max_sizes.iter().zip(self.orderings.iter()) {
+            let max_size = match max_size {
+                &usize::MAX => String::new(),
+                size => size.to_string(),
+            };
+            // names are indexed by label id, the first one is unused
+            for label in (0..names.len() - 1).map(LabelId::from_index) {
+                if let Some(rank) = ordering.get(label) {
+                    let name = names[label.get() as usize];
+                    w.write_record([max_size.as_str(), name, &rank.to_string()])?;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{get_frequency_ordering, parse_single};
+
+    #[test]
+    fn test_orderings_roundtrip() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{b{a}}", "{a{b}{c}}", "{c{c}{c}{a}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let label = |name: &str| ld[name].0;
+
+        let ascending = LabelOrdering::new(OrderingKind::Ascending, &trees, &ld);
+        assert_eq!(ascending.for_size(3), &get_frequency_ordering(&ld));
+        let descending = LabelOrdering::new(OrderingKind::Descending, &trees, &ld);
+        assert_eq!(descending.for_size(1).get(label("a")), Some(&0));
+        assert_eq!(descending.for_size(1).get(label("b")), Some(&2));
+
+        let buckets = LabelOrdering::new(OrderingKind::SizeBuckets, &trees, &ld);
+        // c is the most frequent label of the largest trees only
+        assert_eq!(buckets.for_size(1).get(label("c")), Some(&0));
+        assert_eq!(buckets.for_size(4).get(label("c")), Some(&3));
+        assert_eq!(buckets.for_size(100).get(label("c")), Some(&3));
+
+        let path = std::env::temp_dir().join(format!("ordering-{}.csv", std::process::id()));
+        buckets.write(&path, &ld).unwrap();
+        let read = LabelOrdering::read(&path, &ld).unwrap();
+        assert_eq!(read.max_sizes, buckets.max_sizes);
+        assert_eq!(read.orderings, buckets.orderings);
+
+        std::fs::write(&path, "label,rank\nc,0\nb,1\n").unwrap();
+        let read = LabelOrdering::read(&path, &ld).unwrap();
+        assert_eq!(read.for_size(2).get(label("c")), Some(&0));
+        assert_eq!(read.for_size(2).get(label("a")), Some(&usize::MAX));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+//! Label weights of the weighted label intersection bound.
+//!
+//! Weights are read from a TSV file of `label<TAB>weight` lines without a header, labels
+//! missing in the file weigh 1. Giving rare labels a weight above 1 makes trees that
+//! differ in them further apart than trees differing in ubiquitous labels only.
+use crate::error::Error;
+use crate::parsing::{max_label, LabelDict, LabelId};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelWeights {
+    /// weight of every label, indexed by [`LabelId::index`]
+    weights: Vec<f64>,
+}
+
+impl LabelWeights {
+    /// Labels of the file missing in the label dictionary are skipped
+    pub fn read(path: &impl AsRef<Path>, label_dict: &LabelDict) -> Result<Self, anyhow::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_path(path)?;
+        let mut weights = vec![1.0; max_label(label_dict).index() + 1];
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let line = i + 1;
+            if record.len() != 2 {
+                anyhow::bail!(Error::Parse(format!(
+                    "Expected label and weight separated by a tab on line {line}"
+                )));
+            }
+            let weight = record[1]
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|w| w.is_finite() && *w >= 0.0)
+                .ok_or_else(|| Error::Parse(format!("Invalid weight on line {line}")))?;
+            if let Some((id, _)) = label_dict.get(&record[0]) {
+                weights[id.index()] = weight;
+            }
+        }
+        Ok(Self { weights })
+    }
+
+    pub fn get(&self, label: LabelId) -> f64 {
+        self.weights.get(label.index()).copied().unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_read_weights() {
+        let mut ld = LabelDict::default();
+        parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        let path = std::env::temp_dir().join(format!("weights-{}.tsv", std::process::id()));
+
+        std::fs::write(&path, "a\t0.5\nx\t2\n").unwrap();
+        let weights = LabelWeights::read(&path, &ld).unwrap();
+        assert_eq!(weights.get(ld["a"].0), 0.5);
+        assert_eq!(weights.get(ld["b"].0), 1.0);
+
+        std::fs::write(&path, "a\t-1\n").unwrap();
+        assert!(LabelWeights::read(&path, &ld).is_err());
+        std::fs::write(&path, "a 1\n").unwrap();
+        assert!(LabelWeights::read(&path, &ld).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
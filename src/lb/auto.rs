@@ -0,0 +1,468 @@
+//! Profile guided selection of a lower bound method.
+//!
+//! Every registered method, and every cascade of one method refined by another, is
+//! run on an evenly spaced sample of queries. Exact TED of a few sampled candidates
+//! estimates the cost of verifying a candidate. The plan with the lowest filter time
+//! plus verification cost of its candidates is then run on the full workload.
+//!
+//! The tuner instead builds a pipeline of any length. Starting from all pairs within
+//! the size range, it measures every remaining filter on the pairs left by the chosen
+//! stages and appends the one with the lowest time per pruned pair, until no filter
+//! prunes pairs faster than verifying them would take.
+use super::label_intersection::{label_intersection_k, weighted_label_intersection_k};
+use super::preprocess::Preprocessed;
+use super::sed::sed_k;
+use super::structural_filter::{ted_matching, ted_variant as struct_split_ted_k};
+use super::{run_method, RunConfig};
+use crate::indexing::{index_queries, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::parsing::ParsedTree;
+use crate::ted;
+use crate::LowerBoundMethods;
+use itertools::Itertools;
+use rayon::prelude::*;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Number of sampled queries when not given explicitly
+pub const DEFAULT_SAMPLE: usize = 64;
+/// Number of sampled candidates verified with exact TED
+const VERIFY_SAMPLE: usize = 32;
+/// Methods that can refine candidates of another method
+pub const REFINABLE: [LowerBoundMethods; 4] = [
+    LowerBoundMethods::Lblint,
+    LowerBoundMethods::Sed,
+    LowerBoundMethods::Structural,
+    LowerBoundMethods::StructuralSplit,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plan {
+    Single(LowerBoundMethods),
+    /// Candidates of the first method are refined by the second one
+    Cascade(LowerBoundMethods, LowerBoundMethods),
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Plan::Single(method) => write!(f, "{method:?}"),
+            Plan::Cascade(first, second) => write!(f, "{first:?} > {second:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanProfile {
+    pub plan: Plan,
+    /// Filter time on the sample
+    pub time: Duration,
+    /// Candidates on the sample
+    pub candidates: usize,
+    /// Filter time plus estimated verification time of the candidates
+    pub cost: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub best: Plan,
+    pub sampled_queries: usize,
+    /// Estimated time of verifying a single candidate
+    pub verify_cost: Duration,
+    /// Profiles of all plans, cheapest first
+    pub profiles: Vec<PlanProfile>,
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Auto profiled {} queries, verification {}us per candidate",
+            self.sampled_queries,
+            self.verify_cost.as_micros()
+        )?;
+        for p in &self.profiles {
+            writeln!(
+                f,
+                "  {}: time {}ms, candidates {}, estimated cost {}ms",
+                p.plan,
+                p.time.as_millis(),
+                p.candidates,
+                p.cost.as_millis()
+            )?;
+        }
+        write!(f, "Auto selected {}", self.best)
+    }
+}
+
+/// Evenly spaced sample of at most `sample_size` queries
+fn sample_queries(queries: &[(usize, ParsedTree)], sample_size: usize) -> Vec<(usize, ParsedTree)> {
+    let step = queries.len().div_ceil(sample_size.max(1)).max(1);
+    queries.iter().step_by(step).cloned().collect()
+}
+
+/// Average wall time of verifying a candidate with exact TED when verification
+/// runs on all threads.
+fn verification_cost(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+) -> Duration {
+    if candidates.is_empty() {
+        return Duration::ZERO;
+    }
+    let step = candidates.len().div_ceil(VERIFY_SAMPLE);
+    let sample = candidates.iter().step_by(step).collect_vec();
+    let start = Instant::now();
+    for (qid, tid) in sample.iter() {
+        std::hint::black_box(ted::ted(&queries[*qid].1, &pre.trees()[*tid]));
+    }
+    start.elapsed() / (sample.len() * rayon::current_num_threads()) as u32
+}
+
+/// Profiles all plans on a sample of queries and selects the cheapest one.
+pub fn select(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    sample_size: usize,
+    config: &RunConfig,
+) -> Result<Selection, anyhow::Error> {
+    let sample = sample_queries(queries, sample_size);
+    let mut singles = vec![];
+    for method in LowerBoundMethods::REGISTERED {
+        let (candidates, time) = run_method(method, pre, &sample, config)?;
+        singles.push((method, candidates, time));
+    }
+    // the loosest method has the widest spread of candidates to verify
+    let loosest = singles
+        .iter()
+        .max_by_key(|(_, candidates, _)| candidates.len())
+        .map_or(&[][..], |(_, candidates, _)| &candidates[..]);
+    let verify_cost = verification_cost(pre, &sample, loosest);
+
+    let mut profiles = vec![];
+    let cost = |time: Duration, candidates: usize| time + verify_cost * candidates as u32;
+    for (method, candidates, time) in singles.iter() {
+        profiles.push(PlanProfile {
+            plan: Plan::Single(*method),
+            time: *time,
+            candidates: candidates.len(),
+            cost: cost(*time, candidates.len()),
+        });
+        for (second, _, _) in singles.iter().filter(|(m, _, _)| m != method) {
+            let (refined, refine_time) = refine(*second, pre, &sample, candidates, config)?;
+            profiles.push(PlanProfile {
+                plan: Plan::Cascade(*method, *second),
+                time: *time + refine_time,
+                candidates: refined.len(),
+                cost: cost(*time + refine_time, refined.len()),
+            });
+        }
+    }
+    profiles.sort_by_key(|p| p.cost);
+    let best = profiles
+        .first()
+        .map(|p| p.plan)
+        .ok_or_else(|| anyhow::anyhow!("No lower bound method to select from"))?;
+    Ok(Selection {
+        best,
+        sampled_queries: sample.len(),
+        verify_cost,
+        profiles,
+    })
+}
+
+/// Pruning of a pipeline stage measured on the pairs left by the previous stages
+#[derive(Debug, Clone)]
+pub struct StageProfile {
+    pub method: LowerBoundMethods,
+    /// Pairs the stage was run on
+    pub pairs: usize,
+    pub pruned: usize,
+    pub time: Duration,
+}
+
+impl StageProfile {
+    pub fn time_per_pruned(&self) -> Duration {
+        self.time / self.pruned.max(1) as u32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    /// Filters in the order they are applied
+    pub stages: Vec<LowerBoundMethods>,
+    pub sampled_queries: usize,
+    /// Estimated time of verifying a single candidate
+    pub verify_cost: Duration,
+    /// Profiles of the stages, in pipeline order
+    pub profiles: Vec<StageProfile>,
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Tuner profiled {} queries, verification {}us per candidate",
+            self.sampled_queries,
+            self.verify_cost.as_micros()
+        )?;
+        for p in &self.profiles {
+            writeln!(
+                f,
+                "  {:?}: pruned {} of {} pairs, {}ns per pruned pair",
+                p.method,
+                p.pruned,
+                p.pairs,
+                p.time_per_pruned().as_nanos()
+            )?;
+        }
+        let stages = self.stages.iter().map(|m| format!("{m:?}")).join(" > ");
+        write!(f, "Tuned pipeline: {stages}")
+    }
+}
+
+/// All pairs of queries and trees within the size range of the query threshold
+fn size_range_pairs(pre: &Preprocessed, queries: &[(usize, ParsedTree)]) -> Vec<(usize, usize)> {
+    queries
+        .iter()
+        .enumerate()
+        .flat_map(|(qid, (k, query))| {
+            pre.size_buckets()
+                .within(query.count(), *k)
+                .map(move |tid| (qid, tid))
+        })
+        .collect()
+}
+
+/// Orders filters on a sample of queries, cheapest per pruned pair first. A filter is
+/// only added while it prunes pairs faster than verifying them.
+pub fn tune(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    sample_size: usize,
+    config: &RunConfig,
+) -> Result<Pipeline, anyhow::Error> {
+    let sample = sample_queries(queries, sample_size);
+    let mut pairs = size_range_pairs(pre, &sample);
+    let verify_cost = verification_cost(pre, &sample, &pairs);
+    let mut remaining = LowerBoundMethods::REGISTERED.to_vec();
+    let mut profiles = vec![];
+    while !remaining.is_empty() {
+        let mut measured = vec![];
+        for method in remaining.iter() {
+            let (refined, time) = refine(*method, pre, &sample, &pairs, config)?;
+            let profile = StageProfile {
+                method: *method,
+                pairs: pairs.len(),
+                pruned: pairs.len() - refined.len(),
+                time,
+            };
+            measured.push((profile, refined));
+        }
+        let Some((profile, refined)) = measured
+            .into_iter()
+            .filter(|(p, _)| p.pruned > 0 && p.time <= verify_cost * p.pruned as u32)
+            .min_by_key(|(p, _)| p.time_per_pruned())
+        else {
+            break;
+        };
+        remaining.retain(|m| *m != profile.method);
+        profiles.push(profile);
+        pairs = refined;
+    }
+    Ok(Pipeline {
+        stages: profiles.iter().map(|p| p.method).collect(),
+        sampled_queries: sample.len(),
+        verify_cost,
+        profiles,
+    })
+}
+
+/// Runs filters in order on all queries, returns sorted candidates and the time it took.
+/// Without stages all pairs within the size range are candidates.
+pub fn run_pipeline(
+    stages: &[LowerBoundMethods],
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    config: &RunConfig,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    let Some((first, rest)) = stages.split_first() else {
+        let start = Instant::now();
+        return Ok((size_range_pairs(pre, queries), start.elapsed()));
+    };
+    let (mut candidates, mut time) = run_method(*first, pre, queries, config)?;
+    for method in rest {
+        let (refined, refine_time) = refine(*method, pre, queries, &candidates, config)?;
+        candidates = refined;
+        time += refine_time;
+    }
+    Ok((candidates, time))
+}
+
+/// Runs a plan on all queries, returns sorted candidates and the time it took.
+pub fn run_plan(
+    plan: Plan,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    config: &RunConfig,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    match plan {
+        Plan::Single(method) => run_method(method, pre, queries, config),
+        Plan::Cascade(first, second) => {
+            let (candidates, first_time) = run_method(first, pre, queries, config)?;
+            let (refined, refine_time) = refine(second, pre, queries, &candidates, config)?;
+            Ok((refined, first_time + refine_time))
+        }
+    }
+}
+
+/// Methods with a lower bound of single pairs that [`refine`] supports
+pub fn has_bounds(method: LowerBoundMethods) -> bool {
+    use LowerBoundMethods as LBM;
+    matches!(
+        method,
+        LBM::Lblint | LBM::Sed | LBM::Structural | LBM::StructuralSplit
+    )
+}
+
+/// Keeps only candidates whose lower bound of `method` is within the query threshold.
+pub fn refine(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    config: &RunConfig,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let label_dict = pre.label_dict();
+    let result = match method {
+        LBM::Lblint => {
+            let lblint_queries =
+                index_queries::<InvertedListLabelPostorderIndex>(queries, label_dict)?;
+            let label_sets = pre.label_sets()?;
+            match config.label_weights {
+                Some(weights) => refine_with(&lblint_queries, label_sets, candidates, |q, t, k| {
+                    weighted_label_intersection_k(q, t, k, |l| weights.get(l))
+                }),
+                None => refine_with(
+                    &lblint_queries,
+                    label_sets,
+                    candidates,
+                    label_intersection_k,
+                ),
+            }
+        }
+        LBM::Sed => {
+            let sed_queries = index_queries::<SEDIndex>(queries, label_dict)?;
+            refine_with(&sed_queries, pre.traversals()?, candidates, sed_k)
+        }
+        LBM::Structural => {
+            let structural_queries = pre.structural_queries(queries)?;
+            refine_with(
+                &structural_queries,
+                pre.structural_sets()?,
+                candidates,
+                |q, t, k| ted_matching(q, t, k, config.structural_matching, config.tight_window),
+            )
+        }
+        LBM::StructuralSplit => {
+            let split_queries = pre.split_queries(queries)?;
+            refine_with(
+                &split_queries,
+                pre.split_sets()?,
+                candidates,
+                struct_split_ted_k,
+            )
+        }
+        _ => anyhow::bail!("Method {method:?} is not supported yet"),
+    };
+    Ok(result)
+}
+
+fn refine_with<Q: Sync, T: Sync>(
+    queries: &[(usize, Q)],
+    tree_indexes: &[T],
+    candidates: &[(usize, usize)],
+    lb_func: impl Fn(&Q, &T, usize) -> usize + Sync,
+) -> (Vec<(usize, usize)>, Duration) {
+    let start = Instant::now();
+    let refined = candidates
+        .par_iter()
+        .copied()
+        .filter(|(qid, tid)| {
+            let (t, query) = &queries[*qid];
+            lb_func(query, &tree_indexes[*tid], *t) <= *t
+        })
+        .collect();
+    (refined, start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_auto_plan_matches_methods() {
+        let mut ld = LabelDict::default();
+        let mut trees = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{x{b}{c}}",
+            "{a{b{c}}{d}}",
+            "{a{b}{c}{d}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld))
+        .to_vec();
+        trees.sort_by_key(|t| t.count());
+        let queries = vec![
+            (1, parse_single("{a{b}{c}}".to_owned(), &mut ld)),
+            (2, parse_single("{a{b{c}}}".to_owned(), &mut ld)),
+        ];
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let config = RunConfig::default();
+        let selection = select(&pre, &queries, 1, &config).unwrap();
+        assert_eq!(selection.sampled_queries, 1);
+        assert_eq!(selection.profiles.len(), 9);
+
+        // a cascade gives the intersection of candidates of both methods
+        let (lblint, _) = run_method(LowerBoundMethods::Lblint, &pre, &queries, &config).unwrap();
+        let (sed, _) = run_method(LowerBoundMethods::Sed, &pre, &queries, &config).unwrap();
+        let plan = Plan::Cascade(LowerBoundMethods::Lblint, LowerBoundMethods::Sed);
+        let (cascade, _) = run_plan(plan, &pre, &queries, &config).unwrap();
+        let expected = lblint.into_iter().filter(|c| sed.contains(c)).collect_vec();
+        assert_eq!(cascade, expected);
+
+        // re-checking keeps the candidates the tighter split filter passes
+        let (rechecked, _) = refine(
+            LowerBoundMethods::StructuralSplit,
+            &pre,
+            &queries,
+            &cascade,
+            &config,
+        )
+        .unwrap();
+        let split_queries = pre.split_queries(&queries).unwrap();
+        let split_sets = pre.split_sets().unwrap();
+        let expected = cascade
+            .iter()
+            .copied()
+            .filter(|(qid, tid)| {
+                let (k, query) = &split_queries[*qid];
+                struct_split_ted_k(query, &split_sets[*tid], *k) <= *k
+            })
+            .collect_vec();
+        assert_eq!(rechecked, expected);
+        assert!(refine(LowerBoundMethods::Bib, &pre, &queries, &cascade, &config).is_err());
+
+        let pipeline = tune(&pre, &queries, 2, &config).unwrap();
+        let (tuned, _) = run_pipeline(&pipeline.stages, &pre, &queries, &config).unwrap();
+        let mut expected = size_range_pairs(&pre, &queries);
+        for method in pipeline.stages.iter() {
+            let (candidates, _) = run_method(*method, &pre, &queries, &config).unwrap();
+            expected.retain(|c| candidates.contains(c));
+        }
+        assert_eq!(tuned, expected);
+    }
+}
@@ -1,12 +1,20 @@
 //! This module implements binary branch label converter and lower bound distance
 
+use crate::lb::size_buckets::size_lb;
 use crate::parsing::{LabelId, ParsedTree};
 use indextree::NodeId;
 use itertools::Itertools;
 use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
 
+/// Divisor of the L1 distance of binary branch vectors. One edit operation changes at most
+/// 5 binary branches (Yang, Kalnis, Tung, SIGMOD 2005), so the L1 distance divided by 5 and
+/// rounded up is a lower bound of the tree edit distance. Larger divisors are looser.
+pub const BRANCH_DIVISOR: usize = 5;
+
 pub type BinaryBranchVector = FxHashMap<i32, i32>;
+#[derive(Serialize, Deserialize)]
 pub struct BinaryBranchTuple(usize, BinaryBranchVector);
 
 // Binary branch tuple (root label, left label, right label)
@@ -25,12 +33,12 @@ impl BinaryBranchConverter {
         trees
             .iter()
             .map(|tree| {
-                let Some(root) = tree.iter().next() else {
-                    panic!("tree is empty");
-                };
-                let root_id = tree.get_node_id(root).unwrap();
                 let mut branch_vector = BinaryBranchVector::default();
-                self.create_vector(&root_id, tree, None, &mut branch_vector);
+                // an empty tree has no branches, its bound is the size of the other tree
+                if let Some(root) = tree.iter().next() {
+                    let root_id = tree.get_node_id(root).unwrap();
+                    self.create_vector(&root_id, tree, None, &mut branch_vector);
+                }
                 BinaryBranchTuple(tree.count(), branch_vector)
             })
             .collect_vec()
@@ -79,9 +87,9 @@ impl BinaryBranchConverter {
     }
 }
 
-pub fn ted(t1: &BinaryBranchTuple, t2: &BinaryBranchTuple, k: usize) -> usize {
+pub fn ted(t1: &BinaryBranchTuple, t2: &BinaryBranchTuple, k: usize, divisor: usize) -> usize {
     let (t1s, t2s) = (t1.0, t2.0);
-    if t1s.abs_diff(t2s) > k {
+    if size_lb(t1s, t2s, k) > k {
         return k + 1;
     }
     let mut intersection_size = 0usize;
@@ -93,7 +101,5 @@ pub fn ted(t1: &BinaryBranchTuple, t2: &BinaryBranchTuple, k: usize) -> usize {
         intersection_size += min(*t2postings, *postings) as usize;
     }
 
-    // l1_diff / 5
-    ((t1s + t2s) - (2 * intersection_size)) / 5
-    // ((t1s + t2s) - (l1_diff)) / 5
+    ((t1s + t2s) - (2 * intersection_size)).div_ceil(divisor)
 }
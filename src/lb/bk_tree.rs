@@ -0,0 +1,195 @@
+//! BK-tree over the traversals of the string edit distance bound.
+//!
+//! The maximum of the preorder and postorder string edit distances is a metric, so
+//! subtrees of a node whose edge distance differs from the query distance by more than
+//! the threshold can not contain a result. This is an alternative to the scan of all
+//! trees within the size range for collections where most trees are far apart.
+use super::sed::{sed, sed_k};
+use crate::indexing::SEDIndex;
+use crate::memory::MemoryUsage;
+use rayon::prelude::*;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+struct Node {
+    item: usize,
+    /// distance to the item of the child node and the child node
+    children: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+}
+
+impl MemoryUsage for BkTree {
+    fn heap_bytes(&self) -> usize {
+        self.nodes.capacity() * std::mem::size_of::<Node>()
+            + self
+                .nodes
+                .iter()
+                .map(|n| n.children.capacity() * std::mem::size_of::<(usize, usize)>())
+                .sum::<usize>()
+    }
+}
+
+impl BkTree {
+    /// Builds the tree over all `items`, nodes refer to items by their position
+    pub fn new(items: &[SEDIndex]) -> Self {
+        let mut tree = Self {
+            nodes: Vec::with_capacity(items.len()),
+        };
+        for item in 0..items.len() {
+            tree.insert(items, item);
+        }
+        tree
+    }
+
+    fn insert(&mut self, items: &[SEDIndex], item: usize) {
+        let new = self.nodes.len();
+        self.nodes.push(Node {
+            item,
+            children: vec![],
+        });
+        if new == 0 {
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let distance = sed(&items[self.nodes[current].item], &items[item]);
+            match self.nodes[current]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+            {
+                Some((_, child)) => current = *child,
+                None => {
+                    self.nodes[current].children.push((distance, new));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Items within `k` of the query, in increasing order
+    pub fn within(&self, items: &[SEDIndex], query: &SEDIndex, k: usize) -> Vec<usize> {
+        let mut result = vec![];
+        let mut stack = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            // distances above the largest edge plus k prune all children
+            let bound = node.children.iter().map(|(d, _)| d + k).max().unwrap_or(k);
+            let distance = sed_k(query, &items[node.item], bound);
+            if distance <= k {
+                result.push(node.item);
+            }
+            if distance > bound {
+                continue;
+            }
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= k)
+                    .map(|(_, child)| *child),
+            );
+        }
+        result.sort_unstable();
+        result
+    }
+
+    /// `n` items closest to the query as `(distance, item)`, closest first and ties
+    /// broken by item
+    pub fn nearest(&self, items: &[SEDIndex], query: &SEDIndex, n: usize) -> Vec<(usize, usize)> {
+        let mut best: BinaryHeap<(usize, usize)> = BinaryHeap::with_capacity(n + 1);
+        let mut stack = if self.nodes.is_empty() || n == 0 {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let distance = sed(query, &items[node.item]);
+            best.push((distance, node.item));
+            if best.len() > n {
+                best.pop();
+            }
+            let radius = if best.len() < n {
+                usize::MAX
+            } else {
+                best.peek().unwrap().0
+            };
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= radius)
+                    .map(|(_, child)| *child),
+            );
+        }
+        best.into_sorted_vec()
+    }
+
+    /// Candidates of all queries as (query id, tree id) pairs and the time it took
+    pub fn run(
+        &self,
+        items: &[SEDIndex],
+        queries: &[(usize, SEDIndex)],
+    ) -> (Vec<(usize, usize)>, Duration) {
+        let start = Instant::now();
+        let candidates = queries
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(qid, (k, query))| {
+                self.within(items, query, *k)
+                    .into_iter()
+                    .map(move |tid| (qid, tid))
+            })
+            .collect();
+        (candidates, start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::Indexer;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_matches_scan() {
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a}",
+            "{b}",
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{a{c}{b}}",
+            "{a{b{c}}}",
+            "{x{y}{z}{w}}",
+            "{a{b}{c}{d}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let items = trees
+            .iter()
+            .map(|t| SEDIndex::index_tree(t, &ld).unwrap())
+            .collect::<Vec<_>>();
+        let bk_tree = BkTree::new(&items);
+        for query in items.iter() {
+            for k in 0..4 {
+                let scan = (0..items.len())
+                    .filter(|i| sed_k(query, &items[*i], k) <= k)
+                    .collect::<Vec<_>>();
+                assert_eq!(bk_tree.within(&items, query, k), scan);
+            }
+            let mut distances = (0..items.len())
+                .map(|i| (sed(query, &items[i]), i))
+                .collect::<Vec<_>>();
+            distances.sort_unstable();
+            assert_eq!(bk_tree.nearest(&items, query, 3), distances[..3]);
+        }
+    }
+}
@@ -1,23 +1,180 @@
+use crate::lb::label_postings::{dense_keys, DenseCounts};
 use crate::parsing::{LabelDict, LabelId, ParsedTree};
+use clap::ValueEnum;
 use indextree::NodeId;
+use itertools::Either;
 
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::time::Instant;
 
 type Histogram<K = u32, V = u32> = HashMap<K, V>;
 
+/// Leaf distance, degree and label histograms of a tree
+pub type TreeHistograms = (Histogram, Histogram, Histogram<LabelId, u32>);
+
 pub type Candidate = (usize, usize);
 pub type Candidates = Vec<Candidate>;
 
+/// Divisors of the L1 distances of the leaf distance and degree histograms of two trees.
+/// A unit cost edit operation changes the L1 distance by at most the divisor, so the L1
+/// distance divided by it and rounded up is a lower bound of the tree edit distance:
+///
+/// - leaf distance: inserting or deleting a node adds or removes its count and shifts the
+///   leaf distances of a chain of ancestors by one, their leaf distances are consecutive,
+///   so the counts of the chain move by one place and the L1 distance changes by 1
+/// - degree: deleting a node removes the count of its degree and moves its parent to
+///   another degree, 3 counts change, inserting is the reverse
+///
+/// Renames change neither histogram. Label histograms use the label intersection bound,
+/// which is tighter than their L1 distance halved. Larger divisors give looser bounds,
+/// smaller ones are not lower bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramDivisors {
+    pub leaf: usize,
+    pub degree: usize,
+}
+
+impl HistogramDivisors {
+    pub const TIGHT: Self = Self { leaf: 1, degree: 3 };
+}
+
+impl Default for HistogramDivisors {
+    fn default() -> Self {
+        Self::TIGHT
+    }
+}
+
+/// Sum of the smaller counts of every key
+fn intersection<K: Eq + Hash>(h1: &Histogram<K, u32>, h2: &Histogram<K, u32>) -> usize {
+    h1.iter()
+        .map(|(key, count)| std::cmp::min(*count, *h2.get(key).unwrap_or(&0)) as usize)
+        .sum()
+}
+
+/// L1 distance of histograms of trees of `size1` and `size2` nodes divided by the divisor
+/// and rounded up
+pub fn l1_bound(size1: usize, size2: usize, intersection: usize, divisor: usize) -> usize {
+    (size1 + size2 - 2 * intersection).div_ceil(divisor)
+}
+
+/// Largest bound of the three histograms of two trees of `size1` and `size2` nodes
+pub fn histogram_ted(
+    (size1, h1): (usize, &TreeHistograms),
+    (size2, h2): (usize, &TreeHistograms),
+    divisors: HistogramDivisors,
+) -> usize {
+    let leaf = l1_bound(size1, size2, intersection(&h1.0, &h2.0), divisors.leaf);
+    let degree = l1_bound(size1, size2, intersection(&h1.1, &h2.1), divisors.degree);
+    let label = size1.max(size2) - intersection(&h1.2, &h2.2);
+    leaf.max(degree).max(label)
+}
+
+/// Trees with a key of the histograms and the count of the key, dense for the keys in many
+/// trees
+#[derive(Clone)]
+enum HistogramPostings {
+    Sparse(Vec<(usize, u32)>),
+    Dense(DenseCounts),
+}
+
+impl HistogramPostings {
+    fn push(&mut self, tree_id: usize, count: u32) {
+        match self {
+            Self::Sparse(postings) => postings.push((tree_id, count)),
+            Self::Dense(counts) => counts.set(tree_id, count),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        match self {
+            Self::Sparse(postings) => Either::Left(postings.iter().copied()),
+            Self::Dense(counts) => Either::Right(counts.within(0..counts.trees())),
+        }
+    }
+}
+
+/// Empty postings of `keys` keys of the histograms, keys are positions given by `index`
+fn histogram_postings<K: Copy + Ord + Hash>(
+    hists: &[(usize, Histogram<K, u32>)],
+    keys: usize,
+    index: impl Fn(K) -> usize,
+) -> Vec<HistogramPostings> {
+    let mut frequencies = HashMap::<K, usize>::new();
+    for (_, hist) in hists {
+        for key in hist.keys() {
+            *frequencies.entry(*key).or_default() += 1;
+        }
+    }
+    let mut postings = vec![HistogramPostings::Sparse(vec![]); keys];
+    for key in dense_keys(frequencies, hists.len()) {
+        postings[index(key)] = HistogramPostings::Dense(DenseCounts::new(hists.len()));
+    }
+    postings
+}
+
+/// Histogram filters of a self join of the collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HistFilter {
+    /// Label filter followed by the leaf distance and degree filters
+    All,
+    Leaf,
+    Degree,
+    Label,
+}
+
+/// Joins the size sorted collection with itself, returns the filter time of every tree in
+/// microseconds and the candidate pairs, a tree with the smaller trees before it
+pub fn join(
+    filter: HistFilter,
+    tree_collection: &[ParsedTree],
+    label_dict: &LabelDict,
+    k: usize,
+    divisors: HistogramDivisors,
+) -> (Vec<u128>, Candidates) {
+    let (leaf_hist, degree_hist, label_hist) = create_collection_histograms(tree_collection);
+    match filter {
+        HistFilter::All => index_lookup(
+            &leaf_hist,
+            &degree_hist,
+            &label_hist,
+            label_dict,
+            k,
+            divisors,
+        ),
+        HistFilter::Leaf => leaf_index_lookup(&leaf_hist, k, divisors.leaf),
+        HistFilter::Degree => degree_index_lookup(&degree_hist, k, divisors.degree),
+        HistFilter::Label => label_index_lookup(&label_hist, label_dict, k),
+    }
+}
+
+/// Number of postings of leaf distance or degree histograms, one past their largest key
+fn key_count(hists: &[(usize, Histogram)]) -> usize {
+    hists
+        .iter()
+        .flat_map(|(_, hist)| hist.keys())
+        .max()
+        .map_or(0, |key| *key as usize + 1)
+}
+
 /// Will convert into histograms before getting candidates
 pub fn collection_index_lookup(
     tree_collection: &[ParsedTree],
     label_dict: &LabelDict,
     k: usize,
+    divisors: HistogramDivisors,
 ) -> Candidates {
     // assumes tree collection is sorted by tree size
     let (leaf_hist, degree_hist, label_hist) = create_collection_histograms(tree_collection);
-    index_lookup(&leaf_hist, &degree_hist, &label_hist, label_dict, k).1
+    index_lookup(
+        &leaf_hist,
+        &degree_hist,
+        &label_hist,
+        label_dict,
+        k,
+        divisors,
+    )
+    .1
 }
 
 pub fn index_lookup(
@@ -26,12 +183,13 @@ pub fn index_lookup(
     label_hist: &[(usize, Histogram<LabelId, u32>)],
     label_dict: &LabelDict,
     k: usize,
+    divisors: HistogramDivisors,
 ) -> (Vec<u128>, Candidates) {
     let mut filter_times = vec![];
     let mut candidates = vec![];
     // this is the inverted index, that will be indexed by labelId, and contains a vector of pairs
     // (tree_id, labelId_count_in_tree)
-    let mut il_index = vec![vec![]; label_dict.len() + 1];
+    let mut il_index = histogram_postings(label_hist, label_dict.len(), |label| label.index());
 
     // label intersections counter for each tree. Counts with how many other trees it has an intersection
     // this is here to compute the symmetric difference faster
@@ -55,17 +213,17 @@ pub fn index_lookup(
 
         // get pre-candidates by looking up the inverted index and doing the label intersection
         for (label_id, label_count) in tree_label_histogram.iter() {
-            for (other_tree_id, other_label_count) in il_index[*label_id as usize].iter() {
-                let intersection_size = *std::cmp::min(other_label_count, label_count);
-                if intersections_count[*other_tree_id] == 0 && intersection_size > 0 {
-                    pre_candidates.push(*other_tree_id);
+            for (other_tree_id, other_label_count) in il_index[label_id.index()].iter() {
+                let intersection_size = std::cmp::min(other_label_count, *label_count);
+                if intersections_count[other_tree_id] == 0 && intersection_size > 0 {
+                    pre_candidates.push(other_tree_id);
                 }
-                intersections_count[*other_tree_id] = std::cmp::min(
-                    intersections_count[*other_tree_id] + intersection_size,
+                intersections_count[other_tree_id] = std::cmp::min(
+                    intersections_count[other_tree_id] + intersection_size,
                     *tree_size as u32,
                 )
             }
-            il_index[*label_id as usize].push((tree_id, *label_count));
+            il_index[label_id.index()].push(tree_id, *label_count);
         }
 
         // verify precandidates
@@ -94,21 +252,16 @@ pub fn index_lookup(
         .filter(|(t1, t2)| {
             let (t1size, t1hist) = &leaf_hist[*t1];
             let (t2size, t2hist) = &leaf_hist[*t2];
-            let intersection_size = t1hist.iter().fold(0, |intersection, (degree, count)| {
-                intersection + std::cmp::min(count, t2hist.get(degree).unwrap_or(&0))
-            }) as usize;
+            let intersection_size = intersection(t1hist, t2hist);
 
-            (t1size + t2size) - (2 * intersection_size) <= k
+            l1_bound(*t1size, *t2size, intersection_size, divisors.leaf) <= k
         })
         .filter(|(t1, t2)| {
             let (t1size, t1hist) = &degree_hist[*t1];
             let (t2size, t2hist) = &degree_hist[*t2];
+            let intersection_size = intersection(t1hist, t2hist);
 
-            let intersection_size = t1hist.iter().fold(0, |intersection, (degree, count)| {
-                intersection + std::cmp::min(count, t2hist.get(degree).unwrap_or(&0))
-            }) as usize;
-
-            ((t1size + t2size) - (2 * intersection_size)) / 5 <= k
+            l1_bound(*t1size, *t2size, intersection_size, divisors.degree) <= k
         })
         .collect();
 
@@ -118,14 +271,14 @@ pub fn index_lookup(
 // for some testing purposes, implement only single label filter
 pub fn leaf_index_lookup(
     leaf_hist: &[(usize, Histogram)],
-    label_dict: &LabelDict,
     k: usize,
+    divisor: usize,
 ) -> (Vec<u128>, Candidates) {
     let mut filter_times = Vec::with_capacity(leaf_hist.len());
     let mut candidates = vec![];
     // this is the inverted index, that will be indexed by labelId, and contains a vector of pairs
     // (tree_id, labelId_count_in_tree)
-    let mut il_index = vec![vec![]; label_dict.len() + 1];
+    let mut il_index = histogram_postings(leaf_hist, key_count(leaf_hist), |key| key as usize);
 
     // label intersections counter for each tree. Counts with how many other trees it has an intersection
     // this is here to compute the symmetric difference faster
@@ -139,19 +292,25 @@ pub fn leaf_index_lookup(
         for (leaf_distance_path, leaf_distance_count) in tree_leaf_histogram.iter() {
             for (other_tree_id, other_label_count) in il_index[*leaf_distance_path as usize].iter()
             {
-                let intersection_size = *std::cmp::min(other_label_count, leaf_distance_count);
-                if intersections_count[*other_tree_id] == 0 && intersection_size > 0 {
-                    pre_candidates.push(*other_tree_id);
+                let intersection_size = std::cmp::min(other_label_count, *leaf_distance_count);
+                if intersections_count[other_tree_id] == 0 && intersection_size > 0 {
+                    pre_candidates.push(other_tree_id);
                 }
-                intersections_count[*other_tree_id] += intersection_size as usize;
+                intersections_count[other_tree_id] += intersection_size as usize;
             }
-            il_index[*leaf_distance_path as usize].push((tree_id, *leaf_distance_count));
+            il_index[*leaf_distance_path as usize].push(tree_id, *leaf_distance_count);
         }
 
         // verify pre-candidates
         for pre_cand_id in pre_candidates.iter() {
             let other_tree_size = leaf_hist[*pre_cand_id].0;
-            if (tree_size + other_tree_size) - (intersections_count[*pre_cand_id] * 2) <= k {
+            if l1_bound(
+                *tree_size,
+                other_tree_size,
+                intersections_count[*pre_cand_id],
+                divisor,
+            ) <= k
+            {
                 candidates.push((tree_id, *pre_cand_id))
             }
             intersections_count[*pre_cand_id] = 0;
@@ -164,14 +323,14 @@ pub fn leaf_index_lookup(
 
 pub fn degree_index_lookup(
     degree_hist: &[(usize, Histogram)],
-    label_dict: &LabelDict,
     k: usize,
+    divisor: usize,
 ) -> (Vec<u128>, Candidates) {
     let mut filter_times = Vec::with_capacity(degree_hist.len());
     let mut candidates = vec![];
     // this is the inverted index, that will be indexed by labelId, and contains a vector of pairs
     // (tree_id, labelId_count_in_tree)
-    let mut il_index = vec![vec![]; label_dict.len() + 1];
+    let mut il_index = histogram_postings(degree_hist, key_count(degree_hist), |key| key as usize);
 
     // label intersections counter for each tree. Counts with how many other trees it has an intersection
     // this is here to compute the symmetric difference faster
@@ -184,19 +343,25 @@ pub fn degree_index_lookup(
         // get pre-candidates by looking up the inverted index and doing the label intersection
         for (degree_id, degrees_count) in tree_degree_histogram.iter() {
             for (other_tree_id, other_label_count) in il_index[*degree_id as usize].iter() {
-                let intersection_size = *std::cmp::min(other_label_count, degrees_count);
-                if intersections_count[*other_tree_id] == 0 && intersection_size > 0 {
-                    pre_candidates.push(*other_tree_id);
+                let intersection_size = std::cmp::min(other_label_count, *degrees_count);
+                if intersections_count[other_tree_id] == 0 && intersection_size > 0 {
+                    pre_candidates.push(other_tree_id);
                 }
-                intersections_count[*other_tree_id] += intersection_size as usize;
+                intersections_count[other_tree_id] += intersection_size as usize;
             }
-            il_index[*degree_id as usize].push((tree_id, *degrees_count));
+            il_index[*degree_id as usize].push(tree_id, *degrees_count);
         }
 
         // verify pre-candidates
         for pre_cand_id in pre_candidates.iter() {
             let other_tree_size = degree_hist[*pre_cand_id].0;
-            if ((tree_size + other_tree_size) - (2 * intersections_count[*pre_cand_id])) / 3 <= k {
+            if l1_bound(
+                *tree_size,
+                other_tree_size,
+                intersections_count[*pre_cand_id],
+                divisor,
+            ) <= k
+            {
                 candidates.push((tree_id, *pre_cand_id))
             }
             intersections_count[*pre_cand_id] = 0;
@@ -217,7 +382,7 @@ pub fn label_index_lookup(
     let mut candidates = vec![];
     // this is the inverted index, that will be indexed by labelId, and contains a vector of pairs
     // (tree_id, labelId_count_in_tree)
-    let mut il_index = vec![vec![]; label_dict.len() + 1];
+    let mut il_index = histogram_postings(label_hist, label_dict.len(), |label| label.index());
 
     // label intersections counter for each tree. Counts with how many other trees it has an intersection
     // this is here to compute the symmetric difference faster
@@ -241,17 +406,17 @@ pub fn label_index_lookup(
 
         // get pre-candidates by looking up the inverted index and doing the label intersection
         for (label_id, label_count) in tree_label_histogram.iter() {
-            for (other_tree_id, other_label_count) in il_index[*label_id as usize].iter() {
-                let intersection_size = *std::cmp::min(other_label_count, label_count);
-                if intersections_count[*other_tree_id] == 0 && intersection_size > 0 {
-                    pre_candidates.push(*other_tree_id);
+            for (other_tree_id, other_label_count) in il_index[label_id.index()].iter() {
+                let intersection_size = std::cmp::min(other_label_count, *label_count);
+                if intersections_count[other_tree_id] == 0 && intersection_size > 0 {
+                    pre_candidates.push(other_tree_id);
                 }
-                intersections_count[*other_tree_id] = std::cmp::min(
-                    intersections_count[*other_tree_id] + intersection_size,
+                intersections_count[other_tree_id] = std::cmp::min(
+                    intersections_count[other_tree_id] + intersection_size,
                     *tree_size as u32,
                 )
             }
-            il_index[*label_id as usize].push((tree_id, *label_count));
+            il_index[label_id.index()].push(tree_id, *label_count);
         }
 
         // verify precandidates
@@ -302,18 +467,17 @@ pub fn create_collection_histograms(
     (leaf_hists, degree_hists, label_hists)
 }
 
-/// Creates and returns Leaf, Degree and Label histograms respectively
-pub fn create_tree_histograms(
-    tree: &ParsedTree,
-) -> (Histogram, Histogram, Histogram<LabelId, u32>) {
-    let Some(root) = tree.iter().next() else {
-        panic!("Unable to get tree root, but tree is not empty!");
-    };
+/// Creates and returns Leaf, Degree and Label histograms respectively, all empty for an
+/// empty tree
+pub fn create_tree_histograms(tree: &ParsedTree) -> TreeHistograms {
     let (mut label, mut degree, mut leaf) = (
         Histogram::<LabelId, u32>::new(),
         Histogram::new(),
         Histogram::new(),
     );
+    let Some(root) = tree.iter().next() else {
+        return (leaf, degree, label);
+    };
     let root_id = tree.get_node_id(root).unwrap();
     traverse_tree(&root_id, tree, &mut label, &mut degree, &mut leaf);
 
@@ -359,8 +523,140 @@ fn traverse_tree(
 
 #[cfg(test)]
 mod tests {
-    
-    
+    use super::*;
+    use crate::generate::{generate, GeneratorConfig, SizeDistribution};
+    use crate::parsing::parse_single;
+    use crate::ted::ted;
+
+    #[test]
+    fn test_histogram_bounds_below_ted() {
+        let brackets = generate(&GeneratorConfig {
+            count: 600,
+            min_size: 1,
+            max_size: 9,
+            size_distribution: SizeDistribution::Uniform,
+            max_fanout: 0,
+            depth_bias: 0.5,
+            alphabet: 3,
+            zipf: 0.0,
+            seed: 5,
+        });
+        let mut ld = LabelDict::default();
+        let trees = brackets
+            .into_iter()
+            .map(|b| parse_single(b, &mut ld))
+            .collect::<Vec<_>>();
+        let hists = trees.iter().map(create_tree_histograms).collect::<Vec<_>>();
+        let divisors = HistogramDivisors::TIGHT;
+        let mut tight = [0; 2];
+        for (t1, t2) in (0..trees.len()).step_by(2).map(|i| (i, i + 1)) {
+            let exact = ted(&trees[t1], &trees[t2]);
+            let (s1, s2) = (trees[t1].count(), trees[t2].count());
+            let (h1, h2) = (&hists[t1], &hists[t2]);
+            let leaf = l1_bound(s1, s2, intersection(&h1.0, &h2.0), divisors.leaf);
+            let degree = l1_bound(s1, s2, intersection(&h1.1, &h2.1), divisors.degree);
+            assert!(leaf <= exact, "leaf {leaf} above {exact}");
+            assert!(degree <= exact, "degree {degree} above {exact}");
+            assert!(histogram_ted((s1, h1), (s2, h2), divisors) <= exact);
+            tight[0] += usize::from(leaf == exact && exact > 0);
+            tight[1] += usize::from(degree == exact && exact > 0);
+        }
+        // both divisors are reached, smaller ones would exceed the distance
+        assert!(tight.iter().all(|t| *t > 0), "{tight:?}");
+
+        // a leaf below a leaf shifts the leaf distances of all ancestors
+        let t1 = parse_single("{a{b{c}}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{b{c{d}}}}".to_owned(), &mut ld);
+        let (h1, h2) = (create_tree_histograms(&t1), create_tree_histograms(&t2));
+        assert_eq!(l1_bound(3, 4, intersection(&h1.0, &h2.0), 1), 1);
+        // deleting b removes a degree 2, its parent moves from degree 2 to 3
+        let t1 = parse_single("{a{b{c}{d}}{e}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{c}{d}{e}}".to_owned(), &mut ld);
+        let (h1, h2) = (create_tree_histograms(&t1), create_tree_histograms(&t2));
+        assert_eq!(l1_bound(5, 4, intersection(&h1.1, &h2.1), 1), 3);
+    }
+
+    #[test]
+    fn test_index_lookup_matches_pairs() {
+        let brackets = generate(&GeneratorConfig {
+            count: 200,
+            min_size: 1,
+            max_size: 9,
+            size_distribution: SizeDistribution::Uniform,
+            max_fanout: 0,
+            depth_bias: 0.5,
+            alphabet: 3,
+            zipf: 0.0,
+            seed: 9,
+        });
+        let mut ld = LabelDict::default();
+        let mut trees = brackets
+            .into_iter()
+            .map(|b| parse_single(b, &mut ld))
+            .collect::<Vec<_>>();
+        trees.sort_by_key(|t| t.count());
+        // all 3 labels are in most trees, so their postings are dense
+        let (leaf, degree, label) = create_collection_histograms(&trees);
+        let divisors = HistogramDivisors::TIGHT;
+        for k in 0..4 {
+            let pairs = (0..trees.len()).flat_map(|t1| (0..t1).map(move |t2| (t1, t2)));
+            let expected = pairs
+                .filter(|(t1, t2)| {
+                    let ((s1, h1), (s2, h2)) = (&label[*t1], &label[*t2]);
+                    s1.max(s2) - intersection(h1, h2) <= k
+                })
+                .collect::<Vec<_>>();
+            let (_, mut found) = label_index_lookup(&label, &ld, k);
+            found.sort_unstable();
+            assert_eq!(found, expected, "k={k}");
+
+            let expected = expected
+                .into_iter()
+                .filter(|(t1, t2)| {
+                    let (s1, s2) = (trees[*t1].count(), trees[*t2].count());
+                    let leaf_overlap = intersection(&leaf[*t1].1, &leaf[*t2].1);
+                    let degree_overlap = intersection(&degree[*t1].1, &degree[*t2].1);
+                    l1_bound(s1, s2, leaf_overlap, divisors.leaf) <= k
+                        && l1_bound(s1, s2, degree_overlap, divisors.degree) <= k
+                })
+                .collect::<Vec<_>>();
+            let (_, mut found) = index_lookup(&leaf, &degree, &label, &ld, k, divisors);
+            found.sort_unstable();
+            assert_eq!(found, expected, "k={k}");
+        }
+    }
+
+    #[test]
+    fn test_join_keeps_pairs_within_k() {
+        let brackets = generate(&GeneratorConfig {
+            count: 80,
+            min_size: 1,
+            max_size: 7,
+            size_distribution: SizeDistribution::Uniform,
+            max_fanout: 0,
+            depth_bias: 0.5,
+            alphabet: 3,
+            zipf: 0.0,
+            seed: 11,
+        });
+        let mut ld = LabelDict::default();
+        let mut trees = brackets
+            .into_iter()
+            .map(|b| parse_single(b, &mut ld))
+            .collect::<Vec<_>>();
+        trees.sort_by_key(|t| t.count());
+        let k = 2;
+        let within = (0..trees.len())
+            .flat_map(|t1| (0..t1).map(move |t2| (t1, t2)))
+            .filter(|(t1, t2)| ted(&trees[*t1], &trees[*t2]) <= k)
+            .collect::<Vec<_>>();
+        for filter in HistFilter::value_variants() {
+            let (times, candidates) = join(*filter, &trees, &ld, k, HistogramDivisors::TIGHT);
+            assert_eq!(times.len(), trees.len());
+            let missed = within.iter().filter(|pair| !candidates.contains(pair));
+            assert_eq!(missed.count(), 0, "{filter:?}");
+        }
+    }
 
     /*
     #[test]
@@ -1,138 +1,280 @@
 use std::time::{Duration, Instant};
 
+use clap::ValueEnum;
 use itertools::Itertools;
 use rustc_hash::FxHashMap;
 
+use crate::lb::sed::bounded_string_edit_distance;
+use crate::lb::size_buckets::size_lb;
+use crate::memory::MemoryUsage;
+use crate::parsing::LabelId;
+
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
 struct QSig {
-    sig: Vec<i32>,
+    sig: Vec<LabelId>,
     pos: i32,
 }
 
+/// Which side of a query is split into non-overlapping q-chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum GramScheme {
+    /// Index overlapping q-grams of the data strings, probe with q-chunks of the query
+    #[default]
+    QueryChunks,
+    /// Index q-chunks of the data strings, probe with overlapping q-grams of the query.
+    /// Short data strings have few chunks, so larger thresholds of short queries are served.
+    IndexChunks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexGramOptions {
+    pub q: usize,
+    /// Largest q, a query is chunked by the largest q from `q` to `max_q` that still
+    /// leaves more chunks than its threshold. Only used with [`GramScheme::QueryChunks`],
+    /// the index then holds the q-grams of every size.
+    pub max_q: usize,
+    pub scheme: GramScheme,
+    /// Positional signatures keep only matches shifted by at most the threshold and
+    /// apply the true match filter, otherwise only distinct matching chunks are counted
+    pub positional: bool,
+}
+
+impl IndexGramOptions {
+    pub fn fixed(q: usize) -> Self {
+        Self {
+            q,
+            max_q: q,
+            scheme: GramScheme::default(),
+            positional: true,
+        }
+    }
+}
+
+/// (string id, string length, gram or chunk position)
+type Posting = (usize, i32, i32);
+
+/// (chunk position, chunk signature, position of the matching gram)
+type GramMatch<'a> = (i32, &'a [LabelId], i32);
+
+/// Candidates of a query with the time of the index lookup and of the count and true
+/// match filters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GramCandidates {
+    pub candidates: Vec<usize>,
+    /// the query was too short for the index and the strings of its length range were
+    /// compared by bounded string edit distance instead
+    pub scanned: bool,
+    pub lookup: Duration,
+    pub filter: Duration,
+}
+
 pub struct IndexGram {
-    q: usize,
+    options: IndexGramOptions,
+    /// indexed strings, scanned for queries too short for the index
+    strings: Vec<Vec<LabelId>>,
     // q_grams: Vec<(usize, Vec<QSig>)>,
-    inv_index: FxHashMap<Vec<i32>, Vec<(usize, i32, i32)>>,
+    inv_index: FxHashMap<Vec<LabelId>, Vec<Posting>>,
     pub true_matches: Duration,
     pub cnt: Duration,
 }
 
+impl MemoryUsage for IndexGram {
+    fn heap_bytes(&self) -> usize {
+        self.inv_index.heap_bytes() + self.strings.heap_bytes()
+    }
+}
+
 impl IndexGram {
-    pub const EMPTY_VALUE: i32 = i32::MAX;
-    pub fn new(data: &[Vec<i32>], q: usize) -> Self {
+    pub const EMPTY_VALUE: LabelId = LabelId::MAX;
+    pub fn new(data: Vec<Vec<LabelId>>, options: IndexGramOptions) -> Self {
         let mut inv_index = FxHashMap::default();
+        let mut post = |sig: &[LabelId], posting: Posting| {
+            inv_index
+                .entry(sig.to_vec())
+                .and_modify(|postings: &mut Vec<Posting>| postings.push(posting))
+                .or_insert(vec![posting]);
+        };
 
-        for (sid, mut sdata) in data.iter().cloned().enumerate() {
-            let sig_size = sdata.len().div_ceil(q);
-            let orig_len = sdata.len() as i32;
-            sdata.append(&mut vec![Self::EMPTY_VALUE; sig_size * q - sdata.len()]);
-
-            sdata.windows(q).enumerate().for_each(|(i, w)| {
-                inv_index
-                    .entry(w.to_vec())
-                    .and_modify(|postings: &mut Vec<(usize, i32, i32)>| {
-                        postings.push((sid, orig_len, i as i32))
-                    })
-                    .or_insert(vec![(sid, orig_len, i as i32)]);
-            });
+        match options.scheme {
+            GramScheme::QueryChunks => {
+                // grams of one size share their length, so all sizes fit into one index
+                for q in options.q..=options.max_q.max(options.q) {
+                    for (sid, sdata) in data.iter().enumerate() {
+                        let orig_len = sdata.len() as i32;
+                        // the last chunk of a query is padded, data strings get grams
+                        // ending with every amount of padding to match it
+                        let sdata = Self::pad(sdata, sdata.len() + q - 1);
+                        sdata.windows(q).enumerate().for_each(|(i, w)| {
+                            post(w, (sid, orig_len, i as i32));
+                        });
+                    }
+                }
+            }
+            GramScheme::IndexChunks => {
+                let q = options.q;
+                for (sid, sdata) in data.iter().enumerate() {
+                    let orig_len = sdata.len() as i32;
+                    let sdata = Self::pad(sdata, sdata.len().div_ceil(q) * q);
+                    sdata.chunks(q).enumerate().for_each(|(i, c)| {
+                        post(c, (sid, orig_len, (i * q) as i32));
+                    });
+                }
+            }
         }
 
         IndexGram {
-            q,
+            options,
             // q_grams,
             inv_index,
+            strings: data,
             cnt: Duration::from_micros(0),
             true_matches: Duration::from_micros(0),
         }
     }
 
-    pub fn query(
-        &mut self,
-        mut query: Vec<i32>,
-        k: usize,
-    ) -> Result<(Vec<usize>, Duration, Duration), String> {
-        let index_lookup = Instant::now();
+    fn pad(s: &[LabelId], len: usize) -> Vec<LabelId> {
+        let mut padded = s.to_vec();
+        padded.resize(len.max(s.len()), Self::EMPTY_VALUE);
+        padded
+    }
+
+    /// Postings of the signature with a data string length in `min_len..max_len`, the
+    /// signature is borrowed from the index
+    fn postings(
+        &self,
+        sig: &[LabelId],
+        min_len: i32,
+        max_len: i32,
+    ) -> Option<(&[LabelId], &[Posting])> {
+        let (sig, postings) = self.inv_index.get_key_value(sig)?;
+        let start = postings.partition_point(|p| p.1 < min_len);
+        let end = postings.partition_point(|p| p.1 < max_len);
+        Some((sig, &postings[start..end]))
+    }
+
+    /// Q of the query chunks, `None` when even the smallest q leaves too few chunks
+    fn query_q(&self, len: usize, k: usize) -> Option<usize> {
+        (self.options.q..=self.options.max_q.max(self.options.q))
+            .rev()
+            .find(|q| k < len / q)
+    }
+
+    /// Strings of the length range within the threshold by bounded string edit distance
+    fn scan(&self, query: &[LabelId], k: usize) -> Vec<usize> {
+        self.strings
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                size_lb(query.len(), s.len(), k) <= k
+                    && bounded_string_edit_distance(query, s, k + 1) <= k
+            })
+            .map(|(sid, _)| sid)
+            .collect()
+    }
 
-        let sig_size = query.len().div_ceil(self.q);
-        let min_allowed_sig_size = query.len() / self.q;
-        if k >= min_allowed_sig_size {
-            // eprintln!(
-            //     "{k} > {}, output may have false negatives! lb={lb}",
-            //     chunks.len(),
-            //     lb = sig_size - k
-            // );
-            return Err("Query is too small for that threshold!".to_owned());
+    /// Queries too short for q-chunks of any indexed size under the threshold fall back to
+    /// a scan of the strings, so no query is left without its candidates
+    pub fn query(&self, query: Vec<LabelId>, k: usize) -> GramCandidates {
+        let start = Instant::now();
+        match self.query_index(&query, k) {
+            Some(found) => found,
+            None => GramCandidates {
+                candidates: self.scan(&query, k),
+                scanned: true,
+                lookup: Duration::ZERO,
+                filter: start.elapsed(),
+            },
         }
+    }
+
+    /// `None` when the query has at most `k` chunks
+    fn query_index(&self, query: &[LabelId], k: usize) -> Option<GramCandidates> {
+        let index_lookup = Instant::now();
+
         let min_match_size = query.len().saturating_sub(k) as i32;
         let max_match_size = (query.len() + k + 1) as i32;
-        query.append(&mut vec![
-            Self::EMPTY_VALUE;
-            sig_size * self.q - query.len()
-        ]);
+        let positional = self.options.positional;
+        let within = |p1: i32, p2: i32| !positional || p1.abs_diff(p2) <= (k as u32);
+        // candidate -> required matches and its matches
+        let mut cs: FxHashMap<usize, (usize, Vec<GramMatch>)> = FxHashMap::default();
 
-        let chunks: Vec<QSig> = query
-            .chunks(self.q)
-            .enumerate()
-            .map(|(pos, c)| QSig {
-                sig: c.to_vec(),
-                pos: (pos * self.q) as i32,
-            })
-            .collect();
-        let mut cs = FxHashMap::default();
-
-        // for chunk in chunks.iter().take(k + 1)
-        for chunk in chunks.iter() {
-            // dbg!(chunk);
-            if let Some(postings) = self.inv_index.get(&chunk.sig) {
-                let Err(start) = postings.binary_search_by(|probe| {
-                    probe
-                        .1
-                        .cmp(&min_match_size)
-                        .then(std::cmp::Ordering::Greater)
-                }) else {
-                    panic!("Binary search cannot result in Ok!");
-                };
-                let Err(end) = postings.binary_search_by(|probe| {
-                    probe
-                        .1
-                        .cmp(&max_match_size)
-                        .then(std::cmp::Ordering::Greater)
-                }) else {
-                    panic!("Binary search cannot result in Ok!");
-                };
-                let to_take = end - start;
-                for (cid, _, gram_pos) in postings.iter().skip(start).take(to_take) {
-                    if chunk.pos.abs_diff(*gram_pos) <= (k as u32) {
-                        cs.entry(*cid)
-                            .and_modify(|candidate_grams: &mut Vec<(&QSig, i32)>| {
-                                candidate_grams.push((chunk, *gram_pos))
-                            })
-                            .or_insert(vec![(chunk, *gram_pos)]);
-                        // cs.insert(*cid);
+        let q = match self.options.scheme {
+            GramScheme::QueryChunks => {
+                let q = self.query_q(query.len(), k)?;
+                let sig_size = query.len().div_ceil(q);
+                let query = Self::pad(query, sig_size * q);
+                let chunks: Vec<QSig> = query
+                    .chunks(q)
+                    .enumerate()
+                    .map(|(pos, c)| QSig {
+                        sig: c.to_vec(),
+                        pos: (pos * q) as i32,
+                    })
+                    .collect();
+
+                // for chunk in chunks.iter().take(k + 1)
+                for chunk in chunks.iter() {
+                    let Some((sig, postings)) =
+                        self.postings(&chunk.sig, min_match_size, max_match_size)
+                    else {
+                        continue;
+                    };
+                    for (cid, _, gram_pos) in postings {
+                        if within(chunk.pos, *gram_pos) {
+                            cs.entry(*cid)
+                                .or_insert_with(|| (sig_size - k, vec![]))
+                                .1
+                                .push((chunk.pos, sig, *gram_pos));
+                        }
                     }
                 }
+                q
             }
-        }
+            GramScheme::IndexChunks => {
+                let q = self.options.q;
+                // every data string of a matching size needs more chunks than the threshold
+                if (min_match_size as usize).div_ceil(q) <= k {
+                    return None;
+                }
+                let query = Self::pad(query, query.len() + q - 1);
+                for (gram_pos, gram) in query.windows(q).enumerate() {
+                    let gram_pos = gram_pos as i32;
+                    let Some((sig, postings)) = self.postings(gram, min_match_size, max_match_size)
+                    else {
+                        continue;
+                    };
+                    for (cid, len, chunk_pos) in postings {
+                        if within(*chunk_pos, gram_pos) {
+                            cs.entry(*cid)
+                                .or_insert_with(|| ((*len as usize).div_ceil(q) - k, vec![]))
+                                .1
+                                .push((*chunk_pos, sig, gram_pos));
+                        }
+                    }
+                }
+                q
+            }
+        };
 
         let index_lookup_dur = index_lookup.elapsed();
         let filter_time = Instant::now();
-        let lb: usize = sig_size - k;
         let mut opt = vec![0; 128];
         // count and true matches filter
         let candidates = cs
             .into_iter()
-            .filter_map(|(cid, mut candidate_gram_matches)| {
+            .filter_map(|(cid, (lb, mut candidate_gram_matches))| {
                 if candidate_gram_matches.len() < lb {
                     return None;
                 }
-                candidate_gram_matches.sort_by_key(|(chunk, _)| chunk.pos);
-
-                // true match filter
-                let omni_match = QSig {
-                    sig: vec![-1],
-                    pos: i32::MAX,
-                };
-                candidate_gram_matches.insert(0, (&omni_match, omni_match.pos));
+                candidate_gram_matches.sort_by_key(|(chunk_pos, _, _)| *chunk_pos);
+                if !positional {
+                    // a chunk counts once no matter how many grams it matches
+                    candidate_gram_matches.dedup_by_key(|(chunk_pos, _, _)| *chunk_pos);
+                    return (candidate_gram_matches.len() >= lb).then_some(cid);
+                }
+
+                // true match filter, the empty signature is compatible with every match
+                candidate_gram_matches.insert(0, (i32::MAX, &[], i32::MAX));
                 // let mut opt = vec![0; candidate_gram_matches.len()];
                 opt.fill(0);
 
@@ -140,16 +282,16 @@ impl IndexGram {
                     opt.resize(candidate_gram_matches.len(), 0);
                 }
 
+                // matches of distinct chunks are compatible when their grams do not overlap,
+                // equal chunks at different positions count as distinct matches
                 #[inline(always)]
-                fn compatible(m1: &(&QSig, i32), m2: &(&QSig, i32), n: i32) -> bool {
-                    *unsafe { m2.0.sig.get_unchecked(0) } == -1
-                        || ((m1.0.pos != m2.0.pos && m1.0.sig != m2.0.sig) && m1.1 >= m2.1 + n)
+                fn compatible(m1: &GramMatch, m2: &GramMatch, n: i32) -> bool {
+                    m2.1.is_empty() || (m1.0 != m2.0 && m1.2 >= m2.2 + n)
                 }
 
-                let qsize = self.q as i32;
+                let qsize = q as i32;
                 unsafe {
-                    // the first in tuple is the q-chunk of query, second is q-gram of data string
-
+                    // the chunk is of the query or data string by the scheme, the gram of the other
                     let mut total_max = i32::MIN;
                     for kc in 1..candidate_gram_matches.len() {
                         let mut mx = i32::MIN;
@@ -182,7 +324,12 @@ impl IndexGram {
         let filter_duration = filter_time.elapsed();
         // let candidates = cs.iter().cloned().collect_vec();
 
-        Ok((candidates, index_lookup_dur, filter_duration))
+        Some(GramCandidates {
+            candidates,
+            scanned: false,
+            lookup: index_lookup_dur,
+            filter: filter_duration,
+        })
     }
 
     /*fn count_filter(&mut self, cid: usize, sig_size: usize, k: usize, chunks: &[QSig]) -> bool {
@@ -287,3 +434,74 @@ impl IndexGram {
         grams.len() + (self.q - 1)
     }*/
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(7);
+        let mut random_string = |len: usize| {
+            (0..len)
+                .map(|_| LabelId::from_index(rng.gen_range(0..3)))
+                .collect_vec()
+        };
+        let mut data = (0..300).map(|i| random_string(2 + i % 14)).collect_vec();
+        // edited copies of the strings, so some are within the threshold
+        for i in 0..150 {
+            let mut copy = data[i].clone();
+            copy[i % 2] = LabelId::from_index(3);
+            data.push(copy);
+        }
+        data.sort_by_key(Vec::len);
+
+        let schemes = [
+            IndexGramOptions::fixed(2),
+            IndexGramOptions::fixed(3),
+            IndexGramOptions {
+                max_q: 4,
+                ..IndexGramOptions::fixed(1)
+            },
+            IndexGramOptions {
+                scheme: GramScheme::IndexChunks,
+                ..IndexGramOptions::fixed(2)
+            },
+            IndexGramOptions {
+                positional: false,
+                ..IndexGramOptions::fixed(2)
+            },
+            IndexGramOptions {
+                scheme: GramScheme::IndexChunks,
+                positional: false,
+                ..IndexGramOptions::fixed(3)
+            },
+        ];
+        for options in schemes {
+            let index = IndexGram::new(data.clone(), options);
+            let (mut used, mut scanned) = (0, 0);
+            for query in data.iter().step_by(7) {
+                for k in 0..3 {
+                    let found = index.query(query.clone(), k);
+                    if found.scanned {
+                        scanned += 1;
+                    } else {
+                        used += 1;
+                    }
+                    for (sid, s) in data.iter().enumerate() {
+                        if bounded_string_edit_distance(query, s, k + 1) <= k {
+                            assert!(
+                                found.candidates.contains(&sid),
+                                "{options:?} missed {sid} with k={k}"
+                            );
+                        }
+                    }
+                }
+            }
+            assert!(used > 0, "{options:?} never used the index");
+            assert!(scanned > 0, "{options:?} never scanned");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Partitions of the collection by label sets for approximate joins.
+//!
+//! Trees are ordered by a short MinHash signature of their label multiset, see
+//! [`lsh::signature`], and cut into partitions of about equal numbers of trees. Trees with
+//! similar label sets likely share the first values of their signatures and land in the
+//! same or a neighbouring partition. A query is placed by its own signature and only
+//! compared with the trees of its partition and of `reach` partitions on either side, in
+//! addition to the size window of every method. Trees of pairs within the threshold may
+//! still land in partitions farther apart, so unlike the lower bounds this misses results;
+//! the recall is measured against the ground truth when it is given.
+use crate::lb::lsh::{self, LshParams, Sketch};
+use crate::lb::{self, preprocess::Preprocessed};
+use crate::parsing::ParsedTree;
+use crate::LowerBoundMethods;
+use rayon::prelude::*;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Values of the signature trees are ordered by
+pub const SIGNATURE_VALUES: usize = 4;
+
+fn label_signature(tree: &ParsedTree) -> Vec<u64> {
+    let params = LshParams {
+        sketch: Sketch::Labels,
+        bands: 1,
+        rows: SIGNATURE_VALUES,
+        ..LshParams::default()
+    };
+    lsh::signature(tree, &params)
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelClusters {
+    /// partition of every tree
+    of_tree: Vec<usize>,
+    /// smallest signature of every partition but the first, in increasing order
+    boundaries: Vec<Vec<u64>>,
+    reach: usize,
+}
+
+impl LabelClusters {
+    /// Cuts `trees` into up to `clusters` partitions, trees of equal signatures are kept in
+    /// one partition so there can be fewer
+    pub fn new(trees: &[ParsedTree], clusters: usize, reach: usize) -> Self {
+        let signatures = trees.par_iter().map(label_signature).collect::<Vec<_>>();
+        let mut order = (0..trees.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| signatures[*a].cmp(&signatures[*b]).then(a.cmp(b)));
+
+        let mut of_tree = vec![0; trees.len()];
+        let mut boundaries = vec![];
+        let mut start = 0;
+        for cluster in 1..=clusters.max(1) {
+            let mut end = (trees.len() * cluster / clusters.max(1)).max(start);
+            while end < order.len()
+                && end > 0
+                && signatures[order[end]] == signatures[order[end - 1]]
+            {
+                end += 1;
+            }
+            if end == start {
+                continue;
+            }
+            if start > 0 {
+                boundaries.push(signatures[order[start]].clone());
+            }
+            for &tid in &order[start..end] {
+                of_tree[tid] = boundaries.len();
+            }
+            start = end;
+        }
+        Self {
+            of_tree,
+            boundaries,
+            reach,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+
+    /// Partition the signature of `query` falls into
+    pub fn of_query(&self, query: &ParsedTree) -> usize {
+        let signature = label_signature(query);
+        self.boundaries.partition_point(|b| *b <= signature)
+    }
+
+    /// Partitions the trees compared with queries of `cluster` are taken from
+    pub fn near(&self, cluster: usize) -> RangeInclusive<usize> {
+        cluster.saturating_sub(self.reach)..=(cluster + self.reach).min(self.len() - 1)
+    }
+
+    /// Ids of the trees compared with queries of `cluster` in increasing order
+    pub fn trees_near(&self, cluster: usize) -> Vec<usize> {
+        let near = self.near(cluster);
+        (0..self.of_tree.len())
+            .filter(|tid| near.contains(&self.of_tree[*tid]))
+            .collect()
+    }
+}
+
+/// Candidates of `method` of all queries among the trees of the same or adjacent
+/// partitions. Indexes of the trees are built once for the whole collection and every
+/// partition takes the indexes of its trees, the time of it is included. The bounds
+/// follow `config`.
+pub fn run(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    clusters: &LabelClusters,
+    queries: &[(usize, ParsedTree)],
+    config: &lb::RunConfig,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    let trees = pre.trees();
+    let mut of_cluster = vec![vec![]; clusters.len()];
+    for (qid, (_, query)) in queries.iter().enumerate() {
+        of_cluster[clusters.of_query(query)].push(qid);
+    }
+
+    let mut candidates = vec![];
+    let mut duration = Duration::ZERO;
+    for (cluster, qids) in of_cluster.iter().enumerate() {
+        if qids.is_empty() {
+            continue;
+        }
+        let tree_ids = clusters.trees_near(cluster);
+        // ids stay in increasing order, so the subset stays sorted by size
+        let subset = tree_ids
+            .iter()
+            .map(|tid| trees[*tid].clone())
+            .collect::<Vec<_>>();
+        let cluster_queries = qids
+            .iter()
+            .map(|qid| queries[*qid].clone())
+            .collect::<Vec<_>>();
+        let cluster_pre = pre.restricted(&subset, &tree_ids)?;
+        let (found, time) = lb::run_method(method, &cluster_pre, &cluster_queries, config)?;
+        duration += time;
+        candidates.extend(
+            found
+                .into_iter()
+                .map(|(qid, tid)| (qids[qid], tree_ids[tid])),
+        );
+    }
+    candidates.sort_unstable();
+    Ok((candidates, duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::label_weights::LabelWeights;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_label_clusters() {
+        let mut ld = LabelDict::default();
+        let mut trees = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{x{y}}",
+            "{x{y}{z}}",
+            "{a{b}{c}{d}}",
+            "{x{y}{z}{w}}",
+            "{p{q}}",
+            "{p{q}{r}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld))
+        .to_vec();
+        trees.sort_by_key(|t| t.len());
+        let queries = [(1, "{a{b}{c}}"), (1, "{x{y}{z}}"), (2, "{p{q}}")]
+            .map(|(k, t)| (k, parse_single(t.to_owned(), &mut ld)))
+            .to_vec();
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+
+        let clusters = LabelClusters::new(&trees, 4, 0);
+        assert!(clusters.len() <= 4);
+        // without reach the partitions are disjoint
+        assert_eq!(
+            (0..clusters.len())
+                .map(|c| clusters.trees_near(c).len())
+                .sum::<usize>(),
+            trees.len()
+        );
+        // a query equal to a tree falls into the partition of the tree
+        for (_, query) in &queries {
+            let tid = trees.iter().position(|t| t == query).unwrap();
+            assert!(clusters.trees_near(clusters.of_query(query)).contains(&tid));
+        }
+
+        let path = std::env::temp_dir().join(format!("cluster-weights-{}.tsv", std::process::id()));
+        std::fs::write(&path, "a\t3\nx\t0.5\n").unwrap();
+        let weights = LabelWeights::read(&path, &ld).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let configured = lb::RunConfig {
+            label_weights: Some(&weights),
+            structural_matching: 2,
+            tight_window: true,
+        };
+        let everything = LabelClusters::new(&trees, 4, 4);
+        for config in [lb::RunConfig::default(), configured] {
+            for method in [LowerBoundMethods::Lblint, LowerBoundMethods::Structural] {
+                let (mut expected, _) = lb::run_method(method, &pre, &queries, &config).unwrap();
+                expected.sort_unstable();
+                let (candidates, _) = run(method, &pre, &clusters, &queries, &config).unwrap();
+                assert!(candidates.iter().all(|pair| expected.contains(pair)));
+                // reaching all partitions finds every candidate
+                let (candidates, _) = run(method, &pre, &everything, &queries, &config).unwrap();
+                assert_eq!(candidates, expected, "{method:?} {config:?}");
+            }
+        }
+    }
+}
@@ -1,25 +1,45 @@
+use itertools::Either;
 use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     indexing::InvertedListLabelPostorderIndex,
+    lb::label_postings::{dense_keys, DenseCounts, LabelPostings},
+    lb::label_signature::LabelSignature,
+    lb::size_buckets::{size_lb, SizeBucketed, SizeBuckets},
+    memory::MemoryUsage,
     parsing::{LabelFreqOrdering, LabelId},
 };
 
-pub fn label_intersection(
+/// Number of nodes of the two trees that can be paired by their labels, the sum of the
+/// smaller count of every common label
+pub fn label_intersection_size(
     t1: &InvertedListLabelPostorderIndex,
     t2: &InvertedListLabelPostorderIndex,
 ) -> usize {
-    use std::cmp::{max, min};
-    let mut intersection_size = 0;
-    for (label, postings) in t1.inverted_list.iter() {
-        if let Some(t2postings) = t2.inverted_list.get(label) {
-            intersection_size += min(t2postings.len(), postings.len());
-        }
-    }
+    t1.inverted_list
+        .iter()
+        .filter_map(|(label, postings)| {
+            let t2postings = t2.inverted_list.get(label)?;
+            Some(t2postings.len().min(postings.len()))
+        })
+        .sum()
+}
 
-    max(t1.c.tree_size, t2.c.tree_size) - intersection_size
+/// Label intersection bound, nodes of the bigger tree without a node of the same label
+/// in the other tree
+pub fn label_intersection(
+    t1: &InvertedListLabelPostorderIndex,
+    t2: &InvertedListLabelPostorderIndex,
+) -> usize {
+    t1.c.tree_size.max(t2.c.tree_size) - label_intersection_size(t1, t2)
 }
 
+/// Label intersection bound with threshold `k`. The result is above `k` exactly when
+/// [`label_intersection`] is, and then it is at most [`label_intersection`], so it can
+/// be compared with `k` like the exact bound. Once the pair is known to be within `k` the
+/// remaining labels are skipped and any value up to `k` is returned, not less than the
+/// exact bound.
 pub fn label_intersection_k(
     t1: &InvertedListLabelPostorderIndex,
     t2: &InvertedListLabelPostorderIndex,
@@ -30,17 +50,25 @@ pub fn label_intersection_k(
     let bigger_tree = max(t1.c.tree_size, t2.c.tree_size);
 
     // if all labels matched, but just the size difference was too much, just exit
-    if t1.c.tree_size.abs_diff(t2.c.tree_size) > k {
+    if size_lb(t1.c.tree_size, t2.c.tree_size, k) > k {
         return k + 1;
     }
 
+    let signature_lb = t1
+        .signature
+        .lower_bound(t1.c.tree_size, &t2.signature, t2.c.tree_size);
+    if signature_lb > k {
+        return signature_lb;
+    }
+
     for (label, postings) in t1.inverted_list.iter() {
         let Some(t2postings) = t2.inverted_list.get(label) else {
             continue;
         };
         intersection_size += min(t2postings.len(), postings.len());
 
-        if bigger_tree - intersection_size < k {
+        // the bound only decreases with more labels, the pair passes
+        if bigger_tree - intersection_size <= k {
             return bigger_tree - intersection_size;
         }
     }
@@ -48,37 +76,130 @@ pub fn label_intersection_k(
     bigger_tree - intersection_size
 }
 
+/// Label intersection where a node counts with the weight of its label. It is a lower
+/// bound of the tree edit distance where deleting and inserting a node costs the weight
+/// of its label. Weights up to 1 keep it a bound of the unit cost distance, larger weights
+/// tighten it but may prune pairs within `k`.
+pub fn weighted_label_intersection_k(
+    t1: &InvertedListLabelPostorderIndex,
+    t2: &InvertedListLabelPostorderIndex,
+    k: usize,
+    weight: impl Fn(LabelId) -> f64,
+) -> usize {
+    let weighted_size = |t: &InvertedListLabelPostorderIndex| {
+        t.inverted_list
+            .iter()
+            .map(|(label, postings)| weight(*label) * postings.len() as f64)
+            .sum::<f64>()
+    };
+    let (size1, size2) = (weighted_size(t1), weighted_size(t2));
+    if (size1 - size2).abs() > k as f64 {
+        return k + 1;
+    }
+    let mut overlap = 0.0;
+    for (label, postings) in t1.inverted_list.iter() {
+        if let Some(t2postings) = t2.inverted_list.get(label) {
+            overlap += weight(*label) * t2postings.len().min(postings.len()) as f64;
+        }
+    }
+    // rounding errors of the sums must not push the bound above the distance
+    (size1.max(size2) - overlap - 1e-9).ceil().max(0.0) as usize
+}
+
+/// Trees with a label and its count in them
+#[derive(Serialize, Deserialize)]
+enum LabelTrees {
+    /// tree id and label count, bucketed by tree size
+    Sparse(SizeBucketed<(usize, usize)>),
+    /// label counts of all trees, for labels in a large share of trees
+    Dense(DenseCounts),
+}
+
+impl Default for LabelTrees {
+    fn default() -> Self {
+        Self::Sparse(SizeBucketed::default())
+    }
+}
+
+impl MemoryUsage for LabelTrees {
+    fn heap_bytes(&self) -> usize {
+        match self {
+            Self::Sparse(postings) => postings.heap_bytes(),
+            Self::Dense(counts) => counts.heap_bytes(),
+        }
+    }
+}
+
+impl LabelTrees {
+    /// Tree size, tree id and label count of the trees with size within `k` of `size`
+    fn within<'a>(
+        &'a self,
+        size: usize,
+        k: usize,
+        sizes: &'a SizeBuckets,
+    ) -> impl Iterator<Item = (usize, usize, usize)> + 'a {
+        match self {
+            Self::Sparse(postings) => Either::Left(
+                postings
+                    .within(size, k)
+                    .map(|(tree_size, (tid, count))| (tree_size, *tid, *count)),
+            ),
+            Self::Dense(counts) => Either::Right(
+                counts
+                    .within(sizes.within(size, k))
+                    .map(|(tid, count)| (sizes.size(tid), tid, count as usize)),
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct LabelIntersectionIndex {
-    // the tuple is treeId, tree_size and label count
-    index: FxHashMap<LabelId, Vec<(usize, usize, usize)>>,
-    // first is the tree size, second is starting point
-    size_index: Vec<usize>,
+    index: LabelPostings<LabelTrees>,
+    size_index: SizeBuckets,
+    // label signatures of trees, indexed by tree id
+    signatures: Vec<LabelSignature>,
+}
+
+impl MemoryUsage for LabelIntersectionIndex {
+    fn heap_bytes(&self) -> usize {
+        self.index.heap_bytes() + self.size_index.heap_bytes() + self.signatures.heap_bytes()
+    }
 }
 
 impl LabelIntersectionIndex {
     // asserts trees are in sorted order by tree size when creating a new index
     pub fn new(trees: &[InvertedListLabelPostorderIndex]) -> Self {
-        let mut index: FxHashMap<LabelId, Vec<(usize, usize, usize)>> = FxHashMap::default();
+        let mut index = LabelPostings::<LabelTrees>::default();
         assert!(
             trees.is_sorted_by_key(|tree| tree.c.tree_size),
             "Trees are sorted when indexing!"
         );
-        let mut size_index = vec![];
-        let mut max_tree_size = 0;
+        let size_index = SizeBuckets::from_sizes(trees.iter().map(|t| t.c.tree_size));
+        let mut frequencies = FxHashMap::<LabelId, usize>::default();
+        for t in trees {
+            for label in t.inverted_list.keys() {
+                *frequencies.entry(*label).or_default() += 1;
+            }
+        }
+        for label in dense_keys(frequencies, trees.len()) {
+            index.insert(label, LabelTrees::Dense(DenseCounts::new(trees.len())));
+        }
         for (tid, t) in trees.iter().enumerate() {
-            max_tree_size = std::cmp::max(t.c.tree_size, max_tree_size);
             for (label, lbl_count) in t.inverted_list.iter() {
-                index
-                    .entry(*label)
-                    .and_modify(|postings| postings.push((tid, t.c.tree_size, lbl_count.len())))
-                    .or_insert(vec![(tid, t.c.tree_size, lbl_count.len())]);
+                match index.get_or_default(*label) {
+                    LabelTrees::Sparse(postings) => {
+                        postings.push(t.c.tree_size, (tid, lbl_count.len()))
+                    }
+                    LabelTrees::Dense(counts) => counts.set(tid, lbl_count.len() as u32),
+                }
             }
-            size_index.push(t.c.tree_size);
         }
 
         LabelIntersectionIndex {
             index,
             size_index,
+            signatures: trees.iter().map(|t| t.signature).collect(),
             // skip_list,
         }
     }
@@ -98,38 +219,36 @@ impl LabelIntersectionIndex {
 
         if query_tree.c.tree_size <= k {
             // find candidates that have no label overlap but can fit by size because of threshold
-            for (cid, tree_size) in self
-                .size_index
-                .iter()
-                .enumerate()
-                .take_while(|(_, &ts)| ts <= k)
-            {
-                overlaps.insert(cid, (0, *tree_size));
+            for cid in self.size_index.range(0, k) {
+                overlaps.insert(cid, (0, self.size_index.size(cid)));
             }
         }
 
         // for each TID stores the current intersection size
         for (lbl, query_label_cnt) in prefix.iter().take(k + 1) {
             if let Some(posting_list) = self.index.get(lbl) {
-                for (tid, tree_size, label_cnt) in posting_list.iter().filter(|(_, ts, _)| {
-                    *ts >= query_tree.c.tree_size.saturating_sub(k)
-                        && ts.abs_diff(query_tree.c.tree_size) <= k
-                })
-                // // .skip(start)
-                // .skip_while(|(_, size, _)| query_tree.c.tree_size - size > k)
-                // .take_while(|(_, size, _)| *size <= k + query_tree.c.tree_size)
+                for (tree_size, tid, label_cnt) in
+                    posting_list.within(query_tree.c.tree_size, k, &self.size_index)
                 {
                     overlaps
-                        .entry(*tid)
+                        .entry(tid)
                         .and_modify(|(intersection_size, _)| {
-                            *intersection_size += std::cmp::min(query_label_cnt, label_cnt);
+                            *intersection_size += std::cmp::min(*query_label_cnt, label_cnt);
                         })
-                        .or_insert((std::cmp::min(*query_label_cnt, *label_cnt), *tree_size));
+                        .or_insert((std::cmp::min(*query_label_cnt, label_cnt), tree_size));
                 }
             }
         }
 
         for (&cid, (overlap, size)) in overlaps.iter_mut() {
+            let signature_lb = query_tree.signature.lower_bound(
+                query_tree.c.tree_size,
+                &self.signatures[cid],
+                *size,
+            );
+            if signature_lb > k {
+                continue;
+            }
             if *overlap > 0 {
                 for (label, self_nodes) in prefix.iter().skip(k + 1) {
                     if let Some(nodes) = trees[cid].inverted_list.get(*label) {
@@ -160,32 +279,25 @@ impl LabelIntersectionIndex {
         for (lbl, query_label_cnt) in query_tree.inverted_list.iter() {
             let query_label_cnt = query_label_cnt.len();
             if let Some(posting_list) = self.index.get(lbl) {
-                for (tid, tree_size, label_cnt) in posting_list
-                    .iter()
-                    // .skip(start)
-                    .skip_while(|(_, size, _)| query_tree.c.tree_size - size > k)
-                    .take_while(|(_, size, _)| *size <= k + query_tree.c.tree_size)
+                for (tree_size, tid, label_cnt) in
+                    posting_list.within(query_tree.c.tree_size, k, &self.size_index)
                 {
                     tree_intersections
-                        .entry(*tid)
+                        .entry(tid)
                         .and_modify(|(intersection_size, _)| {
-                            *intersection_size += std::cmp::min(query_label_cnt, *label_cnt);
+                            *intersection_size += std::cmp::min(query_label_cnt, label_cnt);
                         })
-                        .or_insert((std::cmp::min(query_label_cnt, *label_cnt), *tree_size));
+                        .or_insert((std::cmp::min(query_label_cnt, label_cnt), tree_size));
                 }
             }
         }
 
         let mut candidates = vec![];
         // find candidates that have no label overlap but can fit by size because of threshold
-        for (cid, tree_size) in self
-            .size_index
-            .iter()
-            .enumerate()
-            .take_while(|(_, ts)| query_tree.c.tree_size.abs_diff(**ts) <= k)
-        {
+        for cid in self.size_index.within(query_tree.c.tree_size, k) {
+            let tree_size = self.size_index.size(cid);
             if !tree_intersections.contains_key(&cid)
-                && std::cmp::max(query_tree.c.tree_size, *tree_size) <= k
+                && std::cmp::max(query_tree.c.tree_size, tree_size) <= k
             {
                 candidates.push((query_id, cid));
             }
@@ -208,6 +320,8 @@ mod tests {
     use super::*;
     use crate::indexing::{Indexer, InvertedListLabelPostorderIndex};
     use crate::parsing::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
 
     #[test]
     fn test_lblint() {
@@ -217,9 +331,9 @@ mod tests {
         let t3 = parse_single("{d{c}{b{a}{d{a}}}}".to_owned(), &mut ld);
         let t5 = parse_single("{a{b{a}{c{d}}}{d}}".to_owned(), &mut ld);
 
-        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld);
-        let t3i = InvertedListLabelPostorderIndex::index_tree(&t3, &ld);
-        let t5i = InvertedListLabelPostorderIndex::index_tree(&t5, &ld);
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
+        let t3i = InvertedListLabelPostorderIndex::index_tree(&t3, &ld).unwrap();
+        let t5i = InvertedListLabelPostorderIndex::index_tree(&t5, &ld).unwrap();
 
         let t2t3_lb = label_intersection(&t2i, &t3i);
         let t3t5_lb = label_intersection(&t3i, &t5i);
@@ -228,6 +342,93 @@ mod tests {
         assert_eq!(0, t3t5_lb, "Label diff between t3 and t5 should be 0!");
     }
 
+    #[test]
+    fn test_lblint_k_contract() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(7);
+        let mut ld = LabelDict::default();
+        for _ in 0..500 {
+            let size = 1 + rng.gen_range(0..12);
+            let t1 = parse_single(random_bracket_tree(&mut rng, size, 4), &mut ld);
+            let t2 = parse_single(random_bracket_tree(&mut rng, 12, 4), &mut ld);
+            let t1 = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+            let t2 = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
+            let exact = label_intersection(&t1, &t2);
+            assert_eq!(
+                exact,
+                t1.c.tree_size.max(t2.c.tree_size) - label_intersection_size(&t2, &t1)
+            );
+            for k in 0..=14 {
+                let bounded = label_intersection_k(&t1, &t2, k);
+                assert_eq!(bounded <= k, exact <= k, "k={k} exact={exact}");
+                if bounded > k {
+                    assert!(bounded <= exact);
+                } else {
+                    assert!(bounded >= exact);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_with_dense_labels() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(11);
+        let mut ld = LabelDict::default();
+        // the first labels are in most trees and stored dense, the others sparse
+        let mut trees = (0..300)
+            .map(|_| {
+                let size = 1 + rng.gen_range(0..10);
+                let alphabet = if rng.gen_bool(0.5) { 3 } else { 40 };
+                parse_single(random_bracket_tree(&mut rng, size, alphabet), &mut ld)
+            })
+            .collect::<Vec<_>>();
+        trees.sort_by_key(|t| t.count());
+        let queries = (0..20)
+            .map(|_| parse_single(random_bracket_tree(&mut rng, 6, 40), &mut ld))
+            .collect::<Vec<_>>();
+        let trees = InvertedListLabelPostorderIndex::index_collection(&trees, &ld).unwrap();
+        let queries = InvertedListLabelPostorderIndex::index_collection(&queries, &ld).unwrap();
+        let index = LabelIntersectionIndex::new(&trees);
+        assert!(index
+            .index
+            .iter()
+            .any(|(_, postings)| matches!(postings, LabelTrees::Dense(_))));
+        let ordering = get_frequency_ordering(&ld);
+
+        for (qid, query) in queries.iter().enumerate() {
+            for k in 0..5 {
+                let expected = (0..trees.len())
+                    .filter(|tid| label_intersection(query, &trees[*tid]) <= k)
+                    .collect::<Vec<_>>();
+                let found = index.query_index(query, k, Some(qid));
+                let mut found = found.iter().map(|(_, tid)| *tid).collect::<Vec<_>>();
+                found.sort_unstable();
+                assert_eq!(found, expected, "query {qid} k={k}");
+                let prefix = index.query_index_prefix(query, k, &ordering, &trees, Some(qid));
+                for tid in expected {
+                    assert!(prefix.contains(&(qid, tid)), "query {qid} k={k} tree {tid}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_weighted_lblint() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{p{a}{p}{p}}".to_owned(), &mut ld);
+        let t2 = parse_single("{p{b}{p}{p}}".to_owned(), &mut ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
+        let p = ld["p"].0;
+
+        assert_eq!(weighted_label_intersection_k(&t1i, &t2i, 5, |_| 1.0), 1);
+        // overlapping `p` nodes count a tenth, `a` and `b` are left over
+        let rare = |label| if label == p { 0.1 } else { 1.0 };
+        assert_eq!(weighted_label_intersection_k(&t1i, &t2i, 5, rare), 1);
+        let heavy = |label| if label == p { 0.1 } else { 3.0 };
+        assert_eq!(weighted_label_intersection_k(&t1i, &t2i, 5, heavy), 3);
+        assert_eq!(weighted_label_intersection_k(&t1i, &t2i, 0, |_| 0.0), 0);
+    }
+
     #[test]
     fn test_lblint_2() {
         let mut ld = LabelDict::default();
@@ -248,9 +449,9 @@ mod tests {
             &mut ld,
         );
 
-        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld);
-        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld);
-        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
+        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld).unwrap();
 
         let k = 12;
         let t1t2_lb = label_intersection_k(&t1i, &qi, k);
@@ -268,8 +469,8 @@ mod tests {
         let t1 = parse_single(i1, &mut ld);
         let t2 = parse_single(i2, &mut ld);
 
-        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld);
-        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
 
         let lb = label_intersection(&t1i, &t2i);
 
@@ -283,8 +484,8 @@ mod tests {
         let mut ld = LabelDict::default();
         let t1 = parse_single(i, &mut ld);
         let t2 = parse_single(q, &mut ld);
-        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld);
-        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
 
         let lb = label_intersection_k(&t1i, &t2i, 25);
         assert!(lb <= 25, "Lower bound is less than 25");
@@ -305,9 +506,9 @@ mod tests {
         let t1 = parse_single(i, &mut ld);
         let t2 = parse_single(i2, &mut ld);
         let q = parse_single(q, &mut ld);
-        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld);
-        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld);
-        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let t2i = InvertedListLabelPostorderIndex::index_tree(&t2, &ld).unwrap();
+        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld).unwrap();
 
         let k = 12;
 
@@ -328,8 +529,8 @@ mod tests {
         let mut ld = LabelDict::default();
         let t1 = parse_single(i, &mut ld);
         let q = parse_single(q, &mut ld);
-        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld);
-        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld);
+        let t1i = InvertedListLabelPostorderIndex::index_tree(&t1, &ld).unwrap();
+        let qi = InvertedListLabelPostorderIndex::index_tree(&q, &ld).unwrap();
 
         // let lb = label_intersection_k(&qi, &t1i, 2);
         // assert_eq!(lb, 3, "T1 and Q would not pass the filter");
@@ -0,0 +1,244 @@
+//! Postings of an inverted index keyed by label.
+//!
+//! Labels with ids below [`HOT_LABELS`] are looked up by position in a vector, the rest
+//! in a hash map. With `--label-ids frequency` the smallest ids are the most frequent
+//! labels, so lookups of the labels with the longest postings skip hashing and their
+//! entries are adjacent in memory. Any other numbering is correct as well, only the
+//! labels taking the fast path differ.
+//!
+//! Postings of the labels in a large share of the trees are [`DenseCounts`], the count
+//! of the label in every tree indexed by tree id. They take 4 bytes and a bit per tree
+//! instead of a tree id, a count and a tree size per posting, and a scan over a range of
+//! trees reads them in order without following another vector.
+use crate::memory::MemoryUsage;
+use crate::parsing::LabelId;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize, Serializer};
+use std::hash::Hash;
+use std::ops::Range;
+
+/// Labels whose postings are indexed by label id
+pub const HOT_LABELS: usize = 1 << 12;
+/// Most labels stored as [`DenseCounts`]
+pub const DENSE_LABELS: usize = 64;
+/// Labels in at least one in this many trees are dense, below it dense counts take more
+/// memory than postings
+pub const DENSE_SHARE: usize = 6;
+
+/// Up to [`DENSE_LABELS`] keys in the most trees, out of `trees`, that are in at least
+/// one in [`DENSE_SHARE`] of them. `frequencies` are the numbers of trees with each key.
+pub fn dense_keys<K: Copy + Ord + Hash>(
+    frequencies: impl IntoIterator<Item = (K, usize)>,
+    trees: usize,
+) -> FxHashSet<K> {
+    let mut frequencies = frequencies
+        .into_iter()
+        .filter(|(_, count)| count * DENSE_SHARE >= trees)
+        .collect::<Vec<_>>();
+    frequencies
+        .sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    frequencies
+        .into_iter()
+        .take(DENSE_LABELS)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Counts of a label in all trees, indexed by tree id, with a bit per tree set for the
+/// trees with the label
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DenseCounts {
+    present: Vec<u64>,
+    counts: Vec<u32>,
+}
+
+impl DenseCounts {
+    pub fn new(trees: usize) -> Self {
+        Self {
+            present: vec![0; trees.div_ceil(64)],
+            counts: vec![0; trees],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn trees(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn set(&mut self, tree: usize, count: u32) {
+        self.counts[tree] = count;
+        if count > 0 {
+            self.present[tree / 64] |= 1 << (tree % 64);
+        } else {
+            self.present[tree / 64] &= !(1 << (tree % 64));
+        }
+    }
+
+    /// Trees of the range with the label and their counts, in order of tree ids
+    pub fn within(&self, trees: Range<usize>) -> impl Iterator<Item = (usize, u32)> + '_ {
+        let words = trees.start / 64..trees.end.div_ceil(64);
+        words.flat_map(move |word| {
+            let mut bits = self.present[word];
+            // masks the trees of the first and last word out of the range
+            if word == trees.start / 64 {
+                bits &= u64::MAX << (trees.start % 64);
+            }
+            if word == trees.end / 64 {
+                bits &= (1u64 << (trees.end % 64)).wrapping_sub(1);
+            }
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let tree = word * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some((tree, self.counts[tree]))
+            })
+        })
+    }
+}
+
+impl MemoryUsage for DenseCounts {
+    fn heap_bytes(&self) -> usize {
+        self.present.heap_bytes() + self.counts.heap_bytes()
+    }
+}
+
+/// Saved as a map from label id to postings, the same as the hash map it replaced
+#[derive(Debug, Clone, Deserialize)]
+#[serde(
+    from = "FxHashMap<LabelId, T>",
+    bound(deserialize = "T: Deserialize<'de>")
+)]
+pub struct LabelPostings<T> {
+    /// indexed by [`LabelId::index`]
+    hot: Vec<Option<T>>,
+    cold: FxHashMap<LabelId, T>,
+}
+
+impl<T> Default for LabelPostings<T> {
+    fn default() -> Self {
+        Self {
+            hot: vec![],
+            cold: FxHashMap::default(),
+        }
+    }
+}
+
+impl<T> LabelPostings<T> {
+    #[inline(always)]
+    pub fn get(&self, label: &LabelId) -> Option<&T> {
+        match self.hot.get(label.index()) {
+            Some(postings) => postings.as_ref(),
+            None if label.index() < HOT_LABELS => None,
+            None => self.cold.get(label),
+        }
+    }
+
+    pub fn insert(&mut self, label: LabelId, postings: T) {
+        let index = label.index();
+        if index >= HOT_LABELS {
+            self.cold.insert(label, postings);
+            return;
+        }
+        if self.hot.len() <= index {
+            self.hot.resize_with(index + 1, || None);
+        }
+        self.hot[index] = Some(postings);
+    }
+
+    pub fn get_or_default(&mut self, label: LabelId) -> &mut T
+    where
+        T: Default,
+    {
+        let index = label.index();
+        if index >= HOT_LABELS {
+            return self.cold.entry(label).or_default();
+        }
+        if self.hot.len() <= index {
+            self.hot.resize_with(index + 1, || None);
+        }
+        self.hot[index].get_or_insert_with(T::default)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (LabelId, &T)> {
+        self.hot
+            .iter()
+            .enumerate()
+            .filter_map(|(index, postings)| Some((LabelId::from_index(index), postings.as_ref()?)))
+            .chain(self.cold.iter().map(|(label, postings)| (*label, postings)))
+    }
+}
+
+impl<T> From<FxHashMap<LabelId, T>> for LabelPostings<T> {
+    fn from(map: FxHashMap<LabelId, T>) -> Self {
+        let mut postings = Self::default();
+        for (label, items) in map {
+            postings.insert(label, items);
+        }
+        postings
+    }
+}
+
+impl<T: Serialize> Serialize for LabelPostings<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for LabelPostings<T> {
+    fn heap_bytes(&self) -> usize {
+        self.hot.heap_bytes() + self.cold.heap_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_and_cold_postings() {
+        let (hot, cold) = (LabelId::from_index(3), LabelId::from_index(HOT_LABELS + 7));
+        let mut postings = LabelPostings::<Vec<usize>>::default();
+        postings.get_or_default(hot).push(1);
+        postings.get_or_default(cold).push(2);
+        postings.get_or_default(hot).push(3);
+        assert_eq!(postings.get(&hot), Some(&vec![1, 3]));
+        assert_eq!(postings.get(&cold), Some(&vec![2]));
+        assert_eq!(postings.get(&LabelId::from_index(1)), None);
+        assert_eq!(postings.get(&LabelId::from_index(HOT_LABELS + 1)), None);
+        assert_eq!(postings.iter().count(), 2);
+
+        let json = serde_json::to_string(&postings).unwrap();
+        let loaded: LabelPostings<Vec<usize>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            postings.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_dense_counts() {
+        let mut counts = DenseCounts::new(200);
+        for tree in [0, 5, 63, 64, 130, 199] {
+            counts.set(tree, tree as u32 % 7 + 1);
+        }
+        counts.set(5, 0);
+        assert_eq!(
+            counts
+                .within(0..200)
+                .map(|(tree, _)| tree)
+                .collect::<Vec<_>>(),
+            [0, 63, 64, 130, 199]
+        );
+        assert_eq!(
+            counts.within(63..130).collect::<Vec<_>>(),
+            [(63, 1), (64, 2)]
+        );
+        assert_eq!(counts.within(1..63).count(), 0);
+        assert_eq!(counts.within(199..200).collect::<Vec<_>>(), [(199, 4)]);
+
+        let keys = dense_keys([(1, 40), (2, 100), (3, 10), (4, 17)], 100);
+        assert_eq!(keys, FxHashSet::from_iter([1, 2, 4]));
+    }
+}
@@ -0,0 +1,73 @@
+//! Compact label signatures of trees, a 256-bit Bloom filter with a single hash
+//! function over label ids. Comparing two signatures gives a cheap lower bound on
+//! the label intersection lower bound without touching any posting lists.
+use crate::memory::MemoryUsage;
+use crate::parsing::LabelId;
+use serde::{Deserialize, Serialize};
+
+const WORDS: usize = 4;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LabelSignature([u64; WORDS]);
+
+impl LabelSignature {
+    #[inline(always)]
+    fn bit(label: LabelId) -> usize {
+        // fibonacci hashing, top 8 bits select one of the 256 bits
+        ((label.get() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as usize
+    }
+
+    pub fn from_labels(labels: impl IntoIterator<Item = LabelId>) -> Self {
+        let mut signature = Self::default();
+        for label in labels {
+            let bit = Self::bit(label);
+            signature.0[bit / 64] |= 1 << (bit % 64);
+        }
+        signature
+    }
+
+    /// Number of bits set only in this signature. Each such bit belongs to at least
+    /// one label, and so at least one node, missing in the other tree.
+    #[inline(always)]
+    pub fn missing_in(&self, other: &Self) -> usize {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(s, o)| (s & !o).count_ones() as usize)
+            .sum()
+    }
+
+    /// Lower bound of the label intersection lower bound of two trees with given sizes.
+    /// Nodes with labels missing in the other tree can not be in the intersection.
+    #[inline(always)]
+    pub fn lower_bound(&self, size: usize, other: &Self, other_size: usize) -> usize {
+        let max_intersection = std::cmp::min(
+            size - self.missing_in(other),
+            other_size - other.missing_in(self),
+        );
+        std::cmp::max(size, other_size) - max_intersection
+    }
+}
+
+impl MemoryUsage for LabelSignature {
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_lower_bound() {
+        let s1 = LabelSignature::from_labels([1, 2, 3].map(LabelId::from_index));
+        let s2 = LabelSignature::from_labels([1, 2, 3].map(LabelId::from_index));
+        assert_eq!(s1.lower_bound(5, &s2, 4), 1);
+
+        let s3 = LabelSignature::from_labels([4, 5].map(LabelId::from_index));
+        // labels 2 to 6 hash to distinct bits
+        assert_eq!(s1.missing_in(&s3), 3);
+        assert_eq!(s1.lower_bound(3, &s3, 2), 3);
+    }
+}
@@ -0,0 +1,215 @@
+//! Approximate candidate generation with locality sensitive hashing.
+//!
+//! Trees are sketched into MinHash signatures of their label multiset or pq-gram
+//! multiset, signatures are split into bands of rows and trees sharing the hash of any
+//! band are candidates. Unlike the lower bounds this misses similar pairs, the chance of
+//! a pair with Jaccard similarity `s` to become a candidate is `1 - (1 - s^rows)^bands`.
+//! Candidates are limited to trees within the size range of the query threshold.
+use crate::collection::{CollectionView, UnsortedCollection};
+use crate::lb::size_buckets::SizeBuckets;
+use crate::parsing::{LabelId, ParsedTree};
+use clap::ValueEnum;
+use indextree::NodeId;
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHasher};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Sketch {
+    /// Multiset of node labels
+    Labels,
+    /// Multiset of pq-grams, p ancestors and q consecutive children of every node
+    PqGrams,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LshParams {
+    pub sketch: Sketch,
+    pub bands: usize,
+    pub rows: usize,
+    pub p: usize,
+    pub q: usize,
+}
+
+impl Default for LshParams {
+    fn default() -> Self {
+        Self {
+            sketch: Sketch::PqGrams,
+            bands: 32,
+            rows: 4,
+            p: 2,
+            q: 3,
+        }
+    }
+}
+
+/// Label of padding nodes of pq-grams
+const PADDING: LabelId = LabelId::MAX;
+
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = FxHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// splitmix64 finalizer, mixes a seeded element into one of the MinHash permutations
+#[inline(always)]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Hashes of pq-grams of a tree, one per gram
+pub fn pq_grams(tree: &ParsedTree, p: usize, q: usize) -> Vec<u64> {
+    let Some(root) = tree.iter().next() else {
+        return vec![];
+    };
+    let root = tree.get_node_id(root).unwrap();
+    let mut grams = Vec::with_capacity(tree.count() * 2);
+    let mut stack = vec![(root, vec![PADDING; p])];
+    while let Some((nid, mut stem)) = stack.pop() {
+        // stem is the window of p labels ending with the node
+        stem.remove(0);
+        stem.push(*tree[nid].get());
+        let children = nid.children(tree).collect::<Vec<NodeId>>();
+        let mut base = vec![PADDING; q];
+        if children.is_empty() {
+            grams.push(hash_of((&stem, &base)));
+        } else {
+            // windows of q children over children padded with q - 1 nodes on both sides
+            let padded = children
+                .iter()
+                .map(|c| *tree[*c].get())
+                .chain(std::iter::repeat_n(PADDING, q - 1));
+            for label in padded {
+                base.remove(0);
+                base.push(label);
+                grams.push(hash_of((&stem, &base)));
+            }
+        }
+        stack.extend(children.into_iter().map(|c| (c, stem.clone())));
+    }
+    grams
+}
+
+/// Elements of the sketched multiset, repeated elements are told apart by their count
+fn multiset(tree: &ParsedTree, params: &LshParams) -> Vec<u64> {
+    let elements = match params.sketch {
+        Sketch::Labels => tree.iter().map(|n| hash_of(n.get())).collect(),
+        Sketch::PqGrams => pq_grams(tree, params.p, params.q),
+    };
+    let mut counts = FxHashMap::default();
+    elements
+        .into_iter()
+        .map(|e| {
+            let count = counts.entry(e).or_insert(0u32);
+            *count += 1;
+            hash_of((e, *count))
+        })
+        .collect()
+}
+
+/// MinHash signature with `bands * rows` values
+pub fn signature(tree: &ParsedTree, params: &LshParams) -> Vec<u64> {
+    let elements = multiset(tree, params);
+    (0..params.bands * params.rows)
+        .map(|i| {
+            let seed = mix(i as u64 + 1);
+            elements
+                .iter()
+                .map(|e| mix(e ^ seed))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+pub struct LshIndex {
+    params: LshParams,
+    /// trees by band and hash of the band
+    buckets: FxHashMap<(usize, u64), Vec<usize>>,
+    size_buckets: SizeBuckets,
+}
+
+impl LshIndex {
+    pub fn new(collection: CollectionView, params: LshParams) -> Result<Self, UnsortedCollection> {
+        let trees = collection.size_sorted("LSH index")?;
+        let signatures = trees
+            .par_iter()
+            .map(|t| signature(t, &params))
+            .collect::<Vec<_>>();
+        let mut buckets: FxHashMap<(usize, u64), Vec<usize>> = FxHashMap::default();
+        for (tid, signature) in signatures.iter().enumerate() {
+            for (band, rows) in signature.chunks(params.rows).enumerate() {
+                buckets.entry((band, hash_of(rows))).or_default().push(tid);
+            }
+        }
+        Ok(Self {
+            params,
+            buckets,
+            size_buckets: SizeBuckets::from_sizes(trees.iter().map(|t| t.count())),
+        })
+    }
+
+    /// Trees sharing a band with the query and within its size range, in increasing order
+    pub fn candidates(&self, query: &ParsedTree, k: usize) -> Vec<usize> {
+        let size_range = self.size_buckets.within(query.count(), k);
+        let signature = signature(query, &self.params);
+        let mut candidates = signature
+            .chunks(self.params.rows)
+            .enumerate()
+            .filter_map(|(band, rows)| self.buckets.get(&(band, hash_of(rows))))
+            .flatten()
+            .copied()
+            .filter(|tid| size_range.contains(tid))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Candidates of all queries as (query id, tree id) pairs and the time it took
+    pub fn run(&self, queries: &[(usize, ParsedTree)]) -> (Vec<(usize, usize)>, Duration) {
+        let start = Instant::now();
+        let candidates = queries
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(qid, (k, query))| {
+                self.candidates(query, *k)
+                    .into_iter()
+                    .map(move |tid| (qid, tid))
+            })
+            .collect();
+        (candidates, start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_lsh_candidates() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        // a has 4 windows over its 2 children, leaves b and c one each
+        assert_eq!(pq_grams(&tree, 2, 3).len(), 6);
+
+        let trees =
+            ["{a{b}{c}}", "{x{y}{z}}", "{a{b}{c}{d}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        for sketch in [Sketch::Labels, Sketch::PqGrams] {
+            let params = LshParams {
+                sketch,
+                ..LshParams::default()
+            };
+            let index = LshIndex::new(CollectionView::new(&trees), params).unwrap();
+            let query = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+            let candidates = index.candidates(&query, 1);
+            assert!(candidates.contains(&0), "{sketch:?}");
+            assert!(!candidates.contains(&1), "{sketch:?}");
+        }
+    }
+}
@@ -1,51 +1,675 @@
+pub mod auto;
 pub mod binary_branch;
+pub mod bk_tree;
 pub mod indexes;
+pub mod label_clusters;
 pub mod label_intersection;
+pub mod label_postings;
+pub mod label_signature;
+pub mod lsh;
+pub mod packed_labels;
+pub mod preprocess;
+pub mod qgram_count;
 pub mod sed;
+#[cfg(feature = "gpu")]
+pub mod sed_gpu;
+pub mod size_buckets;
 pub mod structural_filter;
 
-macro_rules! iterate_queries {
-    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident) => {{
-        let __start_time = std::time::Instant::now();
-        let mut candidates = vec![];
-        for (qid, (t, query)) in $query_tuple.iter().enumerate() {
-            for (tid, tree) in $tree_indexes.iter().enumerate() {
-                if $lb_func(query, tree, *t) <= *t {
-                    candidates.push((qid, tid));
-                }
+use crate::checkpoint::Checkpointer;
+use crate::collection::{CollectionView, UnsortedCollection};
+use crate::indexing::{
+    index_queries, IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex,
+};
+use crate::label_weights::LabelWeights;
+use crate::memory::MemoryUsage;
+use crate::parsing::{LabelDict, ParsedTree};
+use crate::query_timeout::QueryTimeout;
+use crate::LowerBoundMethods;
+use itertools::Itertools;
+use label_intersection::label_intersection_k;
+use preprocess::Preprocessed;
+use qgram_count::qgram_count_k;
+use rayon::prelude::*;
+use sed::{sed, SedEvaluator};
+use size_buckets::SizeBuckets;
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use structural_filter::{
+    ted as struct_ted_k, ted_matching, ted_variant as struct_split_ted_k, StructuralFilterTuple,
+};
+
+/// Size buckets of a tree collection sorted by size
+pub fn create_size_buckets(collection: CollectionView) -> Result<SizeBuckets, UnsortedCollection> {
+    let trees = collection.size_sorted("Size buckets")?;
+    Ok(SizeBuckets::from_sizes(trees.iter().map(|t| t.count())))
+}
+
+/// Sum of `common` of the pairs of elements with equal keys of two slices sorted by key,
+/// every element is paired at most once, e.g. the bag intersection of sorted profiles
+pub fn sorted_intersection<T, K: Ord>(
+    s1: &[T],
+    s2: &[T],
+    key: impl Fn(&T) -> K,
+    common: impl Fn(&T, &T) -> usize,
+) -> usize {
+    let (mut i, mut j, mut sum) = (0, 0, 0);
+    while i < s1.len() && j < s2.len() {
+        match key(&s1[i]).cmp(&key(&s2[j])) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                sum += common(&s1[i], &s2[j]);
+                i += 1;
+                j += 1;
             }
         }
+    }
+    sum
+}
 
-        (candidates, __start_time.elapsed())
-    }};
-    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident, $size_map:ident) => {{
-        let __start_time = std::time::Instant::now();
-        let mut candidates = vec![];
-        let trees_len = $tree_indexes.len();
-        for (qid, (t, query)) in $query_tuple.iter().enumerate() {
-            let start_idx = $size_map
-                .get(&query.c.tree_size.saturating_sub(*t))
-                .unwrap_or(&0);
-            let end_idx = $size_map
-                .get(&(query.c.tree_size + t + 1))
-                .unwrap_or(&trees_len);
-            let idx_diff = end_idx - start_idx;
-            // println!("Starting from {start_idx} and taking at most {idx_diff} trees!");
-
-            for (tid, tree) in $tree_indexes
+/// Options of a run changing the bounds of the methods, the default computes every bound
+/// as without options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunConfig<'a> {
+    /// weights of labels in the label intersection bound
+    pub label_weights: Option<&'a LabelWeights>,
+    /// label groups with at most this many nodes are matched exactly by the structural
+    /// filter, see [`ted_matching`]
+    pub structural_matching: usize,
+    /// compares nodes of the structural filter only within the tight postorder window
+    pub tight_window: bool,
+}
+
+/// Runs the candidate generation of a single lower bound method over all queries,
+/// returns candidates as (query id, tree id) pairs and the time it took.
+/// Tree indexes are taken from the shared preprocessing.
+pub fn run_method(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    config: &RunConfig,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    run_method_tracked(method, pre, queries, config, None, None)
+}
+
+/// Same as [`run_method`], but saves the candidates of every query to the `checkpoint`
+/// and resumes after the queries saved in it, and stops comparing a query with trees
+/// once its `timeout` passed. Queries are then evaluated one by one instead of in groups.
+pub fn run_method_tracked(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    config: &RunConfig,
+    mut checkpoint: Option<&mut Checkpointer>,
+    mut timeout: Option<&mut QueryTimeout>,
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let label_dict = pre.label_dict();
+    let size_buckets = pre.size_buckets();
+    let tracked = checkpoint.is_some() || timeout.is_some();
+    if checkpoint.is_some()
+        && !matches!(
+            method,
+            LBM::Lblint | LBM::Sed | LBM::Structural | LBM::StructuralSplit | LBM::SedQgram
+        )
+    {
+        anyhow::bail!("Checkpointing is not supported by {method:?}");
+    }
+    let result = match method {
+        LBM::Lblint => {
+            let lblint_indexes = pre.label_sets()?;
+            let lblint_queries =
+                index_queries::<InvertedListLabelPostorderIndex>(queries, label_dict)?;
+            let packed = (!tracked && config.label_weights.is_none())
+                .then(|| {
+                    packed_labels::try_packed(
+                        label_dict,
+                        &lblint_queries,
+                        lblint_indexes,
+                        size_buckets,
+                    )
+                })
+                .flatten();
+            if let Some(weights) = config.label_weights {
+                let weighted_k = |q: &InvertedListLabelPostorderIndex,
+                                  t: &InvertedListLabelPostorderIndex,
+                                  k| {
+                    label_intersection::weighted_label_intersection_k(q, t, k, |l| weights.get(l))
+                };
+                iterate_queries!(
+                    lblint_queries,
+                    lblint_indexes,
+                    weighted_k,
+                    size_buckets;
+                    checkpoint, timeout
+                )
+            } else if let Some(result) = packed {
+                result
+            } else {
+                iterate_queries!(
+                    lblint_queries,
+                    lblint_indexes,
+                    label_intersection_k,
+                    size_buckets;
+                    checkpoint, timeout
+                )
+            }
+        }
+        LBM::Sed => {
+            let sed_indexes = pre.traversals()?;
+            let sed_queries = index_queries::<SEDIndex>(queries, label_dict)?;
+            let gpu_result = if tracked {
+                None
+            } else {
+                try_gpu_sed(&sed_queries, sed_indexes, size_buckets)
+            };
+            if let Some(result) = gpu_result {
+                result
+            } else {
+                let mut evaluator = SedEvaluator::for_queries(&sed_queries);
+                let mut sed_k = |q: &SEDIndex, t: &SEDIndex, k| evaluator.sed_k(q, t, k);
+                iterate_queries!(sed_queries, sed_indexes, sed_k, size_buckets; checkpoint, timeout)
+            }
+        }
+        LBM::SedBkTree => {
+            let sed_queries = index_queries::<SEDIndex>(queries, label_dict)?;
+            pre.sed_tree()?.run(pre.traversals()?, &sed_queries)
+        }
+        LBM::Structural => {
+            let structural_sets = pre.structural_sets()?;
+            let structural_queries = pre.structural_queries(queries)?;
+            let struct_matching_k = |q: &StructuralFilterTuple, t: &StructuralFilterTuple, k| {
+                ted_matching(q, t, k, config.structural_matching, config.tight_window)
+            };
+            iterate_queries!(
+                structural_queries,
+                structural_sets,
+                struct_matching_k;
+                checkpoint, timeout
+            )
+        }
+        LBM::StructuralSplit => {
+            let split_sets = pre.split_sets()?;
+            let split_queries = pre.split_queries(queries)?;
+            iterate_queries!(split_queries, split_sets, struct_split_ted_k; checkpoint, timeout)
+        }
+        LBM::SedQgram => {
+            let profiles = pre.qgram_profiles()?;
+            let qgram_queries = pre.qgram_queries(queries)?;
+            iterate_queries!(qgram_queries, profiles, qgram_count_k, size_buckets; checkpoint, timeout)
+        }
+        LBM::SedUnbounded => {
+            let sed_indexes = pre.traversals()?;
+            let sed_queries = index_queries::<SEDIndex>(queries, label_dict)?;
+            let sed_unbounded = |q: &SEDIndex, t: &SEDIndex, _| sed(q, t);
+            iterate_queries!(sed_queries, sed_indexes, sed_unbounded, size_buckets)
+        }
+        LBM::Auto => {
+            let selection = auto::select(pre, queries, auto::DEFAULT_SAMPLE, config)?;
+            auto::run_plan(selection.best, pre, queries, config)?
+        }
+        LBM::Tuned => {
+            let pipeline = auto::tune(pre, queries, auto::DEFAULT_SAMPLE, config)?;
+            auto::run_pipeline(&pipeline.stages, pre, queries, config)?
+        }
+        _ => anyhow::bail!("Method {method:?} is not supported yet"),
+    };
+    Ok(result)
+}
+
+/// String edit distance bound of every query to every tree without a threshold, as
+/// `(query id, tree id, distance)` sorted by query and then by increasing distance
+pub fn sed_ranking(
+    queries: &[(usize, SEDIndex)],
+    trees: &[SEDIndex],
+) -> Vec<(usize, usize, usize)> {
+    queries
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(qid, (_, query))| {
+            trees
                 .iter()
                 .enumerate()
-                .skip(*start_idx)
-                .take(idx_diff)
-            {
-                if $lb_func(query, tree, *t) <= *t {
+                .map(|(tid, tree)| (qid, tid, sed(query, tree)))
+                .sorted_by_key(|(_, tid, distance)| (*distance, *tid))
+        })
+        .collect()
+}
+
+/// Evaluates the SED bound on the GPU when built with the `gpu` feature and an
+/// adapter is available, `None` means the caller falls back to the CPU.
+pub fn try_gpu_sed(
+    queries: &[(usize, SEDIndex)],
+    trees: &[SEDIndex],
+    size_buckets: &SizeBuckets,
+) -> Option<(Vec<(usize, usize)>, Duration)> {
+    #[cfg(feature = "gpu")]
+    return sed_gpu::GpuSed::get()?.candidates(queries, trees, size_buckets);
+    #[cfg(not(feature = "gpu"))]
+    {
+        let _ = (queries, trees, size_buckets);
+        None
+    }
+}
+
+/// Candidates, the time it took and number of chunks of a chunked run
+pub type ChunkedResult = (Vec<(usize, usize)>, Duration, usize);
+
+/// Estimates bytes of indexes of all trees by indexing an evenly spaced sample
+/// of trees and scaling by the number of nodes. Trees that fail to index count as
+/// empty, the error surfaces once the indexes are built.
+pub fn estimate_index_bytes<I: MemoryUsage>(
+    trees: &[ParsedTree],
+    index_tree: impl Fn(&ParsedTree) -> Result<I, IndexError>,
+) -> usize {
+    const SAMPLE_SIZE: usize = 256;
+    if trees.is_empty() {
+        return 0;
+    }
+    let step = trees.len().div_ceil(SAMPLE_SIZE);
+    let (sample_nodes, sample_bytes) =
+        trees
+            .iter()
+            .step_by(step)
+            .fold((0, 0), |(nodes, bytes), t| {
+                (
+                    nodes + t.count(),
+                    bytes + index_tree(t).map_or(0, |i| i.estimated_bytes()),
+                )
+            });
+    let total_nodes = trees.iter().map(|t| t.count()).sum::<usize>();
+    (sample_bytes as f64 * total_nodes as f64 / sample_nodes.max(1) as f64) as usize
+}
+
+/// Estimated bytes of per tree indexes of a lower bound method
+pub fn estimate_method_bytes(
+    method: LowerBoundMethods,
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+) -> usize {
+    use LowerBoundMethods as LBM;
+    match method {
+        LBM::Lblint => estimate_index_bytes(trees, |t| {
+            InvertedListLabelPostorderIndex::index_tree(t, label_dict)
+        }),
+        LBM::Sed => estimate_index_bytes(trees, |t| SEDIndex::index_tree(t, label_dict)),
+        LBM::Structural => {
+            estimate_index_bytes(trees, |t| StructuralFilterTuple::index_tree(t, label_dict))
+        }
+        _ => 0,
+    }
+}
+
+/// Same as [`run_method`], but keeps only a chunk of tree indexes of at most
+/// `chunk_budget` bytes in memory, see [`iterate_chunked`].
+pub fn run_method_chunked(
+    method: LowerBoundMethods,
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    label_dict: &LabelDict,
+    size_buckets: &SizeBuckets,
+    chunk_budget: usize,
+) -> Result<ChunkedResult, anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let result = match method {
+        LBM::Lblint => {
+            let lblint_queries =
+                index_queries::<InvertedListLabelPostorderIndex>(queries, label_dict)?;
+            iterate_chunked(
+                &lblint_queries,
+                trees,
+                chunk_budget,
+                |q, k| size_buckets.within(q.c.tree_size, k),
+                |t| InvertedListLabelPostorderIndex::index_tree(t, label_dict),
+                label_intersection_k,
+            )?
+        }
+        LBM::Sed => {
+            let sed_queries = index_queries::<SEDIndex>(queries, label_dict)?;
+            let mut evaluator = SedEvaluator::for_queries(&sed_queries);
+            iterate_chunked(
+                &sed_queries,
+                trees,
+                chunk_budget,
+                |q, k| size_buckets.within(q.c.tree_size, k),
+                |t| SEDIndex::index_tree(t, label_dict),
+                |q, t, k| evaluator.sed_k(q, t, k),
+            )?
+        }
+        LBM::Structural => {
+            let structural_queries = index_queries::<StructuralFilterTuple>(queries, label_dict)?;
+            // same as in memory, structural filter visits trees of all sizes
+            iterate_chunked(
+                &structural_queries,
+                trees,
+                chunk_budget,
+                |_, _| 0..trees.len(),
+                |t| StructuralFilterTuple::index_tree(t, label_dict),
+                struct_ted_k,
+            )?
+        }
+        _ => anyhow::bail!("Method {method:?} is not supported yet"),
+    };
+    Ok(result)
+}
+
+/// Streaming variant of [`iterate_queries`] for collections whose indexes do not fit
+/// into memory. Trees are indexed in consecutive chunks whose estimated size fits into
+/// `chunk_budget` bytes, all queries are run against a chunk and the chunk is dropped
+/// before indexing the next one. Only trees in `candidate_range` of a query and threshold
+/// are compared.
+pub fn iterate_chunked<Q, I>(
+    queries: &[(usize, Q)],
+    trees: &[ParsedTree],
+    chunk_budget: usize,
+    candidate_range: impl Fn(&Q, usize) -> Range<usize>,
+    index_tree: impl Fn(&ParsedTree) -> Result<I, IndexError> + Sync,
+    mut lb_func: impl FnMut(&Q, &I, usize) -> usize,
+) -> Result<ChunkedResult, IndexError>
+where
+    I: MemoryUsage + Send,
+{
+    // trees indexed in parallel at once, before the chunk size is checked
+    const BATCH: usize = 1024;
+    let start = Instant::now();
+    let mut candidates = vec![];
+    let mut chunks = 0;
+    let mut chunk_start = 0;
+    while chunk_start < trees.len() {
+        let mut chunk: Vec<I> = vec![];
+        let mut chunk_bytes = 0;
+        while chunk_start + chunk.len() < trees.len()
+            && (chunk.is_empty() || chunk_bytes < chunk_budget)
+        {
+            let from = chunk_start + chunk.len();
+            let to = (from + BATCH).min(trees.len());
+            let batch = trees[from..to]
+                .par_iter()
+                .map(&index_tree)
+                .collect::<Result<Vec<_>, _>>()?;
+            chunk_bytes += batch.iter().map(I::estimated_bytes).sum::<usize>();
+            chunk.extend(batch);
+        }
+        let chunk_range = chunk_start..chunk_start + chunk.len();
+        for (qid, (t, query)) in queries.iter().enumerate() {
+            let range = candidate_range(query, *t);
+            let from = range.start.max(chunk_range.start);
+            let to = range.end.min(chunk_range.end);
+            for tid in from..to {
+                if lb_func(query, &chunk[tid - chunk_start], *t) <= *t {
                     candidates.push((qid, tid));
                 }
             }
         }
+        chunk_start = chunk_range.end;
+        chunks += 1;
+    }
+    Ok((candidates, start.elapsed(), chunks))
+}
 
-        (candidates, __start_time.elapsed())
+/// Number of queries evaluated together against each tree in [`iterate_grouped`]
+const QUERY_GROUP_SIZE: usize = 32;
+
+/// Evaluates queries in groups of similar candidate ranges, which follow query size
+/// and threshold. Every tree in the union of the ranges of a group is loaded once
+/// and compared with all queries of the group it is a candidate for, instead of
+/// streaming the trees through the cache once per query.
+/// Candidates are returned sorted, in the same order as evaluating query by query.
+pub fn iterate_grouped<Q, I>(
+    queries: &[(usize, Q)],
+    tree_indexes: &[I],
+    candidate_range: impl Fn(&Q, usize) -> Range<usize>,
+    mut lb_func: impl FnMut(&Q, &I, usize) -> usize,
+) -> (Vec<(usize, usize)>, Duration) {
+    let start = Instant::now();
+    let ranges = queries
+        .iter()
+        .map(|(t, query)| candidate_range(query, *t))
+        .collect_vec();
+    let mut order = (0..queries.len()).collect_vec();
+    order.sort_by_key(|qid| (ranges[*qid].start, ranges[*qid].end));
+
+    let mut candidates = vec![];
+    for group in order.chunks(QUERY_GROUP_SIZE) {
+        let from = group.iter().map(|qid| ranges[*qid].start).min().unwrap();
+        let to = group.iter().map(|qid| ranges[*qid].end).max().unwrap();
+        for (tid, tree) in tree_indexes.iter().enumerate().take(to).skip(from) {
+            for &qid in group {
+                let (t, query) = &queries[qid];
+                if ranges[qid].contains(&tid) && lb_func(query, tree, *t) <= *t {
+                    candidates.push((qid, tid));
+                }
+            }
+        }
+    }
+    candidates.sort_unstable();
+    (candidates, start.elapsed())
+}
+
+macro_rules! iterate_queries {
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident) => {{
+        let mut __no_checkpoint: Option<$crate::checkpoint::Checkpointer> = None;
+        $crate::lb::iterate_queries!($query_tuple, $tree_indexes, $lb_func; __no_checkpoint)
+    }};
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident; $checkpoint:ident) => {{
+        let mut __no_timeout: Option<$crate::query_timeout::QueryTimeout> = None;
+        $crate::lb::iterate_queries!($query_tuple, $tree_indexes, $lb_func; $checkpoint, __no_timeout)
+    }};
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident; $checkpoint:ident, $timeout:ident) => {{
+        if $checkpoint.is_none() && $timeout.is_none() {
+            $crate::lb::iterate_grouped(
+                &$query_tuple[..],
+                &$tree_indexes[..],
+                |_, _| 0..$tree_indexes.len(),
+                $lb_func,
+            )
+        } else {
+            let __start_time = std::time::Instant::now();
+            let mut candidates = $checkpoint
+                .as_mut()
+                .map_or_else(Vec::new, |c| c.take_resumed());
+            let __resume_from = $checkpoint.as_ref().map_or(0, |c| c.resume_from());
+            if let Some(ref c) = $checkpoint {
+                c.resume_timed_out($timeout.as_mut().map(|t| &mut t.timed_out))?;
+            }
+            for (qid, (t, query)) in $query_tuple.iter().enumerate().skip(__resume_from) {
+                let __before = candidates.len();
+                let __deadline = $timeout.as_ref().map(|t| t.start());
+                for (tid, tree) in $tree_indexes.iter().enumerate() {
+                    if __deadline.is_some_and(|d| d.passed(tid)) {
+                        $timeout.as_mut().unwrap().timed_out.push(qid);
+                        if let Some(ref mut c) = $checkpoint {
+                            c.query_timed_out(qid);
+                        }
+                        break;
+                    }
+                    if $lb_func(query, tree, *t) <= *t {
+                        candidates.push((qid, tid));
+                    }
+                }
+                if let Some(ref mut c) = $checkpoint {
+                    c.query_done(qid, &candidates[__before..])?;
+                }
+            }
+
+            (candidates, __start_time.elapsed())
+        }
+    }};
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident, $size_buckets:ident) => {{
+        let mut __no_checkpoint: Option<$crate::checkpoint::Checkpointer> = None;
+        $crate::lb::iterate_queries!($query_tuple, $tree_indexes, $lb_func, $size_buckets; __no_checkpoint)
+    }};
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident, $size_buckets:ident; $checkpoint:ident) => {{
+        let mut __no_timeout: Option<$crate::query_timeout::QueryTimeout> = None;
+        $crate::lb::iterate_queries!($query_tuple, $tree_indexes, $lb_func, $size_buckets; $checkpoint, __no_timeout)
+    }};
+    ($query_tuple:ident, $tree_indexes:ident, $lb_func:ident, $size_buckets:ident; $checkpoint:ident, $timeout:ident) => {{
+        if $checkpoint.is_none() && $timeout.is_none() {
+            $crate::lb::iterate_grouped(
+                &$query_tuple[..],
+                &$tree_indexes[..],
+                |query, t| $size_buckets.within(query.c.tree_size, t),
+                $lb_func,
+            )
+        } else {
+            let __start_time = std::time::Instant::now();
+            let mut candidates = $checkpoint
+                .as_mut()
+                .map_or_else(Vec::new, |c| c.take_resumed());
+            let __resume_from = $checkpoint.as_ref().map_or(0, |c| c.resume_from());
+            if let Some(ref c) = $checkpoint {
+                c.resume_timed_out($timeout.as_mut().map(|t| &mut t.timed_out))?;
+            }
+            for (qid, (t, query)) in $query_tuple.iter().enumerate().skip(__resume_from) {
+                let __before = candidates.len();
+                let __deadline = $timeout.as_ref().map(|t| t.start());
+                for (__step, tid) in $size_buckets.within(query.c.tree_size, *t).enumerate() {
+                    if __deadline.is_some_and(|d| d.passed(__step)) {
+                        $timeout.as_mut().unwrap().timed_out.push(qid);
+                        if let Some(ref mut c) = $checkpoint {
+                            c.query_timed_out(qid);
+                        }
+                        break;
+                    }
+                    if $lb_func(query, &$tree_indexes[tid], *t) <= *t {
+                        candidates.push((qid, tid));
+                    }
+                }
+                if let Some(ref mut c) = $checkpoint {
+                    c.query_done(qid, &candidates[__before..])?;
+                }
+            }
+
+            (candidates, __start_time.elapsed())
+        }
     }};
 }
 
 pub(crate) use iterate_queries;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lb::binary_branch::BRANCH_DIVISOR;
+    use crate::lb::indexes::histograms::{
+        create_tree_histograms, histogram_ted, HistogramDivisors,
+    };
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_chunked_matches_in_memory() {
+        let mut ld = LabelDict::default();
+        let shapes = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{x{b}{c}}",
+            "{a{b{c}}{d}}",
+            "{a{b}{c}{d}{e}}",
+        ];
+        let mut trees = (0..300)
+            .flat_map(|_| shapes)
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .collect::<Vec<_>>();
+        trees.sort_by_key(|t| t.count());
+        let queries = vec![(1, parse_single("{a{b}{c}}".to_owned(), &mut ld))];
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let size_buckets = pre.size_buckets();
+        for method in LowerBoundMethods::REGISTERED {
+            let (mut expected, _) =
+                run_method(method, &pre, &queries, &RunConfig::default()).unwrap();
+            // zero budget puts every indexed batch of trees into its own chunk
+            let (mut candidates, _, chunks) =
+                run_method_chunked(method, &trees, &queries, &ld, size_buckets, 0).unwrap();
+            assert_eq!(chunks, 2);
+            expected.sort();
+            candidates.sort();
+            assert_eq!(candidates, expected, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn test_single_node_and_empty_trees() {
+        let mut ld = LabelDict::default();
+        let mut trees = ["{a}", "{b}", "{a}", "{a{b}}", "{b{a}{c}}", "{c{a{b}}{d}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        trees.sort_by_key(|t| t.count());
+        let query_trees = ["{a}", "{c}", "{x}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        for k in 0..4 {
+            let queries = query_trees
+                .iter()
+                .map(|query| (k, query.clone()))
+                .collect::<Vec<_>>();
+            let expected = queries
+                .iter()
+                .enumerate()
+                .flat_map(|(qid, (_, query))| {
+                    let trees = &trees;
+                    (0..trees.len())
+                        .filter(move |tid| crate::ted::ted(query, &trees[*tid]) <= k)
+                        .map(move |tid| (qid, tid))
+                })
+                .collect::<Vec<_>>();
+            for method in LowerBoundMethods::REGISTERED {
+                let (candidates, _) =
+                    run_method(method, &pre, &queries, &RunConfig::default()).unwrap();
+                let (chunked, _, _) =
+                    run_method_chunked(method, &trees, &queries, &ld, pre.size_buckets(), 0)
+                        .unwrap();
+                for pair in &expected {
+                    assert!(candidates.contains(pair), "{method:?} k={k} {pair:?}");
+                    assert!(chunked.contains(pair), "{method:?} k={k} {pair:?}");
+                }
+            }
+        }
+
+        // empty trees are reported instead of panicking
+        let empty = vec![(1, ParsedTree::new())];
+        for method in LowerBoundMethods::REGISTERED {
+            assert!(
+                run_method(method, &pre, &empty, &RunConfig::default()).is_err(),
+                "{method:?}"
+            );
+        }
+        // bounds without an index treat them as trees of size 0
+        let pair = [ParsedTree::new(), trees[3].clone()];
+        let branches = binary_branch::BinaryBranchConverter::default().create(&pair);
+        let bib = binary_branch::ted(&branches[0], &branches[1], 3, BRANCH_DIVISOR);
+        let hists = pair.each_ref().map(create_tree_histograms);
+        let hist = histogram_ted((0, &hists[0]), (2, &hists[1]), HistogramDivisors::TIGHT);
+        assert!(bib <= 2 && hist <= 2 && hist > 0, "{bib} {hist}");
+    }
+
+    #[test]
+    fn test_sed_ranking() {
+        let mut ld = LabelDict::default();
+        let trees =
+            ["{a}", "{a{b}}", "{x{b}}", "{a{b}{c}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = vec![(1, parse_single("{a{b}}".to_owned(), &mut ld))];
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let sed_queries = index_queries::<SEDIndex>(&queries, &ld).unwrap();
+        let ranking = sed_ranking(&sed_queries, pre.traversals().unwrap());
+        assert_eq!(ranking, vec![(0, 1, 0), (0, 0, 1), (0, 2, 1), (0, 3, 1)]);
+
+        let (mut unbounded, _) = run_method(
+            LowerBoundMethods::SedUnbounded,
+            &pre,
+            &queries,
+            &RunConfig::default(),
+        )
+        .unwrap();
+        let (mut bounded, _) = run_method(
+            LowerBoundMethods::Sed,
+            &pre,
+            &queries,
+            &RunConfig::default(),
+        )
+        .unwrap();
+        unbounded.sort();
+        bounded.sort();
+        assert_eq!(unbounded, bounded);
+    }
+}
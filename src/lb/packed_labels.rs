@@ -0,0 +1,226 @@
+//! Label intersection bound of bit-packed label sets for tiny alphabets.
+//!
+//! Datasets of parse trees or markup often have only tags as labels, a few dozen labels
+//! repeated in every tree. Their inverted lists are hash maps with a handful of keys
+//! and the bound spends its time hashing. When every label id fits into a 64 or 128 bit
+//! mask, a label set is stored as planes of masks, plane `j` holds the labels occurring
+//! more than `j` times. The sum of the smaller counts of common labels, capped at the
+//! number of planes, is then the sum of popcounts of the ANDed planes. Counts above the
+//! planes are kept in a short sorted list, so the intersection stays exact and the bound
+//! is the same as of the inverted lists.
+use super::size_buckets::{size_lb, SizeBuckets};
+use crate::indexing::{ConstantsIndex, InvertedListLabelPostorderIndex};
+use crate::memory::MemoryUsage;
+use crate::parsing::{max_label, LabelDict};
+use rayon::prelude::*;
+use std::ops::{BitAnd, BitOrAssign};
+use std::time::{Duration, Instant};
+
+/// Number of count planes, label counts above it go to the overflow list
+const PLANES: usize = 4;
+
+pub trait LabelMask: Copy + Default + Send + Sync + BitAnd<Output = Self> + BitOrAssign {
+    const BITS: usize;
+    fn bit(index: usize) -> Self;
+    fn ones(self) -> usize;
+}
+
+macro_rules! label_mask {
+    ($($t:ty),*) => {$(
+        impl LabelMask for $t {
+            const BITS: usize = <$t>::BITS as usize;
+
+            #[inline(always)]
+            fn bit(index: usize) -> Self {
+                1 << index
+            }
+
+            #[inline(always)]
+            fn ones(self) -> usize {
+                self.count_ones() as usize
+            }
+        }
+    )*};
+}
+
+label_mask!(u64, u128);
+
+/// Width of the masks that fits all labels of a dictionary
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Alphabet {
+    Bits64,
+    Bits128,
+}
+
+impl Alphabet {
+    /// `None` when the dictionary has more labels than the widest mask
+    pub fn of(label_dict: &LabelDict) -> Option<Self> {
+        match max_label(label_dict).get() as usize {
+            labels if labels <= u64::BITS as usize => Some(Self::Bits64),
+            labels if labels <= u128::BITS as usize => Some(Self::Bits128),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PackedLabelSet<M> {
+    pub c: ConstantsIndex,
+    planes: [M; PLANES],
+    /// label index and count of labels occurring more than [`PLANES`] times, sorted
+    overflow: Vec<(u8, u32)>,
+}
+
+impl<M: LabelMask> PackedLabelSet<M> {
+    /// `None` when a label does not fit into the mask
+    pub fn new(set: &InvertedListLabelPostorderIndex) -> Option<Self> {
+        let mut planes = [M::default(); PLANES];
+        let mut overflow = vec![];
+        for (label, postings) in set.inverted_list.iter() {
+            if label.index() >= M::BITS {
+                return None;
+            }
+            let bit = M::bit(label.index());
+            for plane in planes.iter_mut().take(postings.len()) {
+                *plane |= bit;
+            }
+            if postings.len() > PLANES {
+                overflow.push((label.index() as u8, postings.len() as u32));
+            }
+        }
+        overflow.sort_unstable();
+        Some(Self {
+            c: ConstantsIndex {
+                tree_size: set.c.tree_size,
+            },
+            planes,
+            overflow,
+        })
+    }
+
+    /// Sum of the smaller counts of all common labels
+    pub fn intersection_size(&self, other: &Self) -> usize {
+        let capped = self
+            .planes
+            .iter()
+            .zip(&other.planes)
+            .map(|(p1, p2)| (*p1 & *p2).ones())
+            .sum::<usize>();
+        let above = super::sorted_intersection(
+            &self.overflow,
+            &other.overflow,
+            |(label, _)| *label,
+            |(_, c1), (_, c2)| *c1.min(c2) as usize - PLANES,
+        );
+        capped + above
+    }
+}
+
+/// Label intersection bound, the same as of the inverted lists of both trees
+pub fn packed_label_intersection_k<M: LabelMask>(
+    t1: &PackedLabelSet<M>,
+    t2: &PackedLabelSet<M>,
+    k: usize,
+) -> usize {
+    if size_lb(t1.c.tree_size, t2.c.tree_size, k) > k {
+        return k + 1;
+    }
+    t1.c.tree_size.max(t2.c.tree_size) - t1.intersection_size(t2)
+}
+
+impl<M> MemoryUsage for PackedLabelSet<M> {
+    fn heap_bytes(&self) -> usize {
+        self.overflow.capacity() * std::mem::size_of::<(u8, u32)>()
+    }
+}
+
+fn pack<M: LabelMask>(sets: &[InvertedListLabelPostorderIndex]) -> Option<Vec<PackedLabelSet<M>>> {
+    sets.par_iter().map(PackedLabelSet::new).collect()
+}
+
+fn packed_candidates<M: LabelMask>(
+    queries: &[(usize, InvertedListLabelPostorderIndex)],
+    trees: &[InvertedListLabelPostorderIndex],
+    size_buckets: &SizeBuckets,
+) -> Option<(Vec<(usize, usize)>, Duration)> {
+    let start = Instant::now();
+    let packed_trees = pack::<M>(trees)?;
+    let packed_queries = queries
+        .par_iter()
+        .map(|(k, query)| Some((*k, PackedLabelSet::<M>::new(query)?)))
+        .collect::<Option<Vec<_>>>()?;
+    let (candidates, _) = super::iterate_grouped(
+        &packed_queries,
+        &packed_trees,
+        |query, k| size_buckets.within(query.c.tree_size, k),
+        packed_label_intersection_k,
+    );
+    Some((candidates, start.elapsed()))
+}
+
+/// Label intersection candidates of packed label sets when the labels of the dictionary
+/// fit into a mask, `None` means the caller falls back to the inverted lists. The time
+/// includes packing the sets.
+pub fn try_packed(
+    label_dict: &LabelDict,
+    queries: &[(usize, InvertedListLabelPostorderIndex)],
+    trees: &[InvertedListLabelPostorderIndex],
+    size_buckets: &SizeBuckets,
+) -> Option<(Vec<(usize, usize)>, Duration)> {
+    match Alphabet::of(label_dict)? {
+        Alphabet::Bits64 => packed_candidates::<u64>(queries, trees, size_buckets),
+        Alphabet::Bits128 => packed_candidates::<u128>(queries, trees, size_buckets),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::Indexer;
+    use crate::lb::label_intersection::label_intersection;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_packed_label_intersection() {
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a{b}{c}}",
+            "{a{a}{a}{a}{a}{a}{b}}",
+            "{a{a}{a}{a}{a}{b}{b}}",
+            "{x{y}}",
+            "{a{b{c{d}}}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        assert_eq!(Alphabet::of(&ld), Some(Alphabet::Bits64));
+        let sets = InvertedListLabelPostorderIndex::index_collection(&trees, &ld).unwrap();
+        let packed64 = pack::<u64>(&sets).unwrap();
+        let packed128 = pack::<u128>(&sets).unwrap();
+        // six a nodes overflow the planes
+        assert_eq!(packed64[1].overflow, [(0, 6)]);
+        for i in 0..trees.len() {
+            for j in 0..trees.len() {
+                let exact = label_intersection(&sets[i], &sets[j]);
+                assert_eq!(
+                    packed_label_intersection_k(&packed64[i], &packed64[j], 10),
+                    exact
+                );
+                assert_eq!(
+                    packed_label_intersection_k(&packed128[i], &packed128[j], 10),
+                    exact
+                );
+            }
+        }
+
+        for i in 0..70 {
+            ld.insert(
+                format!("l{i}"),
+                (crate::parsing::LabelId::from_index(5 + i), 1),
+            );
+        }
+        assert_eq!(Alphabet::of(&ld), Some(Alphabet::Bits128));
+        let wide = parse_single("{l69{a}}".to_owned(), &mut ld);
+        let wide = InvertedListLabelPostorderIndex::index_tree(&wide, &ld).unwrap();
+        assert!(PackedLabelSet::<u64>::new(&wide).is_none());
+        assert!(PackedLabelSet::<u128>::new(&wide).is_some());
+    }
+}
@@ -0,0 +1,293 @@
+//! Preprocessing of a tree collection shared by all lower bound methods.
+//!
+//! Each kind of per tree index is built on first use and then reused by every
+//! following method and threshold run. Label sets of the label intersection and
+//! q-gram profiles are derived from the traversals of the string edit distance bound
+//! instead of traversing the trees again, and the traversals from the structural sets
+//! if those were built first. A restriction to some trees picks their indexes from the
+//! indexes of the whole collection. Indexing fails on empty trees, the error is returned
+//! by every getter until an index is built.
+use super::bk_tree::BkTree;
+use super::qgram_count::QGramProfile;
+use super::size_buckets::SizeBuckets;
+use super::structural_filter::{
+    best_split_distribution, LabelSetConverter, RegionWeights, SplitStructuralFilterTuple,
+    StructuralFilterTuple,
+};
+use crate::collection::{CollectionView, UnsortedCollection};
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::memory::MemoryUsage;
+use crate::parsing::{LabelDict, ParsedTree};
+use rayon::prelude::*;
+use std::sync::OnceLock;
+
+pub struct Preprocessed<'a> {
+    trees: &'a [ParsedTree],
+    label_dict: &'a LabelDict,
+    size_buckets: SizeBuckets,
+    region_weights: RegionWeights,
+    split_groups: usize,
+    qgram_size: usize,
+    traversals: OnceLock<Vec<SEDIndex>>,
+    label_sets: OnceLock<Vec<InvertedListLabelPostorderIndex>>,
+    structural_sets: OnceLock<Vec<StructuralFilterTuple>>,
+    split_sets: OnceLock<Vec<SplitStructuralFilterTuple>>,
+    sed_tree: OnceLock<BkTree>,
+    qgram_profiles: OnceLock<Vec<QGramProfile>>,
+    /// preprocessing of the whole collection and ids of the trees in it, per tree
+    /// indexes are taken from it
+    parent: Option<(&'a Preprocessed<'a>, Vec<usize>)>,
+}
+
+impl<'a> Preprocessed<'a> {
+    /// Fails unless the trees of the collection are sorted by size
+    pub fn new(
+        collection: CollectionView<'a>,
+        label_dict: &'a LabelDict,
+    ) -> Result<Self, UnsortedCollection> {
+        Ok(Self {
+            trees: collection.size_sorted("Preprocessing")?,
+            label_dict,
+            size_buckets: super::create_size_buckets(collection)?,
+            region_weights: RegionWeights::default(),
+            split_groups: LabelSetConverter::MAX_SPLIT,
+            qgram_size: 2,
+            traversals: OnceLock::new(),
+            label_sets: OnceLock::new(),
+            structural_sets: OnceLock::new(),
+            split_sets: OnceLock::new(),
+            sed_tree: OnceLock::new(),
+            qgram_profiles: OnceLock::new(),
+            parent: None,
+        })
+    }
+
+    /// Weights of regions of the structural sets, set before they are built
+    pub fn with_region_weights(mut self, region_weights: RegionWeights) -> Self {
+        self.region_weights = region_weights;
+        self
+    }
+
+    /// Number of label groups of the split sets, set before they are built
+    pub fn with_split_groups(mut self, split_groups: usize) -> Self {
+        self.split_groups = split_groups;
+        self
+    }
+
+    /// Size of the q-grams of the q-gram count bound, set before its profiles are built
+    pub fn with_qgram_size(mut self, qgram_size: usize) -> Self {
+        self.qgram_size = qgram_size;
+        self
+    }
+
+    /// Preprocessing of other trees with the same settings, nothing is built yet
+    pub fn with_trees<'b>(
+        &self,
+        trees: &'b [ParsedTree],
+    ) -> Result<Preprocessed<'b>, UnsortedCollection>
+    where
+        'a: 'b,
+    {
+        Ok(
+            Preprocessed::new(CollectionView::new(trees), self.label_dict)?
+                .with_region_weights(self.region_weights)
+                .with_split_groups(self.split_groups)
+                .with_qgram_size(self.qgram_size),
+        )
+    }
+
+    /// Preprocessing of the trees with `tree_ids` in this collection, given as `trees` in
+    /// the same order. Per tree indexes are picked from the ones of this collection, built
+    /// once on first use, instead of indexing the trees again.
+    pub fn restricted<'b>(
+        &'b self,
+        trees: &'b [ParsedTree],
+        tree_ids: &[usize],
+    ) -> Result<Preprocessed<'b>, UnsortedCollection>
+    where
+        'a: 'b,
+    {
+        debug_assert_eq!(trees.len(), tree_ids.len());
+        let mut restricted = self.with_trees(trees)?;
+        restricted.parent = Some((self, tree_ids.to_vec()));
+        Ok(restricted)
+    }
+
+    /// Index of the trees of a restriction picked from the index of the whole collection
+    fn parent_index<T: Clone>(
+        &self,
+        index: impl for<'p> Fn(&'p Preprocessed<'a>) -> Result<&'p [T], IndexError>,
+    ) -> Option<Result<Vec<T>, IndexError>> {
+        let (parent, tree_ids) = self.parent.as_ref()?;
+        Some(index(parent).map(|all| tree_ids.iter().map(|tid| all[*tid].clone()).collect()))
+    }
+
+    pub fn region_weights(&self) -> RegionWeights {
+        self.region_weights
+    }
+
+    pub fn qgram_size(&self) -> usize {
+        self.qgram_size
+    }
+
+    pub fn trees(&self) -> &'a [ParsedTree] {
+        self.trees
+    }
+
+    pub fn label_dict(&self) -> &'a LabelDict {
+        self.label_dict
+    }
+
+    pub fn size_buckets(&self) -> &SizeBuckets {
+        &self.size_buckets
+    }
+
+    /// Preorder and postorder traversals used by the string edit distance bound, read off
+    /// the structural sets if they are built and their weights keep the node positions
+    pub fn traversals(&self) -> Result<&[SEDIndex], IndexError> {
+        if let Some(traversals) = self.traversals.get() {
+            return Ok(traversals);
+        }
+        if let Some(traversals) = self.parent_index(Self::traversals) {
+            let traversals = traversals?;
+            return Ok(self.traversals.get_or_init(|| traversals));
+        }
+        let from_sets = self.structural_sets.get().and_then(|sets| {
+            sets.par_iter()
+                .map(|set| set.traversals(&self.region_weights))
+                .collect::<Option<Vec<_>>>()
+        });
+        let traversals = match from_sets {
+            Some(traversals) => traversals,
+            None => SEDIndex::index_collection(self.trees, self.label_dict)?,
+        };
+        Ok(self.traversals.get_or_init(|| traversals))
+    }
+
+    /// Label sets used by the label intersection bound
+    pub fn label_sets(&self) -> Result<&[InvertedListLabelPostorderIndex], IndexError> {
+        if let Some(label_sets) = self.label_sets.get() {
+            return Ok(label_sets);
+        }
+        let label_sets = match self.parent_index(Self::label_sets) {
+            Some(label_sets) => label_sets?,
+            None => self
+                .traversals()?
+                .par_iter()
+                .map(InvertedListLabelPostorderIndex::from_traversals)
+                .collect(),
+        };
+        Ok(self.label_sets.get_or_init(|| label_sets))
+    }
+
+    /// Label sets with structural vectors used by the structural filter
+    pub fn structural_sets(&self) -> Result<&[StructuralFilterTuple], IndexError> {
+        if let Some(sets) = self.structural_sets.get() {
+            return Ok(sets);
+        }
+        let sets = match self.parent_index(Self::structural_sets) {
+            Some(sets) => sets?,
+            None => LabelSetConverter::with_weights(self.region_weights).create(self.trees)?,
+        };
+        Ok(self.structural_sets.get_or_init(|| sets))
+    }
+
+    /// Label sets of queries with the same region weights as the structural sets
+    pub fn structural_queries(
+        &self,
+        queries: &[(usize, ParsedTree)],
+    ) -> Result<Vec<(usize, StructuralFilterTuple)>, IndexError> {
+        let mut converter = LabelSetConverter::with_weights(self.region_weights);
+        queries
+            .iter()
+            .map(|(k, q)| Ok((*k, converter.create_single(q)?)))
+            .collect()
+    }
+
+    /// Split label sets used by the split structural filter, labels are split into
+    /// groups by their frequency in the label dictionary
+    pub fn split_sets(&self) -> Result<&[SplitStructuralFilterTuple], IndexError> {
+        if let Some(sets) = self.split_sets.get() {
+            return Ok(sets);
+        }
+        let sets = match self.parent_index(Self::split_sets) {
+            Some(sets) => sets?,
+            None => {
+                let groups = best_split_distribution(self.label_dict, self.split_groups);
+                LabelSetConverter::default()
+                    .create_split(self.trees, |label| groups.get(label).copied().unwrap_or(0))?
+            }
+        };
+        Ok(self.split_sets.get_or_init(|| sets))
+    }
+
+    /// Split label sets of queries with the same label groups as the split sets
+    pub fn split_queries(
+        &self,
+        queries: &[(usize, ParsedTree)],
+    ) -> Result<Vec<(usize, SplitStructuralFilterTuple)>, IndexError> {
+        let groups = best_split_distribution(self.label_dict, self.split_groups);
+        let mut converter = LabelSetConverter::default();
+        queries
+            .iter()
+            .map(|(k, q)| {
+                let mut sets = converter.create_split(std::slice::from_ref(q), |label| {
+                    groups.get(label).copied().unwrap_or(0)
+                })?;
+                Ok((*k, sets.remove(0)))
+            })
+            .collect()
+    }
+
+    /// q-gram profiles of the traversals used by the q-gram count bound
+    pub fn qgram_profiles(&self) -> Result<&[QGramProfile], IndexError> {
+        if let Some(profiles) = self.qgram_profiles.get() {
+            return Ok(profiles);
+        }
+        let profiles = match self.parent_index(Self::qgram_profiles) {
+            Some(profiles) => profiles?,
+            None => self
+                .traversals()?
+                .par_iter()
+                .map(|t| QGramProfile::new(t, self.qgram_size))
+                .collect(),
+        };
+        Ok(self.qgram_profiles.get_or_init(|| profiles))
+    }
+
+    /// q-gram profiles of queries with the same q-gram size as the dataset profiles
+    pub fn qgram_queries(
+        &self,
+        queries: &[(usize, ParsedTree)],
+    ) -> Result<Vec<(usize, QGramProfile)>, IndexError> {
+        queries
+            .iter()
+            .map(|(k, q)| {
+                let traversals = SEDIndex::index_tree(q, self.label_dict)?;
+                Ok((*k, QGramProfile::new(&traversals, self.qgram_size)))
+            })
+            .collect()
+    }
+
+    /// BK-tree over the traversals
+    pub fn sed_tree(&self) -> Result<&BkTree, IndexError> {
+        if let Some(tree) = self.sed_tree.get() {
+            return Ok(tree);
+        }
+        let tree = BkTree::new(self.traversals()?);
+        Ok(self.sed_tree.get_or_init(|| tree))
+    }
+}
+
+impl MemoryUsage for Preprocessed<'_> {
+    /// Only indexes built so far are counted, the trees are borrowed
+    fn heap_bytes(&self) -> usize {
+        self.size_buckets.heap_bytes()
+            + self.traversals.get().map_or(0, |t| t.heap_bytes())
+            + self.label_sets.get().map_or(0, |t| t.heap_bytes())
+            + self.structural_sets.get().map_or(0, |t| t.heap_bytes())
+            + self.split_sets.get().map_or(0, |t| t.heap_bytes())
+            + self.sed_tree.get().map_or(0, |t| t.heap_bytes())
+            + self.qgram_profiles.get().map_or(0, |t| t.heap_bytes())
+    }
+}
@@ -0,0 +1,138 @@
+//! q-gram count lower bound of the traversal strings of the string edit distance bound.
+//!
+//! Strings padded with `q - 1` sentinels on both sides have `n + q - 1` q-grams and
+//! every edit operation changes at most `q` of them, so strings within edit distance
+//! `k` share at least `max(n1, n2) + q - 1 - k * q` q-grams (Gravano et al.). Counting
+//! shared q-grams of sorted profiles takes linear time instead of the `n * k` of the
+//! bounded SED, the bound of the preorder and postorder strings is at most their SED
+//! and so at most the tree edit distance. Profiles are built from the traversals of
+//! the SED bound. q-grams of up to 2 labels are packed exactly, longer ones are hashed,
+//! a collision only adds a shared q-gram and loosens the bound.
+use crate::indexing::{ConstantsIndex, SEDIndex};
+use crate::memory::MemoryUsage;
+use crate::parsing::LabelId;
+use rustc_hash::FxHasher;
+use std::cmp::max;
+use std::hash::{Hash, Hasher};
+
+/// Label of the sentinels padding the strings
+const PADDING: LabelId = LabelId::MAX;
+
+/// Sorted q-grams of the preorder and postorder strings of a tree
+#[derive(Debug, Clone)]
+pub struct QGramProfile {
+    pub c: ConstantsIndex,
+    q: usize,
+    preorder: Vec<u64>,
+    postorder: Vec<u64>,
+}
+
+fn gram_key(gram: &[LabelId]) -> u64 {
+    match gram {
+        [a] => a.get() as u64,
+        [a, b] => ((a.get() as u64) << 32) | b.get() as u64,
+        _ => {
+            let mut hasher = FxHasher::default();
+            gram.hash(&mut hasher);
+            hasher.finish()
+        }
+    }
+}
+
+fn profile(string: &[LabelId], q: usize) -> Vec<u64> {
+    let padding = std::iter::repeat_n(PADDING, q - 1);
+    let padded = padding
+        .clone()
+        .chain(string.iter().copied())
+        .chain(padding)
+        .collect::<Vec<_>>();
+    let mut grams = padded.windows(q).map(gram_key).collect::<Vec<_>>();
+    grams.sort_unstable();
+    grams
+}
+
+/// Number of q-grams in both sorted profiles, counted with multiplicity
+fn shared(g1: &[u64], g2: &[u64]) -> usize {
+    super::sorted_intersection(g1, g2, |gram| *gram, |_, _| 1)
+}
+
+impl QGramProfile {
+    /// Panics if `q` is 0
+    pub fn new(traversals: &SEDIndex, q: usize) -> Self {
+        assert!(q > 0, "q-grams need at least one label");
+        Self {
+            c: ConstantsIndex {
+                tree_size: traversals.c.tree_size,
+            },
+            q,
+            preorder: profile(&traversals.preorder, q),
+            postorder: profile(&traversals.postorder, q),
+        }
+    }
+
+    fn string_bound(&self, g1: &[u64], g2: &[u64]) -> usize {
+        let missing = max(g1.len(), g2.len()) - shared(g1, g2);
+        missing.div_ceil(self.q)
+    }
+}
+
+/// q-gram count bound of two profiles with the same `q`, the postorder strings are
+/// skipped once the preorder strings exceed `k`
+pub fn qgram_count_k(p1: &QGramProfile, p2: &QGramProfile, k: usize) -> usize {
+    debug_assert_eq!(p1.q, p2.q);
+    let pre_bound = p1.string_bound(&p1.preorder, &p2.preorder);
+    if pre_bound > k {
+        return pre_bound;
+    }
+    max(pre_bound, p1.string_bound(&p1.postorder, &p2.postorder))
+}
+
+impl MemoryUsage for QGramProfile {
+    fn heap_bytes(&self) -> usize {
+        self.preorder.heap_bytes() + self.postorder.heap_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::Indexer;
+    use crate::lb::sed::sed;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_qgram_count_bound() {
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a{b}{c}}",
+            "{a{b}{c}}",
+            "{a{b{c}}}",
+            "{a{b}{d}{e}}",
+            "{x{y}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let indexes = trees
+            .iter()
+            .map(|t| SEDIndex::index_tree(t, &ld).unwrap())
+            .collect::<Vec<_>>();
+        for q in 1..=3 {
+            let profiles = indexes
+                .iter()
+                .map(|i| QGramProfile::new(i, q))
+                .collect::<Vec<_>>();
+            assert_eq!(qgram_count_k(&profiles[0], &profiles[1], 10), 0);
+            for (i, j) in (0..trees.len()).flat_map(|i| (0..trees.len()).map(move |j| (i, j))) {
+                let bound = qgram_count_k(&profiles[i], &profiles[j], 10);
+                assert!(bound <= sed(&indexes[i], &indexes[j]), "q={q} {i},{j}");
+            }
+        }
+        // abc against xy, the 4 padded bigrams of abc share none with the 3 of xy
+        let profiles = indexes
+            .iter()
+            .map(|i| QGramProfile::new(i, 2))
+            .collect::<Vec<_>>();
+        assert_eq!(qgram_count_k(&profiles[0], &profiles[4], 10), 2);
+        // postorder b c a and c b a share only the bigram ending the string
+        assert_eq!(qgram_count_k(&profiles[0], &profiles[2], 10), 2);
+    }
+}
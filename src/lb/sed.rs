@@ -1,4 +1,5 @@
 use crate::indexing::SEDIndex;
+use crate::parsing::LabelId;
 
 pub fn sed(t1: &SEDIndex, t2: &SEDIndex) -> usize {
     let (mut t1, mut t2) = (t1, t2);
@@ -13,7 +14,7 @@ pub fn sed(t1: &SEDIndex, t2: &SEDIndex) -> usize {
 }
 
 /// Implements fastest known way to compute exact string edit between two strings
-fn string_edit_distance(s1: &[i32], s2: &[i32]) -> usize {
+fn string_edit_distance(s1: &[LabelId], s2: &[LabelId]) -> usize {
     use std::cmp::min;
     // assumes size of s2 is smaller or equal than s1
     let s2len = s2.len();
@@ -39,71 +40,215 @@ fn string_edit_distance(s1: &[i32], s2: &[i32]) -> usize {
 /// Computes bounded string edit distance with known maximal threshold.
 /// Returns distance at max of K. Algorithm by Hal Berghel and David Roach
 pub fn sed_k(t1: &SEDIndex, t2: &SEDIndex, k: usize) -> usize {
-    let (mut t1, mut t2) = (t1, t2);
-    // if t1.c.tree_size.abs_diff(t2.c.tree_size) > k {
-    //     return k + 1;
-    // }
+    SedEvaluator::default().sed_k(t1, t2, k)
+}
 
-    if t1.preorder.len() > t2.preorder.len() {
-        (t1, t2) = (t2, t1);
-    }
-    let k = k + 1;
-    let pre_dist = bounded_string_edit_distance(&t1.preorder, &t2.preorder, k);
+/// Buffers of the bounded string edit distance reused from pair to pair, so a query
+/// loop evaluating one query against many trees does not allocate per pair. Buffers
+/// grow to the longest strings seen.
+#[derive(Debug, Default)]
+pub struct SedEvaluator {
+    /// match vectors of the bit-parallel algorithm, by position of the first occurrence
+    /// of a symbol in the pattern
+    peq: Vec<(usize, u64)>,
+    current_row: Vec<i64>,
+    next_row: Vec<i64>,
+}
 
-    if pre_dist > k {
-        return pre_dist;
+impl SedEvaluator {
+    /// Buffers for strings of up to `max_len` tokens, e.g. of the largest tree in the
+    /// size window of the queries
+    pub fn with_capacity(max_len: usize) -> Self {
+        // rows of Berghel and Roach hold at most the longer string and a few cells more
+        Self {
+            peq: Vec::with_capacity(MYERS_MAX_LEN),
+            current_row: Vec::with_capacity(max_len + 8),
+            next_row: Vec::with_capacity(max_len + 8),
+        }
     }
 
-    let post_dist = bounded_string_edit_distance(&t1.postorder, &t2.postorder, k);
+    /// Buffers for the size windows of the queries, trees within the threshold of a
+    /// query are at most the threshold larger
+    pub fn for_queries(queries: &[(usize, SEDIndex)]) -> Self {
+        let max_len = queries
+            .iter()
+            .map(|(k, query)| query.preorder.len() + k)
+            .max()
+            .unwrap_or(0);
+        Self::with_capacity(max_len)
+    }
 
-    std::cmp::max(pre_dist, post_dist)
-}
+    /// Same as [`sed_k`]
+    pub fn sed_k(&mut self, t1: &SEDIndex, t2: &SEDIndex, k: usize) -> usize {
+        let (mut t1, mut t2) = (t1, t2);
+        if t1.preorder.len() > t2.preorder.len() {
+            (t1, t2) = (t2, t1);
+        }
+        let k = k + 1;
+        let pre_dist = self.bounded_string_edit_distance(&t1.preorder, &t2.preorder, k);
 
-pub fn bounded_string_edit_distance(s1: &[i32], s2: &[i32], k: usize) -> usize {
-    use std::cmp::{max, min};
-    // assumes size of s2 is smaller or equal than s1
-    let mut s1len = s1.len();
-    let mut s2len = s2.len();
-    // perform suffix trimming
-    for _ in s1
-        .iter()
-        .rev()
-        .zip(s2.iter().rev())
-        .take_while(|(s1c, s2c)| s1c == s2c)
-    {
-        s1len -= 1;
-        s2len -= 1;
-        if s1len == 0 {
-            break;
+        if pre_dist > k {
+            return pre_dist;
         }
+
+        let post_dist = self.bounded_string_edit_distance(&t1.postorder, &t2.postorder, k);
+
+        std::cmp::max(pre_dist, post_dist)
     }
 
-    let mut common_prefix = 0;
+    /// Same as [`bounded_string_edit_distance`]
+    pub fn bounded_string_edit_distance<T: Ord>(&mut self, s1: &[T], s2: &[T], k: usize) -> usize {
+        let (s1, s2) = trim(s1, s2, &T::eq);
+        if !s1.is_empty() && s1.len() <= MYERS_MAX_LEN {
+            return myers_bounded_string_edit_distance(s1, s2, k, &mut self.peq);
+        }
+        self.bounded_string_edit_distance_by(s1, s2, k, T::eq)
+    }
 
-    // now prefix trimming
-    for _ in s1.iter().zip(s2.iter()).take_while(|(s1c, s2c)| s1c == s2c) {
-        common_prefix += 1;
-        if common_prefix >= s1len {
-            break;
+    /// Bounded string edit distance by Berghel and Roach with a custom match of tokens,
+    /// e.g. labels that match only with similar structure. Tokens that do not match are
+    /// substituted with unit cost. Returns distance at max of K like
+    /// [`bounded_string_edit_distance`].
+    pub fn bounded_string_edit_distance_by<T>(
+        &mut self,
+        s1: &[T],
+        s2: &[T],
+        k: usize,
+        matches: impl Fn(&T, &T) -> bool,
+    ) -> usize {
+        let (s1, s2) = trim(s1, s2, &matches);
+        if s1.is_empty() {
+            return s2.len();
         }
+        berghel_roach(
+            s1,
+            s2,
+            k,
+            matches,
+            &mut self.current_row,
+            &mut self.next_row,
+        )
     }
+}
 
-    if s1len == 0 {
-        return s2len;
+/// Strings up to this length fit into a machine word and use the bit-parallel algorithm
+const MYERS_MAX_LEN: usize = u64::BITS as usize;
+
+/// Bit-parallel edit distance by Myers in the formulation of Hyyrö, returns distance at max of K.
+/// The shorter string must fit into a machine word, see [`MYERS_MAX_LEN`].
+fn myers_bounded_string_edit_distance<T: Ord>(
+    s1: &[T],
+    s2: &[T],
+    k: usize,
+    peq: &mut Vec<(usize, u64)>,
+) -> usize {
+    let (pattern, text) = if s1.len() <= s2.len() {
+        (s1, s2)
+    } else {
+        (s2, s1)
+    };
+    let m = pattern.len();
+    debug_assert!(m <= MYERS_MAX_LEN);
+    if m == 0 {
+        return text.len().min(k);
     }
 
-    // prefix trimming done
-    let s1 = &s1[common_prefix..s1len];
-    let s2 = &s2[common_prefix..s2len];
+    // match vectors of pattern symbols, sorted by symbol for binary search
+    peq.clear();
+    for (i, c) in pattern.iter().enumerate() {
+        match peq.binary_search_by(|(sym, _)| pattern[*sym].cmp(c)) {
+            Ok(pos) => peq[pos].1 |= 1 << i,
+            Err(pos) => peq.insert(pos, (i, 1 << i)),
+        }
+    }
 
-    s1len -= common_prefix;
-    s2len -= common_prefix;
-    // one string is gone by suffix and prefix trimming, so just return the remaining size
-    if s1len == 0 {
-        return s2len;
+    let last = 1u64 << (m - 1);
+    let mut pv = u64::MAX >> (MYERS_MAX_LEN - m);
+    let mut mv = 0u64;
+    let mut score = m;
+    let n = text.len();
+
+    for (j, c) in text.iter().enumerate() {
+        let eq = peq
+            .binary_search_by(|(sym, _)| pattern[*sym].cmp(c))
+            .map_or(0, |pos| peq[pos].1);
+        let xv = eq | mv;
+        let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+        let mut ph = mv | !(xh | pv);
+        let mut mh = pv & xh;
+        if ph & last != 0 {
+            score += 1;
+        } else if mh & last != 0 {
+            score -= 1;
+        }
+        // first row of the global distance matrix grows by one in each column
+        ph = (ph << 1) | 1;
+        mh <<= 1;
+        pv = mh | !(xv | ph);
+        mv = ph & xv;
+
+        // the score can decrease by at most one per remaining column
+        if score > k + (n - j - 1) {
+            return k;
+        }
     }
-    let s1len = s1len as i64;
-    let s2len = s2len as i64;
+
+    score.min(k)
+}
+
+/// Strips the common suffix and prefix, the shorter string comes first
+fn trim<'a, T>(s1: &'a [T], s2: &'a [T], matches: &impl Fn(&T, &T) -> bool) -> (&'a [T], &'a [T]) {
+    let (s1, s2) = if s1.len() <= s2.len() {
+        (s1, s2)
+    } else {
+        (s2, s1)
+    };
+    let suffix = s1
+        .iter()
+        .rev()
+        .zip(s2.iter().rev())
+        .take_while(|(c1, c2)| matches(c1, c2))
+        .count();
+    let (s1, s2) = (&s1[..s1.len() - suffix], &s2[..s2.len() - suffix]);
+    let prefix = s1
+        .iter()
+        .zip(s2.iter())
+        .take_while(|(c1, c2)| matches(c1, c2))
+        .count();
+    (&s1[prefix..], &s2[prefix..])
+}
+
+/// Bounded string edit distance of any token sequences, returns distance at max of K.
+/// Short sequences use the bit-parallel algorithm of Myers, long ones the algorithm
+/// by Berghel and Roach. When one sequence is consumed by its common prefix and suffix
+/// the length of the rest is returned, even above K.
+///
+/// ```
+/// use tree_statistics::bounded_string_edit_distance;
+///
+/// let (preorder, other) = (["article", "title", "Trees"], ["article", "title", "Forests"]);
+/// assert_eq!(bounded_string_edit_distance(&preorder, &other, 2), 1);
+/// // distances above the threshold are cut at it
+/// assert_eq!(bounded_string_edit_distance(b"kitten", b"sitting", 3), 3);
+/// assert_eq!(bounded_string_edit_distance(b"kitten", b"sitting", 1), 1);
+/// ```
+pub fn bounded_string_edit_distance<T: Ord>(s1: &[T], s2: &[T], k: usize) -> usize {
+    SedEvaluator::default().bounded_string_edit_distance(s1, s2, k)
+}
+
+/// Algorithm by Berghel and Roach on trimmed strings, `s1` is the shorter one. The rows
+/// are resized and overwritten.
+fn berghel_roach<T>(
+    s1: &[T],
+    s2: &[T],
+    k: usize,
+    matches: impl Fn(&T, &T) -> bool,
+    current_row: &mut Vec<i64>,
+    next_row: &mut Vec<i64>,
+) -> usize {
+    use std::cmp::{max, min};
+    let s1len = s1.len() as i64;
+    let s2len = s2.len() as i64;
 
     let threshold = min(s2len, k as i64);
     let size_diff = s2len - s1len;
@@ -116,15 +261,17 @@ pub fn bounded_string_edit_distance(s1: &[i32], s2: &[i32], k: usize) -> usize {
 
     let arr_len = size_diff + (zero_k) * 2 + 2;
 
-    let mut current_row = vec![-1i64; arr_len as usize];
-    let mut next_row = vec![-1i64; arr_len as usize];
+    current_row.clear();
+    current_row.resize(arr_len as usize, -1);
+    next_row.clear();
+    next_row.resize(arr_len as usize, -1);
     let mut i = 0;
     let condition_row = size_diff + zero_k;
     let end_max = condition_row << 1;
 
     loop {
         i += 1;
-        std::mem::swap(&mut next_row, &mut current_row);
+        std::mem::swap(next_row, current_row);
 
         let start: i64;
         let mut next_cell: i64;
@@ -168,7 +315,10 @@ pub fn bounded_string_edit_distance(s1: &[i32], s2: &[i32], k: usize) -> usize {
             unsafe {
                 while t < s1len
                     && (t + q) < s2len
-                    && s1.get_unchecked(t as usize) == s2.get_unchecked((t + q) as usize)
+                    && matches(
+                        s1.get_unchecked(t as usize),
+                        s2.get_unchecked((t + q) as usize),
+                    )
                 {
                     t += 1;
                 }
@@ -192,10 +342,14 @@ pub fn bounded_string_edit_distance(s1: &[i32], s2: &[i32], k: usize) -> usize {
 mod tests {
     use super::*;
 
+    fn labels(ids: &[u32]) -> Vec<LabelId> {
+        ids.iter().map(|id| LabelId::new(*id).unwrap()).collect()
+    }
+
     #[test]
     fn test_sed() {
-        let v1 = vec![1, 2, 3, 4, 5, 5, 6];
-        let v2 = vec![1, 2, 3, 5, 6, 7, 6];
+        let v1 = labels(&[1, 2, 3, 4, 5, 5, 6]);
+        let v2 = labels(&[1, 2, 3, 5, 6, 7, 6]);
 
         let result = string_edit_distance(&v1, &v2);
         assert_eq!(result, 3);
@@ -203,8 +357,8 @@ mod tests {
 
     #[test]
     fn test_sed_k() {
-        let v1 = vec![1, 2, 3, 4, 5, 5, 6];
-        let v2 = vec![1, 2, 3, 5, 6, 7, 6];
+        let v1 = labels(&[1, 2, 3, 4, 5, 5, 6]);
+        let v2 = labels(&[1, 2, 3, 5, 6, 7, 6]);
 
         let result = bounded_string_edit_distance(&v1, &v2, 2);
         assert_eq!(result, 2);
@@ -212,4 +366,82 @@ mod tests {
         let result = bounded_string_edit_distance(&v1, &v2, 4);
         assert_eq!(result, 3);
     }
+
+    #[test]
+    fn test_myers_matches_exact_distance() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(11);
+        for len in [1, 5, 30, 63, 64, 65, 100] {
+            for _ in 0..20 {
+                let s1: Vec<LabelId> = (0..len)
+                    .map(|_| LabelId::from_index(rng.gen_range(0..4)))
+                    .collect();
+                let s2: Vec<LabelId> = (0..rng.gen_range(len..len + 10))
+                    .map(|_| LabelId::from_index(rng.gen_range(0..4)))
+                    .collect();
+                let exact = string_edit_distance(&s2, &s1);
+                for k in [1, 3, 10, 200] {
+                    // fully trimmed strings return the remaining length even above k
+                    let bounded = bounded_string_edit_distance(&s1, &s2, k);
+                    assert_eq!(bounded.min(k), exact.min(k));
+                    if len <= MYERS_MAX_LEN {
+                        assert_eq!(
+                            myers_bounded_string_edit_distance(&s1, &s2, k, &mut vec![]),
+                            exact.min(k)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_evaluator_reuses_buffers() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(5);
+        let mut evaluator = SedEvaluator::with_capacity(10);
+        // long and short pairs alternate, buffers of longer strings are reused by shorter
+        for len in [200, 3, 90, 40, 150, 70, 2] {
+            let s1: Vec<LabelId> = (0..len)
+                .map(|_| LabelId::from_index(rng.gen_range(0..3)))
+                .collect();
+            let s2: Vec<LabelId> = (0..len + rng.gen_range(0..5))
+                .map(|_| LabelId::from_index(rng.gen_range(0..3)))
+                .collect();
+            let exact = string_edit_distance(&s2, &s1);
+            for k in [2, 20, 300] {
+                let distance = evaluator.bounded_string_edit_distance(&s1, &s2, k);
+                assert_eq!(distance.min(k), exact.min(k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_generic_tokens() {
+        let kitten = "kitten".chars().collect::<Vec<_>>();
+        let sitting = "sitting".chars().collect::<Vec<_>>();
+        assert_eq!(bounded_string_edit_distance(&kitten, &sitting, 10), 3);
+        assert_eq!(bounded_string_edit_distance(&sitting, &kitten, 2), 2);
+
+        // long sequences go through Berghel and Roach with both matches
+        let words = |n: usize, changed: usize| {
+            (0..n)
+                .map(|i| if i % changed == 0 { "x" } else { "word" })
+                .collect::<Vec<_>>()
+        };
+        let (w1, w2) = (words(100, 1000), words(100, 25));
+        assert_eq!(bounded_string_edit_distance(&w1, &w2, 10), 3);
+        let ignore_case = |a: &&str, b: &&str| a.eq_ignore_ascii_case(b);
+        let upper = w2.iter().map(|w| w.to_uppercase()).collect::<Vec<_>>();
+        let upper = upper.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut evaluator = SedEvaluator::default();
+        assert_eq!(
+            evaluator.bounded_string_edit_distance_by(&w1, &upper, 10, ignore_case),
+            3
+        );
+        assert_eq!(
+            evaluator.bounded_string_edit_distance_by(&w1, &upper, 2, ignore_case),
+            2
+        );
+    }
 }
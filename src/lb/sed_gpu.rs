@@ -0,0 +1,248 @@
+//! GPU evaluation of the string edit distance bound through wgpu.
+//!
+//! Traversals of all trees and queries are uploaded once, then (query, tree) pairs
+//! within the size window are evaluated in batches by a banded edit distance kernel.
+//! Pairs with thresholds too large for the kernel band are evaluated on the CPU.
+use super::sed::sed_k;
+use super::size_buckets::SizeBuckets;
+use crate::indexing::SEDIndex;
+use bytemuck::{Pod, Zeroable};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use wgpu::util::DeviceExt;
+
+/// Largest threshold whose band of diagonals fits into the kernel rows
+const MAX_GPU_K: usize = 31;
+/// Pairs per dispatch, keeps workgroup count under the 65535 dispatch limit
+const BATCH_PAIRS: usize = 4_000_000;
+const WORKGROUP_SIZE: usize = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct GpuTraversals {
+    preorder: u32,
+    postorder: u32,
+    len: u32,
+    _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+struct GpuPair {
+    query: u32,
+    tree: u32,
+    k: u32,
+    _pad: u32,
+}
+
+pub struct GpuSed {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    max_binding_size: u64,
+}
+
+impl GpuSed {
+    /// Returns the shared GPU context, or `None` when no adapter is available
+    pub fn get() -> Option<&'static GpuSed> {
+        static GPU: OnceLock<Option<GpuSed>> = OnceLock::new();
+        GPU.get_or_init(|| pollster::block_on(Self::new())).as_ref()
+    }
+
+    async fn new() -> Option<Self> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::from_env_or_default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await
+            .ok()?;
+        let limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: Some("sed"),
+                required_limits: limits.clone(),
+                ..Default::default()
+            })
+            .await
+            .ok()?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("sed"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("sed_gpu.wgsl").into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("sed"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        Some(Self {
+            device,
+            queue,
+            pipeline,
+            max_binding_size: limits.max_storage_buffer_binding_size as u64,
+        })
+    }
+
+    /// Evaluates the bound for all queries against trees within their size window,
+    /// returns sorted candidates and the time it took. Returns `None` when the
+    /// traversals do not fit into a single GPU buffer.
+    pub fn candidates(
+        &self,
+        queries: &[(usize, SEDIndex)],
+        trees: &[SEDIndex],
+        size_buckets: &SizeBuckets,
+    ) -> Option<(Vec<(usize, usize)>, Duration)> {
+        let start = Instant::now();
+        let mut labels: Vec<u32> = vec![];
+        // queries first, tree `tid` is at `queries.len() + tid`
+        let tree_base = queries.len();
+        let traversals = queries
+            .iter()
+            .map(|(_, q)| q)
+            .chain(trees.iter())
+            .map(|index| {
+                let offset = labels.len() as u32;
+                labels.extend(index.preorder.iter().map(|l| l.get()));
+                labels.extend(index.postorder.iter().map(|l| l.get()));
+                GpuTraversals {
+                    preorder: offset,
+                    postorder: offset + index.preorder.len() as u32,
+                    len: index.preorder.len() as u32,
+                    _pad: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+        if std::mem::size_of_val(labels.as_slice()) as u64 > self.max_binding_size {
+            return None;
+        }
+        let labels_buf = self.storage_buffer("labels", bytemuck::cast_slice(&labels));
+        let traversals_buf = self.storage_buffer("traversals", bytemuck::cast_slice(&traversals));
+
+        let mut candidates = vec![];
+        let mut batch = Vec::with_capacity(BATCH_PAIRS.min(1 << 20));
+        for (qid, (k, query)) in queries.iter().enumerate() {
+            let size_range = size_buckets.within(query.c.tree_size, *k);
+            if *k > MAX_GPU_K {
+                candidates.extend(
+                    size_range
+                        .filter(|tid| sed_k(query, &trees[*tid], *k) <= *k)
+                        .map(|tid| (qid, tid)),
+                );
+                continue;
+            }
+            for tid in size_range {
+                batch.push(GpuPair {
+                    query: qid as u32,
+                    tree: (tree_base + tid) as u32,
+                    k: *k as u32,
+                    _pad: 0,
+                });
+                if batch.len() == BATCH_PAIRS {
+                    self.run_batch(
+                        &batch,
+                        tree_base,
+                        &labels_buf,
+                        &traversals_buf,
+                        &mut candidates,
+                    );
+                    batch.clear();
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.run_batch(
+                &batch,
+                tree_base,
+                &labels_buf,
+                &traversals_buf,
+                &mut candidates,
+            );
+        }
+        candidates.sort_unstable();
+        Some((candidates, start.elapsed()))
+    }
+
+    fn storage_buffer(&self, label: &str, contents: &[u8]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+
+    fn run_batch(
+        &self,
+        pairs: &[GpuPair],
+        tree_base: usize,
+        labels: &wgpu::Buffer,
+        traversals: &wgpu::Buffer,
+        candidates: &mut Vec<(usize, usize)>,
+    ) {
+        let pairs_buf = self.storage_buffer("pairs", bytemuck::cast_slice(pairs));
+        let result_size = std::mem::size_of_val(pairs) as u64 / 4;
+        let result_buf = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("result"),
+            size: result_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buf = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: result_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("sed"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[labels, traversals, &pairs_buf, &result_buf]
+                .iter()
+                .enumerate()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("sed") });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("sed"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(pairs.len().div_ceil(WORKGROUP_SIZE) as u32, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&result_buf, 0, &readback_buf, 0, result_size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = readback_buf.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |r| {
+            r.expect("Failed mapping GPU results")
+        });
+        self.device
+            .poll(wgpu::PollType::Wait)
+            .expect("Failed waiting for GPU results");
+        {
+            let mapped = slice.get_mapped_range();
+            let results: &[u32] = bytemuck::cast_slice(&mapped);
+            candidates.extend(
+                pairs
+                    .iter()
+                    .zip(results)
+                    .filter(|(_, candidate)| **candidate != 0)
+                    .map(|(pair, _)| (pair.query as usize, pair.tree as usize - tree_base)),
+            );
+        }
+        readback_buf.unmap();
+    }
+}
@@ -0,0 +1,85 @@
+// Bounded string edit distance of preorder and postorder traversals for a batch
+// of (query, tree) pairs. A pair is a candidate when both distances are at most k.
+
+// band of diagonals -k..=k has to fit into the private rows
+const MAX_BAND: u32 = 64u;
+
+struct Traversals {
+    preorder: u32,
+    postorder: u32,
+    len: u32,
+    _pad: u32,
+}
+
+struct Pair {
+    query: u32,
+    tree: u32,
+    k: u32,
+    _pad: u32,
+}
+
+@group(0) @binding(0) var<storage, read> labels: array<u32>;
+@group(0) @binding(1) var<storage, read> traversals: array<Traversals>;
+@group(0) @binding(2) var<storage, read> pairs: array<Pair>;
+@group(0) @binding(3) var<storage, read_write> result: array<u32>;
+
+// Ukkonen banded dynamic programming, row index d is the diagonal j - i + k
+fn within_k(a: u32, n: u32, b: u32, m: u32, k: u32) -> bool {
+    if max(n, m) - min(n, m) > k {
+        return false;
+    }
+    let inf = k + 1u;
+    let width = 2u * k + 1u;
+    var prev: array<u32, MAX_BAND>;
+    var cur: array<u32, MAX_BAND>;
+    for (var d = 0u; d < width; d++) {
+        if d >= k && d - k <= m {
+            cur[d] = d - k;
+        } else {
+            cur[d] = inf;
+        }
+    }
+    for (var i = 1u; i <= n; i++) {
+        prev = cur;
+        var row_min = inf;
+        for (var d = 0u; d < width; d++) {
+            let j = i32(i + d) - i32(k);
+            var v = inf;
+            if j == 0 {
+                v = min(i, inf);
+            } else if j > 0 && j <= i32(m) {
+                let same = labels[a + i - 1u] == labels[b + u32(j) - 1u];
+                v = prev[d] + select(1u, 0u, same);
+                if d + 1u < width {
+                    v = min(v, prev[d + 1u] + 1u);
+                }
+                if d > 0u {
+                    v = min(v, cur[d - 1u] + 1u);
+                }
+                v = min(v, inf);
+            }
+            cur[d] = v;
+            row_min = min(row_min, v);
+        }
+        if row_min > k {
+            return false;
+        }
+    }
+    return cur[m + k - n] <= k;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+    let idx = gid.x;
+    if idx >= arrayLength(&pairs) {
+        return;
+    }
+    let p = pairs[idx];
+    let q = traversals[p.query];
+    let t = traversals[p.tree];
+    var candidate = within_k(q.preorder, q.len, t.preorder, t.len, p.k);
+    if candidate {
+        candidate = within_k(q.postorder, q.len, t.postorder, t.len, p.k);
+    }
+    result[idx] = select(0u, 1u, candidate);
+}
@@ -0,0 +1,185 @@
+//! Storage of items grouped by tree size, so candidate loops only visit
+//! items whose tree size is within the threshold of the query size.
+use crate::memory::MemoryUsage;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Size lower bound of the tree edit distance of trees with `query` and `tree` nodes,
+/// every node of the size difference has to be inserted or deleted. Like the other
+/// bounded bounds a difference above `k` is returned as `k + 1`. Filters prune pairs
+/// with it before any per label work and [`SizeBuckets::within`] selects exactly the
+/// sizes it keeps.
+#[inline(always)]
+pub fn size_lb(query: usize, tree: usize, k: usize) -> usize {
+    query.abs_diff(tree).min(k + 1)
+}
+
+/// Tree sizes of a sequence of items sorted by tree size.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(into = "Vec<usize>", try_from = "Vec<usize>")]
+pub struct SizeBuckets {
+    sizes: Vec<usize>,
+}
+
+impl SizeBuckets {
+    /// Asserts sizes are sorted, as trees are sorted by size after parsing
+    pub fn from_sizes(sizes: impl IntoIterator<Item = usize>) -> Self {
+        let sizes = sizes.into_iter().collect::<Vec<_>>();
+        assert!(sizes.is_sorted(), "Items have to be sorted by tree size!");
+        Self { sizes }
+    }
+
+    /// Appends an item of given size, sizes have to be pushed in non decreasing order
+    pub fn push(&mut self, size: usize) {
+        debug_assert!(self.sizes.last().is_none_or(|last| *last <= size));
+        self.sizes.push(size);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    pub fn size(&self, idx: usize) -> usize {
+        self.sizes[idx]
+    }
+
+    /// Range of items with size between `min_size` and `max_size` inclusive
+    pub fn range(&self, min_size: usize, max_size: usize) -> Range<usize> {
+        let start = self.sizes.partition_point(|s| *s < min_size);
+        let end = start + self.sizes[start..].partition_point(|s| *s <= max_size);
+        start..end
+    }
+
+    /// Range of items whose size differs from `size` by at most `k`, the items kept by
+    /// [`size_lb`]
+    pub fn within(&self, size: usize, k: usize) -> Range<usize> {
+        self.range(size.saturating_sub(k), size + k)
+    }
+}
+
+impl From<SizeBuckets> for Vec<usize> {
+    fn from(buckets: SizeBuckets) -> Self {
+        buckets.sizes
+    }
+}
+
+/// Deserialized sizes have to be sorted as well
+impl TryFrom<Vec<usize>> for SizeBuckets {
+    type Error = &'static str;
+
+    fn try_from(sizes: Vec<usize>) -> Result<Self, Self::Error> {
+        if !sizes.is_sorted() {
+            return Err("Items have to be sorted by tree size!");
+        }
+        Ok(Self { sizes })
+    }
+}
+
+/// Items, such as index postings, stored in size buckets of their trees.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeBucketed<T> {
+    items: Vec<T>,
+    buckets: SizeBuckets,
+}
+
+impl<T> Default for SizeBucketed<T> {
+    fn default() -> Self {
+        Self {
+            items: vec![],
+            buckets: SizeBuckets::default(),
+        }
+    }
+}
+
+impl<T> SizeBucketed<T> {
+    /// Appends an item of tree with given size, sizes have to be pushed in non decreasing order
+    pub fn push(&mut self, size: usize, item: T) {
+        self.buckets.push(size);
+        self.items.push(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Items together with the sizes of their trees, in non decreasing order of sizes
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.buckets.sizes.iter().copied().zip(&self.items)
+    }
+
+    /// Items whose tree size differs from `size` by at most `k`, together with the tree size
+    pub fn within(&self, size: usize, k: usize) -> impl Iterator<Item = (usize, &T)> {
+        let range = self.buckets.within(size, k);
+        self.buckets.sizes[range.clone()]
+            .iter()
+            .copied()
+            .zip(&self.items[range])
+    }
+}
+
+impl MemoryUsage for SizeBuckets {
+    fn heap_bytes(&self) -> usize {
+        self.sizes.heap_bytes()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for SizeBucketed<T> {
+    fn heap_bytes(&self) -> usize {
+        self.items.heap_bytes() + self.buckets.heap_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    #[test]
+    fn test_size_ranges() {
+        let buckets = SizeBuckets::from_sizes([1, 2, 2, 4, 7, 7, 8]);
+        assert_eq!(buckets.within(4, 0), 3..4);
+        assert_eq!(buckets.within(3, 1), 1..4);
+        assert_eq!(buckets.within(5, 1), 3..4);
+        assert_eq!(buckets.within(5, 0), 4..4);
+        assert_eq!(buckets.within(2, 10), 0..7);
+        assert_eq!(buckets.range(0, 0), 0..0);
+
+        let mut postings = SizeBucketed::default();
+        for (tid, size) in [2, 3, 3, 6].into_iter().enumerate() {
+            postings.push(size, tid);
+        }
+        let found = postings.within(4, 1).collect::<Vec<_>>();
+        assert_eq!(found, vec![(3, &1), (3, &2)]);
+    }
+
+    #[test]
+    fn test_size_lb() {
+        assert_eq!(size_lb(3, 5, 4), 2);
+        assert_eq!(size_lb(5, 3, 4), 2);
+        assert_eq!(size_lb(0, 9, 4), 5);
+        assert_eq!(size_lb(7, 7, 0), 0);
+        let sizes = [0, 1, 1, 2, 4, 7, 7, 8, 12];
+        let buckets = SizeBuckets::from_sizes(sizes);
+        for size in 0..14 {
+            for k in 0..6 {
+                let kept = sizes
+                    .iter()
+                    .positions(|s| size_lb(size, *s, k) <= k)
+                    .collect::<Vec<_>>();
+                assert_eq!(kept, buckets.within(size, k).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn test_deserialize_checks_order() {
+        let buckets: SizeBuckets = serde_json::from_str("[1,2,2,5]").unwrap();
+        assert_eq!(buckets.within(2, 0), 1..3);
+        assert_eq!(serde_json::to_string(&buckets).unwrap(), "[1,2,2,5]");
+        assert!(serde_json::from_str::<SizeBuckets>("[3,1]").is_err());
+    }
+}
@@ -1,13 +1,32 @@
-use crate::parsing::{LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
+use crate::collection_fingerprint::CollectionFingerprint;
+use crate::indexing::{
+    node_counter, root_id, ConstantsIndex, IndexError, Indexer, NodeCounter, SEDIndex,
+};
+use crate::lb::label_postings::LabelPostings;
+use crate::lb::size_buckets::{size_lb, SizeBucketed, SizeBuckets};
+use crate::memory::MemoryUsage;
+use crate::parsing::{label_names, LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
 use indextree::NodeId;
 use itertools::Itertools;
 use rustc_hash::{FxHashMap, FxHashSet};
-use std::{cmp::max, num::NonZeroUsize};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::str::FromStr;
+use std::{cmp::max, mem::size_of};
+use thiserror::Error;
+
+#[cfg(all(
+    feature = "simd",
+    not(feature = "wide-counters"),
+    target_arch = "x86_64"
+))]
+mod simd;
 
 type StructHashMap = FxHashMap<LabelId, LabelSetElement>;
 type SplitStructHashMap = FxHashMap<LabelId, SplitLabelSetElement>;
 
-type RegionNumType = i32;
+type RegionNumType = NodeCounter;
 
 const REGION_LEFT_IDX: usize = 0;
 /// ancestors
@@ -17,11 +36,62 @@ const REGION_RIGHT_IDX: usize = 2;
 /// descendants
 const REGION_DESC_IDX: usize = 3;
 
+/// Weights of the regions in the L1 distance of structural vectors and of the height
+/// of the subtree of a node as an extra region. Region values are multiplied by their
+/// weight when trees are converted, so distance loops stay the same. Weights of 0 or 1
+/// of the four regions keep the bound valid, 0 drops a region and loosens it. Larger
+/// weights and the height tighten the bound, but may prune pairs within the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionWeights {
+    /// Nodes to the left, ancestors, nodes to the right and descendants
+    pub regions: [RegionNumType; 4],
+    /// Height of the subtree of the node, 0 leaves the height out
+    pub height: RegionNumType,
+}
+
+impl Default for RegionWeights {
+    fn default() -> Self {
+        Self {
+            regions: [1; 4],
+            height: 0,
+        }
+    }
+}
+
+impl FromStr for RegionWeights {
+    type Err = String;
+
+    /// Parses `left,ancestors,right,descendants` with an optional `,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|w| {
+                w.trim()
+                    .parse::<RegionNumType>()
+                    .ok()
+                    .filter(|w| *w >= 0)
+                    .ok_or_else(|| format!("Invalid region weight `{w}`"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match weights[..] {
+            [left, ancestors, right, descendants] => Ok(Self {
+                regions: [left, ancestors, right, descendants],
+                height: 0,
+            }),
+            [left, ancestors, right, descendants, height] => Ok(Self {
+                regions: [left, ancestors, right, descendants],
+                height,
+            }),
+            _ => Err(format!("Expected 4 or 5 region weights, got `{s}`")),
+        }
+    }
+}
+
 /// The building block for structural filter, holds information about
 /// the count of ancestral nodes, descendants nodes, to the left and to the right
 // difference between children and descendants? Children nodes are only 1 level below current node level
 // while descendants are all nodes below the current node
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 #[repr(align(16))]
 pub struct StructuralVec {
     label_id: LabelId,
@@ -31,7 +101,7 @@ pub struct StructuralVec {
     pub mapping_regions: [RegionNumType; 4],
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 #[repr(align(16))]
 pub struct SplitStructuralVec {
     svec: StructuralVec,
@@ -39,65 +109,176 @@ pub struct SplitStructuralVec {
 }
 
 /// This is an element holding relevant data of a set.
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LabelSetElementBase {
     pub id: LabelId,
     pub weight: usize,
     pub weigh_so_far: usize,
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
+/// Structural vectors of all nodes with the same label, with postorder ids and
+/// region vectors in parallel arrays. Region vectors are scanned densely in the
+/// distance loops and postorder ids only to find the postorder window.
+/// Nodes are kept sorted by postorder id, so postorder windows can be binary searched.
+/// A region vector stays a row of four regions, one array per region scans slower, see
+/// the `region-rows` and `region-columns` kernels of `bench --kernels`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructuralVecs {
+    pub postorder_ids: Vec<RegionNumType>,
+    pub regions: Vec<[RegionNumType; 4]>,
+    /// Weighted subtree heights, empty unless the height weight is set
+    pub heights: Vec<RegionNumType>,
+}
+
+impl StructuralVecs {
+    /// Appends a node, nodes have to be pushed in postorder
+    pub fn push(&mut self, svec: StructuralVec) {
+        debug_assert!(self
+            .postorder_ids
+            .last()
+            .is_none_or(|last| (*last as usize) < svec.postorder_id));
+        self.postorder_ids.push(svec.postorder_id as RegionNumType);
+        self.regions.push(svec.mapping_regions);
+    }
+
+    /// L1 distance of region vectors and heights of node `n1` and node `n2` of `other`
+    #[inline(always)]
+    fn distance(&self, n1: usize, other: &Self, n2: usize) -> RegionNumType {
+        let height = match (self.heights.get(n1), other.heights.get(n2)) {
+            (Some(h1), Some(h2)) => (h1 - h2).abs(),
+            _ => 0,
+        };
+        svec_l1_strict(&self.regions[n1], &other.regions[n2]) + height
+    }
+
+    /// Returns true if any node of `other` in `window` is within distance `k` of node `n1`
+    #[inline(always)]
+    fn any_within_k(
+        &self,
+        n1: usize,
+        other: &Self,
+        window: Range<usize>,
+        k: RegionNumType,
+    ) -> bool {
+        if self.heights.is_empty() {
+            return any_within_k(&self.regions[n1], &other.regions[window], k);
+        }
+        window
+            .into_iter()
+            .any(|n2| self.distance(n1, other, n2) <= k)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LabelSetElement {
     pub base: LabelSetElementBase,
-    pub struct_vec: Vec<StructuralVec>,
+    pub struct_vec: StructuralVecs,
+}
+
+impl MemoryUsage for LabelSetElement {
+    fn heap_bytes(&self) -> usize {
+        self.struct_vec.postorder_ids.heap_bytes()
+            + self.struct_vec.regions.capacity() * size_of::<[RegionNumType; 4]>()
+            + self.struct_vec.heights.heap_bytes()
+    }
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SplitLabelSetElement {
     pub base: LabelSetElementBase,
     pub struct_vec: Vec<SplitStructuralVec>,
 }
 
 /// Base struct tuple for structural filter
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StructuralFilterTuple(usize, StructHashMap);
 
 impl StructuralFilterTuple {
     pub fn get_prefix(&self, ordering: &LabelFreqOrdering, k: usize) -> Vec<&LabelSetElement> {
         self.1
             .iter()
-            .sorted_by_key(|(&label, _)| {
-                if label as usize >= ordering.len() {
-                    return usize::MAX;
-                }
-                *ordering
-                    .get(NonZeroUsize::new(label as usize).unwrap())
-                    .unwrap()
-            })
+            .sorted_by_key(|(&label, _)| ordering.get(label).copied().unwrap_or(usize::MAX))
             .map(|(_, set_element)| set_element)
             .take(k + 1)
             .collect_vec()
     }
 
+    /// Label sets of all labels of the tree in no particular order
+    pub fn label_sets(&self) -> impl Iterator<Item = &LabelSetElement> {
+        self.1.values()
+    }
+
+    /// Preorder and postorder traversals of the tree, the labels of the sets without their
+    /// region counts. The preorder id of a node is the number of nodes to its left and of
+    /// its ancestors, so it is only recovered if neither region has the weight 0.
+    pub fn traversals(&self, weights: &RegionWeights) -> Option<SEDIndex> {
+        let [left, ancestors, ..] = weights.regions;
+        if left == 0 || ancestors == 0 {
+            return None;
+        }
+        let (mut preorder, mut postorder) =
+            (vec![LabelId::MIN; self.0], vec![LabelId::MIN; self.0]);
+        for set in self.1.values() {
+            let nodes = &set.struct_vec;
+            for (postorder_id, regions) in nodes.postorder_ids.iter().zip(&nodes.regions) {
+                // postorder ids count from 1
+                postorder[*postorder_id as usize - 1] = set.base.id;
+                preorder[(regions[0] / left + regions[1] / ancestors) as usize] = set.base.id;
+            }
+        }
+        Some(SEDIndex {
+            preorder,
+            postorder,
+            c: ConstantsIndex { tree_size: self.0 },
+        })
+    }
+
     pub fn get_sorted_nodes(&self, ordering: &LabelFreqOrdering) -> Vec<&LabelSetElement> {
         self.1
             .iter()
-            .sorted_by_key(|(&label, _)| {
-                if label as usize >= ordering.len() {
-                    return usize::MAX;
-                }
-                *ordering
-                    .get(NonZeroUsize::new(label as usize).unwrap())
-                    .unwrap()
-            })
+            .sorted_by_key(|(&label, _)| ordering.get(label).copied().unwrap_or(usize::MAX))
             .map(|(_, set_element)| set_element)
             .collect_vec()
     }
 }
 
-#[derive(Clone, Debug)]
+impl MemoryUsage for StructuralFilterTuple {
+    fn heap_bytes(&self) -> usize {
+        self.1.heap_bytes()
+    }
+}
+
+impl Indexer for StructuralFilterTuple {
+    fn index_tree(tree: &ParsedTree, _label_dict: &LabelDict) -> Result<Self, IndexError> {
+        LabelSetConverter::default().create_single(tree)
+    }
+
+    /// Trees are converted one by one reusing the state of a single converter
+    fn index_collection(
+        trees: &[ParsedTree],
+        _label_dict: &LabelDict,
+    ) -> Result<Vec<Self>, IndexError> {
+        LabelSetConverter::default().create(trees)
+    }
+}
+
+/// Label sets of the split structural filter, region counts of every node are split
+/// by the label group of the counted nodes, see [`best_split_distribution`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SplitStructuralFilterTuple(usize, SplitStructHashMap);
 
+impl MemoryUsage for SplitLabelSetElement {
+    fn heap_bytes(&self) -> usize {
+        self.struct_vec.capacity() * size_of::<SplitStructuralVec>()
+    }
+}
+
+impl MemoryUsage for SplitStructuralFilterTuple {
+    fn heap_bytes(&self) -> usize {
+        self.1.heap_bytes()
+    }
+}
+
 /// Takes a collection of trees and converts them into a collection of label
 /// sets. A label set consists of labels and each label holds all nodes with that
 /// label. The labels are substituted with their inverted label frequency number.
@@ -108,16 +289,40 @@ pub struct LabelSetConverter {
     actual_depth: [RegionNumType; Self::MAX_SPLIT],
     actual_pre_order_number: [RegionNumType; Self::MAX_SPLIT],
     tree_size_by_split_id: [RegionNumType; Self::MAX_SPLIT],
+    weights: RegionWeights,
 }
 
 impl LabelSetConverter {
     pub const MAX_SPLIT: usize = 4;
 
+    /// Converter of label sets with weighted regions, trees compared with each other
+    /// have to be converted with the same weights
+    pub fn with_weights(weights: RegionWeights) -> Self {
+        Self {
+            weights,
+            ..Self::default()
+        }
+    }
+
+    /// Size of a tree as a region counter, fails if the tree or its largest weighted
+    /// region does not fit into the counters
+    fn region_size(&self, tree: &ParsedTree) -> Result<RegionNumType, IndexError> {
+        let size = node_counter(tree.count())?;
+        let max_weight = self
+            .weights
+            .regions
+            .into_iter()
+            .fold(self.weights.height, max);
+        size.checked_mul(max_weight.max(1))
+            .ok_or(IndexError::TreeTooLarge(tree.count()))?;
+        Ok(size)
+    }
+
     pub fn create_split<F>(
         &mut self,
         trees: &[ParsedTree],
         mut split: F,
-    ) -> Vec<SplitStructuralFilterTuple>
+    ) -> Result<Vec<SplitStructuralFilterTuple>, IndexError>
     where
         F: FnMut(&LabelId) -> usize,
     {
@@ -129,15 +334,13 @@ impl LabelSetConverter {
             // is it a hash map of Label -> Vec<StructVec>
             let mut record_labels = SplitStructHashMap::default();
 
-            let Some(root) = tree.iter().next() else {
-                panic!("tree is empty");
-            };
-            let root_id = tree.get_node_id(root).unwrap();
+            let root_id = root_id(tree)?;
+            self.region_size(tree)?;
             // for recursive postorder traversal
             let mut postorder_id = 0;
 
             for n in root_id.descendants(tree) {
-                let root_label = tree.get(n).unwrap().get();
+                let root_label = tree[n].get();
                 let split_id = split(root_label);
                 self.tree_size_by_split_id[split_id] += 1;
             }
@@ -157,7 +360,7 @@ impl LabelSetConverter {
             self.tree_size_by_split_id = [0; Self::MAX_SPLIT];
             sets_collection.push(SplitStructuralFilterTuple(tree.count(), record_labels));
         }
-        sets_collection
+        Ok(sets_collection)
     }
 
     fn reset(&mut self) {
@@ -166,7 +369,10 @@ impl LabelSetConverter {
         self.tree_size_by_split_id[0] = 0;
     }
 
-    pub fn create(&mut self, trees: &[ParsedTree]) -> Vec<StructuralFilterTuple> {
+    pub fn create(
+        &mut self,
+        trees: &[ParsedTree],
+    ) -> Result<Vec<StructuralFilterTuple>, IndexError> {
         // add one because range are end exclusive
         // frequency vector of pair (label weight, labelId)
         self.reset();
@@ -175,15 +381,11 @@ impl LabelSetConverter {
             // contains structural vectors for the current tree
             // is it a hash map of Label -> Vec<StructVec>
             let mut record_labels = StructHashMap::default();
-
-            let Some(root) = tree.iter().next() else {
-                panic!("tree is empty");
-            };
-            let root_id = tree.get_node_id(root).unwrap();
+            let root_id = root_id(tree)?;
             // for recursive postorder traversal
             let mut postorder_id = 0;
 
-            self.tree_size_by_split_id[0] = tree.count() as RegionNumType;
+            self.tree_size_by_split_id[0] = self.region_size(tree)?;
 
             // array of records stored in sets_collection
             self.create_record(&root_id, tree, &mut postorder_id, &mut record_labels);
@@ -192,23 +394,23 @@ impl LabelSetConverter {
             self.reset();
             sets_collection.push(StructuralFilterTuple(tree.count(), record_labels));
         }
-        sets_collection
+        Ok(sets_collection)
     }
 
-    pub fn create_single(&mut self, tree: &ParsedTree) -> StructuralFilterTuple {
+    pub fn create_single(
+        &mut self,
+        tree: &ParsedTree,
+    ) -> Result<StructuralFilterTuple, IndexError> {
         self.reset();
         let mut record_labels = StructHashMap::default();
-        let Some(root) = tree.iter().next() else {
-            panic!("tree is empty");
-        };
-        let root_id = tree.get_node_id(root).unwrap();
+        let root_id = root_id(tree)?;
         // for recursive postorder traversal
         let mut postorder_id = 0;
-        self.tree_size_by_split_id[0] = tree.count() as RegionNumType;
+        self.tree_size_by_split_id[0] = self.region_size(tree)?;
         // array of records stored in sets_collection
         self.create_record(&root_id, tree, &mut postorder_id, &mut record_labels);
         self.reset();
-        StructuralFilterTuple(tree.count(), record_labels)
+        Ok(StructuralFilterTuple(tree.count(), record_labels))
     }
 
     /*
@@ -270,7 +472,7 @@ impl LabelSetConverter {
         // number of children = subtree_size - 1
         // subtree_size = 1 -> actual node + sum of children
         let mut subtree_size = [0; Self::MAX_SPLIT];
-        let root_label = tree.get(*root_id).unwrap().get();
+        let root_label = tree[*root_id].get();
         let split_id = split(root_label);
         subtree_size[split_id] = 1;
 
@@ -326,7 +528,7 @@ impl LabelSetConverter {
         } else {
             let mut se = SplitLabelSetElement {
                 base: LabelSetElementBase {
-                    id: *tree.get(*root_id).unwrap().get(),
+                    id: *tree[*root_id].get(),
                     weight: 1,
                     ..LabelSetElementBase::default()
                 },
@@ -338,56 +540,61 @@ impl LabelSetConverter {
         subtree_size
     }
 
+    /// Returns size and height of the subtree of the node
     fn create_record(
         &mut self,
         root_id: &NodeId,
         tree: &ParsedTree,
         postorder_id: &mut usize,
         record_labels: &mut StructHashMap,
-    ) -> RegionNumType {
+    ) -> (RegionNumType, RegionNumType) {
         // number of children = subtree_size - 1
         // subtree_size = 1 -> actual node + sum of children
         let mut subtree_size = 1;
+        let mut height = 0;
 
         self.actual_depth[0] += 1;
 
         for cid in root_id.children(tree) {
-            subtree_size += self.create_record(&cid, tree, postorder_id, record_labels);
+            let (child_size, child_height) =
+                self.create_record(&cid, tree, postorder_id, record_labels);
+            subtree_size += child_size;
+            height = height.max(child_height + 1);
         }
 
         *postorder_id += 1;
         self.actual_depth[0] -= 1;
         self.actual_pre_order_number[0] += 1;
 
-        let root_label = tree.get(*root_id).unwrap().get();
+        let root_label = tree[*root_id].get();
+        let regions = [
+            (self.actual_pre_order_number[0] - subtree_size),
+            self.actual_depth[0],
+            (self.tree_size_by_split_id[0]
+                - (self.actual_pre_order_number[0] + self.actual_depth[0])),
+            (subtree_size - 1),
+        ];
         let node_struct_vec = StructuralVec {
             postorder_id: *postorder_id,
             label_id: *root_label,
-            mapping_regions: [
-                (self.actual_pre_order_number[0] - subtree_size),
-                self.actual_depth[0],
-                (self.tree_size_by_split_id[0]
-                    - (self.actual_pre_order_number[0] + self.actual_depth[0])),
-                (subtree_size - 1),
-            ],
+            mapping_regions: std::array::from_fn(|r| regions[r] * self.weights.regions[r]),
         };
 
-        if let Some(se) = record_labels.get_mut(root_label) {
-            se.base.weight += 1;
-            se.struct_vec.push(node_struct_vec);
-        } else {
-            let mut se = LabelSetElement {
+        let se = record_labels
+            .entry(*root_label)
+            .or_insert_with(|| LabelSetElement {
                 base: LabelSetElementBase {
-                    id: *tree.get(*root_id).unwrap().get(),
-                    weight: 1,
+                    id: *root_label,
                     ..LabelSetElementBase::default()
                 },
                 ..LabelSetElement::default()
-            };
-            se.struct_vec.push(node_struct_vec);
-            record_labels.insert(*root_label, se);
+            });
+        se.base.weight += 1;
+        se.struct_vec.push(node_struct_vec);
+        if self.weights.height > 0 {
+            se.struct_vec.heights.push(height * self.weights.height);
         }
-        subtree_size
+        (subtree_size, height)
     }
 }
 
@@ -418,64 +625,98 @@ fn svec_l1(n1: &StructuralVec, n2: &StructuralVec) -> u32 {
         .iter()
         .zip_eq(n2.mapping_regions.iter())
         .map(|(a, b)| (a - b).abs())
-        .sum::<i32>() as u32
+        .sum::<RegionNumType>() as u32
 }
 
 #[inline(always)]
-fn svec_l1_strict(n1: &[RegionNumType; 4], n2: &[RegionNumType; 4]) -> i32 {
+fn svec_l1_strict(n1: &[RegionNumType; 4], n2: &[RegionNumType; 4]) -> RegionNumType {
     n1.iter()
         .zip_eq(n2.iter())
         .fold(0, |acc, (a, b)| acc + (a - b).abs())
 }
 
+/// Returns true if any region vector of the block is within L1 region distance `k` of the query.
+/// Uses SIMD when built with the `simd` feature and the CPU supports it.
+#[inline(always)]
+pub(crate) fn any_within_k(
+    query: &[RegionNumType; 4],
+    block: &[[RegionNumType; 4]],
+    k: RegionNumType,
+) -> bool {
+    #[cfg(all(
+        feature = "simd",
+        not(feature = "wide-counters"),
+        target_arch = "x86_64"
+    ))]
+    if is_x86_feature_detected!("ssse3") {
+        // SAFETY: SSSE3 support was just detected
+        return unsafe { simd::any_within_k(query, block, k) };
+    }
+    block.iter().any(|n| svec_l1_strict(query, n) <= k)
+}
+
 /// Given two sets
 pub fn ted(s1: &StructuralFilterTuple, s2: &StructuralFilterTuple, k: usize) -> usize {
+    ted_in_window(s1, s2, k, false)
+}
+
+/// Postorder ids of nodes of a tree larger by `size_diff` (negative when smaller) that
+/// may be within region distance `k` of a node with postorder id `p1`.
+/// A postorder id is one more than the nodes to the left and the descendants, the rest
+/// of the tree are the ancestors and the nodes to the right. With `d = p2 - p1` the
+/// region distance is at least `|d| + |size_diff - d|`, which is `|size_diff|` for `d`
+/// between 0 and `size_diff` and grows by 2 with every step further away.
+/// Assumes region weights of at least 1, a weight of 0 leaves the postorder id unbounded.
+fn tight_postorder_window(
+    p1: RegionNumType,
+    size_diff: RegionNumType,
+    k: RegionNumType,
+) -> (RegionNumType, RegionNumType) {
+    let slack = (k - size_diff.abs()).max(0) / 2;
+    (p1 + size_diff.min(0) - slack, p1 + size_diff.max(0) + slack)
+}
+
+/// With `tight` only nodes within the [`tight_postorder_window`] are compared, which
+/// gives the same bound as long as no region weight is 0
+fn ted_in_window(
+    s1: &StructuralFilterTuple,
+    s2: &StructuralFilterTuple,
+    k: usize,
+    tight: bool,
+) -> usize {
     use std::cmp::max;
     let bigger = max(s1.0, s2.0);
 
-    if s1.0.abs_diff(s2.0) > k {
+    if size_lb(s1.0, s2.0, k) > k {
         return k + 1;
     }
-    let k = k as i32;
+    let k = k as RegionNumType;
 
     let mut overlap = 0;
     for (lblid, set1) in s1.1.iter() {
         if let Some(set2) = s2.1.get(lblid) {
             if set1.base.weight == 1 && set2.base.weight == 1 {
-                let l1_region_distance = svec_l1_strict(
-                    &set1.struct_vec[0].mapping_regions,
-                    &set2.struct_vec[0].mapping_regions,
-                );
-
-                if l1_region_distance <= k {
+                if set1.struct_vec.distance(0, &set2.struct_vec, 0) <= k {
                     overlap += 1;
                 }
                 continue;
             }
 
+            if tight {
+                overlap += nodes_overlap(set1, set2, k as usize, Some((s1.0, s2.0)));
+                continue;
+            }
+
             let (s1c, s2c) = if set2.base.weight < set1.base.weight {
                 (set2, set1)
             } else {
                 (set1, set2)
             };
 
-            for n1 in s1c.struct_vec.iter() {
-                // let k_window = n1.postorder_id as i32 - k as i32;
-                // let k_window = std::cmp::max(k_window, 0) as usize;
-
-                // apply postorder filter
-                // let s2clen = s2c.struct_vec.len();
-                for n2 in s2c.struct_vec.iter()
-                // .skip_while(|n2| k_window < s2c.struct_vec.len() && n2.postorder_id < k_window)
-                // .take_while(|n2| !(n2.postorder_id > k as usize + n1.postorder_id))
-                {
-                    let l1_region_distance =
-                        svec_l1_strict(&n1.mapping_regions, &n2.mapping_regions);
-
-                    if l1_region_distance <= k {
-                        overlap += 1;
-                        break;
-                    }
+            let (v1, v2) = (&s1c.struct_vec, &s2c.struct_vec);
+            for n1 in 0..v1.regions.len() {
+                if v1.any_within_k(n1, v2, 0..v2.regions.len(), k) {
+                    overlap += 1;
                 }
             }
         }
@@ -484,13 +725,101 @@ pub fn ted(s1: &StructuralFilterTuple, s2: &StructuralFilterTuple, k: usize) ->
     bigger - overlap
 }
 
-#[allow(dead_code)]
+/// Like [`ted`], but label groups with at most `cutoff` nodes in both trees are matched
+/// exactly. The greedy overlap of [`ted`] counts every node of the smaller group with
+/// any node within `k`, several nodes may count the same node. A maximum matching maps
+/// every node at most once, so the bound is never lower. A `cutoff` of 0 is the same
+/// as [`ted`]. A `tight_window` compares only nodes within the
+/// [`tight_postorder_window`] instead of postorder ids at most `k` apart.
+pub fn ted_matching(
+    s1: &StructuralFilterTuple,
+    s2: &StructuralFilterTuple,
+    k: usize,
+    cutoff: usize,
+    tight_window: bool,
+) -> usize {
+    if size_lb(s1.0, s2.0, k) > k {
+        return k + 1;
+    }
+    let sizes = tight_window.then_some((s1.0, s2.0));
+    let mut overlap = 0;
+    for (lblid, set1) in s1.1.iter() {
+        if let Some(set2) = s2.1.get(lblid) {
+            overlap += if max(set1.base.weight, set2.base.weight) <= cutoff {
+                matched_nodes(set1, set2, k, sizes)
+            } else {
+                nodes_overlap(set1, set2, k, sizes)
+            };
+        }
+    }
+    max(s1.0, s2.0) - overlap
+}
+
+/// Size of a maximum matching of nodes of two label groups, nodes can be matched if
+/// their postorder ids are within the window and region vectors within `k`, see
+/// [`nodes_overlap`] for the window
+fn matched_nodes(
+    set1: &LabelSetElement,
+    set2: &LabelSetElement,
+    k: usize,
+    sizes: Option<(usize, usize)>,
+) -> usize {
+    let (v1, v2) = (&set1.struct_vec, &set2.struct_vec);
+    let k = k as RegionNumType;
+    let edges = v1
+        .postorder_ids
+        .iter()
+        .enumerate()
+        .map(|(n1, p1)| {
+            let (low, high) = postorder_window(*p1, k, sizes);
+            v2.postorder_ids
+                .iter()
+                .enumerate()
+                .filter(|(n2, p2)| (low..=high).contains(*p2) && v1.distance(n1, v2, *n2) <= k)
+                .map(|(n2, _)| n2)
+                .collect_vec()
+        })
+        .collect_vec();
+    let mut matched_to = vec![None; v2.regions.len()];
+    let mut visited = vec![false; v2.regions.len()];
+    let mut matched = 0;
+    for n1 in 0..edges.len() {
+        visited.fill(false);
+        if augment(n1, &edges, &mut matched_to, &mut visited) {
+            matched += 1;
+        }
+    }
+    matched
+}
+
+/// Looks for an augmenting path from `n1`, groups are small so recursion stays shallow
+fn augment(
+    n1: usize,
+    edges: &[Vec<usize>],
+    matched_to: &mut [Option<usize>],
+    visited: &mut [bool],
+) -> bool {
+    for &n2 in &edges[n1] {
+        if visited[n2] {
+            continue;
+        }
+        visited[n2] = true;
+        if matched_to[n2].is_none_or(|other| augment(other, edges, matched_to, visited)) {
+            matched_to[n2] = Some(n1);
+            return true;
+        }
+    }
+    false
+}
+
+/// Structural filter bound of split label sets, nodes of the same label are compared
+/// by region counts of every label group
 pub fn ted_variant(
     s1: &SplitStructuralFilterTuple,
     s2: &SplitStructuralFilterTuple,
     k: usize,
 ) -> usize {
-    if s1.0.abs_diff(s2.0) > k {
+    if size_lb(s1.0, s2.0, k) > k {
         return k + 1;
     }
     let bigger = max(s1.0, s2.0);
@@ -554,84 +883,186 @@ fn get_nodes_overlap_with_region_distance(
 }
 
 fn get_nodes_overlap(set1: &LabelSetElement, set2: &LabelSetElement, k: usize) -> usize {
+    nodes_overlap(set1, set2, k, None)
+}
+
+/// Postorder window of a node with postorder id `p1`, ids at most `k` apart without the
+/// sizes of both trees and the [`tight_postorder_window`] with them
+#[inline(always)]
+fn postorder_window(
+    p1: RegionNumType,
+    k: RegionNumType,
+    sizes: Option<(usize, usize)>,
+) -> (RegionNumType, RegionNumType) {
+    match sizes {
+        Some((size1, size2)) => {
+            tight_postorder_window(p1, size2 as RegionNumType - size1 as RegionNumType, k)
+        }
+        None => (p1 - k, p1 + k),
+    }
+}
+
+/// Nodes of the smaller group within `k` of any node of the other group in their
+/// postorder window, `sizes` are the sizes of the trees of `set1` and `set2`
+fn nodes_overlap(
+    set1: &LabelSetElement,
+    set2: &LabelSetElement,
+    k: usize,
+    sizes: Option<(usize, usize)>,
+) -> usize {
     let mut overlap = 0;
     if set1.base.weight == 1 && set2.base.weight == 1 {
-        return usize::from(
-            svec_l1_strict(
-                &set1.struct_vec[0].mapping_regions,
-                &set2.struct_vec[0].mapping_regions,
-            ) as usize
-                <= k,
-        );
+        return usize::from(set1.struct_vec.distance(0, &set2.struct_vec, 0) as usize <= k);
     }
 
-    let (s1c, s2c) = if set2.base.weight < set1.base.weight {
-        (set2, set1)
+    let (s1c, s2c, sizes) = if set2.base.weight < set1.base.weight {
+        (set2, set1, sizes.map(|(size1, size2)| (size2, size1)))
     } else {
-        (set1, set2)
+        (set1, set2, sizes)
     };
 
-    for n1 in s1c.struct_vec.iter() {
-        let k_window = n1.postorder_id as i32 - k as i32;
-        let k_window = std::cmp::max(k_window, 0) as usize;
+    let postorder_ids = &s2c.struct_vec.postorder_ids;
+    for (n1, n1_postorder) in s1c.struct_vec.postorder_ids.iter().enumerate() {
+        let (low, high) = postorder_window(*n1_postorder, k as RegionNumType, sizes);
 
-        // apply postorder filter
-        let s2clen = s2c.struct_vec.len();
-        for n2 in s2c
+        // apply postorder filter, postings are sorted by postorder id
+        let start = postorder_ids.partition_point(|n2| *n2 < low);
+        let end = start + postorder_ids[start..].partition_point(|n2| *n2 <= high);
+
+        if s1c
             .struct_vec
-            .iter()
-            .skip_while(|n2| k_window < s2c.struct_vec.len() && n2.postorder_id < k_window)
-            .take_while(|n2| n2.postorder_id <= k + n1.postorder_id)
+            .any_within_k(n1, &s2c.struct_vec, start..end, k as RegionNumType)
         {
-            let l1_region_distance = svec_l1_strict(&n1.mapping_regions, &n2.mapping_regions);
-
-            if l1_region_distance as usize <= k {
-                overlap += 1;
-                break;
-            }
+            overlap += 1;
         }
     }
     overlap
 }
 
-pub fn best_split_distribution(ld: &LabelDict) -> FxHashMap<&i32, usize> {
-    let sorted_labels = ld.values().sorted_by(|a, b| a.1.cmp(&b.1)).collect_vec();
+/// Assigns labels to `groups` groups, at most [`LabelSetConverter::MAX_SPLIT`], round robin
+/// from the most frequent one, so groups have about the same number of nodes. Labels of
+/// the same frequency are ordered by id, the groups are the same on every run.
+pub fn best_split_distribution(ld: &LabelDict, groups: usize) -> FxHashMap<&LabelId, usize> {
+    assert!((1..=LabelSetConverter::MAX_SPLIT).contains(&groups));
+    let sorted_labels = ld
+        .values()
+        .sorted_by_key(|(lbl, cnt)| (*cnt, *lbl))
+        .collect_vec();
 
     let mut label_distribution = FxHashMap::default();
     let mut i = 0;
     sorted_labels.iter().rev().for_each(|(lbl, _)| {
-        label_distribution.insert(lbl, i % LabelSetConverter::MAX_SPLIT);
+        label_distribution.insert(lbl, i % groups);
         i += 1;
     });
 
     label_distribution
 }
 
+/// Label sets of the indexed trees with their positions by label, bucketed by tree size
+pub(crate) type IndexPostings = LabelPostings<SizeBucketed<(usize, LabelSetElement)>>;
+
+/// Fields written by the library calls of `structural_search` are visible to the crate
+#[derive(Serialize, Deserialize)]
 pub struct StructuralFilterIndex {
-    // the tuple is treeId, tree_size and label count
-    index: FxHashMap<LabelId, Vec<(usize, usize, LabelSetElement)>>,
-    // first is the tree size, second is starting point
-    // skip_list: FxHashMap<LabelId, Vec<(usize, usize)>>,
-    size_index: Vec<usize>,
+    pub(crate) index: IndexPostings,
+    pub(crate) size_index: SizeBuckets,
+    #[serde(default)]
+    pub(crate) query_labels: Option<QueryLabels>,
+    /// Collection the index was built from, checked by [`Self::load`]
+    #[serde(default)]
+    pub(crate) fingerprint: Option<CollectionFingerprint>,
+    /// Positions of deleted trees, queries of the index skip them until [`Self::compact`]
+    #[serde(default)]
+    pub(crate) tombstones: BTreeSet<usize>,
+    /// Positions of trees of shards a persisted build has not written yet, queries of the
+    /// index skip them
+    #[serde(default)]
+    pub(crate) pending: Vec<Range<usize>>,
+}
+
+/// What the index needs to convert query strings by itself, kept with a saved index
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct QueryLabels {
+    pub(crate) label_dict: LabelDict,
+    pub(crate) ordering: LabelFreqOrdering,
+    pub(crate) weights: RegionWeights,
+}
+
+/// A tree position to delete beyond the indexed trees
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("tree {tree} is out of range of the {trees} indexed trees")]
+pub struct OutOfRange {
+    pub tree: usize,
+    pub trees: usize,
+}
+
+/// Tradeoffs of the prefix filter of [`StructuralFilterIndex::query_index_prefix`], the
+/// candidates are the same with any of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefixFilterOptions {
+    /// Label groups of the query probed in the index beyond the `k + 1` needed, longer
+    /// prefixes read more postings but find more of the overlap in them
+    pub extra_labels: usize,
+    /// Completes the overlap with the labels after the prefix only for trees the prefix
+    /// overlap and the nodes after the prefix cannot decide. Otherwise it is completed
+    /// for every tree with any overlap in the prefix.
+    pub bounded_fallback: bool,
+}
+
+/// How the trees found by the prefix filter were decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefixFilterStats {
+    /// trees of the size range found in the postings of the prefix
+    pub probed: usize,
+    /// trees accepted or pruned by their prefix overlap alone
+    pub decided: usize,
+    /// trees whose overlap was completed with the labels after the prefix
+    pub completed: usize,
+}
+
+impl std::ops::Add for PrefixFilterStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            probed: self.probed + other.probed,
+            decided: self.decided + other.decided,
+            completed: self.completed + other.completed,
+        }
+    }
+}
+
+impl MemoryUsage for StructuralFilterIndex {
+    fn heap_bytes(&self) -> usize {
+        self.index.heap_bytes() + self.size_index.heap_bytes()
+    }
 }
 
 impl StructuralFilterIndex {
+    // asserts trees are in sorted order by tree size when creating a new index
     pub fn new(trees: &[StructuralFilterTuple]) -> Self {
-        let mut index: FxHashMap<LabelId, Vec<(usize, usize, LabelSetElement)>> =
-            FxHashMap::default();
-        let mut size_index = vec![];
+        Self {
+            size_index: SizeBuckets::from_sizes(trees.iter().map(|tt| tt.0)),
+            index: Self::postings(trees, 0),
+            query_labels: None,
+            fingerprint: None,
+            tombstones: BTreeSet::new(),
+            pending: vec![],
+        }
+    }
 
-        for (tid, tt) in trees.iter().enumerate() {
+    /// Postings of the label sets of trees sorted by size, positions start at `first`
+    pub(crate) fn postings(trees: &[StructuralFilterTuple], first: usize) -> IndexPostings {
+        let mut index = IndexPostings::default();
+        for (tid, tt) in (first..).zip(trees) {
             for (label, vectors) in tt.1.iter() {
                 index
-                    .entry(*label)
-                    .and_modify(|postings| postings.push((tid, tt.0, vectors.clone())))
-                    .or_insert(vec![(tid, tt.0, vectors.clone())]);
+                    .get_or_default(*label)
+                    .push(tt.0, (tid, vectors.clone()));
             }
-            size_index.push(tt.0);
         }
-
-        Self { size_index, index }
+        index
     }
 
     pub fn query_index_prefix(
@@ -641,58 +1072,107 @@ impl StructuralFilterIndex {
         k: usize,
         trees: &[StructuralFilterTuple],
         query_id: Option<usize>,
-    ) -> Vec<(usize, usize)> {
-        let mut candidates = FxHashSet::default();
+        options: PrefixFilterOptions,
+    ) -> (Vec<(usize, usize)>, PrefixFilterStats) {
+        self.prefix_filter(query_tree, ordering, k, query_id, options, Some(trees))
+    }
+
+    /// Overlaps after the prefix of trees the prefix cannot decide are completed from the
+    /// label sets `trees` of the indexed trees, or from the postings of the index without them
+    pub(crate) fn prefix_filter(
+        &self,
+        query_tree: &StructuralFilterTuple,
+        ordering: &LabelFreqOrdering,
+        k: usize,
+        query_id: Option<usize>,
+        options: PrefixFilterOptions,
+        trees: Option<&[StructuralFilterTuple]>,
+    ) -> (Vec<(usize, usize)>, PrefixFilterStats) {
         let prefix = query_tree.get_sorted_nodes(ordering);
+        let prefix_len = k + 1 + options.extra_labels;
+        // the overlap of labels after the prefix is at most their number of query nodes
+        let rest_nodes = prefix
+            .iter()
+            .skip(prefix_len)
+            .map(|l| l.base.weight)
+            .sum::<usize>();
         let mut overlaps = FxHashMap::default();
+        let mut stats = PrefixFilterStats::default();
 
         if query_tree.0 <= k {
             // find candidates that have no label overlap but can fit by size because of threshold
-            for (cid, tree_size) in self
-                .size_index
-                .iter()
-                .enumerate()
-                .take_while(|(_, &ts)| ts <= k)
-            {
-                overlaps.insert(cid, (*tree_size, 0));
+            for cid in self.size_index.range(0, k) {
+                overlaps.insert(cid, (self.size_index.size(cid), 0));
             }
         }
 
-        for l in prefix.iter().take(k + 1) {
+        for l in prefix.iter().take(prefix_len) {
             if let Some(postings) = self.index.get(&l.base.id) {
                 postings
-                    .iter()
-                    .filter(|(_, ts, _)| {
-                        *ts >= query_tree.0.saturating_sub(k) && ts.abs_diff(query_tree.0) <= k
-                    })
-                    .for_each(|(cid, ts, nodes)| {
+                    .within(query_tree.0, k)
+                    .for_each(|(ts, (cid, nodes))| {
                         let overlap = get_nodes_overlap(l, nodes, k);
                         // dbg!(nodes);
                         overlaps
                             .entry(*cid)
                             .and_modify(|(_, ov)| *ov += overlap)
-                            .or_insert((*ts, overlap));
+                            .or_insert((ts, overlap));
                     });
             }
         }
 
-        for (cid, (size, overlap)) in overlaps.iter_mut() {
-            if *overlap > 0 {
-                for label_set in prefix.iter().skip(k + 1) {
-                    if let Some(nodes) = trees[*cid].1.get(&label_set.base.id) {
-                        *overlap += get_nodes_overlap(label_set, nodes, k);
+        stats.probed = overlaps.len();
+        let undecided = overlaps
+            .iter()
+            .filter(|(_, (size, overlap))| {
+                let bigger = std::cmp::max(query_tree.0, *size);
+                if options.bounded_fallback {
+                    bigger.saturating_sub(*overlap) > k
+                        && bigger.saturating_sub(*overlap + rest_nodes) <= k
+                } else {
+                    *overlap > 0
+                }
+            })
+            .map(|(cid, _)| *cid)
+            .collect::<FxHashSet<_>>();
+        stats.completed = undecided.len();
+        stats.decided = stats.probed - stats.completed;
+
+        match trees {
+            Some(trees) => {
+                for cid in undecided.iter() {
+                    let (_, overlap) = overlaps.get_mut(cid).unwrap();
+                    for label_set in prefix.iter().skip(prefix_len) {
+                        if let Some(nodes) = trees[*cid].1.get(&label_set.base.id) {
+                            *overlap += get_nodes_overlap(label_set, nodes, k);
+                        }
                     }
                 }
             }
-            if std::cmp::max(query_tree.0, *size).saturating_sub(*overlap) <= k {
-                candidates.insert(*cid);
+            None => {
+                for label_set in prefix.iter().skip(prefix_len) {
+                    let Some(postings) = self.index.get(&label_set.base.id) else {
+                        continue;
+                    };
+                    for (_, (cid, nodes)) in postings.within(query_tree.0, k) {
+                        if undecided.contains(cid) {
+                            overlaps.get_mut(cid).unwrap().1 +=
+                                get_nodes_overlap(label_set, nodes, k);
+                        }
+                    }
+                }
             }
         }
 
-        candidates
+        let candidates = overlaps
             .into_iter()
-            .map(|cid| (query_id.unwrap_or(0), cid))
-            .collect::<Vec<(usize, usize)>>()
+            .filter(|(cid, (size, overlap))| {
+                std::cmp::max(query_tree.0, *size).saturating_sub(*overlap) <= k
+                    && self.searchable(*cid)
+            })
+            .map(|(cid, _)| (query_id.unwrap_or(0), cid))
+            .collect::<Vec<(usize, usize)>>();
+        (candidates, stats)
     }
 
     pub fn query_index(
@@ -706,12 +1186,7 @@ impl StructuralFilterIndex {
         let mut tree_intersections = FxHashMap::default();
         for (lbl, query_label_nodes) in query_tree.1.iter() {
             if let Some(posting_list) = self.index.get(lbl) {
-                for (tid, tree_size, posting_nodes) in posting_list
-                    .iter()
-                    // .skip(start)
-                    .skip_while(|(_, size, _)| query_tree.0 - size > k)
-                    .take_while(|(_, size, _)| *size <= k + query_tree.0)
-                {
+                for (tree_size, (tid, posting_nodes)) in posting_list.within(query_tree.0, k) {
                     let overlapping_nodes = get_nodes_overlap(query_label_nodes, posting_nodes, k);
 
                     tree_intersections
@@ -719,21 +1194,18 @@ impl StructuralFilterIndex {
                         .and_modify(|(intersection_size, _)| {
                             *intersection_size += overlapping_nodes;
                         })
-                        .or_insert((overlapping_nodes, *tree_size));
+                        .or_insert((overlapping_nodes, tree_size));
                 }
             }
         }
 
         let mut candidates = vec![];
         // find candidates that have no label overlap but can fit by size because of threshold
-        for (cid, tree_size) in self
-            .size_index
-            .iter()
-            .enumerate()
-            .take_while(|(_, ts)| query_tree.0.abs_diff(**ts) <= k)
-        {
+        for cid in self.size_index.within(query_tree.0, k) {
+            let tree_size = self.size_index.size(cid);
             if !tree_intersections.contains_key(&cid)
-                && std::cmp::max(query_tree.0, *tree_size) <= k
+                && std::cmp::max(query_tree.0, tree_size) <= k
+                && self.searchable(cid)
             {
                 candidates.push((query_id, cid));
             }
@@ -742,13 +1214,117 @@ impl StructuralFilterIndex {
         candidates.extend(
             tree_intersections
                 .iter()
-                .filter(|(_, (intersection_size, tree_size))| {
+                .filter(|(tid, (intersection_size, tree_size))| {
                     std::cmp::max(query_tree.0, *tree_size) - intersection_size <= k
+                        && self.searchable(**tid)
                 })
                 .map(|(tid, _)| (query_id, *tid)),
         );
         candidates
     }
+
+    /// Whether queries return the tree, it is neither deleted nor of a pending shard
+    fn searchable(&self, tid: usize) -> bool {
+        !self.tombstones.contains(&tid) && !self.pending.iter().any(|range| range.contains(&tid))
+    }
+
+    /// Deletes trees by their positions without rebuilding the index, queries skip them
+    /// and the fingerprint still is the one of the collection with them. A position out of
+    /// range deletes no tree
+    pub fn tombstone(
+        &mut self,
+        tree_ids: impl IntoIterator<Item = usize>,
+    ) -> Result<(), OutOfRange> {
+        let tree_ids = tree_ids.into_iter().collect_vec();
+        let trees = self.size_index.len();
+        if let Some(&tree) = tree_ids.iter().find(|tid| **tid >= trees) {
+            return Err(OutOfRange { tree, trees });
+        }
+        self.tombstones.extend(tree_ids);
+        Ok(())
+    }
+
+    pub fn tombstones(&self) -> &BTreeSet<usize> {
+        &self.tombstones
+    }
+
+    /// Index without the trees of the tombstones, the same as an index of the collection
+    /// without them. Positions of the later trees move down by the deleted trees before
+    /// them. The fingerprint is computed again from the postings when the index has its
+    /// labels, otherwise it is dropped and has to be set with [`Self::with_fingerprint`].
+    ///
+    /// ```
+    /// use tree_statistics::example_data::COLLECTION;
+    /// use tree_statistics::{RegionWeights, StructuralFilterIndex};
+    ///
+    /// let mut index = StructuralFilterIndex::from_brackets(COLLECTION, RegionWeights::default())?;
+    /// index.tombstone([0])?;
+    /// assert_eq!(index.search("{book{title{Trees}}}", 1)?, [1]);
+    /// // the second book moves to the first position
+    /// let compacted = index.compact();
+    /// assert_eq!(compacted.search("{book{title{Trees}}}", 1)?, [0]);
+    /// # Ok::<(), tree_statistics::SearchError>(())
+    /// ```
+    pub fn compact(&self) -> Self {
+        let sizes = (0..self.size_index.len())
+            .map(|tid| self.size_index.size(tid))
+            .collect_vec();
+        let mut positions = vec![None; sizes.len()];
+        // kept trees before every position, pending ranges move down by the deleted ones
+        let mut kept_before = Vec::with_capacity(sizes.len() + 1);
+        let mut kept = 0;
+        for (tid, position) in positions.iter_mut().enumerate() {
+            kept_before.push(kept);
+            if !self.tombstones.contains(&tid) {
+                *position = Some(kept);
+                kept += 1;
+            }
+        }
+        kept_before.push(kept);
+        let mut index = LabelPostings::default();
+        let mut tree_labels = vec![vec![]; kept];
+        for (label, postings) in self.index.iter() {
+            let mut kept = SizeBucketed::default();
+            for (size, (tid, element)) in postings.iter() {
+                if let Some(position) = positions[*tid] {
+                    tree_labels[position].push(label);
+                    kept.push(size, (position, element.clone()));
+                }
+            }
+            if !kept.is_empty() {
+                index.insert(label, kept);
+            }
+        }
+        let size_index = SizeBuckets::from_sizes(
+            sizes
+                .iter()
+                .zip(&positions)
+                .filter(|(_, position)| position.is_some())
+                .map(|(size, _)| *size),
+        );
+        let fingerprint = self.query_labels.as_ref().map(|labels| {
+            let names = label_names(&labels.label_dict);
+            CollectionFingerprint::of_labels(tree_labels.iter().enumerate().map(|(tid, tree)| {
+                (
+                    size_index.size(tid),
+                    tree.iter().map(|l| names[l.get() as usize]),
+                )
+            }))
+        });
+        Self {
+            index,
+            size_index,
+            query_labels: self.query_labels.clone(),
+            fingerprint,
+            tombstones: BTreeSet::new(),
+            pending: self
+                .pending
+                .iter()
+                .map(|range| kept_before[range.start]..kept_before[range.end])
+                .filter(|range| !range.is_empty())
+                .collect(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -899,6 +1475,238 @@ mod tests {
     //     assert!(lb <= 10);
     // }
 
+    #[test]
+    fn test_traversals_of_label_sets() {
+        let mut label_dict = LabelDict::new();
+        let trees = ["{a{b}{a{b}{c}{a}}{b}}", "{a{c}{b{a{a}{b}{c}}}}", "{x}"]
+            .map(|t| crate::parsing::parse_single(t.to_owned(), &mut label_dict));
+        for weights in ["1,1,1,1", "2,3,0,1,1"] {
+            let weights = weights.parse::<RegionWeights>().unwrap();
+            let sets = LabelSetConverter::with_weights(weights)
+                .create(&trees)
+                .unwrap();
+            for (tree, set) in trees.iter().zip(&sets) {
+                let expected = SEDIndex::index_tree(tree, &label_dict).unwrap();
+                let traversals = set.traversals(&weights).unwrap();
+                assert_eq!(traversals.preorder, expected.preorder);
+                assert_eq!(traversals.postorder, expected.postorder);
+                assert_eq!(traversals.c, expected.c);
+            }
+        }
+        let weights = "0,1,1,1".parse::<RegionWeights>().unwrap();
+        let sets = LabelSetConverter::with_weights(weights)
+            .create(&trees)
+            .unwrap();
+        assert!(sets[0].traversals(&weights).is_none());
+    }
+
+    #[test]
+    fn test_any_within_k_matches_scalar() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(3);
+        let random_vec = |rng: &mut rand_xoshiro::Xoshiro256PlusPlus| StructuralVec {
+            mapping_regions: [(); 4].map(|_| rng.gen_range(0..20)),
+            ..Default::default()
+        };
+        for len in 0..12 {
+            let block = (0..len).map(|_| random_vec(&mut rng)).collect_vec();
+            let mut nodes = StructuralVecs::default();
+            for (postorder_id, n) in block.iter().enumerate() {
+                nodes.push(StructuralVec {
+                    postorder_id: postorder_id + 1,
+                    ..n.clone()
+                });
+            }
+            let query = random_vec(&mut rng).mapping_regions;
+            for k in [0, 5, 15, 30] {
+                for start in 0..=len.min(3) {
+                    let scalar = block[start..]
+                        .iter()
+                        .any(|n| svec_l1_strict(&query, &n.mapping_regions) <= k);
+                    assert_eq!(any_within_k(&query, &nodes.regions[start..], k), scalar);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_nodes_overlap_postorder_window() {
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{a{b}{a}}{c{a{a}}}{a}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{b{a}}{a{a}{c}}{a{a{a}}}}".to_owned(), &mut ld);
+        let mut lc = LabelSetConverter::default();
+        let (s1, s2) = (
+            lc.create_single(&t1).unwrap(),
+            lc.create_single(&t2).unwrap(),
+        );
+        let a = ld.get("a").unwrap().0;
+        let (set1, set2) = (s1.1.get(&a).unwrap(), s2.1.get(&a).unwrap());
+        for k in 0..6 {
+            // linear scan over the postorder window
+            let expected = set1
+                .struct_vec
+                .postorder_ids
+                .iter()
+                .zip(&set1.struct_vec.regions)
+                .filter(|(p1, r1)| {
+                    let k_window = (**p1 as usize).saturating_sub(k);
+                    set2.struct_vec
+                        .postorder_ids
+                        .iter()
+                        .zip(&set2.struct_vec.regions)
+                        .skip_while(|(p2, _)| {
+                            k_window < set2.struct_vec.postorder_ids.len()
+                                && (**p2 as usize) < k_window
+                        })
+                        .take_while(|(p2, _)| **p2 as usize <= k + **p1 as usize)
+                        .any(|(_, r2)| svec_l1_strict(r1, r2) as usize <= k)
+                })
+                .count();
+            assert_eq!(get_nodes_overlap(set1, set2, k), expected);
+        }
+    }
+
+    #[test]
+    fn test_exact_matching() {
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::new();
+        // both `a` nodes of the first tree are within k = 2 only of the first `a` of the
+        // second tree, greedily both count but only one of them can be mapped
+        let t1 = parse_single("{r{a}{a}{x}{y}{z}}".to_owned(), &mut ld);
+        let t2 = parse_single("{r{a}{x}{y}{z}{a}}".to_owned(), &mut ld);
+        let sets = LabelSetConverter::default().create(&[t1, t2]).unwrap();
+        assert_eq!(ted(&sets[0], &sets[1], 2), 0);
+        assert_eq!(ted_matching(&sets[0], &sets[1], 2, 0, false), 0);
+        assert_eq!(ted_matching(&sets[0], &sets[1], 2, 8, false), 1);
+    }
+
+    #[test]
+    fn test_tight_window_against_ted() {
+        use crate::parsing::{parse_single, random_bracket_tree};
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(11);
+        assert_eq!(tight_postorder_window(5, 0, 3), (4, 6));
+        assert_eq!(tight_postorder_window(5, -2, 4), (2, 6));
+        let mut ld = LabelDict::new();
+        for _ in 0..300 {
+            let (size1, size2) = (1 + rng.gen_range(0..10), 1 + rng.gen_range(0..10));
+            let t1 = parse_single(random_bracket_tree(&mut rng, size1, 3), &mut ld);
+            let t2 = parse_single(random_bracket_tree(&mut rng, size2, 3), &mut ld);
+            let exact = crate::ted::ted(&t1, &t2);
+            let sets = LabelSetConverter::default().create(&[t1, t2]).unwrap();
+            for k in 0..8 {
+                let tight = ted_in_window(&sets[0], &sets[1], k, true);
+                assert_eq!(tight, ted(&sets[0], &sets[1], k), "k={k}");
+                for cutoff in [0, 3] {
+                    let loose = ted_matching(&sets[0], &sets[1], k, cutoff, false);
+                    let tight = ted_matching(&sets[0], &sets[1], k, cutoff, true);
+                    assert_eq!(tight, loose, "k={k} cutoff={cutoff}");
+                    if exact <= k {
+                        assert!(tight <= k, "k={k} exact={exact} bound={tight}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefix_filter_options() {
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::new();
+        let trees = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{a{c}{b}}",
+            "{x{b}{c}}",
+            "{a{b{c}}{d}}",
+            "{a{b}{c}{d}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let sets = LabelSetConverter::default().create(&trees).unwrap();
+        let index = StructuralFilterIndex::new(&sets);
+        let ordering = crate::parsing::get_frequency_ordering(&ld);
+        for k in 0..3 {
+            let (mut expected, default_stats) =
+                index.query_index_prefix(&sets[1], &ordering, k, &sets, None, Default::default());
+            expected.sort();
+            assert_eq!(
+                default_stats.decided + default_stats.completed,
+                default_stats.probed
+            );
+            for (extra_labels, bounded_fallback) in [(0, true), (1, false), (2, true)] {
+                let options = PrefixFilterOptions {
+                    extra_labels,
+                    bounded_fallback,
+                };
+                let (mut candidates, stats) =
+                    index.query_index_prefix(&sets[1], &ordering, k, &sets, None, options);
+                candidates.sort();
+                assert_eq!(candidates, expected, "k={k} {options:?}");
+                assert_eq!(stats.decided + stats.completed, stats.probed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tombstones_and_compact() {
+        use crate::parsing::{get_frequency_ordering, parse_single};
+        let mut ld = LabelDict::new();
+        let trees = ["{a}", "{a{b}}", "{a{c}}", "{a{b}{c}}", "{x{y}{z}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let sets = LabelSetConverter::default().create(&trees).unwrap();
+        let ordering = get_frequency_ordering(&ld);
+        let ids = |candidates: Vec<(usize, usize)>| {
+            candidates
+                .into_iter()
+                .map(|(_, c)| c)
+                .sorted()
+                .collect_vec()
+        };
+        let mut index = StructuralFilterIndex::new(&sets);
+        index.tombstone([2, 4]).unwrap();
+        assert_eq!(
+            index.tombstone([1, 5]),
+            Err(OutOfRange { tree: 5, trees: 5 })
+        );
+        assert_eq!(index.tombstones(), &BTreeSet::from([2, 4]));
+        // both scans of the index skip them
+        let (prefixed, _) =
+            index.query_index_prefix(&sets[1], &ordering, 1, &sets, None, Default::default());
+        assert_eq!(ids(prefixed), [0, 1, 3]);
+        assert_eq!(ids(index.query_index(&sets[1], 1, None)), [0, 1, 3]);
+
+        // the fourth tree moves to the third position
+        let compacted = index.compact();
+        assert!(compacted.tombstones().is_empty());
+        assert_eq!(ids(compacted.query_index(&sets[1], 1, None)), [0, 1, 2]);
+        assert!(ids(compacted.query_index(&sets[2], 0, None)).is_empty());
+    }
+
+    #[test]
+    fn test_region_weights() {
+        use crate::parsing::parse_single;
+        assert_eq!("1,1,1,1".parse(), Ok(RegionWeights::default()));
+        let weights = "0, 0, 0, 0, 1".parse::<RegionWeights>().unwrap();
+        assert_eq!(weights.regions, [0; 4]);
+        assert_eq!(weights.height, 1);
+        assert!("1,1".parse::<RegionWeights>().is_err());
+        assert!("1,-1,1,1".parse::<RegionWeights>().is_err());
+
+        let mut ld = LabelDict::new();
+        let t1 = parse_single("{a{b{c}}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        let trees = [t1, t2];
+        let sets = LabelSetConverter::default().create(&trees).unwrap();
+        assert_eq!(ted(&sets[0], &sets[1], 0), 2);
+        // only the heights of `a` and `b` differ
+        let sets = LabelSetConverter::with_weights(weights)
+            .create(&trees)
+            .unwrap();
+        assert_eq!(ted(&sets[0], &sets[1], 0), 2);
+        assert_eq!(ted(&sets[0], &sets[1], 1), 0);
+    }
+
     #[test]
     fn test_svec_l1_distance_with_axes() {
         let a = StructuralVec {
@@ -0,0 +1,38 @@
+//! SSSE3 region distances, computing L1 distance of four structural vectors at once.
+use super::{svec_l1_strict, RegionNumType};
+use std::arch::x86_64::*;
+
+#[inline(always)]
+unsafe fn load_regions(regions: &[RegionNumType; 4]) -> __m128i {
+    _mm_loadu_si128(regions.as_ptr() as *const __m128i)
+}
+
+/// Returns true if any region vector of the block is within L1 region distance `k` of the query.
+///
+/// # Safety
+/// CPU has to support SSSE3.
+#[target_feature(enable = "ssse3")]
+pub unsafe fn any_within_k(
+    query: &[RegionNumType; 4],
+    block: &[[RegionNumType; 4]],
+    k: RegionNumType,
+) -> bool {
+    let q = load_regions(query);
+    let kv = _mm_set1_epi32(k);
+    let mut chunks = block.chunks_exact(4);
+    for chunk in &mut chunks {
+        let d0 = _mm_abs_epi32(_mm_sub_epi32(load_regions(&chunk[0]), q));
+        let d1 = _mm_abs_epi32(_mm_sub_epi32(load_regions(&chunk[1]), q));
+        let d2 = _mm_abs_epi32(_mm_sub_epi32(load_regions(&chunk[2]), q));
+        let d3 = _mm_abs_epi32(_mm_sub_epi32(load_regions(&chunk[3]), q));
+        // two horizontal adds leave the distance of i-th vector in the i-th lane
+        let sums = _mm_hadd_epi32(_mm_hadd_epi32(d0, d1), _mm_hadd_epi32(d2, d3));
+        if _mm_movemask_epi8(_mm_cmpgt_epi32(sums, kv)) != 0xFFFF {
+            return true;
+        }
+    }
+    chunks
+        .remainder()
+        .iter()
+        .any(|n| svec_l1_strict(query, n) <= k)
+}
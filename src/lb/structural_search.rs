@@ -0,0 +1,532 @@
+//! Library calls of the structural filter index that convert trees in bracket notation
+//! by themselves: searching, saving and loading an index checked against its collection,
+//! and builds persisted shard by shard. The command line tool reads its collections and
+//! queries itself, so this module is only part of the library.
+use crate::collection_fingerprint::{CollectionFingerprint, FingerprintMismatch};
+use crate::indexing::IndexError;
+use crate::lb::size_buckets::SizeBuckets;
+use crate::lb::structural_filter::{
+    IndexPostings, LabelSetConverter, OutOfRange, PrefixFilterOptions, QueryLabels, RegionWeights,
+    StructuralFilterIndex,
+};
+use crate::parsing::{
+    get_frequency_ordering, max_label, parse_tree, parse_tree_tokens, update_label_dict, LabelDict,
+    LabelId, ParsedTree, TreeParseError,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SearchError {
+    #[error("index has no labels to convert queries with, build it with `with_labels`")]
+    MissingLabels,
+    #[error(transparent)]
+    Parse(#[from] TreeParseError),
+    #[error(transparent)]
+    Index(#[from] IndexError),
+    #[error("index is stale, it was {0}")]
+    Stale(#[from] FingerprintMismatch),
+    #[error("index was saved without a collection fingerprint")]
+    MissingFingerprint,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("build in {0} was started with other region weights")]
+    BuildWeights(PathBuf),
+    #[error(transparent)]
+    OutOfRange(#[from] OutOfRange),
+}
+
+/// File of a persisted build with the labels, tree sizes and shards of the collection
+pub const BUILD_MANIFEST: &str = "manifest.json";
+
+/// What a persisted build writes before the first shard, see
+/// [`StructuralFilterIndex::build_persisted`]
+#[derive(Serialize, Deserialize)]
+struct BuildManifest {
+    size_index: SizeBuckets,
+    query_labels: QueryLabels,
+    fingerprint: CollectionFingerprint,
+    /// positions of the trees of every shard in increasing order
+    shards: Vec<Range<usize>>,
+}
+
+/// Shards of a persisted build written so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildProgress {
+    pub built: usize,
+    pub shards: usize,
+}
+
+impl BuildProgress {
+    pub fn is_complete(&self) -> bool {
+        self.built == self.shards
+    }
+}
+
+fn shard_path(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(format!("shard-{shard}.json"))
+}
+
+/// Writes the file next to its final path and renames it, so an interrupted build leaves
+/// no partial file
+fn write_atomically(path: &Path, value: &impl Serialize) -> Result<(), SearchError> {
+    let partial = path.with_extension("json.partial");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&partial)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(partial, path)?;
+    Ok(())
+}
+
+/// Consecutive trees of at least `shard_trees` trees, trees of a size are in one shard
+fn size_shards(sizes: &SizeBuckets, shard_trees: usize) -> Vec<Range<usize>> {
+    let mut shards = vec![];
+    let mut start = 0;
+    while start < sizes.len() {
+        let mut end = (start + shard_trees.max(1)).min(sizes.len());
+        while end < sizes.len() && sizes.size(end) == sizes.size(end - 1) {
+            end += 1;
+        }
+        shards.push(start..end);
+        start = end;
+    }
+    shards
+}
+
+fn tokens(tree: &str) -> Result<Vec<String>, TreeParseError> {
+    if !tree.is_ascii() {
+        return Err(TreeParseError::IsNotAscii);
+    }
+    parse_tree_tokens(tree.trim().to_owned())
+}
+
+/// Tokens, labels and trees of trees in bracket notation
+type ParsedBrackets = (Vec<Vec<String>>, LabelDict, Vec<ParsedTree>);
+
+fn parse_brackets(trees: &[&str]) -> Result<ParsedBrackets, SearchError> {
+    let tokens = trees
+        .iter()
+        .map(|tree| tokens(tree))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut label_dict = LabelDict::new();
+    update_label_dict(&tokens, &mut label_dict);
+    let trees = tokens
+        .iter()
+        .map(|tokens| parse_tree(tokens, &label_dict))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((tokens, label_dict, trees))
+}
+
+impl CollectionFingerprint {
+    /// Fingerprint of tokenized trees in bracket notation
+    pub fn of_tokens<S: AsRef<str>>(trees: &[Vec<S>]) -> Self {
+        Self::of_labels(trees.iter().map(|tokens| {
+            let labels = tokens
+                .iter()
+                .map(AsRef::as_ref)
+                .filter(|token| *token != "{" && *token != "}");
+            (labels.clone().count(), labels)
+        }))
+    }
+}
+
+/// Library calls of an index that converts trees in bracket notation by itself
+impl StructuralFilterIndex {
+    /// Index of trees in bracket notation sorted by size, ready for [`Self::search`]
+    pub fn from_brackets(trees: &[&str], weights: RegionWeights) -> Result<Self, SearchError> {
+        let (tokens, label_dict, trees) = parse_brackets(trees)?;
+        let sets = LabelSetConverter::with_weights(weights).create(&trees)?;
+        Ok(Self::new(&sets)
+            .with_labels(&label_dict, weights)
+            .with_fingerprint(CollectionFingerprint::of_tokens(&tokens)))
+    }
+
+    /// Keeps the fingerprint of the indexed collection, so [`Self::load`] rejects the
+    /// index for another collection
+    pub fn with_fingerprint(mut self, fingerprint: CollectionFingerprint) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Writes the index as JSON, with its labels and fingerprint when it has them
+    pub fn save(&self, writer: impl Write) -> Result<(), SearchError> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// Reads an index written by [`Self::save`] for the collection of trees in bracket
+    /// notation it is used with. Fails unless the index was saved with the fingerprint of
+    /// that collection, an index of another dataset version would return positions of
+    /// other trees.
+    ///
+    /// ```
+    /// use tree_statistics::example_data::COLLECTION;
+    /// use tree_statistics::{RegionWeights, SearchError, StructuralFilterIndex};
+    ///
+    /// let index = StructuralFilterIndex::from_brackets(COLLECTION, RegionWeights::default())?;
+    /// let mut saved = vec![];
+    /// index.save(&mut saved)?;
+    /// let loaded = StructuralFilterIndex::load(saved.as_slice(), COLLECTION)?;
+    /// assert_eq!(loaded.search("{book{title{Trees}}}", 1)?, [0, 1]);
+    /// // a collection with another tree is not the one the index was built from
+    /// let added = [COLLECTION, &["{book{title{Joins}}}"]].concat();
+    /// assert!(matches!(
+    ///     StructuralFilterIndex::load(saved.as_slice(), &added),
+    ///     Err(SearchError::Stale(_))
+    /// ));
+    /// # Ok::<(), SearchError>(())
+    /// ```
+    pub fn load(reader: impl Read, trees: &[&str]) -> Result<Self, SearchError> {
+        let index: Self = serde_json::from_reader(reader)?;
+        let tokens = trees
+            .iter()
+            .map(|tree| tokens(tree))
+            .collect::<Result<Vec<_>, _>>()?;
+        index
+            .fingerprint
+            .as_ref()
+            .ok_or(SearchError::MissingFingerprint)?
+            .check(&CollectionFingerprint::of_tokens(&tokens))?;
+        Ok(index)
+    }
+
+    /// Keeps the labels and region weights the indexed trees were converted with, so
+    /// [`Self::search`] can convert query strings the same way
+    pub fn with_labels(mut self, label_dict: &LabelDict, weights: RegionWeights) -> Self {
+        self.query_labels = Some(QueryLabels {
+            label_dict: label_dict.clone(),
+            ordering: get_frequency_ordering(label_dict),
+            weights,
+        });
+        self
+    }
+
+    /// Positions of the indexed trees in their size order that pass the prefix filter
+    /// with the query tree in bracket notation. The query is parsed with the labels of
+    /// [`Self::with_labels`], its labels missing in them match no tree. The overlap of the
+    /// labels after the prefix is completed from the postings, the label sets of the
+    /// indexed trees are not needed.
+    pub fn search(&self, tree_str: &str, k: usize) -> Result<Vec<usize>, SearchError> {
+        let labels = self
+            .query_labels
+            .as_ref()
+            .ok_or(SearchError::MissingLabels)?;
+        let tokens = tokens(tree_str)?;
+        let unseen = LabelId::from_index(max_label(&labels.label_dict).index() + 1);
+        let query_dict = tokens
+            .iter()
+            .filter(|token| *token != "{" && *token != "}")
+            .map(|label| {
+                let id = labels.label_dict.get(label).map_or(unseen, |(id, _)| *id);
+                (label.clone(), (id, 0))
+            })
+            .collect::<LabelDict>();
+        let tree = parse_tree(&tokens, &query_dict)?;
+        let query = LabelSetConverter::with_weights(labels.weights).create_single(&tree)?;
+        let (candidates, _) = self.prefix_filter(
+            &query,
+            &labels.ordering,
+            k,
+            None,
+            PrefixFilterOptions::default(),
+            None,
+        );
+        Ok(candidates
+            .into_iter()
+            .map(|(_, cid)| cid)
+            .sorted_unstable()
+            .collect())
+    }
+
+    /// Positions of trees not searched until the build writes their shards
+    pub fn pending(&self) -> &[Range<usize>] {
+        &self.pending
+    }
+}
+
+/// Builds persisted shard by shard, for collections whose index takes hours to build
+impl StructuralFilterIndex {
+    /// Builds the index of trees in bracket notation sorted by size into directory `dir`.
+    /// The manifest with the labels, tree sizes and fingerprint of the collection is
+    /// written first, then the postings of every shard of at least `shard_trees` trees of
+    /// consecutive sizes to a file of their own as soon as they are built. A build
+    /// interrupted before it wrote all shards resumes with the missing ones when called
+    /// again with the same trees, a build of other trees fails with
+    /// [`SearchError::Stale`].
+    ///
+    /// ```
+    /// use tree_statistics::example_data::COLLECTION;
+    /// use tree_statistics::{RegionWeights, StructuralFilterIndex};
+    ///
+    /// let dir = std::env::temp_dir().join(format!("example-build-{}", std::process::id()));
+    /// let weights = RegionWeights::default();
+    /// StructuralFilterIndex::build_persisted(COLLECTION, weights, &dir, 4)?;
+    /// // another process queries the shards written so far
+    /// let (index, progress) = StructuralFilterIndex::load_built(&dir, COLLECTION)?;
+    /// assert!(progress.is_complete());
+    /// assert_eq!(index.search("{book{title{Trees}}}", 1)?, [0, 1]);
+    /// std::fs::remove_dir_all(dir)?;
+    /// # Ok::<(), tree_statistics::SearchError>(())
+    /// ```
+    pub fn build_persisted(
+        trees: &[&str],
+        weights: RegionWeights,
+        dir: &Path,
+        shard_trees: usize,
+    ) -> Result<Self, SearchError> {
+        let (tokens, label_dict, trees) = parse_brackets(trees)?;
+        let fingerprint = CollectionFingerprint::of_tokens(&tokens);
+        let manifest_path = dir.join(BUILD_MANIFEST);
+        let manifest = if manifest_path.is_file() {
+            let manifest: BuildManifest = serde_json::from_reader(std::io::BufReader::new(
+                std::fs::File::open(&manifest_path)?,
+            ))?;
+            manifest.fingerprint.check(&fingerprint)?;
+            if manifest.query_labels.weights != weights {
+                return Err(SearchError::BuildWeights(dir.to_owned()));
+            }
+            manifest
+        } else {
+            std::fs::create_dir_all(dir)?;
+            let size_index = SizeBuckets::from_sizes(trees.iter().map(|tree| tree.len()));
+            let manifest = BuildManifest {
+                shards: size_shards(&size_index, shard_trees),
+                size_index,
+                query_labels: QueryLabels {
+                    ordering: get_frequency_ordering(&label_dict),
+                    label_dict,
+                    weights,
+                },
+                fingerprint: fingerprint.clone(),
+            };
+            write_atomically(&manifest_path, &manifest)?;
+            manifest
+        };
+
+        let mut converter = LabelSetConverter::with_weights(weights);
+        for (shard, range) in manifest.shards.iter().enumerate() {
+            let path = shard_path(dir, shard);
+            if path.is_file() {
+                continue;
+            }
+            let sets = converter.create(&trees[range.clone()])?;
+            write_atomically(&path, &Self::postings(&sets, range.start))?;
+        }
+        let (index, _) = Self::read_built(dir, &fingerprint)?;
+        Ok(index)
+    }
+
+    /// Index of the shards a build in `dir` has written so far, for the collection of trees
+    /// in bracket notation it is used with. Queries can start while the build goes on,
+    /// [`Self::search`] returns only trees of the written shards until it completes.
+    pub fn load_built(dir: &Path, trees: &[&str]) -> Result<(Self, BuildProgress), SearchError> {
+        let tokens = trees
+            .iter()
+            .map(|tree| tokens(tree))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::read_built(dir, &CollectionFingerprint::of_tokens(&tokens))
+    }
+
+    fn read_built(
+        dir: &Path,
+        fingerprint: &CollectionFingerprint,
+    ) -> Result<(Self, BuildProgress), SearchError> {
+        let manifest: BuildManifest = serde_json::from_reader(std::io::BufReader::new(
+            std::fs::File::open(dir.join(BUILD_MANIFEST))?,
+        ))?;
+        manifest.fingerprint.check(fingerprint)?;
+        let mut index = IndexPostings::default();
+        let mut pending = vec![];
+        // shards are in increasing order of sizes, appending keeps the postings sorted
+        for (shard, range) in manifest.shards.iter().enumerate() {
+            let path = shard_path(dir, shard);
+            if !path.is_file() {
+                pending.push(range.clone());
+                continue;
+            }
+            let postings: IndexPostings =
+                serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+            for (label, postings) in postings.iter() {
+                let merged = index.get_or_default(label);
+                for (size, item) in postings.iter() {
+                    merged.push(size, item.clone());
+                }
+            }
+        }
+        let progress = BuildProgress {
+            built: manifest.shards.len() - pending.len(),
+            shards: manifest.shards.len(),
+        };
+        Ok((
+            Self {
+                index,
+                size_index: manifest.size_index,
+                query_labels: Some(manifest.query_labels),
+                fingerprint: Some(manifest.fingerprint),
+                tombstones: BTreeSet::new(),
+                pending,
+            },
+            progress,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_saved_index() {
+        use crate::parsing::parse_single;
+        let mut ld = LabelDict::new();
+        let trees = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{a{c}{b}}",
+            "{x{b}{c}}",
+            "{a{b{c}}{d}}",
+            "{a{b}{c}{d}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let sets = LabelSetConverter::default().create(&trees).unwrap();
+        let index = StructuralFilterIndex::new(&sets);
+        assert!(matches!(
+            index.search("{a}", 1),
+            Err(SearchError::MissingLabels)
+        ));
+        let index = index.with_labels(&ld, RegionWeights::default());
+        let saved = serde_json::to_string(&index).unwrap();
+        let index: StructuralFilterIndex = serde_json::from_str(&saved).unwrap();
+        let ordering = crate::parsing::get_frequency_ordering(&ld);
+        for k in 0..4 {
+            for (tree, query) in ["{a{b}{c}}", "{a{c}{b}}", "{x{b}{c}}"]
+                .iter()
+                .zip(&sets[1..])
+            {
+                let (candidates, _) =
+                    index.query_index_prefix(query, &ordering, k, &sets, None, Default::default());
+                let expected = candidates
+                    .into_iter()
+                    .map(|(_, c)| c)
+                    .sorted()
+                    .collect_vec();
+                assert_eq!(index.search(tree, k).unwrap(), expected, "{tree} k={k}");
+            }
+        }
+        // an unknown label matches no node, z is deleted or renamed to c
+        assert_eq!(index.search("{a{b}{z}}", 0).unwrap(), Vec::<usize>::new());
+        assert_eq!(index.search("{a{b}{z}}", 1).unwrap(), [0, 1]);
+        assert!(matches!(index.search("{a", 1), Err(SearchError::Parse(_))));
+
+        // the fingerprint of tokens is the one of the parsed trees
+        let (tokens, parsed_ld, parsed) = parse_brackets(&["{a}", "{a{b}}", "{x{y}{z}}"]).unwrap();
+        assert_eq!(
+            CollectionFingerprint::of_tokens(&tokens),
+            CollectionFingerprint::of_trees(&parsed, &parsed_ld)
+        );
+
+        let index = StructuralFilterIndex::from_brackets(
+            &["{a}", "{a{b}}", "{x{y}{z}}"],
+            RegionWeights::default(),
+        )
+        .unwrap();
+        assert_eq!(index.search("{a{b}}", 0).unwrap(), [1]);
+        assert_eq!(index.search("{a{b}}", 1).unwrap(), [0, 1]);
+
+        let mut saved = vec![];
+        index.save(&mut saved).unwrap();
+        let loaded =
+            StructuralFilterIndex::load(saved.as_slice(), &["{x{y}{z}}", "{a}", "{a{b}}"]).unwrap();
+        assert_eq!(loaded.search("{a{b}}", 1).unwrap(), [0, 1]);
+        assert!(matches!(
+            StructuralFilterIndex::load(saved.as_slice(), &["{a}", "{a{b}}", "{x{y}{w}}"]),
+            Err(SearchError::Stale(FingerprintMismatch::Labels))
+        ));
+        // saved without a fingerprint, it cannot be checked
+        let unchecked = serde_json::to_vec(&StructuralFilterIndex::new(&sets)).unwrap();
+        assert!(matches!(
+            StructuralFilterIndex::load(unchecked.as_slice(), &["{a}"]),
+            Err(SearchError::MissingFingerprint)
+        ));
+    }
+
+    #[test]
+    fn test_saved_tombstones() {
+        let trees = ["{a}", "{a{b}}", "{a{c}}", "{a{b}{c}}", "{x{y}{z}}"];
+        let mut index =
+            StructuralFilterIndex::from_brackets(&trees, RegionWeights::default()).unwrap();
+        assert_eq!(index.search("{a{b}}", 1).unwrap(), [0, 1, 2, 3]);
+        index.tombstone([2, 4]).unwrap();
+        assert_eq!(index.search("{a{b}}", 1).unwrap(), [0, 1, 3]);
+        assert!(matches!(
+            index.tombstone([5]).map_err(SearchError::from),
+            Err(SearchError::OutOfRange(_))
+        ));
+        // tombstones are saved, the index still belongs to the collection with the trees
+        let mut saved = vec![];
+        index.save(&mut saved).unwrap();
+        let loaded = StructuralFilterIndex::load(saved.as_slice(), &trees).unwrap();
+        assert_eq!(loaded.tombstones(), &BTreeSet::from([2, 4]));
+        assert_eq!(loaded.search("{a{b}}", 1).unwrap(), [0, 1, 3]);
+
+        let compacted = loaded.compact();
+        assert_eq!(compacted.search("{a{b}}", 1).unwrap(), [0, 1, 2]);
+        let mut saved = vec![];
+        compacted.save(&mut saved).unwrap();
+        let remaining = ["{a}", "{a{b}}", "{a{b}{c}}"];
+        StructuralFilterIndex::load(saved.as_slice(), &remaining).unwrap();
+        assert!(matches!(
+            StructuralFilterIndex::load(saved.as_slice(), &trees),
+            Err(SearchError::Stale(FingerprintMismatch::Trees { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_persisted_build() {
+        let trees = ["{a}", "{b}", "{a{b}}", "{a{c}}", "{a{b}{c}}", "{x{y}{z}}"];
+        let dir = std::env::temp_dir().join(format!("index-build-{}", std::process::id()));
+        let weights = RegionWeights::default();
+        let built = StructuralFilterIndex::build_persisted(&trees, weights, &dir, 2).unwrap();
+        let full = StructuralFilterIndex::from_brackets(&trees, weights).unwrap();
+        for query in ["{a{b}}", "{x{y}}", "{q}"] {
+            assert_eq!(
+                built.search(query, 1).unwrap(),
+                full.search(query, 1).unwrap()
+            );
+        }
+
+        // the build was interrupted before its second shard, trees of sizes 1 and 3 only
+        std::fs::remove_file(shard_path(&dir, 1)).unwrap();
+        let (partial, progress) = StructuralFilterIndex::load_built(&dir, &trees).unwrap();
+        assert_eq!(
+            progress,
+            BuildProgress {
+                built: 2,
+                shards: 3
+            }
+        );
+        assert_eq!(partial.pending().len(), 1);
+        assert_eq!(partial.pending()[0], 2..4);
+        assert_eq!(partial.search("{a{b}}", 1).unwrap(), [0, 1, 4]);
+        assert!(matches!(
+            StructuralFilterIndex::load_built(&dir, &trees[1..]),
+            Err(SearchError::Stale(_))
+        ));
+
+        let resumed = StructuralFilterIndex::build_persisted(&trees, weights, &dir, 2).unwrap();
+        let (_, progress) = StructuralFilterIndex::load_built(&dir, &trees).unwrap();
+        assert!(progress.is_complete());
+        assert_eq!(
+            resumed.search("{a{b}}", 1).unwrap(),
+            full.search("{a{b}}", 1).unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! Library bindings of the lower bounds, the command line tool lives in `main.rs`.
+//!
+//! The library always exposes [`pair::pair_distances`] of two trees in bracket notation,
+//! the bounded string edit distance of any token sequences, and the [`traversals`] and
+//! structural comparisons in [`tree_cmp`] of trees with any node labels.
+//! The `capi` feature adds a C API, the `wasm` feature a JS API through wasm-bindgen and
+//! the `polars` feature returns statistics and candidates as DataFrames. The `bench`
+//! feature exposes the kernel workloads and tree generator of the criterion benchmarks.
+//!
+//! A similarity join indexes the collection once, the structural filter returns the
+//! candidates of every query without missing any result and the exact distance of the
+//! candidates decides the results. [`example_data`] holds a small collection with its
+//! queries and ground truth:
+//!
+//! ```
+//! use tree_statistics::example_data::{COLLECTION, QUERIES, RESULTS};
+//! use tree_statistics::{pair, RegionWeights, StructuralFilterIndex};
+//!
+//! // trees in bracket notation sorted by size, parsed and indexed with their labels
+//! let index = StructuralFilterIndex::from_brackets(COLLECTION, RegionWeights::default())?;
+//! let mut results = vec![];
+//! for (qid, (k, query)) in QUERIES.iter().enumerate() {
+//!     // positions of the trees passing the filter, a superset of the results
+//!     let candidates = index.search(query, *k)?;
+//!     for tid in candidates {
+//!         let ted = pair::pair_distances(query, COLLECTION[tid], true)?.ted.unwrap();
+//!         if ted <= *k {
+//!             results.push((qid, tid, ted));
+//!         }
+//!     }
+//! }
+//! assert_eq!(results, RESULTS);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod collection_fingerprint;
+#[allow(dead_code)]
+mod indexing;
+#[allow(dead_code)]
+mod memory;
+#[allow(dead_code)]
+mod parsing;
+#[cfg(any(feature = "wasm", feature = "polars"))]
+#[allow(dead_code)]
+mod statistics;
+#[allow(dead_code)]
+mod ted;
+#[cfg(any(feature = "capi", feature = "polars"))]
+mod tree_index;
+#[allow(dead_code)]
+mod lb {
+    pub mod binary_branch;
+    pub mod label_intersection;
+    pub mod label_postings;
+    pub mod label_signature;
+    pub mod sed;
+    pub mod size_buckets;
+    pub mod structural_filter;
+    pub mod structural_search;
+}
+
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "polars")]
+pub mod dataframe;
+pub mod example_data;
+#[cfg(feature = "bench")]
+pub mod generate;
+#[cfg(feature = "bench")]
+pub mod microbench;
+pub mod pair;
+pub mod traversals;
+pub mod tree_cmp;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use collection_fingerprint::{CollectionFingerprint, FingerprintMismatch};
+pub use lb::sed::{bounded_string_edit_distance, SedEvaluator};
+pub use lb::structural_filter::{OutOfRange, RegionWeights, StructuralFilterIndex};
+pub use lb::structural_search::{BuildProgress, SearchError};
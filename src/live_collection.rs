@@ -0,0 +1,255 @@
+//! Tree collection of the servers that trees are inserted into and deleted from at runtime.
+//!
+//! Ids of trees loaded from the dataset are their positions in the size sorted
+//! collection, the same as in candidate files. Inserted trees get the following ids in
+//! order of insertion and ids of deleted trees are not reused. Trees are looked up by
+//! size in a size index, per tree indexes are built only for the served methods.
+use crate::collection;
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::label_intersection_k;
+use crate::lb::sed::sed_k;
+use crate::lb::structural_filter::{ted as struct_ted_k, LabelSetConverter, StructuralFilterTuple};
+use crate::memory::MemoryUsage;
+use crate::parsing::{self, LabelDict, ParsedTree, TreeParseError};
+use crate::query_timeout::{Deadline, CHECK_EVERY};
+use crate::LowerBoundMethods;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A tree with its indexes of the served methods
+struct Entry {
+    tree: ParsedTree,
+    traversals: Option<SEDIndex>,
+    label_set: Option<InvertedListLabelPostorderIndex>,
+    structural_set: Option<StructuralFilterTuple>,
+}
+
+pub struct LiveCollection {
+    label_dict: LabelDict,
+    methods: Vec<LowerBoundMethods>,
+    /// trees by id, `None` once deleted
+    entries: Vec<Option<Entry>>,
+    /// ids of live trees by tree size, in increasing order
+    sizes: BTreeMap<usize, Vec<usize>>,
+    live: usize,
+}
+
+impl LiveCollection {
+    /// Indexes trees for `methods`, trees have to be sorted by size. Methods other than
+    /// the label intersection, string edit distance and structural bounds are rejected.
+    pub fn new(
+        trees: Vec<ParsedTree>,
+        label_dict: LabelDict,
+        methods: &[LowerBoundMethods],
+    ) -> Result<Self, anyhow::Error> {
+        use LowerBoundMethods as LBM;
+        if let Some(method) = methods
+            .iter()
+            .find(|m| !matches!(m, LBM::Lblint | LBM::Sed | LBM::Structural))
+        {
+            anyhow::bail!("Method {method:?} can not be served");
+        }
+        if !collection::is_size_sorted(&trees) {
+            anyhow::bail!("Served trees have to be sorted by size");
+        }
+        let mut collection = Self {
+            label_dict,
+            methods: methods.to_vec(),
+            entries: Vec::with_capacity(trees.len()),
+            sizes: BTreeMap::new(),
+            live: 0,
+        };
+        let entries = trees
+            .into_par_iter()
+            .map(|tree| collection.index(tree))
+            .collect::<Result<Vec<_>, _>>()?;
+        for entry in entries {
+            collection.push(entry);
+        }
+        Ok(collection)
+    }
+
+    fn index(&self, tree: ParsedTree) -> Result<Entry, IndexError> {
+        let serves = |method| self.methods.contains(&method);
+        let traversals = (serves(LowerBoundMethods::Sed) || serves(LowerBoundMethods::Lblint))
+            .then(|| SEDIndex::index_tree(&tree, &self.label_dict))
+            .transpose()?;
+        let label_set = traversals
+            .as_ref()
+            .filter(|_| serves(LowerBoundMethods::Lblint))
+            .map(InvertedListLabelPostorderIndex::from_traversals);
+        let structural_set = serves(LowerBoundMethods::Structural)
+            .then(|| LabelSetConverter::default().create_single(&tree))
+            .transpose()?;
+        Ok(Entry {
+            traversals: traversals.filter(|_| serves(LowerBoundMethods::Sed)),
+            tree,
+            label_set,
+            structural_set,
+        })
+    }
+
+    fn push(&mut self, entry: Entry) -> usize {
+        let id = self.entries.len();
+        self.sizes.entry(entry.tree.count()).or_default().push(id);
+        self.entries.push(Some(entry));
+        self.live += 1;
+        id
+    }
+
+    pub fn label_dict(&self) -> &LabelDict {
+        &self.label_dict
+    }
+
+    pub fn methods(&self) -> &[LowerBoundMethods] {
+        &self.methods
+    }
+
+    /// Number of trees not deleted
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn tree(&self, id: usize) -> Option<&ParsedTree> {
+        self.entries.get(id)?.as_ref().map(|e| &e.tree)
+    }
+
+    /// Adds labels of the tree to the label dictionary and indexes it, returns its id
+    pub fn insert(&mut self, tree: &str) -> Result<usize, anyhow::Error> {
+        if !tree.is_ascii() {
+            return Err(TreeParseError::IsNotAscii.into());
+        }
+        let tokens = parsing::parse_tree_tokens(tree.to_owned())?;
+        parsing::update_label_dict(std::slice::from_ref(&tokens), &mut self.label_dict);
+        let tree = parsing::parse_tree(&tokens, &self.label_dict)?;
+        let entry = self.index(tree)?;
+        Ok(self.push(entry))
+    }
+
+    /// Removes the tree from the size index and drops its indexes, labels stay in the
+    /// label dictionary. Returns `false` for unknown or already deleted trees.
+    pub fn delete(&mut self, id: usize) -> bool {
+        let Some(entry) = self.entries.get_mut(id).and_then(Option::take) else {
+            return false;
+        };
+        let size = entry.tree.count();
+        let ids = self
+            .sizes
+            .get_mut(&size)
+            .expect("live trees are in the size index");
+        ids.retain(|other| *other != id);
+        if ids.is_empty() {
+            self.sizes.remove(&size);
+        }
+        self.live -= 1;
+        true
+    }
+
+    /// Ids of trees within the size range of the query whose lower bound is at most `k`,
+    /// in increasing order. The method has to be served. Candidates stop at the deadline,
+    /// returns whether they stopped and are partial.
+    pub fn candidates_until(
+        &self,
+        query: &ParsedTree,
+        k: usize,
+        method: LowerBoundMethods,
+        deadline: Option<Deadline>,
+    ) -> Result<(Vec<usize>, bool), anyhow::Error> {
+        use LowerBoundMethods as LBM;
+        if !self.methods.contains(&method) {
+            anyhow::bail!("Method {method:?} is not served");
+        }
+        let size = query.count();
+        let ids = self
+            .sizes
+            .range(size.saturating_sub(k)..=size + k)
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect::<Vec<_>>();
+        let entries = &self.entries;
+        let timed_out = AtomicBool::new(false);
+        let filter = |bound: &(dyn Fn(&Entry) -> usize + Sync)| {
+            let mut candidates = ids
+                .par_chunks(CHECK_EVERY)
+                .flat_map_iter(|chunk| {
+                    let stop = deadline.is_some_and(|d| d.expired());
+                    if stop {
+                        timed_out.store(true, Ordering::Relaxed);
+                    }
+                    chunk
+                        .iter()
+                        .copied()
+                        .filter(move |id| !stop && bound(entries[*id].as_ref().unwrap()) <= k)
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+            candidates
+        };
+        let candidates = match method {
+            LBM::Lblint => {
+                let q = InvertedListLabelPostorderIndex::index_tree(query, &self.label_dict)?;
+                filter(&|e| label_intersection_k(&q, e.label_set.as_ref().unwrap(), k))
+            }
+            LBM::Sed => {
+                let q = SEDIndex::index_tree(query, &self.label_dict)?;
+                filter(&|e| sed_k(&q, e.traversals.as_ref().unwrap(), k))
+            }
+            LBM::Structural => {
+                let q = LabelSetConverter::default().create_single(query)?;
+                filter(&|e| struct_ted_k(&q, e.structural_set.as_ref().unwrap(), k))
+            }
+            _ => unreachable!("only methods checked in new are served"),
+        };
+        Ok((candidates, timed_out.into_inner()))
+    }
+
+    /// Estimated bytes of indexes of a served method of live trees
+    pub fn index_bytes(&self, method: LowerBoundMethods) -> usize {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|e| match method {
+                LowerBoundMethods::Lblint => e.label_set.estimated_bytes(),
+                LowerBoundMethods::Sed => e.traversals.estimated_bytes(),
+                LowerBoundMethods::Structural => e.structural_set.estimated_bytes(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        let mut collection =
+            LiveCollection::new(trees, ld, &LowerBoundMethods::REGISTERED).unwrap();
+        let id = collection.insert("{a{b}{d}}").unwrap();
+        assert_eq!(id, 3);
+        assert!(collection.label_dict().contains_key("d"));
+        assert!(collection.insert("a").is_err());
+
+        let query = parse_single("{a{b}{d}}".to_owned(), &mut collection.label_dict.clone());
+        for method in LowerBoundMethods::REGISTERED {
+            let (candidates, _) = collection
+                .candidates_until(&query, 1, method, None)
+                .unwrap();
+            assert_eq!(candidates, vec![1, 2, 3], "{method:?}");
+        }
+        assert!(collection.delete(2));
+        assert!(!collection.delete(2));
+        assert!(!collection.delete(7));
+        assert_eq!(collection.live(), 3);
+        let (candidates, _) = collection
+            .candidates_until(&query, 1, LowerBoundMethods::Sed, None)
+            .unwrap();
+        assert_eq!(candidates, vec![1, 3]);
+    }
+}
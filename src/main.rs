@@ -1,44 +1,220 @@
-use crate::indexing::{Indexer, InvertedListLabelPostorderIndex, SEDIndex};
-use crate::parsing::{tree_to_string, LabelDict, TreeOutput};
-use crate::statistics::TreeStatistics;
+use crate::checkpoint::Checkpointer;
+use crate::collection::CollectionView;
+use crate::collection_fingerprint::CollectionFingerprint;
+use crate::indexing::{index_queries, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::memory::MemoryUsage;
+use crate::parsing::{tree_to_string, LabelDict, LabelDictMerge, LabelId, RemapLabels, TreeOutput};
+use crate::statistics::StatisticsColumns;
 use clap::error::ErrorKind;
 use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use itertools::Itertools;
 use lb::indexes;
-use lb::label_intersection::{self, label_intersection_k};
-use lb::sed::sed_k;
-use lb::structural_filter::{self, ted as struct_ted_k, LabelSetConverter};
+use lb::label_intersection;
+use lb::sed::SedEvaluator;
+use lb::structural_filter;
 use parsing::get_frequency_ordering;
 use rayon::prelude::*;
-use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::{create_dir_all, File};
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::process::{self, exit};
+use std::process::ExitCode;
 use std::time::{Duration, Instant};
 
+mod alloc_stats;
+mod anonymize;
+mod bench;
+mod calibrate;
+mod candidate_file;
+mod checkpoint;
+mod cluster;
+mod collection;
+mod collection_fingerprint;
+#[cfg(feature = "arrow")]
+mod columnar;
+mod common_subtree;
+mod dedup;
+mod dump_index;
+mod edit_script;
+mod edits;
+mod error;
+mod events;
+mod explain;
+mod export;
+mod external_sort;
+#[cfg(feature = "tree-sitter")]
+mod from_source;
+mod generate;
+mod golden;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hashing;
+mod hists_output;
+#[cfg(feature = "html")]
+mod html;
 mod indexing;
+mod join;
+mod knn;
+mod label_ordering;
+mod label_weights;
 mod lb;
+#[cfg(any(feature = "serve", feature = "grpc"))]
+mod live_collection;
+mod memory;
+mod metadata;
+mod method_args;
+mod microbench;
+mod number_format;
+mod pair;
+mod pair_sizes;
+mod pairs;
 mod parsing;
+mod plan;
+mod pq_gram_distance;
+mod pretty;
+mod query_cache;
+mod query_groups;
+#[cfg(any(feature = "serve", feature = "grpc"))]
+mod query_service;
+mod query_timeout;
+mod reorder;
+#[cfg(feature = "sqlite")]
+mod result_store;
+mod routing;
+mod run_info;
+mod self_test;
+#[cfg(feature = "serve")]
+mod serve;
+mod shard;
+mod size_cap;
+mod split;
 mod statistics;
+mod ted;
+mod traversals;
+mod tree_diff;
+mod tune;
 mod validation;
+mod verifier;
+mod verify_sample;
+mod vp_tree;
+#[cfg(any(feature = "serve", feature = "grpc"))]
+mod wal;
+mod workload;
+
+#[cfg(feature = "peak-memory")]
+#[global_allocator]
+static ALLOCATOR: alloc_stats::CountingAllocator = alloc_stats::CountingAllocator;
 
 /// Tree statistics utility
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
-    /// Dataset file of trees in bracket notation
+    /// Dataset file of trees in bracket notation, required by all commands
+    /// except those that synthesize data
     #[arg(short, long, value_name = "FILE")]
-    dataset_path: PathBuf,
+    dataset_path: Option<PathBuf>,
+    /// How trees are read from the dataset path
+    #[arg(long, value_enum, default_value_t = Input::Bracket)]
+    input: Input,
+    /// Language of source files read with `--input from-source`
+    #[cfg(feature = "tree-sitter")]
+    #[arg(long, value_enum, required_if_eq("input", "from-source"))]
+    language: Option<from_source::Language>,
+    /// Includes element ids in labels of `--input html`, e.g. `div#main`
+    #[cfg(feature = "html")]
+    #[arg(long, default_value_t = false)]
+    html_ids: bool,
+    /// Includes sorted element classes in labels of `--input html`, e.g. `div.nav.top`
+    #[cfg(feature = "html")]
+    #[arg(long, default_value_t = false)]
+    html_classes: bool,
+    /// How label ids are assigned to labels of the dataset
+    #[arg(long, value_enum, default_value_t = LabelIds::FirstOccurrence)]
+    label_ids: LabelIds,
     /// outputs only collected statistics
     #[arg(long, default_value_t = false)]
     quiet: bool,
+    /// Number of worker threads used for parsing and all parallel work,
+    /// defaults to number of logical CPUs
+    #[arg(long, short = 't', global = true)]
+    threads: Option<usize>,
+    /// Memory budget, e.g. 512M or 4G. Lower bound candidate generation switches
+    /// to indexing trees in chunks when estimated indexes exceed it, validation and
+    /// candidate conversions sort candidates beyond it in runs on disk
+    #[arg(long, global = true, visible_alias = "memory-limit", value_name = "BYTES", value_parser = memory::parse_byte_size)]
+    max_memory: Option<usize>,
+    /// SQLite database the run, its candidates, timings and validation metrics are added to
+    #[cfg(feature = "sqlite")]
+    #[arg(long, global = true, value_name = "DB")]
+    store: Option<PathBuf>,
+    /// Newline delimited JSON file every phase and the candidate count of every query
+    /// are streamed into as the run goes
+    #[arg(long, global = true, value_name = "FILE")]
+    events: Option<PathBuf>,
+    /// Closes brackets left open at the end of truncated dataset lines, with a warning
+    /// naming the line, instead of skipping the lines
+    #[arg(long, global = true, default_value_t = false)]
+    recover_brackets: bool,
+    /// Reads every dataset and query line as a forest, its top level trees are joined by
+    /// a virtual root labelled `#forest`
+    #[arg(long, global = true, default_value_t = false)]
+    forests: bool,
+    /// Largest number of nodes of a dataset tree, larger trees are handled by `--oversized`
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_tree_size: Option<u64>,
+    /// What happens to dataset trees of more than `--max-tree-size` nodes
+    #[arg(long, global = true, value_enum, default_value_t = size_cap::OversizedPolicy::Skip)]
+    oversized: size_cap::OversizedPolicy,
+    /// Directory of golden snapshots, the summary, candidate counts and precisions of a run
+    /// are recorded on its first run and compared with on later runs, failing on drift
+    #[arg(long, global = true, value_name = "DIR")]
+    golden: Option<PathBuf>,
+    /// Records the snapshot of the run as golden instead of comparing with it
+    #[arg(long, global = true, requires = "golden")]
+    update_golden: bool,
+    /// Decimals of floats in summary lines and CSV and JSON outputs, which always use a
+    /// dot as the decimal separator
+    #[arg(long, global = true, value_name = "DIGITS", default_value_t = number_format::DEFAULT_PRECISION)]
+    float_precision: usize,
+    /// Prints the summaries of `lower-bound` and `validate` as aligned tables, colored on a
+    /// terminal, instead of the raw lines scripts read
+    #[arg(long, global = true, default_value_t = false)]
+    pretty: bool,
+    /// Prints the plan of the run, its dataset, methods, indexes, estimated memory and
+    /// output files, after parsing the inputs instead of running it
+    #[arg(long, global = true, default_value_t = false)]
+    dry_run: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Input {
+    /// One tree in bracket notation per line
+    Bracket,
+    /// Source files of `--language`, the dataset path is a file or a directory searched
+    /// recursively. One syntax tree per file with node kinds as labels
+    #[cfg(feature = "tree-sitter")]
+    FromSource,
+    /// Web pages, the dataset path is a file or a directory searched recursively for
+    /// `.html` files. One DOM tree per page with tag names as labels
+    #[cfg(feature = "html")]
+    Html,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LabelIds {
+    /// In order of the first occurrence of a label in the dataset file
+    FirstOccurrence,
+    /// In lexicographic order of labels, the same for any order of trees in the file
+    Sorted,
+    /// By descending frequency of labels in the dataset, frequent labels get the smallest
+    /// ids and postings of the label indexes looked up by id instead of hashing
+    Frequency,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
 enum LowerBoundMethods {
     /// Histogram lower bound
     Hist,
@@ -46,14 +222,37 @@ enum LowerBoundMethods {
     Lblint,
     /// String edit distance lower bound
     Sed,
+    /// String edit distance lower bound looked up in a BK-tree instead of a scan
+    SedBkTree,
+    /// q-gram count lower bound of the preorder and postorder strings of the string edit
+    /// distance bound, cheaper and looser than the bounded string edit distance
+    SedQgram,
+    /// String edit distance lower bound computed without a threshold, also writes every
+    /// query's trees ranked by it
+    SedUnbounded,
     /// Structural filter lower bound
     Structural,
-    /// Structural variant filter lower bound
-    StructuralVariant,
+    /// Structural filter lower bound with region counts split by groups of labels
+    #[value(alias = "structural-variant")]
+    StructuralSplit,
     /// Binary branch lower bound
     Bib,
+    /// Method or cascade of methods with the lowest estimated cost on a sample of queries
+    Auto,
+    /// Cascade of methods ordered by time per pruned pair measured on a sample of queries
+    Tuned,
+    /// Every query with the method named in its query file line, see `routing`
+    #[value(skip)]
+    Routed,
+}
+
+impl LowerBoundMethods {
+    /// Methods that have a complete candidate generation implementation
+    const REGISTERED: [Self; 3] = [Self::Lblint, Self::Sed, Self::Structural];
 }
 
+// parsed once per run, the size of the arguments of lower-bound does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// outputs data for degree, leaf paths and labels histograms
@@ -61,18 +260,88 @@ enum Commands {
         /// outputs data for degree, leaf paths and labels histograms
         #[arg(long)]
         hists: Option<PathBuf>,
+        /// Prefix of the histogram file names, e.g. the collection name
+        #[arg(long, default_value = "", requires = "hists")]
+        hists_prefix: String,
+        /// What to do with histogram files that already exist
+        #[arg(long, value_enum, default_value_t, requires = "hists")]
+        hists_existing: hists_output::ExistingFiles,
+        /// Compresses histogram files with gzip, requires the `gzip` feature
+        #[arg(long, requires = "hists")]
+        gzip: bool,
+        /// writes statistics of each tree into a Parquet file, requires the `arrow` feature
+        #[arg(long)]
+        parquet: Option<PathBuf>,
+        /// Adds the dataset to the collection aggregated in the state file and prints
+        /// statistics of the whole collection, the file is created if missing and updated
+        #[arg(long, value_name = "STATE")]
+        append_stats: Option<PathBuf>,
+        /// Number of trees a worker gathers statistics of at once into shared buffers
+        #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u64).range(1..))]
+        chunk_size: u64,
+    },
+    /// Writes leaf distance, degree and label histograms of every tree as sparse
+    /// `tree_id,key,count` CSV files, the features of the histogram filter
+    Histograms {
+        /// Output directory of `leaf_histograms.csv`, `degree_histograms.csv` and
+        /// `label_histograms.csv`, label keys are label ids
+        #[arg(long, short = 'o')]
+        output: PathBuf,
     },
     /// Gets pre- and post- order traversals of each tree
     Traversals {
         /// output path for traversals
         #[arg(long)]
         output: PathBuf,
+        /// Traversals written for every tree, one line each in the given order, defaults
+        /// to preorder and postorder
+        #[arg(long, value_enum, value_delimiter = ',')]
+        traversals: Vec<traversals::Traversal>,
+        /// Separator of nodes in a traversal
+        #[arg(long, default_value = ";")]
+        separator: String,
+        /// Writes original label strings instead of label ids
+        #[arg(long, default_value_t = false)]
+        labels: bool,
+        /// Appends numbers of nodes to the left, ancestors, nodes to the right and
+        /// descendants of the structural filter to every node, separated by `:`
+        #[arg(long, default_value_t = false)]
+        structure: bool,
+    },
+    /// Writes the index entries of selected trees as JSON to debug filter results
+    DumpIndex {
+        /// Index whose entries are written
+        #[arg(value_enum)]
+        index: dump_index::DumpedIndex,
+        /// Dumped trees, ids are positions in the size sorted collection
+        #[arg(long, value_delimiter = ',', required = true)]
+        tree_ids: Vec<usize>,
+        /// Output JSON file, written to stdout if missing
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+        /// Weights of regions of the structural filter, see lower-bound --region-weights
+        #[arg(long, value_name = "WEIGHTS", default_value = "1,1,1,1")]
+        region_weights: structural_filter::RegionWeights,
     },
     /// Calculates lower bound candidates
     LowerBound {
-        /// Query file input, on each file <Threshold>,<Query tree>
-        #[arg(long, short = 'q')]
-        query_file: PathBuf,
+        /// Query file input, on each file <Threshold>;<Query tree> or
+        /// <Id>;<Threshold>;<Method>;<Query tree>. Queries naming a method run with it in
+        /// a single routed run, the others with the given method
+        #[arg(long, short = 'q', required_unless_present = "query_ids")]
+        query_file: Option<PathBuf>,
+        /// Dataset trees used as queries instead of a query file, ids are positions in the
+        /// size sorted collection. Candidates of a query leave out its own tree
+        #[arg(
+            long,
+            value_delimiter = ',',
+            conflicts_with = "query_file",
+            requires = "threshold"
+        )]
+        query_ids: Vec<usize>,
+        /// Threshold of queries given by --query-ids
+        #[arg(long, short = 'k', requires = "query_ids")]
+        threshold: Option<usize>,
         /// output path for lower bound candidates
         #[arg(long, short = 'o')]
         output: PathBuf,
@@ -85,6 +354,808 @@ enum Commands {
         /// Q size for QGrams for SED indexing
         #[arg(long = "qgram-size")]
         q: Option<usize>,
+        /// Largest Q for SED indexing, long queries are chunked by the largest Q that still
+        /// leaves more chunks than their threshold
+        #[arg(long = "qgram-max-size", value_name = "Q")]
+        max_q: Option<usize>,
+        /// Which side of the SED index is split into non-overlapping Q chunks
+        #[arg(long, value_enum, default_value_t = indexes::index_gram::GramScheme::QueryChunks)]
+        qgram_scheme: indexes::index_gram::GramScheme,
+        /// Count matching Q chunks without their positions in the SED index
+        #[arg(long, default_value_t = false)]
+        qgram_unpositioned: bool,
+        /// Parameter of a single method as `<method>.<parameter>=<value>`, e.g. `sed.q=3`
+        /// or `structural-split.groups=2`, repeated for more parameters. Overrides the
+        /// dedicated flags of the same parameters
+        #[arg(long = "method-arg", value_name = "KEY=VALUE")]
+        method_args: Vec<method_args::MethodArg>,
+        /// Save a checkpoint of candidate generation after every N queries
+        #[arg(long, value_name = "N")]
+        checkpoint_every: Option<usize>,
+        /// Resume candidate generation from the last checkpoint in the output directory, a
+        /// checkpoint saved for other trees or queries is an error
+        #[arg(long, default_value_t = false)]
+        resume: bool,
+        /// Stops the scan of a query after MS milliseconds, its candidates are partial and
+        /// the query is listed in `<name>.timed_out.csv`. Queries are then scanned one by one
+        #[arg(long, value_name = "MS")]
+        per_query_timeout: Option<u64>,
+        /// Scans the collection once per group of queries with identical trees at the
+        /// largest threshold of the group and rechecks its candidates at smaller thresholds
+        #[arg(long, default_value_t = false)]
+        group_queries: bool,
+        /// Clusters trees by a MinHash signature of their labels into N partitions and
+        /// compares queries only with trees of the same or adjacent partitions. Misses
+        /// results, with --results-path the recall is reported
+        #[arg(long, value_name = "N", conflicts_with_all = ["shard", "group_queries"])]
+        label_clusters: Option<usize>,
+        /// Partitions on either side of the partition of a query compared with it
+        #[arg(
+            long,
+            value_name = "R",
+            default_value_t = 1,
+            requires = "label_clusters"
+        )]
+        cluster_reach: usize,
+        /// Computes the methods of the run on the pair of query QID and tree TID only and
+        /// prints why the tree is a candidate of the query or not
+        #[arg(long, value_name = "QID,TID", value_parser = explain::parse_pair, conflicts_with = "shard")]
+        explain: Option<(usize, usize)>,
+        /// Format of written candidate files
+        #[arg(long, value_enum, default_value_t = candidate_file::CandidateFormat::Csv)]
+        candidates_format: candidate_file::CandidateFormat,
+        /// Order of the ids of written candidate pairs, recorded as a header line of CSV and
+        /// TSV files and in the flags of binary files. `unordered` writes the pairs of a self
+        /// join with --query-ids as tree ids, the smaller first
+        #[arg(long, value_enum, default_value_t)]
+        pair_order: candidate_file::PairOrder,
+        /// Writes only the number of candidates of every query to `<name>.counts.csv`
+        /// instead of the candidates, sorting and writing them is skipped
+        #[arg(long, default_value_t = false, conflicts_with = "metadata")]
+        count_only: bool,
+        /// Name of candidate files without the extension, `{method}`, `{k}` and `{dataset}`
+        /// are replaced by the method, the threshold of the queries and the dataset file
+        /// name, e.g. `{dataset}_{method}_k{k}`
+        #[arg(long, default_value = "{method}_candidates")]
+        candidates_name: String,
+        /// Delta encode binary candidate files
+        #[arg(long, default_value_t = false)]
+        delta: bool,
+        /// Compress binary candidate files with zstd
+        #[arg(long, default_value_t = false)]
+        zstd: bool,
+        /// Number of queries the auto and tuned methods profile methods on
+        #[arg(long, value_name = "N", default_value_t = lb::auto::DEFAULT_SAMPLE)]
+        auto_sample: usize,
+        /// Computes only candidates of trees of shard i out of n, shards split the size
+        /// sorted collection. Shard outputs are combined with merge-candidates
+        #[arg(long, value_name = "i/n")]
+        shard: Option<shard::Shard>,
+        /// CSV with a header whose first column is the tree id, its other columns are
+        /// appended to candidates of the tree. Requires CSV candidate files
+        #[arg(long, value_name = "FILE")]
+        metadata: Option<PathBuf>,
+        /// Appends sizes, or sizes and depths, of the query and the tree to every candidate
+        /// under a header. Requires CSV candidate files
+        #[arg(long, value_enum, conflicts_with_all = ["metadata", "count_only"])]
+        annotate_pairs: Option<pair_sizes::PairAnnotation>,
+        /// Ordering of labels in prefixes of the label intersection and structural indexes
+        #[arg(long, value_enum, default_value_t = label_ordering::OrderingKind::Ascending)]
+        label_ordering: label_ordering::OrderingKind,
+        /// Reads the label ordering from a file, e.g. `label_ordering.csv` written to the
+        /// output directory by a previous run
+        #[arg(long, value_name = "FILE", conflicts_with = "label_ordering")]
+        label_ordering_file: Option<PathBuf>,
+        /// Matches nodes of labels occurring at most N times in both trees exactly in
+        /// the structural filter instead of greedily, 0 keeps the greedy overlap
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        structural_matching: usize,
+        /// Compares nodes in the structural filter only within a postorder window narrowed
+        /// by the sizes of both trees, needs region weights of at least 1
+        #[arg(long, default_value_t = false)]
+        tight_window: bool,
+        /// Relabels query labels missing in the dataset to one label, they never match and
+        /// only inflate label sets and prefixes of queries
+        #[arg(long, default_value_t = false)]
+        merge_unseen_labels: bool,
+        /// Label groups probed by the prefix filter of the structural index beyond the
+        /// threshold + 1 needed
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        prefix_extra_labels: usize,
+        /// Completes the overlap of trees after the prefix of the structural index only when
+        /// the prefix overlap cannot decide them
+        #[arg(long, default_value_t = false)]
+        prefix_bounded_fallback: bool,
+        /// Weights of regions of the structural filter as `left,ancestors,right,descendants`
+        /// with an optional `,height` of subtrees. Weights above 1 and the height may
+        /// prune pairs within the threshold
+        #[arg(long, value_name = "WEIGHTS", default_value = "1,1,1,1")]
+        region_weights: structural_filter::RegionWeights,
+        /// TSV of label<TAB>weight lines, the label intersection counts nodes with the
+        /// weight of their label, missing labels weigh 1
+        #[arg(long, value_name = "FILE")]
+        label_weights: Option<PathBuf>,
+        /// Verifies a random sample of N candidates with the exact tree edit distance and
+        /// estimates precision and the number of results of all candidates
+        #[arg(long, value_name = "N")]
+        verify_sample: Option<usize>,
+        /// Verifies a sample of N pairs within the size window of their query that are not
+        /// candidates, stratified by query, and estimates the results the candidates miss
+        #[arg(long, value_name = "N")]
+        recall_sample: Option<usize>,
+        /// Confidence level of the intervals of the sampled precision and recall
+        #[arg(long, default_value_t = 0.95)]
+        verify_confidence: f64,
+        /// Seed of the random samples of verified pairs
+        #[arg(long, default_value_t = 42)]
+        verify_seed: u64,
+        /// Verifies candidates with the exact tree edit distance in `--verify-order` for at
+        /// most SECONDS, results are written to `<name>.verified.csv` and candidates left
+        /// to `<name>.unverified.csv`. Once all candidates are verified, results are also
+        /// written to `<name>.results.csv` as exact results for `validate`
+        #[arg(long, value_name = "SECONDS")]
+        verify_budget: Option<f64>,
+        /// Order candidates are verified in within `--verify-budget`
+        #[arg(long, value_enum, default_value_t, requires = "verify_budget")]
+        verify_order: join::VerifyOrder,
+        /// Adds the sizes of the largest common subtree and the largest common embedded
+        /// subtree of the query and the tree to every pair verified within the budget
+        #[arg(long, default_value_t = false, requires = "verify_budget")]
+        common: bool,
+        /// Bounds the tree edit distance of candidates by the string edit distance, label
+        /// intersection and top-down distance instead of computing it, intervals are
+        /// written to `<name>.approximate.csv`
+        #[arg(long, default_value_t = false)]
+        approximate_verify: bool,
+        /// Costs of the exact tree edit distance verifying candidates as
+        /// `delete,insert,rename`, every cost is at least 1
+        #[arg(long, value_name = "COSTS", default_value = "1,1,1")]
+        ted_costs: ted::CostModel,
+        /// Algorithm of the exact tree edit distance verifying sampled and budgeted
+        /// candidates
+        #[arg(long, value_enum, default_value_t)]
+        verifier: verifier::VerifierKind,
+        /// Program of the external verifier, e.g. `ted-bin {t1} {t2}` with the trees in
+        /// bracket notation in place of `{t1}` and `{t2}`, or the jar of the APTED verifier,
+        /// `apted.jar` by default
+        #[arg(long, value_name = "COMMAND")]
+        verifier_command: Option<String>,
+        /// Pairs written to one run of the external batch verifier
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        verifier_batch: usize,
+        /// Re-checks candidates with these tighter methods in order before they are
+        /// verified and written, e.g. `structural,structural-split` after `lblint`
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "METHODS")]
+        recheck: Vec<LowerBoundMethods>,
+    },
+    /// Joins the collection with itself with the histogram filters, writes the candidates
+    /// and filter times of every tree and, with real results, the precision of each filter
+    HistJoin {
+        /// Output directory of `candidates-hist-<filter>-<k>.csv`, `hist_<filter>_us.txt`
+        /// and `precision-hist-<filter>-<k>.txt`
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Threshold of the join
+        #[arg(long, short = 'k')]
+        threshold: usize,
+        /// Histogram filters to run, all of them by default
+        #[arg(long, value_enum, value_delimiter = ',')]
+        filters: Vec<indexes::histograms::HistFilter>,
+        /// Real results path, `t1,t2,distance` lines, enables the precision files
+        #[arg(long)]
+        results_path: Option<PathBuf>,
+    },
+    /// Runs lower bound methods across thresholds and reports time, candidates,
+    /// precision and recall
+    Bench {
+        /// Query file input, on each file <Threshold>;<Query tree>
+        #[arg(long, short = 'q', required_unless_present = "kernels")]
+        query_file: Option<PathBuf>,
+        /// Output file for the report, format is chosen by --format
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Methods to benchmark, defaults to all registered methods
+        #[arg(long, value_enum, value_delimiter = ',')]
+        methods: Vec<LowerBoundMethods>,
+        /// Thresholds overriding those in the query file, e.g. 1,2,4
+        #[arg(long, value_delimiter = ',')]
+        thresholds: Vec<usize>,
+        /// Optional real results path, enables precision and recall columns
+        #[arg(long)]
+        results_path: Option<PathBuf>,
+        /// Report format
+        #[arg(long, value_enum, default_value_t = bench::ReportFormat::Csv)]
+        format: bench::ReportFormat,
+        /// Whether runs reuse warm indexes and caches or start cold
+        #[arg(long, value_enum, default_value_t = bench::CacheMode::Warm)]
+        cache: bench::CacheMode,
+        /// Measured runs of every method and threshold, the fastest and slowest are reported
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        runs: u16,
+        /// Runs these kernels, all without a value, on pairs of dataset trees within every
+        /// threshold instead of the methods on queries
+        #[arg(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            num_args = 0..,
+            requires = "thresholds",
+            conflicts_with_all = ["methods", "results_path"]
+        )]
+        kernels: Option<Vec<microbench::Kernel>>,
+        /// Number of pairs compared by every kernel, also the most dataset trees sampled
+        #[arg(long, default_value_t = 10_000)]
+        pairs: usize,
+        /// Also reports every method on N quantiles of the queries by tree size, e.g. 10
+        /// for deciles
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            conflicts_with = "kernels"
+        )]
+        size_buckets: usize,
+    },
+    /// Cross-validates filter configurations on folds of the queries and reports the
+    /// fastest one reaching a recall on the held out fold
+    Tune {
+        /// Query file input, on each file <Threshold>;<Query tree>
+        #[arg(long, short = 'q')]
+        query_file: PathBuf,
+        /// Real results path with t1,t2,distance records of the queries
+        #[arg(long)]
+        results_path: PathBuf,
+        /// Output file for the selection of every fold, format is chosen by --format
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Report format
+        #[arg(long, value_enum, default_value_t = bench::ReportFormat::Csv)]
+        format: bench::ReportFormat,
+        /// Number of folds the queries are split into
+        #[arg(long, default_value_t = 5)]
+        folds: usize,
+        /// Seed of the shuffle of queries into folds
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+        /// Smallest recall on the training folds of a selected configuration
+        #[arg(long, default_value_t = 1.0)]
+        target_recall: f64,
+        /// Methods alone and in cascades, defaults to the registered methods, the split
+        /// structural filter and the q-gram count bound
+        #[arg(long, value_enum, value_delimiter = ',')]
+        methods: Vec<LowerBoundMethods>,
+        /// Q-gram sizes of the q-gram count bound
+        #[arg(long, value_delimiter = ',', default_values_t = [2, 3, 4])]
+        q_values: Vec<usize>,
+        /// Label groups of the split structural filter
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 3, 4])]
+        split_groups: Vec<usize>,
+        /// Labels probed beyond k + 1 by the prefix filter of the structural index
+        #[arg(long, value_delimiter = ',', default_values_t = [0, 1, 2, 4])]
+        prefix_extra_labels: Vec<usize>,
+    },
+    /// Generates approximate candidates with MinHash signatures and banded hashing,
+    /// similar trees may be missed
+    Lsh {
+        /// Query file input, on each file <Threshold>;<Query tree>
+        #[arg(long, short = 'q')]
+        query_file: PathBuf,
+        /// Output CSV file of candidates
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Multiset the signatures are computed over
+        #[arg(long, value_enum, default_value = "pq-grams")]
+        sketch: lb::lsh::Sketch,
+        /// Number of bands, more bands find more similar pairs
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(1..))]
+        bands: u16,
+        /// Number of signature values per band, more rows give fewer false positives
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+        rows: u16,
+        /// Number of ancestors in a pq-gram
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+        pq_p: u16,
+        /// Number of consecutive children in a pq-gram
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..))]
+        pq_q: u16,
+        /// Optional real results path, reports precision and recall of the candidates
+        #[arg(long)]
+        results_path: Option<PathBuf>,
+    },
+    /// Computes the pq-gram distance of all pairs of dataset trees, or of every query to
+    /// every dataset tree, as a similarity measure instead of a lower bound
+    PqGramDistance {
+        /// Query file input, on each file <Threshold>;<Query tree>, thresholds are
+        /// ignored. Without it all pairs of dataset trees are compared
+        #[arg(long, short = 'q')]
+        query_file: Option<PathBuf>,
+        /// Output CSV file with t1,t2,distance or query_id,tree_id,distance lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Number of ancestors in a pq-gram
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+        pq_p: u16,
+        /// Number of consecutive children in a pq-gram
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..))]
+        pq_q: u16,
+        /// Writes only pairs with a distance up to this, from 0 to 1
+        #[arg(long, default_value_t = 1.0)]
+        max_distance: f64,
+    },
+    /// Answers queries exactly with a vantage point tree over tree edit distance,
+    /// optionally comparing it with the scan of the lower bound method
+    VpTree {
+        /// Query file input, on each file <Threshold>;<Query tree>
+        #[arg(long, short = 'q')]
+        query_file: PathBuf,
+        /// Output CSV file with query_id,tree_id,distance lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Lower bound method filtering trees not pruned by the VP-tree
+        #[arg(long, value_enum, default_value = "sed")]
+        method: LowerBoundMethods,
+        /// Largest number of trees in a leaf
+        #[arg(long, default_value_t = 32)]
+        leaf_size: usize,
+        /// Also answer the queries with the scan and verification and compare times
+        #[arg(long, default_value_t = false)]
+        compare_scan: bool,
+        /// Algorithm of the exact tree edit distance verifying candidates of the scan
+        #[arg(long, value_enum, default_value_t)]
+        verifier: verifier::VerifierKind,
+        /// Program of the external verifiers or the jar of the APTED verifier
+        #[arg(long, value_name = "COMMAND")]
+        verifier_command: Option<String>,
+        /// Pairs written to one run of the external batch verifier
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        verifier_batch: usize,
+    },
+    /// Computes lower bounds and optionally exact TED for an explicit list of pairs
+    Pairs {
+        /// CSV file with t1,t2 tree id pairs
+        #[arg(long, short = 'p')]
+        pairs_file: PathBuf,
+        /// Output CSV file
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Threshold the lower bounds are computed with
+        #[arg(long, short = 'k')]
+        threshold: usize,
+        /// Lower bound methods to compute, defaults to all registered methods
+        #[arg(long, value_enum, value_delimiter = ',')]
+        methods: Vec<LowerBoundMethods>,
+        /// Also compute exact tree edit distance
+        #[arg(long, default_value_t = false)]
+        ted: bool,
+        /// Also compute the sizes of the largest common subtree and the largest common
+        /// embedded subtree, what the trees share
+        #[arg(long, default_value_t = false)]
+        common: bool,
+        /// Number of distinct pairs of trees whose values are cached, 0 disables the cache
+        #[arg(long, default_value_t = 0)]
+        cache_size: usize,
+        /// Size of the q-grams of the `sed-qgram` bound
+        #[arg(long, value_name = "Q", default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        qgram_size: u64,
+    },
+    /// Clusters trees by tree edit distance, pairs within the cutoff are found with a
+    /// lower bound and verified, all other pairs are treated as further apart
+    Cluster {
+        /// Output CSV file with tree_id,cluster lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Distance cutoff, clusters are only merged within it
+        #[arg(long, short = 'k')]
+        threshold: usize,
+        /// Lower bound method used to prune pairs
+        #[arg(long, value_enum, default_value = "sed")]
+        method: LowerBoundMethods,
+        /// How distance of two clusters is computed from distances of their trees
+        #[arg(long, value_enum, default_value = "single")]
+        linkage: cluster::Linkage,
+        /// Also write the verified pairs as t1,t2,distance lines
+        #[arg(long)]
+        matrix: Option<PathBuf>,
+        /// Algorithm of the exact tree edit distance verifying candidates
+        #[arg(long, value_enum, default_value_t)]
+        verifier: verifier::VerifierKind,
+        /// Program of the external verifiers or the jar of the APTED verifier
+        #[arg(long, value_name = "COMMAND")]
+        verifier_command: Option<String>,
+        /// Pairs written to one run of the external batch verifier
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        verifier_batch: usize,
+    },
+    /// Finds the nearest trees of every tree by tree edit distance and writes them as
+    /// an edge list
+    KnnGraph {
+        /// Output CSV file with tree,neighbor,distance lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Number of nearest trees of every tree
+        #[arg(long, short = 'n', default_value_t = 5)]
+        neighbors: usize,
+        /// Lower bound method used to prune candidates
+        #[arg(long, value_enum, default_value = "sed")]
+        method: LowerBoundMethods,
+        /// Leaves out neighbors further apart, limits the search on sparse collections
+        #[arg(long)]
+        max_distance: Option<usize>,
+    },
+    /// Finds the k most similar dataset trees of every query tree by tree edit distance
+    TopKJoin {
+        /// Query file, on each line <Threshold>;<Query tree>, thresholds are ignored
+        #[arg(long, short = 'q')]
+        query_file: PathBuf,
+        /// Output CSV file with query,tree,distance lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Number of most similar trees of every query
+        #[arg(short, default_value_t = 5)]
+        k: usize,
+        /// Lower bound method used to prune candidates
+        #[arg(long, value_enum, default_value = "sed")]
+        method: LowerBoundMethods,
+        /// Leaves out trees further apart, limits the search for queries unlike the dataset
+        #[arg(long)]
+        max_distance: Option<usize>,
+    },
+    /// Joins the dataset with a second dataset, finds all pairs of a tree of each within
+    /// the tree edit distance threshold
+    RsJoin {
+        /// Second dataset in bracket notation, its labels are added to the labels of the
+        /// dataset
+        #[arg(long, value_name = "FILE")]
+        other: PathBuf,
+        /// Output CSV file with tree,other_tree,distance lines, ids are positions in the
+        /// size sorted datasets
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Pairs within this tree edit distance are joined
+        #[arg(long, short = 'k')]
+        threshold: usize,
+        /// Lower bound method used to prune pairs
+        #[arg(long, value_enum, default_value = "sed")]
+        method: LowerBoundMethods,
+        /// Writes candidates of the lower bound without verifying them, their distance
+        /// is left empty
+        #[arg(long, default_value_t = false)]
+        candidates_only: bool,
+        /// Algorithm of the exact tree edit distance verifying candidates
+        #[arg(long, value_enum, default_value_t)]
+        verifier: verifier::VerifierKind,
+        /// Program of the external verifiers or the jar of the APTED verifier
+        #[arg(long, value_name = "COMMAND")]
+        verifier_command: Option<String>,
+        /// Pairs written to one run of the external batch verifier
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        verifier_batch: usize,
+    },
+    /// Suggests thresholds returning a number of results per query from the tree edit
+    /// distances to a random sample of dataset trees
+    Calibrate {
+        /// Query file, on each line <Threshold>;<Query tree>, thresholds are ignored
+        #[arg(long, short = 'q')]
+        query_file: PathBuf,
+        /// Output CSV file with query_id,threshold,estimated_results lines
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Desired number of results of every query
+        #[arg(long, short = 'n')]
+        results: usize,
+        /// Number of dataset trees sampled for every query
+        #[arg(long, default_value_t = 200)]
+        sample: usize,
+        /// Seed of the random sample of dataset trees
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+        /// Writes the queries with their suggested thresholds to a query file
+        #[arg(long, value_name = "FILE")]
+        calibrated_queries: Option<PathBuf>,
+    },
+    /// Finds near duplicate trees and groups them into clusters with a representative tree
+    Dedup {
+        /// Output CSV file with cluster,tree_id,representative lines of duplicate trees
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Trees within this tree edit distance are duplicates
+        #[arg(long, short = 'k', default_value_t = 2)]
+        threshold: usize,
+        /// Lower bound method used to prune pairs, auto picks the cheapest cascade
+        #[arg(long, value_enum, default_value = "auto")]
+        method: LowerBoundMethods,
+        /// Also write the dataset with only representatives of duplicate clusters
+        #[arg(long)]
+        deduplicated: Option<PathBuf>,
+        /// Algorithm of the exact tree edit distance verifying candidates, unused with a threshold of 0
+        #[arg(long, value_enum, default_value_t)]
+        verifier: verifier::VerifierKind,
+        /// Program of the external verifiers or the jar of the APTED verifier
+        #[arg(long, value_name = "COMMAND")]
+        verifier_command: Option<String>,
+        /// Pairs written to one run of the external batch verifier
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        verifier_batch: usize,
+    },
+    /// Builds indexes once and serves similarity queries over HTTP with JSON
+    /// requests and responses, see src/serve.rs for the endpoints
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Lower bound methods to build indexes for, defaults to all registered methods
+        #[arg(long, value_enum, value_delimiter = ',')]
+        methods: Vec<LowerBoundMethods>,
+        /// Number of requests handled concurrently
+        #[arg(long, default_value_t = 4)]
+        workers: usize,
+        /// Number of distinct queries whose candidates are cached, 0 disables the cache
+        #[arg(long, default_value_t = 0)]
+        cache_size: usize,
+        /// Write-ahead log of inserted and deleted trees, replayed on start
+        #[arg(long, value_name = "FILE")]
+        wal: Option<PathBuf>,
+        /// Stops filtering of a query after this many milliseconds and answers its
+        /// partial candidates with timed_out set
+        #[arg(long, value_name = "MS")]
+        per_query_timeout: Option<u64>,
+    },
+    /// Builds indexes once and serves streams of similarity queries over gRPC,
+    /// see proto/tree_statistics.proto for the interface
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: std::net::SocketAddr,
+        /// Lower bound methods to build indexes for, defaults to all registered methods
+        #[arg(long, value_enum, value_delimiter = ',')]
+        methods: Vec<LowerBoundMethods>,
+        /// Number of distinct queries whose candidates are cached, 0 disables the cache
+        #[arg(long, default_value_t = 0)]
+        cache_size: usize,
+        /// Stops filtering of a query after this many milliseconds and answers its
+        /// partial candidates with timed_out set
+        #[arg(long, value_name = "MS")]
+        per_query_timeout: Option<u64>,
+    },
+    /// Samples dataset trees into a query file, optionally perturbing them
+    GenQueries {
+        /// Output query file, on each line <Threshold>;<Query tree>
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Number of queries to generate
+        #[arg(long, short = 'n', default_value_t = 100)]
+        count: usize,
+        /// Number of random edit operations applied to each sampled tree
+        #[arg(long, default_value_t = 0)]
+        edits: usize,
+        /// Absolute threshold assigned to every query
+        #[arg(long, short = 'k', conflicts_with = "relative_threshold")]
+        threshold: Option<usize>,
+        /// Threshold as a fraction of the query tree size, e.g. 0.1
+        #[arg(long)]
+        relative_threshold: Option<f64>,
+        /// Seed of the random generator
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    /// Generates a collection of random trees, does not need a dataset
+    Generate {
+        /// Output file for generated trees in bracket notation
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Number of trees to generate
+        #[arg(long, short = 'n', default_value_t = 1000)]
+        count: usize,
+        /// Minimal tree size
+        #[arg(long, default_value_t = 10)]
+        min_size: usize,
+        /// Maximal tree size
+        #[arg(long, default_value_t = 100)]
+        max_size: usize,
+        /// Distribution of tree sizes between min and max size
+        #[arg(long, value_enum, default_value_t = generate::SizeDistribution::Uniform)]
+        size_distribution: generate::SizeDistribution,
+        /// Maximal number of children of a node, 0 means unlimited
+        #[arg(long, default_value_t = 0)]
+        max_fanout: usize,
+        /// Probability of attaching a node below the previously inserted one,
+        /// 0 generates bushy trees, 1 generates deep paths
+        #[arg(long, default_value_t = 0.0)]
+        depth_bias: f64,
+        /// Number of distinct labels
+        #[arg(long, default_value_t = 50)]
+        alphabet: usize,
+        /// Zipf exponent of label frequencies, 0 means uniform
+        #[arg(long, default_value_t = 0.0)]
+        zipf: f64,
+        /// Seed of the random generator
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    /// Prints all lower bounds between two trees in bracket notation, does not need a
+    /// dataset
+    Pair {
+        /// First tree in bracket notation
+        first: String,
+        /// Second tree in bracket notation
+        second: String,
+        /// Also computes the exact tree edit distance
+        #[arg(long)]
+        exact: bool,
+    },
+    /// Checks lower bounds against brute force tree edit distance on random small trees,
+    /// does not need a dataset
+    SelfTest {
+        /// Number of random pairs of trees
+        #[arg(long, default_value_t = 1000)]
+        pairs: usize,
+        /// Maximal tree size, brute force distances grow exponentially with it
+        #[arg(long, default_value_t = 7)]
+        max_size: usize,
+        /// Number of distinct labels
+        #[arg(long, default_value_t = 3)]
+        alphabet: usize,
+        /// Seed of the random generator
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Applies an edit script of insert, delete and rename operations to a tree and prints
+    /// the edited tree, does not need a dataset
+    Apply {
+        /// Tree in bracket notation
+        tree: String,
+        /// Edit script, one operation per line addressing nodes by preorder position
+        #[arg(long, short = 's')]
+        script: PathBuf,
+        /// Also prints the exact tree edit distance of the edited tree to the input
+        #[arg(long)]
+        distance: bool,
+    },
+    /// Applies random edits to every tree and writes mutated trees with ground truth
+    Mutate {
+        /// Output file for mutated trees
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Output ground truth CSV with mutated tree id, original tree id and exact distance
+        #[arg(long, short = 'g')]
+        ground_truth: PathBuf,
+        /// Number of random edit operations applied to each tree
+        #[arg(long, short = 'n', default_value_t = 1)]
+        edits: usize,
+        /// Write mutated trees as a query file with the number of edits as threshold
+        #[arg(long, default_value_t = false)]
+        as_queries: bool,
+        /// Seed of the random generator
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    /// Splits the collection into dataset and query files with an id mapping
+    Split {
+        /// Output file for the dataset part
+        #[arg(long)]
+        dataset_output: PathBuf,
+        /// Output query file, on each line <Threshold>;<Query tree>
+        #[arg(long)]
+        query_output: PathBuf,
+        /// Output CSV mapping new ids of both parts to tree ids of the input collection
+        #[arg(long)]
+        mapping: PathBuf,
+        /// Number of query trees
+        #[arg(long, short = 'n')]
+        queries: usize,
+        /// How query trees are selected
+        #[arg(long, value_enum, default_value_t = split::SplitStrategy::Random)]
+        strategy: split::SplitStrategy,
+        /// Threshold written for each query
+        #[arg(long, short = 'k', default_value_t = 1)]
+        threshold: usize,
+        /// Seed of the random generator
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    /// Replaces labels with anonymous names and writes the label mapping separately
+    Anonymize {
+        /// Output file of anonymized trees, in the same order as tree ids
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Output CSV mapping anonymized labels to original labels
+        #[arg(long)]
+        mapping: PathBuf,
+        /// How anonymous labels are created
+        #[arg(long, value_enum, default_value_t = anonymize::AnonymizeMode::Hash)]
+        mode: anonymize::AnonymizeMode,
+        /// Salt for hashed labels
+        #[arg(long, default_value = "")]
+        salt: String,
+    },
+    /// Exports the size sorted dataset for other tree edit distance tools, line i holds
+    /// the tree with id i
+    Export {
+        /// Output file of exported trees
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = export::ExportFormat::Bracket)]
+        format: export::ExportFormat,
+        /// Query file, on each line <Threshold>;<Query tree>, whose trees are exported
+        /// in their order as well, without thresholds
+        #[arg(long, short = 'q', requires = "queries_output")]
+        query_file: Option<PathBuf>,
+        /// Output file of exported query trees
+        #[arg(long, value_name = "FILE", requires = "query_file")]
+        queries_output: Option<PathBuf>,
+    },
+    /// Prints trees of the size sorted dataset as indented text with their labels
+    Show {
+        /// Ids of the trees, positions in the size sorted collection
+        #[arg(long, required = true, value_delimiter = ',')]
+        id: Vec<usize>,
+    },
+    /// Rewrites the dataset sorted by size with trees of equal size clustered by label
+    /// signature, line i of the output holds the tree with id i when it is loaded again
+    Reorder {
+        /// Output file of the reordered trees in bracket notation
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Output CSV mapping ids of the reordered dataset to tree ids of the input collection
+        #[arg(long, value_name = "FILE")]
+        id_map: PathBuf,
+        #[arg(long, value_enum, default_value_t = reorder::ReorderBy::Signature)]
+        by: reorder::ReorderBy,
+    },
+    /// Converts a candidates file between CSV and binary format
+    ConvertCandidates {
+        /// Input candidates file, format is detected automatically
+        #[arg(long, short = 'i')]
+        input: PathBuf,
+        /// Output candidates file
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Output format
+        #[arg(long, value_enum)]
+        to: candidate_file::CandidateFormat,
+        /// Delta encode binary output
+        #[arg(long, default_value_t = false)]
+        delta: bool,
+        /// Compress binary output with zstd
+        #[arg(long, default_value_t = false)]
+        zstd: bool,
+    },
+    /// Rewrites a saved structural filter index without its deleted trees, does not need
+    /// a dataset
+    CompactIndex {
+        /// Index saved with `StructuralFilterIndex::save`
+        #[arg(long, short = 'i')]
+        input: PathBuf,
+        /// Output index file
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Positions of trees to delete besides the tombstones saved with the index
+        #[arg(long, value_delimiter = ',')]
+        delete: Vec<usize>,
+    },
+    /// Combines candidate files of shards into one sorted file without duplicates
+    MergeCandidates {
+        /// Input candidate files, formats are detected automatically
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// Output candidates file
+        #[arg(long, short = 'o')]
+        output: PathBuf,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = candidate_file::CandidateFormat::Csv)]
+        to: candidate_file::CandidateFormat,
+        /// Delta encode binary output
+        #[arg(long, default_value_t = false)]
+        delta: bool,
+        /// Compress binary output with zstd
+        #[arg(long, default_value_t = false)]
+        zstd: bool,
+        /// CSV with a header whose first column is the tree id, its other columns are
+        /// appended to candidates of the tree. Requires CSV output
+        #[arg(long, value_name = "FILE")]
+        metadata: Option<PathBuf>,
     },
     /// Validates candidate results against real results
     Validate {
@@ -97,6 +1168,12 @@ enum Commands {
         /// Threshold for validation
         #[arg()]
         threshold: usize,
+        /// Also writes missed pairs with their edits as Graphviz graphs
+        #[arg(long, default_value_t = false)]
+        graphviz: bool,
+        /// Format of the trees of missed pairs in `false-positives.<ext>`
+        #[arg(long, value_enum, default_value_t = export::ExportFormat::LabelIds)]
+        tree_format: export::ExportFormat,
     },
     /// Compares 2 candidate files TED execution time
     TedTime {
@@ -110,98 +1187,654 @@ enum Commands {
         #[arg()]
         threshold: usize,
     },
+    /// Prints the shell completion script of the tool or writes its man pages, does not
+    /// need a dataset
+    Completions {
+        /// Shell to print the completion script for
+        #[arg(value_enum, required_unless_present = "man")]
+        shell: Option<clap_complete::Shell>,
+        /// Writes a man page of the tool and of every command into the directory instead
+        #[arg(long, value_name = "DIR", conflicts_with = "shell")]
+        man: Option<PathBuf>,
+    },
 }
 
-fn main() -> Result<(), anyhow::Error> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
     let cli = Cli::parse();
     let mut cmd = Cli::command();
+    let floats = number_format::FloatFormat {
+        precision: cli.float_precision,
+    };
+
+    if let Some(threads) = cli.threads {
+        if threads == 0 {
+            cmd.error(ErrorKind::InvalidValue, "Thread count must be at least 1!")
+                .exit();
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    // commands that do not read a dataset, and have no plan to print
+    if cli.dry_run
+        && matches!(
+            cli.command,
+            Commands::Completions { .. }
+                | Commands::Generate { .. }
+                | Commands::Pair { .. }
+                | Commands::Apply { .. }
+                | Commands::SelfTest { .. }
+                | Commands::ConvertCandidates { .. }
+                | Commands::CompactIndex { .. }
+                | Commands::MergeCandidates { .. }
+        )
+    {
+        anyhow::bail!(error::Error::Config(format!(
+            "--dry-run plans runs over a dataset, {} does not read one",
+            command_name(&cli.command)
+        )));
+    }
+    if let Commands::Completions { shell, man } = &cli.command {
+        if let Some(dir) = man {
+            create_dir_all(dir)?;
+            clap_mangen::generate_to(cmd, dir)?;
+        } else if let Some(shell) = shell {
+            let name = cmd.get_name().to_owned();
+            clap_complete::generate(*shell, &mut cmd, name, &mut std::io::stdout());
+        }
+        return Ok(());
+    }
+    if let Commands::Generate {
+        output,
+        count,
+        min_size,
+        max_size,
+        size_distribution,
+        max_fanout,
+        depth_bias,
+        alphabet,
+        zipf,
+        seed,
+    } = &cli.command
+    {
+        let config = generate::GeneratorConfig {
+            count: *count,
+            min_size: *min_size,
+            max_size: *max_size,
+            size_distribution: *size_distribution,
+            max_fanout: *max_fanout,
+            depth_bias: *depth_bias,
+            alphabet: *alphabet,
+            zipf: *zipf,
+            seed: *seed,
+        };
+        write_file(output, &generate::generate(&config))?;
+        return Ok(());
+    }
+    if let Commands::Pair {
+        first,
+        second,
+        exact,
+    } = &cli.command
+    {
+        print!("{}", pair::pair_distances(first, second, *exact)?);
+        return Ok(());
+    }
+    if let Commands::Apply {
+        tree,
+        script,
+        distance,
+    } = &cli.command
+    {
+        let mut label_dict = LabelDict::default();
+        let tokens = parsing::parse_tree_tokens(tree.trim().to_owned())?;
+        parsing::update_label_dict(std::slice::from_ref(&tokens), &mut label_dict);
+        let tree = parsing::parse_tree(&tokens, &label_dict)?;
+        let script = edit_script::EditScript::parse(&std::fs::read_to_string(script)?)?;
+        let edited = edit_script::apply(&tree, &script, &mut label_dict)?;
+        println!(
+            "{}",
+            parsing::tree_to_labeled_bracket(&edited, &parsing::label_names(&label_dict))
+        );
+        if *distance {
+            println!("operations: {}", script.0.len());
+            println!("ted: {}", ted::ted(&tree, &edited));
+        }
+        return Ok(());
+    }
+    if let Commands::SelfTest {
+        pairs,
+        max_size,
+        alphabet,
+        seed,
+    } = &cli.command
+    {
+        let report = self_test::run(&self_test::SelfTestConfig {
+            pairs: *pairs,
+            max_size: *max_size,
+            alphabet: *alphabet,
+            seed: *seed,
+        })?;
+        print!("{report}");
+        if !report.violations.is_empty() {
+            anyhow::bail!(error::Error::Validation(format!(
+                "{} checks failed",
+                report.violations.len()
+            )));
+        }
+        return Ok(());
+    }
+    if let Commands::ConvertCandidates {
+        input,
+        output,
+        to,
+        delta,
+        zstd,
+    } = &cli.command
+    {
+        let (candidates, _) = validation::sort_candidates(input, cli.max_memory)?;
+        let options = candidate_file::BinaryOptions {
+            delta: *delta,
+            compress: *zstd,
+            order: candidate_file::PairOrder::of_file(input)?,
+        };
+        candidate_file::write_streamed(output, candidates, *to, options)?;
+        return Ok(());
+    }
+    if let Commands::CompactIndex {
+        input,
+        output,
+        delete,
+    } = &cli.command
+    {
+        let mut index: structural_filter::StructuralFilterIndex =
+            serde_json::from_reader(std::io::BufReader::new(File::open(input)?))?;
+        index.tombstone(delete.iter().copied())?;
+        let deleted = index.tombstones().len();
+        serde_json::to_writer(BufWriter::new(File::create(output)?), &index.compact())?;
+        if !cli.quiet {
+            println!("Removed {deleted} trees from the index");
+        }
+        return Ok(());
+    }
+    if let Commands::MergeCandidates {
+        inputs,
+        output,
+        to,
+        delta,
+        zstd,
+        metadata,
+    } = &cli.command
+    {
+        let metadata = read_metadata(metadata.as_ref(), *to, cli.quiet)?;
+        let Ok(order) = inputs
+            .iter()
+            .map(candidate_file::PairOrder::of_file)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .all_equal_value()
+        else {
+            anyhow::bail!(error::Error::Config(
+                "Candidate files to merge have different pair orders".to_owned()
+            ));
+        };
+        if metadata.is_some() && order != candidate_file::PairOrder::QueryTree {
+            anyhow::bail!(error::Error::Config(
+                "Metadata is joined to query-tree pairs only".to_owned()
+            ));
+        }
+        let mut sorter = external_sort::PairSorter::new(cli.max_memory);
+        for input in inputs {
+            candidate_file::visit_pairs(input, |pair| Ok(sorter.push(pair)?))?;
+        }
+        let runs = sorter.runs();
+        let mut last = None;
+        let candidates = sorter.finish()?.filter(move |pair| match pair {
+            Ok(pair) => last.replace(*pair) != Some(*pair),
+            Err(_) => true,
+        });
+        let merged = if let Some(metadata) = metadata {
+            let candidates = candidates.collect::<Result<Vec<_>, _>>()?;
+            metadata.write_candidates(output, &candidates)?;
+            candidates.len() as u64
+        } else {
+            let options = candidate_file::BinaryOptions {
+                delta: *delta,
+                compress: *zstd,
+                order,
+            };
+            candidate_file::write_streamed(output, candidates, *to, options)?
+        };
+        if !cli.quiet {
+            if runs > 0 {
+                println!("Sorted candidates in {runs} runs on disk");
+            }
+            println!("Merged {} files into {merged} candidates", inputs.len());
+        }
+        return Ok(());
+    }
 
-    if !cli.dataset_path.exists() || !cli.dataset_path.is_file() {
+    let Some(dataset_path) = cli
+        .dataset_path
+        .filter(|dp| dp.is_file() || (cli.input != Input::Bracket && dp.is_dir()))
+    else {
         cmd.error(
             ErrorKind::InvalidValue,
             "Path does not exists or is not a valid file!",
         )
         .exit();
+    };
+    // provenance written into output directories of the run
+    let mut run = run_info::RunInfo::new(&command_name(&cli.command));
+    // a dry run logs no events
+    if let Some(events) = cli.events.as_ref().filter(|_| !cli.dry_run) {
+        run.log_events(events)?;
     }
+    let mut snapshot = golden::Snapshot::default();
+    run.add_input(&dataset_path)?;
+    let parsing_start = Instant::now();
     let mut label_dict = LabelDict::default();
-    let mut trees = match parsing::parse_dataset(&cli.dataset_path, &mut label_dict) {
-        Ok(trees) => trees,
-        Err(e) => {
-            eprintln!("Got unexpected error: {}", e);
-            exit(1);
+    let parse_options = parsing::ParseOptions {
+        recover_brackets: cli.recover_brackets,
+        forests: cli.forests,
+    };
+    let parsed = match cli.input {
+        Input::Bracket => {
+            parsing::parse_dataset_reporting(&dataset_path, &mut label_dict, parse_options)
+                .map(|(trees, skipped, recovered)| {
+                    for line in &recovered {
+                        eprintln!("{line}");
+                    }
+                    if !skipped.lines.is_empty() {
+                        eprintln!("{skipped}");
+                    }
+                    trees
+                })
+                .map_err(anyhow::Error::from)
         }
+        #[cfg(feature = "tree-sitter")]
+        Input::FromSource => from_source::parse_sources(
+            &dataset_path,
+            cli.language.expect("language is required by clap"),
+            &mut label_dict,
+        ),
+        #[cfg(feature = "html")]
+        Input::Html => html::parse_pages(
+            &dataset_path,
+            html::HtmlLabels {
+                ids: cli.html_ids,
+                classes: cli.html_classes,
+            },
+            &mut label_dict,
+        ),
     };
-    trees.par_sort_by(|a, b| a.count().cmp(&b.count()));
+    let mut trees: Vec<_> = parsed?;
+    if let Some(max_size) = cli.max_tree_size {
+        let report = size_cap::cap_tree_sizes(&mut trees, max_size as usize, cli.oversized)?;
+        if report.policy.is_some() {
+            eprintln!("{report}");
+        }
+    }
+    match cli.label_ids {
+        LabelIds::FirstOccurrence => {}
+        LabelIds::Sorted => parsing::sort_label_ids(&mut label_dict, &mut trees),
+        LabelIds::Frequency => parsing::frequency_label_ids(&mut label_dict, &mut trees),
+    }
+    collection::sort_by_size(&mut trees);
+    let parsing_time = parsing_start.elapsed();
+    run.add_phase("parsing", parsing_time);
 
     if !cli.quiet {
         println!("Parsed {} trees", trees.len());
     }
+    let budget = memory::MemoryBudget::new(cli.max_memory, cli.quiet);
+    // opening the store records the run, a dry run is not recorded
+    #[cfg(feature = "sqlite")]
+    let mut store = cli
+        .store
+        .as_ref()
+        .filter(|_| !cli.dry_run)
+        .map(|db| result_store::ResultStore::open(db, &command_name(&cli.command), &dataset_path))
+        .transpose()?;
+    #[cfg(feature = "sqlite")]
+    if let (Some(store), false) = (&store, cli.quiet) {
+        println!("Storing results as run {}", store.run_id());
+    }
+    let collection_bytes = trees.estimated_bytes() + label_dict.estimated_bytes();
+    budget.report("parsing", collection_bytes);
+    // the plan of lower-bound lists its methods, the other commands only their dataset
+    if cli.dry_run && !matches!(cli.command, Commands::LowerBound { .. }) {
+        let plan = plan::Plan {
+            command: command_name(&cli.command),
+            datasets: vec![dataset_path],
+            fingerprint: CollectionFingerprint::of_trees(&trees, &label_dict),
+            base_bytes: collection_bytes,
+            limit: cli.max_memory,
+            queries: None,
+            query_count: 0,
+            thresholds: None,
+            methods: vec![],
+            outputs: vec![],
+        };
+        print!("{plan}");
+        return Ok(());
+    }
 
     match cli.command {
-        Commands::Statistics { hists } => {
+        Commands::Statistics {
+            hists,
+            hists_prefix,
+            hists_existing,
+            gzip,
+            parquet,
+            append_stats,
+            chunk_size,
+        } => {
             let freq_ordering = get_frequency_ordering(&label_dict);
-            let stats: Vec<_> = trees
-                .par_iter()
-                .map(|tree| statistics::gather(tree, &freq_ordering))
-                .collect();
-            let summary = statistics::summarize(&stats);
-            println!("Collection statistics\nmin_tree,max_tree,avg_tree,tree_count,avg_unique_labels_per_tree,avg_tree_distinct_labels,distinct_labels\n{summary},{}", label_dict.keys().len());
-            if hists.is_some() {
-                let mut output_path = hists.unwrap();
-                if output_path.exists() && !output_path.is_dir() {
-                    cmd.error(
-                        ErrorKind::InvalidValue,
-                        "Output path must be a directory! Defaulting to current...",
-                    )
-                    .print()?;
-                    output_path = PathBuf::from("./");
-                }
-
-                if !output_path.exists() {
-                    create_dir_all(&output_path)?;
+            let stats = run.time("statistics", || {
+                statistics::gather_chunked(&trees, &freq_ordering, chunk_size as usize)
+            });
+            let (summary, distinct_labels) = match &append_stats {
+                Some(path) => {
+                    let mut state = if path.exists() {
+                        statistics::StatisticsState::read(path)?
+                    } else {
+                        statistics::StatisticsState::default()
+                    };
+                    state.add(&stats, &trees, &label_dict);
+                    state.write(path)?;
+                    (state.summary(), state.labels.len())
                 }
-
-                write_files(&stats, &output_path)?;
+                None => (stats.summary(), label_dict.keys().len()),
+            };
+            println!(
+                "Collection statistics\nmin_tree,max_tree,avg_tree,tree_count,avg_unique_labels_per_tree,avg_tree_distinct_labels,distinct_labels\n{:.*},{distinct_labels}",
+                floats.precision, summary
+            );
+            snapshot.record("summary", format!("{summary},{distinct_labels}"));
+            if let Some(dir) = hists {
+                let files =
+                    hists_output::HistogramFiles::new(&dir, hists_prefix, hists_existing, gzip)?;
+                run.time("writing", || write_files(&stats, &files))?;
+                run.write(&dir)?;
+            }
+            if let Some(path) = parquet {
+                #[cfg(feature = "arrow")]
+                columnar::write_statistics(path, &stats)?;
+                #[cfg(not(feature = "arrow"))]
+                anyhow::bail!(error::Error::Config(format!(
+                    "Writing {} requires building with the `arrow` feature",
+                    path.display()
+                )));
             }
         }
-        Commands::Traversals { output } => {
-            let traversal_strings = trees
-                .par_iter()
-                .map(|tree| SEDIndex::index_tree(tree, &label_dict))
-                .map(|index| {
-                    format!(
-                        "{pre}\n{post}",
-                        pre = index
-                            .preorder
+        Commands::Histograms { output } => {
+            create_dir_all(&output)?;
+            let histograms = run.time("histograms", || {
+                trees
+                    .par_iter()
+                    .map(indexes::histograms::create_tree_histograms)
+                    .collect::<Vec<_>>()
+            });
+            // rows of a tree are sorted by key, so files are the same on every run
+            fn rows<K: Ord + Display>(
+                tree_id: usize,
+                histogram: &HashMap<K, u32>,
+            ) -> impl Iterator<Item = String> + '_ {
+                histogram
+                    .iter()
+                    .sorted_by_key(|(key, _)| *key)
+                    .map(move |(key, count)| format!("{tree_id},{key},{count}"))
+            }
+            let header = || std::iter::once("tree_id,key,count".to_owned());
+            write_file(
+                output.join("leaf_histograms.csv"),
+                &header()
+                    .chain(
+                        histograms
                             .iter()
-                            .map(|x| x.to_string())
-                            .collect::<Vec<_>>()
-                            .join(";"),
-                        post = index
-                            .postorder
+                            .enumerate()
+                            .flat_map(|(i, h)| rows(i, &h.0)),
+                    )
+                    .collect_vec(),
+            )?;
+            write_file(
+                output.join("degree_histograms.csv"),
+                &header()
+                    .chain(
+                        histograms
                             .iter()
-                            .map(|x| x.to_string())
-                            .collect::<Vec<_>>()
-                            .join(";")
+                            .enumerate()
+                            .flat_map(|(i, h)| rows(i, &h.1)),
                     )
-                })
+                    .collect_vec(),
+            )?;
+            write_file(
+                output.join("label_histograms.csv"),
+                &header()
+                    .chain(
+                        histograms
+                            .iter()
+                            .enumerate()
+                            .flat_map(|(i, h)| rows(i, &h.2)),
+                    )
+                    .collect_vec(),
+            )?;
+            run.write(&output)?;
+        }
+        Commands::Traversals {
+            output,
+            traversals,
+            separator,
+            labels,
+            structure,
+        } => {
+            let options = traversals::TraversalOptions {
+                traversals: if traversals.is_empty() {
+                    traversals::Traversal::DEFAULT.to_vec()
+                } else {
+                    traversals
+                },
+                separator,
+                label_names: labels.then(|| parsing::label_names(&label_dict)),
+                structure,
+            };
+            let traversal_strings = trees
+                .par_iter()
+                .flat_map_iter(|tree| traversals::format_tree(tree, &options))
                 .collect::<Vec<_>>();
 
             write_file(output, &traversal_strings)?;
         }
+        Commands::DumpIndex {
+            index,
+            tree_ids,
+            output,
+            region_weights,
+        } => {
+            if let Some(tid) = tree_ids.iter().find(|tid| **tid >= trees.len()) {
+                anyhow::bail!(error::Error::Config(format!(
+                    "Tree id {tid} is out of range, the dataset has {} trees",
+                    trees.len()
+                )));
+            }
+            let dumps = dump_index::dump(&trees, &label_dict, index, &tree_ids, region_weights)?;
+            match output {
+                Some(path) => {
+                    let mut w = BufWriter::new(File::create(path)?);
+                    serde_json::to_writer_pretty(&mut w, &dumps)?;
+                    writeln!(w)?;
+                    w.flush()?;
+                }
+                None => println!("{}", serde_json::to_string_pretty(&dumps)?),
+            }
+        }
         Commands::LowerBound {
             query_file,
+            query_ids,
+            threshold,
             output,
             method: filter_method,
-            results_path: _results,
+            results_path,
             q,
+            max_q,
+            qgram_scheme,
+            qgram_unpositioned,
+            method_args,
+            checkpoint_every,
+            resume,
+            per_query_timeout,
+            group_queries,
+            label_clusters,
+            cluster_reach,
+            explain,
+            candidates_format,
+            pair_order,
+            candidates_name,
+            count_only,
+            delta,
+            zstd,
+            auto_sample,
+            shard,
+            metadata,
+            annotate_pairs,
+            label_ordering,
+            label_ordering_file,
+            structural_matching,
+            tight_window,
+            merge_unseen_labels,
+            prefix_extra_labels,
+            prefix_bounded_fallback,
+            region_weights,
+            label_weights,
+            verify_sample,
+            recall_sample,
+            verify_confidence,
+            verify_seed,
+            verify_budget,
+            verify_order,
+            common,
+            approximate_verify,
+            ted_costs,
+            verifier,
+            verifier_command,
+            verifier_batch,
+            recheck,
         } => {
             use LowerBoundMethods as LBM;
+            if filter_method == Some(LBM::Hist) {
+                anyhow::bail!(error::Error::Config(
+                    "Histogram filters join the whole collection, run them with hist-join"
+                        .to_owned()
+                ));
+            }
             if !output.is_dir() {
-                eprintln!("Output arg must be a directory, is: {output:#?}");
-                process::exit(1);
+                anyhow::bail!(error::Error::Config(format!(
+                    "Output arg must be a directory, is: {output:#?}"
+                )));
+            }
+            let metadata = read_metadata(metadata.as_ref(), candidates_format, cli.quiet)?;
+            if annotate_pairs.is_some() && candidates_format != candidate_file::CandidateFormat::Csv
+            {
+                anyhow::bail!(error::Error::Config(
+                    "Pairs can only be annotated in CSV candidates".to_owned()
+                ));
+            }
+            if pair_order != candidate_file::PairOrder::QueryTree
+                && (metadata.is_some() || annotate_pairs.is_some())
+            {
+                anyhow::bail!(error::Error::Config(
+                    "Metadata and annotations are joined to query-tree pairs only".to_owned()
+                ));
+            }
+            if label_weights.is_some() && ted_costs != ted::CostModel::UNIT {
+                anyhow::bail!(error::Error::Config(
+                    "--ted-costs apply to all labels, verify --label-weights with unit costs"
+                        .to_owned()
+                ));
+            }
+            if pair_order == candidate_file::PairOrder::Unordered && query_ids.is_empty() {
+                anyhow::bail!(error::Error::Config(
+                    "Unordered pairs are written for self joins with --query-ids only".to_owned()
+                ));
+            }
+            run.pair_order = Some(pair_order.to_string());
+            let verify_budget = verify_budget.map(Duration::try_from_secs_f64).transpose()?;
+            let mut method_config = method_args::MethodConfig {
+                sed: method_args::SedArgs {
+                    q: q.unwrap_or(2),
+                    max_q,
+                    scheme: qgram_scheme,
+                    positional: !qgram_unpositioned,
+                },
+                structural: method_args::StructuralArgs {
+                    matching: structural_matching,
+                    tight_window,
+                    prefix_extra_labels,
+                    prefix_bounded_fallback,
+                },
+                split: method_args::SplitArgs::default(),
+                sed_qgram: method_args::SedQgramArgs::default(),
+            };
+            method_config.apply(&method_args)?;
+            let method_args::MethodConfig {
+                sed: sed_args,
+                structural: structural_args,
+                split: split_args,
+                sed_qgram: sed_qgram_args,
+            } = method_config;
+            let q = sed_args.q;
+            if q == 0 || sed_args.max_q.is_some_and(|max_q| max_q < q) {
+                cmd.error(
+                    ErrorKind::InvalidValue,
+                    "Q gram size must be positive and at most the max size!",
+                )
+                .exit();
+            }
+            let prefix_options = structural_filter::PrefixFilterOptions {
+                extra_labels: structural_args.prefix_extra_labels,
+                bounded_fallback: structural_args.prefix_bounded_fallback,
+            };
+            let gram_options = indexes::index_gram::IndexGramOptions {
+                max_q: sed_args.max_q.unwrap_or(q),
+                scheme: sed_args.scheme,
+                positional: sed_args.positional,
+                ..indexes::index_gram::IndexGramOptions::fixed(q)
+            };
+            if !(verify_confidence > 0.0 && verify_confidence < 1.0) {
+                cmd.error(
+                    ErrorKind::InvalidValue,
+                    "Verify confidence must be between 0 and 1 exclusive!",
+                )
+                .exit();
+            }
+            if let Some(method) = recheck
+                .iter()
+                .find(|method| !lb::auto::REFINABLE.contains(method))
+            {
+                anyhow::bail!(error::Error::Config(format!(
+                    "Candidates cannot be re-checked with {method:?}, only with {:?}",
+                    lb::auto::REFINABLE
+                )));
+            }
+            if structural_args.tight_window && region_weights.regions.contains(&0) {
+                cmd.error(
+                    ErrorKind::ArgumentConflict,
+                    "Tight window needs region weights of at least 1!",
+                )
+                .exit();
             }
-            let q = q.unwrap_or(2);
 
             // let mut times = vec![];
             // let mut candidate_times = vec![];
@@ -209,56 +1842,433 @@ fn main() -> Result<(), anyhow::Error> {
             if !cli.quiet {
                 println!("Preparing dataset and running preprocessing for all methods");
             }
-            let mut size_map = BTreeMap::new();
-            let first = trees.first().unwrap();
-            let mut size = first.count();
-            size_map.insert(first.count(), 0);
-            for (idx, t) in trees.iter().enumerate().skip(1) {
-                if size != t.count() {
-                    for i in (size + 1)..t.count() {
-                        size_map.insert(i, idx);
-                    }
-                    size = t.count();
-                    size_map.insert(size, idx);
-                }
-            }
-
             // let _collection_histograms = create_collection_histograms(&trees);
 
-            // let split_distribution_map = structural_filter::best_split_distribution(&label_dict);
-            // let split_distribution =
-            // move |lbl: &LabelId| -> usize { *split_distribution_map.get(lbl).unwrap() };
-            // let _structural_split_sets = lc.create_split(&trees, split_distribution);
-            let ordering = get_frequency_ordering(&label_dict);
+            let ordering = match &label_ordering_file {
+                Some(path) => label_ordering::LabelOrdering::read(path, &label_dict)?,
+                None => label_ordering::LabelOrdering::new(label_ordering, &trees, &label_dict),
+            };
+            // kept with the candidates, so a run can be repeated with the same ordering
+            if !cli.dry_run {
+                ordering.write(&output.join("label_ordering.csv"), &label_dict)?;
+            }
 
-            let queries = parsing::parse_queries(&query_file, &mut label_dict).unwrap();
-            let lbms: [LBM; 3] = [LBM::Lblint, LBM::Sed, LBM::Structural];
+            let query_parsing_start = Instant::now();
+            let dataset_labels = label_dict.len();
+            let (mut queries, routes) = if let Some(query_file) = &query_file {
+                run.add_input(query_file)?;
+                parsing::parse_routed_queries(query_file, &mut label_dict, parse_options)?
+            } else {
+                let threshold = threshold.expect("threshold is required by clap");
+                query_ids
+                    .iter()
+                    .map(|&id| match trees.get(id) {
+                        Some(tree) => Ok((threshold, tree.clone())),
+                        None => Err(error::Error::Config(format!(
+                            "Query id {id} is out of range of {} trees",
+                            trees.len()
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|queries| (queries, vec![]))?
+            };
+            // methods of a routed run, the method argument is the default of the queries
+            let query_methods = routes
+                .iter()
+                .any(|route| route.method.is_some())
+                .then(|| routing::resolve(&routes, filter_method))
+                .transpose()?;
+            if group_queries && query_methods.is_some() {
+                anyhow::bail!(error::Error::Config(
+                    "Routed queries cannot be grouped with --group-queries".to_owned()
+                ));
+            }
+            if label_clusters.is_some() && query_methods.is_some() {
+                anyhow::bail!(error::Error::Config(
+                    "Routed queries cannot be restricted with --label-clusters".to_owned()
+                ));
+            }
+            if merge_unseen_labels {
+                let relabeled = parsing::merge_unseen_labels(&mut queries, dataset_labels);
+                if !cli.quiet {
+                    println!(
+                        "Relabeled {relabeled} query nodes with labels missing in the dataset"
+                    );
+                }
+            }
+            let query_parsing_time = query_parsing_start.elapsed();
+            run.add_phase("query parsing", query_parsing_time);
+            println!(
+                "parse:{}ms\nquery_parse:{}ms",
+                parsing_time.as_millis(),
+                query_parsing_time.as_millis()
+            );
+            // read after the queries, so weights of their labels are known
+            let label_weights = label_weights
+                .map(|path| label_weights::LabelWeights::read(&path, &label_dict))
+                .transpose()?;
+            // options changing the bounds, every way of running a method honors them
+            let run_config = lb::RunConfig {
+                label_weights: label_weights.as_ref(),
+                structural_matching: structural_args.matching,
+                tight_window: structural_args.tight_window,
+            };
+            let base_bytes = collection_bytes + queries.estimated_bytes();
+            let pair_annotator = annotate_pairs
+                .map(|annotation| pair_sizes::PairAnnotator::new(annotation, &queries, &trees));
+            let dataset_name = dataset_path
+                .file_stem()
+                .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+            let query_k = queries.iter().map(|(k, _)| *k).all_equal_value().ok();
+            let candidates_path = |method: &str| {
+                candidate_file::file_name(
+                    &candidates_name,
+                    method,
+                    query_k,
+                    &dataset_name,
+                    candidates_format,
+                )
+                .map(|name| output.join(name))
+            };
+            // fails on an invalid template before any method runs
+            candidates_path("")?;
+            let verifier =
+                if verify_sample.is_some() || recall_sample.is_some() || verify_budget.is_some() {
+                    verifier::build(
+                        verifier,
+                        ted_costs,
+                        verifier_command.as_deref(),
+                        verifier_batch,
+                        &label_dict,
+                    )?
+                } else {
+                    Box::new(verifier::ZhangShasha(ted_costs))
+                };
+            // a shard indexes only its trees, their ids are shifted back before writing
+            let shard_range = shard.map_or(0..trees.len(), |s| s.range(&trees));
+            if let (Some(shard), false) = (shard, cli.quiet) {
+                println!(
+                    "Shard {shard} computes candidates of trees {}..{}",
+                    shard_range.start, shard_range.end
+                );
+            }
+            let trees = &trees[shard_range.clone()];
+            // per tree indexes shared by all methods, built on first use
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(trees), &label_dict)?
+                .with_region_weights(region_weights)
+                .with_split_groups(split_args.groups)
+                .with_qgram_size(sed_qgram_args.q);
+            let size_buckets = pre.size_buckets();
+            let label_clusters = label_clusters.map(|clusters| {
+                let start = Instant::now();
+                let clusters =
+                    lb::label_clusters::LabelClusters::new(trees, clusters, cluster_reach);
+                run.add_phase("label clusters", start.elapsed());
+                clusters
+            });
+            let ground_truth = match (&label_clusters, &results_path) {
+                (Some(_), Some(path)) => Some(bench::read_ground_truth(path)?),
+                _ => None,
+            };
+            // auto, tuned, the BK-tree and the split structural filter run only when asked
+            // for, they repeat work of the registered methods
+            let lbms = LBM::REGISTERED.iter().chain(
+                [
+                    LBM::Auto,
+                    LBM::Tuned,
+                    LBM::SedBkTree,
+                    LBM::StructuralSplit,
+                    LBM::SedQgram,
+                    LBM::SedUnbounded,
+                    LBM::Routed,
+                ]
+                .iter(),
+            );
             // let label_dict = dbg!(label_dict);
-
-            for current_method in lbms.iter().filter(|method| {
-                if let Some(single_method) = filter_method {
-                    return **method == single_method;
+            let methods = lbms
+                .filter(|method| {
+                    if query_methods.is_some() {
+                        return **method == LBM::Routed;
+                    }
+                    if let Some(single_method) = filter_method {
+                        return **method == single_method;
+                    }
+                    LBM::REGISTERED.contains(method)
+                })
+                .collect_vec();
+            if cli.dry_run {
+                let mut method_plans = vec![];
+                for &&method in &methods {
+                    let file = candidates_path(&format!("{method:?}"))?;
+                    let mut outputs = vec![];
+                    match method {
+                        LBM::SedUnbounded => outputs.push(file.with_extension("ranking.csv")),
+                        LBM::Routed => outputs.push(file.with_extension("routes.csv")),
+                        _ => {}
+                    }
+                    if count_only {
+                        outputs.push(file.with_extension("counts.csv"));
+                    } else {
+                        outputs.push(file.clone());
+                    }
+                    if per_query_timeout.is_some() {
+                        outputs.push(file.with_extension("timed_out.csv"));
+                    }
+                    if verify_budget.is_some() {
+                        for extension in ["verified.csv", "unverified.csv", "results.csv"] {
+                            outputs.push(file.with_extension(extension));
+                        }
+                    }
+                    if approximate_verify {
+                        outputs.push(file.with_extension("approximate.csv"));
+                    }
+                    let bytes = matches!(method, LBM::Lblint | LBM::Sed | LBM::Structural)
+                        .then(|| lb::estimate_method_bytes(method, trees, &label_dict));
+                    method_plans.push(plan::MethodPlan {
+                        method,
+                        bytes,
+                        chunked: bytes.is_some_and(|bytes| !budget.fits(base_bytes + 2 * bytes)),
+                        outputs,
+                    });
                 }
-                true
-            }) {
-                let (mut candidates, duration) = match *current_method {
-                    LBM::Lblint => {
-                        let lblint_indexes = trees
-                            .par_iter()
-                            .map(|t| InvertedListLabelPostorderIndex::index_tree(t, &label_dict))
-                            .collect::<Vec<_>>();
-                        let lblint_index =
-                            label_intersection::LabelIntersectionIndex::new(&lblint_indexes);
+                let plan = plan::Plan {
+                    command: "LowerBound".to_owned(),
+                    datasets: vec![dataset_path],
+                    fingerprint: CollectionFingerprint::of_trees(trees, &label_dict),
+                    base_bytes,
+                    limit: cli.max_memory,
+                    queries: query_file,
+                    query_count: queries.len(),
+                    thresholds: queries.iter().map(|(k, _)| *k).minmax().into_option(),
+                    methods: method_plans,
+                    outputs: vec![
+                        output.join("label_ordering.csv"),
+                        output.join(run_info::FILE_NAME),
+                    ],
+                };
+                print!("{plan}");
+                return Ok(());
+            }
+            if let Some((qid, tid)) = explain {
+                let (Some((k, query)), Some(tree)) = (queries.get(qid), trees.get(tid)) else {
+                    anyhow::bail!(error::Error::Config(format!(
+                        "Pair {qid},{tid} is out of range of {} queries and {} trees",
+                        queries.len(),
+                        trees.len()
+                    )));
+                };
+                let methods = methods.into_iter().copied().collect_vec();
+                let explanation = explain::explain(
+                    &methods,
+                    (qid, query),
+                    (tid, tree),
+                    *k,
+                    ordering.for_size(query.count()),
+                    &label_dict,
+                    &pairs::BoundOptions {
+                        run: run_config,
+                        region_weights: pre.region_weights(),
+                        qgram_size: pre.qgram_size(),
+                    },
+                )?;
+                print!("{explanation}");
+                return Ok(());
+            }
+            // with the structural filter and a method on traversals in one run, the structural
+            // sets are built first and the traversals read off them instead of the trees
+            let shares_traversals = methods.contains(&&LBM::Structural)
+                && methods.iter().any(|m| matches!(m, LBM::Lblint | LBM::Sed))
+                && budget.fits(
+                    base_bytes + 2 * lb::estimate_method_bytes(LBM::Structural, trees, &label_dict),
+                );
+            if shares_traversals {
+                let start = Instant::now();
+                // an error is reported by the first method needing the sets
+                if pre.structural_sets().is_ok() {
+                    run.add_phase("structural sets", start.elapsed());
+                }
+            }
 
-                        let lblint_queries = queries
-                            .iter()
-                            .map(|(t, q)| {
-                                (
-                                    *t,
-                                    InvertedListLabelPostorderIndex::index_tree(q, &label_dict),
-                                )
-                            })
-                            .collect_vec();
+            // checkpoints are resumed only for the same trees of the shard and the same queries
+            let checkpoint_inputs = if checkpoint_every.is_some() || resume {
+                let queries = match &query_file {
+                    Some(path) => run_info::hash_file(path)?.1,
+                    None => run_info::fnv1a(
+                        run_info::FNV_OFFSET,
+                        format!("{threshold:?};{query_ids:?}").as_bytes(),
+                    ),
+                };
+                Some(checkpoint::CheckpointInputs {
+                    collection: CollectionFingerprint::of_trees(trees, &label_dict),
+                    queries,
+                })
+            } else {
+                None
+            };
+
+            let mut summaries = vec![];
+            for current_method in methods {
+                let peak_memory = alloc_stats::PeakScope::start();
+                // recall against the ground truth, for runs that may miss results
+                let mut exact_recall = None;
+                // building the per tree indexes, the index of the method and indexing queries,
+                // auto, tuned and chunked runs build their indexes while querying
+                let mut index_time = Duration::ZERO;
+                let mut checkpoint = if let Some(inputs) = &checkpoint_inputs {
+                    let ckpt = Checkpointer::open(
+                        &output,
+                        &format!("{current_method:#?}"),
+                        checkpoint_every.unwrap_or(Checkpointer::DEFAULT_EVERY),
+                        resume,
+                        inputs.clone(),
+                    )?;
+                    if !cli.quiet && ckpt.resume_from() > 0 {
+                        println!(
+                            "Resuming {current_method:?} from query {}",
+                            ckpt.resume_from()
+                        );
+                    }
+                    Some(ckpt)
+                } else {
+                    None
+                };
+                let mut timeout = per_query_timeout
+                    .map(|ms| query_timeout::QueryTimeout::new(Duration::from_millis(ms)));
+                // indexes built for previous methods are kept for the following ones
+                let used_bytes = base_bytes + pre.estimated_bytes();
+                // in memory the per tree indexes are held twice, once more in the inverted index
+                let index_bytes = lb::estimate_method_bytes(*current_method, trees, &label_dict);
+                let chunked = !budget.fits(used_bytes + 2 * index_bytes);
+                let (mut candidates, duration) = match *current_method {
+                    _ if chunked => {
+                        if !matches!(*current_method, LBM::Lblint | LBM::Sed | LBM::Structural) {
+                            anyhow::bail!(error::Error::Config(format!(
+                                "{current_method:?} cannot index trees in chunks when --max-memory is exceeded"
+                            )));
+                        }
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported when indexes exceed --max-memory"
+                                    .to_owned()
+                            ));
+                        }
+                        if *current_method == LBM::Structural
+                            && region_weights != structural_filter::RegionWeights::default()
+                        {
+                            anyhow::bail!(error::Error::Config(
+                                "Region weights are not supported when indexes exceed --max-memory"
+                                    .to_owned()
+                            ));
+                        }
+                        // the chunks are compared with the plain bounds query by query
+                        let structural = *current_method == LBM::Structural;
+                        let ignored = [
+                            (group_queries, "--group-queries"),
+                            (label_clusters.is_some(), "--label-clusters"),
+                            (timeout.is_some(), "--per-query-timeout"),
+                            (
+                                *current_method == LBM::Lblint
+                                    && run_config.label_weights.is_some(),
+                                "--label-weights",
+                            ),
+                            (
+                                structural && run_config.structural_matching > 0,
+                                "--structural-matching",
+                            ),
+                            (structural && run_config.tight_window, "--tight-window"),
+                        ];
+                        if let Some((_, flag)) = ignored.iter().find(|(set, _)| *set) {
+                            anyhow::bail!(error::Error::Config(format!(
+                                "{flag} is not supported when indexes exceed --max-memory"
+                            )));
+                        }
+                        if !cli.quiet {
+                            println!(
+                                "{current_method:?} indexes estimated at {}, indexing trees in chunks",
+                                memory::format_bytes(index_bytes)
+                            );
+                        }
+                        let chunk_budget = budget.remaining(used_bytes).unwrap_or(usize::MAX);
+                        let (candidates, duration, chunks) = lb::run_method_chunked(
+                            *current_method,
+                            trees,
+                            &queries,
+                            &label_dict,
+                            size_buckets,
+                            chunk_budget,
+                        )?;
+                        if !cli.quiet {
+                            println!("{current_method:?} processed {chunks} chunks");
+                        }
+                        (candidates, duration)
+                    }
+                    _ if group_queries => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported with --group-queries".to_owned()
+                            ));
+                        }
+                        let (candidates, duration, groups) =
+                            query_groups::run(*current_method, &pre, &queries, &run_config)?;
+                        if !cli.quiet {
+                            println!(
+                                "{current_method:?} grouped {} queries into {} groups",
+                                queries.len(),
+                                groups.len()
+                            );
+                        }
+                        (candidates, duration)
+                    }
+                    _ if label_clusters.is_some() => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported with --label-clusters".to_owned()
+                            ));
+                        }
+                        let clusters = label_clusters.as_ref().expect("clustered run");
+                        let (candidates, duration) = lb::label_clusters::run(
+                            *current_method,
+                            &pre,
+                            clusters,
+                            &queries,
+                            &run_config,
+                        )?;
+                        if !cli.quiet {
+                            println!(
+                                "{current_method:?} compared queries with {} of {} label clusters",
+                                2 * cluster_reach + 1,
+                                clusters.len()
+                            );
+                        }
+                        if let Some(ground_truth) = &ground_truth {
+                            let recall = bench::recall(&candidates, ground_truth, |qid| {
+                                queries.get(qid).map(|(k, _)| *k)
+                            });
+                            if !cli.pretty {
+                                println!("recall:{}", floats.format(recall));
+                            }
+                            exact_recall = Some(recall);
+                            snapshot.record(
+                                format!("{current_method:?} recall"),
+                                format!("{recall:.6}"),
+                            );
+                        }
+                        (candidates, duration)
+                    }
+                    LBM::Lblint => {
+                        let index_start = Instant::now();
+                        let lblint_indexes = pre.label_sets()?;
+                        let lblint_index =
+                            label_intersection::LabelIntersectionIndex::new(lblint_indexes);
+                        let lblint_queries = index_queries::<InvertedListLabelPostorderIndex>(
+                            &queries,
+                            &label_dict,
+                        )?;
+                        index_time = index_start.elapsed();
+                        budget.report(
+                            "Lblint indexing",
+                            base_bytes + pre.estimated_bytes() + lblint_index.estimated_bytes(),
+                        );
 
                         let start = Instant::now();
                         let mut index_candidates = vec![];
@@ -266,115 +2276,130 @@ fn main() -> Result<(), anyhow::Error> {
                             index_candidates.append(&mut lblint_index.query_index_prefix(
                                 query,
                                 *t,
-                                &ordering,
-                                &lblint_indexes,
+                                ordering.for_size(query.c.tree_size),
+                                lblint_indexes,
                                 Some(qid),
                             ));
                         }
 
-                        println!(
-                            "Lblint index\ntime:{dur}ms\ncandidates:{canlen}",
-                            canlen = index_candidates.len(),
-                            dur = start.elapsed().as_millis()
-                        );
-                        index_candidates.par_sort();
-                        let mut output_file = output.clone();
-                        output_file.push(format!("{current_method:#?}_index_candidates.csv"));
-                        write_file(
-                            output_file,
-                            &index_candidates
-                                .iter()
-                                .map(|(c1, c2)| format!("{c1},{c2}"))
-                                .collect_vec(),
-                        )?;
+                        if cli.pretty {
+                            summaries.push(pretty::MethodSummary {
+                                method: "Lblint index".to_owned(),
+                                time: start.elapsed(),
+                                candidates: index_candidates.len(),
+                                ..Default::default()
+                            });
+                        } else {
+                            println!(
+                                "Lblint index\ntime:{dur}ms\ncandidates:{canlen}",
+                                canlen = index_candidates.len(),
+                                dur = start.elapsed().as_millis()
+                            );
+                        }
+                        for (_, tid) in index_candidates.iter_mut() {
+                            *tid += shard_range.start;
+                        }
+                        if !query_ids.is_empty() {
+                            index_candidates.retain(|&(qid, tid)| tid != query_ids[qid]);
+                        }
+                        if !count_only {
+                            index_candidates.par_sort_unstable();
+                            candidate_file::write_candidates(
+                                candidates_path(&format!("{current_method:?}_index"))?,
+                                &pair_order.arrange(&index_candidates, &query_ids),
+                                candidates_format,
+                                candidate_file::BinaryOptions {
+                                    delta,
+                                    compress: zstd,
+                                    order: pair_order,
+                                },
+                            )?;
+                        }
 
-                        lb::iterate_queries!(
-                            lblint_queries,
-                            lblint_indexes,
-                            label_intersection_k,
-                            size_map
-                        )
+                        lb::run_method_tracked(
+                            LBM::Lblint,
+                            &pre,
+                            &queries,
+                            &run_config,
+                            checkpoint.as_mut(),
+                            timeout.as_mut(),
+                        )?
                     }
                     LBM::Sed => {
-                        let sed_indexes = trees
-                            .par_iter()
-                            .map(|t| SEDIndex::index_tree(t, &label_dict))
-                            .collect::<Vec<_>>();
+                        let index_start = Instant::now();
+                        let sed_indexes = pre.traversals()?;
                         let pre_only = sed_indexes
                             .iter()
                             .map(|si| si.preorder.clone())
-                            .collect::<Vec<Vec<i32>>>();
+                            .collect::<Vec<Vec<LabelId>>>();
                         let start = Instant::now();
                         // TODO: Heuristic: Calculate the best Q for each dataset
-                        // TODO: DBLP with Q = 2 is missing 4 results, find out why!
-                        let mut pre_index = indexes::index_gram::IndexGram::new(&pre_only, q);
+                        let pre_index = indexes::index_gram::IndexGram::new(pre_only, gram_options);
                         // let post_index = indexes::index_gram::IndexGram::new(&post_only, q);
                         if !cli.quiet {
                             println!("Building indexes took: {}ms", start.elapsed().as_millis());
                         }
-                        let sed_queries = queries
-                            .iter()
-                            .map(|(t, q)| (*t, SEDIndex::index_tree(q, &label_dict)))
-                            .collect_vec();
+                        budget.report(
+                            "Sed indexing",
+                            base_bytes + pre.estimated_bytes() + pre_index.estimated_bytes(),
+                        );
+                        let sed_queries = index_queries::<SEDIndex>(&queries, &label_dict)?;
+                        index_time = index_start.elapsed();
 
                         // dbg!(&pre_only[])
 
                         let mut index_used_cnt = 0;
                         let mut index_candidates = Vec::with_capacity(15_000);
                         let start = Instant::now();
-                        let sed_indexes_len = sed_indexes.len();
                         let mut total_lookup_duration = Duration::new(0, 0);
                         let mut total_filter_duration = Duration::new(0, 0);
                         let mut avg_precision = 0.0;
 
+                        let mut evaluator = SedEvaluator::for_queries(&sed_queries);
                         for (qid, (threshold, sed_query)) in sed_queries.iter().enumerate() {
-                            let c1 = pre_index.query(sed_query.preorder.clone(), *threshold);
-                            if let Ok((c1, lookup_duration, filter_duration)) = c1 {
-                                index_used_cnt += 1;
-                                total_lookup_duration += lookup_duration;
-                                total_filter_duration += filter_duration;
-
-                                let mut correct_results = 0;
-                                for cid in c1.iter() {
-                                    if sed_k(sed_query, &sed_indexes[*cid], *threshold)
-                                        <= *threshold
-                                    {
-                                        correct_results += 1;
-                                        index_candidates.push((qid, *cid));
-                                    }
+                            let found = pre_index.query(sed_query.preorder.clone(), *threshold);
+                            let mut correct_results = 0;
+                            for cid in found.candidates.iter() {
+                                let distance =
+                                    evaluator.sed_k(sed_query, &sed_indexes[*cid], *threshold);
+                                if distance <= *threshold {
+                                    correct_results += 1;
+                                    index_candidates.push((qid, *cid));
                                 }
-                                let precision =
-                                    correct_results as f64 / std::cmp::max(c1.len(), 1) as f64;
+                            }
+                            // short queries are scanned by the index, their precision is not
+                            // the one of the filters
+                            if !found.scanned {
+                                index_used_cnt += 1;
+                                total_lookup_duration += found.lookup;
+                                total_filter_duration += found.filter;
+                                let precision = correct_results as f64
+                                    / std::cmp::max(found.candidates.len(), 1) as f64;
                                 avg_precision = avg_precision
                                     + (precision - avg_precision) / (index_used_cnt as f64);
-                            } else {
-                                let start_idx = size_map
-                                    .get(&sed_query.c.tree_size.saturating_sub(*threshold))
-                                    .unwrap_or(&0);
-                                let end_idx = size_map
-                                    .get(&(sed_query.c.tree_size + threshold + 1))
-                                    .unwrap_or(&sed_indexes_len);
-                                let idx_diff = end_idx - start_idx + 1;
-                                // println!("Starting from {start_idx} and taking at most {idx_diff} trees!");
-
-                                for (tid, tree) in sed_indexes
-                                    .iter()
-                                    .enumerate()
-                                    .skip(*start_idx)
-                                    .take(idx_diff)
-                                {
-                                    if sed_k(sed_query, tree, *threshold) <= *threshold {
-                                        index_candidates.push((qid, tid));
-                                    }
-                                }
                             }
                         }
 
-                        println!(
-                            "Sed Index\ntime:{}ms\ncandidates:{}",
-                            start.elapsed().as_millis(),
-                            index_candidates.len(),
-                        );
+                        if cli.pretty {
+                            summaries.push(pretty::MethodSummary {
+                                method: "Sed Index".to_owned(),
+                                time: start.elapsed(),
+                                candidates: index_candidates.len(),
+                                ..Default::default()
+                            });
+                        } else {
+                            println!(
+                                "Sed Index\ntime:{}ms\ncandidates:{}",
+                                start.elapsed().as_millis(),
+                                index_candidates.len(),
+                            );
+                        }
+                        if !cli.quiet {
+                            println!(
+                                "Queries scanned as too short for the q-gram index: {}",
+                                sed_queries.len() - index_used_cnt
+                            );
+                        }
 
                         // println!(
                         //     "Total lookup duration was: {}ms",
@@ -398,37 +2423,68 @@ fn main() -> Result<(), anyhow::Error> {
                         //         .collect_vec(),
                         // )?;
 
-                        lb::iterate_queries!(sed_queries, sed_indexes, sed_k, size_map)
+                        lb::run_method_tracked(
+                            LBM::Sed,
+                            &pre,
+                            &queries,
+                            &run_config,
+                            checkpoint.as_mut(),
+                            timeout.as_mut(),
+                        )?
                     }
                     LBM::Structural => {
-                        let mut lc = LabelSetConverter::default();
-                        let structural_sets = lc.create(&trees);
+                        let index_start = Instant::now();
+                        let structural_sets = pre.structural_sets()?;
                         let struct_index =
-                            structural_filter::StructuralFilterIndex::new(&structural_sets);
-                        let structural_queries = queries
-                            .iter()
-                            .map(|(t, q)| (*t, lc.create_single(q)))
-                            .collect_vec();
+                            structural_filter::StructuralFilterIndex::new(structural_sets);
+                        let structural_queries = pre.structural_queries(&queries)?;
+                        index_time = index_start.elapsed();
+                        budget.report(
+                            "Structural indexing",
+                            base_bytes + pre.estimated_bytes() + struct_index.estimated_bytes(),
+                        );
 
                         let start = Instant::now();
-                        let index_candidates = structural_queries
+                        let (index_candidates, prefix_stats) = structural_queries
                             .par_iter()
                             .enumerate()
-                            .flat_map(|(qid, (t, query))| {
+                            .map(|(qid, (t, query))| {
                                 struct_index.query_index_prefix(
                                     query,
-                                    &ordering,
+                                    ordering.for_size(queries[qid].1.count()),
                                     *t,
-                                    &structural_sets,
+                                    structural_sets,
                                     Some(qid),
+                                    prefix_options,
                                 )
                             })
-                            .collect::<Vec<(usize, usize)>>();
-                        println!(
-                            "Structural Index\ntime:{dur}ms\ncandidates:{canlen}",
-                            canlen = index_candidates.len(),
-                            dur = start.elapsed().as_millis()
-                        );
+                            .reduce(
+                                || (vec![], structural_filter::PrefixFilterStats::default()),
+                                |(mut c1, s1), (mut c2, s2)| {
+                                    c1.append(&mut c2);
+                                    (c1, s1 + s2)
+                                },
+                            );
+                        if cli.pretty {
+                            summaries.push(pretty::MethodSummary {
+                                method: "Structural Index".to_owned(),
+                                time: start.elapsed(),
+                                candidates: index_candidates.len(),
+                                ..Default::default()
+                            });
+                        } else {
+                            println!(
+                                "Structural Index\ntime:{dur}ms\ncandidates:{canlen}",
+                                canlen = index_candidates.len(),
+                                dur = start.elapsed().as_millis()
+                            );
+                        }
+                        if !cli.quiet {
+                            println!(
+                                "Prefix filter decided {} of {} trees, {} completed with all labels",
+                                prefix_stats.decided, prefix_stats.probed, prefix_stats.completed
+                            );
+                        }
                         // index_candidates.par_sort();
                         // let mut output_file = output.clone();
                         // output_file.push(format!("{current_method:#?}_index_candidates.csv"));
@@ -440,69 +2496,1365 @@ fn main() -> Result<(), anyhow::Error> {
                         //         .collect_vec(),
                         // )?;
 
-                        lb::iterate_queries!(structural_queries, structural_sets, struct_ted_k)
+                        lb::run_method_tracked(
+                            LBM::Structural,
+                            &pre,
+                            &queries,
+                            &run_config,
+                            checkpoint.as_mut(),
+                            timeout.as_mut(),
+                        )?
+                    }
+                    LBM::StructuralSplit => {
+                        let index_start = Instant::now();
+                        pre.split_sets()?;
+                        index_time = index_start.elapsed();
+                        budget.report(
+                            "Split structural indexing",
+                            base_bytes + pre.estimated_bytes(),
+                        );
+                        lb::run_method_tracked(
+                            LBM::StructuralSplit,
+                            &pre,
+                            &queries,
+                            &run_config,
+                            checkpoint.as_mut(),
+                            timeout.as_mut(),
+                        )?
+                    }
+                    LBM::SedQgram => {
+                        let index_start = Instant::now();
+                        pre.qgram_profiles()?;
+                        index_time = index_start.elapsed();
+                        budget.report("Sed q-gram indexing", base_bytes + pre.estimated_bytes());
+                        lb::run_method_tracked(
+                            LBM::SedQgram,
+                            &pre,
+                            &queries,
+                            &run_config,
+                            checkpoint.as_mut(),
+                            timeout.as_mut(),
+                        )?
+                    }
+                    LBM::SedUnbounded => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported by the unbounded SED".to_owned()
+                            ));
+                        }
+                        let index_start = Instant::now();
+                        let sed_indexes = pre.traversals()?;
+                        let sed_queries = index_queries::<SEDIndex>(&queries, &label_dict)?;
+                        index_time = index_start.elapsed();
+                        let start = Instant::now();
+                        let ranking = lb::sed_ranking(&sed_queries, sed_indexes);
+                        let duration = start.elapsed();
+                        let rows = ranking.iter().map(|(qid, tid, distance)| {
+                            format!("{qid},{},{distance}", tid + shard_range.start)
+                        });
+                        write_file(
+                            candidates_path(&format!("{current_method:?}"))?
+                                .with_extension("ranking.csv"),
+                            &std::iter::once("query_id,tree_id,sed".to_owned())
+                                .chain(rows)
+                                .collect_vec(),
+                        )?;
+                        let candidates = ranking
+                            .into_iter()
+                            .filter(|(qid, _, distance)| *distance <= sed_queries[*qid].0)
+                            .map(|(qid, tid, _)| (qid, tid))
+                            .collect();
+                        (candidates, duration)
+                    }
+                    LBM::Auto => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported by the auto method".to_owned()
+                            ));
+                        }
+                        let selection = lb::auto::select(&pre, &queries, auto_sample, &run_config)?;
+                        if !cli.quiet {
+                            println!("{selection}");
+                        }
+                        lb::auto::run_plan(selection.best, &pre, &queries, &run_config)?
+                    }
+                    LBM::Tuned => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported by the tuned method".to_owned()
+                            ));
+                        }
+                        let pipeline = lb::auto::tune(&pre, &queries, auto_sample, &run_config)?;
+                        if !cli.quiet {
+                            println!("{pipeline}");
+                        }
+                        lb::auto::run_pipeline(&pipeline.stages, &pre, &queries, &run_config)?
+                    }
+                    LBM::SedBkTree => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported by the BK-tree".to_owned()
+                            ));
+                        }
+                        let start = Instant::now();
+                        pre.sed_tree()?;
+                        index_time = start.elapsed();
+                        if !cli.quiet {
+                            println!("Building BK-tree took: {}ms", index_time.as_millis());
+                        }
+                        lb::run_method(LBM::SedBkTree, &pre, &queries, &run_config)?
+                    }
+                    LBM::Routed => {
+                        if checkpoint.is_some() {
+                            anyhow::bail!(error::Error::Config(
+                                "Checkpointing is not supported by routed queries".to_owned()
+                            ));
+                        }
+                        let query_methods = query_methods.as_deref().expect("routed run");
+                        let routed =
+                            routing::run_routed(&pre, &queries, query_methods, &run_config)?;
+                        if !cli.quiet {
+                            for stats in &routed.stats {
+                                println!("{stats}");
+                            }
+                        }
+                        routing::write_routes(
+                            candidates_path(&format!("{current_method:?}"))?
+                                .with_extension("routes.csv"),
+                            &routes,
+                            query_methods,
+                        )?;
+                        (routed.candidates, routed.duration)
                     }
                     _ => todo!(),
                 };
+                if !query_ids.is_empty() {
+                    candidates.retain(|&(qid, tid)| tid + shard_range.start != query_ids[qid]);
+                }
+                for method in &recheck {
+                    let before = candidates.len();
+                    let (refined, recheck_time) =
+                        lb::auto::refine(*method, &pre, &queries, &candidates, &run_config)?;
+                    candidates = refined;
+                    run.add_phase(
+                        format!("{current_method:?} recheck {method:?}"),
+                        recheck_time,
+                    );
+                    if !cli.quiet {
+                        println!(
+                            "{current_method:?} recheck {method:?}\ntime:{}ms\npruned:{}",
+                            recheck_time.as_millis(),
+                            before - candidates.len()
+                        );
+                    }
+                }
+                run.add_phase(format!("{current_method:?} index"), index_time);
+                run.add_phase(format!("{current_method:?} query"), duration);
+                run.add_query_counts(&format!("{current_method:?}"), queries.len(), &candidates);
 
-                println!(
-                    "{current_method:?}\ntime:{duration_ms}ms\ncandidates:{canlen}",
-                    duration_ms = duration.as_millis(),
-                    canlen = candidates.len()
+                // ids of the shard trees, before they are shifted
+                let estimate = verify_sample.map(|sample_size| {
+                    run.time(format!("{current_method:?} verification"), || {
+                        verify_sample::estimate_precision(
+                            trees,
+                            &queries,
+                            &candidates,
+                            sample_size,
+                            verify_confidence,
+                            verify_seed,
+                            verifier.as_ref(),
+                        )
+                    })
+                });
+                let estimate = estimate.transpose()?;
+                let recall = recall_sample.map(|sample_size| {
+                    // queries from the dataset are not paired with their own tree
+                    let excluded = query_ids
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(qid, &tid)| Some((qid, tid.checked_sub(shard_range.start)?)));
+                    let known = candidates.iter().copied().chain(excluded).collect_vec();
+                    run.time(format!("{current_method:?} recall sampling"), || {
+                        verify_sample::estimate_recall(
+                            trees,
+                            &queries,
+                            &known,
+                            sample_size,
+                            verify_confidence,
+                            verify_seed,
+                            verifier.as_ref(),
+                        )
+                    })
+                });
+                let recall = recall.transpose()?;
+                let budgeted = verify_budget
+                    .map(|budget| {
+                        let start = Instant::now();
+                        let budgeted = join::verify_with_budget(
+                            &pre,
+                            &queries,
+                            &candidates,
+                            budget,
+                            verify_order,
+                            verifier.as_ref(),
+                        );
+                        run.add_phase(format!("{current_method:?} verification"), start.elapsed());
+                        budgeted
+                    })
+                    .transpose()?;
+                let approximate = approximate_verify
+                    .then(|| {
+                        let start = Instant::now();
+                        let approximate =
+                            join::approximate_verify(&pre, &queries, &candidates, &ted_costs);
+                        run.add_phase(format!("{current_method:?} approximation"), start.elapsed());
+                        approximate
+                    })
+                    .transpose()?;
+                for (_, tid) in candidates.iter_mut() {
+                    *tid += shard_range.start;
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(store) = store.as_mut() {
+                    let method = format!("{current_method:?}");
+                    store.add_timing(&method, duration, candidates.len())?;
+                    if !count_only {
+                        store.add_candidates(&method, &candidates)?;
+                    }
+                }
+                budget.report(
+                    &format!("{current_method:?} candidates"),
+                    base_bytes + pre.estimated_bytes() + candidates.estimated_bytes(),
                 );
-                let mut output_file = output.clone();
-                output_file.push(format!("{current_method:#?}_candidates.csv"));
+                let output_file = candidates_path(&format!("{current_method:?}"))?;
+                if let Some(timeout) = &timeout {
+                    // the other methods, chunked, grouped and clustered runs do not scan query
+                    // by query
+                    let scanned = !chunked
+                        && !group_queries
+                        && label_clusters.is_none()
+                        && matches!(
+                            current_method,
+                            LBM::Lblint
+                                | LBM::Sed
+                                | LBM::Structural
+                                | LBM::StructuralSplit
+                                | LBM::SedQgram
+                        );
+                    if !scanned {
+                        eprintln!(
+                            "{current_method:?} does not stop queries at --per-query-timeout"
+                        );
+                    } else if !timeout.timed_out.is_empty() {
+                        let method = format!("{current_method:?}");
+                        let timed_out = query_timeout::TimedOut {
+                            method: &method,
+                            limit: timeout.limit,
+                            queries: &timeout.timed_out,
+                        };
+                        eprintln!("{timed_out}");
+                        timed_out.write(&output_file.with_extension("timed_out.csv"))?;
+                        run.add_timed_out(method, timeout.limit, &timeout.timed_out);
+                    }
+                }
 
-                candidates.par_sort();
-                write_file(
-                    output_file,
-                    &candidates
+                let sort_start = Instant::now();
+                if !count_only {
+                    candidates.par_sort_unstable();
+                }
+                let sort_time = sort_start.elapsed();
+                run.add_phase(format!("{current_method:?} sort"), sort_time);
+                let write_start = Instant::now();
+                if count_only {
+                    let mut counts = vec![0; queries.len()];
+                    for (qid, _) in candidates.iter() {
+                        counts[*qid] += 1;
+                    }
+                    let rows = counts
                         .iter()
-                        .map(|(c1, c2)| format!("{c1},{c2}"))
-                        .collect_vec(),
+                        .enumerate()
+                        .map(|(qid, count)| format!("{qid},{count}"));
+                    write_file(
+                        output_file.with_extension("counts.csv"),
+                        &std::iter::once("query_id,count".to_owned())
+                            .chain(rows)
+                            .collect_vec(),
+                    )?;
+                } else if let Some(metadata) = &metadata {
+                    metadata.write_candidates(&output_file, &candidates)?;
+                } else if let Some(annotator) = &pair_annotator {
+                    annotator.write_candidates(&output_file, &candidates)?;
+                } else {
+                    // candidates stay query-tree pairs for the verification below
+                    candidate_file::write_candidates(
+                        &output_file,
+                        &pair_order.arrange(&candidates, &query_ids),
+                        candidates_format,
+                        candidate_file::BinaryOptions {
+                            delta,
+                            compress: zstd,
+                            order: pair_order,
+                        },
+                    )?;
+                }
+                let write_time = write_start.elapsed();
+                run.add_phase(format!("{current_method:?} write"), write_time);
+
+                if !cli.pretty {
+                    println!(
+                        "{current_method:?}\ntime:{duration_ms}ms\ncandidates:{canlen}\nindex:{index_ms}ms\nsort:{sort_ms}ms\nwrite:{write_ms}ms",
+                        duration_ms = duration.as_millis(),
+                        canlen = candidates.len(),
+                        index_ms = index_time.as_millis(),
+                        sort_ms = sort_time.as_millis(),
+                        write_ms = write_time.as_millis(),
+                    );
+                }
+                if let Some(peak) = peak_memory.peak() {
+                    println!("peak memory:{}", memory::format_bytes(peak));
+                    run.add_peak_memory(format!("{current_method:?}"), peak);
+                }
+                snapshot.record(format!("{current_method:?} candidates"), candidates.len());
+                if let Some(estimate) = &estimate {
+                    if !cli.pretty {
+                        println!("{:.*}", floats.precision, estimate);
+                    }
+                    snapshot.record(
+                        format!("{current_method:?} precision"),
+                        format!("{:.6}", estimate.precision()),
+                    );
+                }
+                if let Some(recall) = recall.as_ref().filter(|_| !cli.pretty) {
+                    println!("{:.*}", floats.precision, recall);
+                    if let Some(estimate) = &estimate {
+                        let (recall, low, high) = recall.recall(estimate);
+                        println!(
+                            "recall:{} ({:.0}% interval {}-{})",
+                            floats.format(recall),
+                            verify_confidence * 100.0,
+                            floats.format(low),
+                            floats.format(high)
+                        );
+                    }
+                }
+                summaries.push(pretty::MethodSummary {
+                    method: format!("{current_method:?}"),
+                    time: duration,
+                    candidates: candidates.len(),
+                    precision: estimate.as_ref().map(|estimate| estimate.precision()),
+                    recall: exact_recall.or_else(|| {
+                        let (recall, _, _) = recall.as_ref()?.recall(estimate.as_ref()?);
+                        Some(recall)
+                    }),
+                });
+                if let Some(mut budgeted) = budgeted {
+                    println!(
+                        "verified:{}\nresults:{}\nunverified:{}",
+                        budgeted.verified,
+                        budgeted.results.len(),
+                        budgeted.unverified.len()
+                    );
+                    let shared = if common {
+                        budgeted
+                            .results
+                            .par_iter()
+                            .map(|(qid, tid, _)| {
+                                let common = common_subtree::common_structure(
+                                    &queries[*qid].1,
+                                    &trees[*tid],
+                                );
+                                format!(",{},{}", common.subtree, common.embedded)
+                            })
+                            .collect()
+                    } else {
+                        vec![String::new(); budgeted.results.len()]
+                    };
+                    for (_, tid, _) in budgeted.results.iter_mut() {
+                        *tid += shard_range.start;
+                    }
+                    for (_, tid) in budgeted.unverified.iter_mut() {
+                        *tid += shard_range.start;
+                    }
+                    let rows = budgeted.results.iter().zip(&shared).map(
+                        |((qid, tid, distance), shared)| format!("{qid},{tid},{distance}{shared}"),
+                    );
+                    let header = if common {
+                        "query_id,tree_id,distance,common_subtree,common_embedded"
+                    } else {
+                        "query_id,tree_id,distance"
+                    };
+                    write_file(
+                        output_file.with_extension("verified.csv"),
+                        &std::iter::once(header.to_owned()).chain(rows).collect_vec(),
+                    )?;
+                    candidate_file::write_csv(
+                        output_file.with_extension("unverified.csv"),
+                        &budgeted.unverified,
+                    )?;
+                    // results of an incomplete candidate set would miss pairs as exact results
+                    let timed_out = timeout.as_ref().is_some_and(|t| !t.timed_out.is_empty());
+                    let partial = [
+                        (!budgeted.unverified.is_empty(), "candidates are unverified"),
+                        (label_clusters.is_some(), "--label-clusters misses results"),
+                        (shard.is_some(), "other shards have the other trees"),
+                        (timed_out, "queries timed out"),
+                    ]
+                    .into_iter()
+                    .find_map(|(partial, reason)| partial.then_some(reason));
+                    if let Some(reason) = partial {
+                        if !cli.quiet {
+                            println!(
+                                "{current_method:?} results are partial, {} is not written: {reason}",
+                                output_file.with_extension("results.csv").display()
+                            );
+                        }
+                    } else {
+                        validation::write_results(
+                            &output_file.with_extension("results.csv"),
+                            &budgeted.results,
+                            pair_order,
+                            &query_ids,
+                        )?;
+                    }
+                }
+                if let Some(approximate) = approximate {
+                    println!(
+                        "certain:{}\nambiguous:{}\nrejected:{}",
+                        approximate.results, approximate.ambiguous, approximate.rejected
+                    );
+                    let rows = approximate.bounded.iter().map(|(qid, tid, lower, upper)| {
+                        format!("{qid},{},{lower},{upper}", tid + shard_range.start)
+                    });
+                    write_file(
+                        output_file.with_extension("approximate.csv"),
+                        &std::iter::once("query_id,tree_id,lower_bound,upper_bound".to_owned())
+                            .chain(rows)
+                            .collect_vec(),
+                    )?;
+                }
+                if let Some(ckpt) = checkpoint {
+                    ckpt.finish()?;
+                }
+            }
+            if cli.pretty && !summaries.is_empty() {
+                let table = pretty::methods_table(&summaries);
+                print!("{}", table.render(floats, pretty::colors_enabled()));
+            }
+            run.write(&output)?;
+        }
+        Commands::HistJoin {
+            output,
+            threshold,
+            filters,
+            results_path,
+        } => {
+            use indexes::histograms::HistFilter;
+            create_dir_all(&output)?;
+            let filters = if filters.is_empty() {
+                HistFilter::value_variants().to_vec()
+            } else {
+                filters
+            };
+            for filter in filters {
+                let name = filter
+                    .to_possible_value()
+                    .map_or_else(|| format!("{filter:?}"), |v| v.get_name().to_owned());
+                let start = Instant::now();
+                let (times, candidates) = indexes::histograms::join(
+                    filter,
+                    &trees,
+                    &label_dict,
+                    threshold,
+                    indexes::histograms::HistogramDivisors::TIGHT,
+                );
+                let total = start.elapsed();
+                if !cli.quiet {
+                    println!(
+                        "hist {name}: {} candidates in {}ms",
+                        candidates.len(),
+                        total.as_millis()
+                    );
+                }
+                let validation = write_precision_and_filter_times(
+                    &output,
+                    &name,
+                    threshold,
+                    &times,
+                    total,
+                    &candidates,
+                    results_path.as_deref(),
+                    cli.max_memory,
                 )?;
+                if let Some(validation) = validation {
+                    snapshot.record(
+                        format!("hist {name} precision"),
+                        format!("{:.6}", validation.precision),
+                    );
+                }
             }
         }
-        Commands::Validate {
+        Commands::Bench {
+            query_file,
+            output,
+            methods,
+            thresholds,
+            results_path,
+            format,
+            cache,
+            runs,
+            kernels,
+            pairs,
+            size_buckets,
+        } => {
+            let options = bench::BenchOptions {
+                cache,
+                runs: runs.into(),
+                quiet: cli.quiet,
+                floats,
+                size_buckets,
+            };
+            if let Some(mut kernels) = kernels {
+                if kernels.is_empty() {
+                    kernels = microbench::Kernel::ALL.to_vec();
+                }
+                let records =
+                    bench::run_kernels(&kernels, &thresholds, &trees, &label_dict, pairs, options)?;
+                bench::write_report(&output, &records, format)?;
+                run.finish();
+                return Ok(());
+            }
+            let query_file = query_file.expect("required without kernels");
+            let methods = if methods.is_empty() {
+                LowerBoundMethods::REGISTERED.to_vec()
+            } else {
+                methods
+            };
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?;
+            let ground_truth = results_path
+                .map(|rp| bench::read_ground_truth(&rp))
+                .transpose()?;
+            let records = bench::run(
+                &methods,
+                &thresholds,
+                &trees,
+                &queries,
+                &label_dict,
+                ground_truth.as_deref(),
+                options,
+            )?;
+            bench::write_report(&output, &records, format)?;
+            #[cfg(feature = "sqlite")]
+            if let Some(store) = store.as_mut() {
+                store.add_bench(&records)?;
+            }
+        }
+        Commands::Tune {
+            query_file,
+            results_path,
+            output,
+            format,
+            folds,
+            seed,
+            target_recall,
+            methods,
+            q_values,
+            split_groups,
+            prefix_extra_labels,
+        } => {
+            if !(target_recall > 0.0 && target_recall <= 1.0) {
+                cmd.error(
+                    ErrorKind::InvalidValue,
+                    "Target recall must be above 0 and at most 1!",
+                )
+                .exit();
+            }
+            if q_values.contains(&0)
+                || split_groups
+                    .iter()
+                    .any(|g| !(1..=structural_filter::LabelSetConverter::MAX_SPLIT).contains(g))
+            {
+                cmd.error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "Q-gram sizes must be positive and split groups from 1 to {}!",
+                        structural_filter::LabelSetConverter::MAX_SPLIT
+                    ),
+                )
+                .exit();
+            }
+            let methods = if methods.is_empty() {
+                LowerBoundMethods::REGISTERED
+                    .iter()
+                    .chain(&[
+                        LowerBoundMethods::StructuralSplit,
+                        LowerBoundMethods::SedQgram,
+                    ])
+                    .copied()
+                    .collect()
+            } else {
+                methods
+            };
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?;
+            let ground_truth = bench::read_ground_truth(&results_path)?;
+            let grid = tune::Grid {
+                methods,
+                q_values,
+                split_groups,
+                prefix_extra_labels,
+            };
+            let options = tune::TuneOptions {
+                folds,
+                seed,
+                target_recall,
+                quiet: cli.quiet,
+                floats,
+            };
+            let tuning = tune::tune(
+                &trees,
+                &label_dict,
+                &queries,
+                &ground_truth,
+                &grid.configs(),
+                options,
+            )?;
+            bench::write_report(&output, &tuning.folds, format)?;
+            println!("Tuned configuration: {}", tuning.best);
+        }
+        Commands::Lsh {
+            query_file,
+            output,
+            sketch,
+            bands,
+            rows,
+            pq_p,
+            pq_q,
             results_path,
+        } => {
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?;
+            let params = lb::lsh::LshParams {
+                sketch,
+                bands: bands.into(),
+                rows: rows.into(),
+                p: pq_p.into(),
+                q: pq_q.into(),
+            };
+            let start = Instant::now();
+            let index = lb::lsh::LshIndex::new(CollectionView::new(&trees), params)?;
+            let index_time = start.elapsed();
+            let (candidates, time) = index.run(&queries);
+            println!(
+                "Indexing took {}ms, candidates {}ms, {} candidates",
+                index_time.as_millis(),
+                time.as_millis(),
+                candidates.len()
+            );
+            if let Some(results_path) = results_path {
+                let ground_truth = bench::read_ground_truth(&results_path)?;
+                let (precision, recall) =
+                    bench::precision_recall(&candidates, &ground_truth, |qid| {
+                        queries.get(qid).map(|(k, _)| *k)
+                    });
+                println!(
+                    "Precision: {}, recall: {}",
+                    floats.format(precision),
+                    floats.format(recall)
+                );
+                snapshot.record("precision", format!("{precision:.6}"));
+                snapshot.record("recall", format!("{recall:.6}"));
+            }
+            snapshot.record("candidates", candidates.len());
+            candidate_file::write_csv(output, &candidates)?;
+        }
+        Commands::PqGramDistance {
+            query_file,
+            output,
+            pq_p,
+            pq_q,
+            max_distance,
+        } => {
+            let (p, q) = (pq_p.into(), pq_q.into());
+            let start = Instant::now();
+            let tree_profiles = pq_gram_distance::profiles(&trees, p, q);
+            let (id_columns, distances) = match query_file {
+                Some(query_file) => {
+                    let queries =
+                        parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?;
+                    let query_profiles =
+                        pq_gram_distance::profiles(queries.par_iter().map(|(_, q)| q), p, q);
+                    let distances = pq_gram_distance::query_distances(
+                        &query_profiles,
+                        &tree_profiles,
+                        max_distance,
+                    );
+                    (["query_id", "tree_id"], distances)
+                }
+                None => (
+                    ["t1", "t2"],
+                    pq_gram_distance::all_pairs(&tree_profiles, max_distance),
+                ),
+            };
+            if !cli.quiet {
+                println!(
+                    "{} pq-gram distances up to {max_distance} in {}ms",
+                    distances.len(),
+                    start.elapsed().as_millis()
+                );
+            }
+            pq_gram_distance::write_distances(output, id_columns, &distances)?;
+        }
+        Commands::VpTree {
+            query_file,
+            output,
+            method,
+            leaf_size,
+            compare_scan,
+            verifier,
+            verifier_command,
+            verifier_batch,
+        } => {
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?;
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+            let start = Instant::now();
+            let vp_tree = vp_tree::VpTree::new(&trees, leaf_size);
+            println!(
+                "VP-tree with {} pivots built in {}ms",
+                vp_tree.pivots(),
+                start.elapsed().as_millis()
+            );
+            let start = Instant::now();
+            let (results, stats) = vp_tree::run(&vp_tree, method, &pre, &queries)?;
+            println!(
+                "VP-tree\ntime:{}ms\npivot distances:{}\npruned:{}\ncandidates:{}\nresults:{}",
+                start.elapsed().as_millis(),
+                stats.pivots,
+                stats.pruned,
+                stats.candidates,
+                results.len()
+            );
+            if compare_scan {
+                let start = Instant::now();
+                let (candidates, _) =
+                    lb::run_method(method, &pre, &queries, &lb::RunConfig::default())?;
+                let verifier = verifier::build(
+                    verifier,
+                    ted::CostModel::UNIT,
+                    verifier_command.as_deref(),
+                    verifier_batch,
+                    &label_dict,
+                )?;
+                let scan_results = join::verify(&trees, &queries, &candidates, verifier.as_ref())?;
+                println!(
+                    "Scan\ntime:{}ms\ncandidates:{}\nresults:{}",
+                    start.elapsed().as_millis(),
+                    candidates.len(),
+                    scan_results.len()
+                );
+                anyhow::ensure!(scan_results == results, "VP-tree and scan results differ");
+            }
+            let mut lines = vec!["query_id,tree_id,distance".to_owned()];
+            lines.extend(results.iter().map(|(q, t, d)| format!("{q},{t},{d}")));
+            write_file(output, &lines)?;
+        }
+        Commands::Pairs {
+            pairs_file,
+            output,
             threshold,
-            candidates_path,
+            methods,
+            ted,
+            common,
+            cache_size,
+            qgram_size,
         } => {
-            let false_positives = validation::validate(&candidates_path, &results_path, threshold)?;
-            let candidates = validation::read_candidates(&candidates_path)?;
-            let (correct, extra, precision, mean_selectivity) =
-                validation::get_precision(&candidates, &results_path, threshold, trees.len())?;
-
-            println!("Correct trees;Extra trees;Precision;Mean Selectivity");
-            println!("{correct};{extra};{precision};{mean_selectivity:.7}%");
-            println!("Printing false positives in bracket");
+            let methods = if methods.is_empty() {
+                LowerBoundMethods::REGISTERED.to_vec()
+            } else {
+                methods
+            };
+            let pairs = pairs::read_pairs(&pairs_file)?;
+            let cache = (cache_size > 0).then(|| pairs::PairCache::new(cache_size));
+            let exact = pairs::ExactValues { ted, common };
+            let mut lines = vec![pairs::header(&methods, exact)];
+            lines.extend(pairs::run(
+                &pairs,
+                &methods,
+                &trees,
+                &label_dict,
+                threshold,
+                &pairs::BoundOptions {
+                    qgram_size: qgram_size as usize,
+                    ..Default::default()
+                },
+                exact,
+                cache.as_ref(),
+            )?);
+            if let (Some(cache), false) = (&cache, cli.quiet) {
+                let (hits, misses) = cache.stats();
+                println!("Cache hits: {hits}, misses: {misses}");
+            }
+            write_file(output, &lines)?;
+        }
+        Commands::Cluster {
+            output,
+            threshold,
+            method,
+            linkage,
+            matrix,
+            verifier,
+            verifier_command,
+            verifier_batch,
+        } => {
+            let verifier = verifier::build(
+                verifier,
+                ted::CostModel::UNIT,
+                verifier_command.as_deref(),
+                verifier_batch,
+                &label_dict,
+            )?;
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+            let pairs = join::self_join(method, &pre, threshold, verifier.as_ref())?;
+            let clusters = cluster::cluster(trees.len(), &pairs, linkage);
+            if !cli.quiet {
+                println!(
+                    "Found {} pairs within {threshold}, {} clusters",
+                    pairs.len(),
+                    clusters.iter().max().map_or(0, |c| c + 1)
+                );
+            }
+            if let Some(matrix) = matrix {
+                let mut lines = vec!["t1,t2,distance".to_owned()];
+                lines.extend(pairs.iter().map(|(t1, t2, d)| format!("{t1},{t2},{d}")));
+                write_file(matrix, &lines)?;
+            }
+            let mut lines = vec!["tree_id,cluster".to_owned()];
+            lines.extend(clusters.iter().enumerate().map(|(t, c)| format!("{t},{c}")));
+            write_file(output, &lines)?;
+        }
+        Commands::KnnGraph {
+            output,
+            neighbors,
+            method,
+            max_distance,
+        } => {
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+            let edges = knn::knn_graph(method, &pre, neighbors, max_distance)?;
+            if !cli.quiet {
+                println!("Found {} edges", edges.len());
+            }
+            let mut lines = vec!["tree,neighbor,distance".to_owned()];
+            lines.extend(edges.iter().map(|(t, n, d)| format!("{t},{n},{d}")));
+            write_file(output, &lines)?;
+        }
+        Commands::TopKJoin {
+            query_file,
+            output,
+            k,
+            method,
+            max_distance,
+        } => {
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?
+                .into_iter()
+                .map(|(_, query)| query)
+                .collect::<Vec<_>>();
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+            let pairs = knn::top_k_join(method, &pre, &queries, k, max_distance)?;
+            if !cli.quiet {
+                println!("Found {} pairs for {} queries", pairs.len(), queries.len());
+            }
+            let mut lines = vec!["query,tree,distance".to_owned()];
+            lines.extend(pairs.iter().map(|(q, t, d)| format!("{q},{t},{d}")));
+            write_file(output, &lines)?;
+        }
+        Commands::RsJoin {
+            other,
+            output,
+            threshold,
+            method,
+            candidates_only,
+            verifier,
+            verifier_command,
+            verifier_batch,
+        } => {
+            // the other collection gets a dictionary of its own, merging remaps its ids
+            let mut other_labels = LabelDict::default();
+            let options = parsing::ParseOptions {
+                recover_brackets: false,
+                ..parse_options
+            };
+            let (mut others, _, _) =
+                parsing::parse_dataset_reporting(&other, &mut other_labels, options)?;
+            let remap = label_dict.merge(&other_labels);
+            if !remap.is_identity() {
+                for tree in &mut others {
+                    tree.remap_labels(&remap)?;
+                }
+            }
+            collection::sort_by_size(&mut others);
+            let verifier = (!candidates_only)
+                .then(|| {
+                    verifier::build(
+                        verifier,
+                        ted::CostModel::UNIT,
+                        verifier_command.as_deref(),
+                        verifier_batch,
+                        &label_dict,
+                    )
+                })
+                .transpose()?;
+            let pre = lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+            let pairs = join::rs_join(method, &pre, &others, threshold, verifier.as_deref())?;
+            if !cli.quiet {
+                println!(
+                    "Found {} {} between {} and {} trees",
+                    pairs.len(),
+                    if candidates_only {
+                        "candidates"
+                    } else {
+                        "pairs"
+                    },
+                    trees.len(),
+                    others.len()
+                );
+            }
+            let mut lines = vec!["tree,other_tree,distance".to_owned()];
+            lines.extend(pairs.iter().map(|(t, o, d)| {
+                format!("{t},{o},{}", d.map_or(String::new(), |d| d.to_string()))
+            }));
+            write_file(output, &lines)?;
+        }
+        Commands::Calibrate {
+            query_file,
+            output,
+            results,
+            sample,
+            seed,
+            calibrated_queries,
+        } => {
+            let queries = parsing::parse_queries_with(&query_file, &mut label_dict, parse_options)?
+                .into_iter()
+                .map(|(_, query)| query)
+                .collect_vec();
+            let calibration = calibrate::calibrate(&trees, &queries, results, sample, seed);
+            let global = calibration.global;
+            println!(
+                "sampled:{}
+threshold:{}
+estimated_results:{:.1}",
+                calibration.sampled, global.threshold, global.estimated_results
+            );
+            let mut lines = vec!["query_id,threshold,estimated_results".to_owned()];
+            lines.extend(
+                calibration
+                    .queries
+                    .iter()
+                    .enumerate()
+                    .map(|(qid, s)| floats.line(&[&qid, &s.threshold, &s.estimated_results], ',')),
+            );
+            write_file(output, &lines)?;
+            if let Some(path) = calibrated_queries {
+                let names = parsing::label_names(&label_dict);
+                let lines = queries
+                    .iter()
+                    .zip(&calibration.queries)
+                    .map(|(query, s)| {
+                        let bracket = parsing::tree_to_labeled_bracket(query, &names);
+                        format!("{};{bracket}", s.threshold)
+                    })
+                    .collect_vec();
+                write_file(path, &lines)?;
+            }
+        }
+        Commands::Dedup {
+            output,
+            threshold,
+            method,
+            deduplicated,
+            verifier,
+            verifier_command,
+            verifier_batch,
+        } => {
+            // identical trees are the only ones within 0, equal fingerprints find them
+            let pairs = if threshold == 0 {
+                dedup::exact_duplicate_pairs(&trees)
+            } else {
+                let verifier = verifier::build(
+                    verifier,
+                    ted::CostModel::UNIT,
+                    verifier_command.as_deref(),
+                    verifier_batch,
+                    &label_dict,
+                )?;
+                let pre =
+                    lb::preprocess::Preprocessed::new(CollectionView::new(&trees), &label_dict)?;
+                join::self_join(method, &pre, threshold, verifier.as_ref())?
+            };
+            let clusters = dedup::duplicate_clusters(trees.len(), &pairs, threshold);
+            let duplicates = clusters.iter().map(|c| c.trees.len()).sum::<usize>();
+            let label_names = parsing::label_names(&label_dict);
+            if !cli.quiet {
+                println!(
+                    "Found {} duplicate clusters with {duplicates} trees, {} trees are redundant",
+                    clusters.len(),
+                    duplicates - clusters.len()
+                );
+                for (i, c) in clusters.iter().take(10).enumerate() {
+                    println!(
+                        "Cluster {i}: {} trees, representative {}: {}",
+                        c.trees.len(),
+                        c.representative,
+                        parsing::tree_to_labeled_bracket(&trees[c.representative], &label_names)
+                    );
+                }
+            }
+            let mut lines = vec!["cluster,tree_id,representative".to_owned()];
+            lines.extend(clusters.iter().enumerate().flat_map(|(i, c)| {
+                c.trees
+                    .iter()
+                    .map(move |t| format!("{i},{t},{}", c.representative))
+            }));
+            write_file(output, &lines)?;
+
+            if let Some(deduplicated) = deduplicated {
+                let mut redundant = vec![false; trees.len()];
+                for c in clusters.iter() {
+                    for t in c.trees.iter().filter(|t| **t != c.representative) {
+                        redundant[*t] = true;
+                    }
+                }
+                let kept = trees
+                    .iter()
+                    .zip(redundant)
+                    .filter(|(_, redundant)| !redundant)
+                    .map(|(t, _)| parsing::tree_to_labeled_bracket(t, &label_names))
+                    .collect_vec();
+                write_file(deduplicated, &kept)?;
+            }
+        }
+        #[cfg(feature = "serve")]
+        Commands::Serve {
+            listen,
+            methods,
+            workers,
+            cache_size,
+            wal,
+            per_query_timeout,
+        } => {
+            let methods = if methods.is_empty() {
+                LowerBoundMethods::REGISTERED.to_vec()
+            } else {
+                methods
+            };
+            serve::run(
+                trees,
+                label_dict,
+                &methods,
+                &listen,
+                workers,
+                cache_size,
+                wal.as_deref(),
+                per_query_timeout.map(Duration::from_millis),
+                cli.quiet,
+            )?;
+        }
+        #[cfg(feature = "grpc")]
+        Commands::Grpc {
+            listen,
+            methods,
+            cache_size,
+            per_query_timeout,
+        } => {
+            let methods = if methods.is_empty() {
+                LowerBoundMethods::REGISTERED.to_vec()
+            } else {
+                methods
+            };
+            grpc::run(
+                trees,
+                label_dict,
+                &methods,
+                listen,
+                cache_size,
+                per_query_timeout.map(Duration::from_millis),
+                cli.quiet,
+            )?;
+            return Ok(());
+        }
+        Commands::GenQueries {
+            output,
+            count,
+            edits,
+            threshold,
+            relative_threshold,
+            seed,
+        } => {
+            let threshold = match (threshold, relative_threshold) {
+                (_, Some(ratio)) => workload::ThresholdSpec::Relative(ratio),
+                (Some(k), None) => workload::ThresholdSpec::Absolute(k),
+                (None, None) => workload::ThresholdSpec::Absolute(1),
+            };
+            let queries =
+                workload::generate_queries(&trees, &label_dict, count, edits, threshold, seed);
+            write_file(output, &queries)?;
+        }
+        Commands::Mutate {
+            output,
+            ground_truth,
+            edits,
+            as_queries,
+            seed,
+        } => {
+            let names = parsing::label_names(&label_dict);
+            let mutated =
+                edits::mutate_collection(&trees, edits, seed, parsing::max_label(&label_dict));
             write_file(
-                PathBuf::from("./resources/results/false-positives.bracket"),
-                &false_positives
+                output,
+                &mutated
                     .iter()
-                    .map(|(c1, c2)| {
-                        format!(
-                            "\"{}\",\"{}\"",
-                            tree_to_string(&trees[*c1], TreeOutput::BracketNotation),
-                            tree_to_string(&trees[*c2], TreeOutput::BracketNotation)
-                        )
+                    .map(|(tree, _)| {
+                        let bracket = parsing::tree_to_labeled_bracket(tree, &names);
+                        if as_queries {
+                            format!("{edits};{bracket}")
+                        } else {
+                            bracket
+                        }
                     })
                     .collect_vec(),
             )?;
-            println!("Printing not found in graphviz");
+            let mut truth = vec!["t1,t2,distance".to_owned()];
+            truth.extend(
+                mutated
+                    .iter()
+                    .enumerate()
+                    .map(|(tid, (_, distance))| format!("{tid},{tid},{distance}")),
+            );
+            write_file(ground_truth, &truth)?;
+            if !cli.quiet {
+                let changed = mutated.iter().filter(|(_, d)| *d > 0).count();
+                println!("Mutated {changed} of {} trees", mutated.len());
+            }
+        }
+        Commands::Anonymize {
+            output,
+            mapping,
+            mode,
+            salt,
+        } => {
+            let anonymous = anonymize::anonymous_names(&label_dict, mode, &salt)?;
+            let names = anonymous.iter().map(String::as_str).collect_vec();
             write_file(
-                PathBuf::from("./resources/results/false-positives.graphviz"),
+                output,
+                &trees
+                    .iter()
+                    .map(|tree| parsing::tree_to_labeled_bracket(tree, &names))
+                    .collect_vec(),
+            )?;
+            anonymize::write_mapping(&mapping, &label_dict, &anonymous)?;
+        }
+        Commands::Export {
+            output,
+            format,
+            query_file,
+            queries_output,
+        } => {
+            // queries first, so label ids of both files come from the same dictionary
+            let queries = query_file
+                .map(|path| parsing::parse_queries_with(&path, &mut label_dict, parse_options))
+                .transpose()?
+                .map(|queries| queries.into_iter().map(|(_, query)| query).collect_vec());
+            write_file(output, &export::export_trees(&trees, &label_dict, format))?;
+            if let (Some(queries), Some(path)) = (queries, queries_output) {
+                write_file(path, &export::export_trees(&queries, &label_dict, format))?;
+            }
+        }
+        Commands::Show { id } => {
+            let names = parsing::label_names(&label_dict);
+            for id in id {
+                let tree = trees.get(id).ok_or_else(|| {
+                    error::Error::Config(format!(
+                        "Tree id {id} is out of range of {} trees",
+                        trees.len()
+                    ))
+                })?;
+                println!("Tree {id}, {} nodes", tree.count());
+                print!("{}", tree_to_string(tree, TreeOutput::Ascii(&names)));
+            }
+        }
+        Commands::Reorder { output, id_map, by } => {
+            let order = reorder::reorder(&trees, by);
+            if !cli.quiet {
+                let identity = (0..trees.len()).collect_vec();
+                println!(
+                    "Mean label signature bits differing between neighbours: {:.2} before, {:.2} after",
+                    reorder::neighbour_distance(&trees, &identity),
+                    reorder::neighbour_distance(&trees, &order)
+                );
+            }
+            write_file(
+                output,
+                &export::export_trees(
+                    order.iter().map(|&tid| &trees[tid]),
+                    &label_dict,
+                    export::ExportFormat::Bracket,
+                ),
+            )?;
+            let mut map_lines = vec!["id,tree_id".to_owned()];
+            map_lines.extend(
+                order
+                    .iter()
+                    .enumerate()
+                    .map(|(id, tid)| format!("{id},{tid}")),
+            );
+            write_file(id_map, &map_lines)?;
+        }
+        Commands::Split {
+            dataset_output,
+            query_output,
+            mapping,
+            queries,
+            strategy,
+            threshold,
+            seed,
+        } => {
+            let query_ids = split::select_queries(trees.len(), queries, strategy, seed);
+            let (dataset_ids, query_ids) = split::partition(trees.len(), &query_ids);
+            let names = parsing::label_names(&label_dict);
+            write_file(
+                dataset_output,
+                &dataset_ids
+                    .iter()
+                    .map(|tid| parsing::tree_to_labeled_bracket(&trees[*tid], &names))
+                    .collect_vec(),
+            )?;
+            write_file(
+                query_output,
+                &query_ids
+                    .iter()
+                    .map(|tid| {
+                        let bracket = parsing::tree_to_labeled_bracket(&trees[*tid], &names);
+                        format!("{threshold};{bracket}")
+                    })
+                    .collect_vec(),
+            )?;
+            let mut mapping_lines = vec!["part,id,tree_id".to_owned()];
+            mapping_lines.extend(
+                dataset_ids
+                    .iter()
+                    .enumerate()
+                    .map(|(id, tid)| format!("dataset,{id},{tid}")),
+            );
+            mapping_lines.extend(
+                query_ids
+                    .iter()
+                    .enumerate()
+                    .map(|(id, tid)| format!("query,{id},{tid}")),
+            );
+            write_file(mapping, &mapping_lines)?;
+        }
+        Commands::Validate {
+            results_path,
+            threshold,
+            candidates_path,
+            graphviz,
+            tree_format,
+        } => {
+            let validation::Validation {
+                not_found: false_positives,
+                correct,
+                extra,
+                precision,
+                mean_selectivity,
+                runs,
+                order,
+            } = validation::validate(
+                &candidates_path,
+                &results_path,
+                threshold,
+                trees.len(),
+                cli.max_memory,
+            )?;
+            if runs > 0 && !cli.quiet {
+                println!("Sorted candidates in {runs} runs on disk");
+            }
+            if order != candidate_file::PairOrder::QueryTree && !cli.quiet {
+                println!("Candidates are {order} pairs");
+            }
+
+            if cli.pretty {
+                let found = correct + false_positives.len();
+                let mut table = pretty::Table::new(vec![
+                    "correct",
+                    "extra",
+                    "precision",
+                    "recall",
+                    "mean selectivity",
+                ]);
+                table.push(vec![
+                    pretty::Cell::Count(correct),
+                    pretty::Cell::Count(extra),
+                    pretty::Cell::Ratio(precision as f64),
+                    pretty::Cell::Ratio(correct as f64 / found.max(1) as f64),
+                    pretty::Cell::Float(mean_selectivity),
+                ]);
+                print!("{}", table.render(floats, pretty::colors_enabled()));
+            } else {
+                println!("Correct trees;Extra trees;Precision;Mean Selectivity");
+                println!(
+                    "{}%",
+                    floats.line(&[&correct, &extra, &precision, &mean_selectivity], ';')
+                );
+            }
+            snapshot.record("correct", correct);
+            snapshot.record("extra", extra);
+            snapshot.record("precision", format!("{precision:.6}"));
+            #[cfg(feature = "sqlite")]
+            if let Some(store) = &store {
+                store.add_validation(
+                    &candidates_path,
+                    threshold,
+                    correct,
+                    extra,
+                    precision,
+                    mean_selectivity,
+                )?;
+            }
+            println!("Printing false positives in {}", tree_format.extension());
+            let names = parsing::label_names(&label_dict);
+            write_file(
+                PathBuf::from("./resources/results/false-positives")
+                    .with_extension(tree_format.extension()),
                 &false_positives
                     .iter()
                     .map(|(c1, c2)| {
-                        format!(
-                            "{}{}\n-------------------------\n",
-                            tree_to_string(&trees[*c1], TreeOutput::Graphviz),
-                            tree_to_string(&trees[*c2], TreeOutput::Graphviz)
-                        )
+                        export::export_pair(&trees[*c1], &trees[*c2], &names, tree_format)
                     })
                     .collect_vec(),
             )?;
+            println!("Printing not found as HTML tree diffs");
+            std::fs::write(
+                "./resources/results/false-positives.html",
+                tree_diff::diff_page(
+                    "Pairs within the threshold missing in candidates",
+                    &false_positives,
+                    &trees,
+                    &label_dict,
+                ),
+            )?;
+            if graphviz {
+                println!("Printing not found in graphviz");
+                std::fs::write(
+                    "./resources/results/false-positives.graphviz",
+                    tree_diff::diff_graphviz(&false_positives, &trees, &label_dict),
+                )?;
+            }
+        }
+        Commands::Generate { .. }
+        | Commands::Pair { .. }
+        | Commands::Apply { .. }
+        | Commands::SelfTest { .. }
+        | Commands::ConvertCandidates { .. }
+        | Commands::CompactIndex { .. }
+        | Commands::MergeCandidates { .. }
+        | Commands::Completions { .. } => {
+            unreachable!("handled before parsing the dataset")
         }
         Commands::TedTime {
             candidates_first: _,
@@ -513,104 +3865,106 @@ fn main() -> Result<(), anyhow::Error> {
         }
     }
 
+    if let Some(dir) = &cli.golden {
+        match golden::check(dir, &run.command, &snapshot, cli.update_golden)? {
+            golden::Outcome::Recorded(path) => {
+                println!("Recorded golden snapshot {}", path.display())
+            }
+            golden::Outcome::Matched(values) => {
+                println!("All {values} values match the golden snapshot")
+            }
+        }
+    }
+    run.finish();
     Ok(())
 }
 
+/// Writes candidates of a histogram filter, its filter time of every tree in microseconds
+/// and, with `results`, validates the candidates and writes their precision and the total
+/// time of the filter in microseconds
+#[allow(clippy::too_many_arguments)]
 fn write_precision_and_filter_times(
     base: &Path,
-    times: &[u128],
-    precision: (usize, usize, f32, u128),
     hist_method: &str,
     k: usize,
-    candidates: Option<&[(usize, usize)]>,
-) -> Result<(), anyhow::Error> {
-    let mut times_output = PathBuf::from(base);
-    let mut precision_output = PathBuf::from(base);
-    let mut candidates_output = PathBuf::from(base);
-    times_output.push(format!("hist_{hist_method}_us.txt"));
-    precision_output.push(format!("precision-hist-{hist_method}-{k}.txt"));
-    let times_file = File::options()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(times_output)?;
-    let mut precision_file = File::options()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(precision_output)?;
-    let mut times_writer = BufWriter::new(times_file);
-
-    for t in times.iter() {
-        writeln!(times_writer, "{t}")?;
-    }
+    times: &[u128],
+    total: Duration,
+    candidates: &[(usize, usize)],
+    results: Option<&Path>,
+    memory_limit: Option<usize>,
+) -> Result<Option<validation::Validation>, anyhow::Error> {
+    let candidates_output = base.join(format!("candidates-hist-{hist_method}-{k}.csv"));
+    candidate_file::write_csv(&candidates_output, candidates)?;
+    write_file(base.join(format!("hist_{hist_method}_us.txt")), times)?;
 
-    writeln!(
-        precision_file,
-        "Correct trees;Incorrect trees;Precision;Total Time"
-    )?;
-    writeln!(
-        precision_file,
-        "{};{};{};{}",
-        precision.0, precision.1, precision.2, precision.3
+    let Some(results) = results else {
+        return Ok(None);
+    };
+    // one filter time per tree of the collection
+    let validation =
+        validation::validate(&candidates_output, &results, k, times.len(), memory_limit)?;
+    write_file(
+        base.join(format!("precision-hist-{hist_method}-{k}.txt")),
+        &[
+            "Correct trees;Incorrect trees;Precision;Total Time us".to_owned(),
+            format!(
+                "{};{};{};{}",
+                validation.correct,
+                validation.extra,
+                validation.precision,
+                total.as_micros()
+            ),
+        ],
     )?;
+    Ok(Some(validation))
+}
 
-    if let Some(candidates) = candidates {
-        candidates_output.push(format!("candidates-hist-{hist_method}-{k}.csv"));
-        write_file(
-            candidates_output,
-            &candidates
-                .iter()
-                .map(|(c1, c2)| format!("{c1},{c2}"))
-                .collect_vec(),
-        )?;
-    }
-
-    Ok(())
+/// Name of the subcommand, e.g. `LowerBound`
+fn command_name(command: &Commands) -> String {
+    let debug = format!("{command:?}");
+    debug
+        .split([' ', '{', '('])
+        .next()
+        .unwrap_or_default()
+        .to_owned()
 }
 
 fn write_files(
-    stats: &[TreeStatistics],
-    output_dir: &impl AsRef<Path>,
+    stats: &StatisticsColumns,
+    files: &hists_output::HistogramFiles,
 ) -> Result<(), anyhow::Error> {
-    let out = output_dir.as_ref().to_path_buf();
-    write_file(
-        [&out, &PathBuf::from("degrees.csv")]
-            .iter()
-            .collect::<PathBuf>(),
-        &stats
-            .iter()
-            .flat_map(|s| &s.degrees)
-            .collect::<Vec<&usize>>(),
-    )?;
-    write_file(
-        [&out, &PathBuf::from("depths.csv")]
-            .iter()
-            .collect::<PathBuf>(),
-        &stats
-            .iter()
-            .flat_map(|s| &s.depths)
-            .collect::<Vec<&usize>>(),
-    )?;
-    write_file(
-        [&out, &PathBuf::from("unique_labels.csv")]
-            .iter()
-            .collect::<PathBuf>(),
-        &stats
-            .iter()
-            .map(|s| s.collection_unique_labels)
-            .collect::<Vec<_>>(),
-    )?;
-    write_file(
-        [&out, &PathBuf::from("distinct_labels.csv")]
-            .iter()
-            .collect::<PathBuf>(),
-        &stats.iter().map(|s| s.distinct_labels).collect::<Vec<_>>(),
-    )?;
-
+    files.check(&["degrees", "depths", "unique_labels", "distinct_labels"])?;
+    files.write("degrees", &stats.degrees)?;
+    files.write("depths", &stats.depths)?;
+    files.write("unique_labels", &stats.collection_unique_labels)?;
+    files.write("distinct_labels", &stats.distinct_labels)?;
     Ok(())
 }
 
+/// Reads metadata joined to written candidates, only CSV candidates have room for it
+fn read_metadata(
+    path: Option<&PathBuf>,
+    format: candidate_file::CandidateFormat,
+    quiet: bool,
+) -> Result<Option<metadata::Metadata>, anyhow::Error> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    if format != candidate_file::CandidateFormat::Csv {
+        anyhow::bail!(error::Error::Config(
+            "Metadata can only be joined to CSV candidates".to_owned()
+        ));
+    }
+    let metadata = metadata::Metadata::read(path)?;
+    if !quiet {
+        println!(
+            "Joining candidates with metadata of {} trees",
+            metadata.trees()
+        );
+    }
+    Ok(Some(metadata))
+}
+
 fn write_file<T>(file_name: impl AsRef<Path>, data: &[T]) -> Result<(), std::io::Error>
 where
     T: Display,
@@ -0,0 +1,168 @@
+//! Lightweight accounting of memory used by collections and indexes.
+//!
+//! Sizes are estimates computed from lengths and capacities of the underlying
+//! containers, allocator overhead is not included.
+use crate::parsing::{LabelId, ParsedTree};
+use std::collections::HashMap;
+use std::mem::size_of;
+
+pub trait MemoryUsage {
+    /// Estimated bytes allocated on the heap and owned by the value
+    fn heap_bytes(&self) -> usize;
+
+    /// Estimated bytes of the value itself and everything it owns
+    fn estimated_bytes(&self) -> usize
+    where
+        Self: Sized,
+    {
+        size_of::<Self>() + self.heap_bytes()
+    }
+}
+
+macro_rules! no_heap_memory {
+    ($($t:ty),*) => {
+        $(impl MemoryUsage for $t {
+            fn heap_bytes(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+no_heap_memory!(usize, i32, i64, u32, u64, LabelId);
+
+impl<T: MemoryUsage> MemoryUsage for Vec<T> {
+    fn heap_bytes(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(T::heap_bytes).sum::<usize>()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Option<T> {
+    fn heap_bytes(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_bytes)
+    }
+}
+
+impl<A: MemoryUsage, B: MemoryUsage> MemoryUsage for (A, B) {
+    fn heap_bytes(&self) -> usize {
+        self.0.heap_bytes() + self.1.heap_bytes()
+    }
+}
+
+impl<A: MemoryUsage, B: MemoryUsage, C: MemoryUsage> MemoryUsage for (A, B, C) {
+    fn heap_bytes(&self) -> usize {
+        self.0.heap_bytes() + self.1.heap_bytes() + self.2.heap_bytes()
+    }
+}
+
+impl MemoryUsage for String {
+    fn heap_bytes(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<K: MemoryUsage, V: MemoryUsage, S> MemoryUsage for HashMap<K, V, S> {
+    fn heap_bytes(&self) -> usize {
+        // one control byte per bucket
+        self.capacity() * (size_of::<(K, V)>() + 1)
+            + self
+                .iter()
+                .map(|(k, v)| k.heap_bytes() + v.heap_bytes())
+                .sum::<usize>()
+    }
+}
+
+impl MemoryUsage for ParsedTree {
+    fn heap_bytes(&self) -> usize {
+        self.capacity() * size_of::<indextree::Node<LabelId>>()
+    }
+}
+
+/// Parses a byte size with an optional binary unit suffix, e.g. `512M`, `4GiB` or `1024`.
+pub fn parse_byte_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size number `{number}`"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Unknown size unit `{unit}`")),
+    };
+    Ok((number * multiplier as f64) as usize)
+}
+
+/// Formats bytes with a binary unit, e.g. `1.5 MiB`
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Reports estimated memory usage after processing phases and checks it against
+/// an optional budget.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    quiet: bool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>, quiet: bool) -> Self {
+        Self { limit, quiet }
+    }
+
+    /// Prints the estimated memory of a phase unless quiet
+    pub fn report(&self, phase: &str, bytes: usize) {
+        if !self.quiet {
+            match self.limit {
+                Some(limit) => println!(
+                    "Memory after {phase}: {} of {}",
+                    format_bytes(bytes),
+                    format_bytes(limit)
+                ),
+                None => println!("Memory after {phase}: {}", format_bytes(bytes)),
+            }
+        }
+    }
+
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.limit.is_none_or(|limit| bytes <= limit)
+    }
+
+    /// Bytes left of the budget when `used` bytes are already taken
+    pub fn remaining(&self, used: usize) -> Option<usize> {
+        self.limit.map(|limit| limit.saturating_sub(used))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_sizes() {
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_byte_size("1.5GiB"), Ok(3 << 29));
+        assert!(parse_byte_size("12Q").is_err());
+        assert_eq!(format_bytes(100), "100 B");
+        assert_eq!(format_bytes(3 << 19), "1.5 MiB");
+    }
+}
@@ -0,0 +1,105 @@
+//! Metadata of trees joined to candidate pairs when they are written.
+//!
+//! The metadata file is a CSV with a header, its first column is the tree id and the
+//! other columns, e.g. a title or URL, are appended to every candidate of the tree.
+//! Tree ids are positions in the size sorted collection, the same as in candidate
+//! files. Candidates of trees missing in the file get empty fields. Joined files keep
+//! the pair in the first two columns, so they are still read as candidate files.
+use crate::error::Error;
+use rustc_hash::FxHashMap;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// names of the metadata columns without the tree id
+    columns: Vec<String>,
+    rows: FxHashMap<usize, Vec<String>>,
+}
+
+impl Metadata {
+    pub fn read(path: &impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let columns = reader
+            .headers()?
+            .iter()
+            .skip(1)
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            anyhow::bail!("Metadata has no columns besides the tree id");
+        }
+        let mut rows = FxHashMap::default();
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            // the header is on the first line
+            let line = i + 2;
+            let id = record[0].trim().parse::<usize>().map_err(|_| {
+                Error::Parse(format!("Invalid tree id on line {line}: {}", &record[0]))
+            })?;
+            let fields = record.iter().skip(1).map(str::to_owned).collect();
+            if rows.insert(id, fields).is_some() {
+                anyhow::bail!("Tree {id} is repeated on line {line}");
+            }
+        }
+        Ok(Self { columns, rows })
+    }
+
+    /// Number of trees with metadata
+    pub fn trees(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Writes `query_id,tree_id` pairs with the metadata of the tree and a header
+    pub fn write_candidates(
+        &self,
+        path: impl AsRef<Path>,
+        candidates: &[(usize, usize)],
+    ) -> Result<(), anyhow::Error> {
+        self.write(std::fs::File::create(path)?, candidates)
+    }
+
+    fn write(&self, w: impl Write, candidates: &[(usize, usize)]) -> Result<(), anyhow::Error> {
+        let mut w = csv::Writer::from_writer(std::io::BufWriter::new(w));
+        let header = ["query_id", "tree_id"]
+            .into_iter()
+            .chain(self.columns.iter().map(String::as_str));
+        w.write_record(header)?;
+        let missing = vec![String::new(); self.columns.len()];
+        for (qid, tid) in candidates {
+            let fields = self.rows.get(tid).unwrap_or(&missing);
+            let ids = [qid.to_string(), tid.to_string()];
+            w.write_record(ids.iter().chain(fields.iter()))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_candidates() {
+        let path = std::env::temp_dir().join(format!("metadata-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "id,title,url\n0,First,a.html\n2,\"Third, last\",c.html\n",
+        )
+        .unwrap();
+        let metadata = Metadata::read(&path).unwrap();
+        assert_eq!(metadata.trees(), 2);
+
+        let mut out = vec![];
+        metadata.write(&mut out, &[(0, 0), (0, 1), (1, 2)]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "query_id,tree_id,title,url\n0,0,First,a.html\n0,1,,\n1,2,\"Third, last\",c.html\n"
+        );
+
+        std::fs::write(&path, "id,title\n0,First\n0,Again\n").unwrap();
+        assert!(Metadata::read(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Parameters of single lower bound methods given as repeated `--method-arg key=value`.
+//!
+//! Keys are `<method>.<parameter>` with the method named as on the command line, e.g.
+//! `sed.q=3` or `structural-split.groups=2`, so every parameter belongs to exactly one
+//! method and a key of another method is an error instead of silently ignored. The
+//! dedicated flags of `lower-bound`, e.g. `--qgram-size`, fill the config first and
+//! method arguments override them.
+use crate::lb::indexes::index_gram::GramScheme;
+use crate::lb::structural_filter::LabelSetConverter;
+use clap::ValueEnum;
+use std::str::FromStr;
+
+/// One `key=value` pair, the key is checked when it is applied to a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodArg {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for MethodArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected key=value, got `{s}`"))?;
+        Ok(Self {
+            key: key.trim().to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+/// Parameters of the q-gram index of the string edit distance bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SedArgs {
+    pub q: usize,
+    pub max_q: Option<usize>,
+    pub scheme: GramScheme,
+    pub positional: bool,
+}
+
+/// Parameters of the structural filter and its index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StructuralArgs {
+    pub matching: usize,
+    pub tight_window: bool,
+    pub prefix_extra_labels: usize,
+    pub prefix_bounded_fallback: bool,
+}
+
+/// Parameters of the q-gram count bound of the traversal strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SedQgramArgs {
+    pub q: usize,
+}
+
+impl Default for SedQgramArgs {
+    fn default() -> Self {
+        Self { q: 2 }
+    }
+}
+
+/// Parameters of the split structural filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitArgs {
+    /// Number of label groups, at most [`LabelSetConverter::MAX_SPLIT`]
+    pub groups: usize,
+}
+
+impl Default for SplitArgs {
+    fn default() -> Self {
+        Self {
+            groups: LabelSetConverter::MAX_SPLIT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodConfig {
+    pub sed: SedArgs,
+    pub structural: StructuralArgs,
+    pub split: SplitArgs,
+    pub sed_qgram: SedQgramArgs,
+}
+
+fn parse<T: FromStr>(arg: &MethodArg) -> Result<T, anyhow::Error> {
+    arg.value.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid value `{}` of method argument {}",
+            arg.value,
+            arg.key
+        )
+    })
+}
+
+impl MethodConfig {
+    /// Overrides parameters by method arguments in order, a later argument of the same
+    /// key wins
+    pub fn apply(&mut self, args: &[MethodArg]) -> Result<(), anyhow::Error> {
+        for arg in args {
+            match arg.key.as_str() {
+                "sed.q" => self.sed.q = parse(arg)?,
+                "sed.max-q" => self.sed.max_q = Some(parse(arg)?),
+                "sed.scheme" => {
+                    self.sed.scheme = GramScheme::from_str(&arg.value, true)
+                        .map_err(|e| anyhow::anyhow!("Invalid sed.scheme: {e}"))?;
+                }
+                "sed.positional" => self.sed.positional = parse(arg)?,
+                "structural.matching" => self.structural.matching = parse(arg)?,
+                "structural.tight-window" => self.structural.tight_window = parse(arg)?,
+                "structural.prefix-extra-labels" => {
+                    self.structural.prefix_extra_labels = parse(arg)?;
+                }
+                "structural.prefix-bounded-fallback" => {
+                    self.structural.prefix_bounded_fallback = parse(arg)?;
+                }
+                "structural-split.groups" => {
+                    let groups = parse(arg)?;
+                    if !(1..=LabelSetConverter::MAX_SPLIT).contains(&groups) {
+                        anyhow::bail!(
+                            "structural-split.groups must be from 1 to {}",
+                            LabelSetConverter::MAX_SPLIT
+                        );
+                    }
+                    self.split.groups = groups;
+                }
+                "sed-qgram.q" => {
+                    self.sed_qgram.q = parse(arg)?;
+                    if self.sed_qgram.q == 0 {
+                        anyhow::bail!("sed-qgram.q must be positive");
+                    }
+                }
+                key => anyhow::bail!("Unknown method argument {key}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_args() {
+        let mut config = MethodConfig {
+            sed: SedArgs {
+                q: 2,
+                max_q: None,
+                scheme: GramScheme::QueryChunks,
+                positional: true,
+            },
+            structural: StructuralArgs {
+                matching: 0,
+                tight_window: false,
+                prefix_extra_labels: 0,
+                prefix_bounded_fallback: false,
+            },
+            split: SplitArgs::default(),
+            sed_qgram: SedQgramArgs::default(),
+        };
+        let args = [
+            "sed.q=3",
+            "sed.scheme = index-chunks",
+            "structural.tight-window=true",
+            "structural-split.groups=2",
+            "sed-qgram.q=3",
+            "sed.q=4",
+        ]
+        .map(|a| a.parse::<MethodArg>().unwrap());
+        config.apply(&args).unwrap();
+        assert_eq!(config.sed.q, 4);
+        assert_eq!(config.sed.scheme, GramScheme::IndexChunks);
+        assert!(config.structural.tight_window);
+        assert_eq!(config.split.groups, 2);
+        assert_eq!(config.sed_qgram.q, 3);
+
+        assert!("sed.q".parse::<MethodArg>().is_err());
+        for invalid in [
+            "sed.q=x",
+            "structural-split.groups=5",
+            "lblint.q=2",
+            "sed-qgram.q=0",
+        ] {
+            let arg = invalid.parse::<MethodArg>().unwrap();
+            assert!(config.apply(&[arg]).is_err(), "{invalid}");
+        }
+    }
+}
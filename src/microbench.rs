@@ -0,0 +1,281 @@
+//! Workloads of the hot kernels for microbenchmarks.
+//!
+//! A workload is a set of trees in bracket notation and random pairs of them with sizes
+//! within a threshold, the pairs size buckets let through to the filters. The criterion
+//! benchmarks in `benches/kernels.rs` run workloads of generated trees, `bench --kernels`
+//! runs them on dataset trees, so both measure the kernels the same way. Kernels run on
+//! a single thread and return the sum of their results, which keeps the work from being
+//! optimized away.
+//!
+//! [`Kernel::RegionRows`] and [`Kernel::RegionColumns`] compare the layout of region
+//! vectors in the structural filter index, rows of four regions per node, with one array
+//! per region.
+use crate::indexing::{
+    IndexError, Indexer, InvertedListLabelPostorderIndex, NodeCounter, SEDIndex,
+};
+use crate::lb::label_intersection::label_intersection_k;
+use crate::lb::sed::sed_k;
+use crate::lb::structural_filter::{self, LabelSetConverter, StructuralFilterTuple};
+use crate::parsing::{parse_single, parse_tree_tokens, LabelDict, ParsedTree, TreeParser};
+use crate::ted::ted;
+use clap::ValueEnum;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::Serialize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kernel {
+    /// Tokenizing and parsing of every tree of the workload
+    Parse,
+    /// Bounded string edit distance of the traversal strings
+    Sed,
+    /// Structural filter distance of the label sets
+    Structural,
+    /// Label intersection bound
+    LabelIntersection,
+    /// Exact tree edit distance, slow on large trees
+    Ted,
+    /// Scan of the region vectors of a node against the nodes with its label in the other
+    /// tree, stored as rows of four regions like in the index
+    RegionRows,
+    /// The scan of [`Kernel::RegionRows`] over one array per region
+    RegionColumns,
+}
+
+impl Kernel {
+    pub const ALL: [Kernel; 7] = [
+        Kernel::Parse,
+        Kernel::Sed,
+        Kernel::Structural,
+        Kernel::LabelIntersection,
+        Kernel::Ted,
+        Kernel::RegionRows,
+        Kernel::RegionColumns,
+    ];
+}
+
+pub struct Workload {
+    lines: Vec<String>,
+    label_dict: LabelDict,
+    trees: Vec<ParsedTree>,
+    pairs: Vec<(usize, usize)>,
+    threshold: usize,
+    sed: Vec<SEDIndex>,
+    structural: Vec<StructuralFilterTuple>,
+    label_sets: Vec<InvertedListLabelPostorderIndex>,
+    region_scan: RegionScan,
+}
+
+impl Workload {
+    /// Parses and indexes the non-empty `lines` and draws `pairs` random pairs of trees
+    /// whose sizes differ by at most `threshold`
+    pub fn new(
+        lines: Vec<String>,
+        pairs: usize,
+        threshold: usize,
+        seed: u64,
+    ) -> Result<Self, IndexError> {
+        let mut label_dict = LabelDict::default();
+        let mut lines = lines
+            .into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let tree = parse_single(line.clone(), &mut label_dict);
+                (tree.count(), line, tree)
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            return Err(IndexError::EmptyTree);
+        }
+        lines.sort_by_key(|(size, _, _)| *size);
+        let sizes = lines.iter().map(|(size, _, _)| *size).collect::<Vec<_>>();
+        let (lines, trees): (Vec<_>, Vec<_>) = lines
+            .into_iter()
+            .map(|(_, line, tree)| (line, tree))
+            .unzip();
+
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+        let mut random = |n: usize| (rng.next_u64() % n as u64) as usize;
+        let pairs = (0..pairs)
+            .map(|_| {
+                let i = random(trees.len());
+                let smallest = sizes.partition_point(|s| s + threshold < sizes[i]);
+                let largest = sizes.partition_point(|s| *s <= sizes[i] + threshold);
+                (i, smallest + random(largest - smallest))
+            })
+            .collect::<Vec<_>>();
+
+        let structural = LabelSetConverter::default().create(&trees)?;
+        Ok(Self {
+            sed: SEDIndex::index_collection(&trees, &label_dict)?,
+            region_scan: RegionScan::new(&structural, &pairs, threshold),
+            structural,
+            label_sets: InvertedListLabelPostorderIndex::index_collection(&trees, &label_dict)?,
+            lines,
+            label_dict,
+            trees,
+            pairs,
+            threshold,
+        })
+    }
+
+    /// Number of trees parsed by [`Kernel::Parse`], nodes scanned by the region kernels or
+    /// pairs compared by other kernels
+    pub fn items(&self, kernel: Kernel) -> usize {
+        match kernel {
+            Kernel::Parse => self.trees.len(),
+            Kernel::RegionRows | Kernel::RegionColumns => self.region_scan.scans.len(),
+            _ => self.pairs.len(),
+        }
+    }
+
+    /// Runs the kernel on all items of the workload
+    pub fn run(&self, kernel: Kernel) -> usize {
+        let k = self.threshold;
+        let sum_pairs = |distance: &dyn Fn(usize, usize) -> usize| {
+            self.pairs.iter().map(|&(i, j)| distance(i, j)).sum()
+        };
+        match kernel {
+            Kernel::Parse => {
+                let mut parser = TreeParser::default();
+                self.lines
+                    .iter()
+                    .map(|line| {
+                        let tokens = parse_tree_tokens(line.clone()).unwrap();
+                        parser.parse(&tokens, &self.label_dict).unwrap().count()
+                    })
+                    .sum()
+            }
+            Kernel::Sed => sum_pairs(&|i, j| sed_k(&self.sed[i], &self.sed[j], k)),
+            Kernel::Structural => sum_pairs(&|i, j| {
+                structural_filter::ted(&self.structural[i], &self.structural[j], k)
+            }),
+            Kernel::LabelIntersection => {
+                sum_pairs(&|i, j| label_intersection_k(&self.label_sets[i], &self.label_sets[j], k))
+            }
+            Kernel::Ted => sum_pairs(&|i, j| ted(&self.trees[i], &self.trees[j])),
+            Kernel::RegionRows => self.region_scan.run_rows(),
+            Kernel::RegionColumns => self.region_scan.run_columns(),
+        }
+    }
+}
+
+/// Region vectors of every node of one tree of a pair scanned against the nodes with
+/// the same label of the other tree, the whole label group without a postorder window
+struct RegionScan {
+    /// region vector of a node and the block of nodes it is scanned against
+    scans: Vec<([NodeCounter; 4], usize)>,
+    /// blocks as rows of four regions, the layout of the index
+    rows: Vec<Vec<[NodeCounter; 4]>>,
+    /// blocks as one array per region
+    columns: Vec<[Vec<NodeCounter>; 4]>,
+    k: NodeCounter,
+}
+
+impl RegionScan {
+    /// Scans of the structural filter of the `pairs` of trees in both region layouts
+    fn new(structural: &[StructuralFilterTuple], pairs: &[(usize, usize)], k: usize) -> Self {
+        let mut scan = Self {
+            scans: vec![],
+            rows: vec![],
+            columns: vec![],
+            k: k as NodeCounter,
+        };
+        for &(i, j) in pairs {
+            for set2 in structural[j].label_sets() {
+                let Some(set1) = structural[i]
+                    .label_sets()
+                    .find(|set1| set1.base.id == set2.base.id)
+                else {
+                    continue;
+                };
+                let block = scan.rows.len();
+                let regions = &set2.struct_vec.regions;
+                scan.rows.push(regions.clone());
+                scan.columns
+                    .push([0, 1, 2, 3].map(|r| regions.iter().map(|n| n[r]).collect()));
+                scan.scans
+                    .extend(set1.struct_vec.regions.iter().map(|query| (*query, block)));
+            }
+        }
+        scan
+    }
+
+    /// Scans with a node within the threshold, with the kernel of the index
+    fn run_rows(&self) -> usize {
+        self.scans
+            .iter()
+            .filter(|(query, block)| {
+                structural_filter::any_within_k(query, &self.rows[*block], self.k)
+            })
+            .count()
+    }
+
+    /// Scans with a node within the threshold, eight nodes at once over the region arrays
+    fn run_columns(&self) -> usize {
+        const LANES: usize = 8;
+        let within = |query: &[NodeCounter; 4], columns: &[Vec<NodeCounter>; 4]| {
+            let len = columns[0].len();
+            let full = len - len % LANES;
+            for start in (0..full).step_by(LANES) {
+                let mut sums = [0; LANES];
+                for (region, column) in columns.iter().enumerate() {
+                    for (sum, value) in sums.iter_mut().zip(&column[start..start + LANES]) {
+                        *sum += (value - query[region]).abs();
+                    }
+                }
+                if sums.iter().any(|sum| *sum <= self.k) {
+                    return true;
+                }
+            }
+            (full..len).any(|n| {
+                (0..4)
+                    .map(|region| (columns[region][n] - query[region]).abs())
+                    .sum::<NodeCounter>()
+                    <= self.k
+            })
+        };
+        self.scans
+            .iter()
+            .filter(|(query, block)| within(query, &self.columns[*block]))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workload() {
+        let lines = ["{a{b}{c}}", "", "{a}", "{a{b}{c}{d}}", "{x{y{z}}}"]
+            .map(str::to_owned)
+            .to_vec();
+        let workload = Workload::new(lines, 50, 1, 3).unwrap();
+        assert_eq!(workload.items(Kernel::Parse), 4);
+        assert_eq!(workload.items(Kernel::Ted), 50);
+        let sizes = workload
+            .trees
+            .iter()
+            .map(ParsedTree::count)
+            .collect::<Vec<_>>();
+        assert!(workload
+            .pairs
+            .iter()
+            .all(|&(i, j)| sizes[i].abs_diff(sizes[j]) <= 1));
+
+        assert_eq!(workload.run(Kernel::Parse), 1 + 3 + 3 + 4);
+        let exact = workload.run(Kernel::Ted);
+        for bound in [Kernel::Sed, Kernel::Structural, Kernel::LabelIntersection] {
+            assert!(workload.run(bound) <= exact, "{bound:?}");
+        }
+        assert!(Workload::new(vec![String::new()], 1, 1, 3).is_err());
+
+        assert!(workload.items(Kernel::RegionRows) > 0);
+        assert_eq!(
+            workload.run(Kernel::RegionColumns),
+            workload.run(Kernel::RegionRows)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Floats of summary lines and CSV and JSON outputs.
+//!
+//! Rust formats floats the same on every system, with a dot as the decimal separator and
+//! without digit grouping whatever the locale, so outputs parse the same everywhere. Only the
+//! number of decimals differed between outputs, `--float-precision` sets it for all of them.
+//! Summaries implement `Display` with the precision of the formatter, so they are printed
+//! with `format!("{:.*}", floats.precision, summary)`. Serialized records keep floats as
+//! numbers rounded to the precision. Golden snapshots keep their own fixed precision, so
+//! they do not drift with the flag.
+
+pub const DEFAULT_PRECISION: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatFormat {
+    /// Number of decimals
+    pub precision: usize,
+}
+
+impl Default for FloatFormat {
+    fn default() -> Self {
+        Self {
+            precision: DEFAULT_PRECISION,
+        }
+    }
+}
+
+impl FloatFormat {
+    pub fn format(self, value: f64) -> String {
+        format!("{value:.*}", self.precision)
+    }
+
+    /// Value rounded to the precision for serialized records, `NaN` and infinities are kept
+    pub fn round(self, value: f64) -> f64 {
+        if value.is_finite() {
+            self.format(value).parse().unwrap_or(value)
+        } else {
+            value
+        }
+    }
+
+    /// Fields joined by the separator, floats with the precision
+    pub fn line(self, fields: &[&dyn Field], separator: char) -> String {
+        let mut line = String::new();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                line.push(separator);
+            }
+            line.push_str(&field.format(self));
+        }
+        line
+    }
+}
+
+/// Value of a line of [`FloatFormat::line`]
+pub trait Field {
+    fn format(&self, floats: FloatFormat) -> String;
+}
+
+impl Field for f64 {
+    fn format(&self, floats: FloatFormat) -> String {
+        floats.format(*self)
+    }
+}
+
+impl Field for f32 {
+    fn format(&self, floats: FloatFormat) -> String {
+        floats.format(f64::from(*self))
+    }
+}
+
+macro_rules! display_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            fn format(&self, _: FloatFormat) -> String {
+                self.to_string()
+            }
+        }
+    )*};
+}
+
+display_field!(usize, u64, &str, String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float_format() {
+        let floats = FloatFormat { precision: 3 };
+        assert_eq!(floats.format(2.0 / 3.0), "0.667");
+        assert_eq!(floats.format(1234567.5), "1234567.500");
+        assert_eq!(floats.round(2.0 / 3.0), 0.667);
+        assert!(floats.round(f64::NAN).is_nan());
+        assert_eq!(
+            floats.line(&[&12usize, &0.5, &"%"], ';'),
+            "12;0.500;%".to_owned()
+        );
+        assert_eq!(FloatFormat { precision: 0 }.format(2.5), "2");
+        assert_eq!(FloatFormat::default().format(0.1), "0.100000");
+    }
+}
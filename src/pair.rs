@@ -0,0 +1,116 @@
+//! Lower bounds and the exact tree edit distance of a single pair of trees.
+//!
+//! Both trees are given in bracket notation and share one label dictionary, no dataset
+//! or query file is needed. Bounds are computed without a threshold, so they are the
+//! tightest value of every method and never exceed the exact distance.
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::binary_branch::{self, BinaryBranchConverter, BRANCH_DIVISOR};
+use crate::lb::label_intersection::label_intersection;
+use crate::lb::sed::sed;
+use crate::lb::structural_filter::{ted as struct_ted_k, StructuralFilterTuple};
+use crate::parsing::{self, LabelDict, TreeParseError};
+use crate::ted;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PairError {
+    #[error(transparent)]
+    Parse(#[from] TreeParseError),
+    #[error(transparent)]
+    Index(#[from] IndexError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairDistances {
+    pub sizes: (usize, usize),
+    pub lblint: usize,
+    pub sed: usize,
+    pub structural: usize,
+    pub binary_branch: usize,
+    /// exact tree edit distance, cubic in the tree sizes so only computed on request
+    pub ted: Option<usize>,
+}
+
+impl fmt::Display for PairDistances {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "sizes: {} {}", self.sizes.0, self.sizes.1)?;
+        writeln!(f, "lblint: {}", self.lblint)?;
+        writeln!(f, "sed: {}", self.sed)?;
+        writeln!(f, "structural: {}", self.structural)?;
+        writeln!(f, "binary_branch: {}", self.binary_branch)?;
+        if let Some(ted) = self.ted {
+            writeln!(f, "ted: {ted}")?;
+        }
+        Ok(())
+    }
+}
+
+fn tokens(tree: &str) -> Result<Vec<String>, TreeParseError> {
+    if !tree.is_ascii() {
+        return Err(TreeParseError::IsNotAscii);
+    }
+    parsing::parse_tree_tokens(tree.trim().to_owned())
+}
+
+/// All lower bounds between two trees in bracket notation and the exact distance
+/// if `exact` is set
+///
+/// ```
+/// use tree_statistics::pair::pair_distances;
+///
+/// let distances = pair_distances("{article{title{Trees}}}", "{article{title{Forests}}}", true)?;
+/// assert_eq!(distances.ted, Some(1));
+/// // every bound is at most the exact distance
+/// assert!(distances.lblint <= 1 && distances.sed <= 1 && distances.structural <= 1);
+/// # Ok::<(), tree_statistics::pair::PairError>(())
+/// ```
+pub fn pair_distances(t1: &str, t2: &str, exact: bool) -> Result<PairDistances, PairError> {
+    let tokens = [tokens(t1)?, tokens(t2)?];
+    let mut label_dict = LabelDict::new();
+    parsing::update_label_dict(&tokens, &mut label_dict);
+    let trees = [
+        parsing::parse_tree(&tokens[0], &label_dict)?,
+        parsing::parse_tree(&tokens[1], &label_dict)?,
+    ];
+    let [t1, t2] = &trees;
+    let sizes = (t1.len(), t2.len());
+    // thresholds above both sizes never stop the bounded methods early
+    let k = sizes.0 + sizes.1;
+    let structural = StructuralFilterTuple::index_collection(&trees, &label_dict)?;
+    let branches = BinaryBranchConverter::default().create(&trees);
+    Ok(PairDistances {
+        sizes,
+        lblint: label_intersection(
+            &InvertedListLabelPostorderIndex::index_tree(t1, &label_dict)?,
+            &InvertedListLabelPostorderIndex::index_tree(t2, &label_dict)?,
+        ),
+        sed: sed(
+            &SEDIndex::index_tree(t1, &label_dict)?,
+            &SEDIndex::index_tree(t2, &label_dict)?,
+        ),
+        structural: struct_ted_k(&structural[0], &structural[1], k),
+        binary_branch: binary_branch::ted(&branches[0], &branches[1], k, BRANCH_DIVISOR),
+        ted: exact.then(|| ted::ted(t1, t2)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pair_distances() {
+        let distances = pair_distances("{a{b}{c}}", "{a{b{c}}}", true).unwrap();
+        assert_eq!(distances.sizes, (3, 3));
+        assert_eq!(distances.ted, Some(2));
+        assert_eq!(distances.lblint, 0);
+        assert_eq!(distances.sed, 2);
+        assert!(distances.structural <= 2 && distances.binary_branch <= 2);
+
+        let distances = pair_distances("{a}", "{x{y}}", false).unwrap();
+        assert_eq!(distances.ted, None);
+        assert_eq!(distances.lblint, 2);
+        assert!(pair_distances("{a", "{a}", false).is_err());
+    }
+}
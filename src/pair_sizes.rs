@@ -0,0 +1,126 @@
+//! Sizes and depths of both trees written next to candidate pairs.
+//!
+//! Verification of a pair takes time growing with the sizes of both trees, with the
+//! sizes in the candidate file pairs can be scheduled largest first or bucketed without
+//! reading the dataset again. The depth of a tree is the number of edges on its longest
+//! root to leaf path, the same as the largest depth of the statistics command. Annotated
+//! files keep the pair in the first two columns, so they are still read as candidate
+//! files.
+use crate::parsing::ParsedTree;
+use clap::ValueEnum;
+use indextree::NodeEdge;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum PairAnnotation {
+    /// Sizes of the query and the tree
+    Sizes,
+    /// Sizes and depths of the query and the tree
+    Depths,
+}
+
+/// Sizes and optionally depths of all queries and trees of a run
+#[derive(Debug)]
+pub struct PairAnnotator {
+    queries: Vec<[usize; 2]>,
+    trees: Vec<[usize; 2]>,
+    depths: bool,
+}
+
+/// Number of edges on the longest root to leaf path, 0 for an empty tree
+pub fn tree_depth(tree: &ParsedTree) -> usize {
+    let Some(root) = tree.iter().next() else {
+        return 0;
+    };
+    let root = tree.get_node_id(root).unwrap();
+    let (mut open, mut depth) = (0, 0);
+    for edge in root.traverse(tree) {
+        match edge {
+            NodeEdge::Start(_) => {
+                depth = depth.max(open);
+                open += 1;
+            }
+            NodeEdge::End(_) => open -= 1,
+        }
+    }
+    depth
+}
+
+impl PairAnnotator {
+    pub fn new(
+        annotation: PairAnnotation,
+        queries: &[(usize, ParsedTree)],
+        trees: &[ParsedTree],
+    ) -> Self {
+        let depths = annotation == PairAnnotation::Depths;
+        let annotate = |tree: &ParsedTree| {
+            let depth = if depths { tree_depth(tree) } else { 0 };
+            [tree.count(), depth]
+        };
+        Self {
+            queries: queries.iter().map(|(_, query)| annotate(query)).collect(),
+            trees: trees.iter().map(annotate).collect(),
+            depths,
+        }
+    }
+
+    /// Writes `query_id,tree_id` pairs with sizes of both trees and a header
+    pub fn write_candidates(
+        &self,
+        path: impl AsRef<Path>,
+        candidates: &[(usize, usize)],
+    ) -> Result<(), anyhow::Error> {
+        self.write(std::fs::File::create(path)?, candidates)
+    }
+
+    fn write(&self, w: impl Write, candidates: &[(usize, usize)]) -> Result<(), anyhow::Error> {
+        let mut w = csv::Writer::from_writer(std::io::BufWriter::new(w));
+        let mut header = vec!["query_id", "tree_id", "query_size", "tree_size"];
+        if self.depths {
+            header.extend(["query_depth", "tree_depth"]);
+        }
+        w.write_record(header)?;
+        for &(qid, tid) in candidates {
+            let (query, tree) = (self.queries[qid], self.trees[tid]);
+            let fields = [qid, tid, query[0], tree[0], query[1], tree[1]];
+            let columns = if self.depths { 6 } else { 4 };
+            w.write_record(fields[..columns].iter().map(usize::to_string))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_annotate_pairs() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b{c}}{d}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = [(1, parse_single("{a{b}}".to_owned(), &mut ld))];
+        assert_eq!(trees.each_ref().map(tree_depth), [0, 2]);
+        assert_eq!(tree_depth(&ParsedTree::new()), 0);
+
+        let mut out = vec![];
+        PairAnnotator::new(PairAnnotation::Sizes, &queries, &trees)
+            .write(&mut out, &[(0, 0), (0, 1)])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "query_id,tree_id,query_size,tree_size\n0,0,2,1\n0,1,2,4\n"
+        );
+
+        let mut out = vec![];
+        PairAnnotator::new(PairAnnotation::Depths, &queries, &trees)
+            .write(&mut out, &[(0, 1)])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "query_id,tree_id,query_size,tree_size,query_depth,tree_depth\n0,1,2,4,1,2\n"
+        );
+    }
+}
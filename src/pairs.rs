@@ -0,0 +1,220 @@
+//! Computes lower bounds, and optionally exact TED, for an explicit list of tree pairs.
+//! Useful when debugging specific false positives.
+use crate::common_subtree::common_structure;
+use crate::error::Error;
+use crate::hashing::{Fingerprint, SubtreeFingerprints};
+use crate::indexing::{Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::{label_intersection_k, weighted_label_intersection_k};
+use crate::lb::qgram_count::{qgram_count_k, QGramProfile};
+use crate::lb::sed::{sed, sed_k};
+use crate::lb::structural_filter::{ted_matching, LabelSetConverter, RegionWeights};
+use crate::lb::RunConfig;
+use crate::parsing::{LabelDict, ParsedTree};
+use crate::query_cache::QueryCache;
+use crate::ted;
+use crate::LowerBoundMethods;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Reads `t1,t2` pairs, or `t1<TAB>t2` if the first line has a tab. A non numeric
+/// first line is treated as a header.
+pub fn read_pairs(pairs_file: &impl AsRef<Path>) -> Result<Vec<(usize, usize)>, anyhow::Error> {
+    let mut pairs = vec![];
+    visit_pairs(pairs_file, |pair| {
+        pairs.push(pair);
+        Ok(())
+    })?;
+    Ok(pairs)
+}
+
+/// Calls `f` with every pair of the file in order, without collecting them
+pub fn visit_pairs(
+    pairs_file: &impl AsRef<Path>,
+    mut f: impl FnMut((usize, usize)) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(File::open(pairs_file)?);
+    let buffered = reader.fill_buf()?;
+    let first_line = buffered.split(|b| *b == b'\n').next().unwrap_or_default();
+    let delimiter = if first_line.contains(&b'\t') {
+        b'\t'
+    } else {
+        b','
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .from_reader(reader);
+    for (i, result) in reader.records().enumerate() {
+        let record = result?;
+        match (record[0].trim().parse(), record[1].trim().parse()) {
+            (Ok(t1), Ok(t2)) => f((t1, t2))?,
+            _ if i == 0 => continue,
+            _ => anyhow::bail!(Error::Parse(format!(
+                "Invalid pair on line {}: {record:?}",
+                i + 1
+            ))),
+        }
+    }
+    Ok(())
+}
+
+/// Methods [`pair_bound`] computes
+pub fn supports(method: LowerBoundMethods) -> bool {
+    use LowerBoundMethods as LBM;
+    matches!(
+        method,
+        LBM::Lblint | LBM::Sed | LBM::SedQgram | LBM::SedUnbounded | LBM::Structural
+    )
+}
+
+/// Options of the bounds of a pair, those of a run, the region weights of its
+/// structural filter and the q-gram size of `sed-qgram`. The default computes every
+/// bound as without options
+#[derive(Debug, Clone, Copy)]
+pub struct BoundOptions<'a> {
+    pub run: RunConfig<'a>,
+    pub region_weights: RegionWeights,
+    pub qgram_size: usize,
+}
+
+impl Default for BoundOptions<'_> {
+    fn default() -> Self {
+        Self {
+            run: RunConfig::default(),
+            region_weights: RegionWeights::default(),
+            qgram_size: 2,
+        }
+    }
+}
+
+/// Computes lower bound of `method` between two trees, bounded by `k`, as a run with
+/// `options` does. The unbounded string edit distance ignores `k`.
+pub fn pair_bound(
+    method: LowerBoundMethods,
+    t1: &ParsedTree,
+    t2: &ParsedTree,
+    label_dict: &LabelDict,
+    k: usize,
+    options: &BoundOptions,
+) -> Result<usize, anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let bound = match method {
+        LBM::Lblint => {
+            let i1 = InvertedListLabelPostorderIndex::index_tree(t1, label_dict)?;
+            let i2 = InvertedListLabelPostorderIndex::index_tree(t2, label_dict)?;
+            match options.run.label_weights {
+                Some(weights) => weighted_label_intersection_k(&i1, &i2, k, |l| weights.get(l)),
+                None => label_intersection_k(&i1, &i2, k),
+            }
+        }
+        LBM::Sed => sed_k(
+            &SEDIndex::index_tree(t1, label_dict)?,
+            &SEDIndex::index_tree(t2, label_dict)?,
+            k,
+        ),
+        LBM::SedQgram => qgram_count_k(
+            &QGramProfile::new(&SEDIndex::index_tree(t1, label_dict)?, options.qgram_size),
+            &QGramProfile::new(&SEDIndex::index_tree(t2, label_dict)?, options.qgram_size),
+            k,
+        ),
+        LBM::SedUnbounded => sed(
+            &SEDIndex::index_tree(t1, label_dict)?,
+            &SEDIndex::index_tree(t2, label_dict)?,
+        ),
+        LBM::Structural => {
+            let mut lc = LabelSetConverter::with_weights(options.region_weights);
+            ted_matching(
+                &lc.create_single(t1)?,
+                &lc.create_single(t2)?,
+                k,
+                options.run.structural_matching,
+                options.run.tight_window,
+            )
+        }
+        _ => anyhow::bail!("Method {method:?} is not supported yet"),
+    };
+    Ok(bound)
+}
+
+/// Values of a pair by fingerprints of its trees, they only depend on the trees as the methods
+/// and threshold are fixed for a run
+pub type PairCache = QueryCache<(Fingerprint, Fingerprint), String>;
+
+/// Exact values of a pair computed besides the bounds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExactValues {
+    pub ted: bool,
+    /// sizes of the largest common subtree and common embedded subtree
+    pub common: bool,
+}
+
+/// Returns one output line per pair:
+/// `t1,t2,<bound per method>[,ted][,common_subtree,common_embedded]`, with a cache
+/// identical pairs of trees are computed once. Bounds are computed with `options`.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    pairs: &[(usize, usize)],
+    methods: &[LowerBoundMethods],
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+    k: usize,
+    options: &BoundOptions,
+    exact: ExactValues,
+    cache: Option<&PairCache>,
+) -> Result<Vec<String>, anyhow::Error> {
+    if let Some((t1, t2)) = pairs
+        .iter()
+        .find(|(t1, t2)| *t1 >= trees.len() || *t2 >= trees.len())
+    {
+        anyhow::bail!(
+            "Pair {t1},{t2} is out of range, dataset has {} trees",
+            trees.len()
+        );
+    }
+
+    pairs
+        .par_iter()
+        .map(|(t1, t2)| {
+            let (tree1, tree2) = (&trees[*t1], &trees[*t2]);
+            let values = || {
+                let mut values = String::new();
+                for method in methods {
+                    let bound = pair_bound(*method, tree1, tree2, label_dict, k, options)?;
+                    values.push_str(&format!(",{bound}"));
+                }
+                if exact.ted {
+                    values.push_str(&format!(",{}", ted::ted(tree1, tree2)));
+                }
+                if exact.common {
+                    let common = common_structure(tree1, tree2);
+                    values.push_str(&format!(",{},{}", common.subtree, common.embedded));
+                }
+                Ok::<_, anyhow::Error>(values)
+            };
+            let values = match cache {
+                Some(cache) => {
+                    let key = (tree1.fingerprint(), tree2.fingerprint());
+                    cache.get_or_insert_with(key, values)?.0
+                }
+                None => values()?,
+            };
+            Ok(format!("{t1},{t2}{values}"))
+        })
+        .collect()
+}
+
+pub fn header(methods: &[LowerBoundMethods], exact: ExactValues) -> String {
+    let mut header = "t1,t2".to_owned();
+    for method in methods {
+        header.push_str(&format!(",{method:?}"));
+    }
+    if exact.ted {
+        header.push_str(",ted");
+    }
+    if exact.common {
+        header.push_str(",common_subtree,common_embedded");
+    }
+    header
+}
@@ -1,17 +1,21 @@
-use crossbeam_channel::Sender;
 // use gxhash::{HashMap, HashMapExt};
 use indextree::{Arena, NodeEdge, NodeId};
 use itertools::Itertools;
 use memchr::memchr2_iter;
+#[cfg(not(target_arch = "wasm32"))]
 use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
 use std::io;
+#[cfg(not(target_arch = "wasm32"))]
 use std::io::{BufRead, BufReader};
-use std::num::NonZeroUsize;
+use std::num::NonZeroU32;
+#[cfg(not(target_arch = "wasm32"))]
 use std::path::Path;
 use std::string::String;
-use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -20,14 +24,66 @@ pub enum DatasetParseError {
     IoError(#[from] io::Error),
     #[error(transparent)]
     ParseError(#[from] TreeParseError),
+    #[error("line {0} uses the label {FOREST_ROOT} reserved for the root of forests")]
+    ReservedLabel(usize),
 }
 
-pub type LabelId = i32;
+/// Id of a label in the label dictionary. Ids are assigned from 1, so dense per
+/// label tables are indexed with [`LabelId::index`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[repr(transparent)]
+#[serde(transparent)]
+pub struct LabelId(NonZeroU32);
+
+impl LabelId {
+    pub const MIN: Self = Self(NonZeroU32::MIN);
+    /// Largest id, used as padding that never matches a real label
+    pub const MAX: Self = Self(NonZeroU32::MAX);
+
+    /// Returns `None` for 0, which is not a valid label id
+    pub const fn new(id: u32) -> Option<Self> {
+        match NonZeroU32::new(id) {
+            Some(id) => Some(Self(id)),
+            None => None,
+        }
+    }
+
+    /// Label id of a zero based position in a dense per label table
+    pub fn from_index(index: usize) -> Self {
+        u32::try_from(index + 1)
+            .ok()
+            .and_then(Self::new)
+            .expect("Label index out of range")
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0.get()
+    }
+
+    /// Zero based position of the label in a dense per label table
+    pub const fn index(self) -> usize {
+        self.0.get() as usize - 1
+    }
+}
+
+/// Defaults to the first label id, a placeholder for structs built with `..Default::default()`
+impl Default for LabelId {
+    fn default() -> Self {
+        Self::MIN
+    }
+}
+
+impl std::fmt::Display for LabelId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
 pub type LabelDict = HashMap<String, (LabelId, usize)>;
 
-// the index is the labelId, and the value on that index is the frequency of it
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+// indexed by `LabelId::index`, the value on that index is the frequency of the label
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct LabelFreqOrdering<T = usize>(Vec<T>);
 
 impl<T> LabelFreqOrdering<T> {
@@ -35,49 +91,33 @@ impl<T> LabelFreqOrdering<T> {
         Self(data)
     }
 
-    pub fn get(&self, index: NonZeroUsize) -> Option<&T> {
-        self.0.get(index.get() - 1)
-    }
-
-    pub fn len(&self) -> usize {
-        self.0.len()
+    pub fn get(&self, label: LabelId) -> Option<&T> {
+        self.0.get(label.index())
     }
 }
 
 pub(crate) type ParsedTree = Arena<LabelId>;
 
-pub enum TreeOutput {
+pub enum TreeOutput<'a> {
     BracketNotation,
-    Graphviz,
+    /// One node per line indented below its parent, with the label names of
+    /// [`label_names`]
+    Ascii(&'a [&'a str]),
+    /// Nested `{"label":"a","children":[...]}` objects with unescaped label names,
+    /// leaves have no children
+    Json(&'a [&'a str]),
+    /// Newick `((c,d)b,e)a;` with unescaped label names, quoted when they hold Newick
+    /// punctuation, underscores or whitespace
+    Newick(&'a [&'a str]),
 }
 
 pub fn tree_to_string(tree: &ParsedTree, out_type: TreeOutput) -> String {
     match out_type {
         TreeOutput::BracketNotation => tree_to_bracket(tree),
-        TreeOutput::Graphviz => tree_to_graphviz(tree),
-    }
-}
-
-fn tree_to_graphviz(tree: &ParsedTree) -> String {
-    let mut graphviz = String::with_capacity(tree.count() * 4);
-    graphviz.push_str("strict digraph G {\n");
-    let mut nodeid_stack = vec![];
-    let Some(root) = tree.iter().next() else {
-        panic!("Root not found!");
-    };
-    let root_id = tree.get_node_id(root).expect("Root ID not found!");
-    nodeid_stack.push((root_id, format!("A{}", root.get())));
-    while let Some((nid, lbl_str)) = nodeid_stack.pop() {
-        for (idx, cnid) in nid.children(tree).enumerate() {
-            let label = tree.get(cnid).unwrap().get();
-            let ascii_char = char::from_u32(idx as u32 + 65).unwrap();
-            graphviz.push_str(&format!("{lbl_str} -> {ascii_char}{label};\n"));
-            nodeid_stack.push((cnid, format!("{ascii_char}{label}")));
-        }
+        TreeOutput::Ascii(label_names) => tree_to_ascii(tree, label_names),
+        TreeOutput::Json(label_names) => tree_to_json(tree, label_names),
+        TreeOutput::Newick(label_names) => tree_to_newick(tree, label_names),
     }
-    graphviz.push('}');
-    graphviz.push('\n');
-    graphviz
 }
 
 pub fn get_frequency_ordering(ld: &LabelDict) -> LabelFreqOrdering {
@@ -90,10 +130,11 @@ pub fn get_frequency_ordering(ld: &LabelDict) -> LabelFreqOrdering {
     ))
 }
 
+/// Outputs tree in bracket notation with label ids, an empty tree as an empty string
 fn tree_to_bracket(tree: &ParsedTree) -> String {
     let mut bracket_notation = String::with_capacity(tree.count() * 4);
     let Some(root) = tree.iter().next() else {
-        panic!("Root not found!");
+        return bracket_notation;
     };
     let root_id = tree.get_node_id(root).expect("Root ID not found!");
 
@@ -112,187 +153,752 @@ fn tree_to_bracket(tree: &ParsedTree) -> String {
     bracket_notation
 }
 
+/// Largest label id in the label dictionary, the first id for an empty one
+pub fn max_label(ld: &LabelDict) -> LabelId {
+    ld.values()
+        .map(|(lbl, _)| *lbl)
+        .max()
+        .unwrap_or(LabelId::MIN)
+}
+
+/// Reverse mapping of the label dictionary, the index is the label id.
+pub fn label_names(ld: &LabelDict) -> Vec<&str> {
+    let mut names = vec![""; max_label(ld).get() as usize + 1];
+    for (name, (lbl, _)) in ld.iter() {
+        names[lbl.get() as usize] = name.as_str();
+    }
+    names
+}
+
+/// Outputs tree in bracket notation with original label strings instead of label ids,
+/// an empty tree as an empty string.
+pub fn tree_to_labeled_bracket(tree: &ParsedTree, label_names: &[&str]) -> String {
+    let mut bracket_notation = String::with_capacity(tree.count() * 8);
+    let Some(root) = tree.iter().next() else {
+        return bracket_notation;
+    };
+    let root_id = tree.get_node_id(root).expect("Root ID not found!");
+
+    for edge in root_id.traverse(tree) {
+        match edge {
+            NodeEdge::Start(node_id) => {
+                bracket_notation.push('{');
+                let label = *tree.get(node_id).unwrap().get();
+                bracket_notation.push_str(label_names[label.get() as usize]);
+            }
+            NodeEdge::End(_) => {
+                bracket_notation.push('}');
+            }
+        }
+    }
+
+    bracket_notation
+}
+
+/// Outputs tree with one label per line, children are connected to their parent by
+/// `+--`, the last child by `` `-- ``.
+fn tree_to_ascii(tree: &ParsedTree, label_names: &[&str]) -> String {
+    let mut ascii = String::with_capacity(tree.count() * 12);
+    let Some(root) = tree.iter().next() else {
+        return ascii;
+    };
+    let root_id = tree.get_node_id(root).expect("Root ID not found!");
+    // indentation of the children of nodes on the path from the root
+    let mut indents: Vec<&str> = vec![];
+    for edge in root_id.traverse(tree) {
+        match edge {
+            NodeEdge::Start(node_id) => {
+                let last = tree[node_id].next_sibling().is_none();
+                if node_id != root_id {
+                    ascii.extend(indents.iter().copied());
+                    ascii.push_str(if last { "`-- " } else { "+-- " });
+                }
+                let label = *tree.get(node_id).unwrap().get();
+                ascii.push_str(label_names[label.get() as usize]);
+                ascii.push('\n');
+                if node_id != root_id {
+                    indents.push(if last { "    " } else { "|   " });
+                }
+            }
+            NodeEdge::End(node_id) => {
+                if node_id != root_id {
+                    indents.pop();
+                }
+            }
+        }
+    }
+    ascii
+}
+
+/// Label as written in the input without the escapes of braces
+fn unescape_label(label: &str) -> String {
+    label.replace(r"\{", "{").replace(r"\}", "}")
+}
+
+/// Outputs tree as nested JSON objects, an empty tree as an empty string
+fn tree_to_json(tree: &ParsedTree, label_names: &[&str]) -> String {
+    let mut json = String::with_capacity(tree.count() * 16);
+    let Some(root) = tree.iter().next() else {
+        return json;
+    };
+    let root_id = tree.get_node_id(root).expect("Root ID not found!");
+    for edge in root_id.traverse(tree) {
+        match edge {
+            NodeEdge::Start(node_id) => {
+                if node_id != root_id && tree[node_id].previous_sibling().is_some() {
+                    json.push(',');
+                }
+                let label = *tree.get(node_id).unwrap().get();
+                let label = unescape_label(label_names[label.get() as usize]);
+                json.push_str("{\"label\":");
+                json.push_str(&serde_json::to_string(&label).expect("strings serialize"));
+                if tree[node_id].first_child().is_some() {
+                    json.push_str(",\"children\":[");
+                }
+            }
+            NodeEdge::End(node_id) => {
+                if tree[node_id].first_child().is_some() {
+                    json.push(']');
+                }
+                json.push('}');
+            }
+        }
+    }
+    json
+}
+
+fn newick_label(label: &str) -> String {
+    let label = unescape_label(label);
+    let quoted = label
+        .chars()
+        .any(|c| c.is_whitespace() || "()[]':;,_".contains(c));
+    if quoted {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label
+    }
+}
+
+/// Outputs tree in Newick format, children in parentheses before the label of their
+/// parent, an empty tree as an empty string
+fn tree_to_newick(tree: &ParsedTree, label_names: &[&str]) -> String {
+    let mut newick = String::with_capacity(tree.count() * 8);
+    let Some(root) = tree.iter().next() else {
+        return newick;
+    };
+    let root_id = tree.get_node_id(root).expect("Root ID not found!");
+    for edge in root_id.traverse(tree) {
+        match edge {
+            NodeEdge::Start(node_id) => {
+                if node_id != root_id && tree[node_id].previous_sibling().is_some() {
+                    newick.push(',');
+                }
+                if tree[node_id].first_child().is_some() {
+                    newick.push('(');
+                }
+            }
+            NodeEdge::End(node_id) => {
+                if tree[node_id].first_child().is_some() {
+                    newick.push(')');
+                }
+                let label = *tree.get(node_id).unwrap().get();
+                newick.push_str(&newick_label(label_names[label.get() as usize]));
+            }
+        }
+    }
+    newick.push(';');
+    newick
+}
+
+/// Attributes of the Graphviz output
+#[derive(Debug, Clone, Default)]
+pub struct GraphvizStyle<'a> {
+    /// prefix of node ids, keeps ids unique when more trees are drawn in one graph
+    pub id_prefix: &'a str,
+    /// writes the tree as subgraph `cluster_<id_prefix>` to embed in a larger graph
+    pub cluster: bool,
+    /// attributes of the graph, e.g. `label="Tree 3"`
+    pub graph_attributes: Vec<String>,
+    /// attributes of all nodes, e.g. `shape=box`
+    pub node_attributes: Vec<String>,
+    /// attributes of single nodes by their preorder position, e.g. `fillcolor=red`
+    pub node_styles: FxHashMap<usize, String>,
+}
+
+fn graphviz_escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Outputs tree as a Graphviz digraph with original label strings. Nodes are named by
+/// the id prefix and their preorder position, so names are unique for repeated labels.
+pub fn tree_to_graphviz(tree: &ParsedTree, label_names: &[&str], style: &GraphvizStyle) -> String {
+    let prefix = style.id_prefix;
+    let mut graphviz = String::with_capacity(tree.count() * 24);
+    if style.cluster {
+        graphviz.push_str(&format!("subgraph cluster_{prefix} {{\n"));
+    } else {
+        graphviz.push_str("digraph G {\n");
+    }
+    for attribute in style.graph_attributes.iter() {
+        graphviz.push_str(&format!("  {attribute};\n"));
+    }
+    if !style.node_attributes.is_empty() {
+        graphviz.push_str(&format!("  node [{}];\n", style.node_attributes.join(", ")));
+    }
+
+    if let Some(root) = tree.iter().next() {
+        let root_id = tree.get_node_id(root).expect("Root ID not found!");
+        // preorder positions of nodes on the path from the root
+        let mut path = vec![];
+        let mut position = 0;
+        for edge in root_id.traverse(tree) {
+            match edge {
+                NodeEdge::Start(node_id) => {
+                    let label = *tree.get(node_id).unwrap().get();
+                    let label = graphviz_escape(label_names[label.get() as usize]);
+                    graphviz.push_str(&format!("  {prefix}n{position} [label=\"{label}\""));
+                    if let Some(attributes) = style.node_styles.get(&position) {
+                        graphviz.push_str(&format!(", {attributes}"));
+                    }
+                    graphviz.push_str("];\n");
+                    if let Some(parent) = path.last() {
+                        graphviz
+                            .push_str(&format!("  {prefix}n{parent} -> {prefix}n{position};\n"));
+                    }
+                    path.push(position);
+                    position += 1;
+                }
+                NodeEdge::End(_) => {
+                    path.pop();
+                }
+            }
+        }
+    }
+    graphviz.push_str("}\n");
+    graphviz
+}
+
+// there is no file system nor threads in the browser, trees are parsed from strings there
+#[cfg(not(target_arch = "wasm32"))]
 macro_rules! buf_open_file {
     ($file_path:ident) => {
         BufReader::new(File::open($file_path)?)
     };
 }
 
-pub fn parse_dataset(
+/// Label of the virtual root joining the trees of a forest line, reserved when reading forests
+pub const FOREST_ROOT: &str = "#forest";
+
+/// How lines in bracket notation are read into trees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Closes brackets left open at the end of dataset lines, see
+    /// [`parse_tree_tokens_recovering`]. Queries are not recovered.
+    pub recover_brackets: bool,
+    /// Reads every line as a forest of top level trees joined by a virtual root labelled
+    /// [`FOREST_ROOT`], also lines of a single tree, so all trees get the same root and
+    /// distances are the ones of the forests
+    pub forests: bool,
+}
+
+impl ParseOptions {
+    /// Tokens of the tree joining the top level trees of `tokens` when reading forests,
+    /// fails when they use the label of the virtual root
+    pub fn root(&self, tokens: Vec<String>) -> Result<Vec<String>, TreeParseError> {
+        if !self.forests {
+            return Ok(tokens);
+        }
+        if tokens.iter().any(|t| t == FOREST_ROOT) {
+            return Err(TreeParseError::ReservedLabel);
+        }
+        let mut rooted = Vec::with_capacity(tokens.len() + 3);
+        rooted.extend(["{".to_owned(), FOREST_ROOT.to_owned()]);
+        rooted.extend(tokens);
+        rooted.push("}".to_owned());
+        Ok(rooted)
+    }
+}
+
+/// Lines of a dataset left out of the collection, empty lines and lines that are not a
+/// tree in bracket notation
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SkippedLines {
+    /// line numbers from 1
+    pub lines: Vec<usize>,
+}
+
+impl std::fmt::Display for SkippedLines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Skipped {} empty or malformed lines of the dataset, lines: {}",
+            self.lines.len(),
+            self.lines.iter().take(10).join(",")
+        )?;
+        if self.lines.len() > 10 {
+            write!(f, ",...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Line of a dataset whose unbalanced brackets were closed at its end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveredLine {
+    /// line number from 1
+    pub line: usize,
+    pub closed: usize,
+}
+
+impl std::fmt::Display for RecoveredLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Line {} of the dataset is unbalanced, closed {} brackets at its end",
+            self.line, self.closed
+        )
+    }
+}
+
+/// Parses a dataset with one tree per line and returns the lines that were skipped as well.
+/// With `recover_brackets`, lines cut short are parsed by [`parse_tree_tokens_recovering`]
+/// instead of being skipped and returned as recovered lines. With `forests`, a dataset using
+/// the label [`FOREST_ROOT`] is rejected.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_dataset_reporting(
     dataset_file: &impl AsRef<Path>,
     label_dict: &mut LabelDict,
-) -> Result<Vec<ParsedTree>, DatasetParseError> {
-    let (sender, receiver) = crossbeam_channel::unbounded::<String>();
-    let ld = Arc::new(Mutex::new(label_dict));
-    let copy_ld = Arc::clone(&ld);
-    let collection_tree_tokens = std::thread::scope(|s| {
-        s.spawn(move || {
-            let mut ld = copy_ld.lock().unwrap();
-            let mut max_node_id = ld.values().len() as LabelId;
-            while let Ok(label) = receiver.recv() {
-                ld.entry(label)
-                    .and_modify(|(_, lblcnt)| *lblcnt += 1)
-                    .or_insert_with(|| {
-                        max_node_id += 1;
-                        (max_node_id, 1)
-                    });
+    options: ParseOptions,
+) -> Result<(Vec<ParsedTree>, SkippedLines, Vec<RecoveredLine>), DatasetParseError> {
+    let reader = buf_open_file!(dataset_file);
+    let tree_lines = reader.lines().collect::<Result<Vec<String>, _>>()?;
+
+    let tokenized = tree_lines
+        .into_par_iter()
+        .map(|tree_line| {
+            if !tree_line.is_ascii() {
+                return Err(TreeParseError::IsNotAscii);
             }
-        });
-
-        let reader = BufReader::new(File::open(dataset_file).unwrap());
-        let tree_lines = reader
-            .lines()
-            .collect::<Result<Vec<String>, _>>()
-            .expect("Unable to read input file");
-        // println!("Consumed {} lines of trees", tree_lines.len());
-
-        tree_lines
-            .into_par_iter()
-            .map_with(sender, |s, tree_line| {
-                if !tree_line.is_ascii() {
-                    return Err(TreeParseError::IsNotAscii);
+            let (tokens, closed) = if options.recover_brackets {
+                parse_tree_tokens_recovering(tree_line)?
+            } else {
+                (parse_tree_tokens(tree_line)?, 0)
+            };
+            Ok((options.root(tokens)?, closed))
+        })
+        .collect::<Vec<_>>();
+    let mut skipped = SkippedLines::default();
+    let mut recovered = vec![];
+    let mut lines = vec![];
+    let mut collection_tree_tokens = vec![];
+    for (line, tokens) in tokenized.into_iter().enumerate() {
+        match tokens {
+            Ok((tokens, closed)) => {
+                if closed > 0 {
+                    recovered.push(RecoveredLine {
+                        line: line + 1,
+                        closed,
+                    });
                 }
-                parse_tree_tokens(tree_line, Some(s))
-            })
-            .filter(Result::is_ok)
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap()
-    });
+                lines.push(line + 1);
+                collection_tree_tokens.push(tokens);
+            }
+            Err(TreeParseError::ReservedLabel) => {
+                return Err(DatasetParseError::ReservedLabel(line + 1))
+            }
+            Err(_) => skipped.lines.push(line + 1),
+        }
+    }
 
-    // println!(
-    //     "Parsed {} lines of tree tokens",
-    //     collection_tree_tokens.len()
-    // );
-    // println!("Parsing tokens into trees");
-    let label_dict = Arc::try_unwrap(ld)
-        .expect("Arc has references")
-        .into_inner()
-        .unwrap();
-    let trees = collection_tree_tokens
+    update_label_dict(&collection_tree_tokens, label_dict);
+    let parsed = collection_tree_tokens
         .par_iter()
-        .map(|tokens| parse_tree(tokens, label_dict))
-        .filter(Result::is_ok)
-        .collect::<Result<Vec<_>, _>>()?;
-    // println!("Final number of trees: {}", trees.len());
+        .map_init(TreeParser::default, |parser, tokens| {
+            parser.parse(tokens, label_dict)
+        })
+        .collect::<Vec<_>>();
+    let mut trees = Vec::with_capacity(parsed.len());
+    for (line, tree) in lines.into_iter().zip(parsed) {
+        match tree {
+            Ok(tree) if tree.count() > 0 => trees.push(tree),
+            _ => skipped.lines.push(line),
+        }
+    }
+    skipped.lines.sort_unstable();
+    recovered.retain(|recovered| skipped.lines.binary_search(&recovered.line).is_err());
 
-    Ok(trees)
+    Ok((trees, skipped, recovered))
+}
+
+/// Id and method of a query line `<id>;<threshold>;<method>;<tree>`, both are empty
+/// for a `<threshold>;<tree>` line. Method names are checked by the caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryRoute {
+    pub id: Option<String>,
+    pub method: Option<String>,
+}
+
+/// Queries with their thresholds and the route of every query
+pub type RoutedQueries = (Vec<(usize, ParsedTree)>, Vec<QueryRoute>);
+
+/// Splits a query line before the tree into its route and threshold
+fn split_query_line(line: &str) -> Option<(QueryRoute, usize, &str)> {
+    let (head, tree) = line.split_at(line.find('{').unwrap_or(line.len()));
+    let fields = head.split(';').map(str::trim).collect_vec();
+    let non_empty = |field: &str| Some(field.to_owned()).filter(|f| !f.is_empty());
+    match fields[..] {
+        [threshold, ""] => Some((QueryRoute::default(), threshold.parse().unwrap(), tree)),
+        [id, threshold, method, ""] => {
+            let route = QueryRoute {
+                id: non_empty(id),
+                method: non_empty(method),
+            };
+            Some((route, threshold.parse().unwrap(), tree))
+        }
+        _ => None,
+    }
 }
 
-pub fn parse_queries(
+/// Parses queries of lines `<threshold>;<tree>`, reading trees as the dataset was read
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_queries_with(
     query_file: &impl AsRef<Path>,
     ld: &mut LabelDict,
+    options: ParseOptions,
 ) -> Result<Vec<(usize, ParsedTree)>, DatasetParseError> {
+    Ok(parse_routed_queries(query_file, ld, options)?.0)
+}
+
+/// Parses queries of lines `<threshold>;<tree>` and `<id>;<threshold>;<method>;<tree>`
+/// in the same file, returns the route of every query next to the queries
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_routed_queries(
+    query_file: &impl AsRef<Path>,
+    ld: &mut LabelDict,
+    options: ParseOptions,
+) -> Result<RoutedQueries, DatasetParseError> {
     let reader = buf_open_file!(query_file);
-    let trees: Vec<(usize, Vec<String>)> = reader
+    let trees: Vec<(QueryRoute, usize, Vec<String>)> = reader
         .lines()
-        .filter_map(|l| {
+        .enumerate()
+        .filter_map(|(line, l)| {
             let l = l.expect("line reading failed!");
-            let (threshold_str, tree) = l.split_once(";")?;
-            Some((threshold_str.parse::<usize>().unwrap(), tree.to_string()))
+            let (route, threshold, tree) = split_query_line(&l)?;
+            Some((line, route, threshold, tree.to_string()))
         })
-        .filter_map(|(t, tree)| {
-            let tokens = parse_tree_tokens(tree, None);
-            if tokens.is_err() {
-                return None;
+        .filter_map(|(line, route, t, tree)| {
+            let tokens = parse_tree_tokens(tree).ok()?;
+            match options.root(tokens) {
+                Ok(tks) => Some(Ok((route, t, tks))),
+                Err(_) => Some(Err(DatasetParseError::ReservedLabel(line + 1))),
             }
-            let tks: Vec<String> = tokens
-                .unwrap()
-                .iter()
-                .map(|tkn| tkn.to_string())
-                .collect_vec();
-
-            Some((t, tks))
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, _>>()?;
 
     let only_tokens = trees
         .iter()
-        .map(|(_, tkns)| tkns.iter().map(|t| t.as_str()).collect_vec())
+        .map(|(_, _, tkns)| tkns.iter().map(|t| t.as_str()).collect_vec())
         .collect_vec();
 
     update_label_dict(&only_tokens, ld);
+    let mut parser = TreeParser::default();
     let trees = trees
-        .iter()
-        .filter_map(|(t, tokens)| {
-            let parsed_tree = parse_tree(tokens, ld);
+        .into_iter()
+        .filter_map(|(route, t, tokens)| {
+            let parsed_tree = parser.parse(&tokens, ld);
             if parsed_tree.is_err() {
                 return None;
             }
 
-            Some((*t, parsed_tree.unwrap()))
+            Some(((t, parsed_tree.unwrap()), route))
         })
-        .collect();
+        .unzip();
 
     Ok(trees)
 }
 
+/// The file itself, or files with one of `extensions` in a directory searched recursively,
+/// sorted by path so tree ids do not change between runs
+#[cfg(any(feature = "tree-sitter", feature = "html"))]
+pub fn dataset_files(
+    path: &Path,
+    extensions: &[&str],
+) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    if path.is_file() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut files = vec![];
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.contains(&ext))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
 pub fn parse_single(tree_str: String, label_dict: &mut LabelDict) -> ParsedTree {
     if !tree_str.is_ascii() {
         panic!("Passed tree string is not ASCII");
     }
 
-    let tokens = parse_tree_tokens(tree_str, None).expect("Failed to parse single tree");
-    let str_tokens = tokens.iter().map(|t| t.as_str()).collect_vec();
-    let token_col = vec![str_tokens];
-    update_label_dict(&token_col, label_dict);
+    let tokens = parse_tree_tokens(tree_str).expect("Failed to parse single tree");
+    update_label_dict(std::slice::from_ref(&tokens), label_dict);
     parse_tree(&tokens, label_dict).unwrap()
 }
 
-pub fn update_label_dict(tokens_collection: &[Vec<&str>], ld: &mut LabelDict) {
-    let labels_only = tokens_collection
+/// Label occurrence counts of one worker, keyed by label with position of the
+/// first occurrence as (tree index, token index) and number of occurrences.
+type LocalLabelCounts<'a> = FxHashMap<&'a str, ((usize, usize), usize)>;
+
+fn count_tree_labels<'a, S: AsRef<str>>(
+    mut counts: LocalLabelCounts<'a>,
+    (tree_idx, tree_tokens): (usize, &'a Vec<S>),
+) -> LocalLabelCounts<'a> {
+    for (token_idx, token) in tree_tokens.iter().enumerate() {
+        let token = token.as_ref();
+        if token == "{" || token == "}" {
+            continue;
+        }
+        counts
+            .entry(token)
+            .and_modify(|(_, lblcnt)| *lblcnt += 1)
+            .or_insert(((tree_idx, token_idx), 1));
+    }
+    counts
+}
+
+/// Counts labels of all trees and adds them into label dictionary.
+/// Each worker counts into its own map, maps are merged at the end. New labels get
+/// ids in order of their first occurrence, the same as counting trees sequentially.
+pub fn update_label_dict<S: AsRef<str> + Sync>(tokens_collection: &[Vec<S>], ld: &mut LabelDict) {
+    #[cfg(not(target_arch = "wasm32"))]
+    let counts = tokens_collection
         .par_iter()
-        .flat_map(|tree_tokens| {
-            tree_tokens
-                .iter()
-                .filter(|token| **token != "{" && **token != "}")
-                .map(|label_token| label_token.to_string())
-                .collect_vec()
-        })
-        .collect::<Vec<_>>();
+        .enumerate()
+        .fold(LocalLabelCounts::default, count_tree_labels)
+        .reduce(LocalLabelCounts::default, |mut merged, counts| {
+            for (label, (first, cnt)) in counts {
+                merged
+                    .entry(label)
+                    .and_modify(|(merged_first, lblcnt)| {
+                        *merged_first = (*merged_first).min(first);
+                        *lblcnt += cnt;
+                    })
+                    .or_insert((first, cnt));
+            }
+            merged
+        });
+    #[cfg(target_arch = "wasm32")]
+    let counts = tokens_collection
+        .iter()
+        .enumerate()
+        .fold(LocalLabelCounts::default(), count_tree_labels);
+
+    let mut next_index = ld.values().len();
+    for (label, (_, cnt)) in counts
+        .into_iter()
+        .sorted_unstable_by_key(|(_, (first, _))| *first)
+    {
+        if let Some((_, lblcnt)) = ld.get_mut(label) {
+            *lblcnt += cnt;
+        } else {
+            ld.insert(label.to_owned(), (LabelId::from_index(next_index), cnt));
+            next_index += 1;
+        }
+    }
+}
 
-    let mut max_node_id = ld.values().len() as LabelId;
-    for lbl in labels_only {
-        ld.entry(lbl)
-            .and_modify(|(_, lblcnt)| *lblcnt += 1)
-            .or_insert_with(|| {
-                max_node_id += 1;
-                (max_node_id, 1)
+/// Renumbers label ids in lexicographic order of the labels and relabels the trees.
+/// Ids then only depend on the set of labels, not on the order of trees in the file.
+pub fn sort_label_ids(ld: &mut LabelDict, trees: &mut [ParsedTree]) {
+    renumber_label_ids(ld, trees, |(a, _), (b, _)| a.cmp(b));
+}
+
+/// Renumbers label ids by descending frequency and relabels the trees, ties in
+/// lexicographic order. Frequent labels get the smallest ids, so their postings are
+/// adjacent in tables indexed by label id.
+pub fn frequency_label_ids(ld: &mut LabelDict, trees: &mut [ParsedTree]) {
+    renumber_label_ids(ld, trees, |(a, (_, a_cnt)), (b, (_, b_cnt))| {
+        b_cnt.cmp(a_cnt).then_with(|| a.cmp(b))
+    });
+}
+
+fn renumber_label_ids(
+    ld: &mut LabelDict,
+    trees: &mut [ParsedTree],
+    order: impl Fn(
+        &(&String, &mut (LabelId, usize)),
+        &(&String, &mut (LabelId, usize)),
+    ) -> std::cmp::Ordering,
+) {
+    let mut new_ids = vec![LabelId::default(); max_label(ld).index() + 1];
+    for (index, (_, (id, _))) in ld.iter_mut().sorted_unstable_by(order).enumerate() {
+        let new_id = LabelId::from_index(index);
+        new_ids[id.index()] = new_id;
+        *id = new_id;
+    }
+    for tree in trees {
+        for node in tree.iter_mut() {
+            let label = node.get_mut();
+            *label = new_ids[label.index()];
+        }
+    }
+}
+
+/// New ids of the labels of a dictionary merged into another, indexed by
+/// [`LabelId::index`] of the ids in the merged dictionary
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdRemap(Vec<Option<LabelId>>);
+
+impl IdRemap {
+    pub fn get(&self, label: LabelId) -> Option<LabelId> {
+        self.0.get(label.index()).copied().flatten()
+    }
+
+    /// Whether every label keeps its id, trees then need no remapping
+    pub fn is_identity(&self) -> bool {
+        self.0
+            .iter()
+            .enumerate()
+            .all(|(index, id)| id.is_none_or(|id| id.index() == index))
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Label id {0} is not in the dictionary the ids were remapped from")]
+pub struct UnmappedLabel(pub LabelId);
+
+/// Merging of label dictionaries of trees parsed separately, e.g. of different datasets.
+/// Ids of both dictionaries start at 1, so trees of one are only comparable with trees
+/// of the other after their labels are remapped with [`RemapLabels`].
+pub trait LabelDictMerge {
+    /// Adds labels of `other` missing in the dictionary with ids after its largest one,
+    /// in order of their ids in `other`, and adds up frequencies of shared labels.
+    /// Returns the ids of the labels of `other` in the merged dictionary.
+    fn merge(&mut self, other: &LabelDict) -> IdRemap;
+}
+
+impl LabelDictMerge for LabelDict {
+    fn merge(&mut self, other: &LabelDict) -> IdRemap {
+        let mut next_index = self.values().map(|(id, _)| id.get()).max().unwrap_or(0) as usize;
+        let mut remap =
+            vec![None; other.values().map(|(id, _)| id.get()).max().unwrap_or(0) as usize];
+        for (label, (id, cnt)) in other.iter().sorted_unstable_by_key(|(_, (id, _))| *id) {
+            let (new_id, lblcnt) = self.entry(label.clone()).or_insert_with(|| {
+                next_index += 1;
+                (LabelId::from_index(next_index - 1), 0)
             });
+            *lblcnt += cnt;
+            remap[id.index()] = Some(*new_id);
+        }
+        IdRemap(remap)
     }
 }
 
-pub fn parse_tree(tokens: &[String], ld: &LabelDict) -> Result<ParsedTree, TreeParseError> {
-    let mut tree_arena = ParsedTree::with_capacity(tokens.len() / 2);
-    let mut node_stack: Vec<NodeId> = vec![];
-
-    for t in tokens.iter().skip(1) {
-        match t.as_str() {
-            "{" => continue,
-            "}" => {
-                let Some(_) = node_stack.pop() else {
-                    return Err(TreeParseError::IncorrectFormat(
-                        "Wrong bracket pairing".to_owned(),
-                    ));
-                };
-            }
-            label_str => {
-                let Some((label, _)) = ld.get(label_str) else {
-                    return Err(TreeParseError::TokenizerError);
-                };
-                let n = tree_arena.new_node(*label);
-                if let Some(last_node) = node_stack.last() {
-                    last_node.append(n, &mut tree_arena);
-                } else if tree_arena.count() > 1 {
-                    return Err(TreeParseError::IncorrectFormat(
-                        "Reached unexpected end of token".to_owned(),
-                    ));
-                };
-                node_stack.push(n);
+pub trait RemapLabels {
+    /// Relabels nodes with their ids in the merged dictionary. Fails without changing
+    /// the tree when a label is not in the remapped dictionary.
+    fn remap_labels(&mut self, remap: &IdRemap) -> Result<(), UnmappedLabel>;
+}
+
+impl RemapLabels for ParsedTree {
+    fn remap_labels(&mut self, remap: &IdRemap) -> Result<(), UnmappedLabel> {
+        if let Some(label) = self
+            .iter()
+            .map(|node| *node.get())
+            .find(|label| remap.get(*label).is_none())
+        {
+            return Err(UnmappedLabel(label));
+        }
+        for node in self.iter_mut() {
+            let label = node.get_mut();
+            *label = remap.get(*label).expect("labels are checked above");
+        }
+        Ok(())
+    }
+}
+
+/// Relabels nodes of queries with labels missing in the dataset, whose labels are the first
+/// `dataset_labels` ids, to the first id after them. Such labels never match a node of the
+/// dataset, one id for all of them leaves distances to dataset trees the same and shrinks
+/// the label sets of queries. Returns the number of relabeled nodes.
+pub fn merge_unseen_labels(queries: &mut [(usize, ParsedTree)], dataset_labels: usize) -> usize {
+    let unknown = LabelId::from_index(dataset_labels);
+    let mut relabeled = 0;
+    for (_, query) in queries {
+        for node in query.iter_mut() {
+            let label = node.get_mut();
+            if label.index() > dataset_labels {
+                *label = unknown;
+                relabeled += 1;
             }
         }
     }
+    relabeled
+}
+
+pub fn parse_tree(tokens: &[String], ld: &LabelDict) -> Result<ParsedTree, TreeParseError> {
+    TreeParser::default().parse(tokens, ld)
+}
+
+/// Tree parser reusing its scratch buffers across trees, use one per worker
+/// when parsing many trees.
+#[derive(Debug, Default)]
+pub struct TreeParser {
+    node_stack: Vec<NodeId>,
+}
+
+impl TreeParser {
+    pub fn parse(
+        &mut self,
+        tokens: &[String],
+        ld: &LabelDict,
+    ) -> Result<ParsedTree, TreeParseError> {
+        // every node is made of 3 tokens: opening brace, label and closing brace
+        let mut tree_arena = ParsedTree::with_capacity(tokens.len() / 3);
+        let node_stack = &mut self.node_stack;
+        node_stack.clear();
+
+        for t in tokens.iter().skip(1) {
+            match t.as_str() {
+                "{" => continue,
+                "}" => {
+                    let Some(_) = node_stack.pop() else {
+                        return Err(TreeParseError::IncorrectFormat(
+                            "Wrong bracket pairing".to_owned(),
+                        ));
+                    };
+                }
+                label_str => {
+                    let Some((label, _)) = ld.get(label_str) else {
+                        return Err(TreeParseError::TokenizerError);
+                    };
+                    let n = tree_arena.new_node(*label);
+                    if let Some(last_node) = node_stack.last() {
+                        last_node.append(n, &mut tree_arena);
+                    } else if tree_arena.count() > 1 {
+                        return Err(TreeParseError::IncorrectFormat(
+                            "Reached unexpected end of token".to_owned(),
+                        ));
+                    };
+                    node_stack.push(n);
+                }
+            }
+        }
 
-    Ok(tree_arena)
+        Ok(tree_arena)
+    }
 }
 
 const TOKEN_START: u8 = b'{';
@@ -316,6 +922,8 @@ pub enum TreeParseError {
     IncorrectFormat(String),
     #[error("Bad tokenizing")]
     TokenizerError,
+    #[error("tree uses the label {FOREST_ROOT} reserved for the root of forests")]
+    ReservedLabel,
 }
 
 fn braces_parity_check(parity: &mut i32, addorsub: i32) -> Result<(), TreeParseError> {
@@ -328,10 +936,20 @@ fn braces_parity_check(parity: &mut i32, addorsub: i32) -> Result<(), TreeParseE
     Ok(())
 }
 
-fn parse_tree_tokens(
+pub fn parse_tree_tokens(tree_bytes: String) -> Result<Vec<String>, TreeParseError> {
+    tokenize(tree_bytes, false).map(|(tokens, _)| tokens)
+}
+
+/// Same as [`parse_tree_tokens`], but a line ending within a label takes the rest of the
+/// line as the label and brackets left open at the end of the line are closed. Returns
+/// the tokens and the number of brackets closed.
+pub fn parse_tree_tokens_recovering(
     tree_bytes: String,
-    sender_channel: Option<&mut Sender<String>>,
-) -> Result<Vec<String>, TreeParseError> {
+) -> Result<(Vec<String>, usize), TreeParseError> {
+    tokenize(tree_bytes, true)
+}
+
+fn tokenize(tree_bytes: String, recover: bool) -> Result<(Vec<String>, usize), TreeParseError> {
     use TreeParseError as TPE;
 
     let tree_bytes = tree_bytes.as_bytes();
@@ -339,7 +957,7 @@ fn parse_tree_tokens(
         .filter(|char_pos| !is_escaped(tree_bytes, *char_pos))
         .collect();
 
-    if token_positions.len() < 2 {
+    if token_positions.len() < if recover { 1 } else { 2 } {
         return Err(TPE::IncorrectFormat(
             "Minimal of 2 brackets not found!".to_owned(),
         ));
@@ -359,42 +977,72 @@ fn parse_tree_tokens(
                         tree_bytes[*token_pos..(token_pos + 1)].to_vec(),
                     ));
                 }
-                let Some(token_end) = token_iterator.peek() else {
-                    let err_msg = format!("Label has no ending token near col {token_pos}");
-                    return Err(TPE::IncorrectFormat(err_msg));
+                let label_end = match token_iterator.peek() {
+                    Some(token_end) => **token_end,
+                    // the line was cut within the label
+                    None if recover => tree_bytes.trim_ascii_end().len().max(token_pos + 1),
+                    None => {
+                        let err_msg = format!("Label has no ending token near col {token_pos}");
+                        return Err(TPE::IncorrectFormat(err_msg));
+                    }
                 };
                 let label = unsafe {
-                    String::from_utf8_unchecked(tree_bytes[(token_pos + 1)..**token_end].to_vec())
+                    String::from_utf8_unchecked(tree_bytes[(token_pos + 1)..label_end].to_vec())
                 };
-                str_tokens.push(label.clone());
-                if let Some(ref s) = sender_channel {
-                    s.send(label).expect("Failed sending label");
-                }
+                str_tokens.push(label);
             }
             TOKEN_END => {
                 braces_parity_check(&mut parity_check, -1)?;
                 let label = unsafe {
                     String::from_utf8_unchecked(tree_bytes[*token_pos..(token_pos + 1)].to_vec())
                 };
-                str_tokens.push(label.clone());
-                if let Some(ref s) = sender_channel {
-                    s.send(label).expect("Failed sending label");
-                }
+                // a token of the structure only, labels are counted without closing brackets
+                str_tokens.push(label);
             }
             _ => return Err(TPE::TokenizerError),
         }
     }
-    Ok(str_tokens)
+    let mut closed = 0;
+    if recover {
+        closed = parity_check as usize;
+        str_tokens.extend(std::iter::repeat_n("}".to_owned(), closed));
+    }
+    Ok((str_tokens, closed))
+}
+
+/// Random tree in bracket notation of `size` nodes with labels `l0` to `l<alphabet - 1>`,
+/// every node hangs below an earlier one
+#[cfg(test)]
+pub fn random_bracket_tree(rng: &mut impl rand::Rng, size: usize, alphabet: usize) -> String {
+    let labels = (0..size)
+        .map(|_| rng.gen_range(0..alphabet))
+        .collect::<Vec<_>>();
+    let mut children = vec![vec![]; size];
+    for node in 1..size {
+        children[rng.gen_range(0..node)].push(node);
+    }
+    fn bracket(node: usize, labels: &[usize], children: &[Vec<usize>]) -> String {
+        let inner = children[node]
+            .iter()
+            .map(|c| bracket(*c, labels, children))
+            .collect::<String>();
+        format!("{{l{}{inner}}}", labels[node])
+    }
+    bracket(0, &labels, &children)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn label(id: u32) -> LabelId {
+        LabelId::new(id).unwrap()
+    }
+
     #[test]
     fn test_parses_into_tokens() {
         let input = "{einsteinstrasse{1}{3}}".to_owned();
-        let tokens = parse_tree_tokens(input, None);
+        let tokens = parse_tree_tokens(input);
         assert!(tokens.is_ok());
         let tokens = tokens.unwrap();
         assert_eq!(
@@ -407,7 +1055,7 @@ mod tests {
     fn test_parses_escaped() {
         use std::string::String;
         let input = String::from(r#"{article{key{An optimization of \log data}}}"#);
-        let tokens = parse_tree_tokens(input, None);
+        let tokens = parse_tree_tokens(input);
         assert!(tokens.is_ok());
         let tokens = tokens.unwrap();
         assert_eq!(
@@ -429,19 +1077,19 @@ mod tests {
     #[test]
     fn test_parses_into_tree_arena() {
         let input = "{einsteinstrasse{1}{3}}".to_owned();
-        let tokens = parse_tree_tokens(input, None);
+        let tokens = parse_tree_tokens(input);
         let tokens = tokens.unwrap();
         let ld = LabelDict::from([
-            ("einsteinstrasse".to_owned(), (1, 1)),
-            ("1".to_owned(), (2, 1)),
-            ("3".to_owned(), (3, 1)),
+            ("einsteinstrasse".to_owned(), (label(1), 1)),
+            ("1".to_owned(), (label(2), 1)),
+            ("3".to_owned(), (label(3), 1)),
         ]);
         let tree_arena = parse_tree(&tokens, &ld).unwrap();
         let mut arena = ParsedTree::new();
 
-        let n1 = arena.new_node(1);
-        let n2 = arena.new_node(2);
-        let n3 = arena.new_node(3);
+        let n1 = arena.new_node(label(1));
+        let n2 = arena.new_node(label(2));
+        let n3 = arena.new_node(label(3));
         n1.append(n2, &mut arena);
         n1.append(n3, &mut arena);
 
@@ -451,46 +1099,316 @@ mod tests {
     #[test]
     fn test_updated_label_dict() {
         let input = "{einsteinstrasse{1}{3}}".to_owned();
-        let tokens = parse_tree_tokens(input, None);
+        let tokens = parse_tree_tokens(input);
         let tokens = tokens.unwrap();
         let input2 = "{weinsteinstrasse{3}{2}}".to_owned();
-        let tokens2 = parse_tree_tokens(input2, None);
+        let tokens2 = parse_tree_tokens(input2);
         let tokens2 = tokens2.unwrap();
         let mut ld = LabelDict::default();
         let token_col = vec![tokens, tokens2];
-        // update_label_dict(&token_col, &mut ld);
+        update_label_dict(&token_col, &mut ld);
 
         let tld = LabelDict::from([
-            ("einsteinstrasse".to_owned(), (1, 1)),
-            ("1".to_owned(), (2, 1)),
-            ("3".to_owned(), (3, 2)),
-            ("weinsteinstrasse".to_owned(), (4, 1)),
-            ("2".to_owned(), (5, 1)),
+            ("einsteinstrasse".to_owned(), (label(1), 1)),
+            ("1".to_owned(), (label(2), 1)),
+            ("3".to_owned(), (label(3), 2)),
+            ("weinsteinstrasse".to_owned(), (label(4), 1)),
+            ("2".to_owned(), (label(5), 1)),
         ]);
         assert_eq!(ld, tld, "Label dicts are equal");
     }
 
+    #[test]
+    fn test_closing_brackets_are_not_labels() {
+        // `}` used to be counted as a label of every tree, so it had a label id, a name
+        // in `label_names` and a place in the frequency ordering
+        let mut ld = LabelDict::default();
+        let trees =
+            ["{a{b}{c{d}}}", "{a{}}", "{\\}{x}}"].map(|t| parse_single(t.to_owned(), &mut ld));
+        assert!(!ld.contains_key("}"));
+        assert!(!label_names(&ld).contains(&"}"));
+        assert_eq!(ld.len(), 7);
+        assert_eq!(trees.map(|t| t.len()), [4, 2, 2]);
+    }
+
+    #[test]
+    fn test_sorted_label_ids() {
+        let parse = |lines: [&str; 2]| {
+            let tokens = lines
+                .map(|l| parse_tree_tokens(l.to_owned()).unwrap())
+                .to_vec();
+            let mut ld = LabelDict::default();
+            update_label_dict(&tokens, &mut ld);
+            let mut trees = tokens
+                .iter()
+                .map(|t| parse_tree(t, &ld).unwrap())
+                .collect_vec();
+            sort_label_ids(&mut ld, &mut trees);
+            (ld, trees)
+        };
+        let (ld, trees) = parse(["{c{a}}", "{b{c}}"]);
+        let (swapped_ld, swapped_trees) = parse(["{b{c}}", "{c{a}}"]);
+        assert_eq!(ld, swapped_ld);
+        assert_eq!(ld["a"], (label(1), 1));
+        assert_eq!(ld["c"], (label(3), 2));
+        assert_eq!(trees[0], swapped_trees[1]);
+        assert_eq!(
+            tree_to_labeled_bracket(&trees[1], &label_names(&ld)),
+            "{b{c}}"
+        );
+    }
+
+    #[test]
+    fn test_frequency_label_ids() {
+        let tokens = ["{c{a}}", "{b{c}{c}}"]
+            .map(|l| parse_tree_tokens(l.to_owned()).unwrap())
+            .to_vec();
+        let mut ld = LabelDict::default();
+        update_label_dict(&tokens, &mut ld);
+        let mut trees = tokens
+            .iter()
+            .map(|t| parse_tree(t, &ld).unwrap())
+            .collect_vec();
+        frequency_label_ids(&mut ld, &mut trees);
+        // ties of a and b are broken lexicographically
+        assert_eq!(ld["c"], (label(1), 3));
+        assert_eq!(ld["a"], (label(2), 1));
+        assert_eq!(ld["b"], (label(3), 1));
+        assert_eq!(
+            tree_to_labeled_bracket(&trees[1], &label_names(&ld)),
+            "{b{c}{c}}"
+        );
+    }
+
+    #[test]
+    fn test_split_query_line() {
+        let (route, threshold, tree) = split_query_line("3;{a{b}}").unwrap();
+        assert_eq!(
+            (route, threshold, tree),
+            (QueryRoute::default(), 3, "{a{b}}")
+        );
+        let (route, threshold, tree) = split_query_line("q7; 2 ;sed;{a;b}").unwrap();
+        assert_eq!(route.id.as_deref(), Some("q7"));
+        assert_eq!(route.method.as_deref(), Some("sed"));
+        assert_eq!((threshold, tree), (2, "{a;b}"));
+        let (route, _, _) = split_query_line("q8;2;;{a}").unwrap();
+        assert_eq!(route.method, None);
+        assert!(split_query_line("q9;2;{a}").is_none());
+        assert!(split_query_line("").is_none());
+    }
+
+    #[test]
+    fn test_merge_label_dicts() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b}}".to_owned(), &mut ld);
+        let mut other_ld = LabelDict::default();
+        let mut other = parse_single("{b{c}{a}}".to_owned(), &mut other_ld);
+        // both dictionaries start at the same id
+        assert_eq!(*other.iter().next().unwrap().get(), label(1));
+
+        let remap = ld.merge(&other_ld);
+        assert!(!remap.is_identity());
+        assert_eq!(ld["a"], (label(1), 2));
+        assert_eq!(ld["b"], (label(2), 2));
+        assert_eq!(ld["c"], (label(3), 1));
+        other.remap_labels(&remap).unwrap();
+        let names = label_names(&ld);
+        assert_eq!(tree_to_labeled_bracket(&other, &names), "{b{c}{a}}");
+        assert_eq!(tree_to_labeled_bracket(&tree, &names), "{a{b}}");
+        assert!(ld.clone().merge(&ld).is_identity());
+
+        let unmapped = other.clone();
+        assert_eq!(
+            other.remap_labels(&IdRemap::default()),
+            Err(UnmappedLabel(label(2)))
+        );
+        assert_eq!(other, unmapped);
+    }
+
+    #[test]
+    fn test_merge_unseen_labels() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b}}".to_owned(), &mut ld);
+        let dataset_labels = ld.len();
+        let mut queries = ["{a{x}{y}}", "{z{b}{x}}"]
+            .map(|q| (1, parse_single(q.to_owned(), &mut ld)))
+            .to_vec();
+        assert_eq!(merge_unseen_labels(&mut queries, dataset_labels), 2);
+        let names = label_names(&ld);
+        assert_eq!(tree_to_labeled_bracket(&queries[0].1, &names), "{a{x}{x}}");
+        assert_eq!(tree_to_labeled_bracket(&queries[1].1, &names), "{x{b}{x}}");
+        assert_eq!(merge_unseen_labels(&mut queries, dataset_labels), 0);
+        assert_eq!(tree_to_labeled_bracket(&tree, &names), "{a{b}}");
+    }
+
     #[test]
     fn test_frequency_ordering_build() {
         let ld: LabelDict = LabelDict::from([
-            ("A".to_owned(), (0, 5)),
-            ("B".to_owned(), (1, 2)),
-            ("C".to_owned(), (2, 3)),
-            ("D".to_owned(), (3, 1)),
-            ("F".to_owned(), (4, 5)),
+            ("A".to_owned(), (label(1), 5)),
+            ("B".to_owned(), (label(2), 2)),
+            ("C".to_owned(), (label(3), 3)),
+            ("D".to_owned(), (label(4), 1)),
+            ("F".to_owned(), (label(5), 5)),
         ]);
 
         let freq_ordering = get_frequency_ordering(&ld);
         assert_eq!(freq_ordering, LabelFreqOrdering::new(vec![5, 2, 3, 1, 5]));
 
-        let mut values = vec![0, 2, 3, 0, 4];
-        values.sort_by_key(|lbl| {
-            freq_ordering
-                .get(NonZeroUsize::new(*lbl as usize).unwrap())
-                .unwrap()
-        });
+        let mut values = [1, 3, 4, 1, 5].map(label);
+        values.sort_by_key(|lbl| freq_ordering.get(*lbl).unwrap());
 
-        assert_eq!(values, vec![3, 2, 0, 0, 4]);
+        assert_eq!(values, [4, 3, 1, 1, 5].map(label));
+        assert_eq!(freq_ordering.get(label(6)), None);
+    }
+
+    #[test]
+    fn test_graphviz_unique_node_ids() {
+        let mut ld = LabelDict::default();
+        let children = "{b}".repeat(30);
+        let tree = parse_single(format!(r#"{{a{children}{{say "hi"}}}}"#), &mut ld);
+        let names = label_names(&ld);
+        let style = GraphvizStyle {
+            node_attributes: vec!["shape=box".to_owned()],
+            node_styles: FxHashMap::from_iter([(31, "color=red".to_owned())]),
+            ..GraphvizStyle::default()
+        };
+        let graphviz = tree_to_graphviz(&tree, &names, &style);
+        assert!(graphviz.contains("  node [shape=box];\n"));
+        assert_eq!(graphviz.matches("[label=\"b\"]").count(), 30);
+        assert!(graphviz.contains("  n30 [label=\"b\"];\n  n0 -> n30;\n"));
+        assert!(graphviz.contains(r#"  n31 [label="say \"hi\"", color=red];"#));
+    }
+
+    #[test]
+    fn test_ascii_output() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b{c}{d}}{e{f}}}".to_owned(), &mut ld);
+        let names = label_names(&ld);
+        let expected = ["a", "+-- b", "|   +-- c", "|   `-- d", "`-- e", "    `-- f"]
+            .map(|line| format!("{line}\n"))
+            .concat();
+        assert_eq!(tree_to_string(&tree, TreeOutput::Ascii(&names)), expected);
+        let leaf = parse_single("{x}".to_owned(), &mut ld);
+        assert_eq!(
+            tree_to_string(&leaf, TreeOutput::Ascii(&label_names(&ld))),
+            "x\n"
+        );
+    }
+
+    #[test]
+    fn test_json_and_newick_output() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single(r#"{a{b{c}{d e}}{say "hi"{it's\}}}}"#.to_owned(), &mut ld);
+        let names = label_names(&ld);
+        let json = tree_to_string(&tree, TreeOutput::Json(&names));
+        assert_eq!(
+            json,
+            r#"{"label":"a","children":[{"label":"b","children":[{"label":"c"},{"label":"d e"}]},{"label":"say \"hi\"","children":[{"label":"it's}"}]}]}"#
+        );
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok());
+        assert_eq!(
+            tree_to_string(&tree, TreeOutput::Newick(&names)),
+            r#"((c,'d e')b,('it''s}')'say "hi"')a;"#
+        );
+        let leaf = parse_single("{x_1}".to_owned(), &mut ld);
+        let names = label_names(&ld);
+        assert_eq!(
+            tree_to_string(&leaf, TreeOutput::Json(&names)),
+            r#"{"label":"x_1"}"#
+        );
+        assert_eq!(tree_to_string(&leaf, TreeOutput::Newick(&names)), "'x_1';");
+    }
+
+    #[test]
+    fn test_skips_empty_lines() {
+        let path = std::env::temp_dir().join(format!("skipped-{}.b", std::process::id()));
+        std::fs::write(&path, "{a}\n\n{a{b}}\nno tree\n{b}\n").unwrap();
+        let mut ld = LabelDict::default();
+        let (trees, skipped, _) =
+            parse_dataset_reporting(&path, &mut ld, ParseOptions::default()).unwrap();
+        assert_eq!(trees.len(), 3);
+        assert_eq!(skipped.lines, [2, 4]);
+        assert_eq!(
+            skipped.to_string(),
+            "Skipped 2 empty or malformed lines of the dataset, lines: 2,4"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let names = label_names(&ld);
+        assert_eq!(tree_to_bracket(&ParsedTree::new()), "");
+        assert_eq!(tree_to_labeled_bracket(&ParsedTree::new(), &names), "");
+    }
+
+    #[test]
+    fn test_recovers_unbalanced_brackets() {
+        let path = std::env::temp_dir().join(format!("unbalanced-{}.b", std::process::id()));
+        std::fs::write(&path, "{a{b}{c\n{a{b}\n{a}}\n").unwrap();
+        let mut ld = LabelDict::default();
+        let (trees, skipped, recovered) =
+            parse_dataset_reporting(&path, &mut ld, ParseOptions::default()).unwrap();
+        assert_eq!((trees.len(), skipped.lines.as_slice()), (1, &[1, 3][..]));
+        assert!(recovered.is_empty());
+
+        let mut ld = LabelDict::default();
+        let recover = ParseOptions {
+            recover_brackets: true,
+            ..ParseOptions::default()
+        };
+        let (trees, skipped, recovered) = parse_dataset_reporting(&path, &mut ld, recover).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(skipped.lines, [3]);
+        assert_eq!(
+            recovered,
+            [
+                RecoveredLine { line: 1, closed: 2 },
+                RecoveredLine { line: 2, closed: 1 }
+            ]
+        );
+        assert_eq!(
+            recovered[0].to_string(),
+            "Line 1 of the dataset is unbalanced, closed 2 brackets at its end"
+        );
+        assert_eq!(trees[0], parse_single("{a{b}{c}}".to_owned(), &mut ld));
+        assert_eq!(
+            parse_tree_tokens_recovering("{a".to_owned()).unwrap(),
+            (vec!["{".to_owned(), "a".to_owned(), "}".to_owned()], 1)
+        );
+    }
+
+    #[test]
+    fn test_forest_lines() {
+        let path = std::env::temp_dir().join(format!("forests-{}.b", std::process::id()));
+        std::fs::write(&path, "{a{b}}{c}\n{a}\n").unwrap();
+        let mut ld = LabelDict::default();
+        let (trees, skipped, _) =
+            parse_dataset_reporting(&path, &mut ld, ParseOptions::default()).unwrap();
+        assert_eq!((trees.len(), skipped.lines.as_slice()), (1, &[1][..]));
+
+        let mut ld = LabelDict::default();
+        let forests = ParseOptions {
+            forests: true,
+            ..ParseOptions::default()
+        };
+        let (trees, skipped, _) = parse_dataset_reporting(&path, &mut ld, forests).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(skipped.lines.is_empty());
+        let names = label_names(&ld);
+        assert_eq!(
+            tree_to_labeled_bracket(&trees[0], &names),
+            "{#forest{a{b}}{c}}"
+        );
+        // single trees get the virtual root as well
+        assert_eq!(tree_to_labeled_bracket(&trees[1], &names), "{#forest{a}}");
+
+        let path = std::env::temp_dir().join(format!("reserved-{}.bracket", std::process::id()));
+        std::fs::write(&path, "{a}\n{b{#forest}}\n").unwrap();
+        let reserved = parse_dataset_reporting(&path, &mut LabelDict::default(), forests);
+        assert!(matches!(reserved, Err(DatasetParseError::ReservedLabel(2))));
+        // the label is an ordinary one when not reading forests
+        let (trees, _, _) =
+            parse_dataset_reporting(&path, &mut LabelDict::default(), Default::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(trees.len(), 2);
     }
 
     /*
@@ -0,0 +1,182 @@
+//! Execution plan of a run printed by `--dry-run` instead of running it.
+//!
+//! The dataset and queries are parsed as in a real run, so a wrong path, a malformed query
+//! file or a bad candidate file template fail the same way, but no index is built and
+//! nothing is written. The collection is summarized by its [`CollectionFingerprint`], the
+//! one saved with indexes, and the memory of the per tree indexes of every method is
+//! estimated on a sample of the trees like before a run, see
+//! [`crate::lb::estimate_method_bytes`]. Methods whose indexes do not fit `--max-memory`
+//! are planned to index trees in chunks.
+use crate::collection_fingerprint::CollectionFingerprint;
+use crate::memory::format_bytes;
+use crate::LowerBoundMethods;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Indexes a method builds for the collection
+pub fn indexes(method: LowerBoundMethods) -> &'static str {
+    use LowerBoundMethods as LBM;
+    match method {
+        LBM::Hist => "histograms of degrees, leaf paths and labels",
+        LBM::Lblint => "label sets with an inverted list of labels",
+        LBM::Sed => "preorder and postorder strings with a q-gram index",
+        LBM::SedBkTree => "preorder and postorder strings in a BK-tree",
+        LBM::SedQgram => "q-gram counts of the preorder and postorder strings",
+        LBM::SedUnbounded => "preorder and postorder strings, scanned without a threshold",
+        LBM::Structural => "structural sets with an inverted list of labels",
+        LBM::StructuralSplit => "split structural sets with an inverted list of labels",
+        LBM::Bib => "binary branch vectors",
+        LBM::Auto => "indexes of the methods chosen on a sample of queries",
+        LBM::Tuned => "indexes of the cascade tuned on a sample of queries",
+        LBM::Routed => "indexes of the methods named by the queries",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodPlan {
+    pub method: LowerBoundMethods,
+    /// estimated bytes of the per tree indexes, `None` for methods without an estimate
+    pub bytes: Option<usize>,
+    /// whether the indexes exceed the memory budget and trees are indexed in chunks
+    pub chunked: bool,
+    pub outputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub command: String,
+    pub datasets: Vec<PathBuf>,
+    pub fingerprint: CollectionFingerprint,
+    /// estimated bytes of the parsed collection and queries
+    pub base_bytes: usize,
+    pub limit: Option<usize>,
+    pub queries: Option<PathBuf>,
+    pub query_count: usize,
+    /// smallest and largest threshold of the queries
+    pub thresholds: Option<(usize, usize)>,
+    pub methods: Vec<MethodPlan>,
+    /// files written once per run
+    pub outputs: Vec<PathBuf>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dry run of {}, nothing is run", self.command)?;
+        for dataset in &self.datasets {
+            writeln!(f, "dataset: {}", dataset.display())?;
+        }
+        writeln!(f, "trees: {}", self.fingerprint.trees())?;
+        for (smallest, largest, trees) in self.fingerprint.size_buckets() {
+            if smallest == largest {
+                writeln!(f, "  {trees} of {smallest} nodes")?;
+            } else {
+                writeln!(f, "  {trees} of {smallest}-{largest} nodes")?;
+            }
+        }
+        if let Some(queries) = &self.queries {
+            writeln!(f, "queries: {}", queries.display())?;
+        }
+        if self.query_count > 0 {
+            match self.thresholds {
+                Some((low, high)) if low != high => writeln!(
+                    f,
+                    "{} queries with thresholds {low}-{high}",
+                    self.query_count
+                )?,
+                Some((k, _)) => writeln!(f, "{} queries with threshold {k}", self.query_count)?,
+                None => writeln!(f, "{} queries", self.query_count)?,
+            }
+        }
+        match self.limit {
+            Some(limit) => writeln!(
+                f,
+                "memory: {} of the parsed inputs, budget {}",
+                format_bytes(self.base_bytes),
+                format_bytes(limit)
+            )?,
+            None => writeln!(
+                f,
+                "memory: {} of the parsed inputs",
+                format_bytes(self.base_bytes)
+            )?,
+        }
+        for method in &self.methods {
+            writeln!(f, "method {:?}", method.method)?;
+            writeln!(f, "  indexes: {}", indexes(method.method))?;
+            match (method.bytes, method.chunked) {
+                (Some(bytes), false) => writeln!(f, "  estimated: {}", format_bytes(bytes))?,
+                (Some(bytes), true) => writeln!(
+                    f,
+                    "  estimated: {}, over the budget, indexed in chunks",
+                    format_bytes(bytes)
+                )?,
+                (None, _) => writeln!(f, "  estimated: unknown")?,
+            }
+            for output in &method.outputs {
+                writeln!(f, "  writes: {}", output.display())?;
+            }
+        }
+        for output in &self.outputs {
+            writeln!(f, "writes: {}", output.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_plan_display() {
+        let mut label_dict = LabelDict::default();
+        let trees = ["{a{b}{c}}", "{a}", "{x{y{z{w}}}}", "{x{y}}"]
+            .map(|t| parse_single(t.to_owned(), &mut label_dict));
+        let plan = Plan {
+            command: "LowerBound".to_owned(),
+            datasets: vec!["trees.bracket".into()],
+            fingerprint: CollectionFingerprint::of_trees(&trees, &label_dict),
+            base_bytes: 2048,
+            limit: Some(4096),
+            queries: Some("queries.csv".into()),
+            query_count: 3,
+            thresholds: Some((1, 3)),
+            methods: vec![
+                MethodPlan {
+                    method: LowerBoundMethods::Structural,
+                    bytes: Some(8192),
+                    chunked: true,
+                    outputs: vec!["out/Structural_candidates.csv".into()],
+                },
+                MethodPlan {
+                    method: LowerBoundMethods::Bib,
+                    bytes: None,
+                    chunked: false,
+                    outputs: vec![],
+                },
+            ],
+            outputs: vec!["out/run.json".into()],
+        };
+        assert_eq!(
+            plan.to_string(),
+            "Dry run of LowerBound, nothing is run\n\
+             dataset: trees.bracket\n\
+             trees: 4\n  \
+             1 of 1 nodes\n  \
+             2 of 2-3 nodes\n  \
+             1 of 4-7 nodes\n\
+             queries: queries.csv\n\
+             3 queries with thresholds 1-3\n\
+             memory: 2.0 KiB of the parsed inputs, budget 4.0 KiB\n\
+             method Structural\n  \
+             indexes: structural sets with an inverted list of labels\n  \
+             estimated: 8.0 KiB, over the budget, indexed in chunks\n  \
+             writes: out/Structural_candidates.csv\n\
+             method Bib\n  \
+             indexes: binary branch vectors\n  \
+             estimated: unknown\n\
+             writes: out/run.json\n"
+        );
+    }
+}
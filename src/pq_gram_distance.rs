@@ -0,0 +1,155 @@
+//! pq-gram distance of Augsten, Böhlen and Gamper as a similarity measure of its own.
+//!
+//! The profile of a tree is the bag of its pq-grams, the same pq-grams `lsh` sketches.
+//! The distance of two trees is `1 - 2 |P1 ∩ P2| / (|P1| + |P2|)` with the bag
+//! intersection of their profiles, 0 for trees with the same profile and 1 for trees
+//! without a common pq-gram. It is a pseudo-metric, different trees can have the same
+//! profile, and it does not bound the tree edit distance, so pairs are not candidates
+//! for verification.
+use crate::lb::lsh::pq_grams;
+use crate::lb::sorted_intersection;
+use crate::parsing::ParsedTree;
+use rayon::prelude::*;
+use std::io::Write;
+use std::path::Path;
+
+/// pq-gram hashes of a tree sorted for merging
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile(Vec<u64>);
+
+impl Profile {
+    pub fn new(tree: &ParsedTree, p: usize, q: usize) -> Self {
+        let mut grams = pq_grams(tree, p, q);
+        grams.sort_unstable();
+        Self(grams)
+    }
+
+    /// Size of the bag intersection with another profile
+    fn intersection(&self, other: &Profile) -> usize {
+        sorted_intersection(&self.0, &other.0, |gram| *gram, |_, _| 1)
+    }
+
+    /// pq-gram distance, 0 for two empty trees
+    pub fn distance(&self, other: &Profile) -> f64 {
+        self.distance_with(other, self.intersection(other))
+    }
+
+    fn distance_with(&self, other: &Profile, common: usize) -> f64 {
+        let total = self.0.len() + other.0.len();
+        if total == 0 {
+            return 0.0;
+        }
+        1.0 - (2 * common) as f64 / total as f64
+    }
+
+    /// Distance up to `max_distance`, profiles of too different sizes are skipped
+    /// without merging them since their intersection is at most the smaller one
+    fn distance_within(&self, other: &Profile, max_distance: f64) -> Option<f64> {
+        let smaller = self.0.len().min(other.0.len());
+        if self.distance_with(other, smaller) > max_distance {
+            return None;
+        }
+        Some(self.distance(other)).filter(|distance| *distance <= max_distance)
+    }
+}
+
+pub fn profiles<'a>(
+    trees: impl IntoParallelIterator<Item = &'a ParsedTree>,
+    p: usize,
+    q: usize,
+) -> Vec<Profile> {
+    trees
+        .into_par_iter()
+        .map(|tree| Profile::new(tree, p, q))
+        .collect()
+}
+
+/// Distances of all pairs `i < j` of profiles up to `max_distance`
+pub fn all_pairs(profiles: &[Profile], max_distance: f64) -> Vec<(usize, usize, f64)> {
+    profiles
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(i, profile)| {
+            profiles[i + 1..]
+                .iter()
+                .enumerate()
+                .filter_map(move |(j, other)| {
+                    let distance = profile.distance_within(other, max_distance)?;
+                    Some((i, i + 1 + j, distance))
+                })
+        })
+        .collect()
+}
+
+/// Distances of every query to every tree up to `max_distance`
+pub fn query_distances(
+    queries: &[Profile],
+    trees: &[Profile],
+    max_distance: f64,
+) -> Vec<(usize, usize, f64)> {
+    queries
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(qid, query)| {
+            trees.iter().enumerate().filter_map(move |(tid, tree)| {
+                let distance = query.distance_within(tree, max_distance)?;
+                Some((qid, tid, distance))
+            })
+        })
+        .collect()
+}
+
+/// Writes distances as CSV with the given names of the id columns
+pub fn write_distances(
+    path: impl AsRef<Path>,
+    id_columns: [&str; 2],
+    distances: &[(usize, usize, f64)],
+) -> Result<(), anyhow::Error> {
+    write(std::fs::File::create(path)?, id_columns, distances)
+}
+
+fn write(
+    w: impl Write,
+    id_columns: [&str; 2],
+    distances: &[(usize, usize, f64)],
+) -> Result<(), anyhow::Error> {
+    let mut w = csv::Writer::from_writer(std::io::BufWriter::new(w));
+    w.write_record([id_columns[0], id_columns[1], "distance"])?;
+    for (first, second, distance) in distances {
+        w.serialize((first, second, distance))?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_pq_gram_distance() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a{b}{c}}", "{a{b}{c}}", "{a{b}{d}}", "{x{y}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let profiles = profiles(&trees, 2, 3);
+        // a node with 2 children has 4 grams, each leaf 1
+        assert_eq!(profiles[0].0.len(), 6);
+        assert_eq!(profiles[0].distance(&profiles[1]), 0.0);
+        assert_eq!(profiles[0].distance(&profiles[3]), 1.0);
+        // only the leaf gram of b and the first gram of a with padding and b are shared
+        assert_eq!(profiles[0].distance(&profiles[2]), 1.0 - 4.0 / 12.0);
+        let empty = Profile::new(&ParsedTree::new(), 2, 3);
+        assert_eq!(empty.distance(&empty), 0.0);
+
+        let pairs = all_pairs(&profiles, 0.5);
+        assert_eq!(pairs, [(0, 1, 0.0)]);
+        assert_eq!(all_pairs(&profiles, 1.0).len(), 6);
+        let distances = query_distances(&profiles[3..], &profiles, 0.0);
+        assert_eq!(distances, [(0, 3, 0.0)]);
+
+        let mut out = vec![];
+        write(&mut out, ["t1", "t2"], &pairs).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "t1,t2,distance\n0,1,0.0\n");
+    }
+}
@@ -0,0 +1,211 @@
+//! Aligned tables of the summaries of `lower-bound` and `validate` for `--pretty`.
+//!
+//! The raw `key:value` lines stay the default output as scripts parse them. With `--pretty`
+//! the summaries are collected into a table printed once all methods ran, numbers are right
+//! aligned and text left aligned. Colors are only written to a terminal and not when
+//! `NO_COLOR` is set: headers are bold, methods cyan and precision and recall green, yellow
+//! or red by their value.
+use crate::number_format::FloatFormat;
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::time::Duration;
+
+const BOLD: &str = "\x1b[1m";
+const CYAN: &str = "\x1b[36m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether tables printed to stdout are colored
+pub fn colors_enabled() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    /// names of methods
+    Method(String),
+    Count(usize),
+    Float(f64),
+    Millis(Duration),
+    /// precision or recall between 0 and 1
+    Ratio(f64),
+    Missing,
+}
+
+impl Cell {
+    fn text(&self, floats: FloatFormat) -> String {
+        match self {
+            Cell::Method(method) => method.clone(),
+            Cell::Count(count) => count.to_string(),
+            Cell::Float(value) => floats.format(*value),
+            Cell::Millis(time) => format!("{}ms", time.as_millis()),
+            Cell::Ratio(ratio) => floats.format(*ratio),
+            Cell::Missing => "-".to_owned(),
+        }
+    }
+
+    fn right_aligned(&self) -> bool {
+        !matches!(self, Cell::Method(_))
+    }
+
+    fn color(&self) -> Option<&'static str> {
+        match self {
+            Cell::Method(_) => Some(CYAN),
+            Cell::Ratio(ratio) if *ratio >= 0.9 => Some(GREEN),
+            Cell::Ratio(ratio) if *ratio >= 0.5 => Some(YELLOW),
+            Cell::Ratio(_) => Some(RED),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new(header: Vec<&'static str>) -> Self {
+        Self {
+            header,
+            rows: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        debug_assert_eq!(row.len(), self.header.len());
+        self.rows.push(row);
+    }
+
+    /// Rows below the header with columns separated by two spaces
+    pub fn render(&self, floats: FloatFormat, colors: bool) -> String {
+        let texts = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.text(floats)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let widths = (0..self.header.len())
+            .map(|column| {
+                texts
+                    .iter()
+                    .map(|row| row[column].chars().count())
+                    .chain([self.header[column].len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+        // the last column is not padded to its width
+        let last = self.header.len().saturating_sub(1);
+
+        let mut out = String::new();
+        for (column, name) in self.header.iter().enumerate() {
+            let right = self
+                .rows
+                .first()
+                .is_some_and(|row| row[column].right_aligned());
+            let padded = pad(name, widths[column], right, column == last);
+            let separator = if column == last { "\n" } else { "  " };
+            if colors {
+                let _ = write!(out, "{BOLD}{padded}{RESET}{separator}");
+            } else {
+                let _ = write!(out, "{padded}{separator}");
+            }
+        }
+        for (row, text) in self.rows.iter().zip(&texts) {
+            for (column, (cell, text)) in row.iter().zip(text).enumerate() {
+                let padded = pad(text, widths[column], cell.right_aligned(), column == last);
+                let separator = if column == last { "\n" } else { "  " };
+                match cell.color().filter(|_| colors) {
+                    Some(color) => {
+                        let _ = write!(out, "{color}{padded}{RESET}{separator}");
+                    }
+                    None => {
+                        let _ = write!(out, "{padded}{separator}");
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn pad(text: &str, width: usize, right: bool, last: bool) -> String {
+    match (right, last) {
+        (true, _) => format!("{text:>width$}"),
+        (false, true) => text.to_owned(),
+        (false, false) => format!("{text:<width$}"),
+    }
+}
+
+/// Summary of one method of `lower-bound`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodSummary {
+    pub method: String,
+    pub time: Duration,
+    pub candidates: usize,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+}
+
+impl MethodSummary {
+    pub fn row(&self) -> Vec<Cell> {
+        let ratio = |value: Option<f64>| value.map_or(Cell::Missing, Cell::Ratio);
+        vec![
+            Cell::Method(self.method.clone()),
+            Cell::Millis(self.time),
+            Cell::Count(self.candidates),
+            ratio(self.precision),
+            ratio(self.recall),
+        ]
+    }
+}
+
+/// Table of the methods of a `lower-bound` run
+pub fn methods_table(summaries: &[MethodSummary]) -> Table {
+    let mut table = Table::new(vec!["method", "time", "candidates", "precision", "recall"]);
+    for summary in summaries {
+        table.push(summary.row());
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_table() {
+        let summaries = [
+            MethodSummary {
+                method: "Structural".to_owned(),
+                time: Duration::from_millis(1234),
+                candidates: 57,
+                precision: Some(0.95),
+                recall: None,
+            },
+            MethodSummary {
+                method: "Bib".to_owned(),
+                time: Duration::from_millis(3),
+                candidates: 1208,
+                precision: Some(0.25),
+                recall: Some(1.0),
+            },
+        ];
+        let floats = FloatFormat { precision: 2 };
+        let table = methods_table(&summaries);
+        assert_eq!(
+            table.render(floats, false),
+            "method        time  candidates  precision  recall\n\
+             Structural  1234ms          57       0.95       -\n\
+             Bib            3ms        1208       0.25    1.00\n"
+        );
+        let colored = table.render(floats, true);
+        assert!(colored.starts_with(&format!("{BOLD}method    {RESET}")));
+        assert!(colored.contains(&format!("{CYAN}Bib       {RESET}")));
+        assert!(colored.contains(&format!("{GREEN}     0.95{RESET}")));
+        assert!(colored.contains(&format!("{RED}     0.25{RESET}")));
+    }
+}
@@ -0,0 +1,140 @@
+//! LRU cache of query results, so repeated identical queries of interactive use skip
+//! the filters. Queries are keyed by the query tree, hashed by its fingerprint, see
+//! [`crate::hashing`].
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Least recently used entries are evicted above `capacity`
+pub struct LruCache<K, V> {
+    capacity: usize,
+    /// values with the tick of their last use
+    entries: FxHashMap<K, (V, u64)>,
+    /// keys by the tick of their last use
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: FxHashMap::default(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, last_use) = self.entries.get_mut(key)?;
+        self.order.remove(last_use);
+        self.order.insert(self.tick, key.clone());
+        *last_use = self.tick;
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&last_use);
+        }
+        self.order.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    #[cfg(any(feature = "serve", feature = "grpc"))]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// LRU cache shared by threads answering queries
+pub struct QueryCache<K, V> {
+    lru: Mutex<LruCache<K, V>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> QueryCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lru: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Cached value of the key or the computed one, which is then cached. The lock is
+    /// not held while computing, concurrent misses of one key compute it each.
+    pub fn get_or_insert_with<E>(
+        &self,
+        key: K,
+        compute: impl FnOnce() -> Result<V, E>,
+    ) -> Result<(V, bool), E> {
+        if let Some(value) = self.lru.lock().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok((value, true));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = compute()?;
+        self.lru.lock().unwrap().insert(key, value.clone());
+        Ok((value, false))
+    }
+
+    /// Drops all entries, e.g. after the queried collection changed
+    #[cfg(any(feature = "serve", feature = "grpc"))]
+    pub fn clear(&self) {
+        self.lru.lock().unwrap().clear();
+    }
+
+    /// Number of hits and misses so far
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::SubtreeFingerprints;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut lru = LruCache::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some("a"));
+        // 2 is the least recently used
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("a"));
+        assert_eq!(lru.entries.len(), 2);
+
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{b{c}}}".to_owned(), &mut ld);
+        let t3 = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        assert_ne!(t1.fingerprint(), t2.fingerprint());
+
+        let cache = QueryCache::new(4);
+        let computed = cache.get_or_insert_with((t1.fingerprint(), 1), || Ok::<_, ()>(vec![1, 2]));
+        assert_eq!(computed, Ok((vec![1, 2], false)));
+        let cached = cache.get_or_insert_with((t3.fingerprint(), 1), || Err(()));
+        assert_eq!(cached, Ok((vec![1, 2], true)));
+        assert_eq!(cache.stats(), (1, 1));
+    }
+}
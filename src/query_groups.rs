@@ -0,0 +1,214 @@
+//! Queries grouped by identical trees.
+//!
+//! Query workloads often repeat a tree with different thresholds. Queries with equal trees
+//! form a group, found by [`SubtreeFingerprints::fingerprint`] and confirmed by comparing
+//! the trees, and the filter scans the collection once per group at the largest threshold
+//! of its queries. Candidates at a
+//! smaller threshold are a subset, they are found by computing the lower bound of the
+//! group's candidates once per smaller threshold, see [`crate::lb::auto::refine`], instead
+//! of scanning the collection again. Bounds of single pairs stop once they are within the
+//! threshold, so a bound computed at the largest threshold does not decide a smaller one.
+//! Methods without bounds of single pairs only group queries with equal thresholds.
+use crate::hashing::{Fingerprint, SubtreeFingerprints};
+use crate::lb::{self, preprocess::Preprocessed};
+use crate::parsing::ParsedTree;
+use crate::LowerBoundMethods;
+use rustc_hash::FxHashMap;
+use std::time::Duration;
+use tree_statistics::tree_cmp;
+
+/// Candidates of all queries, the time it took and the groups
+pub type RunResult = (Vec<(usize, usize)>, Duration, QueryGroups);
+
+#[derive(Debug, Clone)]
+pub struct QueryGroups {
+    /// first query of every group with the largest threshold of the group
+    pub representatives: Vec<(usize, ParsedTree)>,
+    /// query ids of every group in increasing order
+    members: Vec<Vec<usize>>,
+}
+
+impl QueryGroups {
+    /// Groups queries by their tree, also by their threshold unless `by_tree` is set
+    pub fn new(queries: &[(usize, ParsedTree)], by_tree: bool) -> Self {
+        // groups by fingerprint, trees of colliding fingerprints get distinct groups
+        let mut groups = FxHashMap::<(Fingerprint, Option<usize>), Vec<usize>>::default();
+        let mut representatives: Vec<(usize, ParsedTree)> = vec![];
+        let mut members: Vec<Vec<usize>> = vec![];
+        for (qid, (k, query)) in queries.iter().enumerate() {
+            let key = (query.fingerprint(), (!by_tree).then_some(*k));
+            let candidates = groups.entry(key).or_default();
+            let group = match candidates
+                .iter()
+                .find(|group| tree_cmp::structural_eq(&representatives[**group].1, query))
+            {
+                Some(group) => *group,
+                None => {
+                    representatives.push((*k, query.clone()));
+                    members.push(vec![]);
+                    candidates.push(members.len() - 1);
+                    members.len() - 1
+                }
+            };
+            members[group].push(qid);
+            representatives[group].0 = representatives[group].0.max(*k);
+        }
+        Self {
+            representatives,
+            members,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Smaller thresholds of the queries of every group as queries `(threshold, group)`
+    fn smaller_thresholds(&self, queries: &[(usize, ParsedTree)]) -> Vec<(usize, usize)> {
+        let mut levels = self
+            .members
+            .iter()
+            .enumerate()
+            .flat_map(|(group, members)| {
+                let largest = self.representatives[group].0;
+                members
+                    .iter()
+                    .map(|qid| queries[*qid].0)
+                    .filter(move |k| *k < largest)
+                    .map(move |k| (k, group))
+            })
+            .collect::<Vec<_>>();
+        levels.sort_unstable();
+        levels.dedup();
+        levels
+    }
+}
+
+/// Candidates of `method` of all queries scanning the collection once per group of
+/// identical trees, the bounds of the scans and the rechecks follow `config`
+pub fn run(
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    config: &lb::RunConfig,
+) -> Result<RunResult, anyhow::Error> {
+    let groups = QueryGroups::new(queries, lb::auto::has_bounds(method));
+    let (candidates, mut duration) = lb::run_method(method, pre, &groups.representatives, config)?;
+
+    let mut of_group = vec![vec![]; groups.len()];
+    for &(group, tid) in &candidates {
+        of_group[group].push(tid);
+    }
+    // candidates of every smaller threshold are rechecked candidates of the largest
+    let levels = groups.smaller_thresholds(queries);
+    let level_queries = levels
+        .iter()
+        .map(|&(k, group)| (k, groups.representatives[group].1.clone()))
+        .collect::<Vec<_>>();
+    let level_pairs = levels
+        .iter()
+        .enumerate()
+        .flat_map(|(level, (_, group))| of_group[*group].iter().map(move |tid| (level, *tid)))
+        .collect::<Vec<_>>();
+    let mut of_level = FxHashMap::<(usize, usize), Vec<usize>>::default();
+    if !level_pairs.is_empty() {
+        let (refined, refine_time) =
+            lb::auto::refine(method, pre, &level_queries, &level_pairs, config)?;
+        duration += refine_time;
+        for (level, tid) in refined {
+            of_level.entry(levels[level]).or_default().push(tid);
+        }
+    }
+
+    let mut expanded = vec![];
+    for (group, members) in groups.members.iter().enumerate() {
+        let largest = groups.representatives[group].0;
+        for &qid in members {
+            let k = queries[qid].0;
+            let trees = if k == largest {
+                &of_group[group]
+            } else {
+                of_level.get(&(k, group)).map_or(&[][..], Vec::as_slice)
+            };
+            expanded.extend(trees.iter().map(|tid| (qid, *tid)));
+        }
+    }
+    expanded.sort_unstable();
+    Ok((expanded, duration, groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::label_weights::LabelWeights;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_grouped_queries_match_ungrouped() {
+        let mut ld = LabelDict::default();
+        let mut trees = [
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{x{b}{c}}",
+            "{a{b{c}}{d}}",
+            "{a{b}{c}{d}{e}}",
+            "{x{y}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld))
+        .to_vec();
+        trees.sort_by_key(|t| t.len());
+        let queries = [
+            (2, "{a{b}{c}}"),
+            (0, "{a{b}{c}}"),
+            (1, "{x{y}}"),
+            (1, "{a{b}{c}}"),
+            (3, "{x{y}}"),
+        ]
+        .map(|(k, t)| (k, parse_single(t.to_owned(), &mut ld)))
+        .to_vec();
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let groups = QueryGroups::new(&queries, true);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups
+                .representatives
+                .iter()
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(QueryGroups::new(&queries, false).len(), 5);
+
+        let path = std::env::temp_dir().join(format!("group-weights-{}.tsv", std::process::id()));
+        std::fs::write(&path, "a\t3\nb\t0.5\n").unwrap();
+        let weights = LabelWeights::read(&path, &ld).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let configured = lb::RunConfig {
+            label_weights: Some(&weights),
+            structural_matching: 2,
+            tight_window: true,
+        };
+        for config in [lb::RunConfig::default(), configured] {
+            for method in [
+                LowerBoundMethods::Lblint,
+                LowerBoundMethods::Sed,
+                LowerBoundMethods::Structural,
+                LowerBoundMethods::SedQgram,
+            ] {
+                let (mut expected, _) = lb::run_method(method, &pre, &queries, &config).unwrap();
+                expected.sort_unstable();
+                let (candidates, _, _) = run(method, &pre, &queries, &config).unwrap();
+                assert_eq!(candidates, expected, "{method:?} {config:?}");
+            }
+        }
+        // the weights change the label intersection bound of the queries
+        let lblint = |config| {
+            lb::run_method(LowerBoundMethods::Lblint, &pre, &queries, config)
+                .unwrap()
+                .0
+                .len()
+        };
+        assert_ne!(lblint(&configured), lblint(&lb::RunConfig::default()));
+    }
+}
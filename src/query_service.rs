@@ -0,0 +1,382 @@
+//! Similarity queries against a collection loaded once, shared by the HTTP and gRPC servers.
+//!
+//! Indexes of the served lower bound methods are built upfront. Tree ids are positions
+//! in the size sorted collection, the same as in candidate files, trees inserted at
+//! runtime get the following ids, see [`LiveCollection`].
+use crate::hashing::{Fingerprint, SubtreeFingerprints};
+use crate::live_collection::LiveCollection;
+use crate::parsing::{self, LabelDict, LabelId, ParsedTree, TreeParseError};
+use crate::query_cache::QueryCache;
+use crate::query_timeout::Deadline;
+use crate::ted;
+use crate::wal::{Wal, WalRecord};
+use crate::LowerBoundMethods;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tree_statistics::tree_cmp;
+
+#[derive(Debug)]
+pub enum QueryError {
+    /// The request is invalid, e.g. malformed tree or a method that is not served
+    Invalid(String),
+    /// The tree to delete does not exist
+    NotFound(String),
+    Internal(String),
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(msg) | Self::NotFound(msg) | Self::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<TreeParseError> for QueryError {
+    fn from(e: TreeParseError) -> Self {
+        Self::Invalid(format!("Invalid query tree: {e}"))
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryResult {
+    pub method: LowerBoundMethods,
+    pub candidates: Vec<usize>,
+    /// Candidates within the threshold with their exact distance, only when verified
+    pub matches: Option<Vec<(usize, usize)>>,
+    pub filter_time: Duration,
+    pub verify_time: Option<Duration>,
+    /// Candidates were taken from the cache of previous queries
+    pub cached: bool,
+    /// Filtering stopped at the time limit, candidates are partial
+    pub timed_out: bool,
+}
+
+pub fn method_name(method: LowerBoundMethods) -> String {
+    method
+        .to_possible_value()
+        .map_or_else(|| format!("{method:?}"), |v| v.get_name().to_owned())
+}
+
+/// Parses a query without touching the shared label dictionary. Labels missing in
+/// the collection get [`LabelId::MAX`], they never match a tree label so a single id
+/// for all of them gives the same bounds and distances as distinct ids.
+pub fn parse_query(tree: &str, label_dict: &LabelDict) -> Result<ParsedTree, TreeParseError> {
+    if !tree.is_ascii() {
+        return Err(TreeParseError::IsNotAscii);
+    }
+    let tokens = parsing::parse_tree_tokens(tree.to_owned())?;
+    let mut query_dict = LabelDict::new();
+    for token in tokens.iter().filter(|t| *t != "{" && *t != "}") {
+        let id = label_dict.get(token).map_or(LabelId::MAX, |(id, _)| *id);
+        query_dict.entry(token.clone()).or_insert((id, 0)).1 += 1;
+    }
+    parsing::parse_tree(&tokens, &query_dict)
+}
+
+/// Cache key of a query, the query tree with its fingerprint, threshold and method.
+/// Keys are hashed by the fingerprint and equal only for equal trees, so a fingerprint
+/// collision is a cache miss and never returns candidates of another query.
+#[derive(Debug, Clone)]
+struct QueryKey {
+    fingerprint: Fingerprint,
+    tree: ParsedTree,
+    k: usize,
+    method: LowerBoundMethods,
+}
+
+impl QueryKey {
+    fn new(tree: &ParsedTree, k: usize, method: LowerBoundMethods) -> Self {
+        Self {
+            fingerprint: tree.fingerprint(),
+            tree: tree.clone(),
+            k,
+            method,
+        }
+    }
+}
+
+impl PartialEq for QueryKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint
+            && self.k == other.k
+            && self.method == other.method
+            && tree_cmp::structural_eq(&self.tree, &other.tree)
+    }
+}
+
+impl Eq for QueryKey {}
+
+impl Hash for QueryKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fingerprint.hash(state);
+        self.k.hash(state);
+        self.method.hash(state);
+    }
+}
+
+/// Filtering that did not give complete candidates
+enum Filtered {
+    Partial(Vec<usize>),
+    Failed(QueryError),
+}
+
+pub struct QueryService {
+    collection: RwLock<LiveCollection>,
+    cache: Option<QueryCache<QueryKey, Arc<Vec<usize>>>>,
+    wal: Option<Mutex<Wal>>,
+    timeout: Option<Duration>,
+}
+
+impl QueryService {
+    /// Builds indexes of `methods`, trees must be sorted by size
+    pub fn new(
+        trees: Vec<ParsedTree>,
+        label_dict: LabelDict,
+        methods: &[LowerBoundMethods],
+    ) -> Result<Self, anyhow::Error> {
+        if methods.is_empty() {
+            anyhow::bail!("No lower bound method to serve");
+        }
+        Ok(Self {
+            collection: RwLock::new(LiveCollection::new(trees, label_dict, methods)?),
+            cache: None,
+            wal: None,
+            timeout: None,
+        })
+    }
+
+    /// Keeps candidates of the last `capacity` distinct queries, 0 disables the cache
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = (capacity > 0).then(|| QueryCache::new(capacity));
+        self
+    }
+
+    /// Stops filtering of a query after `limit`, its partial candidates are not cached
+    pub fn with_timeout(mut self, limit: Option<Duration>) -> Self {
+        self.timeout = limit;
+        self
+    }
+
+    /// Replays updates logged in `path` and logs following updates there, returns the
+    /// number of replayed updates
+    pub fn with_wal(mut self, path: &impl AsRef<Path>) -> Result<(Self, usize), anyhow::Error> {
+        let collection = self.collection.get_mut().unwrap();
+        let (wal, records) = Wal::open(path, collection.live())?;
+        for record in records.iter() {
+            match record {
+                WalRecord::Insert { tree } => {
+                    collection.insert(tree)?;
+                }
+                WalRecord::Delete { id } => {
+                    collection.delete(*id);
+                }
+                WalRecord::Base { .. } => anyhow::bail!("Log contains more dataset sizes"),
+            }
+        }
+        self.wal = Some(Mutex::new(wal));
+        Ok((self, records.len()))
+    }
+
+    /// Cache hits and misses, `None` without a cache
+    pub fn cache_stats(&self) -> Option<(usize, usize)> {
+        self.cache.as_ref().map(QueryCache::stats)
+    }
+
+    pub fn trees(&self) -> usize {
+        self.collection.read().unwrap().live()
+    }
+
+    pub fn labels(&self) -> usize {
+        self.collection.read().unwrap().label_dict().len()
+    }
+
+    pub fn methods(&self) -> Vec<LowerBoundMethods> {
+        self.collection.read().unwrap().methods().to_vec()
+    }
+
+    /// Estimated bytes of indexes of a method
+    pub fn index_bytes(&self, method: LowerBoundMethods) -> Result<usize, anyhow::Error> {
+        let collection = self.collection.read().unwrap();
+        if !collection.methods().contains(&method) {
+            anyhow::bail!("Method {method:?} is not served");
+        }
+        Ok(collection.index_bytes(method))
+    }
+
+    /// Served method by name, `None` selects the first served method
+    fn method(
+        collection: &LiveCollection,
+        name: Option<&str>,
+    ) -> Result<LowerBoundMethods, QueryError> {
+        let methods = collection.methods();
+        let Some(name) = name else {
+            return Ok(methods[0]);
+        };
+        LowerBoundMethods::from_str(name, true)
+            .ok()
+            .filter(|m| methods.contains(m))
+            .ok_or_else(|| QueryError::Invalid(format!("Method {name} is not served")))
+    }
+
+    /// Logs the update when a log is kept, the caller holds the collection write lock
+    /// so updates are logged in the order they are applied
+    fn log(&self, record: WalRecord) -> Result<(), QueryError> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        wal.lock()
+            .unwrap()
+            .append(&record)
+            .map_err(|e| QueryError::Internal(format!("Unable to write the log: {e}")))
+    }
+
+    /// Inserts a tree in bracket notation, returns its id
+    pub fn insert(&self, tree: &str) -> Result<usize, QueryError> {
+        let mut collection = self.collection.write().unwrap();
+        // invalid trees are rejected before they are logged
+        parse_query(tree, collection.label_dict())?;
+        self.log(WalRecord::Insert {
+            tree: tree.to_owned(),
+        })?;
+        let id = collection
+            .insert(tree)
+            .map_err(|e| QueryError::Internal(format!("Logged tree was not inserted: {e}")))?;
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(id)
+    }
+
+    /// Deletes the tree with the id
+    pub fn delete(&self, id: usize) -> Result<(), QueryError> {
+        let mut collection = self.collection.write().unwrap();
+        if collection.tree(id).is_none() {
+            return Err(QueryError::NotFound(format!("Tree {id} does not exist")));
+        }
+        self.log(WalRecord::Delete { id })?;
+        collection.delete(id);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Candidates of a query tree in bracket notation within threshold `k`, with
+    /// `verify` also exact distances of candidates within `k`
+    pub fn query(
+        &self,
+        tree: &str,
+        k: usize,
+        method: Option<&str>,
+        verify: bool,
+    ) -> Result<QueryResult, QueryError> {
+        let collection = self.collection.read().unwrap();
+        let method = Self::method(&collection, method)?;
+        let query = parse_query(tree, collection.label_dict())?;
+        let start = Instant::now();
+        let deadline = self.timeout.map(Deadline::after);
+        // partial candidates leave the cache as an error so they are not inserted
+        let filter = || match collection.candidates_until(&query, k, method, deadline) {
+            Ok((candidates, false)) => Ok(Arc::new(candidates)),
+            Ok((candidates, true)) => Err(Filtered::Partial(candidates)),
+            Err(e) => Err(Filtered::Failed(QueryError::Internal(e.to_string()))),
+        };
+        let filtered = match &self.cache {
+            Some(cache) => cache.get_or_insert_with(QueryKey::new(&query, k, method), filter),
+            None => filter().map(|candidates| (candidates, false)),
+        };
+        let (candidates, cached, timed_out) = match filtered {
+            Ok((candidates, cached)) => (candidates.to_vec(), cached, false),
+            Err(Filtered::Partial(candidates)) => (candidates, false, true),
+            Err(Filtered::Failed(e)) => return Err(e),
+        };
+        let filter_time = start.elapsed();
+
+        let (matches, verify_time) = if verify {
+            let start = Instant::now();
+            let matches = candidates
+                .par_iter()
+                .map(|tid| (*tid, ted::ted(&query, collection.tree(*tid).unwrap())))
+                .filter(|(_, distance)| *distance <= k)
+                .collect();
+            (Some(matches), Some(start.elapsed()))
+        } else {
+            (None, None)
+        };
+
+        Ok(QueryResult {
+            method,
+            candidates,
+            matches,
+            filter_time,
+            verify_time,
+            cached,
+            timed_out,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_query_with_unknown_labels() {
+        let mut ld = LabelDict::default();
+        let mut trees = ["{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        trees.sort_by_key(|t| t.count());
+        let service = QueryService::new(trees, ld, &LowerBoundMethods::REGISTERED).unwrap();
+        for method in ["lblint", "sed", "structural"] {
+            let result = service.query("{a{b}{q}}", 1, Some(method), true).unwrap();
+            assert_eq!(result.matches.unwrap(), vec![(0, 1), (1, 1)]);
+        }
+        assert_eq!(service.labels(), 7);
+        assert!(service.query("{a}", 1, Some("hist"), false).is_err());
+
+        let service = service.with_cache(8);
+        let first = service.query("{a{b}{q}}", 1, Some("sed"), false).unwrap();
+        // unknown labels map to the same id, so this is the same query
+        let second = service.query("{a{b}{r}}", 1, Some("sed"), false).unwrap();
+        assert!(!first.cached && second.cached);
+        assert_eq!(first.candidates, second.candidates);
+        assert_eq!(service.cache_stats(), Some((1, 1)));
+        // keys with colliding fingerprints of different trees are distinct
+        let collection = service.collection.read().unwrap();
+        let query = parse_query("{a{b}{q}}", collection.label_dict()).unwrap();
+        let other = parse_query("{a{q}{b}}", collection.label_dict()).unwrap();
+        let key = QueryKey::new(&query, 1, LowerBoundMethods::Sed);
+        let colliding = QueryKey {
+            fingerprint: key.fingerprint,
+            ..QueryKey::new(&other, 1, LowerBoundMethods::Sed)
+        };
+        assert!(key != colliding && key == QueryKey::new(&query, 1, LowerBoundMethods::Sed));
+        drop(collection);
+
+        // updates invalidate cached candidates
+        assert_eq!(service.insert("{a{b}{q}}").unwrap(), 3);
+        assert!(service.insert("a").is_err());
+        let inserted = service.query("{a{b}{q}}", 1, Some("sed"), true).unwrap();
+        assert!(!inserted.cached);
+        assert_eq!(inserted.matches.unwrap(), vec![(0, 1), (1, 1), (3, 0)]);
+        service.delete(0).unwrap();
+        assert!(matches!(service.delete(0), Err(QueryError::NotFound(_))));
+        let deleted = service.query("{a{b}{q}}", 1, Some("sed"), false).unwrap();
+        assert_eq!(deleted.candidates, vec![1, 3]);
+        assert_eq!(service.trees(), 3);
+
+        // a query past its time limit is partial and not cached
+        let service = service.with_timeout(Some(Duration::ZERO));
+        let partial = service.query("{x{y}}", 2, Some("lblint"), false).unwrap();
+        assert!(partial.timed_out && partial.candidates.is_empty());
+        let again = service.query("{x{y}}", 2, Some("lblint"), false).unwrap();
+        assert!(again.timed_out && !again.cached);
+    }
+}
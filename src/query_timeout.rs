@@ -0,0 +1,133 @@
+//! Time limits of candidate generation of single queries.
+//!
+//! One pathological query, e.g. a huge tree with a threshold reaching most of the
+//! collection, can take longer than the rest of a batch. With a limit, the scan of a
+//! query checks the clock every [`CHECK_EVERY`] trees and stops once the limit passed.
+//! Candidates of a stopped query are the ones found until then, a partial result that
+//! misses pairs of the remaining trees, so stopped queries are reported next to them.
+use itertools::Itertools;
+use std::fmt::Display;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Trees compared between two reads of the clock
+pub const CHECK_EVERY: usize = 256;
+
+/// End of the time of a query
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn after(limit: Duration) -> Self {
+        Self(Instant::now() + limit)
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    /// Checks the clock only every [`CHECK_EVERY`] calls, `step` counts the calls
+    #[inline(always)]
+    pub fn passed(&self, step: usize) -> bool {
+        step.is_multiple_of(CHECK_EVERY) && self.expired()
+    }
+}
+
+/// Limit of every query of a run and the queries that exceeded it
+#[derive(Debug, Clone)]
+pub struct QueryTimeout {
+    pub limit: Duration,
+    pub timed_out: Vec<usize>,
+}
+
+impl QueryTimeout {
+    pub fn new(limit: Duration) -> Self {
+        Self {
+            limit,
+            timed_out: vec![],
+        }
+    }
+
+    pub fn start(&self) -> Deadline {
+        Deadline::after(self.limit)
+    }
+}
+
+/// Queries of a method stopped at the time limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedOut<'a> {
+    pub method: &'a str,
+    pub limit: Duration,
+    pub queries: &'a [usize],
+}
+
+impl Display for TimedOut<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} queries of {} exceeded the time limit of {}ms, their candidates are partial, queries: {}",
+            self.queries.len(),
+            self.method,
+            self.limit.as_millis(),
+            self.queries.iter().take(10).join(",")
+        )?;
+        if self.queries.len() > 10 {
+            write!(f, ",...")?;
+        }
+        Ok(())
+    }
+}
+
+impl TimedOut<'_> {
+    /// Writes the ids of the stopped queries, one per line below a `query_id` header
+    pub fn write(&self, path: &impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let lines = std::iter::once("query_id".to_owned())
+            .chain(self.queries.iter().map(usize::to_string))
+            .join("\n");
+        std::fs::write(path, lines + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lb::iterate_queries;
+
+    #[test]
+    fn test_query_timeout() {
+        let trees = (0..4 * CHECK_EVERY).collect::<Vec<_>>();
+        let queries = [(0, 1usize), (0, 2)];
+        let slow = |q: &usize, t: &usize, _| {
+            if *q == 2 && *t == CHECK_EVERY {
+                std::thread::sleep(Duration::from_millis(30));
+            }
+            usize::from(!t.is_multiple_of(*q))
+        };
+        let mut no_checkpoint: Option<crate::checkpoint::Checkpointer> = None;
+        let mut timeout = Some(QueryTimeout::new(Duration::from_millis(20)));
+        let (candidates, _) = (|| -> Result<_, anyhow::Error> {
+            Ok(iterate_queries!(queries, trees, slow; no_checkpoint, timeout))
+        })()
+        .unwrap();
+        // the second query stops at the first check after the slow tree
+        assert_eq!(
+            candidates.iter().filter(|(qid, _)| *qid == 0).count(),
+            trees.len()
+        );
+        assert_eq!(
+            candidates.iter().filter(|(qid, _)| *qid == 1).count(),
+            CHECK_EVERY
+        );
+        let timeout = timeout.unwrap();
+        let timed_out = TimedOut {
+            method: "Lblint",
+            limit: timeout.limit,
+            queries: &timeout.timed_out,
+        };
+        assert_eq!(
+            timed_out.to_string(),
+            "1 queries of Lblint exceeded the time limit of 20ms, their candidates are partial, \
+             queries: 1"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! SQLite store of run results, built with the `sqlite` feature and enabled by `--store`.
+//!
+//! Every invocation adds a row into `runs`, artifacts reference it by `run_id`:
+//! - `candidates` with one row per candidate pair of a lower bound method
+//! - `timings` with the filter time and candidate count of a method
+//! - `validations` with precision and selectivity computed by `validate`
+//! - `bench` with the records of the benchmark suite
+//!
+//! A database collects runs of many experiments, e.g. candidate counts of all runs
+//! on a dataset are `SELECT method, candidates FROM timings JOIN runs ON run_id = id`.
+use crate::bench::BenchRecord;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    arguments TEXT NOT NULL,
+    dataset TEXT NOT NULL,
+    version TEXT NOT NULL,
+    threads INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS candidates (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    method TEXT NOT NULL,
+    query_id INTEGER NOT NULL,
+    tree_id INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS timings (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    method TEXT NOT NULL,
+    time_ms REAL NOT NULL,
+    candidates INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS validations (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    candidates_path TEXT NOT NULL,
+    threshold INTEGER NOT NULL,
+    correct INTEGER NOT NULL,
+    extra INTEGER NOT NULL,
+    precision REAL NOT NULL,
+    mean_selectivity REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS bench (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    method TEXT NOT NULL,
+    threshold INTEGER,
+    cache TEXT NOT NULL,
+    time_ms INTEGER NOT NULL,
+    max_time_ms INTEGER NOT NULL,
+    candidates INTEGER NOT NULL,
+    precision REAL,
+    recall REAL
+);
+";
+
+pub struct ResultStore {
+    conn: Connection,
+    run_id: i64,
+}
+
+impl ResultStore {
+    /// Opens or creates the database and records a new run of `command`
+    pub fn open(
+        path: impl AsRef<Path>,
+        command: &str,
+        dataset: &Path,
+    ) -> Result<Self, anyhow::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let arguments = std::env::args().collect::<Vec<_>>().join(" ");
+        conn.execute(
+            "INSERT INTO runs (started_at, command, arguments, dataset, version, threads)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                started_at,
+                command,
+                arguments,
+                dataset.display().to_string(),
+                env!("CARGO_PKG_VERSION"),
+                rayon::current_num_threads() as i64,
+            ],
+        )?;
+        let run_id = conn.last_insert_rowid();
+        Ok(Self { conn, run_id })
+    }
+
+    pub fn run_id(&self) -> i64 {
+        self.run_id
+    }
+
+    /// Inserts candidates of a method in a single transaction
+    pub fn add_candidates(
+        &mut self,
+        method: &str,
+        candidates: &[(usize, usize)],
+    ) -> Result<(), anyhow::Error> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO candidates (run_id, method, query_id, tree_id) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (qid, tid) in candidates {
+                insert.execute(params![self.run_id, method, *qid as i64, *tid as i64])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn add_timing(
+        &self,
+        method: &str,
+        time: Duration,
+        candidates: usize,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.execute(
+            "INSERT INTO timings (run_id, method, time_ms, candidates) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                self.run_id,
+                method,
+                time.as_secs_f64() * 1000.0,
+                candidates as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_validation(
+        &self,
+        candidates_path: &Path,
+        threshold: usize,
+        correct: usize,
+        extra: usize,
+        precision: f32,
+        mean_selectivity: f64,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.execute(
+            "INSERT INTO validations
+             (run_id, candidates_path, threshold, correct, extra, precision, mean_selectivity)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                self.run_id,
+                candidates_path.display().to_string(),
+                threshold as i64,
+                correct as i64,
+                extra as i64,
+                precision,
+                mean_selectivity,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records of all queries, those of size buckets are only written to the report
+    pub fn add_bench(&mut self, records: &[BenchRecord]) -> Result<(), anyhow::Error> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO bench (run_id, method, threshold, cache, time_ms, max_time_ms, candidates,
+                 precision, recall) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for r in records.iter().filter(|r| r.min_size.is_none()) {
+                insert.execute(params![
+                    self.run_id,
+                    r.method,
+                    r.threshold.map(|k| k as i64),
+                    format!("{:?}", r.cache).to_lowercase(),
+                    r.time_ms as i64,
+                    r.max_time_ms as i64,
+                    r.candidates as i64,
+                    r.precision,
+                    r.recall,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_are_appended() {
+        let path = std::env::temp_dir().join("tree-statistics-store.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let mut first = ResultStore::open(&path, "LowerBound", Path::new("trees.bracket")).unwrap();
+        first.add_candidates("Sed", &[(0, 1), (0, 2)]).unwrap();
+        first
+            .add_timing("Sed", Duration::from_millis(3), 2)
+            .unwrap();
+        let second = ResultStore::open(&path, "LowerBound", Path::new("trees.bracket")).unwrap();
+        second
+            .add_timing("Lblint", Duration::from_millis(1), 0)
+            .unwrap();
+        assert_eq!(second.run_id(), first.run_id() + 1);
+
+        let count = |sql: &str| -> i64 { first.conn.query_row(sql, [], |r| r.get(0)).unwrap() };
+        assert_eq!(count("SELECT count(*) FROM runs"), 2);
+        assert_eq!(
+            count("SELECT count(*) FROM candidates WHERE run_id = 1 AND method = 'Sed'"),
+            2
+        );
+        assert_eq!(count("SELECT count(*) FROM timings"), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Routing of queries to the lower bound method named in their query file line.
+//!
+//! A query line `<id>;<threshold>;<method>;<tree>` runs with its own method, other
+//! lines run with the method given on the command line. The queries of each method run
+//! together on the indexes shared by all methods of the run, so a mixed workload sends
+//! tiny queries to a cheap filter and huge ones to a tight one in a single run.
+//! Candidates keep the positions of queries in the file as query ids, the ids of the
+//! lines are written next to them.
+use crate::lb::{self, preprocess::Preprocessed};
+use crate::parsing::{ParsedTree, QueryRoute};
+use crate::LowerBoundMethods;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteStats {
+    pub method: LowerBoundMethods,
+    pub queries: usize,
+    pub candidates: usize,
+    pub duration: Duration,
+}
+
+impl Display for RouteStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}: {} queries, {} candidates in {}ms",
+            self.method,
+            self.queries,
+            self.candidates,
+            self.duration.as_millis()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct RoutedCandidates {
+    pub candidates: Vec<(usize, usize)>,
+    /// Sum of the times of all methods
+    pub duration: Duration,
+    pub stats: Vec<RouteStats>,
+}
+
+/// Method of every query, queries without one get `default`
+pub fn resolve(
+    routes: &[QueryRoute],
+    default: Option<LowerBoundMethods>,
+) -> Result<Vec<LowerBoundMethods>, anyhow::Error> {
+    routes
+        .iter()
+        .enumerate()
+        .map(|(qid, route)| match &route.method {
+            Some(name) => LowerBoundMethods::from_str(name, true)
+                .map_err(|_| anyhow::anyhow!("Unknown method {name} of query {qid}")),
+            None => default.ok_or_else(|| {
+                anyhow::anyhow!("Query {qid} names no method and no method was given")
+            }),
+        })
+        .collect()
+}
+
+/// Runs the queries of every method with it, candidates use the query ids of `queries`
+pub fn run_routed(
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+    methods: &[LowerBoundMethods],
+    config: &lb::RunConfig,
+) -> Result<RoutedCandidates, anyhow::Error> {
+    let mut groups = BTreeMap::<_, Vec<usize>>::new();
+    for (qid, method) in methods.iter().enumerate() {
+        groups.entry(*method).or_default().push(qid);
+    }
+    let mut routed = RoutedCandidates {
+        candidates: vec![],
+        duration: Duration::ZERO,
+        stats: vec![],
+    };
+    for (method, qids) in groups {
+        let group_queries = qids
+            .iter()
+            .map(|&qid| queries[qid].clone())
+            .collect::<Vec<_>>();
+        let (candidates, duration) = lb::run_method(method, pre, &group_queries, config)?;
+        routed.stats.push(RouteStats {
+            method,
+            queries: qids.len(),
+            candidates: candidates.len(),
+            duration,
+        });
+        routed.duration += duration;
+        routed
+            .candidates
+            .extend(candidates.into_iter().map(|(qid, tid)| (qids[qid], tid)));
+    }
+    Ok(routed)
+}
+
+/// Writes `query_id,id,method` of every query, the id is empty for lines without one
+pub fn write_routes(
+    path: impl AsRef<Path>,
+    routes: &[QueryRoute],
+    methods: &[LowerBoundMethods],
+) -> Result<(), anyhow::Error> {
+    let mut w = csv::Writer::from_path(path)?;
+    w.write_record(["query_id", "id", "method"])?;
+    for (qid, (route, method)) in routes.iter().zip(methods).enumerate() {
+        let method = method
+            .to_possible_value()
+            .expect("routed methods are named");
+        w.write_record([
+            qid.to_string().as_str(),
+            route.id.as_deref().unwrap_or_default(),
+            method.get_name(),
+        ])?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_routed_queries() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = ["{a{b}}", "{x{y}{z}}", "{a}"]
+            .map(|q| (1, parse_single(q.to_owned(), &mut ld)))
+            .to_vec();
+        let route = |method: Option<&str>| QueryRoute {
+            id: None,
+            method: method.map(str::to_owned),
+        };
+        let routes = [
+            route(Some("structural")),
+            route(None),
+            route(Some("LBLINT")),
+        ];
+        assert!(resolve(&routes, None).is_err());
+        assert!(resolve(&[route(Some("fastest"))], Some(LowerBoundMethods::Sed)).is_err());
+        let methods = resolve(&routes, Some(LowerBoundMethods::Sed)).unwrap();
+        use LowerBoundMethods as LBM;
+        assert_eq!(methods, [LBM::Structural, LBM::Sed, LBM::Lblint]);
+
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        let mut routed = run_routed(&pre, &queries, &methods, &lb::RunConfig::default()).unwrap();
+        assert_eq!(routed.stats.len(), 3);
+        // every query gets the candidates of its own method
+        let mut expected = vec![];
+        for (qid, method) in methods.iter().enumerate() {
+            let (candidates, _) = lb::run_method(
+                *method,
+                &pre,
+                &queries[qid..=qid],
+                &lb::RunConfig::default(),
+            )
+            .unwrap();
+            expected.extend(candidates.into_iter().map(|(_, tid)| (qid, tid)));
+        }
+        routed.candidates.sort_unstable();
+        assert_eq!(routed.candidates, expected);
+    }
+}
@@ -0,0 +1,276 @@
+//! Provenance of a run written as `run.json` into its output directory.
+//!
+//! The file records the command line, crate version, thread count, sizes and 64-bit
+//! FNV-1a hashes of the input files and the wall-clock time of every phase, so results
+//! can be traced back to the exact inputs and build that produced them. Builds with the
+//! `peak-memory` feature add the heap high-water mark of every method. With an event
+//! log, phases are also streamed as they finish.
+use crate::events::{Event, EventLog};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const FILE_NAME: &str = "run.json";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InputFile {
+    pub path: String,
+    pub bytes: u64,
+    /// hex FNV-1a hash of the content, missing for directories of source files or pages
+    pub fnv1a: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Phase {
+    pub name: String,
+    pub millis: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeakMemory {
+    pub name: String,
+    pub bytes: usize,
+}
+
+/// Queries of a method stopped at `--per-query-timeout`, their candidates are partial
+#[derive(Debug, Clone, Serialize)]
+pub struct TimedOutQueries {
+    pub method: String,
+    pub limit_millis: u128,
+    pub queries: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunInfo {
+    pub command: String,
+    pub arguments: Vec<String>,
+    pub version: &'static str,
+    pub threads: usize,
+    /// seconds since the unix epoch
+    pub started_at: u64,
+    pub inputs: Vec<InputFile>,
+    pub phases: Vec<Phase>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub peak_memory: Vec<PeakMemory>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timed_out: Vec<TimedOutQueries>,
+    /// Order of the ids of written candidate pairs, e.g. `query-tree`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pair_order: Option<String>,
+    #[serde(skip)]
+    events: Option<EventLog>,
+}
+
+impl RunInfo {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+            arguments: std::env::args().collect(),
+            version: env!("CARGO_PKG_VERSION"),
+            threads: rayon::current_num_threads(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            inputs: vec![],
+            phases: vec![],
+            peak_memory: vec![],
+            timed_out: vec![],
+            pair_order: None,
+            events: None,
+        }
+    }
+
+    /// Streams events of the run into the file, starting with the command line
+    pub fn log_events(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        let mut log = EventLog::create(path)?;
+        log.emit(&Event::Start {
+            command: &self.command,
+            arguments: &self.arguments,
+        })?;
+        self.events = Some(log);
+        Ok(())
+    }
+
+    /// Writes the event if events are logged. A failed write stops the log with a
+    /// warning instead of failing the run
+    fn emit(&mut self, event: Event) {
+        if let Some(log) = self.events.as_mut() {
+            if let Err(e) = log.emit(&event) {
+                eprintln!("Stopped writing events: {e}");
+                self.events = None;
+            }
+        }
+    }
+
+    /// Hashes the content of the file, directories are only recorded by path
+    pub fn add_input(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        let (bytes, fnv1a) = if path.is_dir() {
+            (0, None)
+        } else {
+            let (bytes, hash) = hash_file(path)?;
+            (bytes, Some(format!("{hash:016x}")))
+        };
+        self.inputs.push(InputFile {
+            path: path.display().to_string(),
+            bytes,
+            fnv1a,
+        });
+        Ok(())
+    }
+
+    pub fn add_phase(&mut self, name: impl Into<String>, duration: Duration) {
+        let phase = Phase {
+            name: name.into(),
+            millis: duration.as_millis(),
+        };
+        self.emit(Event::Phase {
+            name: &phase.name,
+            millis: phase.millis,
+        });
+        self.phases.push(phase);
+    }
+
+    pub fn add_peak_memory(&mut self, name: impl Into<String>, bytes: usize) {
+        let peak = PeakMemory {
+            name: name.into(),
+            bytes,
+        };
+        self.emit(Event::PeakMemory {
+            name: &peak.name,
+            bytes: peak.bytes,
+        });
+        self.peak_memory.push(peak);
+    }
+
+    pub fn add_timed_out(&mut self, method: impl Into<String>, limit: Duration, queries: &[usize]) {
+        self.timed_out.push(TimedOutQueries {
+            method: method.into(),
+            limit_millis: limit.as_millis(),
+            queries: queries.to_vec(),
+        });
+    }
+
+    /// Logs the number of candidates of every query, nothing is counted without events
+    pub fn add_query_counts(
+        &mut self,
+        method: &str,
+        queries: usize,
+        candidates: &[(usize, usize)],
+    ) {
+        if self.events.is_none() {
+            return;
+        }
+        let mut counts = vec![0; queries];
+        for (qid, _) in candidates {
+            counts[*qid] += 1;
+        }
+        for (query_id, candidates) in counts.into_iter().enumerate() {
+            self.emit(Event::Query {
+                method,
+                query_id,
+                candidates,
+            });
+        }
+    }
+
+    /// Ends the event log with the time since it was opened
+    pub fn finish(&mut self) {
+        if let Some(millis) = self.events.as_ref().map(EventLog::elapsed_millis) {
+            self.emit(Event::Finish { millis });
+        }
+    }
+
+    /// Runs `f` and records its wall-clock time as a phase
+    pub fn time<T>(&mut self, name: impl Into<String>, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add_phase(name, start.elapsed());
+        result
+    }
+
+    /// Writes `run.json` into the directory, replacing a previous one
+    pub fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        let mut w = BufWriter::new(File::create(dir.join(FILE_NAME))?);
+        serde_json::to_writer_pretty(&mut w, self)?;
+        writeln!(w)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Initial value of a 64-bit FNV-1a hash
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continues the 64-bit FNV-1a `hash` with `bytes`
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes
+        .iter()
+        .fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(PRIME))
+}
+
+/// Size and 64-bit FNV-1a hash of the file content
+pub fn hash_file(path: &Path) -> Result<(u64, u64), std::io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = vec![0; 1 << 16];
+    let (mut bytes, mut hash) = (0, FNV_OFFSET);
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((bytes, hash));
+        }
+        bytes += read as u64;
+        hash = fnv1a(hash, &buffer[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_run_info() {
+        let dir = std::env::temp_dir().join(format!("run-info-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.bracket");
+        std::fs::write(&input, "a").unwrap();
+
+        let mut run = RunInfo::new("LowerBound");
+        run.add_input(&input).unwrap();
+        run.add_input(&dir).unwrap();
+        assert_eq!(run.time("parsing", || 1 + 1), 2);
+        run.write(&dir).unwrap();
+
+        let json = std::fs::read_to_string(dir.join(FILE_NAME)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["command"], "LowerBound");
+        // FNV-1a of "a"
+        assert_eq!(json["inputs"][0]["fnv1a"], "af63dc4c8601ec8c");
+        assert_eq!(json["inputs"][0]["bytes"], 1);
+        assert!(json["inputs"][1]["fnv1a"].is_null());
+        assert_eq!(json["phases"][0]["name"], "parsing");
+        assert!(json.get("events").is_none());
+
+        let events = dir.join("events.ndjson");
+        run.log_events(&events).unwrap();
+        run.add_phase("Sed query", Duration::from_millis(5));
+        run.add_query_counts("Sed", 3, &[(0, 1), (2, 1), (2, 4)]);
+        run.finish();
+        let events = std::fs::read_to_string(events).unwrap();
+        let events = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let kinds = events.iter().map(|e| e["event"].as_str().unwrap());
+        assert!(kinds.eq(["start", "phase", "query", "query", "query", "finish"]));
+        assert_eq!(events[0]["command"], "LowerBound");
+        assert_eq!(events[1]["millis"], 5);
+        let counts = events[2..5]
+            .iter()
+            .map(|e| e["candidates"].as_u64().unwrap());
+        assert!(counts.eq([1, 0, 2]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
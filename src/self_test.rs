@@ -0,0 +1,289 @@
+//! Randomized check that the lower bounds never exceed the exact tree edit distance.
+//!
+//! Pairs of small random trees over a tiny alphabet are compared, exact distances come
+//! from a brute force recursion over forests that shares no code with [`crate::ted`],
+//! which is checked against it as well. Bounds computed without a threshold have to be
+//! at most the exact distance. Bounds computed with a threshold `k` may stop early with
+//! any value above `k`, or below `k` once the pair is known to pass, so they are only
+//! checked as filters: a pair within the threshold must never be pruned.
+use crate::generate::{self, GeneratorConfig, SizeDistribution};
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::binary_branch::{self, BinaryBranchConverter, BRANCH_DIVISOR};
+use crate::lb::indexes::histograms::{create_tree_histograms, histogram_ted, HistogramDivisors};
+use crate::lb::label_intersection::{label_intersection, label_intersection_k};
+use crate::lb::sed::{sed, sed_k};
+use crate::lb::structural_filter::{
+    best_split_distribution, ted as struct_ted_k, ted_variant as struct_split_ted_k,
+    LabelSetConverter, StructuralFilterTuple,
+};
+use crate::parsing::{parse_single, LabelDict, LabelId, ParsedTree};
+use crate::ted;
+use indextree::NodeId;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct SelfTestConfig {
+    pub pairs: usize,
+    pub max_size: usize,
+    pub alphabet: usize,
+    pub seed: u64,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            pairs: 1000,
+            max_size: 7,
+            alphabet: 3,
+            seed: 0,
+        }
+    }
+}
+
+/// A pair of trees on which a checked function disagrees with the brute force distance
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub check: &'static str,
+    pub t1: String,
+    pub t2: String,
+    pub k: usize,
+    pub value: usize,
+    pub ted: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub pairs: usize,
+    pub violations: Vec<Violation>,
+}
+
+/// Names of checked functions in the order they are reported
+const CHECKS: [&str; 10] = [
+    "ted",
+    "ted_k",
+    "lblint",
+    "lblint_k",
+    "sed",
+    "sed_k",
+    "hist",
+    "structural",
+    "structural_split",
+    "bib",
+];
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Checked {} pairs", self.pairs)?;
+        for check in CHECKS {
+            let violations = self.violations.iter().filter(|v| v.check == check);
+            writeln!(f, "  {check}: {} violations", violations.clone().count())?;
+            for v in violations.take(3) {
+                writeln!(
+                    f,
+                    "    {} {} k={}: bound {} with exact distance {}",
+                    v.t1, v.t2, v.k, v.value, v.ted
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Node {
+    label: LabelId,
+    children: Vec<Node>,
+}
+
+fn to_node(tree: &ParsedTree, nid: NodeId) -> Node {
+    Node {
+        label: *tree[nid].get(),
+        children: nid.children(tree).map(|c| to_node(tree, c)).collect(),
+    }
+}
+
+fn forest_size(forest: &[Node]) -> usize {
+    forest.iter().map(|n| 1 + forest_size(&n.children)).sum()
+}
+
+type Memo = FxHashMap<(Vec<Node>, Vec<Node>), usize>;
+
+/// Edit distance of forests by the recursion on their rightmost roots
+fn forest_distance(f: &[Node], g: &[Node], memo: &mut Memo) -> usize {
+    let (Some(v), Some(w)) = (f.last(), g.last()) else {
+        return forest_size(f) + forest_size(g);
+    };
+    let key = (f.to_vec(), g.to_vec());
+    if let Some(distance) = memo.get(&key) {
+        return *distance;
+    }
+    let (f_rest, g_rest) = (&f[..f.len() - 1], &g[..g.len() - 1]);
+    let without = |rest: &[Node], root: &Node| {
+        rest.iter()
+            .chain(root.children.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let delete = forest_distance(&without(f_rest, v), g, memo) + 1;
+    let insert = forest_distance(f, &without(g_rest, w), memo) + 1;
+    let matched = forest_distance(f_rest, g_rest, memo)
+        + forest_distance(&v.children, &w.children, memo)
+        + usize::from(v.label != w.label);
+    let distance = delete.min(insert).min(matched);
+    memo.insert(key, distance);
+    distance
+}
+
+/// Exact tree edit distance with unit costs, exponential in tree size
+pub fn brute_force_ted(t1: &ParsedTree, t2: &ParsedTree) -> usize {
+    let root = |t: &ParsedTree| {
+        t.iter()
+            .next()
+            .map(|n| to_node(t, t.get_node_id(n).unwrap()))
+    };
+    let f = root(t1).into_iter().collect::<Vec<_>>();
+    let g = root(t2).into_iter().collect::<Vec<_>>();
+    forest_distance(&f, &g, &mut Memo::default())
+}
+
+/// Compares all checked functions with the brute force distance on random pairs
+pub fn run(config: &SelfTestConfig) -> Result<Report, IndexError> {
+    let brackets = generate::generate(&GeneratorConfig {
+        count: 2 * config.pairs,
+        min_size: 1,
+        max_size: config.max_size,
+        size_distribution: SizeDistribution::Uniform,
+        max_fanout: 0,
+        depth_bias: 0.5,
+        alphabet: config.alphabet,
+        zipf: 0.0,
+        seed: config.seed,
+    });
+    let mut ld = LabelDict::default();
+    let trees = brackets
+        .iter()
+        .map(|b| parse_single(b.clone(), &mut ld))
+        .collect::<Vec<_>>();
+    let traversals = SEDIndex::index_collection(&trees, &ld)?;
+    let label_sets = InvertedListLabelPostorderIndex::index_collection(&trees, &ld)?;
+    let structural_sets = StructuralFilterTuple::index_collection(&trees, &ld)?;
+    let groups = best_split_distribution(&ld, LabelSetConverter::MAX_SPLIT);
+    let split_sets = LabelSetConverter::default().create_split(&trees, |label| groups[label])?;
+    let branches = BinaryBranchConverter::default().create(&trees);
+    let histograms = trees.iter().map(create_tree_histograms).collect::<Vec<_>>();
+
+    let violations = (0..config.pairs)
+        .into_par_iter()
+        .flat_map_iter(|pair| {
+            let (i, j) = (2 * pair, 2 * pair + 1);
+            let exact = brute_force_ted(&trees[i], &trees[j]);
+            let mut violations = vec![];
+            let mut check = |check, k, value, valid: bool| {
+                if !valid {
+                    violations.push(Violation {
+                        check,
+                        t1: brackets[i].clone(),
+                        t2: brackets[j].clone(),
+                        k,
+                        value,
+                        ted: exact,
+                    });
+                }
+            };
+            let computed = ted::ted(&trees[i], &trees[j]);
+            check("ted", 0, computed, computed == exact);
+            let unbounded = [
+                ("lblint", label_intersection(&label_sets[i], &label_sets[j])),
+                ("sed", sed(&traversals[i], &traversals[j])),
+                (
+                    "hist",
+                    histogram_ted(
+                        (trees[i].count(), &histograms[i]),
+                        (trees[j].count(), &histograms[j]),
+                        HistogramDivisors::TIGHT,
+                    ),
+                ),
+            ];
+            for (name, bound) in unbounded {
+                check(name, 0, bound, bound <= exact);
+            }
+            // thresholds below the exact distance may prune the pair, any value is valid
+            let largest = trees[i].count() + trees[j].count();
+            for k in (exact..=exact + 2).chain([largest]) {
+                let bounded = ted::ted_k(&trees[i], &trees[j], k);
+                check("ted_k", k, bounded, bounded == exact);
+                let filters = [
+                    (
+                        "lblint_k",
+                        label_intersection_k(&label_sets[i], &label_sets[j], k),
+                    ),
+                    ("sed_k", sed_k(&traversals[i], &traversals[j], k)),
+                    (
+                        "structural",
+                        struct_ted_k(&structural_sets[i], &structural_sets[j], k),
+                    ),
+                    (
+                        "structural_split",
+                        struct_split_ted_k(&split_sets[i], &split_sets[j], k),
+                    ),
+                    (
+                        "bib",
+                        binary_branch::ted(&branches[i], &branches[j], k, BRANCH_DIVISOR),
+                    ),
+                ];
+                for (name, bound) in filters {
+                    check(name, k, bound, bound <= k);
+                }
+            }
+            violations
+        })
+        .collect();
+    Ok(Report {
+        pairs: config.pairs,
+        violations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brute_force_ted() {
+        let mut ld = LabelDict::default();
+        let mut ted_of = |t1: &str, t2: &str| {
+            let t1 = parse_single(t1.to_owned(), &mut ld);
+            let t2 = parse_single(t2.to_owned(), &mut ld);
+            brute_force_ted(&t1, &t2)
+        };
+        assert_eq!(ted_of("{a{b}{c}}", "{a{b}{c}}"), 0);
+        assert_eq!(ted_of("{a{b}{c}}", "{a{b{c}}}"), 2);
+        assert_eq!(ted_of("{a{b}{c}}", "{x{b}}"), 2);
+        assert_eq!(ted_of("{f{d{a}{c{b}}}{e}}", "{f{c{d{a}{b}}}{e}}"), 2);
+
+        let report = run(&SelfTestConfig {
+            pairs: 50,
+            ..SelfTestConfig::default()
+        })
+        .unwrap();
+        assert_eq!(report.pairs, 50);
+        assert!(report.violations.is_empty(), "{report}");
+    }
+
+    #[cfg(feature = "slow-tests")]
+    #[test]
+    fn test_bounds_on_random_trees() {
+        for seed in 0..4 {
+            let report = run(&SelfTestConfig {
+                pairs: 5000,
+                max_size: 8,
+                seed,
+                ..SelfTestConfig::default()
+            })
+            .unwrap();
+            assert!(report.violations.is_empty(), "{report}");
+        }
+    }
+}
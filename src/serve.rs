@@ -0,0 +1,241 @@
+//! HTTP server answering similarity queries against a collection loaded once.
+//!
+//! Endpoints:
+//! - `POST /query` with `{"tree": "{a{b}}", "k": 2, "method": "sed", "verify": true}`,
+//!   `method` defaults to the first served method. Returns ids of candidate trees and,
+//!   with `verify`, ids and exact distances of trees within `k`.
+//! - `GET /status` with the collection size and served methods.
+//! - `POST /trees` with `{"tree": "{a{b}}"}` inserts a tree and returns its `id`.
+//! - `DELETE /trees/<id>` deletes a tree.
+//!
+//! Tree ids are positions in the size sorted collection, the same as in candidate files,
+//! inserted trees get the following ids. With a write-ahead log updates survive restarts.
+use crate::parsing::{LabelDict, ParsedTree};
+use crate::query_service::{method_name, QueryError, QueryService};
+use crate::LowerBoundMethods;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest accepted request body
+const MAX_BODY_BYTES: usize = 16 << 20;
+
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    tree: String,
+    k: usize,
+    method: Option<String>,
+    #[serde(default)]
+    verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct InsertRequest {
+    tree: String,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateResponse {
+    id: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Match {
+    id: usize,
+    distance: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryResponse {
+    method: String,
+    candidates: Vec<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<Match>>,
+    filter_us: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verify_us: Option<u128>,
+    cached: bool,
+    /// Filtering stopped at the time limit, candidates are partial
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    trees: usize,
+    labels: usize,
+    methods: Vec<String>,
+    index_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_hits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_misses: Option<usize>,
+}
+
+/// Error answered with a status code and a JSON message
+struct HttpError(u16, String);
+
+impl From<serde_json::Error> for HttpError {
+    fn from(e: serde_json::Error) -> Self {
+        Self(400, format!("Invalid request: {e}"))
+    }
+}
+
+impl From<QueryError> for HttpError {
+    fn from(e: QueryError) -> Self {
+        match e {
+            QueryError::Invalid(msg) => Self(400, msg),
+            QueryError::NotFound(msg) => Self(404, msg),
+            QueryError::Internal(msg) => Self(500, msg),
+        }
+    }
+}
+
+fn status(service: &QueryService) -> StatusResponse {
+    StatusResponse {
+        trees: service.trees(),
+        labels: service.labels(),
+        methods: service.methods().iter().copied().map(method_name).collect(),
+        index_bytes: service
+            .methods()
+            .iter()
+            .filter_map(|m| service.index_bytes(*m).ok())
+            .sum(),
+        cache_hits: service.cache_stats().map(|(hits, _)| hits),
+        cache_misses: service.cache_stats().map(|(_, misses)| misses),
+    }
+}
+
+fn query(service: &QueryService, request: QueryRequest) -> Result<QueryResponse, HttpError> {
+    let result = service.query(
+        &request.tree,
+        request.k,
+        request.method.as_deref(),
+        request.verify,
+    )?;
+    if result.timed_out {
+        eprintln!(
+            "Query with k {} exceeded the time limit, its candidates are partial",
+            request.k
+        );
+    }
+    Ok(QueryResponse {
+        method: method_name(result.method),
+        candidates: result.candidates,
+        matches: result.matches.map(|matches| {
+            matches
+                .into_iter()
+                .map(|(id, distance)| Match { id, distance })
+                .collect()
+        }),
+        filter_us: result.filter_time.as_micros(),
+        verify_us: result.verify_time.map(|t| t.as_micros()),
+        cached: result.cached,
+        timed_out: result.timed_out,
+    })
+}
+
+/// Reads the request body up to [`MAX_BODY_BYTES`]
+fn body(request: &mut Request) -> Result<String, HttpError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES as u64 + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| HttpError(400, format!("Unable to read request: {e}")))?;
+    if body.len() > MAX_BODY_BYTES {
+        return Err(HttpError(413, "Request is too large".to_owned()));
+    }
+    Ok(body)
+}
+
+fn handle(service: &QueryService, request: &mut Request) -> Result<String, HttpError> {
+    let url = request.url().to_owned();
+    match (request.method(), url.as_str()) {
+        (Method::Get, "/status") => Ok(serde_json::to_string(&status(service))?),
+        (Method::Post, "/query") => {
+            let response = query(service, serde_json::from_str(&body(request)?)?)?;
+            Ok(serde_json::to_string(&response)?)
+        }
+        (Method::Post, "/trees") => {
+            let insert: InsertRequest = serde_json::from_str(&body(request)?)?;
+            let id = service.insert(&insert.tree)?;
+            Ok(serde_json::to_string(&UpdateResponse { id })?)
+        }
+        (Method::Delete, url) if url.starts_with("/trees/") => {
+            let id = url["/trees/".len()..]
+                .parse()
+                .map_err(|_| HttpError(404, format!("Unknown endpoint {url}")))?;
+            service.delete(id)?;
+            Ok(serde_json::to_string(&UpdateResponse { id })?)
+        }
+        (_, "/status" | "/query" | "/trees") => {
+            Err(HttpError(405, "Method not allowed".to_owned()))
+        }
+        (_, url) if url.starts_with("/trees/") => {
+            Err(HttpError(405, "Method not allowed".to_owned()))
+        }
+        (_, url) => Err(HttpError(404, format!("Unknown endpoint {url}"))),
+    }
+}
+
+/// Builds indexes of `methods` and serves queries on `listen` until the process is killed.
+/// Updates logged in `wal` are replayed before serving, filtering of a query stops after
+/// `timeout`.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    trees: Vec<ParsedTree>,
+    label_dict: LabelDict,
+    methods: &[LowerBoundMethods],
+    listen: &str,
+    workers: usize,
+    cache_size: usize,
+    wal: Option<&Path>,
+    timeout: Option<Duration>,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let start = Instant::now();
+    let mut service = QueryService::new(trees, label_dict, methods)?
+        .with_cache(cache_size)
+        .with_timeout(timeout);
+    if let Some(wal) = wal {
+        let replayed;
+        (service, replayed) = service.with_wal(&wal)?;
+        if !quiet {
+            println!("Replayed {replayed} updates from {}", wal.display());
+        }
+    }
+    let server = Server::http(listen).map_err(|e| anyhow::anyhow!("Unable to listen: {e}"))?;
+    if !quiet {
+        println!(
+            "Built indexes in {}ms, listening on {}",
+            start.elapsed().as_millis(),
+            server.server_addr()
+        );
+    }
+
+    let json = Header::from_bytes("Content-Type", "application/json").unwrap();
+    std::thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            s.spawn(|| {
+                for mut request in server.incoming_requests() {
+                    let (status, body) = match handle(&service, &mut request) {
+                        Ok(body) => (200, body),
+                        Err(HttpError(status, msg)) => {
+                            (status, serde_json::json!({ "error": msg }).to_string())
+                        }
+                    };
+                    let response = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(json.clone());
+                    if let Err(e) = request.respond(response) {
+                        eprintln!("Unable to respond: {e}");
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Partitioning of the collection for joins spread over independent processes.
+//!
+//! A shard `i/n` owns a contiguous range of the size sorted collection, ranges have
+//! about the same number of trees and their bounds never split trees of the same size.
+//! Every process parses the whole collection, so tree ids stay positions in the whole
+//! collection, and computes candidates of all queries against its own trees only.
+//! Candidates of shards are disjoint and together equal those of a single process.
+use crate::parsing::ParsedTree;
+use std::ops::Range;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// 1 based index of the shard
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Shard `{s}` is not in the i/n form"))?;
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid shard number `{n}`"))
+        };
+        let (index, count) = (parse(index)?, parse(count)?);
+        if index == 0 || index > count {
+            return Err(format!("Shard index has to be between 1 and {count}"));
+        }
+        Ok(Self { index, count })
+    }
+}
+
+impl std::fmt::Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// Range of positions of trees sorted by size owned by the shard
+    pub fn range(&self, trees: &[ParsedTree]) -> Range<usize> {
+        // bounds are moved past all trees of the size before them
+        let bound = |shard: usize| {
+            let position = (trees.len() * shard).div_ceil(self.count);
+            if position == 0 || position >= trees.len() {
+                return position.min(trees.len());
+            }
+            let size = trees[position - 1].count();
+            trees.partition_point(|t| t.count() <= size)
+        };
+        bound(self.index - 1)..bound(self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_shards_cover_sizes() {
+        assert!("0/2".parse::<Shard>().is_err());
+        assert!("3/2".parse::<Shard>().is_err());
+        assert!("2".parse::<Shard>().is_err());
+
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a}",
+            "{a{b}}",
+            "{a{c}}",
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{a{b{c}{d}}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let ranges = (1..=3)
+            .map(|i| format!("{i}/3").parse::<Shard>().unwrap().range(&trees))
+            .collect::<Vec<_>>();
+        // the second bound falls between trees of size 2
+        assert_eq!(ranges, vec![0..4, 4..4, 4..6]);
+        assert_eq!("1/1".parse::<Shard>().unwrap().range(&trees), 0..6);
+    }
+}
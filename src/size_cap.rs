@@ -0,0 +1,156 @@
+//! Cap on the size of dataset trees.
+//!
+//! Candidate generation and verification of a pair take time and memory growing with
+//! the sizes of its trees, a single tree far larger than the rest of a dataset can
+//! dominate a run. Trees over the cap are skipped, truncated to the preorder prefix of
+//! the cap's size, which keeps the root and drops the last subtrees, or fail the run.
+//! Trees are capped in dataset order before they are sorted by size, so reported
+//! positions are positions of parsed trees in the dataset.
+use crate::parsing::ParsedTree;
+use clap::ValueEnum;
+use indextree::NodeId;
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use std::fmt::Display;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OversizedPolicy {
+    /// Drops trees over the cap and reports how many were dropped
+    Skip,
+    /// Keeps the first nodes of trees over the cap in preorder
+    Truncate,
+    /// Fails naming the first tree over the cap
+    Fail,
+}
+
+/// Trees over the cap, empty when no tree exceeded it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapReport {
+    pub policy: Option<OversizedPolicy>,
+    pub max_size: usize,
+    /// dataset positions of the trees over the cap
+    pub positions: Vec<usize>,
+    pub largest: usize,
+}
+
+impl Display for CapReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.policy {
+            Some(OversizedPolicy::Truncate) => "Truncated",
+            _ => "Skipped",
+        };
+        write!(
+            f,
+            "{action} {} trees of more than {} nodes, the largest had {} nodes, positions: {}",
+            self.positions.len(),
+            self.max_size,
+            self.largest,
+            self.positions.iter().take(10).join(",")
+        )?;
+        if self.positions.len() > 10 {
+            write!(f, ",...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Preorder prefix of `max_size` nodes, ancestors of a node come before it in preorder,
+/// so the prefix is a tree with the same root
+pub fn truncate(tree: &ParsedTree, max_size: usize) -> ParsedTree {
+    let mut truncated = ParsedTree::with_capacity(max_size);
+    let Some(root) = tree.iter().next() else {
+        return truncated;
+    };
+    let root = tree.get_node_id(root).unwrap();
+    let mut copies = FxHashMap::<NodeId, NodeId>::default();
+    for nid in root.descendants(tree).take(max_size) {
+        let node = &tree[nid];
+        let copy = truncated.new_node(*node.get());
+        if let Some(parent) = node.parent() {
+            copies[&parent].append(copy, &mut truncated);
+        }
+        copies.insert(nid, copy);
+    }
+    truncated
+}
+
+/// Applies the policy to trees of more than `max_size` nodes
+pub fn cap_tree_sizes(
+    trees: &mut Vec<ParsedTree>,
+    max_size: usize,
+    policy: OversizedPolicy,
+) -> Result<CapReport, anyhow::Error> {
+    let mut report = CapReport {
+        max_size,
+        ..CapReport::default()
+    };
+    for (position, tree) in trees.iter().enumerate() {
+        if tree.count() > max_size {
+            if policy == OversizedPolicy::Fail {
+                anyhow::bail!(crate::error::Error::Validation(format!(
+                    "Tree {position} of the dataset has {} nodes, more than the cap of {max_size}",
+                    tree.count()
+                )));
+            }
+            report.positions.push(position);
+            report.largest = report.largest.max(tree.count());
+        }
+    }
+    if report.positions.is_empty() {
+        return Ok(report);
+    }
+    report.policy = Some(policy);
+    match policy {
+        OversizedPolicy::Skip => trees.retain(|tree| tree.count() <= max_size),
+        OversizedPolicy::Truncate => {
+            for &position in &report.positions {
+                trees[position] = truncate(&trees[position], max_size);
+            }
+        }
+        OversizedPolicy::Fail => unreachable!("failed on the first tree over the cap"),
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, tree_to_string, LabelDict, TreeOutput};
+
+    #[test]
+    fn test_cap_tree_sizes() {
+        let mut ld = LabelDict::default();
+        let dataset = ["{a{b{c}{d}}{e}}", "{a}", "{a{b}{c}}", "{x{y{z{w}}}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+
+        let mut trees = dataset.clone();
+        let report = cap_tree_sizes(&mut trees, 3, OversizedPolicy::Skip).unwrap();
+        assert_eq!(report.positions, [0, 3]);
+        assert_eq!(report.largest, 5);
+        assert_eq!(trees, dataset[1..3]);
+        assert!(report.to_string().starts_with("Skipped 2 trees"));
+
+        let mut trees = dataset.clone();
+        cap_tree_sizes(&mut trees, 3, OversizedPolicy::Truncate).unwrap();
+        let truncated = parse_single("{a{b{c}}}".to_owned(), &mut ld);
+        assert_eq!(
+            tree_to_string(&trees[0], TreeOutput::BracketNotation),
+            tree_to_string(&truncated, TreeOutput::BracketNotation)
+        );
+        assert_eq!(trees[0].count(), 3);
+        assert_eq!(trees[1..3], dataset[1..3]);
+
+        let mut trees = dataset.clone();
+        assert!(cap_tree_sizes(&mut trees, 3, OversizedPolicy::Fail).is_err());
+        let report = cap_tree_sizes(&mut trees, 5, OversizedPolicy::Fail).unwrap();
+        assert_eq!(
+            report,
+            CapReport {
+                max_size: 5,
+                ..CapReport::default()
+            }
+        );
+        assert_eq!(trees, dataset);
+    }
+}
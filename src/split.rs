@@ -0,0 +1,78 @@
+//! Partitioning of a collection into dataset and query subsets.
+use clap::ValueEnum;
+use rand::seq::index::sample;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SplitStrategy {
+    /// Uniformly random query trees
+    Random,
+    /// One random query tree from each of equally sized strata of trees sorted by size
+    Stratified,
+    /// Every n-th tree becomes a query
+    EveryNth,
+}
+
+/// Selects `query_count` tree ids out of `tree_count` trees sorted by size.
+/// Returned ids are sorted.
+pub fn select_queries(
+    tree_count: usize,
+    query_count: usize,
+    strategy: SplitStrategy,
+    seed: u64,
+) -> Vec<usize> {
+    let query_count = query_count.min(tree_count);
+    if query_count == 0 {
+        return vec![];
+    }
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut selected = match strategy {
+        SplitStrategy::Random => sample(&mut rng, tree_count, query_count).into_vec(),
+        SplitStrategy::Stratified => (0..query_count)
+            .map(|stratum| {
+                let start = stratum * tree_count / query_count;
+                let end = (stratum + 1) * tree_count / query_count;
+                rng.gen_range(start..end)
+            })
+            .collect(),
+        SplitStrategy::EveryNth => {
+            let step = tree_count / query_count;
+            (0..query_count).map(|i| i * step).collect()
+        }
+    };
+    selected.sort_unstable();
+    selected
+}
+
+/// Splits tree ids into dataset and query ids based on selected query ids.
+pub fn partition(tree_count: usize, query_ids: &[usize]) -> (Vec<usize>, Vec<usize>) {
+    let dataset_ids = (0..tree_count)
+        .filter(|tid| query_ids.binary_search(tid).is_err())
+        .collect();
+    (dataset_ids, query_ids.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_strategies() {
+        for strategy in [
+            SplitStrategy::Random,
+            SplitStrategy::Stratified,
+            SplitStrategy::EveryNth,
+        ] {
+            let queries = select_queries(10, 3, strategy, 5);
+            assert_eq!(queries.len(), 3);
+            let (dataset, queries) = partition(10, &queries);
+            assert_eq!(dataset.len(), 7);
+            assert!(dataset.iter().all(|d| !queries.contains(d)));
+        }
+        assert_eq!(
+            select_queries(10, 3, SplitStrategy::EveryNth, 0),
+            vec![0, 3, 6]
+        );
+    }
+}
@@ -1,29 +1,20 @@
-use crate::parsing::{LabelFreqOrdering, ParsedTree};
+use crate::parsing::{label_names, LabelDict, LabelFreqOrdering, LabelId, ParsedTree};
 
+use indextree::NodeId;
 use itertools::Itertools;
 use num_traits::Num;
 use rayon::prelude::*;
 use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::iter::Sum;
-use std::num::NonZeroUsize;
+use std::path::Path;
 
-#[derive(Default, Debug, Clone)]
-pub struct TreeStatistics {
-    /// Slice of degrees of tree - useful for histograms and average degree
-    pub degrees: Vec<usize>,
-    /// Tree depths - length of each path from root to leaf
-    pub depths: Vec<usize>,
-    /// number of nodes in a tree
-    pub size: usize,
-    /// distinct labels in current tree
-    pub distinct_labels: usize,
-    /// collection wide unique labels in current tree
-    pub collection_unique_labels: usize,
-}
-
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct CollectionStatistics {
     /// min tree size in collection
     pub min_tree_size: usize,
@@ -39,11 +30,14 @@ pub struct CollectionStatistics {
     pub avg_tree_distinct_labels: f64,
 }
 
+/// CSV line of the statistics, averages have the precision of the formatter, 6 decimals
+/// without one
 impl fmt::Display for CollectionStatistics {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(6);
         write!(
             f,
-            "{},{},{:.6},{},{:.6},{:.6}",
+            "{},{},{:.precision$},{},{:.precision$},{:.precision$}",
             self.min_tree_size,
             self.max_tree_size,
             self.avg_tree_size,
@@ -54,25 +48,35 @@ impl fmt::Display for CollectionStatistics {
     }
 }
 
-pub fn gather(tree: &ParsedTree, freq_ordering: &LabelFreqOrdering) -> TreeStatistics {
-    if tree.is_empty() {
-        return TreeStatistics::default();
-    }
+/// Buffers of a tree walk reused for all trees of a worker
+#[derive(Default)]
+struct Scratch {
+    node_stack: Vec<NodeId>,
+    distinct_label_set: FxHashSet<LabelId>,
+}
 
+/// Appends degrees and depths of the tree, returns its distinct and collection wide
+/// unique labels
+fn walk(
+    tree: &ParsedTree,
+    freq_ordering: &LabelFreqOrdering,
+    scratch: &mut Scratch,
+    degrees: &mut Vec<usize>,
+    depths: &mut Vec<usize>,
+) -> (usize, usize) {
     let Some(root) = tree.iter().next() else {
-        panic!("Unable to get root but tree is not empty!");
+        return (0, 0);
     };
 
-    let mut node_stack = vec![];
+    let node_stack = &mut scratch.node_stack;
+    let distinct_label_set = &mut scratch.distinct_label_set;
+    node_stack.clear();
+    distinct_label_set.clear();
 
     let root_id = tree.get_node_id(root).unwrap();
-    let mut degrees = vec![];
-    let mut depths = vec![];
     let mut unique_labels = 0;
 
-    let mut distinct_label_set = FxHashSet::default();
-
-    if let Some(&freq) = freq_ordering.get(NonZeroUsize::new(*root.get() as usize).unwrap()) {
+    if let Some(&freq) = freq_ordering.get(*root.get()) {
         unique_labels += usize::from(freq == 1);
     }
 
@@ -87,7 +91,7 @@ pub fn gather(tree: &ParsedTree, freq_ordering: &LabelFreqOrdering) -> TreeStati
         let n = tree.get(nid).unwrap();
         let mut degree = nid.children(tree).count();
 
-        if let Some(&freq) = freq_ordering.get(NonZeroUsize::new(*n.get() as usize).unwrap()) {
+        if let Some(&freq) = freq_ordering.get(*n.get()) {
             unique_labels += usize::from(freq == 1);
         }
         distinct_label_set.insert(*n.get());
@@ -109,45 +113,200 @@ pub fn gather(tree: &ParsedTree, freq_ordering: &LabelFreqOrdering) -> TreeStati
         degrees.push(degree);
     }
 
-    TreeStatistics {
-        degrees,
-        depths,
-        size: tree.count(),
-        distinct_labels: distinct_label_set.len(),
-        collection_unique_labels: unique_labels,
+    (distinct_label_set.len(), unique_labels)
+}
+
+/// Statistics of trees in flat columns, one value of every tree in the scalar columns.
+/// Degrees and depths of all trees are concatenated, so gathering allocates per chunk of
+/// trees instead of per tree.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct StatisticsColumns {
+    pub sizes: Vec<usize>,
+    pub distinct_labels: Vec<usize>,
+    pub collection_unique_labels: Vec<usize>,
+    pub degrees: Vec<usize>,
+    pub depths: Vec<usize>,
+    /// ends of the degrees and depths of every tree
+    ends: Vec<(usize, usize)>,
+}
+
+impl StatisticsColumns {
+    fn starts(&self, tree: usize) -> (usize, usize) {
+        tree.checked_sub(1).map_or((0, 0), |prev| self.ends[prev])
+    }
+
+    #[cfg_attr(not(any(test, feature = "arrow")), allow(dead_code))]
+    pub fn degrees_of(&self, tree: usize) -> &[usize] {
+        &self.degrees[self.starts(tree).0..self.ends[tree].0]
+    }
+
+    #[cfg_attr(not(any(test, feature = "arrow")), allow(dead_code))]
+    pub fn depths_of(&self, tree: usize) -> &[usize] {
+        &self.depths[self.starts(tree).1..self.ends[tree].1]
+    }
+
+    fn push(
+        &mut self,
+        tree: &ParsedTree,
+        freq_ordering: &LabelFreqOrdering,
+        scratch: &mut Scratch,
+    ) {
+        let (distinct, unique) = walk(
+            tree,
+            freq_ordering,
+            scratch,
+            &mut self.degrees,
+            &mut self.depths,
+        );
+        self.sizes.push(tree.count());
+        self.distinct_labels.push(distinct);
+        self.collection_unique_labels.push(unique);
+        self.ends.push((self.degrees.len(), self.depths.len()));
+    }
+
+    fn concat(chunks: Vec<Self>) -> Self {
+        let total = |column: fn(&Self) -> usize| chunks.iter().map(column).sum::<usize>();
+        let mut all = Self {
+            sizes: Vec::with_capacity(total(|c| c.sizes.len())),
+            distinct_labels: Vec::with_capacity(total(|c| c.sizes.len())),
+            collection_unique_labels: Vec::with_capacity(total(|c| c.sizes.len())),
+            degrees: Vec::with_capacity(total(|c| c.degrees.len())),
+            depths: Vec::with_capacity(total(|c| c.depths.len())),
+            ends: Vec::with_capacity(total(|c| c.sizes.len())),
+        };
+        for chunk in chunks {
+            let (degrees, depths) = (all.degrees.len(), all.depths.len());
+            all.ends.extend(
+                chunk
+                    .ends
+                    .iter()
+                    .map(|(de, dp)| (de + degrees, dp + depths)),
+            );
+            all.sizes.extend(chunk.sizes);
+            all.distinct_labels.extend(chunk.distinct_labels);
+            all.collection_unique_labels
+                .extend(chunk.collection_unique_labels);
+            all.degrees.extend(chunk.degrees);
+            all.depths.extend(chunk.depths);
+        }
+        all
+    }
+
+    /// Statistics of the whole collection
+    pub fn summary(&self) -> CollectionStatistics {
+        let trees = self.sizes.len() as f64;
+        let (min, max) = self
+            .sizes
+            .iter()
+            .copied()
+            .minmax()
+            .into_option()
+            .unwrap_or((0, 0));
+        CollectionStatistics {
+            min_tree_size: min,
+            max_tree_size: max,
+            avg_tree_size: self.sizes.par_iter().sum::<usize>() as f64 / trees,
+            trees: self.sizes.len(),
+            avg_unique_label_per_tree: self.collection_unique_labels.par_iter().sum::<usize>()
+                as f64
+                / trees,
+            avg_tree_distinct_labels: self.distinct_labels.par_iter().sum::<usize>() as f64 / trees,
+        }
     }
 }
 
-pub fn summarize(all_statistics: &[TreeStatistics]) -> CollectionStatistics {
-    use itertools::MinMaxResult as MMR;
+/// Gathers statistics of chunks of `chunk_size` trees in parallel, every worker walks its
+/// chunk with one set of buffers and appends to the columns of the chunk
+pub fn gather_chunked(
+    trees: &[ParsedTree],
+    freq_ordering: &LabelFreqOrdering,
+    chunk_size: usize,
+) -> StatisticsColumns {
+    let chunks = trees
+        .par_chunks(chunk_size.max(1))
+        .map(|chunk| {
+            let mut scratch = Scratch::default();
+            let mut columns = StatisticsColumns::default();
+            for tree in chunk {
+                columns.push(tree, freq_ordering, &mut scratch);
+            }
+            columns
+        })
+        .collect::<Vec<_>>();
+    StatisticsColumns::concat(chunks)
+}
 
-    let (min, max) = match all_statistics.iter().minmax_by_key(|s| s.size) {
-        MMR::NoElements => (0, 0),
-        MMR::OneElement(m) => (m.size, m.size),
-        MMR::MinMax(mi, mx) => (mi.size, mx.size),
-    };
+/// Aggregates of a collection its [`CollectionStatistics`] are computed from. The state
+/// is kept between runs, so statistics of appended trees are combined with earlier ones
+/// without parsing the earlier trees again.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatisticsState {
+    pub trees: usize,
+    pub nodes: usize,
+    pub min_tree_size: usize,
+    pub max_tree_size: usize,
+    /// sum of distinct labels of all trees
+    pub distinct_labels: usize,
+    /// occurrences of every label in all nodes and in roots, a root of a label occurring
+    /// once is counted twice towards unique labels like in [`gather_chunked`]
+    pub labels: BTreeMap<String, (usize, usize)>,
+}
+
+impl StatisticsState {
+    /// Adds trees with their statistics, labels are looked up in their label dictionary
+    pub fn add(&mut self, stats: &StatisticsColumns, trees: &[ParsedTree], label_dict: &LabelDict) {
+        if let Some((min, max)) = stats.sizes.iter().copied().minmax().into_option() {
+            if self.trees == 0 {
+                (self.min_tree_size, self.max_tree_size) = (min, max);
+            } else {
+                self.min_tree_size = self.min_tree_size.min(min);
+                self.max_tree_size = self.max_tree_size.max(max);
+            }
+        }
+        self.trees += stats.sizes.len();
+        self.nodes += stats.sizes.iter().sum::<usize>();
+        self.distinct_labels += stats.distinct_labels.iter().sum::<usize>();
 
-    let avg_size = all_statistics.par_iter().map(|s| s.size).sum::<usize>() as f64
-        / all_statistics.len() as f64;
-    let avg_unique_label_per_tree = all_statistics
-        .par_iter()
-        .map(|s| s.collection_unique_labels)
-        .sum::<usize>() as f64
-        / all_statistics.len() as f64;
-
-    let avg_tree_distinct_labels = all_statistics
-        .par_iter()
-        .map(|s| s.distinct_labels)
-        .sum::<usize>() as f64
-        / all_statistics.len() as f64;
-
-    CollectionStatistics {
-        min_tree_size: min,
-        max_tree_size: max,
-        avg_tree_size: avg_size,
-        trees: all_statistics.len(),
-        avg_tree_distinct_labels,
-        avg_unique_label_per_tree,
+        let names = label_names(label_dict);
+        for (name, (_, count)) in label_dict.iter() {
+            self.labels.entry(name.clone()).or_default().0 += count;
+        }
+        for root in trees.iter().filter_map(|t| t.iter().next()) {
+            let name = names[root.get().get() as usize];
+            self.labels.entry(name.to_owned()).or_default().1 += 1;
+        }
+    }
+
+    /// Statistics of all trees added so far, the same as the summary of the statistics of
+    /// all of them
+    pub fn summary(&self) -> CollectionStatistics {
+        let trees = self.trees as f64;
+        let unique_labels = self
+            .labels
+            .values()
+            .filter(|(count, _)| *count == 1)
+            .map(|(_, roots)| 1 + roots)
+            .sum::<usize>();
+        CollectionStatistics {
+            min_tree_size: self.min_tree_size,
+            max_tree_size: self.max_tree_size,
+            avg_tree_size: self.nodes as f64 / trees,
+            trees: self.trees,
+            avg_unique_label_per_tree: unique_labels as f64 / trees,
+            avg_tree_distinct_labels: self.distinct_labels as f64 / trees,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Writes the state as JSON, replacing the previous file
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut w = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        Ok(())
     }
 }
 
@@ -162,37 +321,76 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parsing::LabelId;
     use indextree::Arena;
 
+    fn label(id: u32) -> LabelId {
+        LabelId::new(id).unwrap()
+    }
+
     #[test]
     fn test_simple_statistics() {
         let mut a = Arena::new();
-        let n1 = a.new_node(1);
-        let n2 = a.new_node(2);
-        let n3 = a.new_node(3);
-        let n4 = a.new_node(4);
+        let n1 = a.new_node(label(1));
+        let n2 = a.new_node(label(2));
+        let n3 = a.new_node(label(3));
+        let n4 = a.new_node(label(4));
 
         n1.append(n2, &mut a);
         n2.append(n3, &mut a);
         n3.append(n4, &mut a);
         let ordering = LabelFreqOrdering::new(vec![1, 1, 1, 1]);
-        let stats = gather(&a, &ordering);
+        let stats = gather_chunked(&[a], &ordering, 1);
+
+        assert_eq!(stats.depths_of(0), [3]);
+        assert_eq!(stats.degrees_of(0), [1, 2, 2, 1]);
+        assert_eq!(stats.sizes, [4]);
+    }
 
-        assert_eq!(stats.depths, vec![3]);
-        assert_eq!(stats.degrees, vec![1, 2, 2, 1]);
-        assert_eq!(stats.size, 4);
+    #[test]
+    fn test_appended_statistics() {
+        use crate::parsing::{get_frequency_ordering, parse_single};
+        let parts = [
+            vec!["{a{b}{c}}", "{x{a}}", "{q}"],
+            vec!["{b{c{d}}}", "{y}", "{a{e}{e}{f}}"],
+        ];
+        let parse = |trees: &[&str]| {
+            let mut ld = LabelDict::default();
+            let trees = trees
+                .iter()
+                .map(|t| parse_single((*t).to_owned(), &mut ld))
+                .collect::<Vec<_>>();
+            let ordering = get_frequency_ordering(&ld);
+            let stats = gather_chunked(&trees, &ordering, 1);
+            let columns = gather_chunked(&trees, &ordering, 2);
+            (trees, ld, stats, columns)
+        };
+
+        let mut state = StatisticsState::default();
+        for part in parts.iter() {
+            let (trees, ld, _, columns) = parse(part);
+            state.add(&columns, &trees, &ld);
+        }
+        let (_, ld, stats, columns) = parse(&parts.concat());
+        let expected = stats.summary();
+        assert_eq!(state.summary().to_string(), expected.to_string());
+        assert_eq!(state.labels.len(), ld.len());
+        assert_eq!((expected.min_tree_size, expected.max_tree_size), (1, 4));
+
+        // chunks of any size give the statistics of every tree in order
+        assert_eq!(columns, stats);
     }
 
     #[test]
     fn test_branched_stats() {
         let mut a = Arena::new();
-        let n1 = a.new_node(1);
-        let n2 = a.new_node(2);
-        let n3 = a.new_node(3);
-        let n4 = a.new_node(4);
-        let n5 = a.new_node(3);
-        let n6 = a.new_node(2);
-        let n7 = a.new_node(5);
+        let n1 = a.new_node(label(1));
+        let n2 = a.new_node(label(2));
+        let n3 = a.new_node(label(3));
+        let n4 = a.new_node(label(4));
+        let n5 = a.new_node(label(3));
+        let n6 = a.new_node(label(2));
+        let n7 = a.new_node(label(5));
 
         n1.append(n2, &mut a);
         n2.append(n3, &mut a);
@@ -203,9 +401,9 @@ mod tests {
         n6.append(n7, &mut a);
         let ordering = LabelFreqOrdering::new(vec![1, 2, 2, 1, 1]);
 
-        let stats = gather(&a, &ordering);
+        let stats = gather_chunked(&[a], &ordering, 1);
 
-        assert_eq!(stats.depths, vec![3, 3, 2]);
-        assert_eq!(stats.degrees, vec![2, 2, 3, 1, 1, 2, 1]);
+        assert_eq!(stats.depths_of(0), [3, 3, 2]);
+        assert_eq!(stats.degrees_of(0), [2, 2, 3, 1, 1, 2, 1]);
     }
 }
@@ -0,0 +1,483 @@
+//! Exact tree edit distance by Zhang and Shasha, with unit costs or a [`CostModel`].
+use crate::lb::size_buckets::size_lb;
+use crate::parsing::{LabelId, ParsedTree};
+use indextree::NodeId;
+use std::str::FromStr;
+
+/// Costs of the edit operations of the exact tree edit distance. Every cost is at least 1,
+/// so the distance is never below the unit cost distance and the lower bounds of all
+/// filters stay valid for verification with any cost model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CostModel {
+    pub delete: usize,
+    pub insert: usize,
+    /// cost of renaming a node to a different label, equal labels are mapped for free
+    pub rename: usize,
+}
+
+impl CostModel {
+    pub const UNIT: Self = Self {
+        delete: 1,
+        insert: 1,
+        rename: 1,
+    };
+
+    fn rename(&self, l1: LabelId, l2: LabelId) -> usize {
+        if l1 == l2 {
+            0
+        } else {
+            self.rename
+        }
+    }
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self::UNIT
+    }
+}
+
+impl FromStr for CostModel {
+    type Err = String;
+
+    /// Parses `delete,insert,rename`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let costs = s
+            .split(',')
+            .map(|c| {
+                c.trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|c| *c >= 1)
+                    .ok_or_else(|| format!("Invalid edit cost `{c}`, costs are at least 1"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match costs[..] {
+            [delete, insert, rename] => Ok(Self {
+                delete,
+                insert,
+                rename,
+            }),
+            _ => Err(format!("Expected 3 edit costs, got `{s}`")),
+        }
+    }
+}
+
+/// Postorder view of a tree needed by the Zhang-Shasha algorithm.
+struct PostorderTree {
+    /// nodes in postorder
+    nodes: Vec<NodeId>,
+    /// labels in postorder
+    labels: Vec<LabelId>,
+    /// postorder id of the leftmost leaf descendant of each node
+    leftmost: Vec<usize>,
+    /// key roots in increasing postorder
+    keyroots: Vec<usize>,
+}
+
+impl PostorderTree {
+    fn new(tree: &ParsedTree) -> Self {
+        let mut nodes = Vec::with_capacity(tree.count());
+        let mut leftmost = Vec::with_capacity(tree.count());
+        if let Some(root) = tree.iter().next() {
+            let root_id = tree.get_node_id(root).unwrap();
+            Self::traverse(root_id, tree, &mut nodes, &mut leftmost);
+        }
+        let labels = nodes
+            .iter()
+            .map(|nid| *tree[*nid].get())
+            .collect::<Vec<_>>();
+
+        // key roots are nodes with no other node of greater postorder id sharing the leftmost leaf
+        let mut seen = vec![false; labels.len()];
+        let mut keyroots = vec![];
+        for i in (0..labels.len()).rev() {
+            if !seen[leftmost[i]] {
+                seen[leftmost[i]] = true;
+                keyroots.push(i);
+            }
+        }
+        keyroots.reverse();
+
+        Self {
+            nodes,
+            labels,
+            leftmost,
+            keyroots,
+        }
+    }
+
+    fn size(&self, i: usize) -> usize {
+        i - self.leftmost[i] + 1
+    }
+
+    /// Postorder ids of the children of every node in their order
+    fn children(&self) -> Vec<Vec<usize>> {
+        (0..self.labels.len())
+            .map(|i| {
+                // the last child precedes its parent, siblings precede its leftmost leaf
+                let mut children = vec![];
+                let mut child = i;
+                while child > self.leftmost[i] {
+                    child -= 1;
+                    children.push(child);
+                    child = self.leftmost[child];
+                }
+                children.reverse();
+                children
+            })
+            .collect()
+    }
+
+    fn traverse(
+        nid: NodeId,
+        tree: &ParsedTree,
+        nodes: &mut Vec<NodeId>,
+        leftmost: &mut Vec<usize>,
+    ) -> usize {
+        let mut first_leaf = None;
+        for cnid in nid.children(tree) {
+            let child_leaf = Self::traverse(cnid, tree, nodes, leftmost);
+            first_leaf.get_or_insert(child_leaf);
+        }
+        let id = nodes.len();
+        let first_leaf = first_leaf.unwrap_or(id);
+        nodes.push(nid);
+        leftmost.push(first_leaf);
+        first_leaf
+    }
+}
+
+/// Distances of forests of the subtrees rooted in `i` and `j` by their prefixes in
+/// postorder, also fills distances of subtrees of `i` and `j` with the same leftmost
+/// leaves as `i` and `j`. Other subtree distances have to be in `tree_dist` already.
+fn forest_distances(
+    a: &PostorderTree,
+    b: &PostorderTree,
+    (i, j): (usize, usize),
+    costs: &CostModel,
+    tree_dist: &mut [Vec<usize>],
+    forest_dist: &mut [Vec<usize>],
+) {
+    let (li, lj) = (a.leftmost[i], b.leftmost[j]);
+    // forest_dist is indexed with offset, [0][0] is the empty forest
+    forest_dist[0][0] = 0;
+    for di in 1..=(i - li + 1) {
+        forest_dist[di][0] = forest_dist[di - 1][0] + costs.delete;
+    }
+    for dj in 1..=(j - lj + 1) {
+        forest_dist[0][dj] = forest_dist[0][dj - 1] + costs.insert;
+    }
+
+    for di in 1..=(i - li + 1) {
+        let x = li + di - 1;
+        for dj in 1..=(j - lj + 1) {
+            let y = lj + dj - 1;
+            let delete = forest_dist[di - 1][dj] + costs.delete;
+            let insert = forest_dist[di][dj - 1] + costs.insert;
+            if a.leftmost[x] == li && b.leftmost[y] == lj {
+                let rename = forest_dist[di - 1][dj - 1] + costs.rename(a.labels[x], b.labels[y]);
+                forest_dist[di][dj] = delete.min(insert).min(rename);
+                tree_dist[x][y] = forest_dist[di][dj];
+            } else {
+                let (px, py) = (a.leftmost[x] - li, b.leftmost[y] - lj);
+                let subtree = forest_dist[px][py] + tree_dist[x][y];
+                forest_dist[di][dj] = delete.min(insert).min(subtree);
+            }
+        }
+    }
+}
+
+/// Distances of all pairs of subtrees, computed for key roots in increasing postorder
+fn tree_distances(a: &PostorderTree, b: &PostorderTree, costs: &CostModel) -> Vec<Vec<usize>> {
+    let (n, m) = (a.labels.len(), b.labels.len());
+    let mut tree_dist = vec![vec![0usize; m]; n];
+    let mut forest_dist = vec![vec![0usize; m + 1]; n + 1];
+    for &i in a.keyroots.iter() {
+        for &j in b.keyroots.iter() {
+            forest_distances(a, b, (i, j), costs, &mut tree_dist, &mut forest_dist);
+        }
+    }
+    tree_dist
+}
+
+/// Computes exact tree edit distance between two trees with unit costs
+/// for node insertion, deletion and rename.
+pub fn ted(t1: &ParsedTree, t2: &ParsedTree) -> usize {
+    ted_with_costs(t1, t2, &CostModel::UNIT)
+}
+
+/// Exact tree edit distance with the costs of the cost model
+pub fn ted_with_costs(t1: &ParsedTree, t2: &ParsedTree, costs: &CostModel) -> usize {
+    let (a, b) = (PostorderTree::new(t1), PostorderTree::new(t2));
+    let (n, m) = (a.labels.len(), b.labels.len());
+    if n == 0 || m == 0 {
+        return n * costs.delete + m * costs.insert;
+    }
+    tree_distances(&a, &b, costs)[n - 1][m - 1]
+}
+
+/// Optimal edit mapping of two trees with unit costs. Nodes of the first tree mapped
+/// to `None` are deleted, nodes of the second tree mapped from `None` are inserted and
+/// mapped nodes are renamed when their labels differ. Every node of both trees is in
+/// exactly one pair.
+pub fn edit_mapping(t1: &ParsedTree, t2: &ParsedTree) -> Vec<(Option<NodeId>, Option<NodeId>)> {
+    let (a, b) = (PostorderTree::new(t1), PostorderTree::new(t2));
+    let (n, m) = (a.labels.len(), b.labels.len());
+    if n == 0 || m == 0 {
+        let deleted = a.nodes.iter().rev().map(|x| (Some(*x), None));
+        let inserted = b.nodes.iter().rev().map(|y| (None, Some(*y)));
+        return deleted.chain(inserted).collect();
+    }
+    let costs = CostModel::UNIT;
+    let mut tree_dist = tree_distances(&a, &b, &costs);
+    let mut forest_dist = vec![vec![0usize; m + 1]; n + 1];
+
+    let mut mapping = vec![];
+    let mut subtrees = vec![(n - 1, m - 1)];
+    while let Some((i, j)) = subtrees.pop() {
+        forest_distances(&a, &b, (i, j), &costs, &mut tree_dist, &mut forest_dist);
+        let (li, lj) = (a.leftmost[i], b.leftmost[j]);
+        let (mut di, mut dj) = (i - li + 1, j - lj + 1);
+        while di > 0 || dj > 0 {
+            let (x, y) = ((li + di).wrapping_sub(1), (lj + dj).wrapping_sub(1));
+            if dj == 0 || (di > 0 && forest_dist[di][dj] == forest_dist[di - 1][dj] + costs.delete)
+            {
+                mapping.push((Some(a.nodes[x]), None));
+                di -= 1;
+            } else if di == 0 || forest_dist[di][dj] == forest_dist[di][dj - 1] + costs.insert {
+                mapping.push((None, Some(b.nodes[y])));
+                dj -= 1;
+            } else if a.leftmost[x] == li && b.leftmost[y] == lj {
+                mapping.push((Some(a.nodes[x]), Some(b.nodes[y])));
+                (di, dj) = (di - 1, dj - 1);
+            } else {
+                // the subtrees of x and y are mapped to each other, their mapping
+                // is recovered from their own forest distances
+                subtrees.push((x, y));
+                (di, dj) = (a.leftmost[x] - li, b.leftmost[y] - lj);
+            }
+        }
+    }
+    mapping
+}
+
+/// Tree edit distance bounded by `k`, any distance above `k` is returned as `k + 1`.
+/// Pairs whose sizes differ by more than `k` are answered without running the algorithm.
+pub fn ted_k(t1: &ParsedTree, t2: &ParsedTree, k: usize) -> usize {
+    ted_k_with_costs(t1, t2, k, &CostModel::UNIT)
+}
+
+/// [`ted_k`] with the costs of the cost model
+pub fn ted_k_with_costs(t1: &ParsedTree, t2: &ParsedTree, k: usize, costs: &CostModel) -> usize {
+    // every cost is at least 1, so the size difference bounds any cost model
+    if size_lb(t1.len(), t2.len(), k) > k {
+        return k + 1;
+    }
+    ted_with_costs(t1, t2, costs).min(k + 1)
+}
+
+/// [`ted_k_with_costs`] restricted to the subtree pairs Touzet's algorithm computes. A
+/// mapping of cost at most `k` only maps nodes whose leftmost leaves have postorder ids
+/// within `k` of each other, the nodes left of them are deleted or inserted otherwise.
+/// Key root pairs with leftmost leaves further apart are skipped, their subtree distances
+/// are never used for a distance within `k`. Exact up to `k`, any distance above `k` is
+/// returned as `k + 1`. Each key root is paired with the key roots of at most `2k + 1`
+/// leftmost leaves instead of all of them.
+pub fn touzet_ted_k(t1: &ParsedTree, t2: &ParsedTree, k: usize, costs: &CostModel) -> usize {
+    if size_lb(t1.len(), t2.len(), k) > k {
+        return k + 1;
+    }
+    let (a, b) = (PostorderTree::new(t1), PostorderTree::new(t2));
+    let (n, m) = (a.labels.len(), b.labels.len());
+    if n == 0 || m == 0 {
+        return (n * costs.delete + m * costs.insert).min(k + 1);
+    }
+    // skipped pairs stay above any distance, sums of two of them do not overflow
+    let mut tree_dist = vec![vec![usize::MAX / 4; m]; n];
+    let mut forest_dist = vec![vec![0usize; m + 1]; n + 1];
+    for &i in a.keyroots.iter() {
+        let li = a.leftmost[i];
+        for &j in b.keyroots.iter() {
+            if li.abs_diff(b.leftmost[j]) <= k {
+                forest_distances(&a, &b, (i, j), costs, &mut tree_dist, &mut forest_dist);
+            }
+        }
+    }
+    tree_dist[n - 1][m - 1].min(k + 1)
+}
+
+/// Top-down distance of Selkow, an upper bound of the tree edit distance of the cost
+/// model. Nodes are only mapped if their parents are mapped to each other, children are
+/// aligned like a string where deleting or inserting a child removes or adds its whole
+/// subtree. It takes time of the product of the sizes, without the key root forests of
+/// the exact distance.
+pub fn top_down_ted(t1: &ParsedTree, t2: &ParsedTree, costs: &CostModel) -> usize {
+    let (a, b) = (PostorderTree::new(t1), PostorderTree::new(t2));
+    let (n, m) = (a.labels.len(), b.labels.len());
+    if n == 0 || m == 0 {
+        return n * costs.delete + m * costs.insert;
+    }
+    let (children_a, children_b) = (a.children(), b.children());
+    let mut dist = vec![vec![0usize; m]; n];
+    let (mut prev, mut row) = (vec![], vec![]);
+    for i in 0..n {
+        for j in 0..m {
+            let (ci, cj) = (&children_a[i], &children_b[j]);
+            // alignment of the children, row of inserted prefixes of the children of j
+            prev.clear();
+            prev.push(0);
+            for &y in cj {
+                prev.push(prev.last().unwrap() + b.size(y) * costs.insert);
+            }
+            for &x in ci {
+                row.clear();
+                row.push(prev[0] + a.size(x) * costs.delete);
+                for (dj, &y) in cj.iter().enumerate() {
+                    let delete = prev[dj + 1] + a.size(x) * costs.delete;
+                    let insert = row[dj] + b.size(y) * costs.insert;
+                    let map = prev[dj] + dist[x][y];
+                    row.push(delete.min(insert).min(map));
+                }
+                std::mem::swap(&mut prev, &mut row);
+            }
+            dist[i][j] = costs.rename(a.labels[i], b.labels[j]) + prev[cj.len()];
+        }
+    }
+    dist[n - 1][m - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+    use itertools::Itertools;
+
+    #[test]
+    fn test_ted_identical() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{b}{c{d}}}".to_owned(), &mut ld);
+        let t2 = parse_single("{a{b}{c{d}}}".to_owned(), &mut ld);
+        assert_eq!(ted(&t1, &t2), 0);
+    }
+
+    #[test]
+    fn test_ted_zhang_shasha_example() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{f{d{a}{c{b}}}{e}}".to_owned(), &mut ld);
+        let t2 = parse_single("{f{c{d{a}{b}}}{e}}".to_owned(), &mut ld);
+        assert_eq!(ted(&t1, &t2), 2);
+    }
+
+    #[test]
+    fn test_ted_rename_and_insert() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        let t2 = parse_single("{x{b}{c}{d}}".to_owned(), &mut ld);
+        assert_eq!(ted(&t1, &t2), 2);
+        assert_eq!(ted(&t2, &t1), 2);
+    }
+
+    #[test]
+    fn test_ted_with_costs() {
+        let mut ld = LabelDict::default();
+        let t1 = parse_single("{a{b}{c}}".to_owned(), &mut ld);
+        let t2 = parse_single("{x{b}{c}{d}}".to_owned(), &mut ld);
+        let costs = "1,3,2".parse::<CostModel>().unwrap();
+        // rename a to x and insert d
+        assert_eq!(ted_with_costs(&t1, &t2, &costs), 5);
+        // delete d and rename x to a
+        assert_eq!(ted_with_costs(&t2, &t1, &costs), 3);
+        // renaming costs more than deleting and inserting
+        let costs = "1,1,5".parse::<CostModel>().unwrap();
+        assert_eq!(ted_with_costs(&t1, &t2, &costs), 3);
+        assert_eq!(ted_k_with_costs(&t1, &t2, 2, &costs), 3);
+        assert_eq!(ted_with_costs(&t1, &t2, &CostModel::UNIT), ted(&t1, &t2));
+        assert!("1,0,1".parse::<CostModel>().is_err());
+        assert!("1,1".parse::<CostModel>().is_err());
+    }
+
+    #[test]
+    fn test_edit_mapping_cost() {
+        let mut ld = LabelDict::default();
+        let pairs = [
+            ("{f{d{a}{c{b}}}{e}}", "{f{c{d{a}{b}}}{e}}"),
+            ("{a{b}{c}}", "{x{b}{c}{d}}"),
+            ("{a{b{c}{d}}{e}}", "{e{d}{a{b}}}"),
+            ("{a}", "{b{c}{d{e}}}"),
+        ];
+        for (t1, t2) in pairs {
+            let t1 = parse_single(t1.to_owned(), &mut ld);
+            let t2 = parse_single(t2.to_owned(), &mut ld);
+            let mapping = edit_mapping(&t1, &t2);
+            let cost = mapping
+                .iter()
+                .filter(|(x, y)| match (x, y) {
+                    (Some(x), Some(y)) => t1[*x].get() != t2[*y].get(),
+                    _ => true,
+                })
+                .count();
+            assert_eq!(cost, ted(&t1, &t2));
+            let mapped = |side: Vec<Option<NodeId>>| side.into_iter().flatten().unique().count();
+            assert_eq!(mapped(mapping.iter().map(|p| p.0).collect()), t1.count());
+            assert_eq!(mapped(mapping.iter().map(|p| p.1).collect()), t2.count());
+        }
+    }
+
+    #[test]
+    fn test_touzet_ted_k() {
+        let mut ld = LabelDict::default();
+        let mut parse = |t: &str| parse_single(t.to_owned(), &mut ld);
+        let trees = [
+            "{f{d{a}{c{b}}}{e}}",
+            "{f{c{d{a}{b}}}{e}}",
+            "{a{b}{c}}",
+            "{x{b}{c}{d}}",
+            "{a{b{c}{d}}{e}}",
+            "{e{d}{a{b}}}",
+            "{a{b{c{d}}}{e{f}}}",
+            "{a{e{f}}{b{d}}}",
+            "{b{c}{d{e}}}",
+        ]
+        .map(&mut parse);
+        let costs = "1,2,3".parse::<CostModel>().unwrap();
+        for (t1, t2) in trees.iter().cartesian_product(&trees) {
+            for k in 0..8 {
+                assert_eq!(touzet_ted_k(t1, t2, k, &CostModel::UNIT), ted_k(t1, t2, k));
+                assert_eq!(
+                    touzet_ted_k(t1, t2, k, &costs),
+                    ted_k_with_costs(t1, t2, k, &costs)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_top_down_ted() {
+        let mut ld = LabelDict::default();
+        let mut parse = |t: &str| parse_single(t.to_owned(), &mut ld);
+        // deleting b keeps c below a, the top-down distance renames b to c instead
+        let (t1, t2) = (parse("{a{b{c}}}"), parse("{a{c}}"));
+        assert_eq!(ted(&t1, &t2), 1);
+        assert_eq!(top_down_ted(&t1, &t2, &CostModel::UNIT), 2);
+
+        let (t1, t2) = (parse("{a{b}{c}}"), parse("{x{b}{c}{d}}"));
+        assert_eq!(top_down_ted(&t1, &t2, &CostModel::UNIT), 2);
+        let costs = "1,3,2".parse::<CostModel>().unwrap();
+        assert_eq!(top_down_ted(&t1, &t2, &costs), 5);
+        assert_eq!(top_down_ted(&t1, &ParsedTree::new(), &costs), 3);
+
+        let pairs = [
+            ("{f{d{a}{c{b}}}{e}}", "{f{c{d{a}{b}}}{e}}"),
+            ("{a{b{c}{d}}{e}}", "{e{d}{a{b}}}"),
+            ("{a}", "{b{c}{d{e}}}"),
+            ("{a{b{c{d}}}{e{f}}}", "{a{e{f}}{b{d}}}"),
+        ];
+        for (t1, t2) in pairs {
+            let (t1, t2) = (parse(t1), parse(t2));
+            let upper = top_down_ted(&t1, &t2, &CostModel::UNIT);
+            assert!(upper >= ted(&t1, &t2));
+            assert_eq!(upper, top_down_ted(&t2, &t1, &CostModel::UNIT));
+        }
+    }
+}
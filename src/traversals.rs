@@ -0,0 +1,214 @@
+//! Label sequences of trees written by the `traversals` command.
+//!
+//! Every tree is written as one line per chosen traversal. Nodes are written as label
+//! ids or original label strings, optionally followed by the numbers of nodes to the
+//! left, ancestors, nodes to the right and descendants of the node, the regions the
+//! structural filter compares, e.g. `3:0:1:2:0` with the default `:` separated form.
+//!
+//! The traversals work on arenas of any node payload, e.g. trees with string labels,
+//! the first node of the arena is the root as in trees built by the parser.
+use crate::parsing::{LabelId, ParsedTree};
+use clap::ValueEnum;
+use indextree::{Arena, NodeEdge, NodeId};
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Traversal {
+    Preorder,
+    Postorder,
+    /// Preorder read backwards
+    ReversedPreorder,
+    /// Postorder read backwards
+    ReversedPostorder,
+    /// Every node when it is entered and when it is left
+    Euler,
+}
+
+impl Traversal {
+    pub const DEFAULT: [Traversal; 2] = [Traversal::Preorder, Traversal::Postorder];
+}
+
+#[derive(Debug, Clone)]
+pub struct TraversalOptions<'a> {
+    pub traversals: Vec<Traversal>,
+    pub separator: String,
+    /// original label strings indexed by label id, label ids are written without them
+    pub label_names: Option<Vec<&'a str>>,
+    /// appends region counts of the structural filter to every node
+    pub structure: bool,
+}
+
+/// Nodes of a tree in every order of [`Traversal`] with their structural regions
+struct NodeOrders {
+    preorder: Vec<NodeId>,
+    postorder: Vec<NodeId>,
+    euler: Vec<NodeId>,
+    /// left, ancestors, right and descendants of every node, only if asked for
+    regions: FxHashMap<NodeId, [usize; 4]>,
+}
+
+impl NodeOrders {
+    fn new<T>(tree: &Arena<T>, with_regions: bool) -> Option<Self> {
+        let root = tree.iter().next()?;
+        let root_id = tree.get_node_id(root)?;
+        let (mut preorder, mut postorder, mut euler) = (vec![], vec![], vec![]);
+        // preorder positions of nodes on the path from the root
+        let mut path = vec![];
+        let mut regions = FxHashMap::default();
+        for edge in root_id.traverse(tree) {
+            match edge {
+                NodeEdge::Start(nid) => {
+                    path.push(preorder.len());
+                    preorder.push(nid);
+                    euler.push(nid);
+                }
+                NodeEdge::End(nid) => {
+                    let pre = path.pop().unwrap();
+                    postorder.push(nid);
+                    euler.push(nid);
+                    if with_regions {
+                        // nodes before in preorder are ancestors or to the left, nodes after
+                        // are descendants or to the right
+                        let ancestors = path.len();
+                        let descendants = preorder.len() - pre - 1;
+                        let left = pre - ancestors;
+                        let right = tree.len() - 1 - left - ancestors - descendants;
+                        regions.insert(nid, [left, ancestors, right, descendants]);
+                    }
+                }
+            }
+        }
+        Some(Self {
+            preorder,
+            postorder,
+            euler,
+            regions,
+        })
+    }
+
+    fn nodes(&self, traversal: Traversal) -> Box<dyn Iterator<Item = &NodeId> + '_> {
+        match traversal {
+            Traversal::Preorder => Box::new(self.preorder.iter()),
+            Traversal::Postorder => Box::new(self.postorder.iter()),
+            Traversal::ReversedPreorder => Box::new(self.preorder.iter().rev()),
+            Traversal::ReversedPostorder => Box::new(self.postorder.iter().rev()),
+            Traversal::Euler => Box::new(self.euler.iter()),
+        }
+    }
+}
+
+/// Lines of the chosen traversals of the tree, nodes are written by `label`.
+/// [`TraversalOptions::label_names`] is not used.
+pub fn format_tree_with<T>(
+    tree: &Arena<T>,
+    options: &TraversalOptions,
+    label: impl Fn(&T) -> String,
+) -> Vec<String> {
+    let Some(orders) = NodeOrders::new(tree, options.structure) else {
+        return vec![];
+    };
+    let node = |nid: &NodeId| {
+        let mut item = label(tree[*nid].get());
+        if options.structure {
+            for count in orders.regions[nid] {
+                item.push(':');
+                item.push_str(&count.to_string());
+            }
+        }
+        item
+    };
+    options
+        .traversals
+        .iter()
+        .map(|traversal| {
+            orders
+                .nodes(*traversal)
+                .map(node)
+                .collect::<Vec<_>>()
+                .join(&options.separator)
+        })
+        .collect()
+}
+
+/// Lines of the chosen traversals of the tree
+pub fn format_tree(tree: &ParsedTree, options: &TraversalOptions) -> Vec<String> {
+    format_tree_with(tree, options, |label: &LabelId| {
+        match &options.label_names {
+            Some(names) => names[label.get() as usize].to_owned(),
+            None => label.to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{label_names, parse_single, LabelDict};
+
+    #[test]
+    fn test_traversal_lines() {
+        let mut ld = LabelDict::default();
+        let tree = parse_single("{a{b{c}}{d}}".to_owned(), &mut ld);
+        let names = label_names(&ld);
+        let mut options = TraversalOptions {
+            traversals: vec![
+                Traversal::Preorder,
+                Traversal::ReversedPostorder,
+                Traversal::Euler,
+            ],
+            separator: " ".to_owned(),
+            label_names: Some(names),
+            structure: false,
+        };
+        assert_eq!(
+            format_tree(&tree, &options),
+            vec!["a b c d", "a d b c", "a b c c b d d a"]
+        );
+
+        options.traversals = vec![Traversal::Preorder];
+        options.structure = true;
+        assert_eq!(
+            format_tree(&tree, &options),
+            vec!["a:0:0:0:3 b:0:1:1:1 c:0:2:1:0 d:2:1:0:0"]
+        );
+        options.label_names = None;
+        options.structure = false;
+        options.separator = ";".to_owned();
+        assert_eq!(format_tree(&tree, &options), vec!["1;2;3;4"]);
+    }
+
+    #[test]
+    fn test_string_labels() {
+        let mut tree = Arena::new();
+        let root = tree.new_node("a".to_owned());
+        let b = tree.new_node("b".to_owned());
+        root.append(b, &mut tree);
+        b.append(tree.new_node("c".to_owned()), &mut tree);
+        root.append(tree.new_node("d".to_owned()), &mut tree);
+
+        let mut options = TraversalOptions {
+            traversals: vec![Traversal::ReversedPreorder],
+            separator: " ".to_owned(),
+            label_names: None,
+            structure: true,
+        };
+        assert_eq!(
+            format_tree_with(&tree, &options, String::clone),
+            vec!["d:2:1:0:0 c:0:2:1:0 b:0:1:1:1 a:0:0:0:3"]
+        );
+
+        let mut ld = LabelDict::default();
+        let parsed = parse_single("{a{b{c}}{d}}".to_owned(), &mut ld);
+        let names = label_names(&ld);
+        options.traversals = vec![Traversal::Postorder];
+        options.structure = false;
+        let parsed_lines = format_tree_with(&parsed, &options, |label: &LabelId| {
+            names[label.get() as usize].to_owned()
+        });
+        assert_eq!(parsed_lines, ["c b d a"]);
+        assert_eq!(
+            format_tree_with(&tree, &options, String::clone),
+            parsed_lines
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Equality, ordering and hashing of trees by their structure.
+//!
+//! Arenas compare by their node layout, two parses of the same tree into arenas filled in
+//! a different order are not equal, and a subtree is not a tree of its own. The functions
+//! here compare trees by the labels of their nodes in preorder and the nesting of the
+//! nodes, like the bracket notation of the trees, wherever the nodes are stored. They work
+//! on arenas of any node payload, the first node of the arena is the root as in trees built
+//! by the parser. [`StructuralTree`] wraps an arena with these comparisons, e.g. as a key of
+//! a map of distinct trees.
+//!
+//! ```
+//! use indextree::Arena;
+//! use tree_statistics::tree_cmp::{structural_eq, subtree, StructuralTree};
+//!
+//! // {a{b{c}}} built top down and bottom up
+//! let mut top_down = Arena::new();
+//! let a = top_down.new_node("a");
+//! let b = top_down.new_node("b");
+//! a.append(b, &mut top_down);
+//! b.append(top_down.new_node("c"), &mut top_down);
+//! let mut bottom_up = Arena::new();
+//! let c = bottom_up.new_node("c");
+//! let b2 = bottom_up.new_node("b");
+//! b2.append(c, &mut bottom_up);
+//! let a2 = bottom_up.new_node("a");
+//! a2.append(b2, &mut bottom_up);
+//! assert!(top_down != bottom_up);
+//! // the root of bottom_up is not its first node, its subtree is the whole tree
+//! let bottom_up = subtree(&bottom_up, a2);
+//! assert!(structural_eq(&top_down, &bottom_up));
+//! assert_eq!(StructuralTree(subtree(&top_down, b)).len(), 2);
+//! ```
+use indextree::{Arena, NodeEdge, NodeId};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+/// A node entered with its payload or left, the bracket notation of a tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Bracket<T> {
+    /// left before any sibling is entered, so trees with fewer children come first
+    Close,
+    Open(T),
+}
+
+fn root<T>(tree: &Arena<T>) -> Option<NodeId> {
+    tree.iter().next().and_then(|node| tree.get_node_id(node))
+}
+
+fn brackets_of<T>(tree: &Arena<T>, node: Option<NodeId>) -> impl Iterator<Item = Bracket<&T>> {
+    node.into_iter()
+        .flat_map(move |node| node.traverse(tree))
+        .map(move |edge| match edge {
+            NodeEdge::Start(nid) => Bracket::Open(tree[nid].get()),
+            NodeEdge::End(_) => Bracket::Close,
+        })
+}
+
+fn brackets<T>(tree: &Arena<T>) -> impl Iterator<Item = Bracket<&T>> {
+    brackets_of(tree, root(tree))
+}
+
+/// Whether both trees have the same labels in the same shape, empty trees are equal
+pub fn structural_eq<T: PartialEq>(t1: &Arena<T>, t2: &Arena<T>) -> bool {
+    brackets(t1).eq(brackets(t2))
+}
+
+/// Orders trees by their labels in preorder, a node with fewer children comes before a
+/// node with the same first children and more of them. The empty tree comes first.
+pub fn structural_cmp<T: Ord>(t1: &Arena<T>, t2: &Arena<T>) -> Ordering {
+    brackets(t1).cmp(brackets(t2))
+}
+
+/// Hashes the labels and shape of the tree, consistent with [`structural_eq`]
+pub fn structural_hash<T: Hash, H: Hasher>(tree: &Arena<T>, state: &mut H) {
+    for bracket in brackets(tree) {
+        bracket.hash(state);
+    }
+}
+
+/// Whether the subtree of `node` in `tree` is structurally equal to `other`
+pub fn subtree_eq<T: PartialEq>(tree: &Arena<T>, node: NodeId, other: &Arena<T>) -> bool {
+    brackets_of(tree, Some(node)).eq(brackets(other))
+}
+
+/// Copy of the subtree of `node` as a tree of its own with the nodes in preorder, `node`
+/// becomes the root
+pub fn subtree<T: Clone>(tree: &Arena<T>, node: NodeId) -> Arena<T> {
+    let mut copy = Arena::with_capacity(node.descendants(tree).count());
+    // copied nodes on the path from the root
+    let mut path: Vec<NodeId> = vec![];
+    for edge in node.traverse(tree) {
+        match edge {
+            NodeEdge::Start(nid) => {
+                let copied = copy.new_node(tree[nid].get().clone());
+                if let Some(parent) = path.last() {
+                    parent.append(copied, &mut copy);
+                }
+                path.push(copied);
+            }
+            NodeEdge::End(_) => {
+                path.pop();
+            }
+        }
+    }
+    copy
+}
+
+/// Subtrees of all nodes of the tree in preorder, the whole tree comes first
+pub fn subtrees<T: Clone>(tree: &Arena<T>) -> Vec<Arena<T>> {
+    root(tree).map_or_else(Vec::new, |root| {
+        root.descendants(tree)
+            .map(|node| subtree(tree, node))
+            .collect()
+    })
+}
+
+/// A tree that is equal to, ordered and hashed with other trees by its structure
+#[derive(Debug, Clone, Default)]
+pub struct StructuralTree<T>(pub Arena<T>);
+
+impl<T> StructuralTree<T> {
+    /// Number of nodes of the tree
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_inner(self) -> Arena<T> {
+        self.0
+    }
+}
+
+impl<T> From<Arena<T>> for StructuralTree<T> {
+    fn from(tree: Arena<T>) -> Self {
+        Self(tree)
+    }
+}
+
+impl<T> AsRef<Arena<T>> for StructuralTree<T> {
+    fn as_ref(&self) -> &Arena<T> {
+        &self.0
+    }
+}
+
+impl<T: PartialEq> PartialEq for StructuralTree<T> {
+    fn eq(&self, other: &Self) -> bool {
+        structural_eq(&self.0, &other.0)
+    }
+}
+
+impl<T: Eq> Eq for StructuralTree<T> {}
+
+impl<T: Hash> Hash for StructuralTree<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        structural_hash(&self.0, state)
+    }
+}
+
+impl<T: Ord> PartialOrd for StructuralTree<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> Ord for StructuralTree<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        structural_cmp(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict, LabelId};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_structural_comparisons() {
+        let mut ld = LabelDict::default();
+        let mut parse = |t: &str| parse_single(t.to_owned(), &mut ld);
+        let tree = parse("{a{b{c}}{d}{b{c}}}");
+        let nodes = root(&tree).unwrap().descendants(&tree).collect::<Vec<_>>();
+        // both {b{c}} subtrees are equal wherever they are in the arena
+        let (first, second) = (subtree(&tree, nodes[1]), subtree(&tree, nodes[4]));
+        assert!(structural_eq(&first, &second));
+        assert!(structural_eq(&first, &parse("{b{c}}")));
+        assert!(subtree_eq(&tree, nodes[4], &first));
+        assert!(!subtree_eq(&tree, nodes[3], &first));
+        assert!(structural_eq(&subtree(&tree, nodes[0]), &tree));
+        assert_eq!(subtrees(&tree).len(), 6);
+        assert!(structural_eq(&subtrees(&tree)[3], &parse("{d}")));
+
+        // same labels in preorder, different shapes
+        let (chain, siblings) = (parse("{a{b{c}}}"), parse("{a{b}{c}}"));
+        assert!(!structural_eq(&chain, &siblings));
+        assert_eq!(structural_cmp(&siblings, &chain), Ordering::Less);
+        assert_eq!(structural_cmp(&parse("{a{b}}"), &siblings), Ordering::Less);
+        assert_eq!(
+            structural_cmp(&Arena::<LabelId>::new(), &chain),
+            Ordering::Less
+        );
+
+        let distinct = [&first, &second, &chain, &siblings, &chain]
+            .map(|t| StructuralTree(t.clone()))
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), 3);
+        let mut sorted = distinct.into_iter().collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(sorted[0], StructuralTree(siblings));
+        assert_eq!(sorted[2].len(), 2);
+    }
+}
@@ -0,0 +1,239 @@
+//! Side by side HTML rendering of tree pairs with the edits of their optimal mapping.
+//!
+//! Both trees of a pair are drawn as nested lists, deleted nodes of the first tree are
+//! red, inserted nodes of the second tree green and renamed nodes of both orange with
+//! the other label in their tooltip. The page is self contained, styles are inline.
+//! Pairs are also written as Graphviz graphs with the same colors.
+use crate::parsing::{label_names, tree_to_graphviz, GraphvizStyle, LabelDict, ParsedTree};
+use crate::ted;
+use indextree::{NodeEdge, NodeId};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::fmt::Write;
+
+const STYLE: &str = "body { font-family: sans-serif; }
+.pair { border-top: 1px solid #ccc; padding: 0.5em 0; }
+.trees { display: flex; gap: 3em; }
+.trees ul { list-style: none; padding-left: 1.2em; margin: 0; border-left: 1px dotted #999; }
+.trees > div > ul { border-left: none; padding-left: 0; }
+.node { padding: 0 0.3em; border-radius: 3px; font-family: monospace; }
+.deleted { background: #f5b7b1; }
+.inserted { background: #abebc6; }
+.renamed { background: #fad7a0; }";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Kept,
+    Deleted,
+    Inserted,
+    /// renamed from or to the label of the mapped node
+    Renamed(NodeId),
+}
+
+fn escape(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Nested list of the tree nodes colored by their edits, `other` is the mapped tree
+fn tree_list(tree: &ParsedTree, other: &ParsedTree, edits: &Edits, names: &[&str]) -> String {
+    let mut html = String::with_capacity(tree.count() * 48);
+    let Some(root) = tree.iter().next() else {
+        return html;
+    };
+    let root_id = tree.get_node_id(root).unwrap();
+    let name = |nid: NodeId, tree: &ParsedTree| escape(names[tree[nid].get().get() as usize]);
+    html.push_str("<ul>");
+    for edge in root_id.traverse(tree) {
+        match edge {
+            NodeEdge::Start(nid) => {
+                let (class, title) = match edits[&nid] {
+                    Edit::Kept => ("node", String::new()),
+                    Edit::Deleted => ("node deleted", String::new()),
+                    Edit::Inserted => ("node inserted", String::new()),
+                    Edit::Renamed(mapped) => (
+                        "node renamed",
+                        format!(" title=\"{}\"", name(mapped, other)),
+                    ),
+                };
+                let _ = write!(
+                    html,
+                    "<li><span class=\"{class}\"{title}>{}</span>",
+                    name(nid, tree)
+                );
+                if nid.children(tree).next().is_some() {
+                    html.push_str("<ul>");
+                }
+            }
+            NodeEdge::End(nid) => {
+                if nid.children(tree).next().is_some() {
+                    html.push_str("</ul>");
+                }
+                html.push_str("</li>");
+            }
+        }
+    }
+    html.push_str("</ul>");
+    html
+}
+
+type Edits = FxHashMap<NodeId, Edit>;
+
+/// Edits of nodes of both trees by their optimal mapping
+fn pair_edits(t1: &ParsedTree, t2: &ParsedTree) -> (Edits, Edits) {
+    let mut left = FxHashMap::default();
+    let mut right = FxHashMap::default();
+    for pair in ted::edit_mapping(t1, t2) {
+        match pair {
+            (Some(x), Some(y)) if t1[x].get() == t2[y].get() => {
+                left.insert(x, Edit::Kept);
+                right.insert(y, Edit::Kept);
+            }
+            (Some(x), Some(y)) => {
+                left.insert(x, Edit::Renamed(y));
+                right.insert(y, Edit::Renamed(x));
+            }
+            (Some(x), None) => {
+                left.insert(x, Edit::Deleted);
+            }
+            (None, Some(y)) => {
+                right.insert(y, Edit::Inserted);
+            }
+            (None, None) => unreachable!("mapping pairs have at least one node"),
+        }
+    }
+    (left, right)
+}
+
+/// Section with both trees of a pair and counts of their edits
+fn pair_section(
+    (id1, t1): (usize, &ParsedTree),
+    (id2, t2): (usize, &ParsedTree),
+    names: &[&str],
+) -> String {
+    let (left, right) = pair_edits(t1, t2);
+    let count = |edits: &Edits, f: fn(&Edit) -> bool| edits.values().filter(|e| f(e)).count();
+    let deleted = count(&left, |e| *e == Edit::Deleted);
+    let inserted = count(&right, |e| *e == Edit::Inserted);
+    let renamed = count(&left, |e| matches!(e, Edit::Renamed(_)));
+    format!(
+        "<div class=\"pair\"><h3>Trees {id1} and {id2}: distance {}</h3>\
+         <p>{deleted} deleted, {inserted} inserted, {renamed} renamed</p>\
+         <div class=\"trees\"><div><h4>{id1} ({} nodes)</h4>{}</div>\
+         <div><h4>{id2} ({} nodes)</h4>{}</div></div></div>\n",
+        deleted + inserted + renamed,
+        t1.count(),
+        tree_list(t1, t2, &left, names),
+        t2.count(),
+        tree_list(t2, t1, &right, names),
+    )
+}
+
+/// Page with the edit mappings of pairs of trees given by their ids
+pub fn diff_page(
+    title: &str,
+    pairs: &[(usize, usize)],
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+) -> String {
+    let names = label_names(label_dict);
+    let sections = pairs
+        .par_iter()
+        .map(|(i, j)| pair_section((*i, &trees[*i]), (*j, &trees[*j]), &names))
+        .collect::<Vec<_>>();
+    let title = escape(title);
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>\n{STYLE}\n</style></head>\n<body><h1>{title}</h1>\
+         <p><span class=\"node deleted\">deleted</span> <span class=\"node inserted\">inserted</span> \
+         <span class=\"node renamed\">renamed</span></p>\n{}</body></html>\n",
+        sections.concat()
+    )
+}
+
+/// Graphviz graph of a pair with both trees in clusters and edited nodes filled
+fn pair_graphviz(
+    (id1, t1): (usize, &ParsedTree),
+    (id2, t2): (usize, &ParsedTree),
+    names: &[&str],
+) -> String {
+    let (left, right) = pair_edits(t1, t2);
+    let cluster = |id: usize, tree: &ParsedTree, edits: &Edits, prefix: &str| {
+        let root = tree.iter().next().and_then(|n| tree.get_node_id(n));
+        let node_styles = root
+            .into_iter()
+            .flat_map(|root| root.descendants(tree))
+            .enumerate()
+            .filter_map(|(position, nid)| {
+                let color = match edits[&nid] {
+                    Edit::Kept => return None,
+                    Edit::Deleted => "#f5b7b1",
+                    Edit::Inserted => "#abebc6",
+                    Edit::Renamed(_) => "#fad7a0",
+                };
+                Some((position, format!("style=filled, fillcolor=\"{color}\"")))
+            })
+            .collect();
+        let style = GraphvizStyle {
+            id_prefix: prefix,
+            cluster: true,
+            graph_attributes: vec![format!("label=\"{id}\"")],
+            node_attributes: vec!["shape=box".to_owned(), "fontname=monospace".to_owned()],
+            node_styles,
+        };
+        tree_to_graphviz(tree, names, &style)
+    };
+    format!(
+        "digraph \"{id1}-{id2}\" {{\n{}{}}}\n",
+        cluster(id1, t1, &left, "a"),
+        cluster(id2, t2, &right, "b")
+    )
+}
+
+/// Graphviz graphs of the edit mappings of pairs of trees, one graph per pair
+pub fn diff_graphviz(
+    pairs: &[(usize, usize)],
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+) -> String {
+    let names = label_names(label_dict);
+    pairs
+        .par_iter()
+        .map(|(i, j)| pair_graphviz((*i, &trees[*i]), (*j, &trees[*j]), &names))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_diff_page_colors_edits() {
+        let mut ld = LabelDict::default();
+        let trees = ["{a{b}{c}}", "{x{b}{c}{<d>}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        let page = diff_page("Not found", &[(0, 1)], &trees, &ld);
+        assert!(page.contains("Trees 0 and 1: distance 2"));
+        assert!(page.contains("0 deleted, 1 inserted, 1 renamed"));
+        assert!(page.contains("<span class=\"node renamed\" title=\"x\">a</span>"));
+        assert!(page.contains("<span class=\"node inserted\">&lt;d&gt;</span>"));
+        assert_eq!(page.matches("<li>").count(), 7);
+
+        let graphviz = diff_graphviz(&[(0, 1)], &trees, &ld);
+        assert!(graphviz.contains("an0 [label=\"a\", style=filled, fillcolor=\"#fad7a0\"];"));
+        assert!(graphviz.contains("bn3 [label=\"<d>\", style=filled, fillcolor=\"#abebc6\"];"));
+        assert!(graphviz.contains("bn0 -> bn3;"));
+    }
+}
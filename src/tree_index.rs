@@ -0,0 +1,81 @@
+//! Per tree indexes of a single lower bound method over a collection sorted by size,
+//! shared by the library APIs.
+use crate::indexing::{IndexError, Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::label_intersection_k;
+use crate::lb::sed::sed_k;
+use crate::lb::size_buckets::SizeBuckets;
+use crate::lb::structural_filter::{ted as struct_ted_k, StructuralFilterTuple};
+use crate::parsing::{LabelDict, ParsedTree};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    /// Label intersection lower bound
+    Lblint,
+    /// String edit distance lower bound
+    Sed,
+    /// Structural filter lower bound
+    Structural,
+}
+
+enum Indexes {
+    Lblint(Vec<InvertedListLabelPostorderIndex>),
+    Sed(Vec<SEDIndex>),
+    Structural(Vec<StructuralFilterTuple>),
+}
+
+pub struct TreeIndex {
+    indexes: Indexes,
+    size_buckets: SizeBuckets,
+}
+
+impl TreeIndex {
+    /// Indexes trees sorted by size
+    pub fn new(trees: &[ParsedTree], ld: &LabelDict, method: Method) -> Result<Self, IndexError> {
+        let indexes = match method {
+            Method::Lblint => Indexes::Lblint(InvertedListLabelPostorderIndex::index_collection(
+                trees, ld,
+            )?),
+            Method::Sed => Indexes::Sed(SEDIndex::index_collection(trees, ld)?),
+            Method::Structural => {
+                Indexes::Structural(StructuralFilterTuple::index_collection(trees, ld)?)
+            }
+        };
+        Ok(Self {
+            indexes,
+            size_buckets: SizeBuckets::from_sizes(trees.iter().map(|t| t.count())),
+        })
+    }
+
+    /// Positions of trees whose lower bound to the query is at most `k`, in increasing
+    /// order. Labels of the query have to be in `ld`.
+    pub fn candidates(
+        &self,
+        query: &ParsedTree,
+        ld: &LabelDict,
+        k: usize,
+    ) -> Result<Vec<usize>, IndexError> {
+        let size_range = self.size_buckets.within(query.count(), k);
+        let candidates = match &self.indexes {
+            Indexes::Lblint(trees) => {
+                let q = InvertedListLabelPostorderIndex::index_tree(query, ld)?;
+                size_range
+                    .filter(|tid| label_intersection_k(&q, &trees[*tid], k) <= k)
+                    .collect()
+            }
+            Indexes::Sed(trees) => {
+                let q = SEDIndex::index_tree(query, ld)?;
+                size_range
+                    .filter(|tid| sed_k(&q, &trees[*tid], k) <= k)
+                    .collect()
+            }
+            Indexes::Structural(trees) => {
+                // same as the command line, structural filter visits trees of all sizes
+                let q = StructuralFilterTuple::index_tree(query, ld)?;
+                (0..trees.len())
+                    .filter(|tid| struct_ted_k(&q, &trees[*tid], k) <= k)
+                    .collect()
+            }
+        };
+        Ok(candidates)
+    }
+}
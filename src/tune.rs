@@ -0,0 +1,496 @@
+//! Cross-validated tuning of filter parameters.
+//!
+//! Queries are shuffled with a seed and dealt into folds. Every configuration, a method or
+//! a cascade of two methods with its parameters, runs once on the queries of every fold
+//! after an unmeasured warm-up run that builds its indexes. For every held out fold, the
+//! scores of the other folds are summed and the fastest configuration whose recall against
+//! the ground truth reaches the target is selected, the one with the highest recall when
+//! none does. The selected configuration is reported with its time and recall on the held
+//! out fold, so a configuration that reached the target only on lucky queries shows up as
+//! a drop in recall there.
+//!
+//! Swept parameters are the q-gram size of the q-gram count bound, the label groups of
+//! the split structural filter, the labels probed beyond `k + 1` by the prefix filter of
+//! the structural index, and the order of methods in cascades.
+use crate::bench;
+use crate::collection::CollectionView;
+use crate::lb::auto::{self, Plan, REFINABLE};
+use crate::lb::preprocess::Preprocessed;
+use crate::lb::structural_filter::{PrefixFilterOptions, StructuralFilterIndex};
+use crate::lb::RunConfig;
+use crate::number_format::FloatFormat;
+use crate::parsing::{get_frequency_ordering, LabelDict, ParsedTree};
+use crate::LowerBoundMethods;
+use itertools::Itertools;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::ops::Add;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Plan(Plan),
+    /// Index of the structural filter probing `k + 1` and this many more label groups
+    StructuralPrefix(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub filter: Filter,
+    /// Q-gram size of the q-gram count bound, `None` when no method uses it
+    pub q: Option<usize>,
+    /// Label groups of the split structural filter, `None` when no method uses it
+    pub split_groups: Option<usize>,
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.filter {
+            Filter::Plan(plan) => write!(f, "{plan}")?,
+            Filter::StructuralPrefix(extra) => write!(f, "Structural index prefix=k+1+{extra}")?,
+        }
+        if let Some(q) = self.q {
+            write!(f, " q={q}")?;
+        }
+        if let Some(groups) = self.split_groups {
+            write!(f, " groups={groups}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Values of every swept parameter
+#[derive(Debug, Clone)]
+pub struct Grid {
+    pub methods: Vec<LowerBoundMethods>,
+    pub q_values: Vec<usize>,
+    pub split_groups: Vec<usize>,
+    pub prefix_extra_labels: Vec<usize>,
+}
+
+impl Grid {
+    /// Every method alone and in cascades with a refinable method in both orders, with
+    /// all values of the parameters of its methods
+    pub fn configs(&self) -> Vec<Config> {
+        let singles = self.methods.iter().map(|m| (Plan::Single(*m), vec![*m]));
+        let cascades = self
+            .methods
+            .iter()
+            .cartesian_product(self.methods.iter().filter(|m| REFINABLE.contains(m)))
+            .filter(|(first, second)| first != second)
+            .map(|(first, second)| (Plan::Cascade(*first, *second), vec![*first, *second]));
+        let mut configs = vec![];
+        for (plan, methods) in singles.chain(cascades) {
+            let values = |method, values: &[usize]| {
+                if methods.contains(&method) {
+                    values.iter().copied().map(Some).collect_vec()
+                } else {
+                    vec![None]
+                }
+            };
+            let q_values = values(LowerBoundMethods::SedQgram, &self.q_values);
+            let split_groups = values(LowerBoundMethods::StructuralSplit, &self.split_groups);
+            for (q, split_groups) in q_values.into_iter().cartesian_product(split_groups) {
+                configs.push(Config {
+                    filter: Filter::Plan(plan),
+                    q,
+                    split_groups,
+                });
+            }
+        }
+        if self.methods.contains(&LowerBoundMethods::Structural) {
+            configs.extend(self.prefix_extra_labels.iter().map(|extra| Config {
+                filter: Filter::StructuralPrefix(*extra),
+                q: None,
+                split_groups: None,
+            }));
+        }
+        configs
+    }
+}
+
+/// Query ids shuffled with the seed and dealt into `folds` folds, sorted in every fold
+pub fn split_folds(queries: usize, folds: usize, seed: u64) -> Vec<Vec<usize>> {
+    let mut ids = (0..queries).collect_vec();
+    ids.shuffle(&mut Xoshiro256PlusPlus::seed_from_u64(seed));
+    let mut split = vec![vec![]; folds];
+    for (i, id) in ids.into_iter().enumerate() {
+        split[i % folds].push(id);
+    }
+    for fold in &mut split {
+        fold.sort_unstable();
+    }
+    split
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Score {
+    pub time: Duration,
+    pub candidates: usize,
+    /// candidates within the threshold by the ground truth
+    pub correct: usize,
+    /// pairs within the threshold by the ground truth
+    pub real: usize,
+}
+
+impl Score {
+    pub fn recall(&self) -> f64 {
+        if self.real == 0 {
+            1.0
+        } else {
+            self.correct as f64 / self.real as f64
+        }
+    }
+}
+
+impl Add for Score {
+    type Output = Score;
+
+    fn add(self, other: Score) -> Score {
+        Score {
+            time: self.time + other.time,
+            candidates: self.candidates + other.candidates,
+            correct: self.correct + other.correct,
+            real: self.real + other.real,
+        }
+    }
+}
+
+/// Index of the fastest score reaching the target recall and whether any did, otherwise
+/// of the highest recall
+fn select(scores: &[Score], target_recall: f64) -> (usize, bool) {
+    let fastest = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.recall() >= target_recall)
+        .min_by_key(|(_, s)| s.time)
+        .map(|(i, _)| i);
+    match fastest {
+        Some(i) => (i, true),
+        None => {
+            let best = scores
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| {
+                    a.recall().total_cmp(&b.recall()).then(b.time.cmp(&a.time))
+                })
+                .map_or(0, |(i, _)| i);
+            (best, false)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TuneOptions {
+    pub folds: usize,
+    pub seed: u64,
+    pub target_recall: f64,
+    pub quiet: bool,
+    /// Recalls are rounded to it
+    pub floats: FloatFormat,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FoldRecord {
+    pub fold: usize,
+    pub queries: usize,
+    /// Configuration selected on the other folds
+    pub config: String,
+    pub train_time_ms: u128,
+    pub train_recall: f64,
+    /// Whether the recall on the other folds reached the target
+    pub reached_target: bool,
+    pub test_time_ms: u128,
+    pub test_candidates: usize,
+    pub test_recall: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub folds: Vec<FoldRecord>,
+    /// Configuration selected for the most folds, ties go to the lowest held out time
+    pub best: Config,
+}
+
+/// Preprocessed collections shared by configurations with the same parameters
+type PreKey = (Option<usize>, Option<usize>);
+
+fn run_config(
+    config: &Config,
+    pres: &FxHashMap<PreKey, Preprocessed>,
+    prefix_index: Option<&StructuralFilterIndex>,
+    queries: &[(usize, ParsedTree)],
+) -> Result<(Vec<(usize, usize)>, Duration), anyhow::Error> {
+    let pre = &pres[&(config.q, config.split_groups)];
+    match config.filter {
+        Filter::Plan(plan) => auto::run_plan(plan, pre, queries, &RunConfig::default()),
+        Filter::StructuralPrefix(extra_labels) => {
+            let sets = pre.structural_sets()?;
+            let index = prefix_index.expect("built for prefix configurations");
+            let ordering = get_frequency_ordering(pre.label_dict());
+            let options = PrefixFilterOptions {
+                extra_labels,
+                ..Default::default()
+            };
+            let start = Instant::now();
+            let structural_queries = pre.structural_queries(queries)?;
+            let candidates = structural_queries
+                .par_iter()
+                .enumerate()
+                .flat_map_iter(|(qid, (k, query))| {
+                    index
+                        .query_index_prefix(query, &ordering, *k, sets, Some(qid), options)
+                        .0
+                })
+                .collect();
+            Ok((candidates, start.elapsed()))
+        }
+    }
+}
+
+/// Scores every configuration on every fold and selects one for every held out fold.
+pub fn tune(
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+    queries: &[(usize, ParsedTree)],
+    ground_truth: &[(usize, usize, usize)],
+    configs: &[Config],
+    options: TuneOptions,
+) -> Result<Tuning, anyhow::Error> {
+    if !(2..=queries.len()).contains(&options.folds) {
+        anyhow::bail!(crate::error::Error::Config(format!(
+            "Folds must be from 2 to the {} queries",
+            queries.len()
+        )));
+    }
+    if configs.is_empty() {
+        anyhow::bail!(crate::error::Error::Config(
+            "No configuration to tune".to_owned()
+        ));
+    }
+    let folds = split_folds(queries.len(), options.folds, options.seed);
+    let mut fold_of = vec![0; queries.len()];
+    for (f, ids) in folds.iter().enumerate() {
+        for &id in ids {
+            fold_of[id] = f;
+        }
+    }
+    let fold_queries = folds
+        .iter()
+        .map(|ids| ids.iter().map(|id| queries[*id].clone()).collect_vec())
+        .collect_vec();
+
+    let mut pres = FxHashMap::default();
+    for config in configs {
+        if let Entry::Vacant(entry) = pres.entry((config.q, config.split_groups)) {
+            let mut pre = Preprocessed::new(CollectionView::new(trees), label_dict)?;
+            if let Some(q) = config.q {
+                pre = pre.with_qgram_size(q);
+            }
+            if let Some(groups) = config.split_groups {
+                pre = pre.with_split_groups(groups);
+            }
+            entry.insert(pre);
+        }
+    }
+    let prefix_index = configs
+        .iter()
+        .any(|c| matches!(c.filter, Filter::StructuralPrefix(_)))
+        .then(|| {
+            pres[&(None, None)]
+                .structural_sets()
+                .map(StructuralFilterIndex::new)
+        })
+        .transpose()?;
+
+    // scores[config][fold]
+    let mut scores = vec![];
+    for config in configs {
+        run_config(config, &pres, prefix_index.as_ref(), &fold_queries[0])?;
+        let mut config_scores = vec![];
+        for (f, (ids, fold)) in folds.iter().zip(&fold_queries).enumerate() {
+            let (candidates, time) = run_config(config, &pres, prefix_index.as_ref(), fold)?;
+            let candidates = candidates
+                .into_iter()
+                .map(|(qid, tid)| (ids[qid], tid))
+                .collect_vec();
+            let (correct, real) = bench::count_correct(&candidates, ground_truth, |q| {
+                (fold_of.get(q) == Some(&f)).then(|| queries[q].0)
+            });
+            config_scores.push(Score {
+                time,
+                candidates: candidates.len(),
+                correct,
+                real,
+            });
+        }
+        if !options.quiet {
+            let total = config_scores
+                .iter()
+                .copied()
+                .fold(Score::default(), Add::add);
+            println!(
+                "{config}: time {}ms, candidates {}, recall {}",
+                total.time.as_millis(),
+                total.candidates,
+                options.floats.format(total.recall())
+            );
+        }
+        scores.push(config_scores);
+    }
+
+    let mut records = vec![];
+    let mut selected = vec![(0usize, Duration::ZERO); configs.len()];
+    for (f, ids) in folds.iter().enumerate() {
+        let train = scores
+            .iter()
+            .map(|s| {
+                s.iter()
+                    .enumerate()
+                    .filter(|(g, _)| *g != f)
+                    .fold(Score::default(), |sum, (_, score)| sum + *score)
+            })
+            .collect_vec();
+        let (best, reached_target) = select(&train, options.target_recall);
+        let test = scores[best][f];
+        selected[best].0 += 1;
+        selected[best].1 += test.time;
+        let record = FoldRecord {
+            fold: f,
+            queries: ids.len(),
+            config: configs[best].to_string(),
+            train_time_ms: train[best].time.as_millis(),
+            train_recall: options.floats.round(train[best].recall()),
+            reached_target,
+            test_time_ms: test.time.as_millis(),
+            test_candidates: test.candidates,
+            test_recall: options.floats.round(test.recall()),
+        };
+        if !options.quiet {
+            println!("{record:?}");
+        }
+        records.push(record);
+    }
+    let best = (0..configs.len())
+        .filter(|i| selected[*i].0 > 0)
+        .max_by(|a, b| {
+            let (count_a, time_a) = selected[*a];
+            let (count_b, time_b) = selected[*b];
+            count_a.cmp(&count_b).then(time_b.cmp(&time_a))
+        })
+        .expect("every fold selects a configuration");
+    Ok(Tuning {
+        folds: records,
+        best: configs[best],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_tune() {
+        let folds = split_folds(10, 3, 7);
+        assert_eq!(folds.iter().map(Vec::len).collect_vec(), [4, 3, 3]);
+        assert_eq!(
+            folds.concat().into_iter().sorted().collect_vec(),
+            (0..10).collect_vec()
+        );
+
+        let grid = Grid {
+            methods: vec![
+                LowerBoundMethods::Lblint,
+                LowerBoundMethods::StructuralSplit,
+            ],
+            q_values: vec![2, 3],
+            split_groups: vec![1, 2],
+            prefix_extra_labels: vec![0],
+        };
+        // 2 splits alone, Lblint > split twice and split > Lblint twice
+        let configs = grid.configs();
+        assert_eq!(configs.len(), 1 + 2 + 2 + 2);
+        assert!(configs.iter().all(|c| c.q.is_none()));
+
+        let fast = Score {
+            time: Duration::from_millis(1),
+            candidates: 5,
+            correct: 1,
+            real: 2,
+        };
+        let exact = Score {
+            time: Duration::from_millis(3),
+            correct: 2,
+            ..fast
+        };
+        assert_eq!(select(&[fast, exact], 1.0), (1, true));
+        assert_eq!(select(&[fast, exact], 0.5), (0, true));
+        assert_eq!(select(&[fast], 1.0), (0, false));
+
+        let mut ld = LabelDict::default();
+        let trees = ["{a}", "{a{b}}", "{a{b}{c}}", "{a{c}{b}}", "{x{y}{z}{w}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld));
+        let queries = ["{a{b}}", "{a{c}}", "{x{y}{z}}", "{a{b}{c}}"]
+            .map(|q| (1, parse_single(q.to_owned(), &mut ld)));
+        let mut ground_truth = vec![];
+        for (qid, (k, query)) in queries.iter().enumerate() {
+            for (tid, tree) in trees.iter().enumerate() {
+                let distance = crate::ted::ted(query, tree);
+                if distance <= *k {
+                    ground_truth.push((qid, tid, distance));
+                }
+            }
+        }
+        let grid = Grid {
+            methods: vec![LowerBoundMethods::Lblint, LowerBoundMethods::Structural],
+            q_values: vec![],
+            split_groups: vec![],
+            prefix_extra_labels: vec![0, 2],
+        };
+        let options = TuneOptions {
+            folds: 2,
+            seed: 42,
+            target_recall: 1.0,
+            quiet: true,
+            floats: FloatFormat::default(),
+        };
+        let tuning = tune(
+            &trees,
+            &ld,
+            &queries,
+            &ground_truth,
+            &grid.configs(),
+            options,
+        )
+        .unwrap();
+        // lower bounds never miss a result
+        assert_eq!(tuning.folds.len(), 2);
+        assert!(tuning
+            .folds
+            .iter()
+            .all(|r| r.reached_target && r.test_recall == 1.0));
+        assert_eq!(tuning.folds.iter().map(|r| r.queries).sum::<usize>(), 4);
+        assert!(grid.configs().contains(&tuning.best));
+
+        let too_many = TuneOptions {
+            folds: 5,
+            ..options
+        };
+        assert!(tune(
+            &trees,
+            &ld,
+            &queries,
+            &ground_truth,
+            &grid.configs(),
+            too_many
+        )
+        .is_err());
+    }
+}
@@ -1,29 +1,40 @@
+use crate::candidate_file::{self, PairOrder};
+use crate::external_sort::{PairSorter, SortedPairs};
 use crate::lb::indexes::histograms::Candidates;
 
+use itertools::Itertools;
 use rayon::prelude::*;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
-pub fn read_candidates(
+/// Reads candidates from CSV or binary candidates file and sorts them, candidates
+/// beyond `memory_limit` bytes are sorted in runs on disk
+pub fn sort_candidates(
     candidates_file: &impl AsRef<Path>,
-) -> Result<Vec<(usize, usize)>, anyhow::Error> {
-    let cfile = File::open(candidates_file)?;
-    let mut candidates = vec![];
+    memory_limit: Option<usize>,
+) -> Result<(SortedPairs, usize), anyhow::Error> {
+    let mut sorter = PairSorter::new(memory_limit);
+    candidate_file::visit_pairs(candidates_file, |pair| Ok(sorter.push(pair)?))?;
+    let runs = sorter.runs();
+    Ok((sorter.finish()?, runs))
+}
 
-    let creader = BufReader::new(cfile);
-    let mut creader = csv::Reader::from_reader(creader);
-    for result in creader.records() {
-        let record = result?;
-        let (t1, t2): (usize, usize) = (record[0].parse()?, record[1].parse()?);
-        candidates.push((t1, t2));
-    }
-    candidates.par_sort();
-    Ok(candidates)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validation {
+    /// pairs within the threshold missing in the candidates in either orientation
+    pub not_found: Candidates,
+    pub correct: usize,
+    pub extra: usize,
+    pub precision: f32,
+    pub mean_selectivity: f64,
+    /// runs the candidates were sorted in on disk
+    pub runs: usize,
+    /// order of the pair ids recorded in the candidates file
+    pub order: PairOrder,
 }
 
-pub fn validate(
-    candidates_file: &impl AsRef<Path>,
+fn read_results(
     results: &impl AsRef<Path>,
     k: usize,
 ) -> Result<Vec<(usize, usize)>, anyhow::Error> {
@@ -41,42 +52,94 @@ pub fn validate(
         }
     }
     real_result.par_sort();
-    let candidates = read_candidates(candidates_file)?;
+    Ok(real_result)
+}
 
-    let not_found = real_result
+/// Writes verified `(query id, tree id, distance)` results as the `t1,t2,dist` lines
+/// [`validate`] reads, so they serve as exact results of later runs. Pairs are arranged in
+/// `order` like the candidates, see [`PairOrder::arrange`].
+pub fn write_results(
+    path: &impl AsRef<Path>,
+    results: &[(usize, usize, usize)],
+    order: PairOrder,
+    query_trees: &[usize],
+) -> Result<(), std::io::Error> {
+    let mut arranged = results
         .iter()
-        .filter_map(|(p1, p2)| {
-            candidates.binary_search(&(*p1, *p2)).map_or_else(
-                |_| {
-                    let flipped = &(*p2, *p1);
-                    candidates
-                        .binary_search(flipped)
-                        .map_or(Some((*p1, *p2)), |_| None)
-                },
-                |_| None,
-            )
+        .map(|&(qid, tid, distance)| match order {
+            PairOrder::QueryTree => (qid, tid, distance),
+            PairOrder::TreeQuery => (tid, qid, distance),
+            PairOrder::Unordered => {
+                let query_tree = query_trees[qid];
+                (query_tree.min(tid), query_tree.max(tid), distance)
+            }
         })
         .collect::<Vec<_>>();
+    arranged.par_sort_unstable();
+    arranged.dedup_by_key(|(t1, t2, _)| (*t1, *t2));
+    let lines = std::iter::once("t1,t2,dist".to_owned())
+        .chain(
+            arranged
+                .iter()
+                .map(|(t1, t2, dist)| format!("{t1},{t2},{dist}")),
+        )
+        .join("\n");
+    std::fs::write(path, lines + "\n")
+}
 
-    let _false_positives = candidates
-        .par_iter()
-        .filter_map(|(p1, p2)| {
-            real_result
-                .binary_search(&(*p1, *p2))
-                .map_or(Some((*p1, *p2)), |_| {
-                    let flipped = &(*p2, *p1);
-                    real_result
-                        .binary_search(flipped)
-                        .map_or(Some((*p1, *p2)), |_| None)
-                })
-        })
-        .collect::<Vec<_>>();
+/// Compares candidates with the pairs of the results within `k`. Candidates are streamed
+/// in sorted order, only the results and a counter per tree are held in memory.
+pub fn validate(
+    candidates_file: &impl AsRef<Path>,
+    results: &impl AsRef<Path>,
+    k: usize,
+    trees_total: usize,
+    memory_limit: Option<usize>,
+) -> Result<Validation, anyhow::Error> {
+    let real_result = read_results(results, k)?;
+    let order = PairOrder::of_file(candidates_file)?;
+    let (candidates, runs) = sort_candidates(candidates_file, memory_limit)?;
+
+    let mut found = vec![false; real_result.len()];
+    let mut mark = |pair: (usize, usize)| {
+        let start = real_result.partition_point(|p| *p < pair);
+        let end = real_result.partition_point(|p| *p <= pair);
+        found[start..end].fill(true);
+        start < end
+    };
+    let mut matches = vec![0usize; trees_total];
+    let (mut total, mut extra) = (0, 0);
+    for candidate in candidates {
+        let (c1, c2) = candidate?;
+        total += 1;
+        // both orientations are marked, a pair is found by either of them
+        let as_is = mark((c1, c2));
+        let flipped = mark((c2, c1));
+        if !as_is && !flipped {
+            extra += 1;
+        }
+        // selectivity is counted per query, the first id unless the file says otherwise
+        let query = if order == PairOrder::TreeQuery {
+            c2
+        } else {
+            c1
+        };
+        if let Some(tau_match) = matches.get_mut(query) {
+            *tau_match += 1;
+        }
+    }
+    let not_found = real_result
+        .iter()
+        .zip(&found)
+        .filter(|(_, found)| !**found)
+        .map(|(pair, _)| *pair)
+        .collect::<Candidates>();
 
     println!(
         "Candidates and real result size diff is: {}, should have found: {} and found: {}",
         not_found.len(),
         real_result.len(),
-        candidates.len()
+        total
     );
 
     if !not_found.is_empty() {
@@ -87,59 +150,76 @@ pub fn validate(
         }
     }
 
-    Ok(not_found)
-}
-
-pub fn get_precision(
-    candidates: &Candidates,
-    results_path: &PathBuf,
-    k: usize,
-    trees_total: usize,
-) -> Result<(usize, usize, f32, f64), anyhow::Error> {
-    let rfile = File::open(results_path)?;
-    let rreader = BufReader::new(rfile);
-    let mut real_result = vec![];
-    let mut rreader = csv::Reader::from_reader(rreader);
-    for result in rreader.records() {
-        let record = result?;
-        let (t1, t2, dist): (usize, usize, usize) =
-            (record[0].parse()?, record[1].parse()?, record[2].parse()?);
-        if dist <= k {
-            real_result.push((t1, t2));
-        }
-    }
-    real_result.par_sort();
-    let mut matches = vec![0; trees_total];
-    matches
-        .par_iter_mut()
-        .enumerate()
-        .for_each(|(tree_id, tau_match)| {
-            for (t1, _) in candidates.iter() {
-                if *t1 == tree_id {
-                    *tau_match += 1;
-                }
-            }
-        });
-
     let selectivity: Vec<f64> = matches
         .par_iter()
         .enumerate()
         .map(|(tree_id, tau_match)| 100f64 * (*tau_match as f64 / (trees_total - tree_id) as f64))
         .collect();
-    let mean_sel = crate::statistics::mean(&selectivity);
-
-    let extra = candidates.iter().fold(0usize, |acc, (c1, c2)| {
-        match real_result.binary_search(&(*c1, *c2)) {
-            Ok(_) => acc,
-            Err(_) => match real_result.binary_search(&(*c2, *c1)) {
-                Ok(_) => acc,
-                Err(_) => acc + 1,
-            },
-        }
-    });
+    let mean_selectivity = crate::statistics::mean(&selectivity);
+
+    let correct = total - extra;
+    let precision = correct as f32 / total as f32;
 
-    let correct = candidates.len() - extra;
-    let precision = correct as f32 / candidates.len() as f32;
+    Ok(Validation {
+        not_found,
+        correct,
+        extra,
+        precision,
+        mean_selectivity,
+        runs,
+        order,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_streamed() {
+        let dir = std::env::temp_dir();
+        let results = dir.join(format!("validation-results-{}.csv", std::process::id()));
+        std::fs::write(&results, "t1,t2,dist\n0,1,1\n0,2,3\n1,3,2\n2,3,5\n").unwrap();
+        let candidates = dir.join(format!("validation-candidates-{}.csv", std::process::id()));
+        // (3, 1) finds (1, 3), (2, 3) is beyond the threshold and (0, 2) is missed
+        std::fs::write(&candidates, "0,1\n3,1\n2,3\n").unwrap();
+        let validation = validate(&candidates, &results, 3, 4, Some(1)).unwrap();
+        assert_eq!(validation.not_found, [(0, 2)]);
+        assert_eq!((validation.correct, validation.extra), (2, 1));
+        assert!((validation.precision - 2.0 / 3.0).abs() < 1e-6);
+        // candidates of tree 0 out of the 4 trees after it, of tree 3 out of 1
+        assert!((validation.mean_selectivity - (25.0 + 0.0 + 50.0 + 100.0) / 4.0).abs() < 1e-9);
+        let validation = validate(&candidates, &results, 1, 4, None).unwrap();
+        assert_eq!(validation.not_found, []);
+        assert_eq!(validation.extra, 2);
 
-    Ok((correct, extra, precision, mean_sel))
+        // the same pairs recorded as tree-query pairs count selectivity by the second id
+        std::fs::write(&candidates, "tree_id,query_id\n1,0\n1,3\n3,2\n").unwrap();
+        let validation = validate(&candidates, &results, 3, 4, None).unwrap();
+        assert_eq!(validation.order, PairOrder::TreeQuery);
+        assert_eq!((validation.correct, validation.extra), (2, 1));
+        assert!((validation.mean_selectivity - (25.0 + 0.0 + 50.0 + 100.0) / 4.0).abs() < 1e-9);
+        std::fs::remove_file(results).unwrap();
+        std::fs::remove_file(candidates).unwrap();
+    }
+
+    #[test]
+    fn test_written_results() {
+        let dir = std::env::temp_dir();
+        let results = dir.join(format!("written-results-{}.csv", std::process::id()));
+        // queries are the trees 2 and 0, both find the pair of them
+        let verified = [(0, 0, 2), (0, 3, 1), (1, 2, 2), (1, 1, 0)];
+        write_results(&results, &verified, PairOrder::Unordered, &[2, 0]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&results).unwrap(),
+            "t1,t2,dist\n0,1,0\n0,2,2\n2,3,1\n"
+        );
+        assert_eq!(read_results(&results, 1).unwrap(), [(0, 1), (2, 3)]);
+        write_results(&results, &verified, PairOrder::TreeQuery, &[]).unwrap();
+        assert_eq!(
+            read_results(&results, 2).unwrap(),
+            [(0, 0), (1, 1), (2, 1), (3, 0)]
+        );
+        std::fs::remove_file(results).unwrap();
+    }
 }
@@ -0,0 +1,469 @@
+//! Exact tree edit distance algorithms verifying candidates, selected with `--verifier`.
+//!
+//! Every verifier answers whether a pair is within the threshold and its distance if so,
+//! so an algorithm bounded by the threshold can stop early. [`ZhangShasha`] and [`Touzet`]
+//! run in process with any [`CostModel`]. [`External`] runs a program for every pair with
+//! the trees in bracket notation and reads the distance it prints, [`VerifierKind::Apted`]
+//! is the reference APTED implementation of Pawlik and Augsten run that way. External
+//! programs compute unit cost distances and start a process per pair, they are meant for
+//! checking the results of the in-process verifiers and for samples, not whole joins.
+//! [`ExternalBatch`] starts a process per batch of pairs instead, for joins verified by an
+//! external tool end to end.
+use crate::error::Error;
+use crate::parsing::{label_names, tree_to_labeled_bracket, LabelDict, ParsedTree};
+use crate::ted::{ted_k_with_costs, touzet_ted_k, CostModel};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub trait Verifier: Sync {
+    /// Tree edit distance of the trees if it is at most `k`, fails only if an external
+    /// program fails
+    fn ted(&self, t1: &ParsedTree, t2: &ParsedTree, k: usize) -> Result<Option<usize>, Error>;
+
+    /// [`Verifier::ted`] of every `(t1, t2, k)` pair in their order, in parallel unless the
+    /// verifier batches pairs itself
+    fn ted_batch(
+        &self,
+        pairs: &[(&ParsedTree, &ParsedTree, usize)],
+    ) -> Result<Vec<Option<usize>>, Error> {
+        pairs
+            .par_iter()
+            .map(|&(t1, t2, k)| self.ted(t1, t2, k))
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum VerifierKind {
+    /// Zhang-Shasha over all key root pairs
+    #[default]
+    ZhangShasha,
+    /// Zhang-Shasha over the key root pairs within the threshold of Touzet
+    Touzet,
+    /// The APTED jar run with `java -jar <JAR> -t <t1> <t2>`, unit costs only
+    Apted,
+    /// A program given with `--verifier-command`, unit costs only
+    External,
+    /// A program given with `--verifier-command` verifying `--verifier-batch` pairs per
+    /// run, unit costs only
+    ExternalBatch,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZhangShasha(pub CostModel);
+
+impl Verifier for ZhangShasha {
+    fn ted(&self, t1: &ParsedTree, t2: &ParsedTree, k: usize) -> Result<Option<usize>, Error> {
+        Ok(Some(ted_k_with_costs(t1, t2, k, &self.0)).filter(|d| *d <= k))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Touzet(pub CostModel);
+
+impl Verifier for Touzet {
+    fn ted(&self, t1: &ParsedTree, t2: &ParsedTree, k: usize) -> Result<Option<usize>, Error> {
+        Ok(Some(touzet_ted_k(t1, t2, k, &self.0)).filter(|d| *d <= k))
+    }
+}
+
+/// Program and arguments of an external verifier, names of labels to write trees with
+#[derive(Debug, Clone)]
+struct ExternalCommand {
+    program: String,
+    args: Vec<String>,
+    /// names of labels by id, labels keep the escapes of the dataset
+    names: Vec<String>,
+}
+
+impl ExternalCommand {
+    /// Splits `command` at whitespace, quoting is not supported
+    fn new(command: &str, label_dict: &LabelDict) -> Result<Self, Error> {
+        let mut words = command.split_whitespace().map(str::to_owned);
+        let program = words
+            .next()
+            .ok_or_else(|| Error::Config("Empty verifier command".to_owned()))?;
+        let names = label_names(label_dict)
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        Ok(Self {
+            program,
+            args: words.collect(),
+            names,
+        })
+    }
+
+    fn has_arg(&self, placeholder: &str) -> bool {
+        self.args.iter().any(|a| a.contains(placeholder))
+    }
+
+    fn bracket(&self, tree: &ParsedTree) -> String {
+        let names = self.names.iter().map(String::as_str).collect::<Vec<_>>();
+        tree_to_labeled_bracket(tree, &names)
+    }
+
+    /// Runs the program with `replace` applied to every argument and `input` written to its
+    /// standard input, returns what it printed
+    fn run(
+        &self,
+        replace: impl Fn(&str) -> String,
+        input: Option<String>,
+    ) -> Result<String, Error> {
+        let mut child = Command::new(&self.program)
+            .args(self.args.iter().map(|a| replace(a)))
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // written from another thread, the program may print before it read all input
+        let writer = input.zip(child.stdin.take()).map(|(input, mut stdin)| {
+            std::thread::spawn(move || stdin.write_all(input.as_bytes()))
+        });
+        let output = child.wait_with_output()?;
+        if let Some(writer) = writer {
+            writer.join().expect("writer of verifier input panicked")?;
+        }
+        if !output.status.success() {
+            return Err(Error::Config(format!(
+                "Verifier `{}` failed with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn no_distance(&self, printed: &str) -> Error {
+        Error::Parse(format!(
+            "Verifier `{}` printed no distance: {}",
+            self.program,
+            printed.trim()
+        ))
+    }
+}
+
+/// Distance in the last word of the text, fractions such as `2.0` are rounded
+fn parse_distance(text: &str) -> Option<usize> {
+    text.split_whitespace()
+        .last()
+        .and_then(|word| word.parse::<f64>().ok())
+        .filter(|d| d.is_finite() && *d >= 0.0)
+        .map(|d| d.round() as usize)
+}
+
+/// Program computing the unit cost distance of two trees in bracket notation. Arguments
+/// `{t1}` and `{t2}` are replaced by the trees, without them the trees are appended. The
+/// last word the program prints is the distance, fractions such as `2.0` are rounded.
+#[derive(Debug, Clone)]
+pub struct External(ExternalCommand);
+
+impl External {
+    /// The program is run once on two single node trees, so a missing program or
+    /// unreadable output fails here rather than in the middle of verification.
+    pub fn new(command: &str, label_dict: &LabelDict) -> Result<Self, Error> {
+        let mut command = ExternalCommand::new(command, label_dict)?;
+        if !command.has_arg("{t1}") && !command.has_arg("{t2}") {
+            command.args.extend(["{t1}".to_owned(), "{t2}".to_owned()]);
+        }
+        let external = Self(command);
+        match external.distance("{a}", "{b}")? {
+            1 => Ok(external),
+            d => Err(Error::Config(format!(
+                "Verifier `{}` gave distance {d} of {{a}} and {{b}}, expected 1",
+                external.0.program
+            ))),
+        }
+    }
+
+    /// [`External`] running the APTED jar
+    pub fn apted(jar: &Path, label_dict: &LabelDict) -> Result<Self, Error> {
+        Self::new(
+            &format!("java -jar {} -t {{t1}} {{t2}}", jar.display()),
+            label_dict,
+        )
+    }
+
+    fn distance(&self, t1: &str, t2: &str) -> Result<usize, Error> {
+        let printed = self
+            .0
+            .run(|a| a.replace("{t1}", t1).replace("{t2}", t2), None)?;
+        parse_distance(&printed).ok_or_else(|| self.0.no_distance(&printed))
+    }
+}
+
+impl Verifier for External {
+    fn ted(&self, t1: &ParsedTree, t2: &ParsedTree, k: usize) -> Result<Option<usize>, Error> {
+        let distance = self.distance(&self.0.bracket(t1), &self.0.bracket(t2))?;
+        Ok((distance <= k).then_some(distance))
+    }
+}
+
+/// Program computing the unit cost distances of a batch of pairs in one run, so a tool
+/// that loads slowly, e.g. the C++ APTED binary behind a wrapper, starts once per batch
+/// instead of once per pair. Pairs are written one per line as the two trees in bracket
+/// notation separated by a tab, to a file in place of a `{pairs}` argument or to the
+/// standard input without it. The program prints a line per pair in their order, the last
+/// word of a line is the distance of its pair. Batches run in parallel.
+#[derive(Debug, Clone)]
+pub struct ExternalBatch {
+    command: ExternalCommand,
+    batch: usize,
+}
+
+/// Files of batches written at the same time get distinct names
+static BATCH_FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl ExternalBatch {
+    /// The program is run once on a batch of two pairs of single node trees, so a missing
+    /// program or unreadable output fails here rather than in the middle of verification.
+    pub fn new(command: &str, batch: usize, label_dict: &LabelDict) -> Result<Self, Error> {
+        let external = Self {
+            command: ExternalCommand::new(command, label_dict)?,
+            batch: batch.max(1),
+        };
+        let distances = external.distances(vec![
+            ("{a}".to_owned(), "{b}".to_owned()),
+            ("{a}".to_owned(), "{a}".to_owned()),
+        ])?;
+        if distances != [1, 0] {
+            return Err(Error::Config(format!(
+                "Verifier `{}` gave distances {distances:?} of {{a}},{{b}} and {{a}},{{a}}, \
+                 expected [1, 0]",
+                external.command.program
+            )));
+        }
+        Ok(external)
+    }
+
+    /// Distances of the pairs of trees in bracket notation, in their order
+    fn distances(&self, pairs: Vec<(String, String)>) -> Result<Vec<usize>, Error> {
+        let input = pairs
+            .iter()
+            .map(|(t1, t2)| format!("{t1}\t{t2}\n"))
+            .collect::<String>();
+        let printed = if self.command.has_arg("{pairs}") {
+            let path = std::env::temp_dir().join(format!(
+                "verifier-batch-{}-{}.tsv",
+                std::process::id(),
+                BATCH_FILES.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&path, input)?;
+            let file = path.display().to_string();
+            let printed = self.command.run(|a| a.replace("{pairs}", &file), None);
+            // a failed run is reported over a failed removal
+            let removed = std::fs::remove_file(&path);
+            let printed = printed?;
+            removed?;
+            printed
+        } else {
+            self.command.run(str::to_owned, Some(input))?
+        };
+        let distances = printed
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_distance(line).ok_or_else(|| self.command.no_distance(line)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if distances.len() != pairs.len() {
+            return Err(Error::Parse(format!(
+                "Verifier `{}` printed {} distances of {} pairs",
+                self.command.program,
+                distances.len(),
+                pairs.len()
+            )));
+        }
+        Ok(distances)
+    }
+}
+
+impl Verifier for ExternalBatch {
+    fn ted(&self, t1: &ParsedTree, t2: &ParsedTree, k: usize) -> Result<Option<usize>, Error> {
+        Ok(self.ted_batch(&[(t1, t2, k)])?[0])
+    }
+
+    fn ted_batch(
+        &self,
+        pairs: &[(&ParsedTree, &ParsedTree, usize)],
+    ) -> Result<Vec<Option<usize>>, Error> {
+        let batches = pairs
+            .par_chunks(self.batch)
+            .map(|chunk| {
+                let brackets = chunk
+                    .iter()
+                    .map(|(t1, t2, _)| (self.command.bracket(t1), self.command.bracket(t2)))
+                    .collect();
+                let distances = self.distances(brackets)?;
+                Ok(chunk
+                    .iter()
+                    .zip(distances)
+                    .map(|(&(.., k), distance)| (distance <= k).then_some(distance))
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(batches.concat())
+    }
+}
+
+/// Verifies `(query id, tree id)` pairs with the verifier, the distance of every pair if it
+/// is within the threshold of its query
+pub fn verify_pairs(
+    verifier: &dyn Verifier,
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    pairs: &[(usize, usize)],
+) -> Result<Vec<Option<usize>>, Error> {
+    let pairs = pairs
+        .iter()
+        .map(|&(qid, tid)| {
+            let (k, query) = &queries[qid];
+            (query, &trees[tid], *k)
+        })
+        .collect::<Vec<_>>();
+    verifier.ted_batch(&pairs)
+}
+
+/// Verifier of the kind, `command` is the program of the external verifiers and the jar of
+/// [`VerifierKind::Apted`], `batch` the pairs of a run of [`VerifierKind::ExternalBatch`].
+/// External verifiers reject costs other than unit costs.
+pub fn build(
+    kind: VerifierKind,
+    costs: CostModel,
+    command: Option<&str>,
+    batch: usize,
+    label_dict: &LabelDict,
+) -> Result<Box<dyn Verifier>, Error> {
+    let external = matches!(
+        kind,
+        VerifierKind::Apted | VerifierKind::External | VerifierKind::ExternalBatch
+    );
+    if external && costs != CostModel::UNIT {
+        return Err(Error::Config(format!(
+            "The {kind:?} verifier only computes unit cost distances, remove --ted-costs"
+        )));
+    }
+    let command = || {
+        command
+            .ok_or_else(|| Error::Config(format!("The {kind:?} verifier needs --verifier-command")))
+    };
+    Ok(match kind {
+        VerifierKind::ZhangShasha => Box::new(ZhangShasha(costs)),
+        VerifierKind::Touzet => Box::new(Touzet(costs)),
+        VerifierKind::Apted => Box::new(External::apted(
+            Path::new(command().unwrap_or("apted.jar")),
+            label_dict,
+        )?),
+        VerifierKind::External => Box::new(External::new(command()?, label_dict)?),
+        VerifierKind::ExternalBatch => Box::new(ExternalBatch::new(command()?, batch, label_dict)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_verifiers_agree() {
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{f{d{a}{c{b}}}{e}}",
+            "{f{c{d{a}{b}}}{e}}",
+            "{a{b}{c}}",
+            "{x{b}{c}{d}}",
+            "{a{b{c}{d}}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let verifiers: [&dyn Verifier; 2] = [&ZhangShasha::default(), &Touzet::default()];
+        for (t1, t2) in itertools::iproduct!(&trees, &trees) {
+            for k in 0..6 {
+                let [zs, touzet] = verifiers.map(|v| v.ted(t1, t2, k).unwrap());
+                assert_eq!(zs, touzet);
+            }
+        }
+        assert_eq!(
+            ZhangShasha::default().ted(&trees[0], &trees[1], 2).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            ZhangShasha::default().ted(&trees[0], &trees[1], 1).unwrap(),
+            None
+        );
+
+        // a program printing the same distance of any trees
+        let dir = std::env::temp_dir().join(format!("verifier-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, output: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\necho {output}\n")).unwrap();
+            format!("sh {}", path.display())
+        };
+        let external = External::new(&script("one.sh", "distance: 1.0"), &ld).unwrap();
+        assert_eq!(external.ted(&trees[0], &trees[2], 1).unwrap(), Some(1));
+        assert_eq!(external.ted(&trees[0], &trees[2], 0).unwrap(), None);
+        // a program failing after the check on creation fails verification
+        let flaky = dir.join("flaky.sh");
+        std::fs::write(
+            &flaky,
+            "#!/bin/sh\nif [ \"$1\" = \"{a}\" ]; then echo 1; else exit 3; fi\n",
+        )
+        .unwrap();
+        let flaky = External::new(&format!("sh {}", flaky.display()), &ld).unwrap();
+        assert!(flaky.ted(&trees[0], &trees[2], 1).is_err());
+        assert!(verify_pairs(&flaky, &trees, &[(1, trees[0].clone())], &[(0, 2)]).is_err());
+        assert!(External::new(&script("none.sh", "oops"), &ld).is_err());
+        assert!(External::new(&script("two.sh", "2"), &ld).is_err());
+        assert!(External::new("", &ld).is_err());
+
+        // a program giving 0 of equal and 1 of different trees of every pair, read from
+        // the file of its argument or the standard input
+        let equal = dir.join("equal.sh");
+        std::fs::write(
+            &equal,
+            "#!/bin/sh\ncat \"$@\" | while IFS=\"$(printf '\\t')\" read -r a b; do\n\
+             if [ \"$a\" = \"$b\" ]; then echo 0; else echo \"d 1.0\"; fi; done\n",
+        )
+        .unwrap();
+        let pairs = [(0, 0, 0), (0, 1, 1), (2, 2, 1), (3, 4, 0), (4, 4, 0)]
+            .map(|(i, j, k)| (&trees[i], &trees[j], k));
+        for command in [
+            format!("sh {}", equal.display()),
+            format!("sh {} {{pairs}}", equal.display()),
+        ] {
+            let batch = ExternalBatch::new(&command, 2, &ld).unwrap();
+            assert_eq!(
+                batch.ted_batch(&pairs).unwrap(),
+                [Some(0), Some(1), Some(0), None, Some(0)]
+            );
+            assert_eq!(batch.ted(&trees[0], &trees[1], 0).unwrap(), None);
+        }
+        assert!(ExternalBatch::new(&script("one.sh", "1"), 2, &ld).is_err());
+        // a program failing after the check on creation fails the batch
+        let flaky = dir.join("flaky-batch.sh");
+        std::fs::write(
+            &flaky,
+            "#!/bin/sh\nif cat \"$@\" | grep -q '{a}'; then printf '1\\n0\\n'; else exit 3; fi\n",
+        )
+        .unwrap();
+        for command in [
+            format!("sh {}", flaky.display()),
+            format!("sh {} {{pairs}}", flaky.display()),
+        ] {
+            let batch = ExternalBatch::new(&command, 2, &ld).unwrap();
+            assert!(batch.ted_batch(&pairs).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            build(VerifierKind::Apted, "1,1,2".parse().unwrap(), None, 1, &ld),
+            Err(Error::Config(_))
+        ));
+    }
+}
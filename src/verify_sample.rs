@@ -0,0 +1,422 @@
+//! Precision and recall estimates of candidates from exact verification of random samples.
+//!
+//! Verifying every candidate with the tree edit distance may take days on large joins.
+//! A uniform sample of candidates without replacement is verified instead, the share of
+//! candidates within their query threshold is extrapolated to all candidates with a
+//! Wilson score interval.
+//!
+//! Recall is estimated the same way from pairs that are not candidates. Trees whose size
+//! differs from the query by more than the threshold are never results, so only pairs
+//! within the size window of their query are sampled. The sample is stratified by query
+//! with allocation proportional to the non-candidates of every query, which weighs every
+//! sampled pair the same and keeps queries with large windows from taking the sample.
+use crate::error::Error;
+use crate::parsing::ParsedTree;
+use crate::verifier::{verify_pairs, Verifier};
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrecisionEstimate {
+    pub candidates: usize,
+    pub sampled: usize,
+    /// sampled candidates within the threshold of their query
+    pub verified: usize,
+    pub confidence: f64,
+    /// bounds of the confidence interval of the precision
+    pub interval: (f64, f64),
+}
+
+impl PrecisionEstimate {
+    pub fn precision(&self) -> f64 {
+        self.verified as f64 / self.sampled.max(1) as f64
+    }
+
+    /// Estimated number of candidates within the threshold with the bounds of the interval
+    pub fn results(&self) -> (f64, f64, f64) {
+        let n = self.candidates as f64;
+        (
+            self.precision() * n,
+            self.interval.0 * n,
+            self.interval.1 * n,
+        )
+    }
+}
+
+/// Rates have the precision of the formatter, 4 decimals without one
+impl fmt::Display for PrecisionEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(4);
+        let (results, low, high) = self.results();
+        writeln!(
+            f,
+            "Verified {} of {} sampled candidates out of {}",
+            self.verified, self.sampled, self.candidates
+        )?;
+        writeln!(
+            f,
+            "precision:{:.precision$} ({:.0}% interval {:.precision$}-{:.precision$})",
+            self.precision(),
+            self.confidence * 100.0,
+            self.interval.0,
+            self.interval.1
+        )?;
+        write!(
+            f,
+            "estimated results:{results:.0} ({:.0}-{:.0})",
+            low.floor(),
+            high.ceil()
+        )
+    }
+}
+
+/// Verifies `sample_size` random candidates with the bounded tree edit distance of the
+/// verifier. A sample of all candidates gives the exact precision with an empty interval.
+pub fn estimate_precision(
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    candidates: &[(usize, usize)],
+    sample_size: usize,
+    confidence: f64,
+    seed: u64,
+    verifier: &dyn Verifier,
+) -> Result<PrecisionEstimate, Error> {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let sampled = sample_size.min(candidates.len());
+    let pairs = sample(&mut rng, candidates.len(), sampled)
+        .into_iter()
+        .map(|i| candidates[i])
+        .collect::<Vec<_>>();
+    let verified = verify_pairs(verifier, trees, queries, &pairs)?
+        .into_iter()
+        .flatten()
+        .count();
+    let interval = if sampled == candidates.len() {
+        let precision = verified as f64 / sampled.max(1) as f64;
+        (precision, precision)
+    } else {
+        wilson_interval(verified, sampled, normal_quantile(0.5 + confidence / 2.0))
+    };
+    Ok(PrecisionEstimate {
+        candidates: candidates.len(),
+        sampled,
+        verified,
+        confidence,
+        interval,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecallEstimate {
+    /// pairs within the size windows of their queries that are not candidates
+    pub non_candidates: usize,
+    pub sampled: usize,
+    /// sampled pairs within the threshold of their query, results the candidates miss
+    pub missed: usize,
+    pub confidence: f64,
+    /// bounds of the confidence interval of the share of results among non-candidates
+    pub interval: (f64, f64),
+}
+
+impl RecallEstimate {
+    pub fn miss_rate(&self) -> f64 {
+        self.missed as f64 / self.sampled.max(1) as f64
+    }
+
+    /// Estimated number of missed results with the bounds of the interval
+    pub fn missed_results(&self) -> (f64, f64, f64) {
+        let n = self.non_candidates as f64;
+        (
+            self.miss_rate() * n,
+            self.interval.0 * n,
+            self.interval.1 * n,
+        )
+    }
+
+    /// Recall with its bounds from the estimated results of the candidates
+    pub fn recall(&self, precision: &PrecisionEstimate) -> (f64, f64, f64) {
+        let recall = |results: f64, missed: f64| {
+            if results + missed == 0.0 {
+                1.0
+            } else {
+                results / (results + missed)
+            }
+        };
+        let (results, results_low, results_high) = precision.results();
+        let (missed, missed_low, missed_high) = self.missed_results();
+        (
+            recall(results, missed),
+            recall(results_low, missed_high),
+            recall(results_high, missed_low),
+        )
+    }
+}
+
+/// Rates have the precision of the formatter, 6 decimals without one
+impl fmt::Display for RecallEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(6);
+        let (missed, low, high) = self.missed_results();
+        writeln!(
+            f,
+            "Found {} results in {} sampled non-candidates out of {}",
+            self.missed, self.sampled, self.non_candidates
+        )?;
+        writeln!(
+            f,
+            "false negative rate:{:.precision$} ({:.0}% interval {:.precision$}-{:.precision$})",
+            self.miss_rate(),
+            self.confidence * 100.0,
+            self.interval.0,
+            self.interval.1
+        )?;
+        write!(
+            f,
+            "estimated missed results:{missed:.0} ({:.0}-{:.0})",
+            low.floor(),
+            high.ceil()
+        )
+    }
+}
+
+/// Sizes of the strata proportional to their populations, rounded by largest remainder
+fn allocate(sample_size: usize, populations: &[usize]) -> Vec<usize> {
+    let total = populations.iter().sum::<usize>() as u128;
+    if total == 0 {
+        return vec![0; populations.len()];
+    }
+    let quota = |population: usize| sample_size as u128 * population as u128;
+    let mut sizes = populations
+        .iter()
+        .map(|&population| (quota(population) / total) as usize)
+        .collect::<Vec<_>>();
+    let left = sample_size - sizes.iter().sum::<usize>();
+    let mut by_remainder = (0..populations.len()).collect::<Vec<_>>();
+    by_remainder.sort_by_key(|&h| std::cmp::Reverse(quota(populations[h]) % total));
+    for &h in by_remainder.iter().take(left) {
+        sizes[h] += 1;
+    }
+    sizes
+}
+
+/// Trees of the window not in `known`, sorted, at the sorted positions `ranks` among them
+fn non_candidates_at(window: Range<usize>, known: &[usize], ranks: &[usize]) -> Vec<usize> {
+    let mut skipped = 0;
+    ranks
+        .iter()
+        .map(|&rank| {
+            while skipped < known.len() && known[skipped] <= window.start + rank + skipped {
+                skipped += 1;
+            }
+            window.start + rank + skipped
+        })
+        .collect()
+}
+
+/// Verifies a sample of `sample_size` pairs that are not in `known`, the candidates and
+/// pairs left out of the join such as a query with its own tree, with the bounded tree
+/// edit distance of the verifier. Trees are sorted by size. A sample of all
+/// non-candidates gives the exact share of missed results with an empty interval.
+pub fn estimate_recall(
+    trees: &[ParsedTree],
+    queries: &[(usize, ParsedTree)],
+    known: &[(usize, usize)],
+    sample_size: usize,
+    confidence: f64,
+    seed: u64,
+    verifier: &dyn Verifier,
+) -> Result<RecallEstimate, Error> {
+    let sizes = trees.iter().map(ParsedTree::count).collect::<Vec<_>>();
+    let windows = queries
+        .iter()
+        .map(|(k, query)| {
+            let size = query.count();
+            sizes.partition_point(|s| s + k < size)..sizes.partition_point(|s| *s <= size + k)
+        })
+        .collect::<Vec<_>>();
+    let mut known_trees = vec![vec![]; queries.len()];
+    for &(qid, tid) in known {
+        if windows[qid].contains(&tid) {
+            known_trees[qid].push(tid);
+        }
+    }
+    for trees in known_trees.iter_mut() {
+        trees.sort_unstable();
+        trees.dedup();
+    }
+    let populations = windows
+        .iter()
+        .zip(&known_trees)
+        .map(|(window, known)| window.len() - known.len())
+        .collect::<Vec<_>>();
+    let non_candidates = populations.iter().sum::<usize>();
+    let sampled = sample_size.min(non_candidates);
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let pairs = allocate(sampled, &populations)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(qid, stratum_size)| {
+            let mut ranks = sample(&mut rng, populations[qid], stratum_size).into_vec();
+            ranks.sort_unstable();
+            non_candidates_at(windows[qid].clone(), &known_trees[qid], &ranks)
+                .into_iter()
+                .map(move |tid| (qid, tid))
+        })
+        .collect::<Vec<_>>();
+    let missed = verify_pairs(verifier, trees, queries, &pairs)?
+        .into_iter()
+        .flatten()
+        .count();
+    let interval = if sampled == non_candidates {
+        let rate = missed as f64 / sampled.max(1) as f64;
+        (rate, rate)
+    } else {
+        wilson_interval(missed, sampled, normal_quantile(0.5 + confidence / 2.0))
+    };
+    Ok(RecallEstimate {
+        non_candidates,
+        sampled,
+        missed,
+        confidence,
+        interval,
+    })
+}
+
+/// Wilson score interval of a proportion of `successes` out of `n` trials
+fn wilson_interval(successes: usize, n: usize, z: f64) -> (f64, f64) {
+    if n == 0 {
+        return (0.0, 1.0);
+    }
+    let n = n as f64;
+    let p = successes as f64 / n;
+    let z2 = z * z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let spread = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((center - spread).max(0.0), (center + spread).min(1.0))
+}
+
+/// Quantile of the standard normal distribution for `p` in (0, 1), rational
+/// approximation of Abramowitz and Stegun 26.2.23 with absolute error below 4.5e-4
+fn normal_quantile(p: f64) -> f64 {
+    let tail = p.min(1.0 - p);
+    let t = (-2.0 * tail.ln()).sqrt();
+    let z = t
+        - (2.515517 + 0.802853 * t + 0.010328 * t * t)
+            / (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t);
+    if p < 0.5 {
+        -z
+    } else {
+        z
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{parse_single, LabelDict};
+    use crate::verifier::ZhangShasha;
+
+    #[test]
+    fn test_estimate_precision() {
+        assert!((normal_quantile(0.975) - 1.96).abs() < 1e-3);
+        let (low, high) = wilson_interval(50, 100, 1.96);
+        assert!((low - 0.404).abs() < 1e-3 && (high - 0.596).abs() < 1e-3);
+
+        let mut ld = LabelDict::default();
+        let trees = ["{a{b}}", "{a{c}}", "{x{y}{z}}"]
+            .map(|t| parse_single(t.to_owned(), &mut ld))
+            .to_vec();
+        let queries = vec![(1, trees[0].clone())];
+        let candidates = [(0, 0), (0, 1), (0, 2)];
+        let all = estimate_precision(
+            &trees,
+            &queries,
+            &candidates,
+            10,
+            0.95,
+            1,
+            &ZhangShasha::default(),
+        )
+        .unwrap();
+        assert_eq!((all.sampled, all.verified), (3, 2));
+        assert_eq!(all.interval, (2.0 / 3.0, 2.0 / 3.0));
+
+        let part = estimate_precision(
+            &trees,
+            &queries,
+            &candidates,
+            2,
+            0.95,
+            1,
+            &ZhangShasha::default(),
+        )
+        .unwrap();
+        assert_eq!(part.sampled, 2);
+        assert!(part.interval.0 <= part.precision() && part.precision() <= part.interval.1);
+        assert!(part.interval.0 < part.interval.1);
+    }
+
+    #[test]
+    fn test_estimate_recall() {
+        assert_eq!(allocate(5, &[10, 0, 30, 10]), [1, 0, 3, 1]);
+        assert_eq!(allocate(3, &[1, 1, 1, 1]).iter().sum::<usize>(), 3);
+        assert_eq!(non_candidates_at(2..8, &[3, 4, 6], &[0, 1, 2]), [2, 5, 7]);
+
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a}",
+            "{a{b}}",
+            "{a{c}}",
+            "{x{y}}",
+            "{a{b}{c}}",
+            "{a{b}{c}{d}{e}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld))
+        .to_vec();
+        let queries = vec![(1, trees[1].clone())];
+        // results are trees 0, 1, 2 and 4, the candidates miss 2 and 4, tree 5 is
+        // outside the size window and never sampled
+        let known = [(0, 0), (0, 1), (0, 3)];
+        let all = estimate_recall(
+            &trees,
+            &queries,
+            &known,
+            10,
+            0.95,
+            1,
+            &ZhangShasha::default(),
+        )
+        .unwrap();
+        assert_eq!((all.non_candidates, all.sampled, all.missed), (2, 2, 2));
+        assert_eq!(all.missed_results(), (2.0, 2.0, 2.0));
+        let precision = estimate_precision(
+            &trees,
+            &queries,
+            &known,
+            10,
+            0.95,
+            1,
+            &ZhangShasha::default(),
+        )
+        .unwrap();
+        let (recall, low, high) = all.recall(&precision);
+        assert_eq!((recall, low, high), (0.5, 0.5, 0.5));
+
+        let part = estimate_recall(
+            &trees,
+            &queries,
+            &known,
+            1,
+            0.95,
+            1,
+            &ZhangShasha::default(),
+        )
+        .unwrap();
+        assert_eq!((part.sampled, part.missed), (1, 1));
+        assert!(part.interval.0 < part.interval.1);
+    }
+}
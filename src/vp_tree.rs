@@ -0,0 +1,308 @@
+//! Vantage point tree over exact tree edit distance, for collections queried repeatedly.
+//!
+//! Inner nodes split trees by their distance to a pivot, picked from a sample as the one
+//! with the largest spread of distances. Trees in leaves keep their distances to all
+//! pivots on the path from the root, a query visits only subtrees and leaf trees not
+//! excluded by the triangle inequality. The remaining trees go through the lower bound
+//! filter and are verified, pivots are answered exactly on the way down.
+use crate::indexing::{Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::label_intersection_k;
+use crate::lb::preprocess::Preprocessed;
+use crate::lb::sed::sed_k;
+use crate::lb::structural_filter::{ted as struct_ted_k, LabelSetConverter};
+use crate::parsing::ParsedTree;
+use crate::ted::{ted, ted_k};
+use crate::LowerBoundMethods;
+use rayon::prelude::*;
+
+/// Number of sampled pivot candidates and of trees their spread is measured on
+const PIVOT_SAMPLE: usize = 4;
+const SPREAD_SAMPLE: usize = 8;
+
+enum Node {
+    Inner {
+        pivot: usize,
+        /// trees within the radius of the pivot are inside
+        radius: usize,
+        inside: usize,
+        outside: usize,
+    },
+    Leaf {
+        /// trees with their distances to pivots on the path from the root
+        trees: Vec<(usize, Vec<usize>)>,
+    },
+}
+
+/// Counters of a query
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueryStats {
+    /// Exact distances to pivots
+    pub pivots: usize,
+    /// Leaf trees excluded by the triangle inequality
+    pub pruned: usize,
+    /// Leaf trees passing the lower bound filter
+    pub candidates: usize,
+}
+
+impl std::ops::AddAssign for QueryStats {
+    fn add_assign(&mut self, other: Self) {
+        self.pivots += other.pivots;
+        self.pruned += other.pruned;
+        self.candidates += other.candidates;
+    }
+}
+
+pub struct VpTree {
+    nodes: Vec<Node>,
+    pivots: usize,
+}
+
+impl VpTree {
+    /// Builds the tree over all trees, nodes with at most `leaf_size` trees are leaves
+    pub fn new(trees: &[ParsedTree], leaf_size: usize) -> Self {
+        let mut vp_tree = Self {
+            nodes: vec![],
+            pivots: 0,
+        };
+        let all = (0..trees.len()).map(|tid| (tid, vec![])).collect();
+        vp_tree.build(trees, all, leaf_size.max(1));
+        vp_tree
+    }
+
+    pub fn pivots(&self) -> usize {
+        self.pivots
+    }
+
+    fn build(
+        &mut self,
+        trees: &[ParsedTree],
+        mut items: Vec<(usize, Vec<usize>)>,
+        leaf_size: usize,
+    ) -> usize {
+        if items.len() <= leaf_size {
+            self.nodes.push(Node::Leaf { trees: items });
+            return self.nodes.len() - 1;
+        }
+        let (pivot, _) = items.swap_remove(Self::select_pivot(trees, &items));
+        let mut distances = items
+            .into_par_iter()
+            .map(|(tid, mut path)| {
+                let distance = ted(&trees[pivot], &trees[tid]);
+                path.push(distance);
+                (distance, (tid, path))
+            })
+            .collect::<Vec<_>>();
+        distances.sort_unstable_by_key(|(distance, (tid, _))| (*distance, *tid));
+        let radius = distances[(distances.len() - 1) / 2].0;
+        let (inside, outside): (Vec<_>, Vec<_>) = distances
+            .into_iter()
+            .partition(|(distance, _)| *distance <= radius);
+
+        self.pivots += 1;
+        let node = self.nodes.len();
+        self.nodes.push(Node::Leaf { trees: vec![] });
+        let inside = self.build(
+            trees,
+            inside.into_iter().map(|(_, t)| t).collect(),
+            leaf_size,
+        );
+        let outside = self.build(
+            trees,
+            outside.into_iter().map(|(_, t)| t).collect(),
+            leaf_size,
+        );
+        self.nodes[node] = Node::Inner {
+            pivot,
+            radius,
+            inside,
+            outside,
+        };
+        node
+    }
+
+    /// Position of the sampled tree with the largest variance of distances to a sample
+    fn select_pivot(trees: &[ParsedTree], items: &[(usize, Vec<usize>)]) -> usize {
+        let sample = |n: usize| (0..items.len()).step_by(items.len().div_ceil(n));
+        let spread_sample = sample(SPREAD_SAMPLE).collect::<Vec<_>>();
+        sample(PIVOT_SAMPLE)
+            .map(|candidate| {
+                let distances = spread_sample
+                    .par_iter()
+                    .map(|i| ted(&trees[items[candidate].0], &trees[items[*i].0]) as f64)
+                    .collect::<Vec<_>>();
+                let mean = distances.iter().sum::<f64>() / distances.len() as f64;
+                let variance = distances.iter().map(|d| (d - mean).powi(2)).sum::<f64>();
+                (candidate, variance)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(0, |(candidate, _)| candidate)
+    }
+
+    /// Trees within `k` of the query as `(tree id, distance)` sorted by tree id. Leaf trees
+    /// not excluded by the triangle inequality are kept if `lower_bound(tree id, k)` is
+    /// at most `k` and then verified.
+    pub fn query(
+        &self,
+        trees: &[ParsedTree],
+        query: &ParsedTree,
+        k: usize,
+        lower_bound: impl Fn(usize, usize) -> usize,
+    ) -> (Vec<(usize, usize)>, QueryStats) {
+        let mut stats = QueryStats::default();
+        let mut results = vec![];
+        let mut stack = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![(0, vec![])]
+        };
+        while let Some((node, path)) = stack.pop() {
+            match &self.nodes[node] {
+                Node::Inner {
+                    pivot,
+                    radius,
+                    inside,
+                    outside,
+                } => {
+                    let distance = ted(query, &trees[*pivot]);
+                    stats.pivots += 1;
+                    if distance <= k {
+                        results.push((*pivot, distance));
+                    }
+                    let mut path = path;
+                    path.push(distance);
+                    if distance + k > *radius {
+                        stack.push((*outside, path.clone()));
+                    }
+                    if distance <= radius + k {
+                        stack.push((*inside, path));
+                    }
+                }
+                Node::Leaf { trees: leaf } => {
+                    for (tid, pivot_distances) in leaf {
+                        if path
+                            .iter()
+                            .zip(pivot_distances)
+                            .any(|(q, t)| q.abs_diff(*t) > k)
+                        {
+                            stats.pruned += 1;
+                            continue;
+                        }
+                        if lower_bound(*tid, k) > k {
+                            continue;
+                        }
+                        stats.candidates += 1;
+                        let distance = ted_k(query, &trees[*tid], k);
+                        if distance <= k {
+                            results.push((*tid, distance));
+                        }
+                    }
+                }
+            }
+        }
+        results.sort_unstable();
+        (results, stats)
+    }
+}
+
+/// Results as `(query id, tree id, distance)` and counters summed over all queries
+pub type RunResult = (Vec<(usize, usize, usize)>, QueryStats);
+
+/// Answers all queries with the VP-tree, lower bounds of `method` filter trees of leaves.
+/// Results are sorted by ids.
+pub fn run(
+    vp_tree: &VpTree,
+    method: LowerBoundMethods,
+    pre: &Preprocessed,
+    queries: &[(usize, ParsedTree)],
+) -> Result<RunResult, anyhow::Error> {
+    use LowerBoundMethods as LBM;
+    let trees = pre.trees();
+    let label_dict = pre.label_dict();
+    let per_query = queries
+        .par_iter()
+        .enumerate()
+        .map(|(qid, (k, query))| {
+            let (results, stats) = match method {
+                LBM::Lblint => {
+                    let q = InvertedListLabelPostorderIndex::index_tree(query, label_dict)?;
+                    let sets = pre.label_sets()?;
+                    vp_tree.query(trees, query, *k, |tid, k| {
+                        label_intersection_k(&q, &sets[tid], k)
+                    })
+                }
+                LBM::Sed | LBM::SedBkTree => {
+                    let q = SEDIndex::index_tree(query, label_dict)?;
+                    let traversals = pre.traversals()?;
+                    vp_tree.query(trees, query, *k, |tid, k| sed_k(&q, &traversals[tid], k))
+                }
+                LBM::Structural => {
+                    let q = LabelSetConverter::with_weights(pre.region_weights())
+                        .create_single(query)?;
+                    let sets = pre.structural_sets()?;
+                    vp_tree.query(trees, query, *k, |tid, k| struct_ted_k(&q, &sets[tid], k))
+                }
+                _ => anyhow::bail!("Method {method:?} is not supported yet"),
+            };
+            Ok((
+                results
+                    .into_iter()
+                    .map(|(tid, distance)| (qid, tid, distance))
+                    .collect::<Vec<_>>(),
+                stats,
+            ))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    let mut stats = QueryStats::default();
+    let mut results = vec![];
+    for (query_results, query_stats) in per_query {
+        results.extend(query_results);
+        stats += query_stats;
+    }
+    Ok((results, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::CollectionView;
+    use crate::parsing::{parse_single, LabelDict};
+
+    #[test]
+    fn test_matches_exact_ted() {
+        let mut ld = LabelDict::default();
+        let trees = [
+            "{a}",
+            "{b}",
+            "{a{b}}",
+            "{a{b}{c}}",
+            "{a{c}{b}}",
+            "{a{b{c}}}",
+            "{x{y}{z}}",
+            "{a{b}{c}{d}}",
+            "{x{y}{z}{w}{v}}",
+        ]
+        .map(|t| parse_single(t.to_owned(), &mut ld));
+        let pre = Preprocessed::new(CollectionView::new(&trees), &ld).unwrap();
+        for leaf_size in [1, 3] {
+            let vp_tree = VpTree::new(&trees, leaf_size);
+            let queries = trees
+                .iter()
+                .flat_map(|t| (0..4).map(|k| (k, t.clone())))
+                .collect::<Vec<_>>();
+            let (results, _) = run(&vp_tree, LowerBoundMethods::Sed, &pre, &queries).unwrap();
+            let expected = queries
+                .iter()
+                .enumerate()
+                .flat_map(|(qid, (k, q))| {
+                    trees
+                        .iter()
+                        .enumerate()
+                        .map(move |(tid, t)| (qid, tid, ted(q, t)))
+                        .filter(move |(_, _, d)| d <= k)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(results, expected);
+        }
+    }
+}
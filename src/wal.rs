@@ -0,0 +1,107 @@
+//! Write-ahead log of collection updates made while serving.
+//!
+//! Every update is appended as a JSON line and synced before it is applied, after a
+//! restart the dataset is loaded again and the logged updates are replayed on top of it.
+//! The first record holds the number of trees of the dataset, a log is only replayed on
+//! a dataset of the same size. A last line without a newline is left by an interrupted
+//! write, that update was never applied and the line is dropped.
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalRecord {
+    /// Number of trees of the dataset the log was started on
+    Base {
+        trees: usize,
+    },
+    Insert {
+        tree: String,
+    },
+    Delete {
+        id: usize,
+    },
+}
+
+pub struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Opens the log or starts a new one, returns it with the updates logged so far
+    pub fn open(
+        path: &impl AsRef<Path>,
+        trees: usize,
+    ) -> Result<(Self, Vec<WalRecord>), anyhow::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let complete = content.rfind('\n').map_or(0, |end| end + 1);
+        if complete < content.len() {
+            file.set_len(complete as u64)?;
+        }
+
+        let mut records = content[..complete]
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| anyhow::anyhow!("Invalid log record on line {}: {e}", i + 1))
+            })
+            .collect::<Result<Vec<WalRecord>, _>>()?;
+        let mut wal = Self { file };
+        match records.first() {
+            None => wal.append(&WalRecord::Base { trees })?,
+            Some(WalRecord::Base { trees: logged }) if *logged == trees => {
+                records.remove(0);
+            }
+            Some(WalRecord::Base { trees: logged }) => anyhow::bail!(
+                "Log was written for a dataset of {logged} trees, the dataset has {trees}"
+            ),
+            Some(_) => anyhow::bail!("Log does not start with the dataset size"),
+        }
+        Ok((wal, records))
+    }
+
+    /// Appends the record and waits until it is on disk
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), std::io::Error> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_drops_torn_record() {
+        let path = std::env::temp_dir().join(format!("wal-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut wal, records) = Wal::open(&path, 3).unwrap();
+        assert!(records.is_empty());
+        let insert = WalRecord::Insert {
+            tree: "{a{b}}".to_owned(),
+        };
+        wal.append(&insert).unwrap();
+        wal.append(&WalRecord::Delete { id: 1 }).unwrap();
+        wal.file.write_all(b"{\"op\":\"ins").unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&path, 3).unwrap();
+        assert_eq!(records, vec![insert.clone(), WalRecord::Delete { id: 1 }]);
+        wal.append(&WalRecord::Delete { id: 3 }).unwrap();
+        let (_, records) = Wal::open(&path, 3).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(Wal::open(&path, 4).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! JS API for exploring small collections in the browser, built with the `wasm` feature,
+//! e.g. `wasm-pack build --target web --features wasm`.
+//!
+//! A [`Collection`] is parsed from text with one tree in bracket notation per line, all
+//! lower bound indexes are built upfront. Trees are identified by their position among
+//! the non empty lines. Statistics are returned as JSON strings.
+use crate::indexing::{Indexer, InvertedListLabelPostorderIndex, SEDIndex};
+use crate::lb::label_intersection::label_intersection;
+use crate::lb::sed::sed;
+use crate::lb::structural_filter::{ted as struct_ted_k, StructuralFilterTuple};
+use crate::parsing::{self, LabelDict, ParsedTree, TreeParseError};
+use crate::statistics;
+use serde_json::json;
+use wasm_bindgen::prelude::*;
+
+fn average(values: &[usize]) -> f64 {
+    values.iter().sum::<usize>() as f64 / values.len() as f64
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Lblint,
+    Sed,
+    Structural,
+}
+
+impl TryFrom<&str> for Method {
+    type Error = JsError;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "lblint" => Ok(Self::Lblint),
+            "sed" => Ok(Self::Sed),
+            "structural" => Ok(Self::Structural),
+            _ => Err(JsError::new(&format!(
+                "Unknown lower bound method {name}, expected lblint, sed or structural"
+            ))),
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct Collection {
+    trees: Vec<ParsedTree>,
+    label_dict: LabelDict,
+    lblint: Vec<InvertedListLabelPostorderIndex>,
+    sed: Vec<SEDIndex>,
+    structural: Vec<StructuralFilterTuple>,
+}
+
+#[wasm_bindgen]
+impl Collection {
+    /// Parses trees in bracket notation, one per line, empty lines are skipped
+    #[wasm_bindgen(constructor)]
+    pub fn new(text: &str) -> Result<Collection, JsError> {
+        let tokens = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                let tokens = if line.is_ascii() {
+                    parsing::parse_tree_tokens(line.to_owned())
+                } else {
+                    Err(TreeParseError::IsNotAscii)
+                };
+                tokens.map_err(|e| JsError::new(&format!("Tree {i}: {e}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut label_dict = LabelDict::new();
+        parsing::update_label_dict(&tokens, &mut label_dict);
+        let trees = tokens
+            .iter()
+            .map(|tokens| parsing::parse_tree(tokens, &label_dict))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            lblint: InvertedListLabelPostorderIndex::index_collection(&trees, &label_dict)?,
+            sed: SEDIndex::index_collection(&trees, &label_dict)?,
+            structural: StructuralFilterTuple::index_collection(&trees, &label_dict)?,
+            trees,
+            label_dict,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    #[wasm_bindgen(js_name = isEmpty)]
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    /// Tree in bracket notation
+    pub fn tree(&self, id: usize) -> Result<String, JsError> {
+        let tree = self.get(id)?;
+        let label_names = parsing::label_names(&self.label_dict);
+        Ok(parsing::tree_to_labeled_bracket(tree, &label_names))
+    }
+
+    /// Size, distinct labels, degrees and depths of a tree as JSON
+    pub fn statistics(&self, id: usize) -> Result<String, JsError> {
+        let tree = self.get(id)?;
+        let freq_ordering = parsing::get_frequency_ordering(&self.label_dict);
+        let stats = statistics::gather_chunked(std::slice::from_ref(tree), &freq_ordering, 1);
+        Ok(json!({
+            "size": stats.sizes[0],
+            "distinctLabels": stats.distinct_labels[0],
+            "collectionUniqueLabels": stats.collection_unique_labels[0],
+            "avgDegree": average(&stats.degrees),
+            "maxDegree": stats.degrees.iter().max(),
+            "avgDepth": average(&stats.depths),
+            "maxDepth": stats.depths.iter().max(),
+            "degrees": stats.degrees,
+            "depths": stats.depths,
+        })
+        .to_string())
+    }
+
+    /// Statistics of the whole collection as JSON
+    pub fn summary(&self) -> String {
+        let freq_ordering = parsing::get_frequency_ordering(&self.label_dict);
+        let summary =
+            statistics::gather_chunked(&self.trees, &freq_ordering, self.trees.len()).summary();
+        json!({
+            "trees": summary.trees,
+            "distinctLabels": self.label_dict.len(),
+            "minTreeSize": summary.min_tree_size,
+            "maxTreeSize": summary.max_tree_size,
+            "avgTreeSize": summary.avg_tree_size,
+            "avgUniqueLabelsPerTree": summary.avg_unique_label_per_tree,
+            "avgTreeDistinctLabels": summary.avg_tree_distinct_labels,
+        })
+        .to_string()
+    }
+
+    /// Lower bound of the tree edit distance between two trees, method is one of
+    /// `lblint`, `sed` or `structural`
+    #[wasm_bindgen(js_name = lowerBound)]
+    pub fn lower_bound(&self, method: &str, first: usize, second: usize) -> Result<usize, JsError> {
+        let method = Method::try_from(method)?;
+        self.get(first)?;
+        self.get(second)?;
+        Ok(self.bound(method, first, second))
+    }
+
+    /// Lower bounds between all pairs of trees, row by row
+    #[wasm_bindgen(js_name = lowerBoundMatrix)]
+    pub fn lower_bound_matrix(&self, method: &str) -> Result<Vec<u32>, JsError> {
+        let method = Method::try_from(method)?;
+        let n = self.trees.len();
+        let mut matrix = vec![0; n * n];
+        for first in 0..n {
+            for second in first + 1..n {
+                let bound = self.bound(method, first, second) as u32;
+                matrix[first * n + second] = bound;
+                matrix[second * n + first] = bound;
+            }
+        }
+        Ok(matrix)
+    }
+}
+
+impl Collection {
+    fn get(&self, id: usize) -> Result<&ParsedTree, JsError> {
+        self.trees.get(id).ok_or_else(|| {
+            JsError::new(&format!(
+                "Tree {id} is out of range of {} trees",
+                self.trees.len()
+            ))
+        })
+    }
+
+    fn bound(&self, method: Method, first: usize, second: usize) -> usize {
+        match method {
+            Method::Lblint => label_intersection(&self.lblint[first], &self.lblint[second]),
+            Method::Sed => sed(&self.sed[first], &self.sed[second]),
+            Method::Structural => {
+                // the bound is exact once the threshold allows any region distance
+                let k = self.trees[first].count().max(self.trees[second].count());
+                struct_ted_k(&self.structural[first], &self.structural[second], k)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_lower_bounds() {
+        let collection = Collection::new("{a{b}{c}}\n\n{a{b}{d}}\n{x{y}{z}{w}}\n").unwrap();
+        assert_eq!(collection.len(), 3);
+        assert_eq!(collection.tree(1).unwrap(), "{a{b}{d}}");
+        for method in ["lblint", "sed", "structural"] {
+            let matrix = collection.lower_bound_matrix(method).unwrap();
+            assert_eq!(matrix.len(), 9);
+            assert_eq!(matrix[1], 1, "{method}");
+            assert_eq!(matrix[3], matrix[1]);
+            assert_eq!(matrix[5], 4, "{method}");
+            assert_eq!(collection.lower_bound(method, 2, 1).unwrap(), 4);
+        }
+        let stats: serde_json::Value =
+            serde_json::from_str(&collection.statistics(2).unwrap()).unwrap();
+        assert_eq!(stats["size"], 4);
+        assert_eq!(stats["maxDegree"], 3);
+    }
+}
@@ -0,0 +1,76 @@
+//! Generation of query workloads by sampling (and optionally perturbing) dataset trees.
+use crate::edits::apply_random_edits;
+use crate::parsing::{label_names, max_label, tree_to_labeled_bracket, LabelDict, ParsedTree};
+use rand::seq::index::sample;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ThresholdSpec {
+    /// Same threshold for every query
+    Absolute(usize),
+    /// Threshold as a fraction of the query tree size, rounded up
+    Relative(f64),
+}
+
+impl ThresholdSpec {
+    pub fn for_size(&self, tree_size: usize) -> usize {
+        match *self {
+            ThresholdSpec::Absolute(k) => k,
+            ThresholdSpec::Relative(ratio) => (tree_size as f64 * ratio).ceil() as usize,
+        }
+    }
+}
+
+/// Samples `count` trees, applies `edits` random edit operations to each and
+/// returns lines of `threshold;tree` query file format.
+pub fn generate_queries(
+    trees: &[ParsedTree],
+    label_dict: &LabelDict,
+    count: usize,
+    edits: usize,
+    threshold: ThresholdSpec,
+    seed: u64,
+) -> Vec<String> {
+    if trees.is_empty() {
+        return vec![];
+    }
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let tree_ids: Vec<usize> = if count <= trees.len() {
+        sample(&mut rng, trees.len(), count).into_vec()
+    } else {
+        (0..count).map(|_| rng.gen_range(0..trees.len())).collect()
+    };
+
+    let names = label_names(label_dict);
+    let max_label = max_label(label_dict);
+    tree_ids
+        .into_iter()
+        .map(|tid| {
+            let query = apply_random_edits(&trees[tid], edits, &mut rng, max_label);
+            let k = threshold.for_size(trees[tid].count());
+            format!("{k};{}", tree_to_labeled_bracket(&query, &names))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::parse_single;
+
+    #[test]
+    fn test_generated_queries_are_reproducible() {
+        let mut ld = LabelDict::default();
+        let trees = vec![
+            parse_single("{a{b}{c}}".to_owned(), &mut ld),
+            parse_single("{x{y{z}}}".to_owned(), &mut ld),
+            parse_single("{a{b{c}{d}}}".to_owned(), &mut ld),
+        ];
+        let q1 = generate_queries(&trees, &ld, 5, 1, ThresholdSpec::Relative(0.5), 3);
+        let q2 = generate_queries(&trees, &ld, 5, 1, ThresholdSpec::Relative(0.5), 3);
+        assert_eq!(q1.len(), 5);
+        assert_eq!(q1, q2);
+        assert!(q1.iter().all(|q| q.split_once(';').is_some()));
+    }
+}